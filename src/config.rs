@@ -1,26 +1,83 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use serde::{Deserialize, Serialize};
 
 use crate::error::{Error, IoResultExt, Result};
 use crate::namespace::NsConfig;
+use crate::types::EntryNamePolicy;
 
 /// repository configuration stored in config.toml
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// on-disk format version; 0 for repositories created before it was
+    /// recorded, which share version 1's layout
+    #[serde(default)]
+    pub format_version: u32,
     /// namespace mapping for this repository
     pub namespace: NsConfig,
     /// configured remotes
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub remotes: Vec<Remote>,
+    /// store regular file content once under a content-only hash, with
+    /// per-entry metadata kept separately (see `objects/content`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub content_addressed_blobs: bool,
+    /// extra restrictions on entry names enforced when building trees
+    #[serde(default, skip_serializing_if = "EntryNamePolicy::is_permissive")]
+    pub entry_name_policy: EntryNamePolicy,
+    /// write blobs through a temp file in their own object directory rather
+    /// than `tmp/`, for stores where the two may be on different devices
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub same_dir_tmp: bool,
+    /// write refs into the single `refs/packed` file instead of one file per
+    /// ref; loose refs are still read and take precedence
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub packed_refs: bool,
+    /// largest size a tree or commit may decompress to; larger ones are
+    /// rejected as corrupt rather than inflated into memory
+    #[serde(
+        default = "default_max_object_size",
+        skip_serializing_if = "is_default_max_object_size"
+    )]
+    pub max_object_size: u64,
+    /// object store location when not `objects/`, absolute or relative to
+    /// the repository
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub objects_dir: Option<PathBuf>,
+    /// read-only object stores consulted when an object is missing from
+    /// this one, absolute or relative to the repository; writes never go there
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternates: Vec<PathBuf>,
+}
+
+/// newest repository format version this build reads and writes
+pub const FORMAT_VERSION: u32 = 1;
+
+/// default for `Config::max_object_size`: 256 MiB
+pub const DEFAULT_MAX_OBJECT_SIZE: u64 = 256 << 20;
+
+fn default_max_object_size() -> u64 {
+    DEFAULT_MAX_OBJECT_SIZE
+}
+
+fn is_default_max_object_size(size: &u64) -> bool {
+    *size == DEFAULT_MAX_OBJECT_SIZE
 }
 
 impl Config {
     /// create a new config with given namespace
     pub fn new(namespace: NsConfig) -> Self {
         Self {
+            format_version: FORMAT_VERSION,
             namespace,
             remotes: vec![],
+            content_addressed_blobs: false,
+            entry_name_policy: EntryNamePolicy::default(),
+            same_dir_tmp: false,
+            packed_refs: false,
+            max_object_size: DEFAULT_MAX_OBJECT_SIZE,
+            objects_dir: None,
+            alternates: vec![],
         }
     }
 
@@ -69,13 +126,29 @@ impl Config {
     pub fn get_remote(&self, name: &str) -> Option<&Remote> {
         self.remotes.iter().find(|r| r.name == name)
     }
+
+    /// resolve a configured remote name, or else a url/path given directly
+    pub fn resolve_remote(&self, name_or_url: &str) -> Result<RemoteUrl> {
+        match self.get_remote(name_or_url) {
+            Some(remote) => remote.resolve(),
+            None => RemoteUrl::parse(name_or_url),
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            format_version: FORMAT_VERSION,
             namespace: NsConfig::default(),
             remotes: vec![],
+            content_addressed_blobs: false,
+            entry_name_policy: EntryNamePolicy::default(),
+            same_dir_tmp: false,
+            packed_refs: false,
+            max_object_size: DEFAULT_MAX_OBJECT_SIZE,
+            objects_dir: None,
+            alternates: vec![],
         }
     }
 }
@@ -94,6 +167,65 @@ impl Remote {
             url: url.into(),
         }
     }
+
+    /// parse the url into the transport it selects
+    pub fn resolve(&self) -> Result<RemoteUrl> {
+        RemoteUrl::parse(&self.url)
+    }
+}
+
+/// where a remote lives, by transport
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RemoteUrl {
+    /// repository on a local (or mounted) filesystem
+    Local(PathBuf),
+    /// repository reached over ssh; `host` may carry a `user@` prefix
+    Ssh { host: String, path: PathBuf },
+}
+
+impl RemoteUrl {
+    /// parse `ssh://[user@]host/path`, scp-style `[user@]host:path`,
+    /// `file:///path` or a plain path
+    ///
+    /// any other `scheme://` is rejected rather than taken as a relative path.
+    pub fn parse(url: &str) -> Result<Self> {
+        if let Some(rest) = url.strip_prefix("ssh://") {
+            let (host, path) = match rest.find('/') {
+                Some(i) => (&rest[..i], &rest[i..]),
+                None => (rest, ""),
+            };
+            if host.is_empty() || host.ends_with('@') || path.len() <= 1 {
+                return Err(Error::UnsupportedRemoteUrl(url.to_string()));
+            }
+            return Ok(Self::Ssh {
+                host: host.to_string(),
+                path: PathBuf::from(path),
+            });
+        }
+
+        if let Some(path) = url.strip_prefix("file://") {
+            return Ok(Self::Local(PathBuf::from(path)));
+        }
+
+        if url.is_empty() || url.contains("://") {
+            return Err(Error::UnsupportedRemoteUrl(url.to_string()));
+        }
+
+        // a colon before any slash means host:path, as with scp
+        if let Some((host, path)) = url.split_once(':') {
+            if !host.contains('/') {
+                if host.is_empty() || host.ends_with('@') || path.is_empty() {
+                    return Err(Error::UnsupportedRemoteUrl(url.to_string()));
+                }
+                return Ok(Self::Ssh {
+                    host: host.to_string(),
+                    path: PathBuf::from(path),
+                });
+            }
+        }
+
+        Ok(Self::Local(PathBuf::from(url)))
+    }
 }
 
 #[cfg(test)]
@@ -104,6 +236,7 @@ mod tests {
     #[test]
     fn test_config_toml_roundtrip() {
         let config = Config {
+            format_version: FORMAT_VERSION,
             namespace: NsConfig {
                 uid_map: vec![MapEntry::new(0, 1000, 1), MapEntry::new(1, 100000, 65536)],
                 gid_map: vec![MapEntry::new(0, 1000, 1), MapEntry::new(1, 100000, 65536)],
@@ -112,6 +245,13 @@ mod tests {
                 Remote::new("origin", "ssh://server/var/zub"),
                 Remote::new("backup", "/mnt/backup/zub"),
             ],
+            content_addressed_blobs: false,
+            entry_name_policy: EntryNamePolicy::default(),
+            same_dir_tmp: false,
+            packed_refs: false,
+            max_object_size: DEFAULT_MAX_OBJECT_SIZE,
+            objects_dir: None,
+            alternates: vec![],
         };
 
         let toml_str = toml::to_string_pretty(&config).unwrap();
@@ -120,6 +260,7 @@ mod tests {
         assert_eq!(config.namespace.uid_map, parsed.namespace.uid_map);
         assert_eq!(config.namespace.gid_map, parsed.namespace.gid_map);
         assert_eq!(config.remotes, parsed.remotes);
+        assert_eq!(parsed.format_version, FORMAT_VERSION);
     }
 
     #[test]
@@ -144,6 +285,80 @@ mod tests {
         assert!(config.remove_remote("origin").is_err());
     }
 
+    #[test]
+    fn test_remote_url_parse() {
+        assert_eq!(
+            RemoteUrl::parse("ssh://deploy@build01/var/zub").unwrap(),
+            RemoteUrl::Ssh {
+                host: "deploy@build01".to_string(),
+                path: PathBuf::from("/var/zub"),
+            }
+        );
+        assert_eq!(
+            RemoteUrl::parse("user@host:/remote/repo").unwrap(),
+            RemoteUrl::Ssh {
+                host: "user@host".to_string(),
+                path: PathBuf::from("/remote/repo"),
+            }
+        );
+        assert_eq!(
+            RemoteUrl::parse("./dir:with-colon").unwrap(),
+            RemoteUrl::Local(PathBuf::from("./dir:with-colon"))
+        );
+        assert_eq!(
+            RemoteUrl::parse("/mnt/backup/zub").unwrap(),
+            RemoteUrl::Local(PathBuf::from("/mnt/backup/zub"))
+        );
+        assert_eq!(
+            RemoteUrl::parse("../sibling").unwrap(),
+            RemoteUrl::Local(PathBuf::from("../sibling"))
+        );
+        assert_eq!(
+            RemoteUrl::parse("file:///srv/zub").unwrap(),
+            RemoteUrl::Local(PathBuf::from("/srv/zub"))
+        );
+
+        for bad in [
+            "",
+            "ssh://host",
+            "ssh:///path",
+            "ssh://user@/p",
+            "http://host/zub",
+            "host:",
+        ] {
+            assert!(
+                matches!(RemoteUrl::parse(bad), Err(Error::UnsupportedRemoteUrl(_))),
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn test_config_resolve_remote() {
+        let mut config = Config::default();
+        config.add_remote("origin", "ssh://server/var/zub").unwrap();
+        config.add_remote("backup", "/mnt/backup/zub").unwrap();
+
+        assert_eq!(
+            config.resolve_remote("origin").unwrap(),
+            RemoteUrl::Ssh {
+                host: "server".to_string(),
+                path: PathBuf::from("/var/zub"),
+            }
+        );
+        assert_eq!(
+            config.resolve_remote("backup").unwrap(),
+            RemoteUrl::Local(PathBuf::from("/mnt/backup/zub"))
+        );
+
+        // names that aren't configured are taken as urls
+        assert_eq!(
+            config.resolve_remote("other/repo").unwrap(),
+            RemoteUrl::Local(PathBuf::from("other/repo"))
+        );
+    }
+
     #[test]
     fn test_config_minimal_toml() {
         let toml_str = r#"
@@ -154,5 +369,6 @@ gid_map = []
         let config: Config = toml::from_str(toml_str).unwrap();
         assert!(config.namespace.uid_map.is_empty());
         assert!(config.remotes.is_empty());
+        assert_eq!(config.format_version, 0);
     }
 }
@@ -0,0 +1,154 @@
+//! warnings from operations that recover and carry on
+//!
+//! checkout skips what it lacks privileges for rather than failing, and
+//! commit leaves out what its policies allow it to. what was skipped goes to
+//! a `Diagnostics` sink, so a library consumer can collect or silence it;
+//! without one, warnings are printed to stderr.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// a problem an operation worked around
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Warning {
+    /// a block or char device node needs privileges we don't have
+    DeviceNodeSkipped {
+        path: PathBuf,
+        /// "block" or "char"
+        device_type: &'static str,
+    },
+    /// a socket needs privileges we don't have
+    SocketSkipped { path: PathBuf },
+    /// an xattr needs privileges we don't have (`security.*`, `trusted.*`)
+    XattrSkipped { path: PathBuf, name: String },
+    /// an xattr couldn't be set on a symlink
+    SymlinkXattrFailed {
+        path: PathBuf,
+        name: String,
+        message: String,
+    },
+    /// a repository inside the commit source was left out (`skip_repo`)
+    RepoSkipped { path: PathBuf },
+    /// the source filesystem has no xattr support; entries are committed
+    /// without xattrs
+    XattrsUnsupported { path: PathBuf },
+    /// a dangling symlink to dereference was left out; `path` is in the tree
+    DanglingSymlinkSkipped { path: String },
+    /// an unreadable file was left out; `path` is in the tree
+    UnreadableSkipped { path: String, message: String },
+    /// an unreadable file was committed empty; `path` is in the tree
+    UnreadablePlaceholder { path: String, message: String },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::DeviceNodeSkipped { path, device_type } => write!(
+                f,
+                "cannot create {} device {:?} without privileges, skipping",
+                device_type, path
+            ),
+            Warning::SocketSkipped { path } => write!(
+                f,
+                "cannot create socket {:?} without privileges, skipping",
+                path
+            ),
+            Warning::XattrSkipped { path, name } => write!(
+                f,
+                "cannot set {} on {:?} (requires privileges), skipping",
+                name, path
+            ),
+            Warning::SymlinkXattrFailed {
+                path,
+                name,
+                message,
+            } => write!(
+                f,
+                "failed to set xattr {} on symlink {:?}: {}",
+                name, path, message
+            ),
+            Warning::RepoSkipped { path } => write!(f, "skipping repository at {:?}", path),
+            Warning::XattrsUnsupported { path } => write!(
+                f,
+                "no xattr support at {}, committing without xattrs",
+                path.display()
+            ),
+            Warning::DanglingSymlinkSkipped { path } => {
+                write!(f, "skipping dangling symlink {}", path)
+            }
+            Warning::UnreadableSkipped { path, message } => {
+                write!(f, "skipping {}: {}", path, message)
+            }
+            Warning::UnreadablePlaceholder { path, message } => {
+                write!(f, "empty placeholder for {}: {}", path, message)
+            }
+        }
+    }
+}
+
+/// where warnings go
+pub trait Diagnostics: Send + Sync {
+    fn warn(&self, warning: Warning);
+}
+
+impl fmt::Debug for dyn Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Diagnostics")
+    }
+}
+
+/// prints each warning to stderr, the default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StderrDiagnostics;
+
+impl Diagnostics for StderrDiagnostics {
+    fn warn(&self, warning: Warning) {
+        eprintln!("warning: {}", warning);
+    }
+}
+
+/// keeps warnings for the caller to inspect
+#[derive(Debug, Default)]
+pub struct CollectDiagnostics {
+    warnings: Mutex<Vec<Warning>>,
+}
+
+impl CollectDiagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// warnings so far, in the order they were raised
+    pub fn warnings(&self) -> Vec<Warning> {
+        self.warnings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+impl Diagnostics for CollectDiagnostics {
+    fn warn(&self, warning: Warning) {
+        self.warnings
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(warning);
+    }
+}
+
+/// drops every warning
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IgnoreDiagnostics;
+
+impl Diagnostics for IgnoreDiagnostics {
+    fn warn(&self, _warning: Warning) {}
+}
+
+/// the sink set in an options struct, or stderr
+pub(crate) fn sink_or_stderr(sink: &Option<Arc<dyn Diagnostics>>) -> &dyn Diagnostics {
+    match sink {
+        Some(sink) => sink.as_ref(),
+        None => &StderrDiagnostics,
+    }
+}
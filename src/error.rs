@@ -17,12 +17,25 @@ pub enum Error {
     #[error("invalid ref name: {0}")]
     InvalidRef(String),
 
+    #[error("ref already exists: {0}")]
+    RefExists(String),
+
+    #[error("ref {ref_name} does not have the expected value")]
+    RefChanged {
+        ref_name: String,
+        expected: Option<Hash>,
+        actual: Option<Hash>,
+    },
+
     #[error("path not found in tree: {0}")]
     PathNotFound(String),
 
     #[error("object not found: {0}")]
     ObjectNotFound(Hash),
 
+    #[error("short hash {0} matches more than one object")]
+    AmbiguousHash(String),
+
     #[error("corrupt object: hash mismatch for {0}")]
     CorruptObject(Hash),
 
@@ -51,6 +64,9 @@ pub enum Error {
     #[error("failed to parse namespace mapping from {0}")]
     NamespaceParseError(PathBuf),
 
+    #[error("invalid id map: {0}")]
+    InvalidIdMap(String),
+
     #[error("remote not found: {0}")]
     RemoteNotFound(String),
 
@@ -97,20 +113,65 @@ pub enum Error {
     #[error("xattr error on {path}: {message}")]
     Xattr { path: PathBuf, message: String },
 
+    #[error("invalid POSIX ACL: {0}")]
+    InvalidAcl(String),
+
+    #[error("filesystem does not support xattrs at {0}")]
+    XattrUnsupported(PathBuf),
+
+    #[error("transport protocol version mismatch: local {local}, remote {remote}")]
+    ProtocolVersion { local: u32, remote: u32 },
+
     #[error("transport error: {message}")]
     Transport { message: String },
 
     #[error("invalid conflict resolution strategy: {0}")]
     InvalidConflictResolution(String),
 
+    #[error("invalid unreadable file policy: {0}")]
+    InvalidUnreadablePolicy(String),
+
     #[error("corrupt object: {0}")]
     CorruptObjectMessage(String),
 
+    #[error("corrupt pack index: {0}")]
+    CorruptPackIndex(PathBuf),
+
     #[error("invalid object type: {0}")]
     InvalidObjectType(String),
 
     #[error("metadata key not found: {0}")]
     MetadataKeyNotFound(String),
+
+    #[error("commit rejected: {0}")]
+    CommitRejected(String),
+
+    #[error("external command failed: {0}")]
+    ExternalCommand(String),
+
+    #[error("unsupported remote url: {0}")]
+    UnsupportedRemoteUrl(String),
+
+    #[error("commit source contains the repository at {0}")]
+    SourceContainsRepo(PathBuf),
+
+    #[error("too many levels of symbolic links at {0}")]
+    SymlinkLoop(PathBuf),
+
+    #[error("dangling symlink: {0}")]
+    DanglingSymlink(PathBuf),
+
+    #[error("invalid dereference mode: {0}")]
+    InvalidDerefMode(String),
+
+    #[error("invalid owner (expected UID:GID): {0}")]
+    InvalidOwner(String),
+
+    #[error("invalid device spec: {0}")]
+    InvalidDeviceSpec(String),
+
+    #[error("unsupported repository format version {0}")]
+    UnsupportedRepoVersion(u32),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
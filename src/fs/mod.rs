@@ -1,13 +1,17 @@
 pub mod hardlink;
 pub mod read;
 pub mod sparse;
+pub mod temp;
 pub mod write;
 
 pub use hardlink::{CheckoutHardlinkTracker, HardlinkTracker};
-pub use read::{read_symlink_target, read_xattrs, FileMetadata, FileType};
-pub use sparse::{detect_sparse_regions, read_data_regions, write_sparse_file};
+pub use read::{read_symlink_target, read_xattrs, read_xattrs_strict, FileMetadata, FileType};
+pub use sparse::{
+    detect_sparse_regions, read_data_regions, write_sparse_file, write_zero_filled_file,
+};
+pub use temp::TempFile;
 pub use write::{
-    apply_metadata, apply_metadata_graceful, create_block_device, create_char_device,
-    create_directory, create_fifo, create_hardlink, create_socket_placeholder, create_symlink,
-    fsync_dir, fsync_file,
+    apply_metadata, apply_metadata_graceful, apply_metadata_reporting, create_block_device,
+    create_char_device, create_directory, create_fifo, create_hardlink, create_socket_placeholder,
+    create_symlink, fsync_dir, fsync_file, set_times,
 };
@@ -5,7 +5,7 @@ use std::path::Path;
 use nix::libc;
 
 use crate::error::{Error, IoResultExt, Result};
-use crate::types::Xattr;
+use crate::types::{EntryTimes, Timespec, Xattr};
 
 /// file type enumeration
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -61,6 +61,10 @@ pub struct FileMetadata {
     pub dev: u64,
     /// number of hard links
     pub nlink: u64,
+    pub mtime: Timespec,
+    pub atime: Timespec,
+    /// birth time, when the platform and filesystem report it (statx on linux)
+    pub btime: Option<Timespec>,
 }
 
 impl FileMetadata {
@@ -93,6 +97,22 @@ impl FileMetadata {
             ino: meta.ino(),
             dev: meta.dev(),
             nlink: meta.nlink(),
+            mtime: Timespec::new(meta.mtime(), meta.mtime_nsec() as u32),
+            atime: Timespec::new(meta.atime(), meta.atime_nsec() as u32),
+            btime: meta
+                .created()
+                .ok()
+                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|d| Timespec::new(d.as_secs() as i64, d.subsec_nanos())),
+        }
+    }
+
+    /// times to record for this entry with `CommitOptions::capture_times`
+    pub fn times(&self) -> EntryTimes {
+        EntryTimes {
+            mtime: self.mtime,
+            atime: self.atime,
+            btime: self.btime,
         }
     }
 
@@ -103,18 +123,30 @@ impl FileMetadata {
 }
 
 /// read all extended attributes from a path
+///
+/// a filesystem without xattr support has none to report, so this returns an
+/// empty set there; `read_xattrs_strict` tells the two apart.
 pub fn read_xattrs(path: &Path) -> Result<Vec<Xattr>> {
+    match read_xattrs_strict(path) {
+        Err(Error::XattrUnsupported(_)) => Ok(vec![]),
+        result => result,
+    }
+}
+
+/// like `read_xattrs`, but fails with `XattrUnsupported` on a filesystem
+/// without xattr support (ENOTSUP), instead of reporting no xattrs
+pub fn read_xattrs_strict(path: &Path) -> Result<Vec<Xattr>> {
     let mut xattrs = Vec::new();
 
     // list xattr names
-    let names: Vec<String> = match xattr::list(path) {
+    let names: Vec<String> = match list_xattrs(path) {
         Ok(iter) => iter.map(|n| n.to_string_lossy().into_owned()).collect(),
         Err(e) => {
-            // ENOTSUP/ENODATA means no xattr support or no xattrs, not an error
-            if e.raw_os_error() == Some(libc::ENOTSUP)
-                || e.raw_os_error() == Some(libc::ENODATA)
-                || e.raw_os_error() == Some(libc::EOPNOTSUPP)
-            {
+            if xattrs_unsupported(&e) {
+                return Err(Error::XattrUnsupported(path.to_path_buf()));
+            }
+            // ENODATA means no xattrs, not an error
+            if e.raw_os_error() == Some(libc::ENODATA) {
                 return Ok(vec![]);
             }
             return Err(Error::Xattr {
@@ -151,6 +183,18 @@ pub fn read_xattrs(path: &Path) -> Result<Vec<Xattr>> {
     Ok(xattrs)
 }
 
+/// whether an xattr call failed because the filesystem doesn't support xattrs
+pub(crate) fn xattrs_unsupported(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOTSUP) || e.raw_os_error() == Some(libc::EOPNOTSUPP)
+}
+
+/// list xattr names; tests can make this fail as on an xattr-less filesystem
+fn list_xattrs(path: &Path) -> std::io::Result<xattr::XAttrs> {
+    #[cfg(test)]
+    tests::check_xattr_support(path)?;
+    xattr::list(path)
+}
+
 /// read symlink target
 pub fn read_symlink_target(path: &Path) -> Result<String> {
     let target = fs::read_link(path).with_path(path)?;
@@ -158,11 +202,31 @@ pub fn read_symlink_target(path: &Path) -> Result<String> {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
     use tempfile::tempdir;
 
+    /// roots under which xattr calls fail with ENOTSUP
+    static NO_XATTR_ROOTS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+    /// make xattr calls under `root` fail as on a filesystem without xattr
+    /// support, for the rest of the test run
+    pub(crate) fn without_xattr_support(root: &Path) {
+        NO_XATTR_ROOTS.lock().unwrap().push(root.to_path_buf());
+    }
+
+    /// the injected ENOTSUP for paths under a `without_xattr_support` root
+    pub(crate) fn check_xattr_support(path: &Path) -> std::io::Result<()> {
+        let roots = NO_XATTR_ROOTS.lock().unwrap_or_else(|e| e.into_inner());
+        if roots.iter().any(|root| path.starts_with(root)) {
+            return Err(std::io::Error::from_raw_os_error(libc::ENOTSUP));
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_file_type_regular() {
         let dir = tempdir().unwrap();
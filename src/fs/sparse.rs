@@ -152,6 +152,48 @@ pub fn write_sparse_file(
     Ok(())
 }
 
+/// write a sparse file's logical contents with holes written out as zeros
+pub fn write_zero_filled_file(
+    path: &Path,
+    data: &[u8],
+    regions: &[SparseRegion],
+    total_size: u64,
+) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o644)
+        .open(path)
+        .with_path(path)?;
+
+    let zeros = [0u8; 64 * 1024];
+    let write_zeros = |file: &mut File, mut len: u64| -> Result<()> {
+        while len > 0 {
+            let n = len.min(zeros.len() as u64) as usize;
+            file.write_all(&zeros[..n]).with_path(path)?;
+            len -= n as u64;
+        }
+        Ok(())
+    };
+
+    let mut pos = 0u64;
+    let mut data_offset = 0usize;
+    for region in regions {
+        write_zeros(&mut file, region.offset - pos)?;
+        let end = data_offset + region.length as usize;
+        file.write_all(&data[data_offset..end]).with_path(path)?;
+        data_offset = end;
+        pos = region.end();
+    }
+    write_zeros(&mut file, total_size.saturating_sub(pos))?;
+
+    file.sync_all().with_path(path)?;
+    Ok(())
+}
+
 /// check if sparse file support is available
 pub fn sparse_support_available() -> bool {
     // try to use SEEK_HOLE on /dev/null or similar
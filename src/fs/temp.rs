@@ -0,0 +1,73 @@
+//! self-removing temp files for atomic writes
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// a temp file path that is unlinked on drop unless persisted
+///
+/// atomic writes fill a temp file, then rename it into place. holding the
+/// path in a `TempFile` means an error anywhere before the rename (a failed
+/// chown, a full disk) can't leave the file behind.
+#[derive(Debug)]
+pub struct TempFile {
+    path: PathBuf,
+    armed: bool,
+}
+
+impl TempFile {
+    /// a fresh uuid-named path in `dir`; creating the file is up to the caller
+    pub fn new_in(dir: &Path) -> Self {
+        Self::at(dir.join(uuid::Uuid::new_v4().to_string()))
+    }
+
+    /// guard an explicit path
+    pub fn at(path: PathBuf) -> Self {
+        Self { path, armed: true }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// stop guarding the path, once the file has been renamed into place
+    pub fn into_persisted(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if self.armed {
+            // the file may never have been created
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_temp_file_removed_unless_persisted() {
+        let dir = tempdir().unwrap();
+
+        let tmp = TempFile::new_in(dir.path());
+        fs::write(tmp.path(), "data").unwrap();
+        let path = tmp.path().to_path_buf();
+        drop(tmp);
+        assert!(!path.exists());
+
+        let tmp = TempFile::new_in(dir.path());
+        fs::write(tmp.path(), "data").unwrap();
+        let dest = dir.path().join("dest");
+        fs::rename(tmp.path(), &dest).unwrap();
+        tmp.into_persisted();
+        assert_eq!(fs::read(&dest).unwrap(), b"data");
+
+        // never created: dropping is a no-op
+        drop(TempFile::new_in(dir.path()));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
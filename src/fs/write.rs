@@ -4,11 +4,14 @@ use std::os::unix::fs::{symlink, PermissionsExt};
 use std::path::Path;
 
 use nix::libc;
-use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use nix::sys::stat::{makedev, mknod, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::TimeSpec;
 use nix::unistd::{chown, Gid, Uid};
 
+use crate::diagnostics::{Diagnostics, Warning};
 use crate::error::{Error, IoResultExt, Result};
-use crate::types::Xattr;
+use crate::fs::read::xattrs_unsupported;
+use crate::types::{EntryTimes, Xattr};
 
 /// create a directory with specified metadata
 pub fn create_directory(
@@ -23,12 +26,15 @@ pub fn create_directory(
 }
 
 /// create a symlink
+///
+/// xattrs that can't be set are reported to `diag` and skipped.
 pub fn create_symlink(
     path: &Path,
     target: &str,
     uid: u32,
     gid: u32,
     xattrs: &[Xattr],
+    diag: &dyn Diagnostics,
 ) -> Result<()> {
     // remove existing if present
     if path.exists() || path.symlink_metadata().is_ok() {
@@ -60,10 +66,11 @@ pub fn create_symlink(
         // note: xattr crate's set follows symlinks by default, need to use fsetxattr
         // for now, skip xattrs on symlinks as most systems don't support them anyway
         if let Err(e) = set_xattr_no_follow(path, &xattr.name, &xattr.value) {
-            eprintln!(
-                "warning: failed to set xattr {} on symlink {:?}: {}",
-                xattr.name, path, e
-            );
+            diag.warn(Warning::SymlinkXattrFailed {
+                path: path.to_path_buf(),
+                name: xattr.name.clone(),
+                message: e.to_string(),
+            });
         }
     }
 
@@ -112,13 +119,15 @@ pub fn create_fifo(path: &Path, uid: u32, gid: u32, mode: u32, xattrs: &[Xattr])
 }
 
 /// create a unix socket placeholder
-/// note: we can't actually create a bound socket, just a placeholder
+/// note: we can't actually create a bound socket, just a placeholder.
+/// without privileges it is skipped and reported to `diag`
 pub fn create_socket_placeholder(
     path: &Path,
     uid: u32,
     gid: u32,
     mode: u32,
     xattrs: &[Xattr],
+    diag: &dyn Diagnostics,
 ) -> Result<()> {
     // sockets can't be created without binding, skip them during checkout
     // instead create an empty file as a marker
@@ -135,10 +144,9 @@ pub fn create_socket_placeholder(
         Ok(()) => apply_metadata(path, uid, gid, mode, xattrs),
         Err(nix::errno::Errno::EPERM) => {
             // no permission, skip socket creation
-            eprintln!(
-                "warning: cannot create socket {:?} without privileges, skipping",
-                path
-            );
+            diag.warn(Warning::SocketSkipped {
+                path: path.to_path_buf(),
+            });
             Ok(())
         }
         Err(e) => Err(Error::Io {
@@ -184,31 +192,70 @@ pub fn apply_metadata(path: &Path, uid: u32, gid: u32, mode: u32, xattrs: &[Xatt
     Ok(())
 }
 
+/// restore mtime and atime on a path, without following symlinks
+///
+/// btime can't be set and is left as is.
+pub fn set_times(path: &Path, times: &EntryTimes) -> Result<()> {
+    let spec = |t: crate::types::Timespec| TimeSpec::new(t.sec, t.nsec as _);
+    utimensat(
+        nix::fcntl::AT_FDCWD,
+        path,
+        &spec(times.atime),
+        &spec(times.mtime),
+        UtimensatFlags::NoFollowSymlink,
+    )
+    .map_err(|e| Error::Io {
+        path: path.to_path_buf(),
+        source: e.into(),
+    })
+}
+
 /// apply metadata with graceful xattr handling for privileged namespaces
 ///
 /// for `security.*` and `trusted.*` xattrs that fail with EPERM,
-/// prints a warning and continues. other xattrs and errors fail normally.
+/// reports a warning to `diag` and continues. xattrs the filesystem doesn't
+/// support (ENOTSUP) are skipped too. other xattrs and errors fail normally.
 pub fn apply_metadata_graceful(
     path: &Path,
     uid: u32,
     gid: u32,
     mode: u32,
     xattrs: &[Xattr],
+    diag: &dyn Diagnostics,
 ) -> Result<()> {
+    apply_metadata_reporting(path, uid, gid, mode, xattrs, diag).map(|_| ())
+}
+
+/// like `apply_metadata_graceful`, returning the xattrs that were skipped
+pub fn apply_metadata_reporting(
+    path: &Path,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    xattrs: &[Xattr],
+    diag: &dyn Diagnostics,
+) -> Result<Vec<Xattr>> {
+    let mut skipped = Vec::new();
+
     // set xattrs first (while we still have write permission)
     for xattr in xattrs {
-        if let Err(e) = xattr::set(path, &xattr.name, &xattr.value) {
+        if let Err(e) = set_xattr(path, &xattr.name, &xattr.value) {
             // check if this is a privileged namespace with permission error
             let is_privileged =
                 xattr.name.starts_with("security.") || xattr.name.starts_with("trusted.");
             let is_permission_error =
                 e.kind() == std::io::ErrorKind::PermissionDenied || e.raw_os_error() == Some(1);
 
-            if is_privileged && is_permission_error {
-                eprintln!(
-                    "warning: cannot set {} on {:?} (requires privileges), skipping",
-                    xattr.name, path
-                );
+            if xattrs_unsupported(&e) {
+                // e.g. tmpfs without xattrs: nothing can be set here, the
+                // caller reports what was dropped
+                skipped.push(xattr.clone());
+            } else if is_privileged && is_permission_error {
+                diag.warn(Warning::XattrSkipped {
+                    path: path.to_path_buf(),
+                    name: xattr.name.clone(),
+                });
+                skipped.push(xattr.clone());
             } else {
                 return Err(Error::Xattr {
                     path: path.to_path_buf(),
@@ -231,7 +278,7 @@ pub fn apply_metadata_graceful(
     // set mode last (might remove write permission)
     fs::set_permissions(path, Permissions::from_mode(mode & 0o7777)).with_path(path)?;
 
-    Ok(())
+    Ok(skipped)
 }
 
 /// helper to create device nodes
@@ -266,6 +313,13 @@ fn create_device_node(
     apply_metadata(path, uid, gid, mode, xattrs)
 }
 
+/// set an xattr; tests can make this fail as on an xattr-less filesystem
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+    #[cfg(test)]
+    super::read::tests::check_xattr_support(path)?;
+    xattr::set(path, name, value)
+}
+
 /// set xattr without following symlinks
 /// note: this is a best-effort implementation
 fn set_xattr_no_follow(path: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
@@ -293,6 +347,7 @@ pub fn fsync_dir(path: &Path) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::diagnostics::IgnoreDiagnostics;
     use std::os::unix::fs::MetadataExt;
     use tempfile::tempdir;
 
@@ -322,7 +377,7 @@ mod tests {
         let path = dir.path().join("link");
         let (uid, gid) = current_ids();
 
-        create_symlink(&path, "/target/path", uid, gid, &[]).unwrap();
+        create_symlink(&path, "/target/path", uid, gid, &[], &IgnoreDiagnostics).unwrap();
 
         assert!(path.symlink_metadata().unwrap().file_type().is_symlink());
         let target = fs::read_link(&path).unwrap();
@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::io;
 
 use crate::types::Xattr;
 use crate::Error;
@@ -19,12 +20,17 @@ impl Hash {
 
     /// parse from hex string
     pub fn from_hex(s: &str) -> crate::Result<Self> {
-        let bytes = hex::decode(s).map_err(|_| Error::InvalidHashHex(s.to_string()))?;
-        if bytes.len() != 32 {
-            return Err(Error::InvalidHashHex(s.to_string()));
-        }
+        Self::from_hex_bytes(s.as_bytes())
+    }
+
+    /// parse from hex digits in a byte slice, decoding in place
+    ///
+    /// nothing is allocated unless the input is invalid, for parsing object
+    /// lists straight out of a read buffer.
+    pub fn from_hex_bytes(s: &[u8]) -> crate::Result<Self> {
         let mut arr = [0u8; 32];
-        arr.copy_from_slice(&bytes);
+        hex::decode_to_slice(s, &mut arr)
+            .map_err(|_| Error::InvalidHashHex(String::from_utf8_lossy(s).into_owned()))?;
         Ok(Self(arr))
     }
 
@@ -38,6 +44,18 @@ impl Hash {
         hex::encode(self.0)
     }
 
+    /// write the hex form to `writer` without allocating
+    pub fn write_hex(&self, writer: &mut impl io::Write) -> io::Result<()> {
+        writer.write_all(&self.hex_digits())
+    }
+
+    /// hex digits in a stack buffer
+    fn hex_digits(&self) -> [u8; 64] {
+        let mut buf = [0u8; 64];
+        hex::encode_to_slice(self.0, &mut buf).expect("64 bytes hold 32 in hex");
+        buf
+    }
+
     /// split into path components for object store
     /// returns (first 2 hex chars, remaining 62 hex chars)
     pub fn to_path_components(&self) -> (String, String) {
@@ -48,13 +66,17 @@ impl Hash {
 
 impl fmt::Display for Hash {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.to_hex())
+        let digits = self.hex_digits();
+        // hex digits are always ascii
+        f.pad(std::str::from_utf8(&digits).map_err(|_| fmt::Error)?)
     }
 }
 
 impl fmt::Debug for Hash {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Hash({})", &self.to_hex()[..12])
+        let digits = self.hex_digits();
+        let short = std::str::from_utf8(&digits[..12]).map_err(|_| fmt::Error)?;
+        write!(f, "Hash({})", short)
     }
 }
 
@@ -127,6 +149,14 @@ pub fn compute_blob_hash(
     Hash(*hasher.finalize().as_bytes())
 }
 
+/// compute the content-only hash used by content-addressed blob storage
+///
+/// unlike `compute_blob_hash`, no metadata is folded in, so identical bytes
+/// always map to the same content object.
+pub fn compute_content_hash(content: &[u8]) -> Hash {
+    Hash(*blake3::hash(content).as_bytes())
+}
+
 /// compute hash for symlink (target is the "content")
 /// always uses SYMLINK_MODE for determinism
 pub fn compute_symlink_hash(
@@ -151,7 +181,6 @@ pub fn compute_compressed_hash(compressed: &[u8]) -> Hash {
 }
 
 /// streaming blob hasher for large files
-#[allow(dead_code)]
 pub struct BlobHasher {
     hasher: blake3::Hasher,
 }
@@ -214,6 +243,27 @@ mod tests {
         .is_err()); // too long
     }
 
+    #[test]
+    fn test_hash_hex_bytes_agree_with_hex() {
+        let hex = "abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789";
+        let hash = Hash::from_hex(hex).unwrap();
+        assert_eq!(Hash::from_hex_bytes(hex.as_bytes()).unwrap(), hash);
+        assert_eq!(
+            Hash::from_hex_bytes(hex.to_uppercase().as_bytes()).unwrap(),
+            hash
+        );
+
+        for bad in [&b"abcd"[..], b"not valid hex", &[b'a'; 66], &[0xff; 64]] {
+            assert!(Hash::from_hex_bytes(bad).is_err());
+        }
+
+        let mut written = Vec::new();
+        hash.write_hex(&mut written).unwrap();
+        assert_eq!(written, hash.to_hex().as_bytes());
+        assert_eq!(hash.to_string(), hash.to_hex());
+        assert_eq!(format!("{:?}", hash), "Hash(abcdef012345)");
+    }
+
     #[test]
     fn test_hash_path_components() {
         let h = Hash::from_hex("abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789")
@@ -33,6 +33,7 @@
 //! ```
 
 mod config;
+mod diagnostics;
 mod error;
 mod hash;
 mod namespace;
@@ -45,25 +46,35 @@ pub mod ops;
 pub mod transport;
 pub mod types;
 
-pub use config::Config;
+pub use config::{Config, Remote, RemoteUrl, DEFAULT_MAX_OBJECT_SIZE, FORMAT_VERSION};
+pub use diagnostics::{
+    CollectDiagnostics, Diagnostics, IgnoreDiagnostics, StderrDiagnostics, Warning,
+};
 pub use error::{Error, Result};
-pub use hash::{compute_blob_hash, Hash};
+pub use hash::{compute_blob_hash, compute_content_hash, Hash};
 pub use namespace::{
     current_gid_map, current_uid_map, inside_to_outside, mappings_equal, outside_to_inside,
-    parse_id_map, remap, MapEntry, NsConfig,
+    parse_id_map, remap, remap_acl, remap_acl_xattrs, MapEntry, NsConfig, ACL_XATTRS,
 };
 pub use object::{
-    artifact_exists, artifact_path, blob_exists, commit_path, read_artifact, read_blob,
-    read_commit, read_tree, tree_path, write_artifact, write_blob, write_commit, write_tree,
+    artifact_exists, artifact_path, blob_exists, blob_file_hash, blob_logical_size, blob_meta_path,
+    blob_stored_size, commit_path, content_exists, list_packed, list_packed_sizes, packed_exists,
+    packed_size, read_artifact, read_blob, read_blob_data, read_commit, read_commit_header,
+    read_content, read_packed, read_tree, rehash_blob_with_xattrs, tree_path, write_artifact,
+    write_blob, write_blob_outcome, write_commit, write_commit_outcome, write_pack,
+    write_regular_blob, write_regular_blob_outcome, write_tree, write_tree_outcome, CacheStats,
+    PackedKind, WriteOutcome,
 };
 pub use ops::{du, du_tree, stats, truncate_history, PathSize, RefSize, RepoStats, TruncateStats};
 pub use refs::{
     artifact_ref_exists, delete_artifact_ref, delete_artifact_refs_matching, delete_ref,
-    delete_refs_matching, list_artifact_refs, list_artifact_refs_matching, list_refs,
-    list_refs_matching, read_artifact_ref, read_ref, ref_exists, resolve_ref, write_artifact_ref,
-    write_ref,
+    delete_refs_matching, list_artifact_refs, list_artifact_refs_matching, list_reflogs, list_refs,
+    list_refs_in, list_refs_matching, list_refs_tree, pack_refs, prune_dangling_refs,
+    read_artifact_ref, read_ref, read_ref_in, read_reflog, recover_ref, ref_exists, resolve_ref,
+    write_artifact_ref, write_ref, write_ref_in, RefTransaction, RefTree, ReflogEntry, HEADS, TAGS,
 };
-pub use repo::Repo;
+pub use repo::{InitOptions, Repo};
 pub use types::{
-    Artifact, ChangeKind, Commit, DiffEntry, EntryKind, SparseRegion, Tree, TreeEntry, Xattr,
+    Artifact, ChangeKind, Commit, CommitHeader, DiffEntry, EntryKind, EntryNamePolicy, EntryTimes,
+    SparseRegion, Timespec, Tree, TreeEntry, Xattr,
 };
@@ -2,25 +2,40 @@
 
 use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::Arc;
 
 use clap::{Parser, Subcommand};
 
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
 
 use zub::ops::{
-    checkout, commit, diff, fsck, gc, log, ls_tree, ls_tree_recursive, map, union_checkout,
-    union_trees, CheckoutOptions, ConflictResolution, LsTreeOptions, MapOptions,
-    UnionCheckoutOptions, UnionOptions,
+    checkout, commit_with_options, diff_ref_worktree, diff_with_options, export_squashfs,
+    format_total, fsck, fsck_ref, gc_with_options, log_range_with_options, log_with_options,
+    ls_tree, ls_tree_recursive, maintenance, map, read_scrub_cursor, scrub, sync, total_size,
+    union_checkout, union_trees, verify_checkout, verify_ref, CheckoutDiscrepancy, CheckoutOptions,
+    CheckoutStats, CommitOptions, ConflictResolution, DanglingSymlinkPolicy, DedupReport,
+    DerefMode, DiffOptions, FsckReport, GcOptions, GcStats, LogEntry, LogOptions, LsTreeEntry,
+    LsTreeOptions, MaintenanceOptions, MaintenanceReport, Manifest, MapOptions, MapStats,
+    ScrubOptions, ScrubReport, SquashfsOptions, SyncOptions, SyncStats, UnionCheckoutOptions,
+    UnionCheckoutResult, UnionOptions, UnreadablePolicy, UnsupportedXattrPolicy,
+};
+use zub::transport::{
+    pull_into_with_progress, pull_local_with_progress, pull_refs, pull_refs_matching,
+    pull_refs_matching_ssh, pull_refs_ssh, pull_ssh_into_with_progress, pull_ssh_with_progress,
+    push_local_with_progress, push_ssh_with_progress, tracking_ref_name, PullOptions,
+    PullRefsResult, PullResult, PushOptions, PushResult, TransferProgress, TransferStats,
+};
+use zub::{
+    read_blob, read_commit, read_tree, Commit, Diagnostics, DiffEntry, Hash, PathSize, RefSize,
+    Remote, RemoteUrl, Repo, RepoStats, StderrDiagnostics, TruncateStats,
 };
-use zub::transport::{pull_local, push_local, PullOptions, PushOptions};
-use zub::{read_blob, read_commit, read_tree, Hash, Repo};
 
 #[derive(Parser)]
 #[command(name = "zub")]
 #[command(about = "git-like object tree - content-addressed filesystem store")]
 #[command(version)]
 struct Cli {
-    /// repository path (default: ZUB_REPO env, .zub symlink/dir, or current directory)
+    /// repository path (default: ZUB_REPO env, .zub symlink/dir, or nearest enclosing repo)
     #[arg(short, long, env = "ZUB_REPO")]
     repo: Option<PathBuf>,
 
@@ -28,7 +43,8 @@ struct Cli {
     command: Commands,
 }
 
-/// resolve the repository path from CLI arg, .zub symlink, or .zub directory
+/// resolve the repository path from CLI arg, .zub symlink, .zub directory, or
+/// the nearest ancestor that is a repository
 fn resolve_repo_path(repo_arg: Option<PathBuf>) -> PathBuf {
     if let Some(path) = repo_arg {
         return path;
@@ -48,8 +64,9 @@ fn resolve_repo_path(repo_arg: Option<PathBuf>) -> PathBuf {
         return zub_path.to_path_buf();
     }
 
-    // default to current directory
-    PathBuf::from(".")
+    // search upwards, falling back to the current directory so the error
+    // (or `init`) refers to it
+    Repo::discover_path(Path::new(".")).unwrap_or_else(|_| PathBuf::from("."))
 }
 
 #[derive(Subcommand)]
@@ -59,6 +76,24 @@ enum Commands {
         /// path to create repository at
         #[arg(default_value = ".")]
         path: PathBuf,
+
+        /// keep the object store here instead of PATH/objects
+        #[arg(long)]
+        objects_dir: Option<PathBuf>,
+
+        /// compress trees and commits with this zstd dictionary (fixed for the repo's lifetime)
+        #[arg(long)]
+        dictionary: Option<PathBuf>,
+
+        /// uid range "INSIDE OUTSIDE COUNT" to pin instead of the current
+        /// process's uid_map (repeatable)
+        #[arg(long, value_name = "RANGE")]
+        uid_map: Vec<String>,
+
+        /// gid range "INSIDE OUTSIDE COUNT" to pin instead of the current
+        /// process's gid_map (repeatable)
+        #[arg(long, value_name = "RANGE")]
+        gid_map: Vec<String>,
     },
 
     /// commit a directory to a ref
@@ -77,6 +112,54 @@ enum Commands {
         /// author name
         #[arg(short, long)]
         author: Option<String>,
+
+        /// leave the repository out if it lies inside the source
+        #[arg(long)]
+        skip_repo: bool,
+
+        /// place the source under this path in the tree (e.g. usr/lib/foo)
+        #[arg(long)]
+        prefix: Option<String>,
+
+        /// don't descend into other filesystems; mount points become empty dirs
+        #[arg(short = 'x', long)]
+        one_file_system: bool,
+
+        /// unreadable files: error, skip, placeholder (empty file)
+        #[arg(long, default_value = "error")]
+        on_unreadable: String,
+
+        /// commit entries without xattrs if the source filesystem doesn't
+        /// support them, instead of failing (changes hashes)
+        #[arg(long)]
+        ignore_unsupported_xattrs: bool,
+
+        /// record mtime/atime (and btime where available); checkout restores them
+        #[arg(long)]
+        capture_times: bool,
+
+        /// symlinks to commit as their target: never, external (leaving
+        /// the source), always
+        #[arg(long, default_value = "never")]
+        dereference: String,
+
+        /// leave out dangling symlinks --dereference would follow, instead
+        /// of failing
+        #[arg(long)]
+        skip_dangling_symlinks: bool,
+
+        /// record every entry as owned by UID:GID (inside ids), e.g. 0:0
+        #[arg(long)]
+        owner: Option<String>,
+
+        /// translate uids/gids named in POSIX ACLs to inside ids
+        #[arg(long)]
+        remap_acls: bool,
+
+        /// add device nodes and fifos listed in FILE, one
+        /// "path type major minor mode uid gid" per line (type b, c or p)
+        #[arg(long, value_name = "FILE")]
+        device_spec: Option<PathBuf>,
     },
 
     /// checkout a ref to a directory
@@ -98,16 +181,101 @@ enum Commands {
         /// preserve sparse file holes
         #[arg(long)]
         sparse: bool,
+
+        /// prepend this path to absolute symlink targets
+        #[arg(long)]
+        symlink_prefix: Option<PathBuf>,
+
+        /// leave entries that already match untouched (allows a non-empty destination)
+        #[arg(long)]
+        skip_unchanged: bool,
+
+        /// write privileged xattrs that couldn't be set to this file, for
+        /// `setfattr --restore` as root in the destination
+        #[arg(long)]
+        xattr_sidecar: Option<PathBuf>,
+
+        /// add to what the destination already holds, merging directories
+        #[arg(long)]
+        overlay: bool,
+
+        /// conflict resolution for --overlay: error, first, last
+        #[arg(long, default_value = "error", requires = "overlay")]
+        on_conflict: String,
+
+        /// track progress in the destination; rerun with --resume after an
+        /// interruption to skip what was already checked out
+        #[arg(long)]
+        resume: bool,
+
+        /// translate uids/gids named in POSIX ACLs back to outside ids
+        #[arg(long)]
+        remap_acls: bool,
+    },
+
+    /// report metadata a checkout couldn't apply (ownership, device nodes, xattrs)
+    VerifyCheckout {
+        /// ref the checkout was made from
+        ref_name: String,
+
+        /// checkout directory
+        destination: PathBuf,
+    },
+
+    /// update an existing checkout in place, rewriting only changed files
+    Sync {
+        /// ref to sync to
+        ref_name: String,
+
+        /// destination directory
+        destination: PathBuf,
+
+        /// remove files not present in the ref
+        #[arg(long)]
+        delete: bool,
+
+        /// use copy instead of hardlinks
+        #[arg(long)]
+        copy: bool,
+
+        /// preserve sparse file holes
+        #[arg(long)]
+        sparse: bool,
+    },
+
+    /// write a ref's tree to a squashfs image (needs mksquashfs 4.6+)
+    ExportSquashfs {
+        /// ref to export
+        ref_name: String,
+
+        /// output image path
+        output: PathBuf,
+
+        /// compressor (gzip, xz, zstd, ...)
+        #[arg(long)]
+        comp: Option<String>,
     },
 
     /// show commit log for a ref
     Log {
-        /// ref to show log for
+        /// ref to show log for, or A..B for commits in B but not in A
         ref_name: String,
 
         /// maximum number of commits to show
         #[arg(short = 'n', long)]
         max_count: Option<usize>,
+
+        /// follow only the first parent of each commit
+        #[arg(long)]
+        first_parent: bool,
+
+        /// don't walk further than this many commits from the head
+        #[arg(long)]
+        depth: Option<usize>,
+
+        /// stop at commits older than this unix timestamp
+        #[arg(long)]
+        since: Option<i64>,
     },
 
     /// list tree contents
@@ -130,6 +298,10 @@ enum Commands {
         /// human-readable sizes (with -l)
         #[arg(short = 'H', long)]
         human: bool,
+
+        /// tag each entry with a rough file type (elf, script, text, gzip, ...)
+        #[arg(long)]
+        classify: bool,
     },
 
     /// show differences between two refs
@@ -139,6 +311,31 @@ enum Commands {
 
         /// second ref
         ref2: String,
+
+        /// print a JSON array of {path, kind} objects
+        #[arg(long)]
+        json: bool,
+
+        /// report content changes only, ignoring owner, mode, xattrs and times
+        #[arg(long)]
+        ignore_metadata: bool,
+
+        /// ignore permission bit changes
+        #[arg(long)]
+        ignore_mode: bool,
+
+        /// ignore uid/gid changes
+        #[arg(long)]
+        ignore_owner: bool,
+    },
+
+    /// show how a directory differs from a ref
+    Status {
+        /// ref to compare against
+        ref_name: String,
+
+        /// directory to compare
+        dir: PathBuf,
     },
 
     /// merge multiple refs into one
@@ -181,21 +378,140 @@ enum Commands {
         /// use copy instead of hardlinks
         #[arg(long)]
         copy: bool,
+
+        /// print which ref each path came from
+        #[arg(long)]
+        origins: bool,
     },
 
     /// verify repository integrity
-    Fsck,
+    Fsck {
+        /// only check objects reachable from this ref
+        #[arg(long = "ref")]
+        ref_name: Option<String>,
+    },
+
+    /// list every object reachable from a ref with a fingerprint of the list
+    Manifest {
+        /// ref to list
+        ref_name: String,
+
+        /// write the manifest as CBOR to stdout instead of text
+        #[arg(long)]
+        cbor: bool,
+    },
+
+    /// verify everything reachable from a ref, including blob hashes
+    Verify {
+        /// ref to verify
+        ref_name: String,
+    },
+
+    /// incrementally verify object hashes at a bounded read rate
+    Scrub {
+        /// maximum bytes read per second
+        #[arg(long)]
+        rate: Option<u64>,
+
+        /// stop after this many objects (resume with --resume)
+        #[arg(long)]
+        max_objects: Option<usize>,
+
+        /// continue from where the last unfinished scrub stopped
+        #[arg(long)]
+        resume: bool,
+    },
 
     /// garbage collect unreachable objects
     Gc {
         /// only show what would be removed
         #[arg(long)]
         dry_run: bool,
+
+        /// recent reflog values of each ref to keep, including the current tip
+        #[arg(long, default_value = "2")]
+        keep_reflog: usize,
+
+        /// keep a deleted ref's reflog, and what its last values reach, for
+        /// this many seconds after the deletion (30 days by default)
+        #[arg(long, value_name = "SECS", default_value = "2592000")]
+        keep_deleted_refs_for: u64,
+
+        /// keep unreachable commits whose timestamp is less than this many
+        /// seconds old, with everything they reference
+        #[arg(long, value_name = "SECS")]
+        keep_unreachable_younger_than: Option<u64>,
+
+        /// don't remove unreachable blobs and content objects
+        #[arg(long)]
+        keep_blobs: bool,
+
+        /// don't remove unreachable trees (nor the blobs they reference)
+        #[arg(long)]
+        keep_trees: bool,
+
+        /// don't remove unreachable commits (nor anything they reference)
+        #[arg(long)]
+        keep_commits: bool,
+
+        /// pack kept trees and commits not read or written for this many seconds
+        #[arg(long, value_name = "SECS")]
+        expire_loose_after: Option<u64>,
+
+        /// delete refs whose tip commit is older than this unix timestamp and
+        /// collect what only they reached
+        #[arg(long, value_name = "TS")]
+        since: Option<i64>,
+
+        /// delete refs whose tip commit is newer than this unix timestamp and
+        /// collect what only they reached
+        #[arg(long, value_name = "TS")]
+        until: Option<i64>,
+
+        /// list the N largest unreachable blobs
+        #[arg(long, value_name = "N", requires = "dry_run")]
+        top: Option<usize>,
+    },
+
+    /// prune dangling refs, gc and clear stale temp files in one locked pass
+    Maintenance {
+        /// only show what would be removed
+        #[arg(long)]
+        dry_run: bool,
+
+        /// recent reflog values of each ref to keep, including the current tip
+        #[arg(long, default_value = "2")]
+        keep_reflog: usize,
+
+        /// keep a deleted ref's reflog, and what its last values reach, for
+        /// this many seconds after the deletion (30 days by default)
+        #[arg(long, value_name = "SECS", default_value = "2592000")]
+        keep_deleted_refs_for: u64,
+
+        /// seconds a temp file must be untouched before it's removed
+        #[arg(long, default_value = "3600")]
+        tmp_grace: u64,
     },
 
     /// show repository statistics
     Stats,
 
+    /// upgrade the repository to the current format version
+    Migrate,
+
+    /// train a zstd dictionary from this repo's trees and commits, for `init --dictionary`
+    TrainDictionary {
+        /// file to write the dictionary to
+        output: PathBuf,
+    },
+
+    /// report blobs that share content but differ in metadata
+    Dedup {
+        /// number of duplicate groups to show (default: 10)
+        #[arg(short = 'n', long, default_value = "10")]
+        limit: usize,
+    },
+
     /// show disk usage per ref (or within a ref with --depth)
     Du {
         /// ref name or glob pattern to filter refs
@@ -230,8 +546,8 @@ enum Commands {
 
     /// push a ref to another repository
     Push {
-        /// destination repository path
-        destination: PathBuf,
+        /// destination: remote name, path, [user@]host:path or ssh:// url
+        destination: String,
 
         /// ref to push
         ref_name: String,
@@ -243,27 +559,100 @@ enum Commands {
         /// dry run - show what would be transferred without doing it
         #[arg(long)]
         dry_run: bool,
+
+        /// limit transfer to this many bytes per second
+        #[arg(long, value_name = "BYTES_PER_SEC")]
+        rate_limit: Option<u64>,
+
+        /// retries for I/O failing with a transient error
+        #[arg(long, default_value = "3")]
+        retries: u32,
+
+        /// between local repos with different uid/gid mappings, chown copied
+        /// blobs to the destination's owner for the same inside ids
+        #[arg(long)]
+        remap_ownership: bool,
+
+        /// with --remap-ownership, keep owners that can't be mapped
+        #[arg(long, requires = "remap_ownership")]
+        keep_unmapped_owners: bool,
     },
 
     /// pull a ref from another repository
     Pull {
-        /// source repository path
-        source: PathBuf,
+        /// source: remote name, path, [user@]host:path or ssh:// url
+        source: String,
 
-        /// ref to pull
+        /// ref to pull (a glob pattern with --refs-only or --mirror)
         ref_name: String,
 
         /// only fetch objects, don't update ref
         #[arg(long)]
         fetch_only: bool,
 
+        /// only update matching refs, fetching no objects
+        #[arg(long, conflicts_with = "fetch_only")]
+        refs_only: bool,
+
+        /// with --refs-only, update refs whose commit isn't present locally
+        #[arg(long, requires = "refs_only")]
+        allow_missing: bool,
+
+        /// pull every ref matching the pattern and delete local refs
+        /// matching it that the source no longer has
+        #[arg(long, conflicts_with = "fetch_only")]
+        mirror: bool,
+
+        /// dry run - show what would be transferred without doing it
+        #[arg(long)]
+        dry_run: bool,
+
+        /// limit transfer to this many bytes per second
+        #[arg(long, value_name = "BYTES_PER_SEC")]
+        rate_limit: Option<u64>,
+
+        /// retries for I/O failing with a transient error
+        #[arg(long, default_value = "3")]
+        retries: u32,
+
+        /// between local repos with different uid/gid mappings, chown copied
+        /// blobs to the destination's owner for the same inside ids
+        #[arg(long)]
+        remap_ownership: bool,
+
+        /// with --remap-ownership, keep owners that can't be mapped
+        #[arg(long, requires = "remap_ownership")]
+        keep_unmapped_owners: bool,
+    },
+
+    /// fetch a ref from a configured remote into remotes/<remote>/<ref>,
+    /// leaving the local ref alone
+    Fetch {
+        /// configured remote name
+        remote: String,
+
+        /// ref to fetch
+        ref_name: String,
+
         /// dry run - show what would be transferred without doing it
         #[arg(long)]
         dry_run: bool,
+
+        /// limit transfer to this many bytes per second
+        #[arg(long, value_name = "BYTES_PER_SEC")]
+        rate_limit: Option<u64>,
+
+        /// retries for I/O failing with a transient error
+        #[arg(long, default_value = "3")]
+        retries: u32,
     },
 
     /// list refs
-    Refs,
+    Refs {
+        /// show refs as a hierarchy of their path components
+        #[arg(long)]
+        tree: bool,
+    },
 
     /// show ref hash
     ShowRef {
@@ -277,10 +666,34 @@ enum Commands {
         ref_name: String,
     },
 
+    /// recreate a deleted ref at its last reflog value
+    RecoverRef {
+        /// ref name
+        ref_name: String,
+    },
+
     /// delete refs matching a glob pattern
     DeleteRefs {
         /// glob pattern (e.g. "x86_64/pkg/*/neovim/*")
         pattern: String,
+
+        /// only show what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+
+        /// allow deleting more than 20 refs at once
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// fold loose refs into the packed refs file
+    PackRefs,
+
+    /// delete refs whose target commit no longer exists
+    PruneRefs {
+        /// only show what would be removed
+        #[arg(long)]
+        dry_run: bool,
     },
 
     /// delete artifact refs matching a glob pattern
@@ -296,18 +709,29 @@ enum Commands {
     ///   zub cat-file myref:path/to/dir      # list directory
     ///   zub cat-file myref                  # show commit info
     ///   zub cat-file -t blob HASH           # raw hash access
+    ///   zub cat-file -p myref:path/to/dir   # directory with full entry metadata
+    ///   zub cat-file --logical myref:disk.img  # sparse file with holes as zeros
     CatFile {
         /// object spec: ref:path, ref, or hash (with -t)
         spec: String,
 
-        /// object type for raw hash access (blob, tree, commit)
+        /// object type for raw hash access (blob, content, tree, commit)
         #[arg(short = 't', long = "type")]
         object_type: Option<String>,
+
+        /// show every entry field for trees, and metadata for commits
+        #[arg(short = 'p', long)]
+        pretty: bool,
+
+        /// expand a sparse file's holes to zeros instead of printing only
+        /// its stored data regions (needs a ref:path spec)
+        #[arg(long, conflicts_with = "object_type")]
+        logical: bool,
     },
 
     /// resolve a ref to a hash
     RevParse {
-        /// ref or hash to resolve
+        /// ref, hash or short hash to resolve
         rev: String,
 
         /// output short hash (first 12 chars)
@@ -325,18 +749,49 @@ enum Commands {
         metadata_key: Option<String>,
     },
 
+    /// manage configured remotes
+    Remote {
+        #[command(subcommand)]
+        action: RemoteCommands,
+    },
+
     /// remote helper (used by SSH transport)
     #[command(name = "zub-remote")]
-    Remote {
+    RemoteHelper {
         /// repository path
         path: PathBuf,
     },
 }
 
+#[derive(Subcommand)]
+enum RemoteCommands {
+    /// add a remote
+    Add {
+        /// remote name
+        name: String,
+
+        /// path, [user@]host:path or ssh://[user@]host/path
+        url: String,
+    },
+
+    /// remove a remote
+    Remove {
+        /// remote name
+        name: String,
+    },
+
+    /// list remotes
+    List,
+}
+
+/// `delete-refs` refuses to remove more refs than this without --force
+const DELETE_REFS_FORCE_THRESHOLD: usize = 20;
+
 fn main() -> ExitCode {
     let cli = Cli::parse();
 
-    if let Err(e) = run(cli) {
+    let result = run(cli).and_then(|outcome| outcome.render(&mut io::stdout().lock()));
+    if let Err(e) = result {
         eprintln!("error: {}", e);
         ExitCode::FAILURE
     } else {
@@ -344,99 +799,1012 @@ fn main() -> ExitCode {
     }
 }
 
-fn run(cli: Cli) -> zub::Result<()> {
-    let repo_path = resolve_repo_path(cli.repo);
-
-    match cli.command {
-        Commands::Init { path } => {
-            Repo::init(&path)?;
-            println!("initialized zub repository at {}", path.display());
-        }
-
-        Commands::Commit {
-            source,
-            ref_name,
-            message,
-            author,
-        } => {
-            let repo = Repo::open(&repo_path)?;
-            let hash = commit(
-                &repo,
-                &source,
-                &ref_name,
-                message.as_deref(),
-                author.as_deref(),
-            )?;
-            println!("{}", hash);
-        }
-
-        Commands::Checkout {
-            ref_name,
-            destination,
-            force,
-            copy,
-            sparse,
-        } => {
-            let repo = Repo::open(&repo_path)?;
-            let options = CheckoutOptions {
-                force,
-                hardlink: !copy,
-                preserve_sparse: sparse,
-            };
-            checkout(&repo, &ref_name, &destination, options)?;
-            println!("checked out {} to {}", ref_name, destination.display());
-        }
-
-        Commands::Log {
-            ref_name,
-            max_count,
-        } => {
-            let repo = Repo::open(&repo_path)?;
-            let entries = log(&repo, &ref_name, max_count)?;
-
-            for entry in entries {
-                println!("{}", entry);
-            }
-        }
-
-        Commands::LsTree {
-            ref_name,
-            path,
-            recursive,
-            long,
-            human,
-        } => {
-            let repo = Repo::open(&repo_path)?;
-            let opts = LsTreeOptions { long, human };
-
-            let entries = if recursive {
-                ls_tree_recursive(&repo, &ref_name, &opts)?
-            } else {
-                ls_tree(&repo, &ref_name, path.as_deref(), &opts)?
-            };
-
-            for entry in entries {
-                println!("{}", entry.format(&opts));
-            }
-        }
-
-        Commands::Diff { ref1, ref2 } => {
-            let repo = Repo::open(&repo_path)?;
-            let changes = diff(&repo, &ref1, &ref2)?;
-
-            for change in changes {
-                let prefix = match change.kind {
-                    zub::ChangeKind::Added => "+",
-                    zub::ChangeKind::Deleted => "-",
-                    zub::ChangeKind::Modified => "M",
-                    zub::ChangeKind::MetadataOnly => "m",
-                };
-                println!("{} {}", prefix, change.path);
-            }
-        }
-
-        Commands::Union {
-            refs,
+/// typed result of a command, rendered to text by `main`
+///
+/// `run` does the work and returns one of these without printing, so the
+/// dispatch can be driven from another tool that wants the values rather
+/// than the human output.
+enum CommandOutcome {
+    /// nothing to report (remote add/remove, remote-helper)
+    Done,
+    Init {
+        path: PathBuf,
+    },
+    /// a single object hash (commit, union, show-ref)
+    Hash(Hash),
+    Checkout {
+        ref_name: String,
+        destination: PathBuf,
+        stats: CheckoutStats,
+        /// report written/skipped counts (--skip-unchanged, --overlay, --resume)
+        counts: bool,
+    },
+    VerifyCheckout {
+        ref_name: String,
+        destination: PathBuf,
+        discrepancies: Vec<CheckoutDiscrepancy>,
+    },
+    Sync {
+        ref_name: String,
+        destination: PathBuf,
+        stats: SyncStats,
+    },
+    ExportSquashfs {
+        ref_name: String,
+        output: PathBuf,
+    },
+    Log(Vec<LogEntry>),
+    LsTree {
+        entries: Vec<LsTreeEntry>,
+        opts: LsTreeOptions,
+    },
+    /// changes from diff or status
+    Diff(Vec<DiffEntry>),
+    /// changes from diff --json
+    DiffJson(Vec<DiffEntry>),
+    UnionCheckout {
+        ref_count: usize,
+        destination: PathBuf,
+        result: UnionCheckoutResult,
+    },
+    /// fsck or verify report; rendering fails if it found problems
+    Fsck {
+        report: FsckReport,
+        subject: String,
+    },
+    Manifest(Manifest),
+    /// raw bytes for stdout (blob data, cbor manifest)
+    Bytes(Vec<u8>),
+    /// rendering fails if corrupt objects were found
+    Scrub(ScrubReport),
+    Gc {
+        stats: GcStats,
+        dry_run: bool,
+    },
+    Maintenance {
+        report: MaintenanceReport,
+        dry_run: bool,
+    },
+    Stats(RepoStats),
+    Migrate {
+        from: u32,
+    },
+    TrainDictionary {
+        output: PathBuf,
+        size: usize,
+    },
+    Dedup {
+        report: DedupReport,
+        limit: usize,
+    },
+    Du {
+        sizes: Vec<RefSize>,
+        limit: usize,
+    },
+    DuTree {
+        sizes: Vec<PathSize>,
+        limit: usize,
+    },
+    TruncateHistory {
+        stats: TruncateStats,
+        dry_run: bool,
+    },
+    Remap {
+        stats: MapStats,
+        dry_run: bool,
+    },
+    Push {
+        result: PushResult,
+        destination: String,
+        dry_run: bool,
+    },
+    Pull {
+        result: PullResult,
+        source: String,
+        dry_run: bool,
+    },
+    Fetch {
+        result: PullResult,
+        tracking_ref: String,
+        dry_run: bool,
+    },
+    /// refs updated by pull --refs-only
+    PullRefs {
+        refs: Vec<(String, Hash)>,
+        dry_run: bool,
+    },
+    /// refs updated and pruned by pull --mirror
+    PullMirror {
+        result: PullRefsResult,
+        source: String,
+        dry_run: bool,
+    },
+    /// (ref name, commit hash) pairs
+    Refs(Vec<(String, Hash)>),
+    /// refs by path component, from refs --tree
+    RefTree(zub::RefTree),
+    DeleteRef(String),
+    RecoverRef {
+        ref_name: String,
+        hash: Hash,
+    },
+    DeleteRefs {
+        pattern: String,
+        deleted: Vec<String>,
+        dry_run: bool,
+    },
+    PackRefs(usize),
+    PruneRefs {
+        pruned: Vec<String>,
+        dry_run: bool,
+    },
+    DeleteArtifacts {
+        pattern: String,
+        deleted: Vec<String>,
+    },
+    /// directory listing from cat-file
+    Tree(zub::Tree),
+    /// directory table from cat-file --pretty
+    PrettyTree(Vec<String>),
+    /// commit header and message from cat-file, plus metadata with --pretty
+    CatCommit {
+        commit: Commit,
+        pretty: bool,
+    },
+    /// symlink target from cat-file
+    Symlink(String),
+    /// hardlink target from cat-file
+    Hardlink(String),
+    /// blob streamed from the object store by cat-file -t blob
+    StreamBlob {
+        repo: Repo,
+        hash: Hash,
+    },
+    /// regular file streamed by cat-file ref:path
+    StreamFile {
+        repo: Repo,
+        kind: zub::EntryKind,
+        logical: bool,
+    },
+    /// type of an entry cat-file has no contents for
+    EntryType(&'static str),
+    RevParse {
+        hash: Hash,
+        short: bool,
+    },
+    Show {
+        hash: Hash,
+        commit: Commit,
+    },
+    /// a single metadata value from show --metadata
+    MetadataValue(String),
+    Remotes(Vec<Remote>),
+}
+
+impl CommandOutcome {
+    /// write the human output, then fail if the outcome reports problems
+    fn render(&self, out: &mut dyn Write) -> zub::Result<()> {
+        self.write_to(out).map_err(|e| zub::Error::Io {
+            path: "stdout".into(),
+            source: e,
+        })?;
+        self.status()
+    }
+
+    /// error for outcomes that are failures once reported
+    fn status(&self) -> zub::Result<()> {
+        match self {
+            CommandOutcome::Fsck { report, subject } if !report.is_ok() => Err(
+                zub::Error::CorruptObjectMessage(format!("{} integrity check failed", subject)),
+            ),
+            CommandOutcome::Scrub(report) if !report.corrupt_objects.is_empty() => Err(
+                zub::Error::CorruptObjectMessage("scrub found corrupt objects".to_string()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        match self {
+            CommandOutcome::Done => {}
+
+            CommandOutcome::Init { path } => {
+                writeln!(out, "initialized zub repository at {}", path.display())?;
+            }
+
+            CommandOutcome::Hash(hash) => writeln!(out, "{}", hash)?,
+
+            CommandOutcome::Checkout {
+                ref_name,
+                destination,
+                stats,
+                counts,
+            } => {
+                if *counts {
+                    writeln!(
+                        out,
+                        "checked out {} to {}: {} written, {} skipped",
+                        ref_name,
+                        destination.display(),
+                        stats.written,
+                        stats.skipped
+                    )?;
+                } else {
+                    writeln!(out, "checked out {} to {}", ref_name, destination.display())?;
+                }
+            }
+
+            CommandOutcome::VerifyCheckout {
+                ref_name,
+                destination,
+                discrepancies,
+            } => {
+                for discrepancy in discrepancies {
+                    writeln!(out, "{}: {}", discrepancy.path, discrepancy.kind)?;
+                }
+                if discrepancies.is_empty() {
+                    writeln!(out, "{} matches {}", destination.display(), ref_name)?;
+                }
+            }
+
+            CommandOutcome::Sync {
+                ref_name,
+                destination,
+                stats,
+            } => {
+                writeln!(
+                    out,
+                    "synced {} to {}: {} created, {} updated, {} unchanged, {} deleted",
+                    ref_name,
+                    destination.display(),
+                    stats.created,
+                    stats.updated,
+                    stats.unchanged,
+                    stats.deleted
+                )?;
+            }
+
+            CommandOutcome::ExportSquashfs { ref_name, output } => {
+                writeln!(out, "exported {} to {}", ref_name, output.display())?;
+            }
+
+            CommandOutcome::Log(entries) => {
+                for entry in entries {
+                    writeln!(out, "{}", entry)?;
+                }
+            }
+
+            CommandOutcome::LsTree { entries, opts } => {
+                for entry in entries {
+                    writeln!(out, "{}", entry.format(opts))?;
+                }
+                if opts.long {
+                    writeln!(out, "{}", format_total(total_size(entries), opts.human))?;
+                }
+            }
+
+            CommandOutcome::Diff(changes) => {
+                for change in changes {
+                    let prefix = match change.kind {
+                        zub::ChangeKind::Added => "+",
+                        zub::ChangeKind::Deleted => "-",
+                        zub::ChangeKind::Modified => "M",
+                        zub::ChangeKind::MetadataOnly => "m",
+                    };
+                    writeln!(out, "{} {}", prefix, change.path)?;
+                }
+            }
+
+            CommandOutcome::DiffJson(changes) => {
+                serde_json::to_writer(&mut *out, changes)?;
+                writeln!(out)?;
+            }
+
+            CommandOutcome::UnionCheckout {
+                ref_count,
+                destination,
+                result,
+            } => {
+                for (path, ref_name) in &result.origins {
+                    writeln!(out, "{}\t{}", path, ref_name)?;
+                }
+                writeln!(
+                    out,
+                    "checked out union of {} refs to {}",
+                    ref_count,
+                    destination.display()
+                )?;
+            }
+
+            CommandOutcome::Fsck { report, subject } => write_fsck_report(out, report, subject)?,
+
+            CommandOutcome::Manifest(manifest) => {
+                for entry in &manifest.entries {
+                    writeln!(out, "{} {} {}", entry.object_type, entry.hash, entry.size)?;
+                }
+                writeln!(out, "manifest {}", manifest.manifest_hash())?;
+            }
+
+            CommandOutcome::Bytes(data) => out.write_all(data)?,
+
+            CommandOutcome::StreamBlob { repo, hash } => {
+                zub::ops::cat_blob(repo, hash, &mut &mut *out).map_err(io::Error::other)?;
+            }
+
+            CommandOutcome::StreamFile {
+                repo,
+                kind,
+                logical,
+            } => {
+                zub::ops::cat_file(repo, kind, *logical, &mut &mut *out)
+                    .map_err(io::Error::other)?;
+            }
+
+            CommandOutcome::Scrub(report) => {
+                writeln!(
+                    out,
+                    "objects checked: {} ({} bytes)",
+                    report.objects_checked, report.bytes_read
+                )?;
+
+                if !report.corrupt_objects.is_empty() {
+                    writeln!(out, "\ncorrupt objects:")?;
+                    for obj in &report.corrupt_objects {
+                        writeln!(out, "  {} {}: {}", obj.object_type, obj.hash, obj.message)?;
+                    }
+                }
+
+                match &report.cursor {
+                    Some(cursor) => writeln!(out, "stopped at {}, continue with --resume", cursor)?,
+                    None => writeln!(out, "scrub complete")?,
+                }
+            }
+
+            CommandOutcome::Gc { stats, dry_run } => {
+                let action = if *dry_run { "would remove" } else { "removed" };
+                for ref_name in &stats.refs_expired {
+                    writeln!(out, "{} expired ref {}", action, ref_name)?;
+                }
+                for ref_name in &stats.reflogs_expired {
+                    writeln!(out, "{} reflog of deleted ref {}", action, ref_name)?;
+                }
+                writeln!(
+                    out,
+                    "{} {} blobs, {} trees, {} commits",
+                    action, stats.blobs_removed, stats.trees_removed, stats.commits_removed
+                )?;
+                if stats.contents_removed > 0 {
+                    writeln!(out, "{} {} content objects", action, stats.contents_removed)?;
+                }
+                if stats.trees_packed + stats.commits_packed > 0 {
+                    let action = if *dry_run { "would pack" } else { "packed" };
+                    writeln!(
+                        out,
+                        "{} {} trees, {} commits",
+                        action, stats.trees_packed, stats.commits_packed
+                    )?;
+                }
+                writeln!(out, "freed {} bytes", stats.bytes_freed)?;
+                if !stats.top_unreachable.is_empty() {
+                    writeln!(out, "largest unreachable blobs:")?;
+                    for (hash, size) in &stats.top_unreachable {
+                        writeln!(out, "  {:>12}  {}", size, hash)?;
+                    }
+                }
+            }
+
+            CommandOutcome::Maintenance { report, dry_run } => {
+                let action = if *dry_run { "would remove" } else { "removed" };
+                for ref_name in &report.pruned_refs {
+                    writeln!(out, "{} dangling ref {}", action, ref_name)?;
+                }
+                let gc = &report.gc;
+                writeln!(
+                    out,
+                    "{} {} blobs, {} trees, {} commits, {} content objects",
+                    action,
+                    gc.blobs_removed,
+                    gc.trees_removed,
+                    gc.commits_removed,
+                    gc.contents_removed
+                )?;
+                writeln!(out, "{} {} temp files", action, report.tmp_removed)?;
+                writeln!(
+                    out,
+                    "freed {} bytes",
+                    gc.bytes_freed + report.tmp_bytes_freed
+                )?;
+            }
+
+            CommandOutcome::Migrate { from } => {
+                if *from == zub::FORMAT_VERSION {
+                    writeln!(out, "already at format version {}", from)?;
+                } else {
+                    writeln!(
+                        out,
+                        "migrated from format version {} to {}",
+                        from,
+                        zub::FORMAT_VERSION
+                    )?;
+                }
+            }
+
+            CommandOutcome::TrainDictionary { output, size } => {
+                writeln!(
+                    out,
+                    "wrote {}-byte dictionary to {}",
+                    size,
+                    output.display()
+                )?;
+            }
+
+            CommandOutcome::Stats(s) => {
+                writeln!(out, "refs: {}", s.total_refs)?;
+                writeln!(out)?;
+                writeln!(out, "objects:")?;
+                writeln!(
+                    out,
+                    "  blobs:   {:>8} total, {:>8} reachable ({:.1} MB, {:.1} MB on disk)",
+                    s.total_blobs,
+                    s.reachable_blobs,
+                    s.total_blobs_bytes as f64 / 1_000_000.0,
+                    s.total_blobs_stored_bytes as f64 / 1_000_000.0
+                )?;
+                writeln!(
+                    out,
+                    "  trees:   {:>8} total, {:>8} reachable ({:.1} MB on disk)",
+                    s.total_trees,
+                    s.reachable_trees,
+                    s.total_trees_bytes as f64 / 1_000_000.0
+                )?;
+                writeln!(
+                    out,
+                    "  commits: {:>8} total, {:>8} reachable ({:.1} MB on disk)",
+                    s.total_commits,
+                    s.reachable_commits,
+                    s.total_commits_bytes as f64 / 1_000_000.0
+                )?;
+                writeln!(out)?;
+                if s.unreachable_blobs_bytes > 0 {
+                    writeln!(
+                        out,
+                        "unreachable blob data: {:.1} MB (run gc to free)",
+                        s.unreachable_blobs_bytes as f64 / 1_000_000.0
+                    )?;
+                }
+            }
+
+            CommandOutcome::Dedup { report, limit } => {
+                writeln!(
+                    out,
+                    "{} blobs, {} distinct contents ({:.1} MB)",
+                    report.blobs_scanned,
+                    report.unique_contents,
+                    report.total_bytes as f64 / 1_000_000.0
+                )?;
+                writeln!(
+                    out,
+                    "{} contents stored more than once, {:.1} MB reclaimable with content-only storage",
+                    report.duplicates.len(),
+                    report.reclaimable_bytes as f64 / 1_000_000.0
+                )?;
+                for dup in report.duplicates.iter().take(*limit) {
+                    writeln!(
+                        out,
+                        "  {} {:>10} bytes x{}",
+                        dup.content_hash,
+                        dup.size,
+                        dup.blobs.len()
+                    )?;
+                }
+            }
+
+            CommandOutcome::Du { sizes, limit } => {
+                for entry in sizes.iter().take(*limit) {
+                    let mb = entry.bytes as f64 / 1_000_000.0;
+                    writeln!(out, "{:>10.1} MB  {}", mb, entry.ref_name)?;
+                }
+
+                if sizes.len() > *limit {
+                    writeln!(out, "... and {} more refs", sizes.len() - limit)?;
+                }
+            }
+
+            CommandOutcome::DuTree { sizes, limit } => {
+                for entry in sizes.iter().take(*limit) {
+                    let mb = entry.bytes as f64 / 1_000_000.0;
+                    writeln!(out, "{:>10.1} MB  {}", mb, entry.path)?;
+                }
+
+                if sizes.len() > *limit {
+                    writeln!(out, "... and {} more paths", sizes.len() - limit)?;
+                }
+            }
+
+            CommandOutcome::TruncateHistory { stats, dry_run } => {
+                let action = if *dry_run {
+                    "would truncate"
+                } else {
+                    "truncated"
+                };
+                writeln!(
+                    out,
+                    "{} {}/{} refs",
+                    action, stats.refs_truncated, stats.refs_processed
+                )?;
+                if !dry_run && stats.refs_truncated > 0 {
+                    writeln!(out, "run gc to free unreachable objects")?;
+                }
+            }
+
+            CommandOutcome::Remap { stats, dry_run } => {
+                if stats.total == 0 && stats.remapped == 0 {
+                    writeln!(out, "namespace mappings match, nothing to do")?;
+                } else {
+                    let action = if *dry_run { "would remap" } else { "remapped" };
+                    writeln!(
+                        out,
+                        "{} {} of {} blobs",
+                        action, stats.remapped, stats.total
+                    )?;
+                    if stats.skipped_unmapped_source > 0 {
+                        writeln!(
+                            out,
+                            "skipped {} blobs (uid/gid not in source namespace)",
+                            stats.skipped_unmapped_source
+                        )?;
+                    }
+                    if stats.skipped_unmapped_target > 0 {
+                        writeln!(
+                            out,
+                            "skipped {} blobs (uid/gid not mappable to current namespace)",
+                            stats.skipped_unmapped_target
+                        )?;
+                    }
+                }
+            }
+
+            CommandOutcome::Push {
+                result,
+                destination,
+                dry_run,
+            } => {
+                if *dry_run {
+                    writeln!(out, "would push {} to {}", result.hash, destination)?;
+                    writeln!(out, "would transfer {} objects", result.objects_to_transfer)?;
+                } else {
+                    writeln!(out, "pushed {} to {}", result.hash, destination)?;
+                    write_transfer_stats(out, &result.stats)?;
+                }
+            }
+
+            CommandOutcome::Pull {
+                result,
+                source,
+                dry_run,
+            } => {
+                if *dry_run {
+                    writeln!(out, "would pull {} from {}", result.hash, source)?;
+                    writeln!(out, "would transfer {} objects", result.objects_to_transfer)?;
+                } else {
+                    writeln!(out, "pulled {} from {}", result.hash, source)?;
+                    write_transfer_stats(out, &result.stats)?;
+                }
+            }
+
+            CommandOutcome::Fetch {
+                result,
+                tracking_ref,
+                dry_run,
+            } => {
+                if *dry_run {
+                    writeln!(out, "would fetch {} into {}", result.hash, tracking_ref)?;
+                    writeln!(out, "would transfer {} objects", result.objects_to_transfer)?;
+                } else {
+                    writeln!(out, "fetched {} into {}", result.hash, tracking_ref)?;
+                    write_transfer_stats(out, &result.stats)?;
+                }
+            }
+
+            CommandOutcome::PullRefs { refs, dry_run } => {
+                let verb = if *dry_run { "would update" } else { "updated" };
+                for (ref_name, hash) in refs {
+                    writeln!(out, "{} {} -> {}", verb, ref_name, hash)?;
+                }
+            }
+
+            CommandOutcome::PullMirror {
+                result,
+                source,
+                dry_run,
+            } => {
+                let (verb, action) = if *dry_run {
+                    ("would update", "would delete")
+                } else {
+                    ("updated", "deleted")
+                };
+                for (ref_name, hash) in &result.updated {
+                    writeln!(out, "{} {} -> {}", verb, ref_name, hash)?;
+                }
+                for ref_name in &result.deleted {
+                    writeln!(out, "{} ref {}", action, ref_name)?;
+                }
+                if *dry_run {
+                    writeln!(out, "would transfer {} objects", result.objects_to_transfer)?;
+                } else {
+                    writeln!(
+                        out,
+                        "mirrored {} refs from {}",
+                        result.updated.len(),
+                        source
+                    )?;
+                    write_transfer_stats(out, &result.stats)?;
+                }
+            }
+
+            CommandOutcome::Refs(refs) => {
+                for (ref_name, hash) in refs {
+                    writeln!(out, "{} {}", hash, ref_name)?;
+                }
+            }
+
+            CommandOutcome::RefTree(tree) => {
+                for line in tree.format() {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+
+            CommandOutcome::DeleteRef(ref_name) => writeln!(out, "deleted ref {}", ref_name)?,
+
+            CommandOutcome::RecoverRef { ref_name, hash } => {
+                writeln!(out, "recovered ref {} at {}", ref_name, hash)?;
+            }
+
+            CommandOutcome::DeleteRefs {
+                pattern,
+                deleted,
+                dry_run,
+            } => {
+                if deleted.is_empty() {
+                    writeln!(out, "no refs matched pattern {}", pattern)?;
+                }
+                let action = if *dry_run { "would delete" } else { "deleted" };
+                for r in deleted {
+                    writeln!(out, "{} ref {}", action, r)?;
+                }
+            }
+
+            CommandOutcome::PackRefs(packed) => writeln!(out, "packed {} refs", packed)?,
+
+            CommandOutcome::PruneRefs { pruned, dry_run } => {
+                let action = if *dry_run { "would delete" } else { "deleted" };
+                if pruned.is_empty() {
+                    writeln!(out, "no dangling refs")?;
+                }
+                for r in pruned {
+                    writeln!(out, "{} ref {}", action, r)?;
+                }
+            }
+
+            CommandOutcome::DeleteArtifacts { pattern, deleted } => {
+                if deleted.is_empty() {
+                    writeln!(out, "no artifact refs matched pattern {}", pattern)?;
+                }
+                for r in deleted {
+                    writeln!(out, "deleted artifact ref {}", r)?;
+                }
+            }
+
+            CommandOutcome::Tree(tree) => {
+                for entry in tree.entries() {
+                    writeln!(out, "{} {}", entry.kind.type_name(), entry.name)?;
+                }
+            }
+
+            CommandOutcome::PrettyTree(lines) => {
+                for line in lines {
+                    writeln!(out, "{}", line)?;
+                }
+            }
+
+            CommandOutcome::CatCommit { commit, pretty } => {
+                write_commit_header(out, commit)?;
+                if *pretty {
+                    write_commit_metadata(out, commit)?;
+                }
+                writeln!(out)?;
+                writeln!(out, "{}", commit.message)?;
+            }
+
+            CommandOutcome::Symlink(target) => writeln!(out, "{}", target)?,
+
+            CommandOutcome::Hardlink(target_path) => writeln!(out, "-> {}", target_path)?,
+
+            CommandOutcome::EntryType(type_name) => writeln!(out, "{}", type_name)?,
+
+            CommandOutcome::RevParse { hash, short } => {
+                if *short {
+                    writeln!(out, "{}", &hash.to_hex()[..12])?;
+                } else {
+                    writeln!(out, "{}", hash)?;
+                }
+            }
+
+            CommandOutcome::Show { hash, commit } => {
+                writeln!(out, "commit {}", hash)?;
+                write_commit_header(out, commit)?;
+                write_commit_metadata(out, commit)?;
+                writeln!(out)?;
+                writeln!(out, "{}", commit.message)?;
+            }
+
+            CommandOutcome::MetadataValue(value) => writeln!(out, "{}", value)?,
+
+            CommandOutcome::Remotes(remotes) => {
+                for remote in remotes {
+                    writeln!(out, "{}\t{}", remote.name, remote.url)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// run a parsed command line and return its outcome without printing
+fn run(cli: Cli) -> zub::Result<CommandOutcome> {
+    let repo_path = resolve_repo_path(cli.repo);
+
+    let outcome = match cli.command {
+        Commands::Init {
+            path,
+            objects_dir,
+            dictionary,
+            uid_map,
+            gid_map,
+        } => {
+            let namespace = if !uid_map.is_empty() || !gid_map.is_empty() {
+                // a map left out is taken from the current process
+                let uid_map = if uid_map.is_empty() {
+                    zub::current_uid_map()?
+                } else {
+                    parse_map_arg(&uid_map)?
+                };
+                let gid_map = if gid_map.is_empty() {
+                    zub::current_gid_map()?
+                } else {
+                    parse_map_arg(&gid_map)?
+                };
+                Some(zub::NsConfig { uid_map, gid_map })
+            } else {
+                None
+            };
+            let dictionary = match dictionary {
+                Some(dictionary) => {
+                    Some(std::fs::read(&dictionary).map_err(|e| zub::Error::Io {
+                        path: dictionary.clone(),
+                        source: e,
+                    })?)
+                }
+                None => None,
+            };
+            let opts = zub::InitOptions {
+                namespace,
+                objects_dir,
+                dictionary,
+            };
+            Repo::init_with_options(&path, &opts)?;
+            CommandOutcome::Init { path }
+        }
+
+        Commands::Commit {
+            source,
+            ref_name,
+            message,
+            author,
+            skip_repo,
+            prefix,
+            one_file_system,
+            on_unreadable,
+            ignore_unsupported_xattrs,
+            capture_times,
+            dereference,
+            skip_dangling_symlinks,
+            owner,
+            remap_acls,
+            device_spec,
+        } => {
+            let repo = Repo::open(&repo_path)?;
+            let options = CommitOptions {
+                message,
+                author,
+                skip_repo,
+                mount_prefix: prefix,
+                one_filesystem: one_file_system,
+                on_unreadable: parse_unreadable_policy(&on_unreadable)?,
+                on_unsupported_xattrs: if ignore_unsupported_xattrs {
+                    UnsupportedXattrPolicy::Ignore
+                } else {
+                    UnsupportedXattrPolicy::Error
+                },
+                capture_times,
+                dereference: parse_deref_mode(&dereference)?,
+                on_dangling_symlink: if skip_dangling_symlinks {
+                    DanglingSymlinkPolicy::Skip
+                } else {
+                    DanglingSymlinkPolicy::Error
+                },
+                owner_override: owner.as_deref().map(parse_owner).transpose()?,
+                remap_acls,
+                device_spec,
+                diagnostics: Some(stderr_diagnostics()),
+                ..Default::default()
+            };
+            CommandOutcome::Hash(commit_with_options(&repo, &source, &ref_name, &options)?)
+        }
+
+        Commands::Checkout {
+            ref_name,
+            destination,
+            force,
+            copy,
+            sparse,
+            symlink_prefix,
+            skip_unchanged,
+            xattr_sidecar,
+            overlay,
+            on_conflict,
+            resume,
+            remap_acls,
+        } => {
+            let repo = Repo::open(&repo_path)?;
+            let options = CheckoutOptions {
+                force,
+                hardlink: !copy,
+                preserve_sparse: sparse,
+                symlink_prefix,
+                skip_if_matches: skip_unchanged,
+                xattr_sidecar,
+                overlay,
+                on_conflict: parse_conflict_resolution(&on_conflict)?,
+                diagnostics: Some(stderr_diagnostics()),
+                resume,
+                remap_acls,
+            };
+            let stats = checkout(&repo, &ref_name, &destination, options)?;
+            CommandOutcome::Checkout {
+                ref_name,
+                destination,
+                stats,
+                counts: skip_unchanged || overlay || resume,
+            }
+        }
+
+        Commands::VerifyCheckout {
+            ref_name,
+            destination,
+        } => {
+            let repo = Repo::open(&repo_path)?;
+            let discrepancies = verify_checkout(&repo, &ref_name, &destination)?;
+            CommandOutcome::VerifyCheckout {
+                ref_name,
+                destination,
+                discrepancies,
+            }
+        }
+
+        Commands::Sync {
+            ref_name,
+            destination,
+            delete,
+            copy,
+            sparse,
+        } => {
+            let repo = Repo::open(&repo_path)?;
+            let options = SyncOptions {
+                delete,
+                hardlink: !copy,
+                preserve_sparse: sparse,
+                diagnostics: Some(stderr_diagnostics()),
+            };
+            let stats = sync(&repo, &ref_name, &destination, options)?;
+            CommandOutcome::Sync {
+                ref_name,
+                destination,
+                stats,
+            }
+        }
+
+        Commands::ExportSquashfs {
+            ref_name,
+            output,
+            comp,
+        } => {
+            let repo = Repo::open(&repo_path)?;
+            let opts = SquashfsOptions {
+                compressor: comp,
+                ..Default::default()
+            };
+            export_squashfs(&repo, &ref_name, &output, opts)?;
+            CommandOutcome::ExportSquashfs { ref_name, output }
+        }
+
+        Commands::Log {
+            ref_name,
+            max_count,
+            first_parent,
+            depth,
+            since,
+        } => {
+            let repo = Repo::open(&repo_path)?;
+            let opts = LogOptions {
+                max_count,
+                first_parent_only: first_parent,
+                depth,
+                since,
+            };
+            let entries = match ref_name.split_once("..") {
+                Some((from, to)) => {
+                    let from = if from.is_empty() { None } else { Some(from) };
+                    log_range_with_options(&repo, from, to, false, &opts)?
+                }
+                None => log_with_options(&repo, &ref_name, &opts)?,
+            };
+            CommandOutcome::Log(entries)
+        }
+
+        Commands::LsTree {
+            ref_name,
+            path,
+            recursive,
+            long,
+            human,
+            classify,
+        } => {
+            let repo = Repo::open(&repo_path)?;
+            let opts = LsTreeOptions {
+                long,
+                human,
+                classify,
+            };
+
+            let entries = if recursive {
+                ls_tree_recursive(&repo, &ref_name, &opts)?
+            } else {
+                ls_tree(&repo, &ref_name, path.as_deref(), &opts)?
+            };
+            CommandOutcome::LsTree { entries, opts }
+        }
+
+        Commands::Diff {
+            ref1,
+            ref2,
+            json,
+            ignore_metadata,
+            ignore_mode,
+            ignore_owner,
+        } => {
+            let repo = Repo::open(&repo_path)?;
+            let opts = DiffOptions {
+                ignore_metadata,
+                ignore_mode,
+                ignore_owner,
+            };
+            let changes = diff_with_options(&repo, &ref1, &ref2, &opts)?;
+            if json {
+                CommandOutcome::DiffJson(changes)
+            } else {
+                CommandOutcome::Diff(changes)
+            }
+        }
+
+        Commands::Status { ref_name, dir } => {
+            let repo = Repo::open(&repo_path)?;
+            CommandOutcome::Diff(diff_ref_worktree(&repo, &ref_name, &dir)?)
+        }
+
+        Commands::Union {
+            refs,
             output,
             on_conflict,
             message,
@@ -450,8 +1818,7 @@ fn run(cli: Cli) -> zub::Result<()> {
                 author: None,
                 on_conflict: resolution,
             };
-            let hash = union_trees(&repo, &ref_strs, &output, opts)?;
-            println!("{}", hash);
+            CommandOutcome::Hash(union_trees(&repo, &ref_strs, &output, opts)?)
         }
 
         Commands::UnionCheckout {
@@ -460,6 +1827,7 @@ fn run(cli: Cli) -> zub::Result<()> {
             force,
             on_conflict,
             copy,
+            origins,
         } => {
             let repo = Repo::open(&repo_path)?;
             let resolution = parse_conflict_resolution(&on_conflict)?;
@@ -469,98 +1837,146 @@ fn run(cli: Cli) -> zub::Result<()> {
                 force,
                 on_conflict: resolution,
                 hardlink: !copy,
+                record_origins: origins,
+                diagnostics: Some(stderr_diagnostics()),
             };
-            union_checkout(&repo, &ref_strs, &destination, options)?;
-            println!(
-                "checked out union of {} refs to {}",
-                refs.len(),
-                destination.display()
-            );
+            let result = union_checkout(&repo, &ref_strs, &destination, options)?;
+            CommandOutcome::UnionCheckout {
+                ref_count: refs.len(),
+                destination,
+                result,
+            }
         }
 
-        Commands::Fsck => {
+        Commands::Fsck { ref_name } => {
             let repo = Repo::open(&repo_path)?;
-            let report = fsck(&repo)?;
-
-            println!("objects checked: {}", report.objects_checked);
-
-            if !report.corrupt_objects.is_empty() {
-                println!("\ncorrupt objects:");
-                for obj in &report.corrupt_objects {
-                    println!("  {} {}: {}", obj.object_type, obj.hash, obj.message);
-                }
+            let report = match &ref_name {
+                Some(ref_name) => fsck_ref(&repo, ref_name)?,
+                None => fsck(&repo)?,
+            };
+            CommandOutcome::Fsck {
+                report,
+                subject: "repository".to_string(),
             }
+        }
 
-            if !report.missing_objects.is_empty() {
-                println!("\nmissing objects:");
-                for obj in &report.missing_objects {
-                    println!(
-                        "  {} {} (referenced by {})",
-                        obj.object_type, obj.hash, obj.referenced_by
-                    );
-                }
+        Commands::Manifest { ref_name, cbor } => {
+            let repo = Repo::open(&repo_path)?;
+            let manifest = zub::ops::manifest(&repo, &ref_name)?;
+            if cbor {
+                let mut data = Vec::new();
+                ciborium::into_writer(&manifest, &mut data)?;
+                CommandOutcome::Bytes(data)
+            } else {
+                CommandOutcome::Manifest(manifest)
             }
+        }
 
-            if !report.dangling_objects.is_empty() {
-                println!("\ndangling objects: {}", report.dangling_objects.len());
+        Commands::Verify { ref_name } => {
+            let repo = Repo::open(&repo_path)?;
+            let report = verify_ref(&repo, &ref_name)?;
+            CommandOutcome::Fsck {
+                report,
+                subject: ref_name,
             }
+        }
 
-            if report.is_ok() {
-                println!("\nrepository is healthy");
-            } else {
-                println!("\nrepository has issues");
-                return Err(zub::Error::CorruptObjectMessage(
-                    "repository integrity check failed".to_string(),
-                ));
-            }
+        Commands::Scrub {
+            rate,
+            max_objects,
+            resume,
+        } => {
+            let repo = Repo::open(&repo_path)?;
+            let opts = ScrubOptions {
+                bytes_per_sec: rate,
+                resume_from: if resume {
+                    read_scrub_cursor(&repo)?
+                } else {
+                    None
+                },
+                max_objects,
+                progress: None,
+            };
+            CommandOutcome::Scrub(scrub(&repo, &opts)?)
         }
 
-        Commands::Gc { dry_run } => {
+        Commands::Gc {
+            dry_run,
+            keep_reflog,
+            keep_deleted_refs_for,
+            keep_unreachable_younger_than,
+            keep_blobs,
+            keep_trees,
+            keep_commits,
+            expire_loose_after,
+            since,
+            until,
+            top,
+        } => {
             let repo = Repo::open(&repo_path)?;
-            let stats = gc(&repo, dry_run)?;
+            let opts = GcOptions {
+                dry_run,
+                keep_reflog_generations: keep_reflog,
+                keep_deleted_refs_for: Some(std::time::Duration::from_secs(keep_deleted_refs_for)),
+                keep_unreachable_commits_younger_than: keep_unreachable_younger_than
+                    .map(std::time::Duration::from_secs),
+                collect_blobs: !keep_blobs,
+                collect_trees: !keep_trees,
+                collect_commits: !keep_commits,
+                expire_loose_after: expire_loose_after.map(std::time::Duration::from_secs),
+                keep_refs_since: since,
+                keep_refs_until: until,
+                top_unreachable: top.unwrap_or(0),
+            };
+            let stats = gc_with_options(&repo, &opts)?;
+            CommandOutcome::Gc { stats, dry_run }
+        }
 
-            let action = if dry_run { "would remove" } else { "removed" };
-            println!(
-                "{} {} blobs, {} trees, {} commits",
-                action, stats.blobs_removed, stats.trees_removed, stats.commits_removed
-            );
-            println!("freed {} bytes", stats.bytes_freed);
+        Commands::Maintenance {
+            dry_run,
+            keep_reflog,
+            keep_deleted_refs_for,
+            tmp_grace,
+        } => {
+            let repo = Repo::open(&repo_path)?;
+            let opts = MaintenanceOptions {
+                dry_run,
+                keep_reflog_generations: keep_reflog,
+                keep_deleted_refs_for: Some(std::time::Duration::from_secs(keep_deleted_refs_for)),
+                tmp_grace: std::time::Duration::from_secs(tmp_grace),
+            };
+            let report = maintenance(&repo, &opts)?;
+            CommandOutcome::Maintenance { report, dry_run }
         }
 
         Commands::Stats => {
             let repo = Repo::open(&repo_path)?;
-            let s = zub::stats(&repo)?;
-
-            println!("refs: {}", s.total_refs);
-            println!();
-            println!("objects:");
-            println!(
-                "  blobs:   {:>8} total, {:>8} reachable ({:.1} MB on disk)",
-                s.total_blobs,
-                s.reachable_blobs,
-                s.total_blobs_bytes as f64 / 1_000_000.0
-            );
-            println!(
-                "  trees:   {:>8} total, {:>8} reachable ({:.1} MB on disk)",
-                s.total_trees,
-                s.reachable_trees,
-                s.total_trees_bytes as f64 / 1_000_000.0
-            );
-            println!(
-                "  commits: {:>8} total, {:>8} reachable ({:.1} MB on disk)",
-                s.total_commits,
-                s.reachable_commits,
-                s.total_commits_bytes as f64 / 1_000_000.0
-            );
-            println!();
-            if s.unreachable_blobs_bytes > 0 {
-                println!(
-                    "unreachable blob data: {:.1} MB (run gc to free)",
-                    s.unreachable_blobs_bytes as f64 / 1_000_000.0
-                );
+            CommandOutcome::Stats(zub::stats(&repo)?)
+        }
+
+        Commands::Migrate => CommandOutcome::Migrate {
+            from: Repo::migrate(&repo_path)?,
+        },
+
+        Commands::TrainDictionary { output } => {
+            let repo = Repo::open(&repo_path)?;
+            let dict = zub::ops::train_dictionary(&repo)?;
+            std::fs::write(&output, &dict).map_err(|e| zub::Error::Io {
+                path: output.clone(),
+                source: e,
+            })?;
+            CommandOutcome::TrainDictionary {
+                output,
+                size: dict.len(),
             }
         }
 
+        Commands::Dedup { limit } => {
+            let repo = Repo::open(&repo_path)?;
+            let report = zub::ops::analyze_dedup(&repo)?;
+            CommandOutcome::Dedup { report, limit }
+        }
+
         Commands::Du {
             pattern,
             limit,
@@ -574,71 +1990,25 @@ fn run(cli: Cli) -> zub::Result<()> {
                     zub::Error::RefNotFound("ref name required with --depth".to_string())
                 })?;
                 let sizes = zub::du_tree(&repo, ref_name, d)?;
-
-                for entry in sizes.iter().take(limit) {
-                    let mb = entry.bytes as f64 / 1_000_000.0;
-                    println!("{:>10.1} MB  {}", mb, entry.path);
-                }
-
-                if sizes.len() > limit {
-                    println!("... and {} more paths", sizes.len() - limit);
-                }
+                CommandOutcome::DuTree { sizes, limit }
             } else {
                 // show disk usage per ref
                 let sizes = zub::du(&repo, pattern.as_deref())?;
-
-                for entry in sizes.iter().take(limit) {
-                    let mb = entry.bytes as f64 / 1_000_000.0;
-                    println!("{:>10.1} MB  {}", mb, entry.ref_name);
-                }
-
-                if sizes.len() > limit {
-                    println!("... and {} more refs", sizes.len() - limit);
-                }
+                CommandOutcome::Du { sizes, limit }
             }
         }
 
         Commands::TruncateHistory { dry_run } => {
             let repo = Repo::open(&repo_path)?;
             let stats = zub::truncate_history(&repo, dry_run)?;
-
-            let action = if dry_run {
-                "would truncate"
-            } else {
-                "truncated"
-            };
-            println!(
-                "{} {}/{} refs",
-                action, stats.refs_truncated, stats.refs_processed
-            );
-            if !dry_run && stats.refs_truncated > 0 {
-                println!("run gc to free unreachable objects");
-            }
+            CommandOutcome::TruncateHistory { stats, dry_run }
         }
 
         Commands::Remap { force, dry_run } => {
             let mut repo = Repo::open(&repo_path)?;
             let options = MapOptions { force, dry_run };
             let stats = map(&mut repo, &options)?;
-
-            if stats.total == 0 && stats.remapped == 0 {
-                println!("namespace mappings match, nothing to do");
-            } else {
-                let action = if dry_run { "would remap" } else { "remapped" };
-                println!("{} {} of {} blobs", action, stats.remapped, stats.total);
-                if stats.skipped_unmapped_source > 0 {
-                    println!(
-                        "skipped {} blobs (uid/gid not in source namespace)",
-                        stats.skipped_unmapped_source
-                    );
-                }
-                if stats.skipped_unmapped_target > 0 {
-                    println!(
-                        "skipped {} blobs (uid/gid not mappable to current namespace)",
-                        stats.skipped_unmapped_target
-                    );
-                }
-            }
+            CommandOutcome::Remap { stats, dry_run }
         }
 
         Commands::Push {
@@ -646,25 +2016,42 @@ fn run(cli: Cli) -> zub::Result<()> {
             ref_name,
             force,
             dry_run,
+            rate_limit,
+            retries,
+            remap_ownership,
+            keep_unmapped_owners,
         } => {
             let src = Repo::open(&repo_path)?;
-            let dst = Repo::open(&destination)?;
-
-            let options = PushOptions { force, dry_run };
-            let result = push_local(&src, &dst, &ref_name, &options)?;
 
-            if dry_run {
-                println!("would push {} to {}", result.hash, destination.display());
-                println!("would transfer {} objects", result.objects_to_transfer);
-            } else {
-                println!("pushed {} to {}", result.hash, destination.display());
-                println!(
-                    "transferred: {} copied, {} hardlinked, {} skipped, {} bytes",
-                    result.stats.copied,
-                    result.stats.hardlinked,
-                    result.stats.skipped,
-                    result.stats.bytes_transferred
-                );
+            let options = PushOptions {
+                force,
+                dry_run,
+                rate_limit_bytes_per_sec: rate_limit,
+                max_retries: retries,
+                remap_ownership,
+                keep_unmapped_owners,
+            };
+            let mut line = ProgressLine::new();
+            let mut progress = |p: TransferProgress| line.update(p);
+            let result = match src.config().resolve_remote(&destination)? {
+                RemoteUrl::Local(path) => {
+                    let dst = Repo::open(&path)?;
+                    push_local_with_progress(&src, &dst, &ref_name, &options, Some(&mut progress))?
+                }
+                RemoteUrl::Ssh { host, path } => push_ssh_with_progress(
+                    &src,
+                    &host,
+                    &path,
+                    &ref_name,
+                    &options,
+                    Some(&mut progress),
+                )?,
+            };
+            line.finish();
+            CommandOutcome::Push {
+                result,
+                destination,
+                dry_run,
             }
         }
 
@@ -672,109 +2059,220 @@ fn run(cli: Cli) -> zub::Result<()> {
             source,
             ref_name,
             fetch_only,
+            refs_only,
+            allow_missing,
+            mirror,
             dry_run,
+            rate_limit,
+            retries,
+            remap_ownership,
+            keep_unmapped_owners,
         } => {
-            let src = Repo::open(&source)?;
             let dst = Repo::open(&repo_path)?;
 
             let options = PullOptions {
                 fetch_only,
                 dry_run,
+                refs_only,
+                allow_missing,
+                mirror,
+                rate_limit_bytes_per_sec: rate_limit,
+                max_retries: retries,
+                remap_ownership,
+                keep_unmapped_owners,
+            };
+            if mirror {
+                let result = match dst.config().resolve_remote(&source)? {
+                    RemoteUrl::Local(path) => {
+                        pull_refs_matching(&Repo::open(&path)?, &dst, &ref_name, &options)?
+                    }
+                    RemoteUrl::Ssh { host, path } => {
+                        pull_refs_matching_ssh(&host, &path, &dst, &ref_name, &options)?
+                    }
+                };
+                return Ok(CommandOutcome::PullMirror {
+                    result,
+                    source,
+                    dry_run,
+                });
+            }
+            if refs_only {
+                let refs = match dst.config().resolve_remote(&source)? {
+                    RemoteUrl::Local(path) => {
+                        pull_refs(&Repo::open(&path)?, &dst, &ref_name, &options)?
+                    }
+                    RemoteUrl::Ssh { host, path } => {
+                        pull_refs_ssh(&host, &path, &dst, &ref_name, &options)?
+                    }
+                };
+                return Ok(CommandOutcome::PullRefs { refs, dry_run });
+            }
+            let mut line = ProgressLine::new();
+            let mut progress = |p: TransferProgress| line.update(p);
+            let result = match dst.config().resolve_remote(&source)? {
+                RemoteUrl::Local(path) => {
+                    let src = Repo::open(&path)?;
+                    pull_local_with_progress(&src, &dst, &ref_name, &options, Some(&mut progress))?
+                }
+                RemoteUrl::Ssh { host, path } => pull_ssh_with_progress(
+                    &host,
+                    &path,
+                    &dst,
+                    &ref_name,
+                    &options,
+                    Some(&mut progress),
+                )?,
             };
-            let result = pull_local(&src, &dst, &ref_name, &options)?;
+            line.finish();
+            CommandOutcome::Pull {
+                result,
+                source,
+                dry_run,
+            }
+        }
 
-            if dry_run {
-                println!("would pull {} from {}", result.hash, source.display());
-                println!("would transfer {} objects", result.objects_to_transfer);
-            } else {
-                println!("pulled {} from {}", result.hash, source.display());
-                println!(
-                    "transferred: {} copied, {} hardlinked, {} skipped, {} bytes",
-                    result.stats.copied,
-                    result.stats.hardlinked,
-                    result.stats.skipped,
-                    result.stats.bytes_transferred
-                );
+        Commands::Fetch {
+            remote,
+            ref_name,
+            dry_run,
+            rate_limit,
+            retries,
+        } => {
+            let dst = Repo::open(&repo_path)?;
+            let url = dst
+                .config()
+                .get_remote(&remote)
+                .ok_or_else(|| zub::Error::RemoteNotFound(remote.clone()))?
+                .resolve()?;
+            let options = PullOptions {
+                dry_run,
+                rate_limit_bytes_per_sec: rate_limit,
+                max_retries: retries,
+                ..Default::default()
+            };
+            let prefix = format!("remotes/{}", remote);
+            let mut line = ProgressLine::new();
+            let mut progress = |p: TransferProgress| line.update(p);
+            let result = match url {
+                RemoteUrl::Local(path) => pull_into_with_progress(
+                    &Repo::open(&path)?,
+                    &dst,
+                    &ref_name,
+                    &prefix,
+                    &options,
+                    Some(&mut progress),
+                )?,
+                RemoteUrl::Ssh { host, path } => pull_ssh_into_with_progress(
+                    &host,
+                    &path,
+                    &dst,
+                    &ref_name,
+                    &prefix,
+                    &options,
+                    Some(&mut progress),
+                )?,
+            };
+            line.finish();
+            CommandOutcome::Fetch {
+                result,
+                tracking_ref: tracking_ref_name(&prefix, &ref_name),
+                dry_run,
             }
         }
 
-        Commands::Refs => {
+        Commands::Refs { tree } => {
             let repo = Repo::open(&repo_path)?;
-            let refs = zub::list_refs(&repo)?;
-
-            for ref_name in refs {
-                let hash = zub::read_ref(&repo, &ref_name)?;
-                println!("{} {}", hash, ref_name);
+            if tree {
+                return Ok(CommandOutcome::RefTree(zub::list_refs_tree(&repo)?));
             }
+            let refs = zub::list_refs(&repo)?
+                .into_iter()
+                .map(|ref_name| {
+                    let hash = zub::read_ref(&repo, &ref_name)?;
+                    Ok((ref_name, hash))
+                })
+                .collect::<zub::Result<_>>()?;
+            CommandOutcome::Refs(refs)
         }
 
         Commands::ShowRef { ref_name } => {
             let repo = Repo::open(&repo_path)?;
-            let hash = zub::resolve_ref(&repo, &ref_name)?;
-            println!("{}", hash);
+            CommandOutcome::Hash(zub::resolve_ref(&repo, &ref_name)?)
         }
 
         Commands::DeleteRef { ref_name } => {
             let repo = Repo::open(&repo_path)?;
             zub::delete_ref(&repo, &ref_name)?;
-            println!("deleted ref {}", ref_name);
+            CommandOutcome::DeleteRef(ref_name)
+        }
+
+        Commands::RecoverRef { ref_name } => {
+            let repo = Repo::open(&repo_path)?;
+            let hash = zub::recover_ref(&repo, &ref_name)?;
+            CommandOutcome::RecoverRef { ref_name, hash }
         }
 
-        Commands::DeleteRefs { pattern } => {
+        Commands::DeleteRefs {
+            pattern,
+            dry_run,
+            force,
+        } => {
             let repo = Repo::open(&repo_path)?;
-            let deleted = zub::delete_refs_matching(&repo, &pattern)?;
-            if deleted.is_empty() {
-                println!("no refs matched pattern {}", pattern);
+            let matching = zub::list_refs_matching(&repo, &pattern)?;
+            let deleted = if matching.is_empty() || dry_run {
+                matching
+            } else if matching.len() > DELETE_REFS_FORCE_THRESHOLD && !force {
+                return Err(zub::Error::InvalidRef(format!(
+                    "pattern {} matches {} refs; use --dry-run to review or --force to delete",
+                    pattern,
+                    matching.len()
+                )));
             } else {
-                for r in deleted {
-                    println!("deleted ref {}", r);
-                }
+                zub::delete_refs_matching(&repo, &pattern)?
+            };
+            CommandOutcome::DeleteRefs {
+                pattern,
+                deleted,
+                dry_run,
             }
         }
 
+        Commands::PackRefs => {
+            let repo = Repo::open(&repo_path)?;
+            CommandOutcome::PackRefs(zub::pack_refs(&repo)?)
+        }
+
+        Commands::PruneRefs { dry_run } => {
+            let repo = Repo::open(&repo_path)?;
+            let pruned = zub::prune_dangling_refs(&repo, dry_run)?;
+            CommandOutcome::PruneRefs { pruned, dry_run }
+        }
+
         Commands::DeleteArtifacts { pattern } => {
             let repo = Repo::open(&repo_path)?;
             let deleted = zub::delete_artifact_refs_matching(&repo, &pattern)?;
-            if deleted.is_empty() {
-                println!("no artifact refs matched pattern {}", pattern);
-            } else {
-                for r in deleted {
-                    println!("deleted artifact ref {}", r);
-                }
-            }
+            CommandOutcome::DeleteArtifacts { pattern, deleted }
         }
 
-        Commands::CatFile { spec, object_type } => {
+        Commands::CatFile {
+            spec,
+            object_type,
+            pretty,
+            logical,
+        } => {
             let repo = Repo::open(&repo_path)?;
 
             if let Some(obj_type) = object_type {
                 // raw hash mode: -t blob HASH
                 let hash = Hash::from_hex(&spec)?;
                 match obj_type.as_str() {
-                    "blob" => {
-                        let data = read_blob(&repo, &hash)?;
-                        io::stdout().write_all(&data).map_err(|e| zub::Error::Io {
-                            path: "stdout".into(),
-                            source: e,
-                        })?;
-                    }
-                    "tree" => {
-                        let tree = read_tree(&repo, &hash)?;
-                        for entry in tree.entries() {
-                            println!("{} {}", entry.kind.type_name(), entry.name);
-                        }
-                    }
-                    "commit" => {
-                        let commit = read_commit(&repo, &hash)?;
-                        println!("tree {}", commit.tree);
-                        for parent in &commit.parents {
-                            println!("parent {}", parent);
-                        }
-                        println!("author {}", commit.author);
-                        println!("timestamp {}", commit.timestamp);
-                        println!();
-                        println!("{}", commit.message);
-                    }
+                    "blob" => CommandOutcome::StreamBlob { repo, hash },
+                    "content" => CommandOutcome::Bytes(zub::read_content(&repo, &hash)?),
+                    "tree" => tree_outcome(&repo, read_tree(&repo, &hash)?, pretty),
+                    "commit" => CommandOutcome::CatCommit {
+                        commit: read_commit(&repo, &hash)?,
+                        pretty,
+                    },
                     _ => {
                         return Err(zub::Error::InvalidObjectType(obj_type));
                     }
@@ -786,74 +2284,193 @@ fn run(cli: Cli) -> zub::Result<()> {
                 let tree = read_tree(&repo, &commit.tree)?;
 
                 // walk the path
-                cat_file_path(&repo, &tree, path)?;
+                cat_file_path(repo, tree, path, pretty, logical)?
             } else {
                 // just a ref - show commit
                 let commit_hash = zub::resolve_ref(&repo, &spec)?;
-                let commit = read_commit(&repo, &commit_hash)?;
-                println!("tree {}", commit.tree);
-                for parent in &commit.parents {
-                    println!("parent {}", parent);
+                CommandOutcome::CatCommit {
+                    commit: read_commit(&repo, &commit_hash)?,
+                    pretty,
                 }
-                println!("author {}", commit.author);
-                println!("timestamp {}", commit.timestamp);
-                println!();
-                println!("{}", commit.message);
             }
         }
 
         Commands::RevParse { rev, short } => {
             let repo = Repo::open(&repo_path)?;
             let hash = zub::resolve_ref(&repo, &rev)?;
-            if short {
-                println!("{}", &hash.to_hex()[..12]);
-            } else {
-                println!("{}", hash);
-            }
+            CommandOutcome::RevParse { hash, short }
         }
 
         Commands::Show { rev, metadata_key } => {
             let repo = Repo::open(&repo_path)?;
             let hash = zub::resolve_ref(&repo, &rev)?;
-            let commit = read_commit(&repo, &hash)?;
 
             match metadata_key {
-                Some(key) => {
-                    // print specific metadata key
-                    match commit.metadata.get(&key) {
-                        Some(value) => println!("{}", value),
-                        None => {
-                            return Err(zub::Error::MetadataKeyNotFound(key));
-                        }
-                    }
-                }
-                None => {
-                    // print full commit info
-                    println!("commit {}", hash);
-                    println!("tree {}", commit.tree);
-                    for parent in &commit.parents {
-                        println!("parent {}", parent);
-                    }
-                    println!("author {}", commit.author);
-                    println!("timestamp {}", commit.timestamp);
-                    if !commit.metadata.is_empty() {
-                        println!();
-                        println!("metadata:");
-                        for (k, v) in &commit.metadata {
-                            println!("  {}: {}", k, v);
-                        }
+                // print specific metadata key
+                Some(key) => match zub::read_commit_header(&repo, &hash)?.metadata.remove(&key) {
+                    Some(value) => CommandOutcome::MetadataValue(value),
+                    None => {
+                        return Err(zub::Error::MetadataKeyNotFound(key));
                     }
-                    println!();
-                    println!("{}", commit.message);
-                }
+                },
+                // print full commit info
+                None => CommandOutcome::Show {
+                    hash,
+                    commit: read_commit(&repo, &hash)?,
+                },
             }
         }
 
-        Commands::Remote { path } => {
+        Commands::Remote { action } => match action {
+            RemoteCommands::Add { name, url } => {
+                let mut repo = Repo::open(&repo_path)?;
+                // reject urls we couldn't dispatch later
+                RemoteUrl::parse(&url)?;
+                repo.config_mut().add_remote(name, url)?;
+                repo.save_config()?;
+                CommandOutcome::Done
+            }
+            RemoteCommands::Remove { name } => {
+                let mut repo = Repo::open(&repo_path)?;
+                repo.config_mut().remove_remote(&name)?;
+                repo.save_config()?;
+                CommandOutcome::Done
+            }
+            RemoteCommands::List => {
+                let repo = Repo::open(&repo_path)?;
+                CommandOutcome::Remotes(repo.config().remotes.clone())
+            }
+        },
+
+        Commands::RemoteHelper { path } => {
             run_remote_helper(&path)?;
+            CommandOutcome::Done
+        }
+    };
+
+    Ok(outcome)
+}
+
+/// write an fsck/verify report
+fn write_fsck_report(out: &mut dyn Write, report: &FsckReport, subject: &str) -> io::Result<()> {
+    writeln!(out, "objects checked: {}", report.objects_checked)?;
+
+    if !report.corrupt_objects.is_empty() {
+        writeln!(out, "\ncorrupt objects:")?;
+        for obj in &report.corrupt_objects {
+            writeln!(out, "  {} {}: {}", obj.object_type, obj.hash, obj.message)?;
+        }
+    }
+
+    if !report.missing_objects.is_empty() {
+        writeln!(out, "\nmissing objects:")?;
+        for obj in &report.missing_objects {
+            writeln!(
+                out,
+                "  {} {} (referenced by {})",
+                obj.object_type, obj.hash, obj.referenced_by
+            )?;
+        }
+    }
+
+    if !report.dangling_objects.is_empty() {
+        writeln!(out, "\ndangling objects: {}", report.dangling_objects.len())?;
+    }
+
+    if report.is_ok() {
+        writeln!(out, "\n{} is healthy", subject)
+    } else {
+        writeln!(out, "\n{} has issues", subject)
+    }
+}
+
+/// push/pull progress on a single stderr line, redrawn at most every 100ms
+///
+/// only drawn when stderr is a terminal, so logs and pipes stay clean.
+struct ProgressLine {
+    enabled: bool,
+    drawn: bool,
+    last_draw: Option<std::time::Instant>,
+}
+
+impl ProgressLine {
+    fn new() -> Self {
+        Self {
+            enabled: io::stderr().is_terminal(),
+            drawn: false,
+            last_draw: None,
+        }
+    }
+
+    fn update(&mut self, p: TransferProgress) {
+        if !self.enabled {
+            return;
+        }
+        let now = std::time::Instant::now();
+        let due = self
+            .last_draw
+            .is_none_or(|last| now.duration_since(last).as_millis() >= 100);
+        if !due && p.objects_done < p.objects_total {
+            return;
+        }
+        self.last_draw = Some(now);
+        self.drawn = true;
+        eprint!(
+            "\rtransferring objects: {}/{}, {} bytes",
+            p.objects_done, p.objects_total, p.bytes_transferred
+        );
+    }
+
+    /// end the progress line so later output starts on a fresh one
+    fn finish(&self) {
+        if self.drawn {
+            eprintln!();
         }
     }
+}
+
+/// write push/pull transfer counts
+fn write_transfer_stats(out: &mut dyn Write, stats: &TransferStats) -> io::Result<()> {
+    writeln!(
+        out,
+        "transferred: {} copied, {} hardlinked, {} skipped, {} bytes ({} bytes/s)",
+        stats.copied,
+        stats.hardlinked,
+        stats.skipped,
+        stats.bytes_transferred,
+        stats.bytes_per_sec()
+    )?;
+    if stats.remapped > 0 {
+        writeln!(out, "remapped: {} blobs", stats.remapped)?;
+    }
+    Ok(())
+}
+
+/// write the commit header lines shared by cat-file and show
+fn write_commit_header(out: &mut dyn Write, commit: &Commit) -> io::Result<()> {
+    writeln!(out, "tree {}", commit.tree)?;
+    for parent in &commit.parents {
+        writeln!(out, "parent {}", parent)?;
+    }
+    writeln!(out, "author {}", commit.author)?;
+    writeln!(out, "timestamp {}", commit.timestamp)
+}
 
+/// write the namespace fingerprint and metadata map shown by show and cat-file -p
+fn write_commit_metadata(out: &mut dyn Write, commit: &Commit) -> io::Result<()> {
+    if let Some(fingerprint) = &commit.ns_fingerprint {
+        writeln!(out, "namespace {}", fingerprint)?;
+    }
+    if let Some(generation) = commit.generation {
+        writeln!(out, "generation {}", generation)?;
+    }
+    if !commit.metadata.is_empty() {
+        writeln!(out)?;
+        writeln!(out, "metadata:")?;
+        for (k, v) in &commit.metadata {
+            writeln!(out, "  {}: {}", k, v)?;
+        }
+    }
     Ok(())
 }
 
@@ -866,26 +2483,88 @@ fn parse_conflict_resolution(s: &str) -> zub::Result<ConflictResolution> {
     }
 }
 
+fn parse_unreadable_policy(s: &str) -> zub::Result<UnreadablePolicy> {
+    match s.to_lowercase().as_str() {
+        "error" => Ok(UnreadablePolicy::Error),
+        "skip" => Ok(UnreadablePolicy::Skip),
+        "placeholder" => Ok(UnreadablePolicy::Placeholder),
+        _ => Err(zub::Error::InvalidUnreadablePolicy(s.to_string())),
+    }
+}
+
+fn parse_deref_mode(s: &str) -> zub::Result<DerefMode> {
+    match s.to_lowercase().as_str() {
+        "never" => Ok(DerefMode::Never),
+        "external" => Ok(DerefMode::External),
+        "always" => Ok(DerefMode::Always),
+        _ => Err(zub::Error::InvalidDerefMode(s.to_string())),
+    }
+}
+
+/// parse `--owner UID:GID`
+fn parse_owner(s: &str) -> zub::Result<(u32, u32)> {
+    let parsed = s
+        .split_once(':')
+        .and_then(|(uid, gid)| Some((uid.parse().ok()?, gid.parse().ok()?)));
+    parsed.ok_or_else(|| zub::Error::InvalidOwner(s.to_string()))
+}
+
+/// library warnings go to stderr, so stdout stays parseable
+fn stderr_diagnostics() -> Arc<dyn Diagnostics> {
+    Arc::new(StderrDiagnostics)
+}
+
+/// parse `--uid-map`/`--gid-map` values, each one or more
+/// "INSIDE OUTSIDE COUNT" lines as in /proc/self/uid_map
+fn parse_map_arg(values: &[String]) -> zub::Result<Vec<zub::MapEntry>> {
+    let mut map = Vec::new();
+    for value in values {
+        let lines = value.lines().filter(|l| !l.trim().is_empty()).count();
+        match zub::parse_id_map(value) {
+            Ok(entries) if entries.len() == lines && lines > 0 => map.extend(entries),
+            _ => {
+                return Err(zub::Error::InvalidIdMap(format!(
+                    "expected \"INSIDE OUTSIDE COUNT\", got {:?}",
+                    value
+                )))
+            }
+        }
+    }
+    Ok(map)
+}
+
 /// run the remote helper protocol (server side of SSH transport)
 fn run_remote_helper(repo_path: &Path) -> zub::Result<()> {
     let repo = Repo::open(repo_path)?;
     zub::transport::serve_remote(&repo)
 }
 
-/// cat-file helper: walk tree path and output contents
-fn cat_file_path(repo: &Repo, tree: &zub::Tree, path: &str) -> zub::Result<()> {
+/// cat-file helper: a directory listing, as a full table with --pretty
+fn tree_outcome(repo: &Repo, tree: zub::Tree, pretty: bool) -> CommandOutcome {
+    if pretty {
+        CommandOutcome::PrettyTree(zub::ops::format_tree_pretty(repo, &tree))
+    } else {
+        CommandOutcome::Tree(tree)
+    }
+}
+
+/// cat-file helper: walk tree path and return its contents
+fn cat_file_path(
+    repo: Repo,
+    tree: zub::Tree,
+    path: &str,
+    pretty: bool,
+    logical: bool,
+) -> zub::Result<CommandOutcome> {
     use zub::EntryKind;
 
     let components: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
     if components.is_empty() {
         // empty path = list root tree
-        for entry in tree.entries() {
-            println!("{} {}", entry.kind.type_name(), entry.name);
-        }
-        return Ok(());
+        return Ok(tree_outcome(&repo, tree, pretty));
     }
 
-    let mut current_tree = tree.clone();
+    let mut current_tree = tree;
 
     for (i, component) in components.iter().enumerate() {
         let entry = current_tree
@@ -893,56 +2572,126 @@ fn cat_file_path(repo: &Repo, tree: &zub::Tree, path: &str) -> zub::Result<()> {
             .ok_or_else(|| zub::Error::PathNotFound(path.to_string()))?;
 
         let is_last = i == components.len() - 1;
+        if !is_last && !matches!(entry.kind, EntryKind::Directory { .. }) {
+            return Err(zub::Error::PathNotFound(path.to_string()));
+        }
 
         match &entry.kind {
             EntryKind::Directory { hash, .. } => {
-                let subtree = read_tree(repo, hash)?;
+                let subtree = read_tree(&repo, hash)?;
                 if is_last {
                     // list directory contents
-                    for e in subtree.entries() {
-                        println!("{} {}", e.kind.type_name(), e.name);
-                    }
-                    return Ok(());
+                    return Ok(tree_outcome(&repo, subtree, pretty));
                 }
                 current_tree = subtree;
             }
-            EntryKind::Regular { hash, .. } => {
-                if !is_last {
-                    return Err(zub::Error::PathNotFound(path.to_string()));
-                }
-                let data = read_blob(repo, hash)?;
-                io::stdout().write_all(&data).map_err(|e| zub::Error::Io {
-                    path: "stdout".into(),
-                    source: e,
-                })?;
-                return Ok(());
+            EntryKind::Regular { .. } => {
+                return Ok(CommandOutcome::StreamFile {
+                    repo,
+                    kind: entry.kind.clone(),
+                    logical,
+                });
             }
             EntryKind::Symlink { hash, .. } => {
-                if !is_last {
-                    return Err(zub::Error::PathNotFound(path.to_string()));
-                }
-                let data = read_blob(repo, hash)?;
-                let target = String::from_utf8_lossy(&data);
-                println!("{}", target);
-                return Ok(());
+                let data = read_blob(&repo, hash)?;
+                let target = String::from_utf8_lossy(&data).into_owned();
+                return Ok(CommandOutcome::Symlink(target));
             }
             EntryKind::Hardlink { target_path } => {
-                if !is_last {
-                    return Err(zub::Error::PathNotFound(path.to_string()));
-                }
-                // follow hardlink and output that file
-                println!("-> {}", target_path);
-                return Ok(());
+                return Ok(CommandOutcome::Hardlink(target_path.clone()));
             }
-            _ => {
-                if !is_last {
-                    return Err(zub::Error::PathNotFound(path.to_string()));
-                }
-                println!("{}", entry.kind.type_name());
-                return Ok(());
+            _ => return Ok(CommandOutcome::EntryType(entry.kind.type_name())),
+        }
+    }
+
+    Ok(CommandOutcome::Done)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn test_repo() -> (tempfile::TempDir, PathBuf) {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        Repo::init(&repo_path).unwrap();
+        (dir, repo_path)
+    }
+
+    fn run_args(repo_path: &Path, args: &[&str]) -> CommandOutcome {
+        let repo_arg = repo_path.to_str().unwrap();
+        let cli = Cli::parse_from(["zub", "--repo", repo_arg].iter().chain(args));
+        run(cli).unwrap()
+    }
+
+    #[test]
+    fn test_run_returns_typed_outcomes() {
+        let (dir, repo_path) = test_repo();
+        let source = dir.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("file.txt"), "content").unwrap();
+
+        let source_arg = source.to_str().unwrap();
+        let hash = match run_args(&repo_path, &["commit", source_arg, "--ref-name", "main"]) {
+            CommandOutcome::Hash(hash) => hash,
+            _ => panic!("commit should return a hash"),
+        };
+
+        match run_args(&repo_path, &["refs"]) {
+            CommandOutcome::Refs(refs) => assert_eq!(refs, vec![("main".to_string(), hash)]),
+            _ => panic!("refs should return ref/hash pairs"),
+        }
+
+        match run_args(&repo_path, &["stats"]) {
+            CommandOutcome::Stats(stats) => {
+                assert_eq!(stats.total_refs, 1);
+                assert_eq!(stats.total_blobs, 1);
+                assert_eq!(stats.reachable_commits, 1);
             }
+            _ => panic!("stats should return repo stats"),
         }
+
+        // rendering is where the human text comes from
+        let mut out = Vec::new();
+        run_args(&repo_path, &["refs"]).render(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{} main\n", hash));
     }
 
-    Ok(())
+    #[test]
+    fn test_diff_json() {
+        let (dir, repo_path) = test_repo();
+        let source = dir.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("file.txt"), "v1").unwrap();
+        let source_arg = source.to_str().unwrap();
+        run_args(&repo_path, &["commit", source_arg, "--ref-name", "a"]);
+
+        std::fs::write(source.join("file.txt"), "v2").unwrap();
+        std::fs::write(source.join("new.txt"), "new").unwrap();
+        run_args(&repo_path, &["commit", source_arg, "--ref-name", "b"]);
+
+        let mut out = Vec::new();
+        run_args(&repo_path, &["diff", "a", "b", "--json"])
+            .render(&mut out)
+            .unwrap();
+        let mut changes: Vec<DiffEntry> = serde_json::from_slice(&out).unwrap();
+        changes.sort_by(|x, y| x.path.cmp(&y.path));
+        assert_eq!(
+            changes,
+            vec![
+                DiffEntry {
+                    path: "file.txt".to_string(),
+                    kind: zub::ChangeKind::Modified,
+                },
+                DiffEntry {
+                    path: "new.txt".to_string(),
+                    kind: zub::ChangeKind::Added,
+                },
+            ]
+        );
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains(r#""kind":"added""#));
+    }
 }
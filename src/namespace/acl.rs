@@ -0,0 +1,126 @@
+//! uid/gid translation inside POSIX ACL xattrs
+//!
+//! `system.posix_acl_access` and `system.posix_acl_default` hold a binary
+//! ACL: a 4-byte version header, then 8-byte entries of (tag, perm, id),
+//! all little-endian. named user and group entries carry an id that needs
+//! the same namespace translation as file ownership.
+
+use crate::error::{Error, Result};
+use crate::namespace::{MapEntry, NsConfig};
+use crate::types::Xattr;
+
+/// xattrs holding a POSIX ACL
+pub const ACL_XATTRS: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+const ACL_VERSION: u32 = 2;
+const HEADER_LEN: usize = 4;
+const ENTRY_LEN: usize = 8;
+
+/// entry tag of a named user
+const ACL_USER: u16 = 0x02;
+/// entry tag of a named group
+const ACL_GROUP: u16 = 0x08;
+
+/// translate the named user and group ids of an encoded ACL with `map_id`
+/// (`outside_to_inside` on commit, `inside_to_outside` on checkout)
+pub fn remap_acl(
+    value: &[u8],
+    ns: &NsConfig,
+    map_id: fn(u32, &[MapEntry]) -> Option<u32>,
+) -> Result<Vec<u8>> {
+    if value.len() < HEADER_LEN || !(value.len() - HEADER_LEN).is_multiple_of(ENTRY_LEN) {
+        return Err(Error::InvalidAcl(format!("bad length {}", value.len())));
+    }
+    let version = u32::from_le_bytes(value[..HEADER_LEN].try_into().unwrap());
+    if version != ACL_VERSION {
+        return Err(Error::InvalidAcl(format!(
+            "unsupported version {}",
+            version
+        )));
+    }
+
+    let mut out = value.to_vec();
+    for entry in out[HEADER_LEN..].chunks_exact_mut(ENTRY_LEN) {
+        let tag = u16::from_le_bytes([entry[0], entry[1]]);
+        let id = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+        let mapped = match tag {
+            ACL_USER => map_id(id, &ns.uid_map).ok_or(Error::UnmappedUid(id))?,
+            ACL_GROUP => map_id(id, &ns.gid_map).ok_or(Error::UnmappedGid(id))?,
+            _ => continue,
+        };
+        entry[4..8].copy_from_slice(&mapped.to_le_bytes());
+    }
+    Ok(out)
+}
+
+/// `remap_acl` over the ACL xattrs in `xattrs`; others are left alone
+pub fn remap_acl_xattrs(
+    xattrs: &mut [Xattr],
+    ns: &NsConfig,
+    map_id: fn(u32, &[MapEntry]) -> Option<u32>,
+) -> Result<()> {
+    for xattr in xattrs
+        .iter_mut()
+        .filter(|x| ACL_XATTRS.contains(&x.name.as_str()))
+    {
+        xattr.value = remap_acl(&xattr.value, ns, map_id)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespace::{inside_to_outside, outside_to_inside};
+
+    /// encode (tag, perm, id) entries as a version 2 ACL
+    fn encode_acl(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut out = ACL_VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            out.extend_from_slice(&tag.to_le_bytes());
+            out.extend_from_slice(&perm.to_le_bytes());
+            out.extend_from_slice(&id.to_le_bytes());
+        }
+        out
+    }
+
+    #[test]
+    fn test_remap_acl_round_trip() {
+        let ns = NsConfig {
+            uid_map: vec![MapEntry::new(0, 100000, 65536)],
+            gid_map: vec![MapEntry::new(0, 200000, 65536)],
+        };
+        let outside = encode_acl(&[
+            (0x01, 6, u32::MAX),
+            (ACL_USER, 4, 101000),
+            (0x04, 4, u32::MAX),
+            (ACL_GROUP, 5, 200050),
+            (0x10, 7, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ]);
+
+        let inside = remap_acl(&outside, &ns, outside_to_inside).unwrap();
+        assert_eq!(
+            inside,
+            encode_acl(&[
+                (0x01, 6, u32::MAX),
+                (ACL_USER, 4, 1000),
+                (0x04, 4, u32::MAX),
+                (ACL_GROUP, 5, 50),
+                (0x10, 7, u32::MAX),
+                (0x20, 0, u32::MAX),
+            ])
+        );
+        assert_eq!(remap_acl(&inside, &ns, inside_to_outside).unwrap(), outside);
+
+        let unmapped = encode_acl(&[(ACL_USER, 4, 5000)]);
+        assert!(matches!(
+            remap_acl(&unmapped, &ns, outside_to_inside),
+            Err(Error::UnmappedUid(5000))
+        ));
+        assert!(matches!(
+            remap_acl(&outside[..7], &ns, outside_to_inside),
+            Err(Error::InvalidAcl(_))
+        ));
+    }
+}
@@ -1,5 +1,8 @@
 use serde::{Deserialize, Serialize};
 
+use crate::error::{Error, Result};
+use crate::hash::Hash;
+
 /// a single range in a uid/gid mapping
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MapEntry {
@@ -68,6 +71,71 @@ impl NsConfig {
             && self.gid_map[0].outside_start == 0
             && self.gid_map[0].count == u32::MAX
     }
+
+    /// check both maps are well-formed, as the kernel requires of
+    /// `/proc/<pid>/{uid,gid}_map`: non-empty, no empty or wrapping ranges,
+    /// and no two ranges overlapping on either side
+    pub fn validate(&self) -> Result<()> {
+        validate_map("uid", &self.uid_map)?;
+        validate_map("gid", &self.gid_map)
+    }
+
+    /// hash identifying this mapping, recorded in commits made under it
+    ///
+    /// covers the uid and gid ranges in order; configs with the same ranges
+    /// get the same fingerprint.
+    pub fn fingerprint(&self) -> Hash {
+        let mut hasher = blake3::Hasher::new();
+        for (tag, map) in [(b"uid", &self.uid_map), (b"gid", &self.gid_map)] {
+            hasher.update(tag);
+            hasher.update(&(map.len() as u64).to_le_bytes());
+            for entry in map {
+                hasher.update(&entry.inside_start.to_le_bytes());
+                hasher.update(&entry.outside_start.to_le_bytes());
+                hasher.update(&entry.count.to_le_bytes());
+            }
+        }
+        Hash::from_bytes(*hasher.finalize().as_bytes())
+    }
+}
+
+/// check one map per `NsConfig::validate`
+fn validate_map(kind: &str, map: &[MapEntry]) -> Result<()> {
+    if map.is_empty() {
+        return Err(Error::InvalidIdMap(format!("{} map is empty", kind)));
+    }
+    for entry in map {
+        let wraps = |start: u32| u64::from(start) + u64::from(entry.count) > 1 << 32;
+        if entry.count == 0 || wraps(entry.inside_start) || wraps(entry.outside_start) {
+            return Err(Error::InvalidIdMap(format!(
+                "{} range {} {} {} is empty or out of bounds",
+                kind, entry.inside_start, entry.outside_start, entry.count
+            )));
+        }
+    }
+    for (i, a) in map.iter().enumerate() {
+        for b in &map[i + 1..] {
+            let overlaps = |a_start: u32, b_start: u32| {
+                let (a_start, b_start) = (u64::from(a_start), u64::from(b_start));
+                a_start < b_start + u64::from(b.count) && b_start < a_start + u64::from(a.count)
+            };
+            if overlaps(a.inside_start, b.inside_start)
+                || overlaps(a.outside_start, b.outside_start)
+            {
+                return Err(Error::InvalidIdMap(format!(
+                    "{} ranges {} {} {} and {} {} {} overlap",
+                    kind,
+                    a.inside_start,
+                    a.outside_start,
+                    a.count,
+                    b.inside_start,
+                    b.outside_start,
+                    b.count
+                )));
+            }
+        }
+    }
+    Ok(())
 }
 
 /// convert outside (on-disk) id to inside (logical namespace) id
@@ -106,6 +174,33 @@ pub fn mappings_equal(a: &NsConfig, b: &NsConfig) -> bool {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_ns_config_validate() {
+        assert!(NsConfig::identity().validate().is_ok());
+        let valid = NsConfig {
+            uid_map: vec![MapEntry::new(0, 1000, 1), MapEntry::new(1, 100000, 65536)],
+            gid_map: vec![MapEntry::new(0, 1000, 1)],
+        };
+        assert!(valid.validate().is_ok());
+
+        let with_uid_map = |uid_map| NsConfig {
+            uid_map,
+            gid_map: vec![MapEntry::new(0, 1000, 1)],
+        };
+        for bad in [
+            vec![],
+            vec![MapEntry::new(0, 1000, 0)],
+            vec![MapEntry::new(2, 0, u32::MAX)],
+            // inside ranges overlap
+            vec![MapEntry::new(0, 1000, 10), MapEntry::new(9, 2000, 10)],
+            // outside ranges overlap
+            vec![MapEntry::new(0, 1000, 10), MapEntry::new(10, 1005, 10)],
+        ] {
+            let err = with_uid_map(bad.clone()).validate();
+            assert!(matches!(err, Err(Error::InvalidIdMap(_))), "{:?}", bad);
+        }
+    }
+
     #[test]
     fn test_map_entry_contains() {
         let entry = MapEntry::new(1, 100000, 65536);
@@ -1,6 +1,8 @@
+mod acl;
 mod mapping;
 mod proc;
 
+pub use acl::{remap_acl, remap_acl_xattrs, ACL_XATTRS};
 pub use mapping::{
     inside_to_outside, mappings_equal, outside_to_inside, remap, MapEntry, NsConfig,
 };
@@ -3,6 +3,7 @@ use std::io::Write;
 use std::path::PathBuf;
 
 use crate::error::{Error, IoResultExt, Result};
+use crate::fs::TempFile;
 use crate::hash::Hash;
 use crate::repo::Repo;
 use crate::types::Artifact;
@@ -35,15 +36,17 @@ pub fn write_artifact(repo: &Repo, artifact: &Artifact) -> Result<Hash> {
     fs::create_dir_all(&artifact_dir).with_path(&artifact_dir)?;
 
     // atomic write: temp -> fsync -> rename
-    let tmp_path = repo.tmp_path().join(uuid::Uuid::new_v4().to_string());
+    let tmp = TempFile::new_in(&repo.objects_tmp_path());
+    let tmp_path = tmp.path();
     {
-        let mut tmp_file = File::create(&tmp_path).with_path(&tmp_path)?;
-        tmp_file.write_all(&cbor_bytes).with_path(&tmp_path)?;
-        tmp_file.sync_all().with_path(&tmp_path)?;
+        let mut tmp_file = File::create(tmp_path).with_path(tmp_path)?;
+        tmp_file.write_all(&cbor_bytes).with_path(tmp_path)?;
+        tmp_file.sync_all().with_path(tmp_path)?;
     }
 
     // rename to final location
-    fs::rename(&tmp_path, &artifact_path).with_path(&artifact_path)?;
+    fs::rename(tmp_path, &artifact_path).with_path(&artifact_path)?;
+    tmp.into_persisted();
 
     // fsync parent directory
     let dir_file = File::open(&artifact_dir).with_path(&artifact_dir)?;
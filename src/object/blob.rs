@@ -1,12 +1,15 @@
-use std::fs::{self, File, Permissions};
+use std::fs::{self, File, OpenOptions, Permissions};
 use std::io::{Read, Write};
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use nix::fcntl::{Flock, FlockArg};
 use nix::unistd::{Gid, Uid};
 
 use crate::error::{Error, IoResultExt, Result};
-use crate::hash::{compute_blob_hash, Hash};
-use crate::namespace::inside_to_outside;
+use crate::fs::TempFile;
+use crate::hash::{compute_blob_hash, compute_content_hash, Hash};
+use crate::namespace::{inside_to_outside, outside_to_inside};
+use crate::object::WriteOutcome;
 use crate::repo::Repo;
 use crate::types::Xattr;
 
@@ -24,15 +27,131 @@ pub fn write_blob(
     mode: u32,
     xattrs: &[Xattr],
 ) -> Result<Hash> {
+    write_blob_outcome(repo, content, inside_uid, inside_gid, mode, xattrs).map(|o| o.hash)
+}
+
+/// like `write_blob`, also telling whether the blob was newly stored
+pub fn write_blob_outcome(
+    repo: &Repo,
+    content: &[u8],
+    inside_uid: u32,
+    inside_gid: u32,
+    mode: u32,
+    xattrs: &[Xattr],
+) -> Result<WriteOutcome> {
+    let hash = compute_blob_hash(inside_uid, inside_gid, mode, xattrs, content);
+
+    // note: xattrs are NOT stored on the blob file - they are stored in tree metadata
+    // this avoids requiring CAP_SETFCAP for security.capability xattrs during commit
+    let created = store_blob_file(repo, &hash, content, inside_uid, inside_gid, mode)?;
+
+    Ok(WriteOutcome { hash, created })
+}
+
+/// write a regular file's blob, honoring `content_addressed_blobs`
+///
+/// returns the blob (metadata) hash and, in content-addressed mode, the
+/// content hash. in that mode the bytes go to `objects/content` once and the
+/// blob file is an empty stub carrying only uid/gid/mode, stored under
+/// `blob_file_hash` so it never stands in for the full blob.
+pub fn write_regular_blob(
+    repo: &Repo,
+    content: &[u8],
+    inside_uid: u32,
+    inside_gid: u32,
+    mode: u32,
+    xattrs: &[Xattr],
+) -> Result<(Hash, Option<Hash>)> {
+    write_regular_blob_outcome(repo, content, inside_uid, inside_gid, mode, xattrs)
+        .map(|(outcome, content_hash)| (outcome.hash, content_hash))
+}
+
+/// like `write_regular_blob`, also telling whether the blob was newly stored
+///
+/// in content-addressed mode `created` is about the blob stub; its content
+/// may still have been shared with another blob.
+pub fn write_regular_blob_outcome(
+    repo: &Repo,
+    content: &[u8],
+    inside_uid: u32,
+    inside_gid: u32,
+    mode: u32,
+    xattrs: &[Xattr],
+) -> Result<(WriteOutcome, Option<Hash>)> {
+    if !repo.config().content_addressed_blobs {
+        let outcome = write_blob_outcome(repo, content, inside_uid, inside_gid, mode, xattrs)?;
+        return Ok((outcome, None));
+    }
+
     let hash = compute_blob_hash(inside_uid, inside_gid, mode, xattrs, content);
+    let content_hash = write_content(repo, content)?;
+    let stub = blob_file_hash(&hash, Some(&content_hash));
+    let created = store_blob_file(repo, &stub, b"", inside_uid, inside_gid, mode)?;
+
+    Ok((WriteOutcome { hash, created }, Some(content_hash)))
+}
+
+/// write raw content to the content store, returns its content hash
+pub fn write_content(repo: &Repo, content: &[u8]) -> Result<Hash> {
+    let hash = compute_content_hash(content);
 
+    let (dir, file) = hash.to_path_components();
+    let content_dir = repo.content_path().join(&dir);
+    let path = content_dir.join(&file);
+
+    if path.exists() {
+        return Ok(hash);
+    }
+
+    // another writer may be storing the same content: wait, then recheck
+    let _lock = ObjectWriteLock::acquire(repo, &hash)?;
+    if path.exists() {
+        return Ok(hash);
+    }
+
+    fs::create_dir_all(&content_dir).with_path(&content_dir)?;
+
+    write_atomic(repo, &path, |tmp_path| {
+        {
+            let tmp_file = File::create(tmp_path).with_path(tmp_path)?;
+            write_skipping_zeros(&tmp_file, content, 0).with_path(tmp_path)?;
+            tmp_file.set_len(content.len() as u64).with_path(tmp_path)?;
+            tmp_file.sync_all().with_path(tmp_path)?;
+        }
+
+        // content objects are shared across modes, keep them read-only
+        fs::set_permissions(tmp_path, Permissions::from_mode(0o444)).with_path(tmp_path)
+    })?;
+    fsync_dir(&content_dir)?;
+
+    Ok(hash)
+}
+
+/// atomically store a blob file with on-disk ownership and mode applied
+///
+/// returns false when the blob was already stored and nothing was written.
+fn store_blob_file(
+    repo: &Repo,
+    hash: &Hash,
+    content: &[u8],
+    inside_uid: u32,
+    inside_gid: u32,
+    mode: u32,
+) -> Result<bool> {
     let (dir, file) = hash.to_path_components();
     let blob_dir = repo.blobs_path().join(&dir);
     let blob_path = blob_dir.join(&file);
 
     // deduplication: if blob already exists, we're done
     if blob_path.exists() {
-        return Ok(hash);
+        return Ok(false);
+    }
+
+    // another writer may be storing the same blob: wait for it, then recheck
+    // so only one of them writes the content
+    let _lock = ObjectWriteLock::acquire(repo, hash)?;
+    if blob_path.exists() {
+        return Ok(false);
     }
 
     // convert inside uid/gid to outside values for storage
@@ -46,44 +165,153 @@ pub fn write_blob(
     fs::create_dir_all(&blob_dir).with_path(&blob_dir)?;
 
     // atomic write: temp file -> set metadata -> fsync -> rename
-    let tmp_path = repo.tmp_path().join(uuid::Uuid::new_v4().to_string());
+    write_atomic(repo, &blob_path, |tmp_path| {
+        {
+            let tmp_file = File::create(tmp_path).with_path(tmp_path)?;
+            write_skipping_zeros(&tmp_file, content, 0).with_path(tmp_path)?;
+            tmp_file.set_len(content.len() as u64).with_path(tmp_path)?;
+            tmp_file.sync_all().with_path(tmp_path)?;
+        }
+        set_blob_metadata(tmp_path, outside_uid, outside_gid, mode)
+    })?;
 
-    // write content
-    {
-        let mut tmp_file = File::create(&tmp_path).with_path(&tmp_path)?;
-        tmp_file.write_all(content).with_path(&tmp_path)?;
-        tmp_file.sync_all().with_path(&tmp_path)?;
-    }
+    // fsync parent directory
+    fsync_dir(&blob_dir)?;
 
-    // set permissions (before chown, so we have write access)
-    fs::set_permissions(&tmp_path, Permissions::from_mode(mode & 0o7777)).with_path(&tmp_path)?;
+    Ok(true)
+}
 
-    // set ownership (skip if already matches to avoid permission errors when not root)
-    let current_uid = nix::unistd::getuid().as_raw();
-    let current_gid = nix::unistd::getgid().as_raw();
-    if outside_uid != current_uid || outside_gid != current_gid {
-        nix::unistd::chown(
-            &tmp_path,
-            Some(Uid::from_raw(outside_uid)),
-            Some(Gid::from_raw(outside_gid)),
-        )
-        .map_err(|e| Error::Io {
-            path: tmp_path.clone(),
-            source: std::io::Error::new(std::io::ErrorKind::PermissionDenied, e),
-        })?;
+/// dir under the objects tmp dir holding per-object write locks
+pub(crate) const LOCKS_DIR: &str = "locks";
+
+/// advisory lock on a single object hash, held while that object is written
+///
+/// the lock file, `locks/<hash>` under the objects tmp dir, is removed on drop
+/// while still locked, so waiters that opened it before then retry on a fresh
+/// one. the `locks` dir is created by the first writer and then left in place.
+struct ObjectWriteLock {
+    path: PathBuf,
+    _flock: Flock<File>,
+}
+
+impl ObjectWriteLock {
+    fn acquire(repo: &Repo, hash: &Hash) -> Result<Self> {
+        let dir = repo.objects_tmp_path().join(LOCKS_DIR);
+        let path = dir.join(hash.to_hex());
+        loop {
+            let file = match OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)
+            {
+                Ok(file) => file,
+                // first lock in this repo
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    fs::create_dir_all(&dir).with_path(&dir)?;
+                    continue;
+                }
+                Err(e) => return Err(e).with_path(&path),
+            };
+            let flock =
+                Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, errno)| Error::Io {
+                    path: path.clone(),
+                    source: errno.into(),
+                })?;
+
+            // the previous holder unlinks the file before unlocking; only a
+            // lock on the file still at `path` excludes later writers
+            let held = flock.metadata().with_path(&path)?;
+            match fs::metadata(&path) {
+                Ok(current) if current.ino() == held.ino() && current.dev() == held.dev() => {
+                    return Ok(Self {
+                        path,
+                        _flock: flock,
+                    });
+                }
+                Ok(_) => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_path(&path),
+            }
+        }
     }
+}
 
-    // note: xattrs are NOT stored on the blob file - they are stored in tree metadata
-    // this avoids requiring CAP_SETFCAP for security.capability xattrs during commit
-    let _ = xattrs; // xattrs used only for hash computation above
+impl Drop for ObjectWriteLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
 
-    // rename to final location
-    fs::rename(&tmp_path, &blob_path).with_path(&blob_path)?;
+/// store a regular file's blob under new xattrs, reusing its stored bytes
+///
+/// the new hash is computed in a single pass over the existing data (the
+/// content object when `content` is set). uid/gid/mode come from the blob
+/// file, as at commit time, and since xattrs live in the tree rather than on
+/// the blob file, the new blob is a hardlink to the old one. callers update
+/// the tree entry's xattrs; its content hash, if any, stays the same.
+///
+/// symlink blobs hash with a fixed mode and aren't handled here.
+pub fn rehash_blob_with_xattrs(
+    repo: &Repo,
+    hash: &Hash,
+    content: Option<&Hash>,
+    xattrs: &[Xattr],
+) -> Result<Hash> {
+    let path = blob_meta_path(repo, hash, content);
+    let meta = fs::metadata(&path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            Error::ObjectNotFound(*hash)
+        } else {
+            Error::Io {
+                path: path.clone(),
+                source: e,
+            }
+        }
+    })?;
 
-    // fsync parent directory
-    fsync_dir(&blob_dir)?;
+    let ns = &repo.config().namespace;
+    let inside_uid =
+        outside_to_inside(meta.uid(), &ns.uid_map).ok_or(Error::UnmappedUid(meta.uid()))?;
+    let inside_gid =
+        outside_to_inside(meta.gid(), &ns.gid_map).ok_or(Error::UnmappedGid(meta.gid()))?;
+
+    let data_path = blob_data_path(repo, hash, content);
+    let mut hasher = crate::hash::BlobHasher::new(inside_uid, inside_gid, meta.mode(), xattrs);
+    let mut file = File::open(&data_path).with_path(&data_path)?;
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).with_path(&data_path)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let new_hash = hasher.finalize();
 
-    Ok(hash)
+    let (dir, file) = blob_file_hash(&new_hash, content).to_path_components();
+    let new_dir = repo.blobs_path().join(&dir);
+    let new_path = new_dir.join(&file);
+    if new_path.exists() {
+        return Ok(new_hash);
+    }
+
+    fs::create_dir_all(&new_dir).with_path(&new_dir)?;
+    match fs::hard_link(&path, &new_path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Ok(new_hash),
+        // e.g. EMLINK on a heavily shared blob: fall back to a copy
+        Err(_) => write_atomic(repo, &new_path, |tmp_path| {
+            fs::copy(&path, tmp_path).with_path(tmp_path)?;
+            File::open(tmp_path)
+                .and_then(|f| f.sync_all())
+                .with_path(tmp_path)?;
+            set_blob_metadata(tmp_path, meta.uid(), meta.gid(), meta.mode())
+        })?,
+    }
+    fsync_dir(&new_dir)?;
+
+    Ok(new_hash)
 }
 
 /// write a blob with streaming content (for large files)
@@ -97,22 +325,26 @@ pub fn write_blob_streaming<R: Read>(
     xattrs: &[Xattr],
 ) -> Result<Hash> {
     // for streaming, we need to write to temp first, then compute hash
-    let tmp_path = repo.tmp_path().join(uuid::Uuid::new_v4().to_string());
+    let tmp = TempFile::new_in(&repo.objects_tmp_path());
+    let tmp_path = tmp.path();
 
     // write content to temp file while computing hash
     let mut hasher = crate::hash::BlobHasher::new(inside_uid, inside_gid, mode, xattrs);
     {
-        let mut tmp_file = File::create(&tmp_path).with_path(&tmp_path)?;
+        let tmp_file = File::create(tmp_path).with_path(tmp_path)?;
         let mut buf = [0u8; 64 * 1024]; // 64KB buffer
+        let mut offset = 0u64;
         loop {
-            let n = reader.read(&mut buf).with_path(&tmp_path)?;
+            let n = reader.read(&mut buf).with_path(tmp_path)?;
             if n == 0 {
                 break;
             }
             hasher.update(&buf[..n]);
-            tmp_file.write_all(&buf[..n]).with_path(&tmp_path)?;
+            write_skipping_zeros(&tmp_file, &buf[..n], offset).with_path(tmp_path)?;
+            offset += n as u64;
         }
-        tmp_file.sync_all().with_path(&tmp_path)?;
+        tmp_file.set_len(offset).with_path(tmp_path)?;
+        tmp_file.sync_all().with_path(tmp_path)?;
     }
 
     let hash = hasher.finalize();
@@ -121,9 +353,8 @@ pub fn write_blob_streaming<R: Read>(
     let blob_dir = repo.blobs_path().join(&dir);
     let blob_path = blob_dir.join(&file);
 
-    // dedup check
+    // dedup check; the temp file goes with `tmp`
     if blob_path.exists() {
-        fs::remove_file(&tmp_path).with_path(&tmp_path)?;
         return Ok(hash);
     }
 
@@ -137,36 +368,110 @@ pub fn write_blob_streaming<R: Read>(
     // ensure directory exists
     fs::create_dir_all(&blob_dir).with_path(&blob_dir)?;
 
-    // set metadata
-    fs::set_permissions(&tmp_path, Permissions::from_mode(mode & 0o7777)).with_path(&tmp_path)?;
-    let current_uid = nix::unistd::getuid().as_raw();
-    let current_gid = nix::unistd::getgid().as_raw();
-    if outside_uid != current_uid || outside_gid != current_gid {
-        nix::unistd::chown(
-            &tmp_path,
-            Some(Uid::from_raw(outside_uid)),
-            Some(Gid::from_raw(outside_gid)),
-        )
-        .map_err(|e| Error::Io {
-            path: tmp_path.clone(),
-            source: std::io::Error::new(std::io::ErrorKind::PermissionDenied, e),
-        })?;
-    }
-
     // note: xattrs are NOT stored on the blob file - they are stored in tree metadata
     // this avoids requiring CAP_SETFCAP for security.capability xattrs during commit
 
-    // rename to final location
-    fs::rename(&tmp_path, &blob_path).with_path(&blob_path)?;
+    set_blob_metadata(tmp_path, outside_uid, outside_gid, mode)?;
+    match fs::rename(tmp_path, &blob_path) {
+        Ok(()) => tmp.into_persisted(),
+        Err(e) if is_cross_device(&e) => {
+            // the content is already on disk; copy it next to the blob instead
+            write_atomic_same_dir(&blob_path, |same_dir_tmp| {
+                fs::copy(tmp_path, same_dir_tmp).with_path(same_dir_tmp)?;
+                File::open(same_dir_tmp)
+                    .and_then(|f| f.sync_all())
+                    .with_path(same_dir_tmp)?;
+                set_blob_metadata(same_dir_tmp, outside_uid, outside_gid, mode)
+            })?;
+        }
+        Err(e) => {
+            return Err(Error::Io {
+                path: blob_path,
+                source: e,
+            })
+        }
+    }
     fsync_dir(&blob_dir)?;
 
     Ok(hash)
 }
 
-/// get the filesystem path to a blob
+/// block size below which zero runs are written out rather than skipped
+const HOLE_BLOCK: usize = 4096;
+
+/// write `data` at `offset`, leaving all-zero blocks unwritten
+///
+/// the caller sets the final length, so skipped blocks read back as zeros
+/// without taking space. a sparse file's blob holds only its data regions,
+/// but those can still be mostly zeros (e.g. a preallocated disk image).
+fn write_skipping_zeros(file: &File, data: &[u8], offset: u64) -> std::io::Result<()> {
+    let mut run_start = None;
+    for (i, block) in data.chunks(HOLE_BLOCK).enumerate() {
+        let start = i * HOLE_BLOCK;
+        let zero = block.iter().all(|&b| b == 0);
+        match (zero, run_start) {
+            (false, None) => run_start = Some(start),
+            (true, Some(run)) => {
+                file.write_all_at(&data[run..start], offset + run as u64)?;
+                run_start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(run) = run_start {
+        file.write_all_at(&data[run..], offset + run as u64)?;
+    }
+    Ok(())
+}
+
+/// bytes a file takes on disk, which holes don't count towards
+pub(crate) fn stored_size(meta: &fs::Metadata) -> u64 {
+    meta.blocks() * 512
+}
+
+/// size of a blob's content, as read back, resolving through the content
+/// hash if present
+pub fn blob_logical_size(repo: &Repo, hash: &Hash, content: Option<&Hash>) -> Result<u64> {
+    Ok(blob_metadata(repo, hash, content)?.len())
+}
+
+/// space a blob's data takes on disk; less than `blob_logical_size` when its
+/// zero blocks were stored as holes
+pub fn blob_stored_size(repo: &Repo, hash: &Hash, content: Option<&Hash>) -> Result<u64> {
+    Ok(stored_size(&blob_metadata(repo, hash, content)?))
+}
+
+fn blob_metadata(repo: &Repo, hash: &Hash, content: Option<&Hash>) -> Result<fs::Metadata> {
+    let path = blob_data_path(repo, hash, content);
+    fs::symlink_metadata(&path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            Error::ObjectNotFound(*content.unwrap_or(hash))
+        } else {
+            Error::Io { path, source: e }
+        }
+    })
+}
+
+/// get the filesystem path to a blob, in an alternate if only it has one
 pub fn blob_path(repo: &Repo, hash: &Hash) -> PathBuf {
-    let (dir, file) = hash.to_path_components();
-    repo.blobs_path().join(dir).join(file)
+    super::locate_object(repo, "blobs", hash)
+}
+
+/// key of the blob file carrying a regular entry's ownership and mode
+///
+/// that is the blob hash itself, except for content-addressed entries: their
+/// empty stub gets a key derived from the blob hash, so it can't be mistaken
+/// for the full blob of the same file written outside that mode.
+pub fn blob_file_hash(hash: &Hash, content: Option<&Hash>) -> Hash {
+    match content {
+        Some(_) => Hash::from_bytes(blake3::derive_key("zub blob stub v1", hash.as_bytes())),
+        None => *hash,
+    }
+}
+
+/// path of the blob file carrying a regular entry's ownership and mode
+pub fn blob_meta_path(repo: &Repo, hash: &Hash, content: Option<&Hash>) -> PathBuf {
+    blob_path(repo, &blob_file_hash(hash, content))
 }
 
 /// check if a blob exists in the object store
@@ -186,8 +491,73 @@ pub fn read_blob(repo: &Repo, hash: &Hash) -> Result<Vec<u8>> {
     })
 }
 
+/// get the filesystem path to a content object, in an alternate if only it has one
+pub fn content_path(repo: &Repo, hash: &Hash) -> PathBuf {
+    super::locate_object(repo, "content", hash)
+}
+
+/// check if a content object exists
+pub fn content_exists(repo: &Repo, hash: &Hash) -> bool {
+    content_path(repo, hash).exists()
+}
+
+/// path holding a regular file's bytes: the content object if the entry has
+/// one, otherwise the blob itself
+pub fn blob_data_path(repo: &Repo, hash: &Hash, content: Option<&Hash>) -> PathBuf {
+    match content {
+        Some(content_hash) => content_path(repo, content_hash),
+        None => blob_path(repo, hash),
+    }
+}
+
+/// read a content object
+pub fn read_content(repo: &Repo, hash: &Hash) -> Result<Vec<u8>> {
+    let path = content_path(repo, hash);
+    fs::read(&path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            Error::ObjectNotFound(*hash)
+        } else {
+            Error::Io { path, source: e }
+        }
+    })
+}
+
+/// read a regular file's bytes, resolving through the content hash if present
+pub fn read_blob_data(repo: &Repo, hash: &Hash, content: Option<&Hash>) -> Result<Vec<u8>> {
+    match content {
+        Some(content_hash) => read_content(repo, content_hash),
+        None => read_blob(repo, hash),
+    }
+}
+
+/// read at most `len` bytes from the start of a regular file's data,
+/// resolving through the content hash if present
+pub fn read_blob_prefix(
+    repo: &Repo,
+    hash: &Hash,
+    content: Option<&Hash>,
+    len: usize,
+) -> Result<Vec<u8>> {
+    let path = blob_data_path(repo, hash, content);
+    let file = File::open(&path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            Error::ObjectNotFound(*content.unwrap_or(hash))
+        } else {
+            Error::Io {
+                path: path.clone(),
+                source: e,
+            }
+        }
+    })?;
+
+    let mut buf = Vec::with_capacity(len);
+    file.take(len as u64)
+        .read_to_end(&mut buf)
+        .with_path(&path)?;
+    Ok(buf)
+}
+
 /// read blob content into a writer (streaming)
-#[allow(dead_code)]
 pub fn read_blob_to<W: Write>(repo: &Repo, hash: &Hash, writer: &mut W) -> Result<u64> {
     let path = blob_path(repo, hash);
     let mut file = File::open(&path).map_err(|e| {
@@ -214,6 +584,84 @@ pub fn read_blob_to<W: Write>(repo: &Repo, hash: &Hash, writer: &mut W) -> Resul
     Ok(total)
 }
 
+/// apply on-disk mode and ownership to a blob temp file
+fn set_blob_metadata(path: &Path, outside_uid: u32, outside_gid: u32, mode: u32) -> Result<()> {
+    // set permissions (before chown, so we have write access)
+    fs::set_permissions(path, Permissions::from_mode(mode & 0o7777)).with_path(path)?;
+
+    // set ownership (skip if already matches to avoid permission errors when not root)
+    let current_uid = nix::unistd::getuid().as_raw();
+    let current_gid = nix::unistd::getgid().as_raw();
+    if outside_uid != current_uid || outside_gid != current_gid {
+        nix::unistd::chown(
+            path,
+            Some(Uid::from_raw(outside_uid)),
+            Some(Gid::from_raw(outside_gid)),
+        )
+        .map_err(|e| Error::Io {
+            path: path.to_path_buf(),
+            source: std::io::Error::new(std::io::ErrorKind::PermissionDenied, e),
+        })?;
+    }
+    Ok(())
+}
+
+/// create an object at `dest` by filling a temp file and renaming it into place
+///
+/// the temp file lives in the repo's `tmp/` unless `same_dir_tmp` is set. if
+/// the rename fails with EXDEV (e.g. `tmp/` and the object dir sit on
+/// different overlay layers), the write is redone next to `dest`.
+fn write_atomic<F>(repo: &Repo, dest: &Path, fill: F) -> Result<()>
+where
+    F: Fn(&Path) -> Result<()>,
+{
+    if repo.config().same_dir_tmp {
+        return write_atomic_same_dir(dest, fill);
+    }
+
+    let tmp = TempFile::new_in(&repo.objects_tmp_path());
+    fill(tmp.path())?;
+    match fs::rename(tmp.path(), dest) {
+        Ok(()) => {
+            tmp.into_persisted();
+            Ok(())
+        }
+        Err(e) if is_cross_device(&e) => {
+            drop(tmp);
+            write_atomic_same_dir(dest, fill)
+        }
+        Err(e) => Err(Error::Io {
+            path: dest.to_path_buf(),
+            source: e,
+        }),
+    }
+}
+
+/// like `write_atomic`, with the temp file in `dest`'s own directory
+fn write_atomic_same_dir<F>(dest: &Path, fill: F) -> Result<()>
+where
+    F: Fn(&Path) -> Result<()>,
+{
+    let tmp = TempFile::at(same_dir_tmp_path(dest));
+    fill(tmp.path())?;
+    fs::rename(tmp.path(), dest).with_path(dest)?;
+    tmp.into_persisted();
+    Ok(())
+}
+
+/// hidden temp name next to `dest`; never a valid object name, so listings skip it
+fn same_dir_tmp_path(dest: &Path) -> PathBuf {
+    let name = format!(".tmp-{}", uuid::Uuid::new_v4());
+    match dest.parent() {
+        Some(dir) => dir.join(name),
+        None => PathBuf::from(name),
+    }
+}
+
+fn is_cross_device(e: &std::io::Error) -> bool {
+    e.raw_os_error() == Some(nix::errno::Errno::EXDEV as i32)
+}
+
 /// fsync a directory
 fn fsync_dir(path: &Path) -> Result<()> {
     let dir = File::open(path).with_path(path)?;
@@ -222,10 +670,18 @@ fn fsync_dir(path: &Path) -> Result<()> {
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use tempfile::tempdir;
 
+    /// entries left in a tmp dir, not counting the persistent lock dir
+    pub(crate) fn tmp_leftovers(tmp: &Path) -> usize {
+        fs::read_dir(tmp)
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().file_name() != LOCKS_DIR)
+            .count()
+    }
+
     fn test_repo() -> (tempfile::TempDir, Repo) {
         let dir = tempdir().unwrap();
         let repo_path = dir.path().join("repo");
@@ -268,6 +724,24 @@ mod tests {
         assert_eq!(h1, h2);
     }
 
+    #[test]
+    fn test_write_outcome_reports_creation() {
+        let (_dir, repo) = test_repo();
+        let (uid, gid) = current_ids();
+
+        let first = write_blob_outcome(&repo, b"written once", uid, gid, 0o644, &[]).unwrap();
+        let second = write_blob_outcome(&repo, b"written once", uid, gid, 0o644, &[]).unwrap();
+        assert!(first.created);
+        assert!(!second.created);
+        assert_eq!(first.hash, second.hash);
+
+        let tree = crate::types::Tree::new(vec![]).unwrap();
+        let first = crate::object::write_tree_outcome(&repo, &tree).unwrap();
+        let second = crate::object::write_tree_outcome(&repo, &tree).unwrap();
+        assert!(first.created);
+        assert!(!second.created);
+    }
+
     #[test]
     fn test_different_mode_different_blob() {
         let (_dir, repo) = test_repo();
@@ -282,6 +756,135 @@ mod tests {
         assert!(blob_exists(&repo, &h2));
     }
 
+    #[test]
+    fn test_content_addressed_dedup_across_modes() {
+        let (_dir, mut repo) = test_repo();
+        repo.config_mut().content_addressed_blobs = true;
+        let (uid, gid) = current_ids();
+
+        let content = b"same bytes, different modes";
+        let (h1, c1) = write_regular_blob(&repo, content, uid, gid, 0o100755, &[]).unwrap();
+        let (h2, c2) = write_regular_blob(&repo, content, uid, gid, 0o100555, &[]).unwrap();
+
+        // metadata hashes still differ, content is shared
+        assert_ne!(h1, h2);
+        assert_eq!(c1, c2);
+        let c1 = c1.unwrap();
+
+        assert_eq!(fs::read(content_path(&repo, &c1)).unwrap(), content);
+        assert_eq!(read_blob_data(&repo, &h1, Some(&c1)).unwrap(), content);
+
+        // blob stubs carry metadata only
+        assert_eq!(
+            fs::metadata(blob_meta_path(&repo, &h1, Some(&c1)))
+                .unwrap()
+                .len(),
+            0
+        );
+        assert_eq!(
+            fs::metadata(blob_meta_path(&repo, &h2, Some(&c1)))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777,
+            0o555
+        );
+    }
+
+    #[test]
+    fn test_same_dir_tmp() {
+        let (_dir, mut repo) = test_repo();
+        repo.config_mut().same_dir_tmp = true;
+        repo.config_mut().content_addressed_blobs = true;
+        let (uid, gid) = current_ids();
+
+        let (hash, content) = write_regular_blob(&repo, b"beside", uid, gid, 0o640, &[]).unwrap();
+        let content = content.unwrap();
+        assert_eq!(
+            read_blob_data(&repo, &hash, Some(&content)).unwrap(),
+            b"beside"
+        );
+        let meta = fs::metadata(blob_meta_path(&repo, &hash, Some(&content))).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o640);
+
+        // temp files were renamed away, nothing left beside the objects or in tmp/
+        for path in [
+            blob_meta_path(&repo, &hash, Some(&content)),
+            content_path(&repo, &content),
+        ] {
+            let names: Vec<_> = fs::read_dir(path.parent().unwrap())
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect();
+            assert_eq!(names, vec![path.file_name().unwrap().to_owned()]);
+        }
+        assert_eq!(tmp_leftovers(&repo.tmp_path()), 0);
+    }
+
+    #[test]
+    fn test_write_blob_streaming_failure_leaves_no_temp() {
+        use crate::namespace::MapEntry;
+
+        let (_dir, mut repo) = test_repo();
+        repo.config_mut().namespace.uid_map = vec![MapEntry::new(0, 0, 1000)];
+
+        // the content is in the temp file before the uid turns out unmapped
+        let mut reader = &b"streamed"[..];
+        let result = write_blob_streaming(&repo, &mut reader, 5000, 0, 0o100644, &[]);
+        assert!(matches!(result, Err(Error::UnmappedUid(5000))));
+        assert_eq!(fs::read_dir(repo.objects_tmp_path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_same_dir_fallback_is_atomic() {
+        let (_dir, repo) = test_repo();
+        let shard = repo.blobs_path().join("ab");
+        fs::create_dir_all(&shard).unwrap();
+        let dest = shard.join("object");
+
+        // a failed write leaves neither the object nor its temp file behind
+        let failed = write_atomic_same_dir(&dest, |tmp| {
+            fs::write(tmp, b"partial").unwrap();
+            Err(Error::InvalidObjectType("simulated".into()))
+        });
+        assert!(failed.is_err());
+        assert_eq!(fs::read_dir(&shard).unwrap().count(), 0);
+
+        write_atomic_same_dir(&dest, |tmp| fs::write(tmp, b"whole").with_path(tmp)).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"whole");
+        assert_eq!(fs::read_dir(&shard).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_write_regular_blob_default_mode() {
+        let (_dir, repo) = test_repo();
+        let (uid, gid) = current_ids();
+
+        let (hash, content) = write_regular_blob(&repo, b"plain", uid, gid, 0o644, &[]).unwrap();
+        assert!(content.is_none());
+        assert_eq!(read_blob(&repo, &hash).unwrap(), b"plain");
+    }
+
+    #[test]
+    fn test_read_blob_from_alternate() {
+        let (dir, base) = test_repo();
+        let (uid, gid) = current_ids();
+        let hash = write_blob(&base, b"base layer", uid, gid, 0o644, &[]).unwrap();
+
+        let mut overlay = Repo::init(&dir.path().join("overlay")).unwrap();
+        assert!(!blob_exists(&overlay, &hash));
+        overlay.config_mut().alternates = vec![base.objects_path()];
+
+        assert!(blob_exists(&overlay, &hash));
+        assert_eq!(read_blob(&overlay, &hash).unwrap(), b"base layer");
+        assert!(blob_path(&overlay, &hash).starts_with(base.objects_path()));
+
+        // writes land in the overlay, never the alternate
+        let own = write_blob(&overlay, b"overlay only", uid, gid, 0o644, &[]).unwrap();
+        assert!(blob_path(&overlay, &own).starts_with(overlay.objects_path()));
+        assert!(!blob_exists(&base, &own));
+    }
+
     #[test]
     fn test_blob_path_structure() {
         let (_dir, repo) = test_repo();
@@ -335,6 +938,45 @@ mod tests {
         assert_eq!(hash, expected_hash);
     }
 
+    #[test]
+    fn test_rehash_blob_with_xattrs() {
+        let (uid, gid) = current_ids();
+        let content = b"identical bytes, new signature";
+        let mode = 0o100644;
+        let xattrs = vec![Xattr::new("security.ima", vec![3, 1, 4])];
+        let expected = compute_blob_hash(uid, gid, mode, &xattrs, content);
+
+        for content_addressed in [false, true] {
+            let (_dir, mut repo) = test_repo();
+            repo.config_mut().content_addressed_blobs = content_addressed;
+
+            let (hash, content_hash) =
+                write_regular_blob(&repo, content, uid, gid, mode, &[]).unwrap();
+            let new_hash =
+                rehash_blob_with_xattrs(&repo, &hash, content_hash.as_ref(), &xattrs).unwrap();
+
+            assert_eq!(new_hash, expected);
+            assert_ne!(new_hash, hash);
+            assert!(blob_exists(
+                &repo,
+                &blob_file_hash(&hash, content_hash.as_ref())
+            ));
+            assert_eq!(
+                read_blob_data(&repo, &new_hash, content_hash.as_ref()).unwrap(),
+                content
+            );
+            let meta =
+                fs::metadata(blob_meta_path(&repo, &new_hash, content_hash.as_ref())).unwrap();
+            assert_eq!(meta.mode(), mode);
+            assert_eq!((meta.uid(), meta.gid()), (uid, gid));
+
+            // same xattrs again is a no-op returning the same hash
+            let again =
+                rehash_blob_with_xattrs(&repo, &hash, content_hash.as_ref(), &xattrs).unwrap();
+            assert_eq!(again, new_hash);
+        }
+    }
+
     #[test]
     fn test_read_blob_to_writer() {
         let (_dir, repo) = test_repo();
@@ -349,4 +991,33 @@ mod tests {
         assert_eq!(bytes_read, content.len() as u64);
         assert_eq!(output, content);
     }
+
+    #[test]
+    fn test_concurrent_blob_writes_write_once() {
+        let (_dir, repo) = test_repo();
+        let (uid, gid) = current_ids();
+
+        let content = vec![0xab; 4 * 1024 * 1024];
+
+        let barrier = std::sync::Barrier::new(2);
+        let outcomes: Vec<WriteOutcome> = std::thread::scope(|s| {
+            let writers: Vec<_> = (0..2)
+                .map(|_| {
+                    s.spawn(|| {
+                        barrier.wait();
+                        write_blob_outcome(&repo, &content, uid, gid, 0o100644, &[]).unwrap()
+                    })
+                })
+                .collect();
+            writers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+
+        assert_eq!(outcomes[0].hash, outcomes[1].hash);
+        assert_eq!(outcomes.iter().filter(|o| o.created).count(), 1);
+        assert_eq!(read_blob(&repo, &outcomes[0].hash).unwrap(), content);
+
+        // lock files don't outlive their writers
+        let locks = repo.objects_tmp_path().join(LOCKS_DIR);
+        assert_eq!(fs::read_dir(locks).unwrap().count(), 0);
+    }
 }
@@ -0,0 +1,228 @@
+//! in-memory cache of decoded trees and commits
+//!
+//! objects are immutable, so entries never need invalidation; the cache only
+//! bounds how many are kept.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::hash::Hash;
+use crate::types::{Commit, Tree};
+
+/// cache hit/miss counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// reads served from memory
+    pub hits: u64,
+    /// reads that went to disk
+    pub misses: u64,
+}
+
+#[derive(Clone)]
+enum Cached {
+    Tree(Arc<Tree>),
+    Commit(Arc<Commit>),
+}
+
+/// least-recently-used map from object hash to a cheaply cloned value
+///
+/// entries are ordered by last use, so eviction doesn't scan the map.
+pub(crate) struct Lru<V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<Hash, (V, u64)>,
+    by_use: BTreeMap<u64, Hash>,
+}
+
+impl<V: Clone> Lru<V> {
+    /// an empty cache keeping at most `capacity` entries (at least one)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            tick: 0,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, hash: &Hash) -> Option<V> {
+        self.tick += 1;
+        let (value, used) = self.entries.get_mut(hash)?;
+        self.by_use.remove(used);
+        *used = self.tick;
+        self.by_use.insert(self.tick, *hash);
+        Some(value.clone())
+    }
+
+    pub fn insert(&mut self, hash: Hash, value: V) {
+        if let Some((_, used)) = self.entries.get(&hash) {
+            self.by_use.remove(used);
+        } else if self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.by_use.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.by_use.insert(self.tick, hash);
+        self.entries.insert(hash, (value, self.tick));
+    }
+}
+
+struct Inner {
+    entries: Lru<Cached>,
+    stats: CacheStats,
+}
+
+/// least-recently-used cache shared by all readers of a repo
+pub(crate) struct ObjectCache {
+    inner: Mutex<Inner>,
+}
+
+impl ObjectCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                entries: Lru::new(capacity),
+                stats: CacheStats::default(),
+            }),
+        }
+    }
+
+    pub fn get_tree(&self, hash: &Hash) -> Option<Arc<Tree>> {
+        match self.get(hash)? {
+            Cached::Tree(tree) => Some(tree),
+            Cached::Commit(_) => None,
+        }
+    }
+
+    pub fn get_commit(&self, hash: &Hash) -> Option<Arc<Commit>> {
+        match self.get(hash)? {
+            Cached::Commit(commit) => Some(commit),
+            Cached::Tree(_) => None,
+        }
+    }
+
+    pub fn insert_tree(&self, hash: Hash, tree: Arc<Tree>) {
+        self.lock().entries.insert(hash, Cached::Tree(tree));
+    }
+
+    pub fn insert_commit(&self, hash: Hash, commit: Arc<Commit>) {
+        self.lock().entries.insert(hash, Cached::Commit(commit));
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.lock().stats
+    }
+
+    /// look up an entry, counting a miss if absent
+    fn get(&self, hash: &Hash) -> Option<Cached> {
+        let mut inner = self.lock();
+        let found = inner.entries.get(hash);
+        match found {
+            Some(_) => inner.stats.hits += 1,
+            None => inner.stats.misses += 1,
+        }
+        found
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        // a panic while holding the lock can't leave an entry half-written
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{read_commit, read_tree};
+    use crate::ops::{commit, fsck};
+    use crate::repo::Repo;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn test_repo() -> (tempfile::TempDir, Repo) {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        Repo::init(&repo_path).unwrap();
+        let repo = Repo::open_with_cache(&repo_path, 16).unwrap();
+        (dir, repo)
+    }
+
+    #[test]
+    fn test_second_read_is_cached() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file.txt"), "content").unwrap();
+        let commit_hash = commit(&repo, &source, "test", None, None).unwrap();
+
+        let before = repo.cache_stats().unwrap();
+        let tree_hash = read_commit(&repo, &commit_hash).unwrap().tree;
+        let first = read_tree(&repo, &tree_hash).unwrap();
+        let after_first = repo.cache_stats().unwrap();
+        assert_eq!(after_first.misses, before.misses + 2);
+
+        let second = read_tree(&repo, &tree_hash).unwrap();
+        let after_second = repo.cache_stats().unwrap();
+        assert_eq!(after_second.misses, after_first.misses);
+        assert_eq!(after_second.hits, after_first.hits + 1);
+        assert_eq!(first, second);
+
+        // uncached repos report nothing
+        let plain = Repo::open(repo.path()).unwrap();
+        assert!(plain.cache_stats().is_none());
+    }
+
+    #[test]
+    fn test_fsck_unchanged_with_cache() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("a/b")).unwrap();
+        fs::write(source.join("a/b/file.txt"), "content").unwrap();
+        commit(&repo, &source, "one", None, None).unwrap();
+        fs::write(source.join("a/other.txt"), "other").unwrap();
+        commit(&repo, &source, "two", None, None).unwrap();
+
+        let plain = Repo::open(repo.path()).unwrap();
+        let expected = fsck(&plain).unwrap();
+
+        // run twice so the second pass is served from the cache
+        for _ in 0..2 {
+            let report = fsck(&repo).unwrap();
+            assert_eq!(report.objects_checked, expected.objects_checked);
+            assert_eq!(report.dangling_objects, expected.dangling_objects);
+            assert!(report.is_ok());
+        }
+        assert!(repo.cache_stats().unwrap().hits > 0);
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = ObjectCache::new(2);
+        let tree = Arc::new(Tree::new(vec![]).unwrap());
+        let (a, b, c) = (
+            Hash::from_bytes([1; 32]),
+            Hash::from_bytes([2; 32]),
+            Hash::from_bytes([3; 32]),
+        );
+
+        cache.insert_tree(a, tree.clone());
+        cache.insert_tree(b, tree.clone());
+        assert!(cache.get_tree(&a).is_some());
+        cache.insert_tree(c, tree);
+
+        // b was least recently used
+        assert!(cache.get_tree(&b).is_none());
+        assert!(cache.get_tree(&a).is_some());
+        assert!(cache.get_tree(&c).is_some());
+        assert!(cache.get_commit(&a).is_none());
+
+        // hits hand out the stored tree, and re-inserting evicts nothing
+        let stored = cache.get_tree(&a).unwrap();
+        assert!(Arc::ptr_eq(&stored, &cache.get_tree(&c).unwrap()));
+        cache.insert_tree(a, stored);
+        assert!(cache.get_tree(&c).is_some());
+    }
+}
@@ -1,26 +1,31 @@
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use crate::error::{Error, IoResultExt, Result};
+use crate::fs::TempFile;
 use crate::hash::Hash;
+use crate::object::WriteOutcome;
 use crate::repo::Repo;
-use crate::types::Commit;
+use crate::types::{Commit, CommitHeader};
 
 /// write a commit to the object store
 ///
 /// commits are serialized as CBOR, then zstd compressed.
 /// the hash is computed over the compressed bytes.
 pub fn write_commit(repo: &Repo, commit: &Commit) -> Result<Hash> {
+    write_commit_outcome(repo, commit).map(|o| o.hash)
+}
+
+/// like `write_commit`, also telling whether the commit was newly stored
+pub fn write_commit_outcome(repo: &Repo, commit: &Commit) -> Result<WriteOutcome> {
     // serialize to cbor
     let mut cbor_bytes = Vec::new();
     ciborium::into_writer(commit, &mut cbor_bytes)?;
 
-    // compress with zstd (level 3)
-    let compressed = zstd::encode_all(&cbor_bytes[..], 3).map_err(|e| Error::Io {
-        path: PathBuf::from("<zstd>"),
-        source: e,
-    })?;
+    // compress with zstd (level 3), with the repo's dictionary if it has one
+    let compressed = super::compress_object(repo, &cbor_bytes)?;
 
     // hash the compressed bytes
     let hash = Hash::from_bytes(*blake3::hash(&compressed).as_bytes());
@@ -31,44 +36,76 @@ pub fn write_commit(repo: &Repo, commit: &Commit) -> Result<Hash> {
 
     // dedup: if commit already exists, we're done
     if commit_path.exists() {
-        return Ok(hash);
+        return Ok(WriteOutcome {
+            hash,
+            created: false,
+        });
     }
 
     // ensure directory exists
     fs::create_dir_all(&commit_dir).with_path(&commit_dir)?;
 
     // atomic write: temp -> fsync -> rename
-    let tmp_path = repo.tmp_path().join(uuid::Uuid::new_v4().to_string());
+    let tmp = TempFile::new_in(&repo.objects_tmp_path());
+    let tmp_path = tmp.path();
     {
-        let mut tmp_file = File::create(&tmp_path).with_path(&tmp_path)?;
-        tmp_file.write_all(&compressed).with_path(&tmp_path)?;
-        tmp_file.sync_all().with_path(&tmp_path)?;
+        let mut tmp_file = File::create(tmp_path).with_path(tmp_path)?;
+        tmp_file.write_all(&compressed).with_path(tmp_path)?;
+        tmp_file.sync_all().with_path(tmp_path)?;
     }
 
     // rename to final location
-    fs::rename(&tmp_path, &commit_path).with_path(&commit_path)?;
+    fs::rename(tmp_path, &commit_path).with_path(&commit_path)?;
+    tmp.into_persisted();
 
     // fsync parent directory
     let dir_file = File::open(&commit_dir).with_path(&commit_dir)?;
     dir_file.sync_all().with_path(&commit_dir)?;
 
-    Ok(hash)
+    Ok(WriteOutcome {
+        hash,
+        created: true,
+    })
 }
 
 /// read a commit from the object store
+///
+/// only the commit object itself is read; commits refer to their tree by
+/// hash and never load it.
 pub fn read_commit(repo: &Repo, hash: &Hash) -> Result<Commit> {
+    if let Some(commit) = repo.object_cache().and_then(|c| c.get_commit(hash)) {
+        return Ok(Arc::unwrap_or_clone(commit));
+    }
+
+    // deserialize
+    let cbor_bytes = read_commit_bytes(repo, hash)?;
+    let commit: Commit = ciborium::from_reader(&cbor_bytes[..])?;
+
+    if let Some(cache) = repo.object_cache() {
+        cache.insert_commit(*hash, Arc::new(commit.clone()));
+    }
+
+    Ok(commit)
+}
+
+/// read a commit without decoding its message
+///
+/// for walking history (parents, timestamps, metadata) where the message
+/// isn't shown. like `read_commit`, no tree object is touched.
+pub fn read_commit_header(repo: &Repo, hash: &Hash) -> Result<CommitHeader> {
+    if let Some(commit) = repo.object_cache().and_then(|c| c.get_commit(hash)) {
+        return Ok(Arc::unwrap_or_clone(commit).into());
+    }
+
+    let cbor_bytes = read_commit_bytes(repo, hash)?;
+    Ok(ciborium::from_reader(&cbor_bytes[..])?)
+}
+
+/// read, verify and decompress a commit object
+fn read_commit_bytes(repo: &Repo, hash: &Hash) -> Result<Vec<u8>> {
     let path = commit_path(repo, hash);
 
-    let compressed = fs::read(&path).map_err(|e| {
-        if e.kind() == std::io::ErrorKind::NotFound {
-            Error::ObjectNotFound(*hash)
-        } else {
-            Error::Io {
-                path: path.clone(),
-                source: e,
-            }
-        }
-    })?;
+    let compressed = super::read_stored(repo, super::PackedKind::Commit, hash)?;
 
     // verify hash
     let actual_hash = Hash::from_bytes(*blake3::hash(&compressed).as_bytes());
@@ -77,27 +114,23 @@ pub fn read_commit(repo: &Repo, hash: &Hash) -> Result<Commit> {
     }
 
     // decompress
-    let cbor_bytes = zstd::decode_all(&compressed[..]).map_err(|e| Error::Io {
-        path: path.clone(),
-        source: e,
-    })?;
-
-    // deserialize
-    let commit: Commit = ciborium::from_reader(&cbor_bytes[..])?;
-
-    Ok(commit)
+    super::decompress_object(
+        &compressed,
+        repo.zstd_dictionary(),
+        repo.config().max_object_size,
+        hash,
+        &path,
+    )
 }
 
-/// get the filesystem path to a commit object
+/// get the filesystem path to a commit object, in an alternate if only it has one
 pub fn commit_path(repo: &Repo, hash: &Hash) -> PathBuf {
-    let (dir, file) = hash.to_path_components();
-    repo.commits_path().join(dir).join(file)
+    super::locate_object(repo, "commits", hash)
 }
 
 /// check if a commit exists in the object store
-#[allow(dead_code)]
 pub fn commit_exists(repo: &Repo, hash: &Hash) -> bool {
-    commit_path(repo, hash).exists()
+    commit_path(repo, hash).exists() || super::packed_exists(repo, super::PackedKind::Commit, hash)
 }
 
 #[cfg(test)]
@@ -1,9 +1,101 @@
 pub mod artifact;
 pub mod blob;
+pub(crate) mod cache;
 pub mod commit;
+pub mod pack;
 pub mod tree;
 
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::hash::Hash;
+use crate::repo::Repo;
+
 pub use artifact::{artifact_exists, artifact_path, read_artifact, write_artifact};
-pub use blob::{blob_exists, blob_path, read_blob, write_blob};
-pub use commit::{commit_path, read_commit, write_commit};
-pub use tree::{read_tree, tree_path, write_tree};
+pub use blob::{
+    blob_data_path, blob_exists, blob_file_hash, blob_logical_size, blob_meta_path, blob_path,
+    blob_stored_size, content_exists, content_path, read_blob, read_blob_data, read_blob_prefix,
+    read_blob_to, read_content, rehash_blob_with_xattrs, write_blob, write_blob_outcome,
+    write_regular_blob, write_regular_blob_outcome,
+};
+pub use cache::CacheStats;
+pub(crate) use cache::{Lru, ObjectCache};
+pub use commit::{
+    commit_exists, commit_path, read_commit, read_commit_header, write_commit, write_commit_outcome,
+};
+pub use pack::{
+    list_packed, list_packed_sizes, packed_exists, packed_size, read_packed, write_pack, PackedKind,
+};
+pub(crate) use pack::{read_stored, repack_without, with_packed, PackCache};
+pub(crate) use tree::read_tree_shared;
+pub use tree::{read_tree, tree_path, write_tree, write_tree_outcome};
+
+/// result of writing an object: its hash, and whether this write stored it
+/// or it was already there (a dedup hit, or another writer got there first)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOutcome {
+    pub hash: Hash,
+    pub created: bool,
+}
+
+/// where an object of one kind is stored, falling through to the alternates
+///
+/// `subdir` names the kind's directory in an object store (`blobs`, `trees`,
+/// ...). the primary store's path is returned when no alternate holds the
+/// object either, so writes and not-found errors still point there. packs in
+/// alternates aren't consulted.
+pub(crate) fn locate_object(repo: &Repo, subdir: &str, hash: &Hash) -> PathBuf {
+    let (dir, file) = hash.to_path_components();
+    let primary = repo.objects_path().join(subdir).join(&dir).join(&file);
+    if repo.config().alternates.is_empty() || primary.exists() {
+        return primary;
+    }
+    repo.alternate_objects_paths()
+        .into_iter()
+        .map(|store| store.join(subdir).join(&dir).join(&file))
+        .find(|path| path.exists())
+        .unwrap_or(primary)
+}
+
+/// zstd level for trees and commits
+const OBJECT_ZSTD_LEVEL: i32 = 3;
+
+/// zstd-compress a tree or commit, with the repo's dictionary if it has one
+pub(crate) fn compress_object(repo: &Repo, data: &[u8]) -> Result<Vec<u8>> {
+    let compressed = match repo.zstd_dictionary() {
+        Some(dict) => zstd::bulk::Compressor::with_dictionary(OBJECT_ZSTD_LEVEL, dict)
+            .and_then(|mut c| c.compress(data)),
+        None => zstd::encode_all(data, OBJECT_ZSTD_LEVEL),
+    };
+    compressed.map_err(|e| Error::Io {
+        path: PathBuf::from("<zstd>"),
+        source: e,
+    })
+}
+
+/// zstd-decompress a stored tree or commit, refusing to inflate it past
+/// `limit` bytes so a crafted object can't exhaust memory
+pub(crate) fn decompress_object(
+    compressed: &[u8],
+    dictionary: Option<&[u8]>,
+    limit: u64,
+    hash: &Hash,
+    path: &Path,
+) -> Result<Vec<u8>> {
+    let decoder: Box<dyn Read + '_> = match dictionary {
+        Some(dict) => Box::new(
+            zstd::stream::read::Decoder::with_dictionary(compressed, dict).with_path(path)?,
+        ),
+        None => Box::new(zstd::stream::read::Decoder::new(compressed).with_path(path)?),
+    };
+    let mut data = Vec::new();
+    decoder
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut data)
+        .with_path(path)?;
+    if data.len() as u64 > limit {
+        return Err(Error::CorruptObject(*hash));
+    }
+    Ok(data)
+}
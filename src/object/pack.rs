@@ -0,0 +1,495 @@
+//! pack files for cold trees and commits
+//!
+//! a pack is `objects/pack/NNNN.pack`, the stored (compressed) bytes of its
+//! objects back to back after a magic header, and `NNNN.idx`, a magic header
+//! followed by fixed-size `kind, hash, offset, len` records sorted by kind
+//! and hash. the index is renamed into place last, so a pack without one is
+//! an interrupted write and is ignored.
+//!
+//! objects keep their hash when packed, since it covers the stored bytes.
+//! blobs are never packed: checkout hardlinks them.
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::fs::TempFile;
+use crate::hash::Hash;
+use crate::repo::Repo;
+
+const PACK_MAGIC: &[u8; 8] = b"ZUBPACK1";
+const INDEX_MAGIC: &[u8; 8] = b"ZUBIDX01";
+/// kind byte, hash, offset and length
+const INDEX_RECORD_LEN: usize = 1 + 32 + 8 + 8;
+
+/// object types that can be packed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PackedKind {
+    Tree,
+    Commit,
+}
+
+impl PackedKind {
+    fn tag(self) -> u8 {
+        match self {
+            PackedKind::Tree => 1,
+            PackedKind::Commit => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(PackedKind::Tree),
+            2 => Some(PackedKind::Commit),
+            _ => None,
+        }
+    }
+
+    /// the kind for a wire object type name (`tree`, `commit`)
+    pub(crate) fn from_type_name(name: &str) -> Option<Self> {
+        match name {
+            "tree" => Some(PackedKind::Tree),
+            "commit" => Some(PackedKind::Commit),
+            _ => None,
+        }
+    }
+
+    /// path of the loose object
+    pub fn loose_path(self, repo: &Repo, hash: &Hash) -> PathBuf {
+        match self {
+            PackedKind::Tree => super::tree_path(repo, hash),
+            PackedKind::Commit => super::commit_path(repo, hash),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    kind: PackedKind,
+    hash: Hash,
+    offset: u64,
+    len: u64,
+}
+
+/// a loaded pack index
+#[derive(Debug)]
+struct PackIndex {
+    name: String,
+    pack: PathBuf,
+    entries: Vec<IndexEntry>,
+}
+
+impl PackIndex {
+    fn find(&self, kind: PackedKind, hash: &Hash) -> Option<&IndexEntry> {
+        self.entries
+            .binary_search_by(|e| (e.kind, e.hash).cmp(&(kind, *hash)))
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+}
+
+/// pack indexes loaded so far; new packs are picked up on a lookup miss
+#[derive(Debug, Default)]
+pub(crate) struct PackCache {
+    indexes: Mutex<Vec<Arc<PackIndex>>>,
+}
+
+impl PackCache {
+    /// load indexes of packs written since the last refresh, and drop those
+    /// of packs removed by a repack
+    fn refresh(&self, repo: &Repo) -> Result<()> {
+        let names = index_names(&repo.packs_path())?;
+        let mut indexes = self.indexes.lock().unwrap();
+        indexes.retain(|i| names.contains(&i.name));
+        for name in names {
+            if indexes.iter().any(|i| i.name == name) {
+                continue;
+            }
+            indexes.push(Arc::new(load_index(&repo.packs_path(), &name)?));
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Vec<Arc<PackIndex>> {
+        self.indexes.lock().unwrap().clone()
+    }
+
+    fn find(
+        &self,
+        repo: &Repo,
+        kind: PackedKind,
+        hash: &Hash,
+    ) -> Result<Option<(PathBuf, IndexEntry)>> {
+        let lookup = |indexes: &[Arc<PackIndex>]| {
+            indexes
+                .iter()
+                .find_map(|i| i.find(kind, hash).map(|e| (i.pack.clone(), *e)))
+        };
+        if let Some(found) = lookup(&self.snapshot()) {
+            return Ok(Some(found));
+        }
+        if !repo.packs_path().exists() {
+            return Ok(None);
+        }
+        self.refresh(repo)?;
+        Ok(lookup(&self.snapshot()))
+    }
+}
+
+/// stems of the complete packs in `dir`, in order
+fn index_names(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(names),
+        Err(e) => {
+            return Err(Error::Io {
+                path: dir.to_path_buf(),
+                source: e,
+            })
+        }
+    };
+    for entry in entries {
+        let name = entry.with_path(dir)?.file_name();
+        let Some(stem) = name.to_str().and_then(|n| n.strip_suffix(".idx")) else {
+            continue;
+        };
+        names.push(stem.to_string());
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn load_index(dir: &Path, name: &str) -> Result<PackIndex> {
+    let path = dir.join(format!("{}.idx", name));
+    let data = fs::read(&path).with_path(&path)?;
+    let corrupt = || Error::CorruptPackIndex(path.clone());
+
+    let records = data.strip_prefix(INDEX_MAGIC).ok_or_else(corrupt)?;
+    if records.len() % INDEX_RECORD_LEN != 0 {
+        return Err(corrupt());
+    }
+    let mut entries = Vec::with_capacity(records.len() / INDEX_RECORD_LEN);
+    for record in records.chunks_exact(INDEX_RECORD_LEN) {
+        let kind = PackedKind::from_tag(record[0]).ok_or_else(corrupt)?;
+        let hash = Hash::from_bytes(record[1..33].try_into().unwrap());
+        let offset = u64::from_le_bytes(record[33..41].try_into().unwrap());
+        let len = u64::from_le_bytes(record[41..49].try_into().unwrap());
+        entries.push(IndexEntry {
+            kind,
+            hash,
+            offset,
+            len,
+        });
+    }
+    if !entries.is_sorted_by_key(|e| (e.kind, e.hash)) {
+        return Err(corrupt());
+    }
+
+    Ok(PackIndex {
+        name: name.to_string(),
+        pack: dir.join(format!("{}.pack", name)),
+        entries,
+    })
+}
+
+/// read the stored bytes of a packed object, none if no pack has it
+///
+/// the bytes are not hash-checked here; callers verify them as they would a
+/// loose object.
+pub fn read_packed(repo: &Repo, kind: PackedKind, hash: &Hash) -> Result<Option<Vec<u8>>> {
+    let mut reloaded = false;
+    loop {
+        let Some((pack, entry)) = repo.pack_cache().find(repo, kind, hash)? else {
+            return Ok(None);
+        };
+        let file = match File::open(&pack) {
+            Ok(file) => file,
+            // repacked since its index was loaded; a kept object is in the new pack
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !reloaded => {
+                repo.pack_cache().refresh(repo)?;
+                reloaded = true;
+                continue;
+            }
+            Err(e) => {
+                return Err(Error::Io {
+                    path: pack,
+                    source: e,
+                })
+            }
+        };
+        let mut data = vec![0; entry.len as usize];
+        file.read_exact_at(&mut data, entry.offset)
+            .with_path(&pack)?;
+        return Ok(Some(data));
+    }
+}
+
+/// check if a pack holds an object
+pub fn packed_exists(repo: &Repo, kind: PackedKind, hash: &Hash) -> bool {
+    matches!(repo.pack_cache().find(repo, kind, hash), Ok(Some(_)))
+}
+
+/// stored size of a packed object, none if no pack has it
+pub fn packed_size(repo: &Repo, kind: PackedKind, hash: &Hash) -> Result<Option<u64>> {
+    Ok(repo
+        .pack_cache()
+        .find(repo, kind, hash)?
+        .map(|(_, entry)| entry.len))
+}
+
+/// add the packed objects of one kind to a list of loose ones, sorted and
+/// deduplicated
+pub(crate) fn with_packed(
+    repo: &Repo,
+    mut hashes: Vec<Hash>,
+    kind: PackedKind,
+) -> Result<Vec<Hash>> {
+    hashes.extend(list_packed(repo, kind)?);
+    hashes.sort();
+    hashes.dedup();
+    Ok(hashes)
+}
+
+/// list the packed objects of one kind, sorted and deduplicated
+pub fn list_packed(repo: &Repo, kind: PackedKind) -> Result<Vec<Hash>> {
+    Ok(list_packed_sizes(repo, kind)?
+        .into_iter()
+        .map(|(hash, _)| hash)
+        .collect())
+}
+
+/// list the packed objects of one kind with their stored sizes, sorted and
+/// deduplicated
+pub fn list_packed_sizes(repo: &Repo, kind: PackedKind) -> Result<Vec<(Hash, u64)>> {
+    repo.pack_cache().refresh(repo)?;
+    let mut objects: Vec<(Hash, u64)> = repo
+        .pack_cache()
+        .snapshot()
+        .iter()
+        .flat_map(|i| {
+            i.entries
+                .iter()
+                .filter(|e| e.kind == kind)
+                .map(|e| (e.hash, e.len))
+        })
+        .collect();
+    objects.sort();
+    objects.dedup_by_key(|(hash, _)| *hash);
+    Ok(objects)
+}
+
+/// read a tree or commit's stored bytes, loose or packed
+pub(crate) fn read_stored(repo: &Repo, kind: PackedKind, hash: &Hash) -> Result<Vec<u8>> {
+    let path = kind.loose_path(repo, hash);
+    match fs::read(&path) {
+        Ok(data) => Ok(data),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            read_packed(repo, kind, hash)?.ok_or(Error::ObjectNotFound(*hash))
+        }
+        Err(e) => Err(Error::Io { path, source: e }),
+    }
+}
+
+/// write objects' stored bytes into a new pack, returning its path
+///
+/// the loose copies are left in place; removing them is up to the caller
+/// once this returns.
+pub fn write_pack(repo: &Repo, objects: &[(PackedKind, Hash, Vec<u8>)]) -> Result<PathBuf> {
+    let dir = repo.packs_path();
+    fs::create_dir_all(&dir).with_path(&dir)?;
+
+    let mut entries = Vec::with_capacity(objects.len());
+    let mut pack = PACK_MAGIC.to_vec();
+    for (kind, hash, data) in objects {
+        entries.push(IndexEntry {
+            kind: *kind,
+            hash: *hash,
+            offset: pack.len() as u64,
+            len: data.len() as u64,
+        });
+        pack.extend_from_slice(data);
+    }
+    entries.sort_by_key(|e| (e.kind, e.hash));
+    entries.dedup_by_key(|e| (e.kind, e.hash));
+
+    let mut index = INDEX_MAGIC.to_vec();
+    for entry in &entries {
+        index.push(entry.kind.tag());
+        index.extend_from_slice(entry.hash.as_bytes());
+        index.extend_from_slice(&entry.offset.to_le_bytes());
+        index.extend_from_slice(&entry.len.to_le_bytes());
+    }
+
+    let tmp_pack = write_tmp(repo, &pack)?;
+    let tmp_index = write_tmp(repo, &index)?;
+    let tmp_pack_path = tmp_pack.path();
+
+    // claim the next free number; hard_link fails rather than replace a
+    // pack another writer just took
+    let mut number = index_names(&dir)?
+        .iter()
+        .filter_map(|n| n.parse::<u32>().ok())
+        .max()
+        .map_or(0, |n| n + 1);
+    let pack_path = loop {
+        let path = dir.join(format!("{:04}.pack", number));
+        match fs::hard_link(tmp_pack_path, &path) {
+            Ok(()) => break path,
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => number += 1,
+            Err(e) => return Err(Error::Io { path, source: e }),
+        }
+    };
+    drop(tmp_pack);
+    let index_path = pack_path.with_extension("idx");
+    fs::rename(tmp_index.path(), &index_path).with_path(&index_path)?;
+    tmp_index.into_persisted();
+
+    let dir_file = File::open(&dir).with_path(&dir)?;
+    dir_file.sync_all().with_path(&dir)?;
+
+    Ok(pack_path)
+}
+
+/// rewrite every pack into one without the `drop` objects, then remove the
+/// old packs
+///
+/// the new pack is in place before the old ones go, so a reader finds a kept
+/// object in one or the other. it is written even when nothing is kept, so
+/// pack numbers keep growing and a removed pack's name is never reused.
+pub(crate) fn repack_without(repo: &Repo, drop: &HashSet<(PackedKind, Hash)>) -> Result<()> {
+    let cache = repo.pack_cache();
+    cache.refresh(repo)?;
+    let indexes = cache.snapshot();
+
+    let mut kept = Vec::new();
+    for index in &indexes {
+        let file = File::open(&index.pack).with_path(&index.pack)?;
+        for entry in &index.entries {
+            if drop.contains(&(entry.kind, entry.hash)) {
+                continue;
+            }
+            let mut data = vec![0; entry.len as usize];
+            file.read_exact_at(&mut data, entry.offset)
+                .with_path(&index.pack)?;
+            kept.push((entry.kind, entry.hash, data));
+        }
+    }
+    write_pack(repo, &kept)?;
+
+    // index first, so the pack is never listed without its file
+    for index in &indexes {
+        for path in [index.pack.with_extension("idx"), index.pack.clone()] {
+            match fs::remove_file(&path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(Error::Io { path, source: e }),
+            }
+        }
+    }
+    cache.refresh(repo)
+}
+
+fn write_tmp(repo: &Repo, data: &[u8]) -> Result<TempFile> {
+    let tmp = TempFile::new_in(&repo.objects_tmp_path());
+    let tmp_path = tmp.path();
+    let mut tmp_file = File::create(tmp_path).with_path(tmp_path)?;
+    tmp_file.write_all(data).with_path(tmp_path)?;
+    tmp_file.sync_all().with_path(tmp_path)?;
+    Ok(tmp)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::object::tree::tree_exists;
+    use crate::object::{read_commit, read_tree, write_commit, write_tree};
+    use crate::transport::local::list_all_objects;
+    use crate::types::{Commit, EntryKind, Tree, TreeEntry};
+    use tempfile::tempdir;
+
+    /// move every loose tree and commit into a new pack
+    pub(crate) fn pack_loose(repo: &Repo) {
+        let all = list_all_objects(repo).unwrap();
+        let mut objects = Vec::new();
+        for (kind, hashes) in [
+            (PackedKind::Tree, &all.trees),
+            (PackedKind::Commit, &all.commits),
+        ] {
+            for hash in hashes {
+                let path = kind.loose_path(repo, hash);
+                objects.push((kind, *hash, fs::read(&path).unwrap()));
+            }
+        }
+        write_pack(repo, &objects).unwrap();
+        for (kind, hash, _) in &objects {
+            fs::remove_file(kind.loose_path(repo, hash)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_read_packed_tree_and_commit() {
+        let dir = tempdir().unwrap();
+        let repo = Repo::init(&dir.path().join("repo")).unwrap();
+
+        let tree = Tree::new(vec![]).unwrap();
+        let tree_hash = write_tree(&repo, &tree).unwrap();
+        let commit = Commit::new(tree_hash, vec![], "test", "packed");
+        let commit_hash = write_commit(&repo, &commit).unwrap();
+
+        let mut objects = Vec::new();
+        for (kind, hash) in [
+            (PackedKind::Tree, tree_hash),
+            (PackedKind::Commit, commit_hash),
+        ] {
+            let path = kind.loose_path(&repo, &hash);
+            objects.push((kind, hash, fs::read(&path).unwrap()));
+        }
+        // a fresh handle, so nothing is served from a loaded index
+        let pack = write_pack(&repo, &objects).unwrap();
+        assert!(pack.ends_with("0000.pack"));
+        for (kind, hash, _) in &objects {
+            fs::remove_file(kind.loose_path(&repo, hash)).unwrap();
+        }
+
+        let repo = Repo::open(repo.path()).unwrap();
+        assert!(tree_exists(&repo, &tree_hash));
+        assert_eq!(read_tree(&repo, &tree_hash).unwrap(), tree);
+        assert_eq!(read_commit(&repo, &commit_hash).unwrap().message, "packed");
+        assert_eq!(
+            list_packed(&repo, PackedKind::Commit).unwrap(),
+            vec![commit_hash]
+        );
+        assert!(!packed_exists(&repo, PackedKind::Commit, &tree_hash));
+
+        // a second pack takes the next number and is found after the first was loaded
+        let entry = TreeEntry::new(
+            "f",
+            EntryKind::regular(Hash::from_bytes([1; 32]), 0, vec![]),
+        );
+        let other = write_tree(&repo, &Tree::new(vec![entry]).unwrap()).unwrap();
+        let data = fs::read(PackedKind::Tree.loose_path(&repo, &other)).unwrap();
+        let pack = write_pack(&repo, &[(PackedKind::Tree, other, data)]).unwrap();
+        assert!(pack.ends_with("0001.pack"));
+        fs::remove_file(PackedKind::Tree.loose_path(&repo, &other)).unwrap();
+        assert!(read_tree(&repo, &other).is_ok());
+    }
+
+    #[test]
+    fn test_corrupt_pack_index() {
+        let dir = tempdir().unwrap();
+        let repo = Repo::init(&dir.path().join("repo")).unwrap();
+        fs::create_dir_all(repo.packs_path()).unwrap();
+        fs::write(repo.packs_path().join("0000.idx"), b"ZUBIDX01short").unwrap();
+
+        let hash = Hash::from_bytes([7; 32]);
+        assert!(matches!(
+            read_packed(&repo, PackedKind::Tree, &hash),
+            Err(Error::CorruptPackIndex(_))
+        ));
+    }
+}
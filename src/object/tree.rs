@@ -1,9 +1,12 @@
 use std::fs::{self, File};
 use std::io::Write;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use crate::error::{Error, IoResultExt, Result};
+use crate::fs::TempFile;
 use crate::hash::Hash;
+use crate::object::WriteOutcome;
 use crate::repo::Repo;
 use crate::types::Tree;
 
@@ -12,15 +15,18 @@ use crate::types::Tree;
 /// trees are serialized as CBOR, then zstd compressed.
 /// the hash is computed over the compressed bytes.
 pub fn write_tree(repo: &Repo, tree: &Tree) -> Result<Hash> {
+    write_tree_outcome(repo, tree).map(|o| o.hash)
+}
+
+/// like `write_tree`, also telling whether the tree was newly stored
+pub fn write_tree_outcome(repo: &Repo, tree: &Tree) -> Result<WriteOutcome> {
     // serialize to cbor
     let mut cbor_bytes = Vec::new();
     ciborium::into_writer(tree, &mut cbor_bytes)?;
 
-    // compress with zstd (level 3 - fast, reasonable ratio)
-    let compressed = zstd::encode_all(&cbor_bytes[..], 3).map_err(|e| Error::Io {
-        path: PathBuf::from("<zstd>"),
-        source: e,
-    })?;
+    // compress with zstd (level 3 - fast, reasonable ratio), using the
+    // repo's dictionary if it was created with one
+    let compressed = super::compress_object(repo, &cbor_bytes)?;
 
     // hash the compressed bytes
     let hash = Hash::from_bytes(*blake3::hash(&compressed).as_bytes());
@@ -31,44 +37,54 @@ pub fn write_tree(repo: &Repo, tree: &Tree) -> Result<Hash> {
 
     // dedup: if tree already exists, we're done
     if tree_path.exists() {
-        return Ok(hash);
+        return Ok(WriteOutcome {
+            hash,
+            created: false,
+        });
     }
 
     // ensure directory exists
     fs::create_dir_all(&tree_dir).with_path(&tree_dir)?;
 
     // atomic write: temp -> fsync -> rename
-    let tmp_path = repo.tmp_path().join(uuid::Uuid::new_v4().to_string());
+    let tmp = TempFile::new_in(&repo.objects_tmp_path());
+    let tmp_path = tmp.path();
     {
-        let mut tmp_file = File::create(&tmp_path).with_path(&tmp_path)?;
-        tmp_file.write_all(&compressed).with_path(&tmp_path)?;
-        tmp_file.sync_all().with_path(&tmp_path)?;
+        let mut tmp_file = File::create(tmp_path).with_path(tmp_path)?;
+        tmp_file.write_all(&compressed).with_path(tmp_path)?;
+        tmp_file.sync_all().with_path(tmp_path)?;
     }
 
     // rename to final location
-    fs::rename(&tmp_path, &tree_path).with_path(&tree_path)?;
+    fs::rename(tmp_path, &tree_path).with_path(&tree_path)?;
+    tmp.into_persisted();
 
     // fsync parent directory
     let dir_file = File::open(&tree_dir).with_path(&tree_dir)?;
     dir_file.sync_all().with_path(&tree_dir)?;
 
-    Ok(hash)
+    Ok(WriteOutcome {
+        hash,
+        created: true,
+    })
 }
 
 /// read a tree from the object store
 pub fn read_tree(repo: &Repo, hash: &Hash) -> Result<Tree> {
+    read_tree_shared(repo, hash).map(Arc::unwrap_or_clone)
+}
+
+/// read a tree, sharing it with the repo's object cache instead of copying
+pub(crate) fn read_tree_shared(repo: &Repo, hash: &Hash) -> Result<Arc<Tree>> {
+    if let Some(tree) = repo.object_cache().and_then(|c| c.get_tree(hash)) {
+        return Ok(tree);
+    }
+
     let path = tree_path(repo, hash);
+    #[cfg(test)]
+    tests::record_tree_read();
 
-    let compressed = fs::read(&path).map_err(|e| {
-        if e.kind() == std::io::ErrorKind::NotFound {
-            Error::ObjectNotFound(*hash)
-        } else {
-            Error::Io {
-                path: path.clone(),
-                source: e,
-            }
-        }
-    })?;
+    let compressed = super::read_stored(repo, super::PackedKind::Tree, hash)?;
 
     // verify hash
     let actual_hash = Hash::from_bytes(*blake3::hash(&compressed).as_bytes());
@@ -77,35 +93,55 @@ pub fn read_tree(repo: &Repo, hash: &Hash) -> Result<Tree> {
     }
 
     // decompress
-    let cbor_bytes = zstd::decode_all(&compressed[..]).map_err(|e| Error::Io {
-        path: path.clone(),
-        source: e,
-    })?;
+    let cbor_bytes = super::decompress_object(
+        &compressed,
+        repo.zstd_dictionary(),
+        repo.config().max_object_size,
+        hash,
+        &path,
+    )?;
 
     // deserialize
-    let tree: Tree = ciborium::from_reader(&cbor_bytes[..])?;
+    let tree: Arc<Tree> = Arc::new(ciborium::from_reader(&cbor_bytes[..])?);
+
+    if let Some(cache) = repo.object_cache() {
+        cache.insert_tree(*hash, tree.clone());
+    }
 
     Ok(tree)
 }
 
-/// get the filesystem path to a tree object
+/// get the filesystem path to a tree object, in an alternate if only it has one
 pub fn tree_path(repo: &Repo, hash: &Hash) -> PathBuf {
-    let (dir, file) = hash.to_path_components();
-    repo.trees_path().join(dir).join(file)
+    super::locate_object(repo, "trees", hash)
 }
 
 /// check if a tree exists in the object store
 #[allow(dead_code)]
 pub fn tree_exists(repo: &Repo, hash: &Hash) -> bool {
-    tree_path(repo, hash).exists()
+    tree_path(repo, hash).exists() || super::packed_exists(repo, super::PackedKind::Tree, hash)
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::types::{EntryKind, TreeEntry};
+    use std::cell::Cell;
     use tempfile::tempdir;
 
+    thread_local! {
+        static TREE_READS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    pub(super) fn record_tree_read() {
+        TREE_READS.with(|reads| reads.set(reads.get() + 1));
+    }
+
+    /// trees read from disk on this thread so far
+    pub(crate) fn tree_reads() -> usize {
+        TREE_READS.with(Cell::get)
+    }
+
     fn test_repo() -> (tempfile::TempDir, Repo) {
         let dir = tempdir().unwrap();
         let repo_path = dir.path().join("repo");
@@ -143,6 +179,40 @@ mod tests {
         assert_eq!(h1, h2);
     }
 
+    #[test]
+    fn test_read_tree_rejects_decompression_bomb() {
+        let (_dir, mut repo) = test_repo();
+        repo.config_mut().max_object_size = 1 << 20;
+
+        // 64 MiB of zeros squeezed into a few KiB
+        let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), 19).unwrap();
+        let chunk = vec![0u8; 1 << 20];
+        for _ in 0..64 {
+            encoder.write_all(&chunk).unwrap();
+        }
+        let bomb = encoder.finish().unwrap();
+        assert!(bomb.len() < 64 << 10);
+
+        let hash = Hash::from_bytes(*blake3::hash(&bomb).as_bytes());
+        let path = tree_path(&repo, &hash);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &bomb).unwrap();
+
+        assert!(matches!(
+            read_tree(&repo, &hash),
+            Err(Error::CorruptObject(h)) if h == hash
+        ));
+
+        // ordinary trees stay well within the limit
+        let tree = Tree::new(vec![TreeEntry::new(
+            "file.txt",
+            EntryKind::regular(Hash::ZERO, 100, vec![]),
+        )])
+        .unwrap();
+        let hash = write_tree(&repo, &tree).unwrap();
+        assert_eq!(read_tree(&repo, &hash).unwrap(), tree);
+    }
+
     #[test]
     fn test_empty_tree() {
         let (_dir, repo) = test_repo();
@@ -183,6 +253,7 @@ mod tests {
                     gid: 6,
                     mode: 0o660,
                     xattrs: vec![],
+                    times: None,
                 },
             ),
             TreeEntry::new(
@@ -194,6 +265,7 @@ mod tests {
                     gid: 0,
                     mode: 0o666,
                     xattrs: vec![],
+                    times: None,
                 },
             ),
             TreeEntry::new(
@@ -203,6 +275,7 @@ mod tests {
                     gid: 0,
                     mode: 0o644,
                     xattrs: vec![],
+                    times: None,
                 },
             ),
             TreeEntry::new("hardlink", EntryKind::hardlink("regular")),
@@ -0,0 +1,289 @@
+//! commit ancestry queries
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::error::{Error, Result};
+use crate::hash::Hash;
+use crate::object::read_commit_header;
+use crate::repo::Repo;
+
+/// check if `ancestor` is reachable from `descendant` (a commit is its own ancestor)
+///
+/// missing commits end the walk along that path, so truncated histories
+/// simply report false rather than erroring.
+///
+/// when `ancestor` has a generation number, commits whose generation is no
+/// higher can't lead to it and aren't walked past. commits without one are
+/// walked through as before.
+pub fn is_ancestor(repo: &Repo, ancestor: &Hash, descendant: &Hash) -> Result<bool> {
+    if ancestor == descendant {
+        return Ok(true);
+    }
+
+    let target_generation = match read_commit_header(repo, ancestor) {
+        Ok(commit) => commit.generation,
+        Err(Error::ObjectNotFound(_)) => None,
+        Err(e) => return Err(e),
+    };
+
+    let mut to_visit = vec![*descendant];
+    let mut visited = HashSet::new();
+
+    while let Some(hash) = to_visit.pop() {
+        if hash == *ancestor {
+            return Ok(true);
+        }
+        if !visited.insert(hash) {
+            continue;
+        }
+        let commit = match read_commit_header(repo, &hash) {
+            Ok(commit) => commit,
+            Err(Error::ObjectNotFound(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        // everything behind a commit has a lower generation than it
+        if let (Some(target), Some(generation)) = (target_generation, commit.generation) {
+            if generation <= target {
+                continue;
+            }
+        }
+        to_visit.extend(commit.parents);
+    }
+
+    Ok(false)
+}
+
+/// generation number for a new commit with `parents`
+///
+/// 0 for a root, else one more than the highest parent generation. none if
+/// any parent is missing or predates generation numbers, so the property
+/// that a commit outranks all its ancestors is never broken.
+pub fn next_generation(repo: &Repo, parents: &[Hash]) -> Result<Option<u64>> {
+    let mut highest: Option<u64> = None;
+    for parent in parents {
+        let generation = match read_commit_header(repo, parent) {
+            Ok(commit) => commit.generation,
+            Err(Error::ObjectNotFound(_)) => None,
+            Err(e) => return Err(e),
+        };
+        let Some(generation) = generation else {
+            return Ok(None);
+        };
+        highest = Some(highest.map_or(generation, |h| h.max(generation)));
+    }
+    Ok(Some(highest.map_or(0, |h| h + 1)))
+}
+
+/// find the best common ancestor of two commits
+///
+/// walks `b`'s history breadth-first against the ancestor set of `a`, then
+/// drops candidates that are ancestors of other candidates. when several
+/// remain (criss-cross merges), the one closest to `b` is returned.
+pub fn merge_base(repo: &Repo, a: &Hash, b: &Hash) -> Result<Option<Hash>> {
+    let ancestors_a = ancestors(repo, a)?;
+
+    let mut candidates = Vec::new();
+    let mut queue = VecDeque::from([*b]);
+    let mut visited = HashSet::new();
+
+    while let Some(hash) = queue.pop_front() {
+        if !visited.insert(hash) {
+            continue;
+        }
+        if ancestors_a.contains(&hash) {
+            // everything behind a common ancestor is common too, and worse
+            candidates.push(hash);
+            continue;
+        }
+        if let Some(parents) = parents(repo, &hash)? {
+            queue.extend(parents);
+        }
+    }
+
+    for candidate in &candidates {
+        let mut dominated = false;
+        for other in &candidates {
+            if other != candidate && is_ancestor(repo, candidate, other)? {
+                dominated = true;
+                break;
+            }
+        }
+        if !dominated {
+            return Ok(Some(*candidate));
+        }
+    }
+
+    Ok(None)
+}
+
+/// all commits reachable from `start`, including itself
+fn ancestors(repo: &Repo, start: &Hash) -> Result<HashSet<Hash>> {
+    let mut visited = HashSet::new();
+    let mut to_visit = vec![*start];
+
+    while let Some(hash) = to_visit.pop() {
+        if !visited.insert(hash) {
+            continue;
+        }
+        if let Some(parents) = parents(repo, &hash)? {
+            to_visit.extend(parents);
+        }
+    }
+
+    Ok(visited)
+}
+
+/// parents of a commit, none if the commit is missing
+fn parents(repo: &Repo, hash: &Hash) -> Result<Option<Vec<Hash>>> {
+    match read_commit_header(repo, hash) {
+        Ok(commit) => Ok(Some(commit.parents)),
+        Err(Error::ObjectNotFound(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{write_commit, write_tree};
+    use crate::ops::commit::commit;
+    use crate::types::{Commit, Tree};
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn test_repo() -> (tempfile::TempDir, Repo) {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        let repo = Repo::init(&repo_path).unwrap();
+        (dir, repo)
+    }
+
+    /// write an empty-tree commit with the given parents
+    fn make_commit(repo: &Repo, parents: Vec<Hash>, message: &str) -> Hash {
+        let tree = write_tree(repo, &Tree::empty()).unwrap();
+        write_commit(repo, &Commit::new(tree, parents, "test", message)).unwrap()
+    }
+
+    /// like `make_commit`, recording a generation number
+    fn make_commit_with_generation(repo: &Repo, parents: Vec<Hash>, message: &str) -> Hash {
+        let tree = write_tree(repo, &Tree::empty()).unwrap();
+        let generation = next_generation(repo, &parents).unwrap().unwrap();
+        let commit = Commit::new(tree, parents, "test", message).with_generation(generation);
+        write_commit(repo, &commit).unwrap()
+    }
+
+    fn generation(repo: &Repo, hash: &Hash) -> Option<u64> {
+        read_commit_header(repo, hash).unwrap().generation
+    }
+
+    #[test]
+    fn test_generation_numbers() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "v1").unwrap();
+        let first = commit(&repo, &source, "test", Some("v1"), None).unwrap();
+        fs::write(source.join("file.txt"), "v2").unwrap();
+        let second = commit(&repo, &source, "test", Some("v2"), None).unwrap();
+        assert_eq!(generation(&repo, &first), Some(0));
+        assert_eq!(generation(&repo, &second), Some(1));
+
+        // a merge outranks its highest parent
+        let side = make_commit_with_generation(&repo, vec![], "side");
+        let merge = make_commit_with_generation(&repo, vec![second, side], "merge");
+        assert_eq!(generation(&repo, &merge), Some(2));
+
+        // history without generations stays without them
+        let old = make_commit(&repo, vec![], "old");
+        assert_eq!(next_generation(&repo, &[old]).unwrap(), None);
+        assert_eq!(next_generation(&repo, &[second, old]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_is_ancestor_with_generations_matches_walk() {
+        let (_dir, repo) = test_repo();
+
+        // the same graph twice, with and without generation numbers:
+        //   0 - 1 - 2 - 3 ----- 6
+        //        \- 4 - 5 -/
+        //   7 (unrelated)
+        let shape: [(&str, &[usize]); 8] = [
+            ("0", &[]),
+            ("1", &[0]),
+            ("2", &[1]),
+            ("3", &[2]),
+            ("4", &[1]),
+            ("5", &[4]),
+            ("6", &[3, 5]),
+            ("7", &[]),
+        ];
+        let mut plain: Vec<Hash> = Vec::new();
+        let mut numbered: Vec<Hash> = Vec::new();
+        for (name, parents) in shape {
+            let plain_parents = parents.iter().map(|&i| plain[i]).collect();
+            plain.push(make_commit(&repo, plain_parents, name));
+            let numbered_parents = parents.iter().map(|&i| numbered[i]).collect();
+            numbered.push(make_commit_with_generation(&repo, numbered_parents, name));
+        }
+        assert_eq!(generation(&repo, &numbered[6]), Some(4));
+
+        for a in 0..shape.len() {
+            for b in 0..shape.len() {
+                assert_eq!(
+                    is_ancestor(&repo, &numbered[a], &numbered[b]).unwrap(),
+                    is_ancestor(&repo, &plain[a], &plain[b]).unwrap(),
+                    "is_ancestor({}, {})",
+                    a,
+                    b
+                );
+            }
+        }
+        assert!(is_ancestor(&repo, &numbered[4], &numbered[6]).unwrap());
+        assert!(!is_ancestor(&repo, &numbered[4], &numbered[3]).unwrap());
+    }
+
+    #[test]
+    fn test_is_ancestor() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+
+        fs::write(source.join("file.txt"), "v1").unwrap();
+        let hash1 = commit(&repo, &source, "test", Some("v1"), None).unwrap();
+
+        fs::write(source.join("file.txt"), "v2").unwrap();
+        let hash2 = commit(&repo, &source, "test", Some("v2"), None).unwrap();
+
+        fs::write(source.join("file.txt"), "v3").unwrap();
+        let hash3 = commit(&repo, &source, "test", Some("v3"), None).unwrap();
+
+        assert!(is_ancestor(&repo, &hash1, &hash3).unwrap());
+        assert!(is_ancestor(&repo, &hash1, &hash2).unwrap());
+        assert!(is_ancestor(&repo, &hash2, &hash3).unwrap());
+        assert!(!is_ancestor(&repo, &hash3, &hash1).unwrap());
+        assert!(is_ancestor(&repo, &hash2, &hash2).unwrap());
+    }
+
+    #[test]
+    fn test_merge_base_diamond() {
+        let (_dir, repo) = test_repo();
+
+        //   root - base - left  - merge
+        //              \- right -/
+        let root = make_commit(&repo, vec![], "root");
+        let base = make_commit(&repo, vec![root], "base");
+        let left = make_commit(&repo, vec![base], "left");
+        let right = make_commit(&repo, vec![base], "right");
+        let merge = make_commit(&repo, vec![left, right], "merge");
+
+        assert_eq!(merge_base(&repo, &left, &right).unwrap(), Some(base));
+        assert_eq!(merge_base(&repo, &right, &left).unwrap(), Some(base));
+        assert_eq!(merge_base(&repo, &merge, &right).unwrap(), Some(right));
+        assert_eq!(merge_base(&repo, &left, &left).unwrap(), Some(left));
+
+        let unrelated = make_commit(&repo, vec![], "unrelated");
+        assert_eq!(merge_base(&repo, &left, &unrelated).unwrap(), None);
+    }
+}
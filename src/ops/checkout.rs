@@ -1,17 +1,26 @@
-use std::fs;
-use std::path::Path;
-
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use crate::diagnostics::{sink_or_stderr, Diagnostics, Warning};
 use crate::error::{Error, IoResultExt, Result};
 use crate::fs::{
-    apply_metadata_graceful, create_block_device, create_char_device, create_fifo,
-    create_hardlink, create_socket_placeholder, create_symlink, write_sparse_file,
-    CheckoutHardlinkTracker,
+    apply_metadata_reporting, create_block_device, create_char_device, create_fifo,
+    create_hardlink, create_socket_placeholder, create_symlink, read_xattrs, set_times,
+    write_sparse_file, write_zero_filled_file, CheckoutHardlinkTracker, FileMetadata, FileType,
 };
 use crate::hash::Hash;
-use crate::object::{blob_path, read_blob, read_commit, read_tree};
+use crate::namespace::{inside_to_outside, remap_acl_xattrs};
+use crate::object::{
+    blob_data_path, blob_meta_path, blob_path, read_blob, read_blob_data, read_commit, read_tree,
+};
+use crate::ops::union::ConflictResolution;
 use crate::refs::resolve_ref;
 use crate::repo::Repo;
-use crate::types::{EntryKind, Tree, Xattr};
+use crate::types::{EntryKind, EntryTimes, SparseRegion, Tree, Xattr};
 
 /// checkout options
 #[derive(Clone)]
@@ -22,20 +31,78 @@ pub struct CheckoutOptions {
     pub hardlink: bool,
     /// preserve sparse file holes
     pub preserve_sparse: bool,
+    /// prepend this path to absolute symlink targets (relative ones are kept)
+    pub symlink_prefix: Option<PathBuf>,
+    /// allow a non-empty target and leave entries that already match the tree
+    /// untouched; differing entries are rewritten, extra ones are kept
+    pub skip_if_matches: bool,
+    /// write the privileged xattrs that couldn't be set here, in
+    /// `getfattr --dump` format, for `setfattr --restore` run as root from
+    /// the checkout directory
+    pub xattr_sidecar: Option<PathBuf>,
+    /// allow a non-empty target and lay the tree over it, like one step of
+    /// `checkout_union`: directories merge, and an existing non-directory at
+    /// a path the tree also has is resolved per `on_conflict` (`First` keeps
+    /// what's there, `Last` replaces it)
+    pub overlay: bool,
+    /// conflict resolution for `overlay`
+    pub on_conflict: ConflictResolution,
+    /// where skipped device nodes, sockets and xattrs are reported;
+    /// stderr when `None`
+    pub diagnostics: Option<Arc<dyn Diagnostics>>,
+    /// record completed entries in `CHECKOUT_PROGRESS_FILE` in the target,
+    /// so an interrupted checkout picks up where it stopped: entries listed
+    /// there that still match the tree are skipped, the manifest is removed
+    /// once the checkout completes
+    pub resume: bool,
+    /// translate the uids/gids named in POSIX ACL xattrs back to outside
+    /// ids; for trees committed with `CommitOptions::remap_acls`
+    pub remap_acls: bool,
 }
 
+/// progress manifest `resume` keeps in the checkout target
+pub const CHECKOUT_PROGRESS_FILE: &str = ".zub-checkout-progress";
+
 impl Default for CheckoutOptions {
     fn default() -> Self {
         Self {
             force: false,
             hardlink: true,
             preserve_sparse: false,
+            symlink_prefix: None,
+            skip_if_matches: false,
+            xattr_sidecar: None,
+            overlay: false,
+            on_conflict: ConflictResolution::Error,
+            diagnostics: None,
+            resume: false,
+            remap_acls: false,
         }
     }
 }
 
+/// checkout statistics (directories aren't counted)
+#[derive(Debug, Default)]
+pub struct CheckoutStats {
+    /// entries created or rewritten
+    pub written: usize,
+    /// entries left as found: already matching (`skip_if_matches`), kept
+    /// by `overlay` with `ConflictResolution::First`, or completed by the
+    /// run `resume` picked up from
+    pub skipped: usize,
+    /// `security.*`/`trusted.*` xattrs (e.g. file capabilities) that need
+    /// privileges we don't have, and any xattr the target filesystem
+    /// doesn't support, by path
+    pub unapplied_xattrs: Vec<(String, Xattr)>,
+}
+
 /// checkout a ref to a target directory
-pub fn checkout(repo: &Repo, ref_name: &str, target: &Path, opts: CheckoutOptions) -> Result<()> {
+pub fn checkout(
+    repo: &Repo,
+    ref_name: &str,
+    target: &Path,
+    opts: CheckoutOptions,
+) -> Result<CheckoutStats> {
     // resolve ref to commit
     let commit_hash = resolve_ref(repo, ref_name)?;
     let commit = read_commit(repo, &commit_hash)?;
@@ -49,13 +116,14 @@ pub fn checkout_from_tree_hash(
     tree_hash: &Hash,
     target: &Path,
     opts: CheckoutOptions,
-) -> Result<()> {
+) -> Result<CheckoutStats> {
     // load root tree
     let tree = read_tree(repo, tree_hash)?;
 
-    // check target
+    // check target; a checkout being resumed has already filled it
+    let resuming = opts.resume && target.join(CHECKOUT_PROGRESS_FILE).exists();
     if target.exists() {
-        if !opts.force {
+        if !opts.force && !opts.skip_if_matches && !opts.overlay && !resuming {
             // check if empty
             let is_empty = target.read_dir().with_path(target)?.next().is_none();
             if !is_empty {
@@ -69,6 +137,13 @@ pub fn checkout_from_tree_hash(
     // checkout tree, collecting pending hardlinks
     let mut hardlink_tracker = CheckoutHardlinkTracker::new();
     let mut pending_hardlinks = Vec::new();
+    let mut pending_dirs = Vec::new();
+    let mut pending_times = Vec::new();
+    let mut stats = CheckoutStats::default();
+    let mut progress = match opts.resume {
+        true => Some(CheckoutProgress::open(target)?),
+        false => None,
+    };
     checkout_tree(
         repo,
         &tree,
@@ -76,7 +151,11 @@ pub fn checkout_from_tree_hash(
         "",
         &mut hardlink_tracker,
         &mut pending_hardlinks,
+        &mut pending_dirs,
+        &mut pending_times,
+        &mut progress,
         &opts,
+        &mut stats,
     )?;
 
     // create all hardlinks now that all files are checked out
@@ -85,23 +164,192 @@ pub fn checkout_from_tree_hash(
             .get(&pending.target_path)
             .ok_or_else(|| Error::HardlinkTargetNotFound(pending.target_path.clone()))?;
 
+        if opts.skip_if_matches || progress.is_some() {
+            if let Ok(meta) = fs::symlink_metadata(&pending.entry_path) {
+                let done = progress
+                    .as_ref()
+                    .is_some_and(|p| p.is_done(&pending.logical_path));
+                let target_meta = fs::symlink_metadata(target_fs_path).with_path(target_fs_path)?;
+                if (opts.skip_if_matches || done)
+                    && meta.dev() == target_meta.dev()
+                    && meta.ino() == target_meta.ino()
+                {
+                    stats.skipped += 1;
+                    continue;
+                }
+                remove_path(&pending.entry_path, &meta)?;
+            }
+        }
+
         create_hardlink(&pending.entry_path, target_fs_path)?;
+        mark_done(&mut progress, &pending.logical_path)?;
+        stats.written += 1;
     }
 
-    Ok(())
+    // directory metadata goes on only now, deepest first, so a read-only
+    // directory can't lock out the hardlinks placed into it above
+    let diag = sink_or_stderr(&opts.diagnostics);
+    for dir in pending_dirs {
+        let unapplied =
+            apply_metadata_reporting(&dir.path, dir.uid, dir.gid, dir.mode, &dir.xattrs, diag)?;
+        record_unapplied(&mut stats, &dir.logical_path, unapplied);
+    }
+
+    // last, so creating hardlinks doesn't bump directory mtimes again
+    for (path, times) in pending_times {
+        set_times(&path, &times)?;
+    }
+
+    if let Some(sidecar) = &opts.xattr_sidecar {
+        write_xattr_sidecar(sidecar, &stats.unapplied_xattrs)?;
+    }
+
+    if let Some(progress) = progress {
+        progress.finish()?;
+    }
+
+    Ok(stats)
+}
+
+/// the non-directory entries a resumable checkout has completed
+///
+/// one escaped logical path per line, each appended with a single write as
+/// its entry is done, so an interrupted run leaves at most a torn last line.
+/// entries listed are still checked against the tree before being skipped.
+struct CheckoutProgress {
+    path: PathBuf,
+    done: HashSet<String>,
+    file: File,
+}
+
+impl CheckoutProgress {
+    /// open the manifest in `target`, loading what an earlier run completed
+    fn open(target: &Path) -> Result<Self> {
+        let path = target.join(CHECKOUT_PROGRESS_FILE);
+        let done = match fs::read(&path) {
+            Ok(data) => data
+                .split_inclusive(|&b| b == b'\n')
+                .filter_map(|line| line.strip_suffix(b"\n"))
+                .map(unescape_progress_line)
+                .collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(Error::Io { path, source: e }),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_path(&path)?;
+        Ok(Self { path, done, file })
+    }
+
+    fn is_done(&self, logical_path: &str) -> bool {
+        self.done.contains(logical_path)
+    }
+
+    fn record(&mut self, logical_path: &str) -> Result<()> {
+        let mut line = escape_progress_line(logical_path);
+        line.push('\n');
+        self.file.write_all(line.as_bytes()).with_path(&self.path)
+    }
+
+    /// the checkout completed; drop the manifest
+    fn finish(self) -> Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path).with_path(&self.path)
+    }
+}
+
+fn mark_done(progress: &mut Option<CheckoutProgress>, logical_path: &str) -> Result<()> {
+    match progress {
+        Some(progress) => progress.record(logical_path),
+        None => Ok(()),
+    }
+}
+
+/// escape backslashes and newlines so each path stays on one line
+fn escape_progress_line(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape_progress_line(line: &[u8]) -> String {
+    let line = String::from_utf8_lossy(line);
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+/// write xattrs in the `getfattr --dump` format `setfattr --restore` reads,
+/// with paths relative to the checkout root and hex-encoded values
+fn write_xattr_sidecar(path: &Path, xattrs: &[(String, Xattr)]) -> Result<()> {
+    let mut out = String::new();
+    let mut current: Option<&str> = None;
+    for (entry_path, xattr) in xattrs {
+        if current != Some(entry_path) {
+            if current.is_some() {
+                out.push('\n');
+            }
+            out.push_str(&format!("# file: {}\n", escape_getfattr(entry_path)));
+            current = Some(entry_path);
+        }
+        let value: String = xattr.value.iter().map(|b| format!("{:02x}", b)).collect();
+        out.push_str(&format!("{}=0x{}\n", escape_getfattr(&xattr.name), value));
+    }
+    if current.is_some() {
+        out.push('\n');
+    }
+    fs::write(path, out).with_path(path)
+}
+
+/// octal-escape what getfattr would: control bytes, backslash, `=`, non-ascii
+fn escape_getfattr(s: &str) -> String {
+    let mut out = String::new();
+    for &b in s.as_bytes() {
+        if !(0x20..0x7f).contains(&b) || b == b'\\' || b == b'=' {
+            out.push_str(&format!("\\{:03o}", b));
+        } else {
+            out.push(b as char);
+        }
+    }
+    out
 }
 
 /// pending hardlink to be created after all files are checked out
 struct PendingHardlink {
     entry_path: std::path::PathBuf,
+    logical_path: String,
     target_path: String,
 }
 
+/// directory metadata to apply once everything under it is in place
+pub(super) struct PendingDirMetadata {
+    pub(super) path: PathBuf,
+    pub(super) logical_path: String,
+    pub(super) uid: u32,
+    pub(super) gid: u32,
+    pub(super) mode: u32,
+    pub(super) xattrs: Vec<Xattr>,
+}
+
 /// checkout a tree to a directory (recursive helper)
 ///
 /// hardlinks are collected and returned to be processed after all files
 /// in the entire tree are checked out. this handles cases where the target
 /// is in a sibling directory that would otherwise be processed later.
+/// directory metadata and recorded times of non-regular entries are
+/// collected the same way, directories after their contents.
+#[allow(clippy::too_many_arguments)]
 fn checkout_tree(
     repo: &Repo,
     tree: &Tree,
@@ -109,7 +357,11 @@ fn checkout_tree(
     prefix: &str,
     hardlink_tracker: &mut CheckoutHardlinkTracker,
     pending_hardlinks: &mut Vec<PendingHardlink>,
+    pending_dirs: &mut Vec<PendingDirMetadata>,
+    pending_times: &mut Vec<(PathBuf, EntryTimes)>,
+    progress: &mut Option<CheckoutProgress>,
     opts: &CheckoutOptions,
+    stats: &mut CheckoutStats,
 ) -> Result<()> {
     fs::create_dir_all(target).with_path(target)?;
 
@@ -122,28 +374,108 @@ fn checkout_tree(
             format!("{}/{}", prefix, entry.name)
         };
 
-        match &entry.kind {
+        // what an interrupted run left: completed entries that still match
+        // are kept, anything else (e.g. a half-written file) is replaced
+        if let Some(progress) = progress.as_ref() {
+            if !entry.kind.is_directory() && !matches!(entry.kind, EntryKind::Hardlink { .. }) {
+                if let Ok(meta) = fs::symlink_metadata(&entry_path) {
+                    let prefix = opts.symlink_prefix.as_deref();
+                    if progress.is_done(&logical_path)
+                        && entry_matches(repo, &entry_path, &meta, &entry.kind, prefix)?
+                    {
+                        stats.skipped += 1;
+                        hardlink_tracker.record(&logical_path, entry_path);
+                        continue;
+                    }
+                    if !opts.overlay {
+                        remove_path(&entry_path, &meta)?;
+                    }
+                }
+            }
+        }
+
+        if opts.skip_if_matches
+            && !entry.kind.is_directory()
+            && !matches!(entry.kind, EntryKind::Hardlink { .. })
+        {
+            if let Ok(meta) = fs::symlink_metadata(&entry_path) {
+                let prefix = opts.symlink_prefix.as_deref();
+                if entry_matches(repo, &entry_path, &meta, &entry.kind, prefix)? {
+                    stats.skipped += 1;
+                    hardlink_tracker.record(&logical_path, entry_path);
+                    continue;
+                }
+                remove_path(&entry_path, &meta)?;
+            }
+        }
+
+        if opts.overlay {
+            if let Ok(meta) = fs::symlink_metadata(&entry_path) {
+                if !overlay_make_room(&entry_path, &meta, &entry.kind, opts.on_conflict)? {
+                    stats.skipped += 1;
+                    hardlink_tracker.record(&logical_path, entry_path);
+                    continue;
+                }
+            }
+        }
+
+        let remapped;
+        let kind = if opts.remap_acls {
+            remapped = acls_to_outside(repo, &entry.kind)?;
+            &remapped
+        } else {
+            &entry.kind
+        };
+
+        match kind {
             EntryKind::Hardlink { target_path } => {
                 // defer hardlink creation until all files are checked out
                 pending_hardlinks.push(PendingHardlink {
                     entry_path,
+                    logical_path,
                     target_path: target_path.clone(),
                 });
+                continue;
             }
 
             EntryKind::Regular {
                 hash,
+                size,
                 sparse_map,
                 xattrs,
+                content,
                 ..
             } => {
-                checkout_regular_file(repo, &entry_path, hash, sparse_map.as_deref(), xattrs, opts)?;
+                let unapplied = checkout_regular_file(
+                    repo,
+                    &entry_path,
+                    hash,
+                    *size,
+                    content.as_ref(),
+                    sparse_map.as_deref(),
+                    xattrs,
+                    entry.kind.times(),
+                    opts,
+                )?;
+                record_unapplied(stats, &logical_path, unapplied);
+                mark_done(progress, &logical_path)?;
+                stats.written += 1;
                 hardlink_tracker.record(&logical_path, entry_path);
+                continue;
             }
 
-            EntryKind::Symlink { hash, xattrs } => {
-                checkout_symlink(repo, &entry_path, hash, xattrs)?;
-                hardlink_tracker.record(&logical_path, entry_path);
+            EntryKind::Symlink { hash, xattrs, .. } => {
+                checkout_symlink(
+                    repo,
+                    &entry_path,
+                    hash,
+                    xattrs,
+                    opts.symlink_prefix.as_deref(),
+                    sink_or_stderr(&opts.diagnostics),
+                )?;
+                mark_done(progress, &logical_path)?;
+                stats.written += 1;
+                hardlink_tracker.record(&logical_path, entry_path.clone());
             }
 
             EntryKind::Directory {
@@ -152,7 +484,12 @@ fn checkout_tree(
                 gid,
                 mode,
                 xattrs,
+                ..
             } => {
+                // a directory left read-only by an earlier checkout has to
+                // take new entries; its mode is reapplied afterwards anyway
+                make_dir_writable(&entry_path)?;
+
                 // recurse
                 let subtree = read_tree(repo, hash)?;
                 checkout_tree(
@@ -162,165 +499,738 @@ fn checkout_tree(
                     &logical_path,
                     hardlink_tracker,
                     pending_hardlinks,
+                    pending_dirs,
+                    pending_times,
+                    progress,
                     opts,
+                    stats,
                 )?;
 
-                // apply directory metadata after contents are created
-                apply_metadata_graceful(&entry_path, *uid, *gid, *mode, xattrs)?;
+                pending_dirs.push(PendingDirMetadata {
+                    path: entry_path.clone(),
+                    logical_path,
+                    uid: *uid,
+                    gid: *gid,
+                    mode: *mode,
+                    xattrs: xattrs.clone(),
+                });
+            }
+
+            EntryKind::BlockDevice { .. }
+            | EntryKind::CharDevice { .. }
+            | EntryKind::Fifo { .. }
+            | EntryKind::Socket { .. } => {
+                checkout_special(&entry_path, kind, sink_or_stderr(&opts.diagnostics))?;
+                mark_done(progress, &logical_path)?;
+                stats.written += 1;
+                // device nodes may have been skipped for lack of privileges
+                if fs::symlink_metadata(&entry_path).is_err() {
+                    continue;
+                }
             }
+        }
+
+        if let Some(times) = entry.kind.times() {
+            pending_times.push((entry_path, *times));
+        }
+    }
+
+    Ok(())
+}
+
+/// give the owner write and search access to an existing directory
+pub(super) fn make_dir_writable(path: &Path) -> Result<()> {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    let mode = meta.permissions().mode();
+    if meta.is_dir() && mode & 0o300 != 0o300 {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o700)).with_path(path)?;
+    }
+    Ok(())
+}
+
+/// resolve an overlay conflict with `meta`, the entry already at `path`
+///
+/// returns whether the tree's entry should be written: directories merge,
+/// and a non-directory is kept (`First`) or removed (`Last`).
+fn overlay_make_room(
+    path: &Path,
+    meta: &fs::Metadata,
+    kind: &EntryKind,
+    on_conflict: ConflictResolution,
+) -> Result<bool> {
+    match (meta.is_dir(), kind.is_directory()) {
+        (true, true) => return Ok(true),
+        (false, false) => {}
+        (existing_dir, _) => {
+            let first_type = if existing_dir {
+                "directory"
+            } else {
+                file_type_name(FileMetadata::from_path(path)?.file_type)
+            };
+            return Err(Error::UnionTypeConflict {
+                path: path.to_path_buf(),
+                first_type,
+                second_type: kind.type_name(),
+            });
+        }
+    }
+
+    match on_conflict {
+        ConflictResolution::Error => Err(Error::UnionConflict(path.to_path_buf())),
+        ConflictResolution::First => Ok(false),
+        ConflictResolution::Last => {
+            remove_path(path, meta)?;
+            Ok(true)
+        }
+    }
+}
+
+/// `kind` with the ids in its ACL xattrs translated to outside ids
+fn acls_to_outside(repo: &Repo, kind: &EntryKind) -> Result<EntryKind> {
+    let mut kind = kind.clone();
+    if let Some(xattrs) = kind.xattrs_mut() {
+        remap_acl_xattrs(xattrs, &repo.config().namespace, inside_to_outside)?;
+    }
+    Ok(kind)
+}
+
+fn record_unapplied(stats: &mut CheckoutStats, logical_path: &str, xattrs: Vec<Xattr>) {
+    let entries = xattrs.into_iter().map(|x| (logical_path.to_string(), x));
+    stats.unapplied_xattrs.extend(entries);
+}
+
+/// an entry whose on-disk state differs from the tree after a checkout
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckoutDiscrepancy {
+    /// path relative to the checkout root
+    pub path: String,
+    pub kind: DiscrepancyKind,
+}
+
+/// what differs; owners are outside ids, modes permission bits
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscrepancyKind {
+    /// nothing on disk, e.g. a device node skipped without privileges
+    Missing,
+    WrongType {
+        expected: &'static str,
+        found: &'static str,
+    },
+    WrongOwner {
+        expected: (u32, u32),
+        found: (u32, u32),
+    },
+    WrongMode {
+        expected: u32,
+        found: u32,
+    },
+    /// an xattr from the tree that isn't set, e.g. `security.*` without privileges
+    MissingXattr(String),
+}
 
-            EntryKind::BlockDevice {
-                major,
-                minor,
+impl std::fmt::Display for DiscrepancyKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing => write!(f, "missing"),
+            Self::WrongType { expected, found } => {
+                write!(f, "type {} instead of {}", found, expected)
+            }
+            Self::WrongOwner { expected, found } => write!(
+                f,
+                "owner {}:{} instead of {}:{}",
+                found.0, found.1, expected.0, expected.1
+            ),
+            Self::WrongMode { expected, found } => {
+                write!(f, "mode {:04o} instead of {:04o}", found, expected)
+            }
+            Self::MissingXattr(name) => write!(f, "xattr {} not set", name),
+        }
+    }
+}
+
+/// compare a checkout against its ref without modifying anything
+///
+/// reports what an unprivileged checkout couldn't apply (ownership, device
+/// nodes, privileged xattrs) along with any other metadata drift. contents
+/// are not compared, and files not in the tree are ignored.
+pub fn verify_checkout(
+    repo: &Repo,
+    ref_name: &str,
+    target: &Path,
+) -> Result<Vec<CheckoutDiscrepancy>> {
+    let commit = read_commit(repo, &resolve_ref(repo, ref_name)?)?;
+    let tree = read_tree(repo, &commit.tree)?;
+
+    let mut found = Vec::new();
+    verify_tree(repo, &tree, target, "", &mut found)?;
+    Ok(found)
+}
+
+fn verify_tree(
+    repo: &Repo,
+    tree: &Tree,
+    target: &Path,
+    prefix: &str,
+    found: &mut Vec<CheckoutDiscrepancy>,
+) -> Result<()> {
+    let ns = &repo.config().namespace;
+
+    for entry in tree.entries() {
+        let entry_path = target.join(&entry.name);
+        let logical_path = if prefix.is_empty() {
+            entry.name.clone()
+        } else {
+            format!("{}/{}", prefix, entry.name)
+        };
+        let mut report = |kind| {
+            found.push(CheckoutDiscrepancy {
+                path: logical_path.clone(),
+                kind,
+            })
+        };
+
+        let meta = match FileMetadata::from_path(&entry_path) {
+            Ok(meta) => meta,
+            Err(Error::Io { source, .. }) if source.kind() == std::io::ErrorKind::NotFound => {
+                report(DiscrepancyKind::Missing);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        // a hardlink carries its target's metadata, which is checked there
+        if let EntryKind::Hardlink { .. } = &entry.kind {
+            if meta.file_type != FileType::Regular {
+                report(DiscrepancyKind::WrongType {
+                    expected: "regular",
+                    found: file_type_name(meta.file_type),
+                });
+            }
+            continue;
+        }
+
+        // socket placeholders are empty regular files
+        let type_ok = match &entry.kind {
+            EntryKind::Socket { .. } => {
+                matches!(meta.file_type, FileType::Socket | FileType::Regular)
+            }
+            kind => file_type_name(meta.file_type) == kind.type_name(),
+        };
+        if !type_ok {
+            report(DiscrepancyKind::WrongType {
+                expected: entry.kind.type_name(),
+                found: file_type_name(meta.file_type),
+            });
+            continue;
+        }
+
+        // expected (uid, gid, mode, xattrs); blob files carry outside ids
+        let (owner, mode, xattrs) = match &entry.kind {
+            EntryKind::Regular { hash, xattrs, .. } | EntryKind::Symlink { hash, xattrs, .. } => {
+                let blob = blob_meta_path(repo, hash, entry.kind.content_hash());
+                let blob_meta = fs::metadata(&blob).with_path(&blob)?;
+                let mode = match &entry.kind {
+                    EntryKind::Symlink { .. } => None,
+                    _ => Some(blob_meta.mode()),
+                };
+                ((blob_meta.uid(), blob_meta.gid()), mode, xattrs)
+            }
+            EntryKind::Directory {
                 uid,
                 gid,
                 mode,
                 xattrs,
-            } => {
-                match create_block_device(&entry_path, *major, *minor, *uid, *gid, *mode, xattrs) {
-                    Ok(()) => {}
-                    Err(Error::DeviceNodePermission(_)) => {
-                        eprintln!(
-                            "warning: cannot create block device {:?} without privileges, skipping",
-                            entry_path
-                        );
-                    }
-                    Err(e) => return Err(e),
-                }
+                ..
             }
-
-            EntryKind::CharDevice {
-                major,
-                minor,
+            | EntryKind::BlockDevice {
                 uid,
                 gid,
                 mode,
                 xattrs,
-            } => match create_char_device(&entry_path, *major, *minor, *uid, *gid, *mode, xattrs) {
-                Ok(()) => {}
-                Err(Error::DeviceNodePermission(_)) => {
-                    eprintln!(
-                        "warning: cannot create char device {:?} without privileges, skipping",
-                        entry_path
-                    );
-                }
-                Err(e) => return Err(e),
-            },
-
-            EntryKind::Fifo {
+                ..
+            }
+            | EntryKind::CharDevice {
                 uid,
                 gid,
                 mode,
                 xattrs,
-            } => {
-                create_fifo(&entry_path, *uid, *gid, *mode, xattrs)?;
+                ..
             }
-
-            EntryKind::Socket {
+            | EntryKind::Fifo {
+                uid,
+                gid,
+                mode,
+                xattrs,
+                ..
+            }
+            | EntryKind::Socket {
                 uid,
                 gid,
                 mode,
                 xattrs,
+                ..
             } => {
-                create_socket_placeholder(&entry_path, *uid, *gid, *mode, xattrs)?;
+                let owner = (
+                    inside_to_outside(*uid, &ns.uid_map).unwrap_or(*uid),
+                    inside_to_outside(*gid, &ns.gid_map).unwrap_or(*gid),
+                );
+                (owner, Some(*mode), xattrs)
+            }
+            EntryKind::Hardlink { .. } => unreachable!("handled above"),
+        };
+
+        if (meta.uid, meta.gid) != owner {
+            report(DiscrepancyKind::WrongOwner {
+                expected: owner,
+                found: (meta.uid, meta.gid),
+            });
+        }
+        if let Some(mode) = mode {
+            if meta.mode & 0o7777 != mode & 0o7777 {
+                report(DiscrepancyKind::WrongMode {
+                    expected: mode & 0o7777,
+                    found: meta.mode & 0o7777,
+                });
             }
         }
+        if !xattrs.is_empty() {
+            let on_disk = read_xattrs(&entry_path)?;
+            for xattr in xattrs.iter().filter(|x| !on_disk.contains(x)) {
+                report(DiscrepancyKind::MissingXattr(xattr.name.clone()));
+            }
+        }
+
+        if let EntryKind::Directory { hash, .. } = &entry.kind {
+            let subtree = read_tree(repo, hash)?;
+            verify_tree(repo, &subtree, &entry_path, &logical_path, found)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// `EntryKind::type_name` of the matching on-disk type
+fn file_type_name(file_type: FileType) -> &'static str {
+    match file_type {
+        FileType::Regular => "regular",
+        FileType::Directory => "directory",
+        FileType::Symlink => "symlink",
+        FileType::BlockDevice => "block_device",
+        FileType::CharDevice => "char_device",
+        FileType::Fifo => "fifo",
+        FileType::Socket => "socket",
+    }
+}
+
+/// create a device node, fifo or socket placeholder
+///
+/// device nodes that need privileges we don't have are skipped and
+/// reported to `diag`.
+pub(super) fn checkout_special(
+    entry_path: &Path,
+    kind: &EntryKind,
+    diag: &dyn Diagnostics,
+) -> Result<()> {
+    match kind {
+        EntryKind::BlockDevice {
+            major,
+            minor,
+            uid,
+            gid,
+            mode,
+            xattrs,
+            ..
+        } => match create_block_device(entry_path, *major, *minor, *uid, *gid, *mode, xattrs) {
+            Ok(()) => {}
+            Err(Error::DeviceNodePermission(_)) => diag.warn(Warning::DeviceNodeSkipped {
+                path: entry_path.to_path_buf(),
+                device_type: "block",
+            }),
+            Err(e) => return Err(e),
+        },
+
+        EntryKind::CharDevice {
+            major,
+            minor,
+            uid,
+            gid,
+            mode,
+            xattrs,
+            ..
+        } => match create_char_device(entry_path, *major, *minor, *uid, *gid, *mode, xattrs) {
+            Ok(()) => {}
+            Err(Error::DeviceNodePermission(_)) => diag.warn(Warning::DeviceNodeSkipped {
+                path: entry_path.to_path_buf(),
+                device_type: "char",
+            }),
+            Err(e) => return Err(e),
+        },
+
+        EntryKind::Fifo {
+            uid,
+            gid,
+            mode,
+            xattrs,
+            ..
+        } => {
+            create_fifo(entry_path, *uid, *gid, *mode, xattrs)?;
+        }
+
+        EntryKind::Socket {
+            uid,
+            gid,
+            mode,
+            xattrs,
+            ..
+        } => {
+            create_socket_placeholder(entry_path, *uid, *gid, *mode, xattrs, diag)?;
+        }
+
+        _ => {}
     }
 
     Ok(())
 }
 
 /// checkout a regular file (hardlink from blob store, or copy for sparse/--copy/xattrs)
-fn checkout_regular_file(
+///
+/// sparse entries are always rebuilt to their logical `size`; `preserve_sparse`
+/// only picks real holes over zero-filled ones. recorded `times` are restored,
+/// on a copy, since the blob's inode is shared.
+///
+/// returns the privileged xattrs that couldn't be set.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn checkout_regular_file(
     repo: &Repo,
     dest: &Path,
     hash: &Hash,
+    size: u64,
+    content: Option<&Hash>,
     sparse_map: Option<&[crate::types::SparseRegion]>,
     xattrs: &[Xattr],
+    times: Option<&EntryTimes>,
     opts: &CheckoutOptions,
-) -> Result<()> {
+) -> Result<Vec<Xattr>> {
     // remove existing
     if dest.exists() {
         fs::remove_file(dest).with_path(dest)?;
     }
 
     // can only hardlink if no xattrs (since blob no longer stores xattrs)
-    // and no sparse map to preserve
-    let can_hardlink = opts.hardlink && xattrs.is_empty() && sparse_map.is_none();
-
-    match sparse_map {
-        Some(regions) if !regions.is_empty() && opts.preserve_sparse => {
-            // sparse file: must copy and recreate holes
-            let data = read_blob(repo, hash)?;
-            let total_size: u64 = regions.iter().map(|r| r.end()).max().unwrap_or(0);
-            write_sparse_file(dest, &data, regions, total_size)?;
+    // and no sparse map to expand. content objects are shared across
+    // metadata variants, so they're never hardlinked either, and setting
+    // times on a hardlink would change the blob's
+    let can_hardlink = opts.hardlink
+        && xattrs.is_empty()
+        && sparse_map.is_none()
+        && content.is_none()
+        && times.is_none();
+    let diag = sink_or_stderr(&opts.diagnostics);
+
+    let unapplied = match sparse_map {
+        Some(regions) => {
+            // the blob only holds the data regions, so the holes have to be
+            // put back either way
+            let data = read_blob_data(repo, hash, content)?;
+            if opts.preserve_sparse {
+                write_sparse_file(dest, &data, regions, size)?;
+            } else {
+                write_zero_filled_file(dest, &data, regions, size)?;
+            }
 
             // apply metadata from blob (uid, gid, mode) and xattrs from tree
-            apply_blob_metadata_with_xattrs(repo, hash, dest, xattrs)?;
-        }
-
-        Some([]) => {
-            // all holes (empty sparse file)
-            fs::write(dest, b"").with_path(dest)?;
+            apply_blob_metadata_with_xattrs(repo, hash, content, dest, xattrs, diag)?
         }
 
-        _ if can_hardlink => {
+        None if can_hardlink => {
             // non-sparse with hardlink and no xattrs: hardlink from blob store
             let blob = blob_path(repo, hash);
             fs::hard_link(&blob, dest).with_path(dest)?;
             // metadata (uid, gid, mode) comes along with the hardlink (shared inode)
             // note: no xattrs to apply since we only hardlink when xattrs is empty
+            vec![]
         }
 
-        _ => {
-            // copy mode (--copy flag, has xattrs, or sparse without preserve_sparse)
-            let blob = blob_path(repo, hash);
-            fs::copy(&blob, dest).with_path(dest)?;
+        None => {
+            // copy mode (--copy flag, has xattrs, content-addressed or times)
+            let data_path = blob_data_path(repo, hash, content);
+            fs::copy(&data_path, dest).with_path(dest)?;
 
             // apply metadata from blob (uid, gid, mode) and xattrs from tree
-            apply_blob_metadata_with_xattrs(repo, hash, dest, xattrs)?;
+            apply_blob_metadata_with_xattrs(repo, hash, content, dest, xattrs, diag)?
         }
+    };
+
+    if let Some(times) = times {
+        set_times(dest, times)?;
     }
 
-    Ok(())
+    Ok(unapplied)
 }
 
 /// apply metadata (uid, gid, mode from blob file, xattrs from tree) to a destination path
 fn apply_blob_metadata_with_xattrs(
     repo: &Repo,
     hash: &Hash,
+    content: Option<&Hash>,
     dest: &Path,
     xattrs: &[Xattr],
-) -> Result<()> {
-    use std::os::unix::fs::MetadataExt;
+    diag: &dyn Diagnostics,
+) -> Result<Vec<Xattr>> {
 
-    let blob = blob_path(repo, hash);
+    let blob = blob_meta_path(repo, hash, content);
     let meta = fs::metadata(&blob).with_path(&blob)?;
 
-    apply_metadata_graceful(dest, meta.uid(), meta.gid(), meta.mode(), xattrs)
+    apply_metadata_reporting(dest, meta.uid(), meta.gid(), meta.mode(), xattrs, diag)
 }
 
 /// checkout a symlink
-fn checkout_symlink(repo: &Repo, dest: &Path, hash: &Hash, xattrs: &[Xattr]) -> Result<()> {
+pub(super) fn checkout_symlink(
+    repo: &Repo,
+    dest: &Path,
+    hash: &Hash,
+    xattrs: &[Xattr],
+    prefix: Option<&Path>,
+    diag: &dyn Diagnostics,
+) -> Result<()> {
     // symlink blob contains the target path as content
     let target_bytes = read_blob(repo, hash)?;
-    let target = String::from_utf8_lossy(&target_bytes);
+    let mut target = String::from_utf8_lossy(&target_bytes).into_owned();
+    if let Some(prefix) = prefix {
+        target = prefix_symlink_target(&target, prefix);
+    }
 
     // read uid/gid from blob file (still stored there), xattrs from tree
     let blob = blob_path(repo, hash);
     let meta = fs::symlink_metadata(&blob).with_path(&blob)?;
 
-    use std::os::unix::fs::MetadataExt;
-    create_symlink(dest, &target, meta.uid(), meta.gid(), xattrs)?;
+    create_symlink(dest, &target, meta.uid(), meta.gid(), xattrs, diag)?;
 
     Ok(())
 }
 
+/// whether what's on disk at `path` already matches a non-directory tree entry
+///
+/// regular files match when they are the blob itself (hardlinked) or have the
+/// same size, ownership, mode, xattrs and bytes. symlinks are compared against
+/// the target they would be written with under `symlink_prefix`.
+pub(super) fn entry_matches(
+    repo: &Repo,
+    path: &Path,
+    meta: &fs::Metadata,
+    kind: &EntryKind,
+    symlink_prefix: Option<&Path>,
+) -> Result<bool> {
+    let file_type = meta.file_type();
+    match kind {
+        EntryKind::Regular {
+            hash,
+            size,
+            sparse_map,
+            xattrs,
+            content,
+            ..
+        } => {
+            if !file_type.is_file() || meta.len() != *size {
+                return Ok(false);
+            }
+
+            let blob = blob_meta_path(repo, hash, content.as_ref());
+            let blob_meta = fs::metadata(&blob).with_path(&blob)?;
+            if meta.dev() == blob_meta.dev() && meta.ino() == blob_meta.ino() {
+                // hardlinked straight from the store
+                return Ok(true);
+            }
+            if !owner_mode_matches(meta, blob_meta.uid(), blob_meta.gid(), blob_meta.mode())
+                || !xattrs_present(path, xattrs)?
+            {
+                return Ok(false);
+            }
+
+            match sparse_map {
+                Some(regions) => {
+                    let data = read_blob_data(repo, hash, content.as_ref())?;
+                    let expected = expand_sparse(hash, &data, regions, *size)?;
+                    Ok(fs::read(path).with_path(path)? == expected)
+                }
+                None => {
+                    let data_path = blob_data_path(repo, hash, content.as_ref());
+                    files_equal(path, &data_path)
+                }
+            }
+        }
+
+        EntryKind::Symlink { hash, .. } => {
+            if !file_type.is_symlink() {
+                return Ok(false);
+            }
+            let target_bytes = read_blob(repo, hash)?;
+            let mut target = String::from_utf8_lossy(&target_bytes).into_owned();
+            if let Some(prefix) = symlink_prefix {
+                target = prefix_symlink_target(&target, prefix);
+            }
+            let on_disk = fs::read_link(path).with_path(path)?;
+            let blob = blob_path(repo, hash);
+            let blob_meta = fs::symlink_metadata(&blob).with_path(&blob)?;
+            Ok(on_disk.as_os_str().as_encoded_bytes() == target.as_bytes()
+                && meta.uid() == blob_meta.uid()
+                && meta.gid() == blob_meta.gid())
+        }
+
+        EntryKind::BlockDevice {
+            major,
+            minor,
+            uid,
+            gid,
+            mode,
+            ..
+        } => Ok(file_type.is_block_device()
+            && meta.rdev() == nix::sys::stat::makedev(*major as u64, *minor as u64)
+            && owner_mode_matches(meta, *uid, *gid, *mode)),
+
+        EntryKind::CharDevice {
+            major,
+            minor,
+            uid,
+            gid,
+            mode,
+            ..
+        } => Ok(file_type.is_char_device()
+            && meta.rdev() == nix::sys::stat::makedev(*major as u64, *minor as u64)
+            && owner_mode_matches(meta, *uid, *gid, *mode)),
+
+        EntryKind::Fifo { uid, gid, mode, .. } => {
+            Ok(file_type.is_fifo() && owner_mode_matches(meta, *uid, *gid, *mode))
+        }
+
+        // sockets are checked out as placeholders; any file left there is fine
+        EntryKind::Socket { uid, gid, mode, .. } => {
+            Ok(!meta.is_dir() && owner_mode_matches(meta, *uid, *gid, *mode))
+        }
+
+        EntryKind::Directory { .. } | EntryKind::Hardlink { .. } => Ok(false),
+    }
+}
+
+pub(super) fn owner_mode_matches(meta: &fs::Metadata, uid: u32, gid: u32, mode: u32) -> bool {
+    meta.uid() == uid && meta.gid() == gid && meta.mode() & 0o7777 == mode & 0o7777
+}
+
+/// every xattr from the tree is on disk with the same value (extras are ignored)
+fn xattrs_present(path: &Path, xattrs: &[Xattr]) -> Result<bool> {
+    if xattrs.is_empty() {
+        return Ok(true);
+    }
+    let on_disk = read_xattrs(path)?;
+    Ok(xattrs.iter().all(|x| on_disk.contains(x)))
+}
+
+/// a sparse file's full contents, from its data regions and logical size
+///
+/// a map reaching past `size` or past the stored data makes the blob
+/// `hash` corrupt.
+pub(super) fn expand_sparse(
+    hash: &Hash,
+    data: &[u8],
+    regions: &[SparseRegion],
+    size: u64,
+) -> Result<Vec<u8>> {
+    let corrupt = || Error::CorruptObject(*hash);
+    let mut file = vec![0u8; usize::try_from(size).map_err(|_| corrupt())?];
+    let mut pos = 0usize;
+    for region in regions {
+        let start = usize::try_from(region.offset).map_err(|_| corrupt())?;
+        let len = usize::try_from(region.length).map_err(|_| corrupt())?;
+        let end = pos.checked_add(len).ok_or_else(corrupt)?;
+        let src = data.get(pos..end).ok_or_else(corrupt)?;
+        let dest = start
+            .checked_add(len)
+            .and_then(|dest_end| file.get_mut(start..dest_end))
+            .ok_or_else(corrupt)?;
+        dest.copy_from_slice(src);
+        pos = end;
+    }
+    Ok(file)
+}
+
+/// compare two files chunk by chunk
+fn files_equal(a: &Path, b: &Path) -> Result<bool> {
+    let mut file_a = File::open(a).with_path(a)?;
+    let mut file_b = File::open(b).with_path(b)?;
+    let mut buf_a = vec![0u8; 64 * 1024];
+    let mut buf_b = vec![0u8; 64 * 1024];
+
+    loop {
+        let n = read_full(&mut file_a, &mut buf_a).with_path(a)?;
+        let m = read_full(&mut file_b, &mut buf_b).with_path(b)?;
+        if n != m || buf_a[..n] != buf_b[..m] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// read until the buffer is full or EOF
+fn read_full(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// remove a file, symlink or whole directory
+pub(super) fn remove_path(path: &Path, meta: &fs::Metadata) -> Result<()> {
+    if meta.is_dir() {
+        fs::remove_dir_all(path).with_path(path)
+    } else {
+        fs::remove_file(path).with_path(path)
+    }
+}
+
+/// re-root an absolute symlink target under `prefix`
+///
+/// `..` is resolved lexically and clamped at the root, the way it would be
+/// inside a chroot, so a link can never escape the prefix.
+fn prefix_symlink_target(target: &str, prefix: &Path) -> String {
+    if !target.starts_with('/') {
+        return target.to_string();
+    }
+
+    let mut parts: Vec<&std::ffi::OsStr> = Vec::new();
+    for component in Path::new(target).components() {
+        match component {
+            Component::Normal(part) => parts.push(part),
+            Component::ParentDir => {
+                parts.pop();
+            }
+            _ => {}
+        }
+    }
+
+    let mut rewritten = prefix.to_path_buf();
+    rewritten.extend(parts);
+    let mut rewritten = rewritten.to_string_lossy().into_owned();
+    if target.len() > 1 && target.ends_with('/') && !rewritten.ends_with('/') {
+        rewritten.push('/');
+    }
+    rewritten
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::object::{write_commit, write_tree};
     use crate::ops::commit::commit;
-    use std::os::unix::fs::MetadataExt;
+    use crate::types::{Commit, TreeEntry};
+    use std::os::unix::fs::PermissionsExt;
     use tempfile::tempdir;
 
     fn test_repo() -> (tempfile::TempDir, Repo) {
@@ -395,6 +1305,188 @@ mod tests {
         assert_eq!(content, "deep content");
     }
 
+    #[test]
+    fn test_checkout_empty_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("empty")).unwrap();
+        fs::set_permissions(source.join("empty"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        let target = dir.path().join("target");
+        checkout(&repo, "test", &target, Default::default()).unwrap();
+
+        let source_meta = fs::metadata(source.join("empty")).unwrap();
+        let meta = fs::metadata(target.join("empty")).unwrap();
+        assert!(meta.is_dir());
+        assert_eq!(meta.mode() & 0o7777, 0o700);
+        assert_eq!(meta.uid(), source_meta.uid());
+        assert_eq!(meta.gid(), source_meta.gid());
+        assert_eq!(fs::read_dir(target.join("empty")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_checkout_read_only_dir_with_hardlinks() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("ro")).unwrap();
+        fs::write(source.join("ro/a.txt"), "shared").unwrap();
+        fs::hard_link(source.join("ro/a.txt"), source.join("ro/b.txt")).unwrap();
+        fs::set_permissions(source.join("ro"), fs::Permissions::from_mode(0o555)).unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        let target = dir.path().join("target");
+        checkout(&repo, "test", &target, Default::default()).unwrap();
+
+        let meta = fs::metadata(target.join("ro")).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o555);
+        let a = fs::metadata(target.join("ro/a.txt")).unwrap();
+        let b = fs::metadata(target.join("ro/b.txt")).unwrap();
+        assert_eq!(a.ino(), b.ino());
+
+        // checking out again over the read-only directory works too
+        fs::set_permissions(target.join("ro"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_file(target.join("ro/b.txt")).unwrap();
+        fs::set_permissions(target.join("ro"), fs::Permissions::from_mode(0o555)).unwrap();
+        let opts = CheckoutOptions {
+            skip_if_matches: true,
+            ..Default::default()
+        };
+        checkout(&repo, "test", &target, opts).unwrap();
+        let b = fs::metadata(target.join("ro/b.txt")).unwrap();
+        assert_eq!(a.ino(), b.ino());
+        let meta = fs::metadata(target.join("ro")).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o555);
+    }
+
+    #[test]
+    fn test_checkout_skip_if_matches() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a.txt"), "a").unwrap();
+        fs::write(source.join("sub/b.txt"), "b").unwrap();
+        fs::hard_link(source.join("a.txt"), source.join("a-link")).unwrap();
+        std::os::unix::fs::symlink("/abs/target", source.join("link")).unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        for hardlink in [true, false] {
+            let target = dir.path().join(format!("target-{}", hardlink));
+            let opts = || CheckoutOptions {
+                hardlink,
+                symlink_prefix: Some(PathBuf::from("/prefix")),
+                skip_if_matches: true,
+                ..Default::default()
+            };
+
+            let first = checkout(&repo, "test", &target, opts()).unwrap();
+            assert_eq!(first.written, 4);
+            assert_eq!(first.skipped, 0);
+
+            let second = checkout(&repo, "test", &target, opts()).unwrap();
+            assert_eq!(second.written, 0);
+            assert_eq!(second.skipped, 4);
+
+            // a changed entry is rewritten, the rest stay skipped
+            fs::remove_file(target.join("sub/b.txt")).unwrap();
+            fs::write(target.join("sub/b.txt"), "changed").unwrap();
+            let third = checkout(&repo, "test", &target, opts()).unwrap();
+            assert_eq!(third.written, 1);
+            assert_eq!(third.skipped, 3);
+            assert_eq!(fs::read(target.join("sub/b.txt")).unwrap(), b"b");
+        }
+    }
+
+    #[test]
+    fn test_verify_checkout() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/a.txt"), "a").unwrap();
+        fs::set_permissions(source.join("sub/a.txt"), fs::Permissions::from_mode(0o640)).unwrap();
+        std::os::unix::fs::symlink("sub/a.txt", source.join("link")).unwrap();
+        let commit_hash = commit(&repo, &source, "test", None, None).unwrap();
+
+        // add a device node to the tree, as a privileged commit would
+        let tree = read_tree(&repo, &read_commit(&repo, &commit_hash).unwrap().tree).unwrap();
+        let mut entries = tree.entries().to_vec();
+        entries.push(TreeEntry::new(
+            "null",
+            EntryKind::CharDevice {
+                major: 1,
+                minor: 3,
+                uid: 0,
+                gid: 0,
+                mode: 0o666,
+                xattrs: vec![],
+                times: None,
+            },
+        ));
+        let tree_hash = write_tree(&repo, &Tree::new(entries).unwrap()).unwrap();
+        let commit_hash =
+            write_commit(&repo, &Commit::new(tree_hash, vec![], "test", "dev")).unwrap();
+        crate::refs::write_ref(&repo, "dev", &commit_hash).unwrap();
+
+        // copies, so changing the checkout leaves the blobs alone
+        let target = dir.path().join("target");
+        let opts = CheckoutOptions {
+            hardlink: false,
+            ..Default::default()
+        };
+        checkout(&repo, "dev", &target, opts).unwrap();
+
+        // what an unprivileged checkout leaves behind
+        let _ = fs::remove_file(target.join("null"));
+        let owner = fs::metadata(target.join("sub")).unwrap();
+        let (uid, gid) = (owner.uid(), owner.gid());
+        std::os::unix::fs::lchown(target.join("sub"), Some(uid + 1), None).unwrap();
+        fs::set_permissions(target.join("sub/a.txt"), fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut found = verify_checkout(&repo, "dev", &target).unwrap();
+        found.sort_by(|a, b| a.path.cmp(&b.path));
+        assert_eq!(
+            found,
+            vec![
+                CheckoutDiscrepancy {
+                    path: "null".into(),
+                    kind: DiscrepancyKind::Missing,
+                },
+                CheckoutDiscrepancy {
+                    path: "sub".into(),
+                    kind: DiscrepancyKind::WrongOwner {
+                        expected: (uid, gid),
+                        found: (uid + 1, gid),
+                    },
+                },
+                CheckoutDiscrepancy {
+                    path: "sub/a.txt".into(),
+                    kind: DiscrepancyKind::WrongMode {
+                        expected: 0o640,
+                        found: 0o600,
+                    },
+                },
+            ]
+        );
+
+        // a file swapped for a directory is a type mismatch
+        fs::remove_file(target.join("link")).unwrap();
+        fs::create_dir(target.join("link")).unwrap();
+        let found = verify_checkout(&repo, "test", &target).unwrap();
+        assert!(found.iter().any(|d| d.path == "link"
+            && d.kind
+                == DiscrepancyKind::WrongType {
+                    expected: "symlink",
+                    found: "directory",
+                }));
+    }
+
     #[test]
     fn test_checkout_symlink() {
         let (dir, repo) = test_repo();
@@ -411,6 +1503,40 @@ mod tests {
         assert_eq!(link_target.to_string_lossy(), "/target/path");
     }
 
+    #[test]
+    fn test_checkout_symlink_prefix() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        std::os::unix::fs::symlink("/usr/lib", source.join("lib")).unwrap();
+        std::os::unix::fs::symlink("../share", source.join("relative")).unwrap();
+        std::os::unix::fs::symlink("/", source.join("root")).unwrap();
+        std::os::unix::fs::symlink("/../../etc/./passwd", source.join("escape")).unwrap();
+        let commit_hash = commit(&repo, &source, "symlink", None, None).unwrap();
+
+        let target = dir.path().join("target");
+        let opts = CheckoutOptions {
+            symlink_prefix: Some(target.clone()),
+            ..Default::default()
+        };
+        checkout(&repo, "symlink", &target, opts).unwrap();
+
+        let link = |name: &str| fs::read_link(target.join(name)).unwrap();
+        assert_eq!(link("lib"), target.join("usr/lib"));
+        assert_eq!(link("relative"), Path::new("../share"));
+        assert_eq!(link("root"), target);
+        assert_eq!(link("escape"), target.join("etc/passwd"));
+
+        // stored blobs are untouched
+        let tree = read_tree(&repo, &read_commit(&repo, &commit_hash).unwrap().tree).unwrap();
+        if let EntryKind::Symlink { hash, .. } = &tree.get("lib").unwrap().kind {
+            assert_eq!(read_blob(&repo, hash).unwrap(), b"/usr/lib");
+        } else {
+            panic!("expected symlink");
+        }
+    }
+
     #[test]
     fn test_checkout_hardlinks() {
         let (dir, repo) = test_repo();
@@ -463,6 +1589,63 @@ mod tests {
         assert!(target.join("file.txt").exists());
     }
 
+    #[test]
+    fn test_checkout_overlay() {
+        let (dir, repo) = test_repo();
+
+        let a = dir.path().join("a");
+        fs::create_dir_all(a.join("etc")).unwrap();
+        fs::write(a.join("etc/a.conf"), "a").unwrap();
+        fs::write(a.join("common"), "from a").unwrap();
+        commit(&repo, &a, "a", None, None).unwrap();
+
+        let b = dir.path().join("b");
+        fs::create_dir_all(b.join("etc")).unwrap();
+        fs::write(b.join("etc/b.conf"), "b").unwrap();
+        fs::write(b.join("common"), "from b").unwrap();
+        fs::hard_link(b.join("common"), b.join("common-link")).unwrap();
+        commit(&repo, &b, "b", None, None).unwrap();
+
+        let overlay = |on_conflict| CheckoutOptions {
+            overlay: true,
+            on_conflict,
+            ..Default::default()
+        };
+
+        let target = dir.path().join("target");
+        checkout(&repo, "a", &target, Default::default()).unwrap();
+
+        // the shared file conflicts by default
+        let result = checkout(&repo, "b", &target, overlay(ConflictResolution::Error));
+        assert!(matches!(result, Err(Error::UnionConflict(p)) if p == target.join("common")));
+
+        let target = dir.path().join("first");
+        checkout(&repo, "a", &target, Default::default()).unwrap();
+        let stats = checkout(&repo, "b", &target, overlay(ConflictResolution::First)).unwrap();
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(fs::read_to_string(target.join("etc/a.conf")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(target.join("etc/b.conf")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(target.join("common")).unwrap(), "from a");
+        // links to a kept file point at what was kept
+        assert_eq!(
+            fs::read_to_string(target.join("common-link")).unwrap(),
+            "from a"
+        );
+
+        let target = dir.path().join("last");
+        checkout(&repo, "a", &target, Default::default()).unwrap();
+        checkout(&repo, "b", &target, overlay(ConflictResolution::Last)).unwrap();
+        assert_eq!(fs::read_to_string(target.join("etc/a.conf")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(target.join("etc/b.conf")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(target.join("common")).unwrap(), "from b");
+
+        // a directory over a file is never resolvable
+        fs::remove_dir_all(target.join("etc")).unwrap();
+        fs::write(target.join("etc"), "file").unwrap();
+        let result = checkout(&repo, "b", &target, overlay(ConflictResolution::Last));
+        assert!(matches!(result, Err(Error::UnionTypeConflict { .. })));
+    }
+
     #[test]
     fn test_roundtrip() {
         let (dir, repo) = test_repo();
@@ -543,4 +1726,397 @@ mod tests {
         let target_ino = fs::metadata(&checked_out).unwrap().ino();
         assert_ne!(source_ino, target_ino);
     }
+
+    const CAP_MKNOD: u32 = 27;
+    const CAP_SETFCAP: u32 = 31;
+
+    /// drop a capability from the calling thread's effective set
+    fn drop_capability(cap: u32) {
+        use nix::libc;
+
+        #[repr(C)]
+        struct Header {
+            version: u32,
+            pid: i32,
+        }
+        #[repr(C)]
+        #[derive(Clone, Copy, Default)]
+        struct Data {
+            effective: u32,
+            permitted: u32,
+            inheritable: u32,
+        }
+
+        let mut header = Header {
+            version: 0x20080522,
+            pid: 0,
+        };
+        let mut data = [Data::default(); 2];
+        let header = &mut header as *mut Header;
+        let data = data.as_mut_ptr();
+        unsafe {
+            assert_eq!(libc::syscall(libc::SYS_capget, header, data), 0);
+            (*data).effective &= !(1 << cap);
+            assert_eq!(libc::syscall(libc::SYS_capset, header, data), 0);
+        }
+    }
+
+    #[test]
+    fn test_checkout_unprivileged_device_node_warns() {
+        use crate::diagnostics::CollectDiagnostics;
+
+        let (dir, repo) = test_repo();
+        let device = EntryKind::CharDevice {
+            major: 1,
+            minor: 3,
+            uid: 0,
+            gid: 0,
+            mode: 0o20666,
+            xattrs: vec![],
+            times: None,
+        };
+        let tree = Tree::new(vec![TreeEntry::new("null", device)]).unwrap();
+        let tree_hash = write_tree(&repo, &tree).unwrap();
+
+        // checkout on a thread that can no longer create device nodes
+        let target = dir.path().join("target");
+        let sink = Arc::new(CollectDiagnostics::new());
+        let stats = std::thread::scope(|s| {
+            s.spawn(|| {
+                drop_capability(CAP_MKNOD);
+                let opts = CheckoutOptions {
+                    diagnostics: Some(sink.clone()),
+                    ..Default::default()
+                };
+                checkout_from_tree_hash(&repo, &tree_hash, &target, opts)
+            })
+            .join()
+            .unwrap()
+        })
+        .unwrap();
+
+        assert_eq!(stats.written, 1);
+        assert!(fs::symlink_metadata(target.join("null")).is_err());
+        assert_eq!(
+            sink.warnings(),
+            vec![Warning::DeviceNodeSkipped {
+                path: target.join("null"),
+                device_type: "char",
+            }]
+        );
+    }
+
+    const CAP_DAC_OVERRIDE: u32 = 1;
+    const CAP_DAC_READ_SEARCH: u32 = 2;
+
+    #[test]
+    fn test_checkout_resume_after_interrupt() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        for name in ["a.txt", "b.txt", "c.txt", "d.txt", "sub/e.txt"] {
+            fs::write(source.join(name), name).unwrap();
+        }
+        fs::hard_link(source.join("sub/e.txt"), source.join("sub/link")).unwrap();
+        commit(&repo, &source, "main", None, None).unwrap();
+
+        // d.txt's blob can't be read by a thread without DAC overrides, so
+        // the checkout stops there, after a.txt, b.txt and c.txt
+        let tree = read_tree(
+            &repo,
+            &read_commit(&repo, &resolve_ref(&repo, "main").unwrap())
+                .unwrap()
+                .tree,
+        )
+        .unwrap();
+        let EntryKind::Regular { hash, .. } = &tree.get("d.txt").unwrap().kind else {
+            panic!("d.txt should be a regular file");
+        };
+        let blob = blob_path(&repo, hash);
+        let blob_mode = fs::metadata(&blob).unwrap().permissions().mode();
+        fs::set_permissions(&blob, fs::Permissions::from_mode(0o000)).unwrap();
+
+        let target = dir.path().join("target");
+        let opts = || CheckoutOptions {
+            hardlink: false,
+            resume: true,
+            ..Default::default()
+        };
+        let interrupted = std::thread::scope(|s| {
+            s.spawn(|| {
+                drop_capability(CAP_DAC_OVERRIDE);
+                drop_capability(CAP_DAC_READ_SEARCH);
+                checkout(&repo, "main", &target, opts())
+            })
+            .join()
+            .unwrap()
+        });
+        assert!(interrupted.is_err());
+        let manifest = fs::read_to_string(target.join(CHECKOUT_PROGRESS_FILE)).unwrap();
+        assert_eq!(manifest, "a.txt\nb.txt\nc.txt\n");
+        assert!(!target.join("sub/e.txt").exists());
+
+        // without resume the partial checkout is just a non-empty target
+        let fresh = CheckoutOptions {
+            hardlink: false,
+            ..Default::default()
+        };
+        assert!(matches!(
+            checkout(&repo, "main", &target, fresh),
+            Err(Error::TargetNotEmpty(_))
+        ));
+
+        fs::set_permissions(&blob, fs::Permissions::from_mode(blob_mode)).unwrap();
+        let stats = checkout(&repo, "main", &target, opts()).unwrap();
+        assert_eq!(stats.skipped, 3);
+        assert_eq!(stats.written, 3); // d.txt, sub/e.txt, sub/link
+
+        assert!(!target.join(CHECKOUT_PROGRESS_FILE).exists());
+        assert_eq!(fs::read_to_string(target.join("d.txt")).unwrap(), "d.txt");
+        assert_eq!(
+            fs::metadata(target.join("sub/link")).unwrap().ino(),
+            fs::metadata(target.join("sub/e.txt")).unwrap().ino()
+        );
+        assert!(verify_checkout(&repo, "main", &target).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_progress_line_escaping() {
+        for path in ["plain", "new\nline", "back\\slash\\n", "trailing\\"] {
+            let line = escape_progress_line(path);
+            assert!(!line.contains('\n'));
+            assert_eq!(unescape_progress_line(line.as_bytes()), path);
+        }
+    }
+
+    #[test]
+    fn test_checkout_unprivileged_file_capability() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("bin")).unwrap();
+        let file_path = source.join("bin/ping");
+        fs::write(&file_path, "binary").unwrap();
+
+        // vfs_cap_data v2, effective, permitted cap_net_raw
+        let mut cap = vec![0x01, 0x00, 0x00, 0x02];
+        cap.extend_from_slice(&(1u32 << 13).to_le_bytes());
+        cap.extend_from_slice(&[0; 12]);
+        if xattr::set(&file_path, "security.capability", &cap).is_err() {
+            // no CAP_SETFCAP or no security xattrs here, skip test
+            return;
+        }
+        commit(&repo, &source, "caps", None, None).unwrap();
+
+        // checkout on a thread that can no longer set capabilities
+        let target = dir.path().join("target");
+        let sidecar = dir.path().join("caps.dump");
+        let sink = Arc::new(crate::diagnostics::CollectDiagnostics::new());
+        let stats = std::thread::scope(|s| {
+            s.spawn(|| {
+                drop_capability(CAP_SETFCAP);
+                let opts = CheckoutOptions {
+                    xattr_sidecar: Some(sidecar.clone()),
+                    diagnostics: Some(sink.clone()),
+                    ..Default::default()
+                };
+                checkout(&repo, "caps", &target, opts)
+            })
+            .join()
+            .unwrap()
+        })
+        .unwrap();
+
+        assert_eq!(fs::read(target.join("bin/ping")).unwrap(), b"binary");
+        assert_eq!(stats.unapplied_xattrs.len(), 1);
+        let (path, xattr) = &stats.unapplied_xattrs[0];
+        assert_eq!(path, "bin/ping");
+        assert_eq!(xattr.name, "security.capability");
+        assert_eq!(xattr.value, cap);
+        assert_eq!(
+            sink.warnings(),
+            vec![Warning::XattrSkipped {
+                path: target.join("bin/ping"),
+                name: "security.capability".to_string(),
+            }]
+        );
+
+        let hex: String = cap.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(
+            fs::read_to_string(&sidecar).unwrap(),
+            format!("# file: bin/ping\nsecurity.capability=0x{}\n\n", hex)
+        );
+
+        // the missing capability shows up when verifying the checkout
+        let found = verify_checkout(&repo, "caps", &target).unwrap();
+        assert!(found.contains(&CheckoutDiscrepancy {
+            path: "bin/ping".to_string(),
+            kind: DiscrepancyKind::MissingXattr("security.capability".to_string()),
+        }));
+    }
+
+    #[test]
+    fn test_checkout_without_xattr_support() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        let file_path = source.join("file.txt");
+        fs::write(&file_path, "content").unwrap();
+        if xattr::set(&file_path, "user.test", b"value").is_err() {
+            // xattrs not supported on this filesystem, skip test
+            return;
+        }
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        // the target behaves like tmpfs mounted without xattrs
+        let target = dir.path().join("target");
+        crate::fs::read::tests::without_xattr_support(&target);
+        let stats = checkout(&repo, "test", &target, Default::default()).unwrap();
+
+        assert_eq!(fs::read(target.join("file.txt")).unwrap(), b"content");
+        assert_eq!(stats.unapplied_xattrs.len(), 1);
+        assert_eq!(stats.unapplied_xattrs[0].0, "file.txt");
+        assert_eq!(stats.unapplied_xattrs[0].1.name, "user.test");
+    }
+
+    #[test]
+    fn test_escape_getfattr() {
+        assert_eq!(escape_getfattr("plain/path.txt"), "plain/path.txt");
+        assert_eq!(escape_getfattr("a b=c\\d\ne"), "a b\\075c\\134d\\012e");
+    }
+
+    #[test]
+    fn test_checkout_content_addressed_modes() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (dir, mut repo) = test_repo();
+        repo.config_mut().content_addressed_blobs = true;
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("rw"), "same bytes").unwrap();
+        fs::write(source.join("ro"), "same bytes").unwrap();
+        fs::set_permissions(source.join("rw"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(source.join("ro"), fs::Permissions::from_mode(0o555)).unwrap();
+        let commit_hash = commit(&repo, &source, "test", None, None).unwrap();
+
+        // both entries share a single content object
+        let tree = read_tree(&repo, &read_commit(&repo, &commit_hash).unwrap().tree).unwrap();
+        let rw = tree.get("rw").unwrap();
+        let ro = tree.get("ro").unwrap();
+        assert_ne!(rw.kind.hash(), ro.kind.hash());
+        assert!(rw.kind.content_hash().is_some());
+        assert_eq!(rw.kind.content_hash(), ro.kind.content_hash());
+        assert_eq!(fs::read_dir(repo.content_path()).unwrap().count(), 1);
+
+        let target = dir.path().join("target");
+        checkout(&repo, "test", &target, Default::default()).unwrap();
+
+        assert_eq!(fs::read_to_string(target.join("rw")).unwrap(), "same bytes");
+        assert_eq!(fs::read_to_string(target.join("ro")).unwrap(), "same bytes");
+        assert_eq!(
+            fs::metadata(target.join("rw")).unwrap().mode() & 0o7777,
+            0o755
+        );
+        assert_eq!(
+            fs::metadata(target.join("ro")).unwrap().mode() & 0o7777,
+            0o555
+        );
+    }
+
+    #[test]
+    fn test_checkout_after_toggling_content_addressed() {
+        let (dir, mut repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file"), "full bytes").unwrap();
+
+        // same file and metadata, so the same blob hash in every mode: the
+        // empty stubs must not stand in for the full blob, nor the reverse
+        for (i, content_addressed) in [true, false, true].into_iter().enumerate() {
+            repo.config_mut().content_addressed_blobs = content_addressed;
+            let ref_name = format!("mode{}", i);
+            commit(&repo, &source, &ref_name, None, None).unwrap();
+
+            for hardlink in [true, false] {
+                let target = dir.path().join(format!("{}-{}", ref_name, hardlink));
+                let opts = CheckoutOptions {
+                    hardlink,
+                    ..Default::default()
+                };
+                checkout(&repo, &ref_name, &target, opts).unwrap();
+                assert_eq!(
+                    fs::read_to_string(target.join("file")).unwrap(),
+                    "full bytes"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_checkout_sparse_without_preserve_sparse() {
+        use crate::object::write_blob;
+        use crate::types::SparseRegion;
+
+        let (dir, repo) = test_repo();
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+
+        // data at the start and at 1 MiB, then a trailing hole up to 2 MiB;
+        // the blob only holds the 8 data bytes
+        let size = 2 << 20;
+        let regions = vec![SparseRegion::new(0, 4), SparseRegion::new(1 << 20, 4)];
+        let blob = write_blob(&repo, b"headtail", uid, gid, 0o100644, &[]).unwrap();
+        let holes = write_blob(&repo, b"", uid, gid, 0o100600, &[]).unwrap();
+        let entries = vec![
+            TreeEntry::new(
+                "sparse",
+                EntryKind::Regular {
+                    hash: blob,
+                    size,
+                    sparse_map: Some(regions),
+                    xattrs: vec![],
+                    content: None,
+                    times: None,
+                },
+            ),
+            TreeEntry::new(
+                "holes",
+                EntryKind::Regular {
+                    hash: holes,
+                    size: 4096,
+                    sparse_map: Some(vec![]),
+                    xattrs: vec![],
+                    content: None,
+                    times: None,
+                },
+            ),
+        ];
+        let tree_hash = write_tree(&repo, &Tree::new(entries).unwrap()).unwrap();
+        let commit_hash =
+            write_commit(&repo, &Commit::new(tree_hash, vec![], "test", "sparse")).unwrap();
+        crate::refs::write_ref(&repo, "sparse", &commit_hash).unwrap();
+
+        let mut expected = vec![0u8; size as usize];
+        expected[..4].copy_from_slice(b"head");
+        expected[1 << 20..(1 << 20) + 4].copy_from_slice(b"tail");
+
+        for preserve_sparse in [false, true] {
+            let target = dir.path().join(format!("target-{}", preserve_sparse));
+            let opts = CheckoutOptions {
+                preserve_sparse,
+                ..Default::default()
+            };
+            checkout(&repo, "sparse", &target, opts).unwrap();
+
+            assert_eq!(fs::read(target.join("sparse")).unwrap(), expected);
+            assert_eq!(fs::read(target.join("holes")).unwrap(), vec![0u8; 4096]);
+            assert_eq!(
+                fs::metadata(target.join("holes")).unwrap().mode() & 0o7777,
+                0o600
+            );
+        }
+    }
 }
@@ -1,19 +1,169 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::Read;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 use rayon::prelude::*;
 use walkdir::WalkDir;
 
-use crate::error::{IoResultExt, Result};
-use crate::fs::{detect_sparse_regions, read_data_regions, read_xattrs, FileMetadata, FileType};
+use crate::diagnostics::{sink_or_stderr, Diagnostics, Warning};
+use crate::error::{Error, IoResultExt, Result};
+use crate::fs::{
+    detect_sparse_regions, read_data_regions, read_xattrs, read_xattrs_strict, FileMetadata,
+    FileType,
+};
 use crate::hash::{compute_symlink_hash, Hash, SYMLINK_MODE};
-use crate::namespace::outside_to_inside;
-use crate::object::{write_blob, write_commit, write_tree};
+use crate::namespace::{outside_to_inside, remap_acl_xattrs};
+use crate::object::{
+    read_commit, read_tree, write_blob_outcome, write_commit, write_regular_blob_outcome,
+    write_tree, WriteOutcome,
+};
+use crate::ops::ancestry::next_generation;
 use crate::refs::write_ref;
 use crate::repo::Repo;
-use crate::types::{Commit, EntryKind, Tree, TreeEntry};
+use crate::types::{Commit, EntryKind, EntryTimes, SparseRegion, Tree, TreeEntry, Xattr};
+
+/// validation hook run on the root tree before a commit is written
+pub type CommitValidator = Box<dyn Fn(&Tree, &Repo) -> Result<()>>;
+
+/// commit options
+#[derive(Default)]
+pub struct CommitOptions {
+    pub message: Option<String>,
+    pub author: Option<String>,
+    /// extra commit metadata key/value pairs
+    pub metadata: Vec<(String, String)>,
+    /// called with the fully-built root tree; an error aborts the commit
+    /// before the commit object or ref are written
+    pub validator: Option<CommitValidator>,
+    /// leave the repository out (with a warning) when it lies inside the
+    /// source, instead of failing with `SourceContainsRepo`
+    pub skip_repo: bool,
+    /// place the source tree under this `/`-separated path in the commit
+    /// (e.g. `usr/lib/foo`), synthesizing the directories along the way
+    pub mount_prefix: Option<String>,
+    /// ownership and permissions of directories synthesized for `mount_prefix`
+    pub prefix_dirs: PrefixDirMeta,
+    /// don't cross into other filesystems: mount points under the source are
+    /// recorded as empty directories, other entries on a different device
+    /// are left out
+    pub one_filesystem: bool,
+    /// what to do with regular files whose content or xattrs can't be read
+    pub on_unreadable: UnreadablePolicy,
+    /// what to do when the source filesystem has no xattr support
+    pub on_unsupported_xattrs: UnsupportedXattrPolicy,
+    /// record mtime, atime and (where available) btime on entries; off by
+    /// default since times change tree hashes
+    pub capture_times: bool,
+    /// which symlinks to replace by what they point to
+    pub dereference: DerefMode,
+    /// what to do with a symlink to dereference that leads nowhere
+    pub on_dangling_symlink: DanglingSymlinkPolicy,
+    /// record every entry as owned by this inside (uid, gid), whatever owns
+    /// it on disk; ownership is part of blob hashes, so this changes them
+    pub owner_override: Option<(u32, u32)>,
+    /// translate the uids/gids named in POSIX ACL xattrs to inside ids, as
+    /// entry ownership is; otherwise ACLs are recorded byte for byte
+    pub remap_acls: bool,
+    /// file of device nodes and fifos to add to the tree without them
+    /// existing on disk, one `path type major minor mode uid gid` per line
+    /// (see `read_device_spec`)
+    pub device_spec: Option<PathBuf>,
+    /// where warnings about what was left out go; stderr if unset
+    pub diagnostics: Option<Arc<dyn Diagnostics>>,
+}
+
+/// symlinks committed as their target's content and metadata
+///
+/// a followed link to a file becomes that file, one to a directory becomes
+/// the directory with everything in it. chains are followed up to
+/// `MAX_SYMLINK_DEPTH` links; a loop, in a chain or through directories,
+/// fails with `SymlinkLoop`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DerefMode {
+    /// keep every symlink as a symlink
+    #[default]
+    Never,
+    /// follow symlinks whose target resolves outside the source, so the
+    /// tree is self-contained; links within it are kept
+    External,
+    /// follow every symlink
+    Always,
+}
+
+/// handling of a dangling symlink that `dereference` would follow
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DanglingSymlinkPolicy {
+    /// abort the commit with `DanglingSymlink`
+    #[default]
+    Error,
+    /// leave it out of the tree, with a warning
+    Skip,
+}
+
+/// longest symlink chain followed when dereferencing, as in the kernel
+const MAX_SYMLINK_DEPTH: usize = 40;
+
+/// handling of regular files that can't be opened or read during commit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnreadablePolicy {
+    /// abort the commit
+    #[default]
+    Error,
+    /// leave the file out of the tree, with a warning
+    Skip,
+    /// commit an empty file with the original owner and mode, with a warning
+    Placeholder,
+}
+
+/// handling of a source filesystem without xattr support (ENOTSUP) during
+/// commit, e.g. tmpfs mounted without xattrs
+///
+/// recording no xattrs changes blob and tree hashes compared to a commit of
+/// the same files elsewhere, so the default refuses.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnsupportedXattrPolicy {
+    /// abort the commit with `XattrUnsupported`
+    #[default]
+    Error,
+    /// record such entries without xattrs, with a warning
+    Ignore,
+}
+
+/// result of `commit_with_report`
+#[derive(Debug)]
+pub struct CommitReport {
+    /// the new commit
+    pub hash: Hash,
+    /// sorted paths skipped or replaced by placeholders per `on_unreadable`
+    pub unreadable: Vec<String>,
+    /// blobs this commit stored
+    pub new_blobs: usize,
+    /// blobs written that the store already had
+    pub deduped_blobs: usize,
+}
+
+/// metadata for directories synthesized by `CommitOptions::mount_prefix`
+///
+/// uid/gid are inside ids, like every tree entry; mode holds permission bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixDirMeta {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+}
+
+impl Default for PrefixDirMeta {
+    fn default() -> Self {
+        Self {
+            uid: 0,
+            gid: 0,
+            mode: 0o755,
+        }
+    }
+}
 
 /// commit a directory tree to a ref
 pub fn commit(
@@ -35,9 +185,141 @@ pub fn commit_with_metadata(
     author: Option<&str>,
     metadata: &[(&str, &str)],
 ) -> Result<Hash> {
+    let opts = CommitOptions {
+        message: message.map(String::from),
+        author: author.map(String::from),
+        metadata: metadata
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+        validator: None,
+        skip_repo: false,
+        mount_prefix: None,
+        prefix_dirs: PrefixDirMeta::default(),
+        one_filesystem: false,
+        on_unreadable: UnreadablePolicy::Error,
+        on_unsupported_xattrs: UnsupportedXattrPolicy::Error,
+        capture_times: false,
+        dereference: DerefMode::Never,
+        on_dangling_symlink: DanglingSymlinkPolicy::Error,
+        owner_override: None,
+        remap_acls: false,
+        device_spec: None,
+        diagnostics: None,
+    };
+    commit_with_options(repo, source, ref_name, &opts)
+}
+
+/// where the repository sits inside `source`, if it does
+///
+/// the returned path is `source` joined with the repository's relative
+/// location, so it compares equal to paths met while walking `source`.
+/// paths are compared canonicalized and component-wise, so a sibling
+/// sharing a name prefix (`data` vs `data-repo`) doesn't match.
+pub fn repo_within_source(repo: &Repo, source: &Path) -> Result<Option<PathBuf>> {
+    let source_abs = fs::canonicalize(source).with_path(source)?;
+    let repo_abs = fs::canonicalize(repo.path()).with_path(repo.path())?;
+
+    Ok(repo_abs
+        .strip_prefix(&source_abs)
+        .ok()
+        .map(|rel| source.join(rel)))
+}
+
+/// commit a directory tree to a ref with full options
+pub fn commit_with_options(
+    repo: &Repo,
+    source: &Path,
+    ref_name: &str,
+    opts: &CommitOptions,
+) -> Result<Hash> {
+    commit_with_report(repo, source, ref_name, opts).map(|report| report.hash)
+}
+
+/// like `commit_with_options`, also reporting files handled by `on_unreadable`
+pub fn commit_with_report(
+    repo: &Repo,
+    source: &Path,
+    ref_name: &str,
+    opts: &CommitOptions,
+) -> Result<CommitReport> {
+    let built = build_tree(repo, source, opts)?;
+
+    // get parent commit if ref exists
+    let parents = match crate::refs::read_ref(repo, ref_name) {
+        Ok(parent) => vec![parent],
+        Err(crate::Error::RefNotFound(_)) => vec![],
+        Err(e) => return Err(e),
+    };
+
+    let commit_hash = write_commit_for_tree(repo, built.hash, parents, opts)?;
+
+    // update ref
+    write_ref(repo, ref_name, &commit_hash)?;
+
+    Ok(CommitReport {
+        hash: commit_hash,
+        unreadable: built.unreadable,
+        new_blobs: built.new_blobs,
+        deduped_blobs: built.deduped_blobs,
+    })
+}
+
+/// write a directory's objects and return its root tree hash
+///
+/// no commit or ref is created; the hash is the tree a `commit` of the
+/// same directory would point at.
+pub fn snapshot_tree(repo: &Repo, source: &Path) -> Result<Hash> {
+    snapshot_tree_with_options(repo, source, &CommitOptions::default())
+}
+
+/// like `snapshot_tree`, walking with `opts`
+///
+/// only the walk options apply (including `mount_prefix`); message, author,
+/// metadata and the validator belong to a commit and are ignored.
+pub fn snapshot_tree_with_options(
+    repo: &Repo,
+    source: &Path,
+    opts: &CommitOptions,
+) -> Result<Hash> {
+    build_tree(repo, source, opts).map(|built| built.hash)
+}
+
+/// root tree of a walked source, with what the walk ran into
+struct BuiltTree {
+    hash: Hash,
+    /// sorted paths handled by `on_unreadable`
+    unreadable: Vec<String>,
+    new_blobs: usize,
+    deduped_blobs: usize,
+}
+
+/// walk `source` into trees
+fn build_tree(repo: &Repo, source: &Path, opts: &CommitOptions) -> Result<BuiltTree> {
+    // never walk into the object store itself
+    let repo_in_source = repo_within_source(repo, source)?;
+    if let Some(inner) = &repo_in_source {
+        if !opts.skip_repo || inner.as_path() == source {
+            return Err(Error::SourceContainsRepo(inner.clone()));
+        }
+        sink_or_stderr(&opts.diagnostics).warn(Warning::RepoSkipped {
+            path: inner.clone(),
+        });
+    }
+    let skip = repo_in_source.as_deref();
+    let root_dev = if opts.one_filesystem {
+        Some(FileMetadata::from_path(source)?.dev)
+    } else {
+        None
+    };
+
     // phase 1: collect all files and detect hardlinks
     let mut hardlink_map = HashMap::new();
-    for entry in WalkDir::new(source).into_iter().filter_map(|e| e.ok()) {
+    let walker = WalkDir::new(source)
+        .same_file_system(opts.one_filesystem)
+        .into_iter()
+        .filter_entry(|e| Some(e.path()) != skip);
+    for entry in walker.filter_map(|e| e.ok()) {
         let path = entry.path();
         if let Ok(meta) = FileMetadata::from_path(path) {
             if meta.file_type == FileType::Regular && meta.could_be_hardlink() {
@@ -53,6 +335,13 @@ pub fn commit_with_metadata(
     for (_key, paths) in hardlink_map {
         if paths.len() > 1 {
             let first = &paths[0];
+            // links to a skipped file can't point at it; each is then
+            // handled (and skipped) as the unreadable file it is
+            if opts.on_unreadable == UnreadablePolicy::Skip
+                && File::open(source.join(first)).is_err()
+            {
+                continue;
+            }
             for path in paths.iter().skip(1) {
                 hardlink_targets.insert(path.clone(), first.clone());
             }
@@ -60,49 +349,665 @@ pub fn commit_with_metadata(
     }
 
     // phase 2: commit the root tree with parallel file processing
-    let tree_hash = commit_tree_parallel(repo, source, "", &hardlink_targets)?;
-
-    // get parent commit if ref exists
-    let parents = match crate::refs::read_ref(repo, ref_name) {
-        Ok(parent) => vec![parent],
-        Err(crate::Error::RefNotFound(_)) => vec![],
-        Err(e) => return Err(e),
+    let root = if opts.dereference == DerefMode::Never {
+        PathBuf::new()
+    } else {
+        fs::canonicalize(source).with_path(source)?
+    };
+    let ctx = WalkContext {
+        hardlink_targets,
+        skip,
+        root_dev,
+        on_unreadable: opts.on_unreadable,
+        unreadable: Mutex::new(Vec::new()),
+        new_blobs: AtomicUsize::new(0),
+        deduped_blobs: AtomicUsize::new(0),
+        on_unsupported_xattrs: opts.on_unsupported_xattrs,
+        warned_unsupported_xattrs: AtomicBool::new(false),
+        capture_times: opts.capture_times,
+        dereference: opts.dereference,
+        on_dangling_symlink: opts.on_dangling_symlink,
+        root,
+        walking: Mutex::new(Vec::new()),
+        owner_override: opts.owner_override,
+        remap_acls: opts.remap_acls,
+        diagnostics: sink_or_stderr(&opts.diagnostics),
     };
+    let mut tree_hash = commit_tree_parallel(repo, source, "", &ctx)?;
+    if let Some(prefix) = &opts.mount_prefix {
+        tree_hash = wrap_in_prefix(repo, tree_hash, prefix, &opts.prefix_dirs)?;
+    }
+    if let Some(spec) = &opts.device_spec {
+        let splices = read_device_spec(spec)?;
+        if !splices.is_empty() {
+            tree_hash = splice_tree(repo, Some(&tree_hash), splices, "", &opts.prefix_dirs)?;
+        }
+    }
+
+    let mut unreadable = ctx
+        .unreadable
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner());
+    unreadable.sort();
+    Ok(BuiltTree {
+        hash: tree_hash,
+        unreadable,
+        new_blobs: ctx.new_blobs.into_inner(),
+        deduped_blobs: ctx.deduped_blobs.into_inner(),
+    })
+}
+
+/// validate `tree_hash` and write a commit for it with `opts`' message,
+/// author and metadata
+fn write_commit_for_tree(
+    repo: &Repo,
+    tree_hash: Hash,
+    parents: Vec<Hash>,
+    opts: &CommitOptions,
+) -> Result<Hash> {
+    // run validation before anything points at the new tree
+    if let Some(validator) = &opts.validator {
+        let tree = read_tree(repo, &tree_hash)?;
+        validator(&tree, repo)?;
+    }
 
     // create commit with metadata
+    let generation = next_generation(repo, &parents)?;
     let mut commit = Commit::new(
         tree_hash,
         parents,
-        author.unwrap_or("zub"),
-        message.unwrap_or(""),
-    );
-    for (key, value) in metadata {
-        commit = commit.with_metadata(*key, *value);
+        opts.author.as_deref().unwrap_or("zub"),
+        opts.message.as_deref().unwrap_or(""),
+    )
+    .with_ns_fingerprint(repo.config().namespace.fingerprint());
+    if let Some(generation) = generation {
+        commit = commit.with_generation(generation);
+    }
+    for (key, value) in &opts.metadata {
+        commit = commit.with_metadata(key.as_str(), value.as_str());
     }
 
-    let commit_hash = write_commit(repo, &commit)?;
+    write_commit(repo, &commit)
+}
 
-    // update ref
-    write_ref(repo, ref_name, &commit_hash)?;
+/// commit a few paths on top of an existing ref
+///
+/// each change maps a logical path in the tree (e.g. `usr/lib/libfoo.so`) to
+/// a source file or directory whose entry replaces or adds that path. only the
+/// directories along the changed paths are rewritten; every other subtree keeps
+/// its hash. missing parent directories are created per `opts.prefix_dirs`.
+/// the new commit has `base_ref`'s commit as parent and is written to `output_ref`.
+///
+/// `opts` applies as for `commit_with_options`, except `skip_repo`,
+/// `mount_prefix`, `dereference` and `device_spec`, which are ignored.
+pub fn commit_paths(
+    repo: &Repo,
+    base_ref: &str,
+    changes: &[(PathBuf, &Path)],
+    output_ref: &str,
+    opts: &CommitOptions,
+) -> Result<Hash> {
+    let ctx = splice_context(opts);
+    let mut splices = Vec::with_capacity(changes.len());
+    for (logical, source) in changes {
+        let components = logical_components(logical)?;
+        let logical_path = components.join("/");
+        let meta = FileMetadata::from_path(source)?;
+        // a file skipped per `on_unreadable` leaves the base entry as is
+        if let Some(kind) = source_entry(repo, source, &logical_path, &meta, &ctx)? {
+            splices.push((components, Some(kind)));
+        }
+    }
 
+    commit_splices(repo, base_ref, splices, output_ref, opts)
+}
+
+/// commit the changes an external watcher saw under `source` since `base_ref`
+///
+/// `changed_paths` (relative to `source`, or absolute within it) are trusted
+/// to list every path added, modified or removed; nothing else is looked at.
+/// a listed path that exists replaces its entry in the base tree (a directory
+/// is committed whole), one that no longer exists is removed from it. every
+/// other subtree keeps its hash, as with `commit_paths`, and directories
+/// along the changed paths keep their base metadata unless listed themselves.
+///
+/// `opts` applies as for `commit_paths`.
+pub fn commit_with_changelist(
+    repo: &Repo,
+    source: &Path,
+    base_ref: &str,
+    changed_paths: &[PathBuf],
+    output_ref: &str,
+    opts: &CommitOptions,
+) -> Result<Hash> {
+    let mut changes = Vec::with_capacity(changed_paths.len());
+    for path in changed_paths {
+        let relative = path.strip_prefix(source).unwrap_or(path);
+        if relative.is_absolute() {
+            return Err(Error::InvalidEntryName(path.display().to_string()));
+        }
+        changes.push(logical_components(relative)?);
+    }
+    // a listed directory is committed whole, covering anything listed below it
+    changes.sort();
+    changes.dedup();
+    let mut covering: Vec<&Vec<String>> = Vec::new();
+    let mut listed = Vec::with_capacity(changes.len());
+    for components in &changes {
+        if covering.iter().any(|c| components.starts_with(c)) {
+            continue;
+        }
+        covering.push(components);
+        listed.push(components.clone());
+    }
+
+    let ctx = splice_context(opts);
+    let mut splices = Vec::with_capacity(listed.len());
+    for components in listed {
+        let logical_path = components.join("/");
+        let path = source.join(&logical_path);
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(_) => FileMetadata::from_path(&path)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                splices.push((components, None));
+                continue;
+            }
+            Err(e) => return Err(e).with_path(&path),
+        };
+        if let Some(kind) = source_entry(repo, &path, &logical_path, &meta, &ctx)? {
+            splices.push((components, Some(kind)));
+        }
+    }
+
+    commit_splices(repo, base_ref, splices, output_ref, opts)
+}
+
+/// splice entries into `base_ref`'s tree and commit the result on top of it
+fn commit_splices(
+    repo: &Repo,
+    base_ref: &str,
+    splices: Vec<Splice>,
+    output_ref: &str,
+    opts: &CommitOptions,
+) -> Result<Hash> {
+    let base = crate::refs::read_ref(repo, base_ref)?;
+    let base_tree = read_commit(repo, &base)?.tree;
+    let tree_hash = splice_tree(repo, Some(&base_tree), splices, "", &opts.prefix_dirs)?;
+    let commit_hash = write_commit_for_tree(repo, tree_hash, vec![base], opts)?;
+    write_ref(repo, output_ref, &commit_hash)?;
     Ok(commit_hash)
 }
 
+/// walk state for committing single paths: no hardlink detection across
+/// the source and no dereferencing
+fn splice_context(opts: &CommitOptions) -> WalkContext<'_> {
+    WalkContext {
+        hardlink_targets: HashMap::new(),
+        skip: None,
+        root_dev: None,
+        on_unreadable: opts.on_unreadable,
+        unreadable: Mutex::new(Vec::new()),
+        new_blobs: AtomicUsize::new(0),
+        deduped_blobs: AtomicUsize::new(0),
+        on_unsupported_xattrs: opts.on_unsupported_xattrs,
+        warned_unsupported_xattrs: AtomicBool::new(false),
+        capture_times: opts.capture_times,
+        dereference: DerefMode::Never,
+        on_dangling_symlink: opts.on_dangling_symlink,
+        root: PathBuf::new(),
+        walking: Mutex::new(Vec::new()),
+        owner_override: opts.owner_override,
+        remap_acls: opts.remap_acls,
+        diagnostics: sink_or_stderr(&opts.diagnostics),
+    }
+}
+
+/// the normal components of a logical path, which must have at least one
+fn logical_components(logical: &Path) -> Result<Vec<String>> {
+    let components = logical
+        .components()
+        .map(|c| match c {
+            Component::Normal(name) => Ok(name.to_string_lossy().to_string()),
+            _ => Err(Error::InvalidEntryName(logical.display().to_string())),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if components.is_empty() {
+        return Err(Error::InvalidEntryName(logical.display().to_string()));
+    }
+    Ok(components)
+}
+
+/// tree entry for a single source path, committing a directory whole
+///
+/// `None` when the file is unreadable and skipped per `on_unreadable`.
+fn source_entry(
+    repo: &Repo,
+    source: &Path,
+    logical_path: &str,
+    meta: &FileMetadata,
+    ctx: &WalkContext,
+) -> Result<Option<EntryKind>> {
+    if meta.file_type == FileType::Directory {
+        if let Some(inner) = repo_within_source(repo, source)? {
+            return Err(Error::SourceContainsRepo(inner));
+        }
+        return Ok(Some(directory_entry(
+            repo,
+            source,
+            logical_path,
+            meta,
+            ctx,
+        )?));
+    }
+    file_entry(repo, source, logical_path, meta, ctx)
+}
+
+/// an entry to place at (or, for `None`, remove from) a component path
+type Splice = (Vec<String>, Option<EntryKind>);
+
+/// rewrite `tree` with entries placed at (or, for `None`, removed from) the
+/// given component paths
+///
+/// `tree` is `None` for a directory synthesized along a new path.
+fn splice_tree(
+    repo: &Repo,
+    tree: Option<&Hash>,
+    splices: Vec<Splice>,
+    prefix: &str,
+    dir_meta: &PrefixDirMeta,
+) -> Result<Hash> {
+    let mut entries: BTreeMap<String, EntryKind> = match tree {
+        Some(hash) => read_tree(repo, hash)?
+            .into_entries()
+            .into_iter()
+            .map(|e| (e.name, e.kind))
+            .collect(),
+        None => BTreeMap::new(),
+    };
+
+    // split into entries replaced here and changes further down, per name
+    let mut here = BTreeMap::new();
+    let mut below: BTreeMap<String, Vec<Splice>> = BTreeMap::new();
+    for (mut components, kind) in splices {
+        let name = components.remove(0);
+        if components.is_empty() {
+            here.insert(name, kind);
+        } else {
+            below.entry(name).or_default().push((components, kind));
+        }
+    }
+    for (name, kind) in here {
+        match kind {
+            Some(kind) => entries.insert(name, kind),
+            None => entries.remove(&name),
+        };
+    }
+
+    for (name, nested) in below {
+        let path = if prefix.is_empty() {
+            name.clone()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+        let kind = match entries.remove(&name) {
+            Some(EntryKind::Directory {
+                hash,
+                uid,
+                gid,
+                mode,
+                xattrs,
+                times,
+            }) => {
+                let hash = splice_tree(repo, Some(&hash), nested, &path, dir_meta)?;
+                EntryKind::Directory {
+                    hash,
+                    uid,
+                    gid,
+                    mode,
+                    xattrs,
+                    times,
+                }
+            }
+            // removing something below a path that isn't a directory (any
+            // more) leaves nothing to do
+            Some(kind) if only_removals(&nested) => kind,
+            None if only_removals(&nested) => continue,
+            Some(_) => return Err(Error::PathNotFound(path)),
+            None => {
+                let hash = splice_tree(repo, None, nested, &path, dir_meta)?;
+                let mode = 0o40000 | (dir_meta.mode & 0o7777);
+                EntryKind::directory(hash, dir_meta.uid, dir_meta.gid, mode)
+            }
+        };
+        entries.insert(name, kind);
+    }
+
+    let entries = entries
+        .into_iter()
+        .map(|(name, kind)| TreeEntry::new(name, kind))
+        .collect();
+    let tree = Tree::with_policy(entries, &repo.config().entry_name_policy)?;
+    write_tree(repo, &tree)
+}
+
+/// parse a device spec file into entries to splice into the root tree
+///
+/// each line is `path type major minor mode uid gid`, as in mksquashfs
+/// pseudo files: `path` is taken from the root of the committed tree (after
+/// `mount_prefix`), `type` is `b` (block), `c` (char) or `p` (fifo, whose
+/// major and minor are ignored), `mode` holds octal permission bits and
+/// uid/gid are inside ids. blank lines and `#` comments are skipped. missing
+/// parent directories are created per `prefix_dirs`.
+fn read_device_spec(path: &Path) -> Result<Vec<Splice>> {
+    let text = fs::read_to_string(path).with_path(path)?;
+    let mut splices = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |message: &str| {
+            Error::InvalidDeviceSpec(format!("{}:{}: {}", path.display(), i + 1, message))
+        };
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let [logical, kind, major, minor, mode, uid, gid] = fields[..] else {
+            return Err(invalid("expected path type major minor mode uid gid"));
+        };
+        let number = |field: &str, radix| {
+            u32::from_str_radix(field, radix).map_err(|_| invalid(&format!("bad number {}", field)))
+        };
+        let (major, minor) = (number(major, 10)?, number(minor, 10)?);
+        let mode = number(mode, 8)? & 0o7777;
+        let (uid, gid) = (number(uid, 10)?, number(gid, 10)?);
+
+        let entry = match kind {
+            "b" => EntryKind::BlockDevice {
+                major,
+                minor,
+                uid,
+                gid,
+                mode: 0o060000 | mode,
+                xattrs: vec![],
+                times: None,
+            },
+            "c" => EntryKind::CharDevice {
+                major,
+                minor,
+                uid,
+                gid,
+                mode: 0o020000 | mode,
+                xattrs: vec![],
+                times: None,
+            },
+            "p" => EntryKind::Fifo {
+                uid,
+                gid,
+                mode: 0o010000 | mode,
+                xattrs: vec![],
+                times: None,
+            },
+            other => return Err(invalid(&format!("unknown type {}", other))),
+        };
+        let components = logical_components(Path::new(logical.trim_start_matches('/')))
+            .map_err(|_| invalid(&format!("bad path {}", logical)))?;
+        splices.push((components, Some(entry)));
+    }
+    Ok(splices)
+}
+
+/// whether every splice removes its path
+fn only_removals(splices: &[Splice]) -> bool {
+    splices.iter().all(|(_, kind)| kind.is_none())
+}
+
+/// nest `tree_hash` under each component of `prefix`, innermost first
+///
+/// the synthesized trees depend only on the prefix and `meta`, so the
+/// resulting root hash is deterministic.
+fn wrap_in_prefix(
+    repo: &Repo,
+    mut tree_hash: Hash,
+    prefix: &str,
+    meta: &PrefixDirMeta,
+) -> Result<Hash> {
+    let mode = 0o40000 | (meta.mode & 0o7777);
+    for name in prefix.split('/').filter(|c| !c.is_empty()).rev() {
+        let entry = TreeEntry::new(
+            name,
+            EntryKind::directory(tree_hash, meta.uid, meta.gid, mode),
+        );
+        let tree = Tree::with_policy(vec![entry], &repo.config().entry_name_policy)?;
+        tree_hash = write_tree(repo, &tree)?;
+    }
+    Ok(tree_hash)
+}
+
 /// processed file entry ready for tree building
 struct ProcessedEntry {
     name: String,
     kind: EntryKind,
 }
 
+/// state shared by the whole tree walk
+struct WalkContext<'a> {
+    /// logical path -> logical path of the first link to the same inode
+    hardlink_targets: HashMap<String, String>,
+    /// directory left out of the walk (the repository)
+    skip: Option<&'a Path>,
+    /// device of the source root, with `one_filesystem`
+    root_dev: Option<u64>,
+    on_unreadable: UnreadablePolicy,
+    /// logical paths handled by `on_unreadable`
+    unreadable: Mutex<Vec<String>>,
+    /// blobs written by the walk that were newly stored, and already there
+    new_blobs: AtomicUsize,
+    deduped_blobs: AtomicUsize,
+    on_unsupported_xattrs: UnsupportedXattrPolicy,
+    /// set once the `Ignore` warning has been raised
+    warned_unsupported_xattrs: AtomicBool,
+    capture_times: bool,
+    dereference: DerefMode,
+    on_dangling_symlink: DanglingSymlinkPolicy,
+    /// canonical source root, unless `dereference` is `Never`
+    root: PathBuf,
+    /// (dev, ino) of the directories being walked, unless `dereference` is
+    /// `Never`; a followed link back to one of them would never end
+    walking: Mutex<Vec<(u64, u64)>>,
+    owner_override: Option<(u32, u32)>,
+    remap_acls: bool,
+    diagnostics: &'a dyn Diagnostics,
+}
+
+/// what to commit for a symlink met in the walk
+enum Follow {
+    /// the symlink itself
+    Keep,
+    /// nothing, per `on_dangling_symlink`
+    Skip,
+    /// the file or directory it resolves to
+    Target(PathBuf),
+}
+
+/// end of a symlink chain
+enum Resolved {
+    /// an existing non-symlink
+    Target(PathBuf),
+    /// the missing path the chain ends at
+    Dangling(PathBuf),
+}
+
+impl WalkContext<'_> {
+    /// tally a blob write towards `new_blobs` or `deduped_blobs`
+    fn count_blob(&self, outcome: &WriteOutcome) {
+        let counter = if outcome.created {
+            &self.new_blobs
+        } else {
+            &self.deduped_blobs
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// inside (uid, gid) to record for an entry: `owner_override`, or its
+    /// on-disk owner mapped through the repo namespace
+    fn owner(&self, repo: &Repo, meta: &FileMetadata) -> Result<(u32, u32)> {
+        if let Some(owner) = self.owner_override {
+            return Ok(owner);
+        }
+        let ns = &repo.config().namespace;
+        let uid = outside_to_inside(meta.uid, &ns.uid_map).ok_or(Error::UnmappedUid(meta.uid))?;
+        let gid = outside_to_inside(meta.gid, &ns.gid_map).ok_or(Error::UnmappedGid(meta.gid))?;
+        Ok((uid, gid))
+    }
+
+    /// times to record for an entry, with `capture_times`
+    fn times(&self, meta: &FileMetadata) -> Option<EntryTimes> {
+        self.capture_times.then(|| meta.times())
+    }
+
+    /// xattrs to record for an entry, per `on_unsupported_xattrs` and
+    /// `remap_acls`
+    fn xattrs(&self, repo: &Repo, path: &Path) -> Result<Vec<Xattr>> {
+        let mut xattrs = match read_xattrs_strict(path) {
+            Err(Error::XattrUnsupported(_))
+                if self.on_unsupported_xattrs == UnsupportedXattrPolicy::Ignore =>
+            {
+                if !self.warned_unsupported_xattrs.swap(true, Ordering::Relaxed) {
+                    self.diagnostics.warn(Warning::XattrsUnsupported {
+                        path: path.to_path_buf(),
+                    });
+                }
+                vec![]
+            }
+            result => result?,
+        };
+        if self.remap_acls {
+            remap_acl_xattrs(&mut xattrs, &repo.config().namespace, outside_to_inside)?;
+        }
+        Ok(xattrs)
+    }
+
+    /// what to commit for the symlink at `path`, per `dereference`
+    fn follow(&self, path: &Path, logical_path: &str) -> Result<Follow> {
+        let (resolved, dangling) = match resolve_symlink(path)? {
+            Resolved::Target(target) => (target, false),
+            Resolved::Dangling(target) => (target, true),
+        };
+        if self.dereference == DerefMode::External && resolved.starts_with(&self.root) {
+            return Ok(Follow::Keep);
+        }
+        if !dangling {
+            return Ok(Follow::Target(resolved));
+        }
+        match self.on_dangling_symlink {
+            DanglingSymlinkPolicy::Error => Err(Error::DanglingSymlink(path.to_path_buf())),
+            DanglingSymlinkPolicy::Skip => {
+                self.diagnostics.warn(Warning::DanglingSymlinkSkipped {
+                    path: logical_path.to_string(),
+                });
+                Ok(Follow::Skip)
+            }
+        }
+    }
+}
+
+/// follow the symlink chain starting at `path` to its end
+///
+/// each link is resolved against the canonical directory holding it, so
+/// links in directory components are followed too. a chain revisiting a
+/// link or longer than `MAX_SYMLINK_DEPTH` fails with `SymlinkLoop`.
+fn resolve_symlink(path: &Path) -> Result<Resolved> {
+    let mut current = match canonical_parent(path, path)? {
+        Some(current) => current,
+        None => return Ok(Resolved::Dangling(path.to_path_buf())),
+    };
+    let mut seen = HashSet::new();
+    for _ in 0..MAX_SYMLINK_DEPTH {
+        if !seen.insert(current.clone()) {
+            return Err(Error::SymlinkLoop(path.to_path_buf()));
+        }
+        let target = fs::read_link(&current).with_path(&current)?;
+        let next = current.parent().unwrap_or(Path::new("/")).join(target);
+        current = match canonical_parent(&next, path)? {
+            Some(next) => next,
+            None => return Ok(Resolved::Dangling(next)),
+        };
+        match fs::symlink_metadata(&current) {
+            Ok(meta) if meta.file_type().is_symlink() => continue,
+            Ok(_) => return Ok(Resolved::Target(current)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Resolved::Dangling(current))
+            }
+            Err(e) => {
+                return Err(Error::Io {
+                    path: current,
+                    source: e,
+                })
+            }
+        }
+    }
+    Err(Error::SymlinkLoop(path.to_path_buf()))
+}
+
+/// `path` with its directory canonicalized and its last component (which
+/// may be a symlink) kept; none if the directory doesn't exist
+///
+/// a path ending in `..` or naming `/` is canonicalized whole.
+fn canonical_parent(path: &Path, link: &Path) -> Result<Option<PathBuf>> {
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => (parent, Some(name)),
+        (Some(_), Some(name)) => (Path::new("."), Some(name)),
+        _ => (path, None),
+    };
+    match fs::canonicalize(dir) {
+        Ok(dir) => Ok(Some(match name {
+            Some(name) => dir.join(name),
+            None => dir,
+        })),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) if e.raw_os_error() == Some(nix::errno::Errno::ELOOP as i32) => {
+            Err(Error::SymlinkLoop(link.to_path_buf()))
+        }
+        Err(e) => Err(Error::Io {
+            path: dir.to_path_buf(),
+            source: e,
+        }),
+    }
+}
+
+/// whether an entry lives on another filesystem than the commit root
+///
+/// `root_dev` is only set with `CommitOptions::one_filesystem`.
+fn on_other_filesystem(meta: &FileMetadata, root_dev: Option<u64>) -> bool {
+    root_dev.is_some_and(|dev| meta.dev != dev)
+}
+
 /// commit a directory tree with parallel file processing
-fn commit_tree_parallel(
-    repo: &Repo,
-    dir: &Path,
-    prefix: &str,
-    hardlink_targets: &HashMap<String, String>,
-) -> Result<Hash> {
-    let ns = &repo.config().namespace;
+///
+/// with `root_dev` set, directories on another device (mount points) are
+/// committed empty and other entries on another device are skipped.
+fn commit_tree_parallel(repo: &Repo, dir: &Path, prefix: &str, ctx: &WalkContext) -> Result<Hash> {
+    if ctx.dereference == DerefMode::Never {
+        return commit_dir_entries(repo, dir, prefix, ctx);
+    }
 
+    // a directory reached again through a followed link is a cycle
+    let meta = FileMetadata::from_path(dir)?;
+    let id = (meta.dev, meta.ino);
+    {
+        let mut walking = ctx.walking.lock().unwrap_or_else(|e| e.into_inner());
+        if walking.contains(&id) {
+            return Err(Error::SymlinkLoop(dir.to_path_buf()));
+        }
+        walking.push(id);
+    }
+    let result = commit_dir_entries(repo, dir, prefix, ctx);
+    ctx.walking.lock().unwrap_or_else(|e| e.into_inner()).pop();
+    result
+}
+
+/// commit the entries of `dir`, recursing through `commit_tree_parallel`
+fn commit_dir_entries(repo: &Repo, dir: &Path, prefix: &str, ctx: &WalkContext) -> Result<Hash> {
     // read directory entries
     let mut dir_entries: Vec<_> = fs::read_dir(dir)
         .with_path(dir)?
@@ -115,7 +1020,7 @@ fn commit_tree_parallel(
     let mut files = Vec::new();
 
     for entry in dir_entries {
-        let path = entry.path();
+        let mut path = entry.path();
         let name = entry.file_name().to_string_lossy().to_string();
         let logical_path = if prefix.is_empty() {
             name.clone()
@@ -123,11 +1028,27 @@ fn commit_tree_parallel(
             format!("{}/{}", prefix, name)
         };
 
-        let meta = FileMetadata::from_path(&path)?;
+        let mut meta = FileMetadata::from_path(&path)?;
+
+        // resolved here, not in the parallel file pass, so a followed link
+        // to a directory is walked in order with the others
+        if meta.file_type == FileType::Symlink && ctx.dereference != DerefMode::Never {
+            match ctx.follow(&path, &logical_path)? {
+                Follow::Keep => {}
+                Follow::Skip => continue,
+                Follow::Target(target) => {
+                    meta = FileMetadata::from_path(&target)?;
+                    path = target;
+                }
+            }
+        }
 
         if meta.file_type == FileType::Directory {
+            if Some(path.as_path()) == ctx.skip {
+                continue;
+            }
             directories.push((path, name, logical_path, meta));
-        } else {
+        } else if !on_other_filesystem(&meta, ctx.root_dev) {
             files.push((path, name, logical_path, meta));
         }
     }
@@ -136,164 +1057,247 @@ fn commit_tree_parallel(
     let dir_entries: Vec<ProcessedEntry> = directories
         .into_iter()
         .map(|(path, name, logical_path, meta)| {
-            let inside_uid = outside_to_inside(meta.uid, &ns.uid_map)
-                .ok_or(crate::Error::UnmappedUid(meta.uid))?;
-            let inside_gid = outside_to_inside(meta.gid, &ns.gid_map)
-                .ok_or(crate::Error::UnmappedGid(meta.gid))?;
-
-            let xattrs = read_xattrs(&path)?;
-            let subtree_hash = commit_tree_parallel(repo, &path, &logical_path, hardlink_targets)?;
-
-            let kind = EntryKind::directory_with_xattrs(
-                subtree_hash,
-                inside_uid,
-                inside_gid,
-                meta.mode,
-                xattrs,
-            );
-
+            let kind = directory_entry(repo, &path, &logical_path, &meta, ctx)?;
             Ok(ProcessedEntry { name, kind })
         })
         .collect::<Result<Vec<_>>>()?;
 
-    // process files in parallel
-    let file_entries: Vec<Result<ProcessedEntry>> = files
+    // process files in parallel; unreadable files skipped by policy are none
+    let file_entries: Vec<Result<Option<ProcessedEntry>>> = files
         .par_iter()
         .map(|(path, name, logical_path, meta)| {
-            let inside_uid = outside_to_inside(meta.uid, &ns.uid_map)
-                .ok_or(crate::Error::UnmappedUid(meta.uid))?;
-            let inside_gid = outside_to_inside(meta.gid, &ns.gid_map)
-                .ok_or(crate::Error::UnmappedGid(meta.gid))?;
-
-            let kind = match meta.file_type {
-                FileType::Regular => {
-                    // check for hardlink
-                    if let Some(target) = hardlink_targets.get(logical_path) {
-                        return Ok(ProcessedEntry {
-                            name: name.clone(),
-                            kind: EntryKind::hardlink(target.clone()),
-                        });
-                    }
-
-                    // read file content and xattrs
-                    let xattrs = read_xattrs(path)?;
-                    let mut file = File::open(path).with_path(path)?;
-
-                    // check for sparse file
-                    let sparse_regions = detect_sparse_regions(&file)?;
-
-                    let (content, sparse_map) = match sparse_regions {
-                        Some(ref regions) if !regions.is_empty() => {
-                            let data = read_data_regions(&mut file, regions)?;
-                            (data, Some(regions.clone()))
-                        }
-                        Some(_) => (vec![], Some(vec![])),
-                        None => {
-                            use std::io::Seek;
-                            file.seek(std::io::SeekFrom::Start(0)).with_path(path)?;
-                            let mut content = Vec::new();
-                            file.read_to_end(&mut content).with_path(path)?;
-                            (content, None)
-                        }
-                    };
-
-                    // write blob
-                    let hash =
-                        write_blob(repo, &content, inside_uid, inside_gid, meta.mode, &xattrs)?;
-
-                    match sparse_map {
-                        Some(map) => EntryKind::sparse(hash, meta.size, map, xattrs),
-                        None => EntryKind::regular(hash, meta.size, xattrs),
-                    }
+            // check for hardlink
+            if meta.file_type == FileType::Regular {
+                if let Some(target) = ctx.hardlink_targets.get(logical_path) {
+                    return Ok(Some(ProcessedEntry {
+                        name: name.clone(),
+                        kind: EntryKind::hardlink(target.clone()),
+                    }));
                 }
+            }
 
-                FileType::Symlink => {
-                    let target = crate::fs::read_symlink_target(path)?;
-                    let xattrs = read_xattrs(path)?;
-                    let hash = compute_symlink_hash(inside_uid, inside_gid, &xattrs, &target);
-                    write_blob(
-                        repo,
-                        target.as_bytes(),
-                        inside_uid,
-                        inside_gid,
-                        SYMLINK_MODE,
-                        &xattrs,
-                    )?;
-                    EntryKind::symlink(hash, xattrs)
-                }
+            let kind = file_entry(repo, path, logical_path, meta, ctx)?;
+            Ok(kind.map(|kind| ProcessedEntry {
+                name: name.clone(),
+                kind,
+            }))
+        })
+        .collect();
+
+    // collect file entries, propagating errors
+    let file_entries: Vec<ProcessedEntry> = file_entries
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect();
+
+    // combine and sort entries by name
+    let mut entries: Vec<TreeEntry> = dir_entries
+        .into_iter()
+        .chain(file_entries.into_iter())
+        .map(|e| TreeEntry::new(e.name, e.kind))
+        .collect();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // create and write tree
+    let tree = Tree::with_policy(entries, &repo.config().entry_name_policy)?;
+    write_tree(repo, &tree)
+}
+
+/// tree entry for a source directory, committing its contents
+///
+/// a mount point (with `root_dev`) is committed as an empty directory.
+fn directory_entry(
+    repo: &Repo,
+    path: &Path,
+    logical_path: &str,
+    meta: &FileMetadata,
+    ctx: &WalkContext,
+) -> Result<EntryKind> {
+    let (inside_uid, inside_gid) = ctx.owner(repo, meta)?;
+
+    // a mount point: keep the directory, not what's mounted on it
+    if on_other_filesystem(meta, ctx.root_dev) {
+        let empty = write_tree(repo, &Tree::new(vec![])?)?;
+        let kind = EntryKind::directory(empty, inside_uid, inside_gid, meta.mode)
+            .with_times(ctx.times(meta));
+        return Ok(kind);
+    }
+
+    let xattrs = ctx.xattrs(repo, path)?;
+    let subtree_hash = commit_tree_parallel(repo, path, logical_path, ctx)?;
 
-                FileType::BlockDevice => {
-                    let (major, minor) = meta.rdev.unwrap_or((0, 0));
-                    let xattrs = read_xattrs(path)?;
-                    EntryKind::BlockDevice {
-                        major,
-                        minor,
-                        uid: inside_uid,
-                        gid: inside_gid,
-                        mode: meta.mode,
-                        xattrs,
+    let kind =
+        EntryKind::directory_with_xattrs(subtree_hash, inside_uid, inside_gid, meta.mode, xattrs)
+            .with_times(ctx.times(meta));
+
+    Ok(kind)
+}
+
+/// tree entry for a non-directory source file, writing its blob
+///
+/// `None` when the file is unreadable and skipped per `on_unreadable`.
+fn file_entry(
+    repo: &Repo,
+    path: &Path,
+    logical_path: &str,
+    meta: &FileMetadata,
+    ctx: &WalkContext,
+) -> Result<Option<EntryKind>> {
+    let (inside_uid, inside_gid) = ctx.owner(repo, meta)?;
+
+    let kind = match meta.file_type {
+        FileType::Regular => {
+            let mut size = meta.size;
+            let (content, sparse_map, xattrs) = match ctx
+                .xattrs(repo, path)
+                .and_then(|xattrs| read_regular_data(path, xattrs))
+            {
+                Ok(read) => read,
+                Err(e @ (Error::Io { .. } | Error::Xattr { .. })) => {
+                    if ctx.on_unreadable == UnreadablePolicy::Error {
+                        return Err(e);
+                    }
+                    ctx.unreadable
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .push(logical_path.to_string());
+                    if ctx.on_unreadable == UnreadablePolicy::Skip {
+                        ctx.diagnostics.warn(Warning::UnreadableSkipped {
+                            path: logical_path.to_string(),
+                            message: e.to_string(),
+                        });
+                        return Ok(None);
                     }
+                    ctx.diagnostics.warn(Warning::UnreadablePlaceholder {
+                        path: logical_path.to_string(),
+                        message: e.to_string(),
+                    });
+                    size = 0;
+                    (vec![], None, vec![])
                 }
+                Err(e) => return Err(e),
+            };
+
+            // write blob
+            let (outcome, content_hash) = write_regular_blob_outcome(
+                repo, &content, inside_uid, inside_gid, meta.mode, &xattrs,
+            )?;
+            ctx.count_blob(&outcome);
+            let hash = outcome.hash;
+
+            match sparse_map {
+                Some(map) => EntryKind::sparse(hash, size, map, xattrs),
+                None => EntryKind::regular(hash, size, xattrs),
+            }
+            .with_content(content_hash)
+        }
+
+        FileType::Symlink => {
+            let target = crate::fs::read_symlink_target(path)?;
+            let xattrs = ctx.xattrs(repo, path)?;
+            let hash = compute_symlink_hash(inside_uid, inside_gid, &xattrs, &target);
+            let outcome = write_blob_outcome(
+                repo,
+                target.as_bytes(),
+                inside_uid,
+                inside_gid,
+                SYMLINK_MODE,
+                &xattrs,
+            )?;
+            ctx.count_blob(&outcome);
+            EntryKind::symlink(hash, xattrs)
+        }
+
+        FileType::BlockDevice => {
+            let (major, minor) = meta.rdev.unwrap_or((0, 0));
+            let xattrs = ctx.xattrs(repo, path)?;
+            EntryKind::BlockDevice {
+                major,
+                minor,
+                uid: inside_uid,
+                gid: inside_gid,
+                mode: meta.mode,
+                xattrs,
+                times: None,
+            }
+        }
+
+        FileType::CharDevice => {
+            let (major, minor) = meta.rdev.unwrap_or((0, 0));
+            let xattrs = ctx.xattrs(repo, path)?;
+            EntryKind::CharDevice {
+                major,
+                minor,
+                uid: inside_uid,
+                gid: inside_gid,
+                mode: meta.mode,
+                xattrs,
+                times: None,
+            }
+        }
 
-                FileType::CharDevice => {
-                    let (major, minor) = meta.rdev.unwrap_or((0, 0));
-                    let xattrs = read_xattrs(path)?;
-                    EntryKind::CharDevice {
-                        major,
-                        minor,
-                        uid: inside_uid,
-                        gid: inside_gid,
-                        mode: meta.mode,
-                        xattrs,
-                    }
-                }
+        FileType::Fifo => {
+            let xattrs = ctx.xattrs(repo, path)?;
+            EntryKind::Fifo {
+                uid: inside_uid,
+                gid: inside_gid,
+                mode: meta.mode,
+                xattrs,
+                times: None,
+            }
+        }
 
-                FileType::Fifo => {
-                    let xattrs = read_xattrs(path)?;
-                    EntryKind::Fifo {
-                        uid: inside_uid,
-                        gid: inside_gid,
-                        mode: meta.mode,
-                        xattrs,
-                    }
-                }
+        FileType::Socket => {
+            let xattrs = ctx.xattrs(repo, path)?;
+            EntryKind::Socket {
+                uid: inside_uid,
+                gid: inside_gid,
+                mode: meta.mode,
+                xattrs,
+                times: None,
+            }
+        }
 
-                FileType::Socket => {
-                    let xattrs = read_xattrs(path)?;
-                    EntryKind::Socket {
-                        uid: inside_uid,
-                        gid: inside_gid,
-                        mode: meta.mode,
-                        xattrs,
-                    }
-                }
+        FileType::Directory => {
+            unreachable!("directories handled separately")
+        }
+    }
+    .with_times(ctx.times(meta));
 
-                FileType::Directory => {
-                    unreachable!("directories handled separately")
-                }
-            };
+    Ok(Some(kind))
+}
 
-            Ok(ProcessedEntry {
-                name: name.clone(),
-                kind,
-            })
-        })
-        .collect();
+/// file content (data regions only if sparse), sparse map and xattrs
+pub(crate) type RegularFileData = (Vec<u8>, Option<Vec<SparseRegion>>, Vec<Xattr>);
 
-    // collect file entries, propagating errors
-    let file_entries: Vec<ProcessedEntry> = file_entries.into_iter().collect::<Result<Vec<_>>>()?;
+/// read a regular file's content and xattrs, detecting sparse regions
+pub(crate) fn read_regular_file(path: &Path) -> Result<RegularFileData> {
+    read_regular_data(path, read_xattrs(path)?)
+}
 
-    // combine and sort entries by name
-    let mut entries: Vec<TreeEntry> = dir_entries
-        .into_iter()
-        .chain(file_entries.into_iter())
-        .map(|e| TreeEntry::new(e.name, e.kind))
-        .collect();
-    entries.sort_by(|a, b| a.name.cmp(&b.name));
+/// read a regular file's content, detecting sparse regions, alongside
+/// xattrs already read
+fn read_regular_data(path: &Path, xattrs: Vec<Xattr>) -> Result<RegularFileData> {
+    let mut file = File::open(path).with_path(path)?;
 
-    // create and write tree
-    let tree = Tree::new(entries)?;
-    write_tree(repo, &tree)
+    let (content, sparse_map) = match detect_sparse_regions(&file)? {
+        Some(regions) if !regions.is_empty() => {
+            let data = read_data_regions(&mut file, &regions)?;
+            (data, Some(regions))
+        }
+        Some(_) => (vec![], Some(vec![])),
+        None => {
+            use std::io::Seek;
+            file.seek(std::io::SeekFrom::Start(0)).with_path(path)?;
+            let mut content = Vec::new();
+            file.read_to_end(&mut content).with_path(path)?;
+            (content, None)
+        }
+    };
+
+    Ok((content, sparse_map, xattrs))
 }
 
 /// count files in a directory (for progress reporting)
@@ -372,6 +1376,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_snapshot_tree_matches_commit() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("a/b")).unwrap();
+        fs::write(source.join("a/b/file.txt"), "deep").unwrap();
+        fs::write(source.join("top.txt"), "top").unwrap();
+        fs::hard_link(source.join("top.txt"), source.join("a/link.txt")).unwrap();
+
+        let tree_hash = snapshot_tree(&repo, &source).unwrap();
+        assert!(crate::refs::list_refs(&repo).unwrap().is_empty());
+
+        let hash = commit(&repo, &source, "snap", None, None).unwrap();
+        let commit_obj = crate::object::read_commit(&repo, &hash).unwrap();
+        assert_eq!(tree_hash, commit_obj.tree);
+    }
+
     #[test]
     fn test_commit_symlink() {
         let (dir, repo) = test_repo();
@@ -389,6 +1411,120 @@ mod tests {
         assert!(entry.kind.is_symlink());
     }
 
+    /// source with links into it and out of it:
+    /// source/{local.txt, inner -> local.txt, outer -> ../sdk/lib.so,
+    /// sdkdir -> ../sdk}, sdk/{lib.so, nested -> lib.so}
+    fn deref_fixture(dir: &Path) -> PathBuf {
+        let source = dir.join("source");
+        let sdk = dir.join("sdk");
+        fs::create_dir(&source).unwrap();
+        fs::create_dir(&sdk).unwrap();
+        fs::write(sdk.join("lib.so"), "sdk library").unwrap();
+        symlink("lib.so", sdk.join("nested")).unwrap();
+        fs::write(source.join("local.txt"), "local").unwrap();
+        symlink("local.txt", source.join("inner")).unwrap();
+        symlink("../sdk/lib.so", source.join("outer")).unwrap();
+        symlink("../sdk", source.join("sdkdir")).unwrap();
+        source
+    }
+
+    fn snapshot_deref(repo: &Repo, source: &Path, dereference: DerefMode) -> Result<Tree> {
+        let opts = CommitOptions {
+            dereference,
+            ..Default::default()
+        };
+        let hash = snapshot_tree_with_options(repo, source, &opts)?;
+        read_tree(repo, &hash)
+    }
+
+    #[test]
+    fn test_commit_dereference_modes() {
+        let (dir, repo) = test_repo();
+        let source = deref_fixture(dir.path());
+
+        let tree = snapshot_deref(&repo, &source, DerefMode::Never).unwrap();
+        for name in ["inner", "outer", "sdkdir"] {
+            assert!(tree.get(name).unwrap().kind.is_symlink(), "{}", name);
+        }
+
+        // external: links leaving the source become what they point to
+        let tree = snapshot_deref(&repo, &source, DerefMode::External).unwrap();
+        assert!(tree.get("inner").unwrap().kind.is_symlink());
+        let outer = tree.get("outer").unwrap();
+        assert!(matches!(outer.kind, EntryKind::Regular { size: 11, .. }));
+        let EntryKind::Directory { hash, .. } = &tree.get("sdkdir").unwrap().kind else {
+            panic!("expected sdkdir to be a directory");
+        };
+        let sdk = read_tree(&repo, hash).unwrap();
+        assert!(sdk.get("lib.so").unwrap().kind.is_regular());
+        // inside the followed directory, links within it also leave the source
+        assert!(sdk.get("nested").unwrap().kind.is_regular());
+
+        let tree = snapshot_deref(&repo, &source, DerefMode::Always).unwrap();
+        let inner = tree.get("inner").unwrap();
+        assert_eq!(
+            inner.kind.hash(),
+            tree.get("local.txt").unwrap().kind.hash()
+        );
+        assert!(inner.kind.is_regular());
+        assert!(tree.get("outer").unwrap().kind.is_regular());
+    }
+
+    #[test]
+    fn test_commit_dereference_loops() {
+        let (dir, repo) = test_repo();
+
+        // a chain of links that comes back to itself
+        let source = dir.path().join("chain");
+        fs::create_dir(&source).unwrap();
+        symlink("b", source.join("a")).unwrap();
+        symlink("a", source.join("b")).unwrap();
+        let err = snapshot_deref(&repo, &source, DerefMode::Always).unwrap_err();
+        assert!(matches!(err, Error::SymlinkLoop(_)), "{:?}", err);
+
+        // a directory link back to an ancestor
+        let source = dir.path().join("cycle");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        symlink("..", source.join("sub/up")).unwrap();
+        let err = snapshot_deref(&repo, &source, DerefMode::Always).unwrap_err();
+        assert!(matches!(err, Error::SymlinkLoop(_)), "{:?}", err);
+
+        // kept as symlinks, neither is a problem
+        snapshot_deref(&repo, &source, DerefMode::External).unwrap();
+        snapshot_deref(&repo, &dir.path().join("chain"), DerefMode::Never).unwrap();
+    }
+
+    #[test]
+    fn test_commit_dereference_dangling() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file"), "x").unwrap();
+        symlink("/nonexistent/zub/target", source.join("gone")).unwrap();
+
+        let err = snapshot_deref(&repo, &source, DerefMode::External).unwrap_err();
+        assert!(matches!(err, Error::DanglingSymlink(_)), "{:?}", err);
+
+        let sink = Arc::new(crate::diagnostics::CollectDiagnostics::new());
+        let opts = CommitOptions {
+            dereference: DerefMode::External,
+            on_dangling_symlink: DanglingSymlinkPolicy::Skip,
+            diagnostics: Some(sink.clone()),
+            ..Default::default()
+        };
+        let hash = snapshot_tree_with_options(&repo, &source, &opts).unwrap();
+        let tree = read_tree(&repo, &hash).unwrap();
+        assert!(tree.get("gone").is_none());
+        assert!(tree.get("file").is_some());
+        assert_eq!(
+            sink.warnings(),
+            vec![Warning::DanglingSymlinkSkipped {
+                path: "gone".to_string()
+            }]
+        );
+    }
+
     #[test]
     fn test_commit_hardlinks() {
         let (dir, repo) = test_repo();
@@ -454,4 +1590,879 @@ mod tests {
 
         assert!(tree.is_empty());
     }
+
+    #[test]
+    fn test_commit_refuses_source_containing_repo() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("data");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+
+        let repo_path = source.join("store");
+        let repo = Repo::init(&repo_path).unwrap();
+
+        let result = commit(&repo, &source, "test", None, None);
+        assert!(matches!(result, Err(Error::SourceContainsRepo(ref p)) if *p == repo_path));
+
+        // source that is the repo itself, reached through a relative path
+        let result = commit(&repo, &repo_path.join("objects/.."), "test", None, None);
+        assert!(matches!(result, Err(Error::SourceContainsRepo(_))));
+
+        // skip_repo leaves the store out of the tree
+        let sink = Arc::new(crate::diagnostics::CollectDiagnostics::new());
+        let opts = CommitOptions {
+            skip_repo: true,
+            diagnostics: Some(sink.clone()),
+            ..Default::default()
+        };
+        let hash = commit_with_options(&repo, &source, "test", &opts).unwrap();
+        let tree_hash = crate::object::read_commit(&repo, &hash).unwrap().tree;
+        let tree = read_tree(&repo, &tree_hash).unwrap();
+        let names: Vec<_> = tree.entries().iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["file.txt"]);
+        assert_eq!(
+            sink.warnings(),
+            vec![Warning::RepoSkipped { path: repo_path }]
+        );
+
+        // a sibling sharing a name prefix is not mistaken for the repo
+        let sibling_repo = Repo::init(&dir.path().join("data-repo")).unwrap();
+        commit(&sibling_repo, &source, "test", None, None).unwrap();
+    }
+
+    #[test]
+    fn test_commit_mount_prefix() {
+        use crate::ops::ls_tree::{ls_tree_recursive, LsTreeOptions};
+
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("build");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("libfoo.so"), "elf").unwrap();
+        fs::write(source.join("foo.conf"), "conf").unwrap();
+
+        let opts = CommitOptions {
+            mount_prefix: Some("/usr/lib/foo/".to_string()),
+            ..Default::default()
+        };
+        let first = commit_with_options(&repo, &source, "image", &opts).unwrap();
+
+        let entries = ls_tree_recursive(&repo, "image", &LsTreeOptions::default()).unwrap();
+        let paths: Vec<_> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "usr",
+                "usr/lib",
+                "usr/lib/foo",
+                "usr/lib/foo/foo.conf",
+                "usr/lib/foo/libfoo.so"
+            ]
+        );
+        match &entries[1].entry.kind {
+            EntryKind::Directory { uid, gid, mode, .. } => {
+                assert_eq!((*uid, *gid, *mode), (0, 0, 0o40755));
+            }
+            other => panic!("expected directory, got {:?}", other),
+        }
+
+        // same source and prefix give the same tree
+        let second = commit_with_options(&repo, &source, "image2", &opts).unwrap();
+        let tree_of = |h| crate::object::read_commit(&repo, &h).unwrap().tree;
+        assert_eq!(tree_of(first), tree_of(second));
+
+        let bad = CommitOptions {
+            mount_prefix: Some("usr/../etc".to_string()),
+            ..Default::default()
+        };
+        assert!(commit_with_options(&repo, &source, "bad", &bad).is_err());
+    }
+
+    #[test]
+    fn test_commit_device_spec() {
+        use crate::ops::ls_tree::{ls_tree_recursive, LsTreeOptions};
+
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("rootfs");
+        fs::create_dir_all(source.join("etc")).unwrap();
+        fs::write(source.join("etc/hostname"), "box").unwrap();
+        let spec = dir.path().join("devices");
+        fs::write(
+            &spec,
+            "# rootless /dev\n/dev/null c 1 3 666 0 0\n\ndev/sda b 8 0 660 0 6\nrun/initctl p 0 0 600 0 0\n",
+        )
+        .unwrap();
+
+        let opts = CommitOptions {
+            device_spec: Some(spec.clone()),
+            ..Default::default()
+        };
+        commit_with_options(&repo, &source, "image", &opts).unwrap();
+
+        let entries = ls_tree_recursive(&repo, "image", &LsTreeOptions::default()).unwrap();
+        let paths: Vec<_> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "dev",
+                "dev/null",
+                "dev/sda",
+                "etc",
+                "etc/hostname",
+                "run",
+                "run/initctl"
+            ]
+        );
+        let kind = |path: &str| &entries.iter().find(|e| e.path == path).unwrap().entry.kind;
+        assert!(matches!(
+            kind("dev/null"),
+            EntryKind::CharDevice {
+                major: 1,
+                minor: 3,
+                uid: 0,
+                gid: 0,
+                mode: 0o20666,
+                ..
+            }
+        ));
+        assert!(matches!(
+            kind("dev/sda"),
+            EntryKind::BlockDevice {
+                major: 8,
+                minor: 0,
+                gid: 6,
+                mode: 0o60660,
+                ..
+            }
+        ));
+        assert!(matches!(
+            kind("run/initctl"),
+            EntryKind::Fifo { mode: 0o10600, .. }
+        ));
+        assert!(matches!(
+            kind("dev"),
+            EntryKind::Directory {
+                uid: 0,
+                gid: 0,
+                mode: 0o40755,
+                ..
+            }
+        ));
+
+        fs::write(&spec, "/dev/null x 1 3 666 0 0\n").unwrap();
+        let result = commit_with_options(&repo, &source, "bad", &opts);
+        assert!(
+            matches!(result, Err(Error::InvalidDeviceSpec(_))),
+            "{:?}",
+            result
+        );
+        fs::write(&spec, "/dev/null c 1 3\n").unwrap();
+        let result = commit_with_options(&repo, &source, "bad", &opts);
+        assert!(
+            matches!(result, Err(Error::InvalidDeviceSpec(_))),
+            "{:?}",
+            result
+        );
+    }
+
+    #[test]
+    fn test_commit_one_filesystem() {
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("proc/1")).unwrap();
+        fs::write(source.join("proc/1/status"), "running").unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+
+        let mut meta = FileMetadata::from_path(&source).unwrap();
+        let dev = meta.dev;
+        assert!(!on_other_filesystem(&meta, None));
+        assert!(!on_other_filesystem(&meta, Some(dev)));
+        meta.dev = dev + 1;
+        assert!(on_other_filesystem(&meta, Some(dev)));
+
+        // everything here shares one device, so nothing is left out
+        let opts = CommitOptions {
+            one_filesystem: true,
+            ..Default::default()
+        };
+        let with = commit_with_options(&repo, &source, "one", &opts).unwrap();
+        let without = commit(&repo, &source, "all", None, None).unwrap();
+        let tree_of = |h| crate::object::read_commit(&repo, &h).unwrap().tree;
+        assert_eq!(tree_of(with), tree_of(without));
+
+        // pretend the root is on another device: every directory below it
+        // is then a mount point and every file foreign
+        let ctx = WalkContext {
+            hardlink_targets: HashMap::new(),
+            skip: None,
+            root_dev: Some(dev + 1),
+            on_unreadable: UnreadablePolicy::Error,
+            unreadable: Mutex::new(Vec::new()),
+            new_blobs: AtomicUsize::new(0),
+            deduped_blobs: AtomicUsize::new(0),
+            on_unsupported_xattrs: UnsupportedXattrPolicy::Error,
+            warned_unsupported_xattrs: AtomicBool::new(false),
+            capture_times: false,
+            dereference: DerefMode::Never,
+            on_dangling_symlink: DanglingSymlinkPolicy::Error,
+            root: PathBuf::new(),
+            walking: Mutex::new(Vec::new()),
+            owner_override: None,
+            remap_acls: false,
+            diagnostics: &crate::diagnostics::IgnoreDiagnostics,
+        };
+        let hash = commit_tree_parallel(&repo, &source, "", &ctx).unwrap();
+        let tree = read_tree(&repo, &hash).unwrap();
+        let names: Vec<_> = tree.entries().iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, vec!["proc"]);
+        let proc_hash = tree.get("proc").unwrap().kind.hash().unwrap();
+        assert!(read_tree(&repo, proc_hash).unwrap().entries().is_empty());
+    }
+
+    #[test]
+    fn test_commit_records_ns_fingerprint() {
+        use crate::namespace::{MapEntry, NsConfig};
+
+        let (dir, mut repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file"), "content").unwrap();
+
+        // same ids for root either way, but a different mapping overall
+        let configs = [
+            NsConfig::identity(),
+            NsConfig {
+                uid_map: vec![MapEntry::new(0, 0, 1), MapEntry::new(1, 100000, 65536)],
+                gid_map: vec![MapEntry::new(0, 0, 1), MapEntry::new(1, 100000, 65536)],
+            },
+        ];
+        let mut fingerprints = Vec::new();
+        for ns in configs {
+            repo.config_mut().namespace = ns.clone();
+            let hash = commit(&repo, &source, "test", None, None).unwrap();
+            let fingerprint = crate::object::read_commit(&repo, &hash)
+                .unwrap()
+                .ns_fingerprint;
+            assert_eq!(fingerprint, Some(ns.fingerprint()));
+            fingerprints.push(fingerprint);
+        }
+
+        assert_ne!(fingerprints[0], fingerprints[1]);
+    }
+
+    #[test]
+    fn test_commit_validator_rejects() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("ok.txt"), "fine").unwrap();
+        fs::write(source.join("sub/forbidden"), "nope").unwrap();
+
+        fn contains_forbidden(tree: &Tree, repo: &Repo) -> Result<bool> {
+            for entry in tree.entries() {
+                if entry.name == "forbidden" {
+                    return Ok(true);
+                }
+                if let EntryKind::Directory { hash, .. } = &entry.kind {
+                    if contains_forbidden(&read_tree(repo, hash)?, repo)? {
+                        return Ok(true);
+                    }
+                }
+            }
+            Ok(false)
+        }
+
+        let opts = CommitOptions {
+            validator: Some(Box::new(|tree, repo| {
+                if contains_forbidden(tree, repo)? {
+                    return Err(crate::Error::CommitRejected("forbidden file".to_string()));
+                }
+                Ok(())
+            })),
+            ..Default::default()
+        };
+
+        let result = commit_with_options(&repo, &source, "test", &opts);
+        assert!(matches!(result, Err(crate::Error::CommitRejected(_))));
+        assert!(!crate::refs::ref_exists(&repo, "test"));
+
+        // without the offending file the same validator lets it through
+        fs::remove_file(source.join("sub/forbidden")).unwrap();
+        commit_with_options(&repo, &source, "test", &opts).unwrap();
+        assert!(crate::refs::ref_exists(&repo, "test"));
+    }
+
+    /// drop CAP_DAC_OVERRIDE and CAP_DAC_READ_SEARCH from the calling
+    /// thread, so mode 000 files are unreadable even to root
+    fn drop_dac_caps() {
+        use nix::libc;
+
+        #[repr(C)]
+        struct Header {
+            version: u32,
+            pid: i32,
+        }
+        #[repr(C)]
+        #[derive(Clone, Copy, Default)]
+        struct Data {
+            effective: u32,
+            permitted: u32,
+            inheritable: u32,
+        }
+
+        let mut header = Header {
+            version: 0x20080522,
+            pid: 0,
+        };
+        let mut data = [Data::default(); 2];
+        let header = &mut header as *mut Header;
+        let data = data.as_mut_ptr();
+        unsafe {
+            assert_eq!(libc::syscall(libc::SYS_capget, header, data), 0);
+            (*data).effective &= !((1 << 1) | (1 << 2));
+            assert_eq!(libc::syscall(libc::SYS_capset, header, data), 0);
+        }
+    }
+
+    /// commit `source` on a single thread without DAC override
+    fn commit_unprivileged(
+        repo: &Repo,
+        source: &Path,
+        policy: UnreadablePolicy,
+    ) -> Result<CommitReport> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .start_handler(|_| drop_dac_caps())
+            .build()
+            .unwrap();
+        pool.install(|| {
+            let opts = CommitOptions {
+                on_unreadable: policy,
+                ..Default::default()
+            };
+            commit_with_report(repo, source, "test", &opts)
+        })
+    }
+
+    fn unreadable_source(dir: &Path) -> PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let source = dir.join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("ok.txt"), "fine").unwrap();
+        fs::write(source.join("sub/secret"), "hidden").unwrap();
+        fs::set_permissions(source.join("sub/secret"), fs::Permissions::from_mode(0o000)).unwrap();
+        source
+    }
+
+    #[test]
+    fn test_commit_without_xattr_support() {
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file.txt"), "content").unwrap();
+        symlink("file.txt", source.join("sub/link")).unwrap();
+        crate::fs::read::tests::without_xattr_support(&source);
+
+        // refused by default, since dropping xattrs changes hashes
+        let result = commit(&repo, &source, "test", None, None);
+        assert!(
+            matches!(result, Err(Error::XattrUnsupported(_))),
+            "{:?}",
+            result
+        );
+        assert!(!crate::refs::ref_exists(&repo, "test"));
+
+        let opts = CommitOptions {
+            on_unsupported_xattrs: UnsupportedXattrPolicy::Ignore,
+            ..Default::default()
+        };
+        let hash = commit_with_options(&repo, &source, "test", &opts).unwrap();
+        let commit_obj = crate::object::read_commit(&repo, &hash).unwrap();
+        let tree = read_tree(&repo, &commit_obj.tree).unwrap();
+        let sub = read_tree(&repo, tree.get("sub").unwrap().kind.hash().unwrap()).unwrap();
+        assert!(matches!(
+            &sub.get("file.txt").unwrap().kind,
+            EntryKind::Regular { xattrs, .. } if xattrs.is_empty()
+        ));
+        assert!(matches!(
+            &sub.get("link").unwrap().kind,
+            EntryKind::Symlink { xattrs, .. } if xattrs.is_empty()
+        ));
+    }
+
+    #[test]
+    fn test_commit_report_counts_new_and_deduped_blobs() {
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("a.txt"), "same").unwrap();
+        fs::write(source.join("b.txt"), "same").unwrap();
+        fs::write(source.join("c.txt"), "other").unwrap();
+
+        // a.txt and b.txt share a blob, stored by whichever came first
+        let opts = CommitOptions::default();
+        let report = commit_with_report(&repo, &source, "test", &opts).unwrap();
+        assert_eq!((report.new_blobs, report.deduped_blobs), (2, 1));
+
+        // nothing new the second time round
+        let report = commit_with_report(&repo, &source, "test", &opts).unwrap();
+        assert_eq!((report.new_blobs, report.deduped_blobs), (0, 3));
+    }
+
+    #[test]
+    fn test_commit_unreadable_error() {
+        let (dir, repo) = test_repo();
+        let source = unreadable_source(dir.path());
+
+        let result = commit_unprivileged(&repo, &source, UnreadablePolicy::Error);
+        assert!(matches!(result, Err(Error::Io { .. })), "{:?}", result);
+        assert!(!crate::refs::ref_exists(&repo, "test"));
+    }
+
+    #[test]
+    fn test_commit_unreadable_skip() {
+        let (dir, repo) = test_repo();
+        let source = unreadable_source(dir.path());
+
+        let report = commit_unprivileged(&repo, &source, UnreadablePolicy::Skip).unwrap();
+        assert_eq!(report.unreadable, vec!["sub/secret".to_string()]);
+
+        let commit_obj = crate::object::read_commit(&repo, &report.hash).unwrap();
+        let tree = read_tree(&repo, &commit_obj.tree).unwrap();
+        assert!(tree.get("ok.txt").is_some());
+        let sub = read_tree(&repo, tree.get("sub").unwrap().kind.hash().unwrap()).unwrap();
+        assert!(sub.entries().is_empty());
+    }
+
+    #[test]
+    fn test_commit_unreadable_placeholder() {
+        let (dir, repo) = test_repo();
+        let source = unreadable_source(dir.path());
+
+        let report = commit_unprivileged(&repo, &source, UnreadablePolicy::Placeholder).unwrap();
+        assert_eq!(report.unreadable, vec!["sub/secret".to_string()]);
+
+        let commit_obj = crate::object::read_commit(&repo, &report.hash).unwrap();
+        let tree = read_tree(&repo, &commit_obj.tree).unwrap();
+        let sub = read_tree(&repo, tree.get("sub").unwrap().kind.hash().unwrap()).unwrap();
+        match &sub.get("secret").unwrap().kind {
+            EntryKind::Regular { hash, size, .. } => {
+                assert_eq!(*size, 0);
+                assert!(crate::object::read_blob(&repo, hash).unwrap().is_empty());
+            }
+            other => panic!("expected regular file, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_commit_capture_times() {
+        use crate::fs::set_times;
+        use crate::ops::{checkout, ls_tree, CheckoutOptions, LsTreeOptions};
+        use crate::types::Timespec;
+        use std::os::unix::fs::MetadataExt;
+
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+
+        let mtime = Timespec::new(1_000_000_000, 5);
+        let atime = Timespec::new(1_100_000_000, 0);
+        let times = EntryTimes {
+            mtime,
+            atime,
+            btime: None,
+        };
+        set_times(&source.join("file.txt"), &times).unwrap();
+
+        // off by default, so existing tree hashes don't change
+        commit(&repo, &source, "plain", None, None).unwrap();
+        let plain = ls_tree(&repo, "plain", None, &LsTreeOptions::default()).unwrap();
+        assert!(plain[0].entry.kind.times().is_none());
+
+        // reading the file for the first commit moved its atime
+        set_times(&source.join("file.txt"), &times).unwrap();
+        let opts = CommitOptions {
+            capture_times: true,
+            ..Default::default()
+        };
+        commit_with_options(&repo, &source, "timed", &opts).unwrap();
+        let timed = ls_tree(&repo, "timed", None, &LsTreeOptions::default()).unwrap();
+        let recorded = timed[0].entry.kind.times().unwrap();
+        assert_eq!(recorded.mtime, mtime);
+        assert_eq!(recorded.atime, atime);
+        // btime depends on statx and the filesystem; either way is fine
+
+        let target = dir.path().join("checkout");
+        checkout(&repo, "timed", &target, CheckoutOptions::default()).unwrap();
+        let meta = fs::metadata(target.join("file.txt")).unwrap();
+        assert_eq!((meta.mtime(), meta.mtime_nsec()), (1_000_000_000, 5));
+        // restoring times must not touch the shared blob
+        assert_ne!(
+            meta.ino(),
+            fs::metadata(crate::object::blob_path(
+                &repo,
+                timed[0].entry.kind.hash().unwrap()
+            ))
+            .unwrap()
+            .ino()
+        );
+    }
+
+    #[test]
+    fn test_commit_paths_shares_subtrees() {
+        use crate::ops::{checkout, CheckoutOptions};
+
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("usr/lib/foo")).unwrap();
+        fs::create_dir_all(source.join("usr/share")).unwrap();
+        fs::create_dir_all(source.join("etc")).unwrap();
+        fs::write(source.join("usr/lib/foo/libfoo.so"), "old").unwrap();
+        fs::write(source.join("usr/lib/foo/other"), "other").unwrap();
+        fs::write(source.join("usr/lib/bar"), "bar").unwrap();
+        fs::write(source.join("usr/share/doc"), "doc").unwrap();
+        fs::write(source.join("etc/conf"), "conf").unwrap();
+        let base = commit(&repo, &source, "base", None, None).unwrap();
+
+        let patch = dir.path().join("libfoo.so");
+        fs::write(&patch, "new").unwrap();
+        let opts = CommitOptions {
+            message: Some("patch libfoo".to_string()),
+            ..Default::default()
+        };
+        let patched = commit_paths(
+            &repo,
+            "base",
+            &[(PathBuf::from("usr/lib/foo/libfoo.so"), patch.as_path())],
+            "patched",
+            &opts,
+        )
+        .unwrap();
+
+        let commit = read_commit(&repo, &patched).unwrap();
+        assert_eq!(commit.parents, vec![base]);
+        assert_eq!(crate::refs::read_ref(&repo, "base").unwrap(), base);
+
+        let old_root = read_commit(&repo, &base).unwrap().tree;
+        let subtree = |root: &Hash, path: &str| {
+            let mut hash = *root;
+            for name in path.split('/') {
+                hash = *read_tree(&repo, &hash)
+                    .unwrap()
+                    .get(name)
+                    .unwrap()
+                    .kind
+                    .hash()
+                    .unwrap();
+            }
+            hash
+        };
+
+        // siblings along the path keep their hashes, the path itself changes
+        for sibling in ["etc", "usr/share", "usr/lib/bar", "usr/lib/foo/other"] {
+            assert_eq!(subtree(&old_root, sibling), subtree(&commit.tree, sibling));
+        }
+        for changed in ["usr", "usr/lib", "usr/lib/foo", "usr/lib/foo/libfoo.so"] {
+            assert_ne!(subtree(&old_root, changed), subtree(&commit.tree, changed));
+        }
+
+        let target = dir.path().join("target");
+        checkout(&repo, "patched", &target, CheckoutOptions::default()).unwrap();
+        assert_eq!(
+            fs::read_to_string(target.join("usr/lib/foo/libfoo.so")).unwrap(),
+            "new"
+        );
+        assert_eq!(fs::read_to_string(target.join("etc/conf")).unwrap(), "conf");
+
+        // a path through a file is rejected
+        let err = commit_paths(
+            &repo,
+            "base",
+            &[(PathBuf::from("etc/conf/x"), patch.as_path())],
+            "patched",
+            &opts,
+        );
+        assert!(matches!(err, Err(Error::PathNotFound(p)) if p == "etc/conf"));
+    }
+
+    #[test]
+    fn test_commit_with_changelist() {
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("usr/lib")).unwrap();
+        fs::create_dir_all(source.join("usr/share")).unwrap();
+        fs::create_dir_all(source.join("etc")).unwrap();
+        fs::write(source.join("usr/lib/libfoo.so"), "old").unwrap();
+        fs::write(source.join("usr/lib/other"), "other").unwrap();
+        fs::write(source.join("usr/share/doc"), "doc").unwrap();
+        fs::write(source.join("etc/conf"), "conf").unwrap();
+        fs::write(source.join("etc/stale"), "stale").unwrap();
+        let base = commit(&repo, &source, "base", None, None).unwrap();
+        let old_root = read_commit(&repo, &base).unwrap().tree;
+
+        // unlisted changes are not picked up
+        fs::write(source.join("usr/lib/libfoo.so"), "new").unwrap();
+        fs::write(source.join("usr/share/doc"), "unseen").unwrap();
+        let changed = commit_with_changelist(
+            &repo,
+            &source,
+            "base",
+            &[PathBuf::from("usr/lib/libfoo.so")],
+            "changed",
+            &CommitOptions::default(),
+        )
+        .unwrap();
+        let commit = read_commit(&repo, &changed).unwrap();
+        assert_eq!(commit.parents, vec![base]);
+
+        let lookup = |root: &Hash, path: &str| {
+            let mut hash = *root;
+            for name in path.split('/') {
+                hash = *read_tree(&repo, &hash).unwrap().get(name)?.kind.hash()?;
+            }
+            Some(hash)
+        };
+        for untouched in ["etc", "usr/share", "usr/lib/other"] {
+            assert_eq!(
+                lookup(&old_root, untouched),
+                lookup(&commit.tree, untouched)
+            );
+        }
+        for updated in ["usr", "usr/lib", "usr/lib/libfoo.so"] {
+            assert_ne!(lookup(&old_root, updated), lookup(&commit.tree, updated));
+        }
+        let blob = lookup(&commit.tree, "usr/lib/libfoo.so").unwrap();
+        assert_eq!(crate::object::read_blob(&repo, &blob).unwrap(), b"new");
+
+        // removed paths leave the tree; absolute paths and ones that were
+        // never there are fine
+        fs::remove_file(source.join("etc/stale")).unwrap();
+        let pruned = commit_with_changelist(
+            &repo,
+            &source,
+            "changed",
+            &[source.join("etc/stale"), PathBuf::from("never/there")],
+            "changed",
+            &CommitOptions::default(),
+        )
+        .unwrap();
+        let tree = read_commit(&repo, &pruned).unwrap().tree;
+        assert!(lookup(&tree, "etc/stale").is_none());
+        assert!(lookup(&tree, "etc/conf").is_some());
+        assert!(lookup(&tree, "never").is_none());
+        assert_eq!(lookup(&tree, "usr"), lookup(&commit.tree, "usr"));
+
+        // paths outside the source are rejected
+        let err = commit_with_changelist(
+            &repo,
+            &source,
+            "changed",
+            &[dir.path().join("elsewhere")],
+            "changed",
+            &CommitOptions::default(),
+        );
+        assert!(matches!(err, Err(Error::InvalidEntryName(_))));
+    }
+
+    #[test]
+    fn test_commit_sparse_file_blob_stored_sparse() {
+        use crate::object::{blob_logical_size, blob_stored_size};
+        use crate::ops::checkout::{checkout, CheckoutOptions};
+        use std::io::{Seek, SeekFrom, Write};
+
+        for content_mode in [false, true] {
+            let dir = tempdir().unwrap();
+            let mut repo = Repo::init(&dir.path().join("repo")).unwrap();
+            repo.config_mut().content_addressed_blobs = content_mode;
+
+            // a disk image: a header, 1 MiB of written-out zeros, a hole up to
+            // 8 MiB and a trailer. the zeros are data, so they land in the blob
+            let source = dir.path().join("source");
+            fs::create_dir(&source).unwrap();
+            let path = source.join("disk.img");
+            let size = 8 << 20;
+            {
+                let mut file = fs::File::create(&path).unwrap();
+                file.write_all(&[0xab; 4096]).unwrap();
+                file.write_all(&vec![0u8; 1 << 20]).unwrap();
+                file.write_all(&[0xcd; 4096]).unwrap();
+                file.seek(SeekFrom::Start(size - 4096)).unwrap();
+                file.write_all(&[0xef; 4096]).unwrap();
+            }
+            let expected = fs::read(&path).unwrap();
+
+            let hash = commit(&repo, &source, "main", None, None).unwrap();
+            let commit_obj = crate::object::read_commit(&repo, &hash).unwrap();
+            let tree = crate::object::read_tree(&repo, &commit_obj.tree).unwrap();
+            let EntryKind::Regular {
+                hash: blob,
+                sparse_map,
+                content,
+                ..
+            } = &tree.get("disk.img").unwrap().kind
+            else {
+                panic!("disk.img should be a regular file");
+            };
+            if sparse_map.is_none() {
+                // filesystem without hole detection
+                return;
+            }
+            assert_eq!(content.is_some(), content_mode);
+
+            let logical = blob_logical_size(&repo, blob, content.as_ref()).unwrap();
+            let stored = blob_stored_size(&repo, blob, content.as_ref()).unwrap();
+            assert!(logical < size);
+            assert!(stored < logical, "stored {} of {} bytes", stored, logical);
+
+            let stats = crate::ops::stats(&repo).unwrap();
+            assert!(stats.total_blobs_stored_bytes < stats.total_blobs_bytes);
+
+            for hardlink in [true, false] {
+                let target = dir.path().join(format!("target-{}", hardlink));
+                let opts = CheckoutOptions {
+                    hardlink,
+                    ..Default::default()
+                };
+                checkout(&repo, "main", &target, opts).unwrap();
+                assert_eq!(fs::read(target.join("disk.img")).unwrap(), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_commit_owner_override() {
+        use crate::ops::ls_tree::{ls_tree_recursive, LsTreeOptions};
+        use std::os::unix::fs::{chown, lchown};
+
+        let (dir, repo) = test_repo();
+
+        // build output owned by a build user
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("bin")).unwrap();
+        fs::write(source.join("bin/tool"), "tool").unwrap();
+        fs::write(source.join("README"), "readme").unwrap();
+        symlink("bin/tool", source.join("tool")).unwrap();
+        for path in ["bin", "bin/tool", "README"] {
+            chown(source.join(path), Some(1000), Some(1000)).unwrap();
+        }
+        lchown(source.join("tool"), Some(1000), Some(1000)).unwrap();
+
+        let long = LsTreeOptions {
+            long: true,
+            ..Default::default()
+        };
+        let owners = |ref_name| -> Vec<(String, u32, u32)> {
+            ls_tree_recursive(&repo, ref_name, &long)
+                .unwrap()
+                .into_iter()
+                .map(|e| {
+                    let meta = e.metadata.unwrap();
+                    (e.path, meta.uid, meta.gid)
+                })
+                .collect()
+        };
+
+        commit(&repo, &source, "as-is", None, None).unwrap();
+        assert!(owners("as-is")
+            .iter()
+            .all(|(_, uid, gid)| (*uid, *gid) == (1000, 1000)));
+
+        let opts = CommitOptions {
+            owner_override: Some((0, 0)),
+            ..Default::default()
+        };
+        commit_with_options(&repo, &source, "root", &opts).unwrap();
+        let root_owned = owners("root");
+        assert_eq!(root_owned.len(), 4);
+        assert!(root_owned
+            .iter()
+            .all(|(_, uid, gid)| (*uid, *gid) == (0, 0)));
+
+        // the same files under different owners hash differently
+        let tree_of = |r| {
+            let hash = crate::refs::resolve_ref(&repo, r).unwrap();
+            crate::object::read_commit(&repo, &hash).unwrap().tree
+        };
+        assert_ne!(tree_of("as-is"), tree_of("root"));
+    }
+
+    #[test]
+    fn test_commit_remap_acls() {
+        use crate::namespace::{MapEntry, NsConfig};
+        use crate::ops::checkout::{checkout, CheckoutOptions};
+
+        let (dir, mut repo) = test_repo();
+        repo.config_mut().namespace = NsConfig {
+            uid_map: vec![MapEntry::new(0, 0, 1), MapEntry::new(1, 100000, 65536)],
+            gid_map: vec![MapEntry::new(0, 0, 1), MapEntry::new(1, 100000, 65536)],
+        };
+
+        // user::rw-, user:101000:r--, group::r--, mask::r--, other::---
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in [
+            (0x01u16, 6u16, u32::MAX),
+            (0x02, 4, 101000),
+            (0x04, 4, u32::MAX),
+            (0x10, 4, u32::MAX),
+            (0x20, 0, u32::MAX),
+        ] {
+            acl.extend_from_slice(&tag.to_le_bytes());
+            acl.extend_from_slice(&perm.to_le_bytes());
+            acl.extend_from_slice(&id.to_le_bytes());
+        }
+        let named_user = |value: &[u8]| {
+            value[4..]
+                .chunks_exact(8)
+                .find(|e| e[..2] == 0x02u16.to_le_bytes())
+                .map(|e| u32::from_le_bytes(e[4..8].try_into().unwrap()))
+        };
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        let file = source.join("file");
+        fs::write(&file, "content").unwrap();
+        if xattr::set(&file, "system.posix_acl_access", &acl).is_err() {
+            // no ACL support here
+            return;
+        }
+
+        let recorded = |ref_name| {
+            let hash = crate::refs::resolve_ref(&repo, ref_name).unwrap();
+            let tree = read_tree(
+                &repo,
+                &crate::object::read_commit(&repo, &hash).unwrap().tree,
+            );
+            match &tree.unwrap().entries()[0].kind {
+                EntryKind::Regular { xattrs, .. } => xattrs
+                    .iter()
+                    .find(|x| x.name == "system.posix_acl_access")
+                    .map(|x| named_user(&x.value).unwrap()),
+                other => panic!("unexpected entry {:?}", other),
+            }
+        };
+
+        commit(&repo, &source, "raw", None, None).unwrap();
+        assert_eq!(recorded("raw"), Some(101000));
+
+        let opts = CommitOptions {
+            remap_acls: true,
+            ..Default::default()
+        };
+        commit_with_options(&repo, &source, "remapped", &opts).unwrap();
+        assert_eq!(recorded("remapped"), Some(1001));
+
+        // checkout translates the ACL back
+        let target = dir.path().join("target");
+        let opts = CheckoutOptions {
+            remap_acls: true,
+            ..Default::default()
+        };
+        checkout(&repo, "remapped", &target, opts).unwrap();
+        let on_disk = xattr::get(target.join("file"), "system.posix_acl_access")
+            .unwrap()
+            .unwrap();
+        assert_eq!(named_user(&on_disk), Some(101000));
+    }
 }
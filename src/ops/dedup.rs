@@ -0,0 +1,148 @@
+//! content-only duplicate analysis for blobs
+
+use std::collections::HashMap;
+use std::fs::File;
+
+use walkdir::WalkDir;
+
+use crate::error::{IoResultExt, Result};
+use crate::hash::Hash;
+use crate::repo::Repo;
+
+/// a piece of content stored in more than one blob
+#[derive(Debug, Clone)]
+pub struct DuplicateContent {
+    /// blake3 of the raw content, ignoring metadata
+    pub content_hash: Hash,
+    /// size of the content in bytes
+    pub size: u64,
+    /// blobs holding this content (differing in uid/gid/mode/xattrs)
+    pub blobs: Vec<Hash>,
+}
+
+/// result of a dedup analysis
+#[derive(Debug, Default)]
+pub struct DedupReport {
+    pub blobs_scanned: usize,
+    pub total_bytes: u64,
+    /// number of distinct contents across all blobs
+    pub unique_contents: usize,
+    /// contents stored more than once, largest savings first
+    pub duplicates: Vec<DuplicateContent>,
+    /// bytes that content-only storage would save
+    pub reclaimable_bytes: u64,
+}
+
+/// group blobs by content alone and report what content-only storage would save
+///
+/// diagnostic only: reads and re-hashes every blob, nothing is modified.
+pub fn analyze_dedup(repo: &Repo) -> Result<DedupReport> {
+    let mut report = DedupReport::default();
+    let mut groups: HashMap<Hash, (u64, Vec<Hash>)> = HashMap::new();
+
+    let blobs_dir = repo.blobs_path();
+    if !blobs_dir.exists() {
+        return Ok(report);
+    }
+
+    for entry in WalkDir::new(&blobs_dir).min_depth(2).max_depth(2) {
+        let entry = entry.map_err(|e| crate::Error::Io {
+            path: blobs_dir.clone(),
+            source: e
+                .into_io_error()
+                .unwrap_or_else(|| std::io::Error::other("walkdir error")),
+        })?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path();
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let parent_name = path
+            .parent()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .unwrap_or("");
+
+        let blob_hash = match Hash::from_hex(&format!("{}{}", parent_name, file_name)) {
+            Ok(h) => h,
+            Err(_) => continue,
+        };
+
+        let mut file = File::open(path).with_path(path)?;
+        let mut hasher = blake3::Hasher::new();
+        hasher.update_reader(&mut file).with_path(path)?;
+        let content_hash = Hash::from_bytes(*hasher.finalize().as_bytes());
+        let size = file.metadata().with_path(path)?.len();
+
+        report.blobs_scanned += 1;
+        report.total_bytes += size;
+
+        groups
+            .entry(content_hash)
+            .or_insert_with(|| (size, Vec::new()))
+            .1
+            .push(blob_hash);
+    }
+
+    report.unique_contents = groups.len();
+
+    for (content_hash, (size, mut blobs)) in groups {
+        if blobs.len() < 2 {
+            continue;
+        }
+        blobs.sort();
+        report.reclaimable_bytes += size * (blobs.len() as u64 - 1);
+        report.duplicates.push(DuplicateContent {
+            content_hash,
+            size,
+            blobs,
+        });
+    }
+
+    report.duplicates.sort_by(|a, b| {
+        let saved_a = a.size * (a.blobs.len() as u64 - 1);
+        let saved_b = b.size * (b.blobs.len() as u64 - 1);
+        saved_b
+            .cmp(&saved_a)
+            .then_with(|| a.content_hash.cmp(&b.content_hash))
+    });
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::write_blob;
+    use tempfile::tempdir;
+
+    fn test_repo() -> (tempfile::TempDir, Repo) {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        let repo = Repo::init(&repo_path).unwrap();
+        (dir, repo)
+    }
+
+    #[test]
+    fn test_same_content_different_mode() {
+        let (_dir, repo) = test_repo();
+
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+        let content = b"#!/bin/sh\necho hello\n";
+
+        let a = write_blob(&repo, content, uid, gid, 0o100755, &[]).unwrap();
+        let b = write_blob(&repo, content, uid, gid, 0o100555, &[]).unwrap();
+        write_blob(&repo, b"unrelated", uid, gid, 0o100644, &[]).unwrap();
+        assert_ne!(a, b);
+
+        let report = analyze_dedup(&repo).unwrap();
+
+        assert_eq!(report.blobs_scanned, 3);
+        assert_eq!(report.unique_contents, 2);
+        assert_eq!(report.duplicates.len(), 1);
+        assert_eq!(report.duplicates[0].blobs.len(), 2);
+        assert_eq!(report.reclaimable_bytes, content.len() as u64);
+    }
+}
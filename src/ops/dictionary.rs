@@ -0,0 +1,146 @@
+use std::path::Path;
+
+use walkdir::WalkDir;
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::hash::Hash;
+use crate::object::decompress_object;
+use crate::repo::Repo;
+
+/// maximum size of a trained dictionary
+const DICTIONARY_SIZE: usize = 32 * 1024;
+
+/// maximum number of objects sampled for training
+const MAX_SAMPLES: usize = 10_000;
+
+/// train a zstd dictionary from the repo's trees and commits
+///
+/// the result is meant for `InitOptions::dictionary` on a new repository;
+/// an existing repository's dictionary can't be changed.
+pub fn train_dictionary(repo: &Repo) -> Result<Vec<u8>> {
+    let mut samples = Vec::new();
+    for dir in [repo.trees_path(), repo.commits_path()] {
+        collect_samples(repo, &dir, &mut samples)?;
+    }
+
+    zstd::dict::from_samples(&samples, DICTIONARY_SIZE).map_err(|e| Error::Io {
+        path: "<zstd>".into(),
+        source: e,
+    })
+}
+
+/// decompress objects under `dir` into `samples`, up to `MAX_SAMPLES` in total
+fn collect_samples(repo: &Repo, dir: &Path, samples: &mut Vec<Vec<u8>>) -> Result<()> {
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    for entry in WalkDir::new(dir)
+        .min_depth(2)
+        .max_depth(2)
+        .sort_by_file_name()
+    {
+        if samples.len() >= MAX_SAMPLES {
+            break;
+        }
+        let entry = entry.map_err(|e| Error::Io {
+            path: dir.to_path_buf(),
+            source: e
+                .into_io_error()
+                .unwrap_or_else(|| std::io::Error::other("walkdir error")),
+        })?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let path = entry.path();
+        let name = format!(
+            "{}{}",
+            path.parent()
+                .and_then(|p| p.file_name())
+                .and_then(|n| n.to_str())
+                .unwrap_or(""),
+            path.file_name().and_then(|n| n.to_str()).unwrap_or("")
+        );
+        let Ok(hash) = Hash::from_hex(&name) else {
+            continue;
+        };
+
+        let compressed = std::fs::read(path).with_path(path)?;
+        samples.push(decompress_object(
+            &compressed,
+            repo.zstd_dictionary(),
+            repo.config().max_object_size,
+            &hash,
+            path,
+        )?);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{read_tree, write_blob, write_tree};
+    use crate::types::{EntryKind, Tree, TreeEntry};
+    use tempfile::tempdir;
+
+    fn test_repo() -> (tempfile::TempDir, Repo) {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        let repo = Repo::init(&repo_path).unwrap();
+        (dir, repo)
+    }
+
+    fn sample_tree(repo: &Repo, i: usize) -> Tree {
+        let entries = ["bin", "etc", "lib", "usr"]
+            .iter()
+            .map(|name| {
+                let data = format!("{name}-{i}");
+                let blob = write_blob(repo, data.as_bytes(), 0, 0, 0o100644, &[]).unwrap();
+                TreeEntry::new(
+                    format!("{name}.conf"),
+                    EntryKind::regular(blob, data.len() as u64, vec![]),
+                )
+            })
+            .collect();
+        Tree::new(entries).unwrap()
+    }
+
+    #[test]
+    fn test_dictionary_shrinks_trees() {
+        let (dir, plain) = test_repo();
+        for i in 0..200 {
+            write_tree(&plain, &sample_tree(&plain, i)).unwrap();
+        }
+        let dict = train_dictionary(&plain).unwrap();
+        assert!(!dict.is_empty());
+
+        let dict_path = dir.path().join("dict");
+        let opts = crate::repo::InitOptions {
+            dictionary: Some(dict.clone()),
+            ..Default::default()
+        };
+        let with_dict = Repo::init_with_options(&dict_path, &opts).unwrap();
+        assert!(with_dict.dictionary_path().exists());
+
+        let tree = sample_tree(&plain, 1000);
+        let plain_hash = write_tree(&plain, &tree).unwrap();
+        let dict_hash = write_tree(&with_dict, &sample_tree(&with_dict, 1000)).unwrap();
+
+        let size = |repo: &Repo, hash: &Hash| {
+            let (d, f) = hash.to_path_components();
+            std::fs::metadata(repo.trees_path().join(d).join(f))
+                .unwrap()
+                .len()
+        };
+        assert!(size(&with_dict, &dict_hash) < size(&plain, &plain_hash));
+
+        // round-trips, including after reopening
+        assert_eq!(read_tree(&with_dict, &dict_hash).unwrap(), tree);
+        let reopened = Repo::open(&dict_path).unwrap();
+        assert_eq!(reopened.zstd_dictionary(), Some(&dict[..]));
+        assert_eq!(read_tree(&reopened, &dict_hash).unwrap(), tree);
+    }
+}
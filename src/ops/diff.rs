@@ -1,23 +1,72 @@
-use crate::error::Result;
-use crate::hash::Hash;
-use crate::object::{read_commit, read_tree};
+use std::collections::BTreeMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{IoResultExt, Result};
+use crate::fs::{read_xattrs, FileMetadata, FileType};
+use crate::hash::{compute_blob_hash, compute_content_hash, compute_symlink_hash, Hash};
+use crate::namespace::outside_to_inside;
+use crate::object::{blob_meta_path, read_blob, read_blob_data, read_commit, read_tree};
+use crate::ops::commit::read_regular_file;
 use crate::refs::resolve_ref;
 use crate::repo::Repo;
-use crate::types::{ChangeKind, DiffEntry, EntryKind, Tree};
+use crate::types::{ChangeKind, DiffEntry, EntryKind, EntryTimes, Tree, Xattr};
+
+/// metadata differences for `diff_with_options` to leave out
+///
+/// an entry whose content is unchanged and whose other differences are all
+/// ignored isn't reported. the default ignores nothing, like `diff`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiffOptions {
+    /// ignore all metadata (owner, mode, xattrs, times): content changes only
+    pub ignore_metadata: bool,
+    /// ignore permission bit changes
+    pub ignore_mode: bool,
+    /// ignore uid/gid changes
+    pub ignore_owner: bool,
+}
+
+impl DiffOptions {
+    fn ignores_any(&self) -> bool {
+        self.ignore_metadata || self.ignore_mode || self.ignore_owner
+    }
+}
 
 /// compare two refs and return list of changes
 pub fn diff(repo: &Repo, ref1: &str, ref2: &str) -> Result<Vec<DiffEntry>> {
+    diff_with_options(repo, ref1, ref2, &DiffOptions::default())
+}
+
+/// compare two refs, leaving out the metadata changes `opts` ignores
+pub fn diff_with_options(
+    repo: &Repo,
+    ref1: &str,
+    ref2: &str,
+    opts: &DiffOptions,
+) -> Result<Vec<DiffEntry>> {
     let commit1 = resolve_ref(repo, ref1)?;
     let commit2 = resolve_ref(repo, ref2)?;
 
     let tree1 = read_commit(repo, &commit1)?.tree;
     let tree2 = read_commit(repo, &commit2)?.tree;
 
-    diff_trees(repo, &tree1, &tree2, "")
+    diff_trees_with_options(repo, &tree1, &tree2, "", opts)
 }
 
 /// compare two tree hashes
 pub fn diff_trees(repo: &Repo, tree1: &Hash, tree2: &Hash, prefix: &str) -> Result<Vec<DiffEntry>> {
+    diff_trees_with_options(repo, tree1, tree2, prefix, &DiffOptions::default())
+}
+
+/// compare two tree hashes, leaving out the metadata changes `opts` ignores
+pub fn diff_trees_with_options(
+    repo: &Repo,
+    tree1: &Hash,
+    tree2: &Hash,
+    prefix: &str,
+    opts: &DiffOptions,
+) -> Result<Vec<DiffEntry>> {
     // if trees are identical, no changes
     if tree1 == tree2 {
         return Ok(vec![]);
@@ -26,11 +75,17 @@ pub fn diff_trees(repo: &Repo, tree1: &Hash, tree2: &Hash, prefix: &str) -> Resu
     let t1 = read_tree(repo, tree1)?;
     let t2 = read_tree(repo, tree2)?;
 
-    diff_tree_contents(repo, &t1, &t2, prefix)
+    diff_tree_contents(repo, &t1, &t2, prefix, opts)
 }
 
 /// compare two tree contents
-fn diff_tree_contents(repo: &Repo, t1: &Tree, t2: &Tree, prefix: &str) -> Result<Vec<DiffEntry>> {
+fn diff_tree_contents(
+    repo: &Repo,
+    t1: &Tree,
+    t2: &Tree,
+    prefix: &str,
+    opts: &DiffOptions,
+) -> Result<Vec<DiffEntry>> {
     let mut changes = Vec::new();
 
     // collect all names
@@ -95,6 +150,7 @@ fn diff_tree_contents(repo: &Repo, t1: &Tree, t2: &Tree, prefix: &str) -> Result
                             gid: g1,
                             mode: m1,
                             xattrs: x1,
+                            times: t1,
                         },
                         EntryKind::Directory {
                             hash: h2,
@@ -102,15 +158,18 @@ fn diff_tree_contents(repo: &Repo, t1: &Tree, t2: &Tree, prefix: &str) -> Result
                             gid: g2,
                             mode: m2,
                             xattrs: x2,
+                            times: t2,
                         },
                     ) => {
                         // both directories - recurse
                         if h1 != h2 {
-                            let sub_changes = diff_trees(repo, h1, h2, &path)?;
+                            let sub_changes = diff_trees_with_options(repo, h1, h2, &path, opts)?;
                             changes.extend(sub_changes);
                         }
                         // check directory metadata (excluding tree hash which is content)
-                        if u1 != u2 || g1 != g2 || m1 != m2 || x1 != x2 {
+                        let meta1 = EntryMeta::new(*u1, *g1, *m1, x1, t1.as_ref());
+                        let meta2 = EntryMeta::new(*u2, *g2, *m2, x2, t2.as_ref());
+                        if meta1.differs(&meta2, opts) {
                             changes.push(DiffEntry {
                                 path,
                                 kind: ChangeKind::MetadataOnly,
@@ -120,6 +179,13 @@ fn diff_tree_contents(repo: &Repo, t1: &Tree, t2: &Tree, prefix: &str) -> Result
 
                     _ => {
                         // not both directories
+                        if e1.kind.type_name() == e2.kind.type_name()
+                            && e1.kind != e2.kind
+                            && opts.ignores_any()
+                            && only_ignored_changes(repo, &e1.kind, &e2.kind, opts)?
+                        {
+                            continue;
+                        }
                         if e1.kind.type_name() != e2.kind.type_name() {
                             // type changed (e.g., file -> symlink)
                             changes.push(DiffEntry {
@@ -153,6 +219,185 @@ fn diff_tree_contents(repo: &Repo, t1: &Tree, t2: &Tree, prefix: &str) -> Result
     Ok(changes)
 }
 
+/// the metadata of an entry, apart from what identifies its content
+#[derive(Debug, PartialEq)]
+struct EntryMeta<'a> {
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    xattrs: &'a [Xattr],
+    times: Option<&'a EntryTimes>,
+}
+
+impl<'a> EntryMeta<'a> {
+    fn new(
+        uid: u32,
+        gid: u32,
+        mode: u32,
+        xattrs: &'a [Xattr],
+        times: Option<&'a EntryTimes>,
+    ) -> Self {
+        Self {
+            uid,
+            gid,
+            mode,
+            xattrs,
+            times,
+        }
+    }
+
+    /// metadata of a non-directory entry; files and symlinks keep owner
+    /// and mode on their blob. `None` for hardlinks, which have none
+    fn of(repo: &Repo, kind: &'a EntryKind) -> Result<Option<Self>> {
+        let meta = match kind {
+            EntryKind::Regular {
+                hash,
+                xattrs,
+                times,
+                ..
+            }
+            | EntryKind::Symlink {
+                hash,
+                xattrs,
+                times,
+            } => {
+                let blob = blob_meta_path(repo, hash, kind.content_hash());
+                let stat = fs::symlink_metadata(&blob).with_path(&blob)?;
+                Self::new(stat.uid(), stat.gid(), stat.mode(), xattrs, times.as_ref())
+            }
+            EntryKind::Directory {
+                uid,
+                gid,
+                mode,
+                xattrs,
+                times,
+                ..
+            }
+            | EntryKind::BlockDevice {
+                uid,
+                gid,
+                mode,
+                xattrs,
+                times,
+                ..
+            }
+            | EntryKind::CharDevice {
+                uid,
+                gid,
+                mode,
+                xattrs,
+                times,
+                ..
+            }
+            | EntryKind::Fifo {
+                uid,
+                gid,
+                mode,
+                xattrs,
+                times,
+            }
+            | EntryKind::Socket {
+                uid,
+                gid,
+                mode,
+                xattrs,
+                times,
+            } => Self::new(*uid, *gid, *mode, xattrs, times.as_ref()),
+            EntryKind::Hardlink { .. } => return Ok(None),
+        };
+        Ok(Some(meta))
+    }
+
+    /// whether the two differ in something `opts` doesn't ignore
+    fn differs(&self, other: &Self, opts: &DiffOptions) -> bool {
+        if opts.ignore_metadata {
+            return false;
+        }
+        let owner = (self.uid, self.gid) != (other.uid, other.gid);
+        let mode = self.mode != other.mode;
+        (owner && !opts.ignore_owner)
+            || (mode && !opts.ignore_mode)
+            || self.xattrs != other.xattrs
+            || self.times != other.times
+    }
+}
+
+/// whether two different non-directory entries of the same type have the
+/// same content and differ only in metadata `opts` ignores
+fn only_ignored_changes(
+    repo: &Repo,
+    k1: &EntryKind,
+    k2: &EntryKind,
+    opts: &DiffOptions,
+) -> Result<bool> {
+    let same_content = match (k1, k2) {
+        (
+            EntryKind::Regular {
+                hash: h1,
+                size: s1,
+                sparse_map: m1,
+                content: c1,
+                ..
+            },
+            EntryKind::Regular {
+                hash: h2,
+                size: s2,
+                sparse_map: m2,
+                content: c2,
+                ..
+            },
+        ) => {
+            s1 == s2
+                && m1 == m2
+                && match (c1, c2) {
+                    (Some(c1), Some(c2)) => c1 == c2,
+                    _ => {
+                        h1 == h2
+                            || read_blob_data(repo, h1, c1.as_ref())?
+                                == read_blob_data(repo, h2, c2.as_ref())?
+                    }
+                }
+        }
+        (EntryKind::Symlink { hash: h1, .. }, EntryKind::Symlink { hash: h2, .. }) => {
+            h1 == h2 || read_blob(repo, h1)? == read_blob(repo, h2)?
+        }
+        (
+            EntryKind::BlockDevice {
+                major: a1,
+                minor: i1,
+                ..
+            },
+            EntryKind::BlockDevice {
+                major: a2,
+                minor: i2,
+                ..
+            },
+        )
+        | (
+            EntryKind::CharDevice {
+                major: a1,
+                minor: i1,
+                ..
+            },
+            EntryKind::CharDevice {
+                major: a2,
+                minor: i2,
+                ..
+            },
+        ) => (a1, i1) == (a2, i2),
+        (EntryKind::Fifo { .. }, EntryKind::Fifo { .. })
+        | (EntryKind::Socket { .. }, EntryKind::Socket { .. }) => true,
+        _ => false,
+    };
+    if !same_content {
+        return Ok(false);
+    }
+    match (EntryMeta::of(repo, k1)?, EntryMeta::of(repo, k2)?) {
+        (Some(m1), Some(m2)) => Ok(!m1.differs(&m2, opts)),
+        _ => Ok(false),
+    }
+}
+
 /// report all entries in a tree as added/deleted
 fn report_all_entries(
     repo: &Repo,
@@ -178,6 +423,257 @@ fn report_all_entries(
     Ok(())
 }
 
+/// compare a ref against a directory on disk, e.g. before committing it
+///
+/// the worktree is hashed the way commit would hash it, with ids mapped into
+/// the repo namespace, but nothing is written to the object store.
+pub fn diff_ref_worktree(repo: &Repo, ref_name: &str, worktree: &Path) -> Result<Vec<DiffEntry>> {
+    let commit = resolve_ref(repo, ref_name)?;
+    let tree = read_tree(repo, &read_commit(repo, &commit)?.tree)?;
+
+    let mut changes = Vec::new();
+    diff_tree_worktree(repo, &tree, worktree, worktree, "", &mut changes)?;
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(changes)
+}
+
+/// compare a tree with the directory `dir` of the worktree at `root`
+fn diff_tree_worktree(
+    repo: &Repo,
+    tree: &Tree,
+    root: &Path,
+    dir: &Path,
+    prefix: &str,
+    changes: &mut Vec<DiffEntry>,
+) -> Result<()> {
+    let on_disk = read_dir_names(dir)?;
+
+    let mut all_names: Vec<&str> = tree
+        .entries()
+        .iter()
+        .map(|e| e.name.as_str())
+        .chain(on_disk.keys().map(|n| n.as_str()))
+        .collect();
+    all_names.sort();
+    all_names.dedup();
+
+    for name in all_names {
+        let path = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}/{}", prefix, name)
+        };
+
+        match (tree.get(name), on_disk.get(name)) {
+            (None, Some(fs_path)) => {
+                changes.push(DiffEntry {
+                    path: path.clone(),
+                    kind: ChangeKind::Added,
+                });
+                if FileMetadata::from_path(fs_path)?.file_type == FileType::Directory {
+                    report_worktree_entries(fs_path, &path, changes)?;
+                }
+            }
+
+            (Some(entry), None) => {
+                changes.push(DiffEntry {
+                    path: path.clone(),
+                    kind: ChangeKind::Deleted,
+                });
+                if let EntryKind::Directory { hash, .. } = &entry.kind {
+                    let subtree = read_tree(repo, hash)?;
+                    report_all_entries(repo, &subtree, &path, ChangeKind::Deleted, changes)?;
+                }
+            }
+
+            (Some(entry), Some(fs_path)) => {
+                let meta = FileMetadata::from_path(fs_path)?;
+
+                match (&entry.kind, meta.file_type) {
+                    (
+                        EntryKind::Directory {
+                            hash,
+                            uid,
+                            gid,
+                            mode,
+                            xattrs,
+                            ..
+                        },
+                        FileType::Directory,
+                    ) => {
+                        let (disk_uid, disk_gid) = inside_ids(repo, &meta)?;
+                        if *uid != disk_uid
+                            || *gid != disk_gid
+                            || *mode != meta.mode
+                            || *xattrs != read_xattrs(fs_path)?
+                        {
+                            changes.push(DiffEntry {
+                                path: path.clone(),
+                                kind: ChangeKind::MetadataOnly,
+                            });
+                        }
+                        let subtree = read_tree(repo, hash)?;
+                        diff_tree_worktree(repo, &subtree, root, fs_path, &path, changes)?;
+                    }
+
+                    (EntryKind::Hardlink { target_path }, FileType::Regular) => {
+                        // unchanged as long as it's still the same inode as
+                        // its target; the target's own content is compared
+                        // at the target's path
+                        let same_inode = FileMetadata::from_path(&root.join(target_path))
+                            .map(|t| t.dev == meta.dev && t.ino == meta.ino)
+                            .unwrap_or(false);
+                        if !same_inode {
+                            changes.push(DiffEntry {
+                                path,
+                                kind: ChangeKind::Modified,
+                            });
+                        }
+                    }
+
+                    (_, FileType::Directory) | (EntryKind::Directory { .. }, _) => {
+                        // type changed between directory and non-directory
+                        changes.push(DiffEntry {
+                            path,
+                            kind: ChangeKind::Modified,
+                        });
+                    }
+
+                    _ => {
+                        // recorded times aren't compared: reading files for
+                        // hashing alone moves atime, and btime can't be restored
+                        let disk = worktree_entry_kind(repo, fs_path, &meta)?
+                            .with_times(entry.kind.times().copied());
+                        if entry.kind.type_name() != disk.type_name()
+                            || entry.kind.hash() != disk.hash()
+                        {
+                            changes.push(DiffEntry {
+                                path,
+                                kind: ChangeKind::Modified,
+                            });
+                        } else if entry.kind != disk {
+                            changes.push(DiffEntry {
+                                path,
+                                kind: ChangeKind::MetadataOnly,
+                            });
+                        }
+                    }
+                }
+            }
+
+            (None, None) => unreachable!(),
+        }
+    }
+
+    Ok(())
+}
+
+/// directory entries by (lossy) name, as commit names them
+fn read_dir_names(dir: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let mut names = BTreeMap::new();
+    for entry in fs::read_dir(dir).with_path(dir)? {
+        let entry = entry.with_path(dir)?;
+        names.insert(
+            entry.file_name().to_string_lossy().to_string(),
+            entry.path(),
+        );
+    }
+    Ok(names)
+}
+
+/// report everything below a worktree directory as added
+fn report_worktree_entries(dir: &Path, prefix: &str, changes: &mut Vec<DiffEntry>) -> Result<()> {
+    for (name, fs_path) in read_dir_names(dir)? {
+        let path = format!("{}/{}", prefix, name);
+        changes.push(DiffEntry {
+            path: path.clone(),
+            kind: ChangeKind::Added,
+        });
+        if FileMetadata::from_path(&fs_path)?.file_type == FileType::Directory {
+            report_worktree_entries(&fs_path, &path, changes)?;
+        }
+    }
+    Ok(())
+}
+
+/// owner of a worktree entry mapped into the repo namespace
+fn inside_ids(repo: &Repo, meta: &FileMetadata) -> Result<(u32, u32)> {
+    let ns = &repo.config().namespace;
+    let uid =
+        outside_to_inside(meta.uid, &ns.uid_map).ok_or(crate::Error::UnmappedUid(meta.uid))?;
+    let gid =
+        outside_to_inside(meta.gid, &ns.gid_map).ok_or(crate::Error::UnmappedGid(meta.gid))?;
+    Ok((uid, gid))
+}
+
+/// the tree entry commit would record for a non-directory, without storing it
+fn worktree_entry_kind(repo: &Repo, path: &Path, meta: &FileMetadata) -> Result<EntryKind> {
+    let (uid, gid) = inside_ids(repo, meta)?;
+
+    let kind = match meta.file_type {
+        FileType::Regular => {
+            let (content, sparse_map, xattrs) = read_regular_file(path)?;
+            let hash = compute_blob_hash(uid, gid, meta.mode, &xattrs, &content);
+            let content_hash = repo
+                .config()
+                .content_addressed_blobs
+                .then(|| compute_content_hash(&content));
+            match sparse_map {
+                Some(map) => EntryKind::sparse(hash, meta.size, map, xattrs),
+                None => EntryKind::regular(hash, meta.size, xattrs),
+            }
+            .with_content(content_hash)
+        }
+        FileType::Symlink => {
+            let target = crate::fs::read_symlink_target(path)?;
+            let xattrs = read_xattrs(path)?;
+            EntryKind::symlink(compute_symlink_hash(uid, gid, &xattrs, &target), xattrs)
+        }
+        FileType::BlockDevice => {
+            let (major, minor) = meta.rdev.unwrap_or((0, 0));
+            EntryKind::BlockDevice {
+                major,
+                minor,
+                uid,
+                gid,
+                mode: meta.mode,
+                xattrs: read_xattrs(path)?,
+                times: None,
+            }
+        }
+        FileType::CharDevice => {
+            let (major, minor) = meta.rdev.unwrap_or((0, 0));
+            EntryKind::CharDevice {
+                major,
+                minor,
+                uid,
+                gid,
+                mode: meta.mode,
+                xattrs: read_xattrs(path)?,
+                times: None,
+            }
+        }
+        FileType::Fifo => EntryKind::Fifo {
+            uid,
+            gid,
+            mode: meta.mode,
+            xattrs: read_xattrs(path)?,
+            times: None,
+        },
+        FileType::Socket => EntryKind::Socket {
+            uid,
+            gid,
+            mode: meta.mode,
+            xattrs: read_xattrs(path)?,
+            times: None,
+        },
+        FileType::Directory => unreachable!("directories compared separately"),
+    };
+
+    Ok(kind)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -315,4 +811,118 @@ mod tests {
             .iter()
             .any(|c| c.path == "newdir/b.txt" && c.kind == ChangeKind::Added));
     }
+
+    #[test]
+    fn test_diff_with_options_ignores_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        let file = source.join("run.sh");
+        fs::write(&file, "#!/bin/sh").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+        commit(&repo, &source, "a", None, None).unwrap();
+
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o755)).unwrap();
+        commit(&repo, &source, "b", None, None).unwrap();
+
+        assert_eq!(diff(&repo, "a", "b").unwrap().len(), 1);
+
+        let ignore_mode = DiffOptions {
+            ignore_mode: true,
+            ..Default::default()
+        };
+        assert!(diff_with_options(&repo, "a", "b", &ignore_mode)
+            .unwrap()
+            .is_empty());
+
+        let ignore_metadata = DiffOptions {
+            ignore_metadata: true,
+            ..Default::default()
+        };
+        assert!(diff_with_options(&repo, "a", "b", &ignore_metadata)
+            .unwrap()
+            .is_empty());
+
+        // owner isn't what changed
+        let ignore_owner = DiffOptions {
+            ignore_owner: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            diff_with_options(&repo, "a", "b", &ignore_owner)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        // a content change is still reported
+        fs::write(&file, "#!/bin/bash").unwrap();
+        commit(&repo, &source, "c", None, None).unwrap();
+        assert_eq!(
+            diff_with_options(&repo, "a", "c", &ignore_metadata)
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_diff_ref_worktree() {
+        use crate::ops::checkout::{checkout, CheckoutOptions};
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a.txt"), "a").unwrap();
+        fs::write(source.join("sub/b.txt"), "b").unwrap();
+        fs::hard_link(source.join("a.txt"), source.join("sub/link")).unwrap();
+        symlink("a.txt", source.join("sym")).unwrap();
+        commit(&repo, &source, "main", None, None).unwrap();
+
+        let work = dir.path().join("work");
+        let opts = CheckoutOptions {
+            hardlink: false,
+            ..Default::default()
+        };
+        checkout(&repo, "main", &work, opts).unwrap();
+        assert!(diff_ref_worktree(&repo, "main", &work).unwrap().is_empty());
+
+        fs::write(work.join("sub/b.txt"), "changed").unwrap();
+        let changes = diff_ref_worktree(&repo, "main", &work).unwrap();
+        assert_eq!(
+            changes,
+            vec![DiffEntry {
+                path: "sub/b.txt".to_string(),
+                kind: ChangeKind::Modified,
+            }]
+        );
+
+        // an owner-only dir, one file gone (its hardlink stays unchanged)
+        // and one new; none of it is written to the store
+        let before = crate::transport::local::list_all_objects(&repo).unwrap();
+        fs::set_permissions(work.join("sub"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::remove_file(work.join("a.txt")).unwrap();
+        fs::write(work.join("new.txt"), "new").unwrap();
+        let changes = diff_ref_worktree(&repo, "main", &work).unwrap();
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.kind.clone()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("a.txt", ChangeKind::Deleted),
+                ("new.txt", ChangeKind::Added),
+                ("sub", ChangeKind::MetadataOnly),
+                ("sub/b.txt", ChangeKind::Modified),
+            ]
+        );
+        let after = crate::transport::local::list_all_objects(&repo).unwrap();
+        assert_eq!(after.blobs, before.blobs);
+    }
 }
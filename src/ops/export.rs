@@ -1,12 +1,18 @@
 use std::fs;
-use std::path::Path;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use nix::libc;
 
+use crate::diagnostics::StderrDiagnostics;
 use crate::error::{Error, IoResultExt, Result};
 use crate::fs::{create_symlink, write_sparse_file};
 use crate::hash::Hash;
-use crate::object::{blob_path, read_blob, read_commit, read_tree};
+use crate::namespace::outside_to_inside;
+use crate::object::{
+    blob_data_path, blob_meta_path, blob_path, read_blob, read_blob_data, read_commit, read_tree,
+};
 use crate::refs::resolve_ref;
 use crate::repo::Repo;
 use crate::types::{EntryKind, Tree};
@@ -51,16 +57,36 @@ pub fn export_path(
     let entry = resolve_entry(repo, &tree, normalized)?;
     match entry {
         EntryKind::Regular {
-            hash, sparse_map, ..
-        } => export_regular(repo, dest, &hash, sparse_map.as_deref(), &opts),
+            hash,
+            sparse_map,
+            content,
+            ..
+        } => export_regular(
+            repo,
+            dest,
+            &hash,
+            content.as_ref(),
+            sparse_map.as_deref(),
+            &opts,
+        ),
         EntryKind::Symlink { hash, .. } => export_symlink(repo, dest, &hash, &opts),
         EntryKind::Hardlink { target_path } => {
             let target_norm = target_path.trim_start_matches('/');
             let target = resolve_entry(repo, &tree, target_norm)?;
             match target {
                 EntryKind::Regular {
-                    hash, sparse_map, ..
-                } => export_regular(repo, dest, &hash, sparse_map.as_deref(), &opts),
+                    hash,
+                    sparse_map,
+                    content,
+                    ..
+                } => export_regular(
+                    repo,
+                    dest,
+                    &hash,
+                    content.as_ref(),
+                    sparse_map.as_deref(),
+                    &opts,
+                ),
                 EntryKind::Symlink { hash, .. } => export_symlink(repo, dest, &hash, &opts),
                 _ => Err(Error::InvalidObjectType(target.type_name().to_string())),
             }
@@ -116,6 +142,7 @@ fn export_regular(
     repo: &Repo,
     dest: &Path,
     hash: &Hash,
+    content: Option<&Hash>,
     sparse_map: Option<&[crate::types::SparseRegion]>,
     opts: &ExportOptions,
 ) -> Result<()> {
@@ -123,10 +150,10 @@ fn export_regular(
 
     match sparse_map {
         Some(regions) if !regions.is_empty() && opts.preserve_sparse => {
-            let data = read_blob(repo, hash)?;
+            let data = read_blob_data(repo, hash, content)?;
             let total_size: u64 = regions.iter().map(|r| r.end()).max().unwrap_or(0);
             write_sparse_file(dest, &data, regions, total_size)?;
-            let blob = blob_path(repo, hash);
+            let blob = blob_meta_path(repo, hash, content);
             let meta = fs::metadata(&blob).with_path(&blob)?;
             fs::set_permissions(dest, meta.permissions()).with_path(dest)?;
             return Ok(());
@@ -138,7 +165,17 @@ fn export_regular(
         _ => {}
     }
 
-    let blob = blob_path(repo, hash);
+    let blob = blob_meta_path(repo, hash, content);
+
+    // content objects are shared across modes: copy the bytes, then take the
+    // permissions from the blob stub
+    if content.is_some() {
+        let data_path = blob_data_path(repo, hash, content);
+        fs::copy(&data_path, dest).with_path(dest)?;
+        let meta = fs::metadata(&blob).with_path(&blob)?;
+        fs::set_permissions(dest, meta.permissions()).with_path(dest)?;
+        return Ok(());
+    }
 
     if opts.hardlink {
         match fs::hard_link(&blob, dest) {
@@ -168,16 +205,294 @@ fn export_symlink(repo: &Repo, dest: &Path, hash: &Hash, opts: &ExportOptions) -
     let blob = blob_path(repo, hash);
     let meta = fs::symlink_metadata(&blob).with_path(&blob)?;
 
-    use std::os::unix::fs::MetadataExt;
-    create_symlink(dest, &target, meta.uid(), meta.gid(), &[])?;
+    // without xattrs there is nothing to warn about
+    create_symlink(
+        dest,
+        &target,
+        meta.uid(),
+        meta.gid(),
+        &[],
+        &StderrDiagnostics,
+    )?;
+    Ok(())
+}
+
+/// Options for exporting a ref as a squashfs image.
+#[derive(Clone)]
+pub struct SquashfsOptions {
+    /// mksquashfs binary to run (default: `mksquashfs` from PATH)
+    pub mksquashfs: PathBuf,
+    /// Compressor passed as `-comp` (mksquashfs default when unset)
+    pub compressor: Option<String>,
+    /// Extra arguments appended to the mksquashfs command line
+    pub extra_args: Vec<String>,
+}
+
+impl Default for SquashfsOptions {
+    fn default() -> Self {
+        Self {
+            mksquashfs: PathBuf::from("mksquashfs"),
+            compressor: None,
+            extra_args: Vec::new(),
+        }
+    }
+}
+
+/// Export a ref's tree as a squashfs image without checking it out first.
+///
+/// The tree is described to `mksquashfs` as pseudo-file definitions, so
+/// ownership, modes, symlinks and device nodes are recorded without root and
+/// file contents are streamed straight from the blob store. Hardlinks become
+/// squashfs hardlinks. Needs squashfs-tools 4.6 or later; xattrs are not
+/// carried over.
+pub fn export_squashfs(
+    repo: &Repo,
+    ref_name: &str,
+    output: &Path,
+    opts: SquashfsOptions,
+) -> Result<()> {
+    let commit_hash = resolve_ref(repo, ref_name)?;
+    let commit = read_commit(repo, &commit_hash)?;
+    let tree = read_tree(repo, &commit.tree)?;
+
+    let pseudo = squashfs_pseudo_definitions(repo, &tree)?;
+
+    // mksquashfs wants a source directory; an empty one leaves only the
+    // pseudo definitions in the image
+    let work = repo.tmp_path().join(uuid::Uuid::new_v4().to_string());
+    let empty_root = work.join("root");
+    fs::create_dir_all(&empty_root).with_path(&empty_root)?;
+    let pseudo_path = work.join("pseudo");
+
+    let result = fs::write(&pseudo_path, pseudo)
+        .with_path(&pseudo_path)
+        .and_then(|()| run_mksquashfs(&empty_root, output, &pseudo_path, &opts));
+    let _ = fs::remove_dir_all(&work);
+    result
+}
+
+fn run_mksquashfs(
+    source: &Path,
+    output: &Path,
+    pseudo_path: &Path,
+    opts: &SquashfsOptions,
+) -> Result<()> {
+    let mut cmd = Command::new(&opts.mksquashfs);
+    cmd.arg(source)
+        .arg(output)
+        .args(["-noappend", "-no-progress", "-quiet", "-pf"])
+        .arg(pseudo_path);
+    if let Some(compressor) = &opts.compressor {
+        cmd.args(["-comp", compressor]);
+    }
+    cmd.args(&opts.extra_args);
+
+    let out = cmd
+        .output()
+        .map_err(|e| Error::ExternalCommand(format!("{}: {}", opts.mksquashfs.display(), e)))?;
+    if !out.status.success() {
+        return Err(Error::ExternalCommand(format!(
+            "{} exited with {}: {}",
+            opts.mksquashfs.display(),
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
     Ok(())
 }
 
+/// Build mksquashfs pseudo-file definitions for a whole tree.
+///
+/// Directories come before their contents and hardlinks come last, so every
+/// link target is already defined when it is referenced.
+fn squashfs_pseudo_definitions(repo: &Repo, tree: &Tree) -> Result<String> {
+    let mut out = String::new();
+    let mut hardlinks = Vec::new();
+    pseudo_tree(repo, tree, "", &mut out, &mut hardlinks)?;
+    for (path, target) in hardlinks {
+        out.push_str(&format!(
+            "{} L {}\n",
+            pseudo_quote(&path),
+            pseudo_quote(target.trim_start_matches('/'))
+        ));
+    }
+    Ok(out)
+}
+
+fn pseudo_tree(
+    repo: &Repo,
+    tree: &Tree,
+    prefix: &str,
+    out: &mut String,
+    hardlinks: &mut Vec<(String, String)>,
+) -> Result<()> {
+    let ns = &repo.config().namespace;
+    // blobs carry on-disk ids; the image gets the logical ones
+    let inside_ids = |meta: &fs::Metadata| {
+        (
+            outside_to_inside(meta.uid(), &ns.uid_map).unwrap_or(meta.uid()),
+            outside_to_inside(meta.gid(), &ns.gid_map).unwrap_or(meta.gid()),
+        )
+    };
+
+    for entry in tree.entries() {
+        let path = if prefix.is_empty() {
+            entry.name.clone()
+        } else {
+            format!("{}/{}", prefix, entry.name)
+        };
+        let name = pseudo_quote(&path);
+
+        match &entry.kind {
+            EntryKind::Directory {
+                hash,
+                uid,
+                gid,
+                mode,
+                ..
+            } => {
+                out.push_str(&format!("{} d {:o} {} {}\n", name, mode & 0o7777, uid, gid));
+                let subtree = read_tree(repo, hash)?;
+                pseudo_tree(repo, &subtree, &path, out, hardlinks)?;
+            }
+            EntryKind::Regular {
+                hash,
+                size,
+                sparse_map,
+                content,
+                ..
+            } => {
+                let blob = blob_meta_path(repo, hash, content.as_ref());
+                let meta = fs::metadata(&blob).with_path(&blob)?;
+                let (uid, gid) = inside_ids(&meta);
+                let data = blob_data_path(repo, hash, content.as_ref());
+                out.push_str(&format!(
+                    "{} f {:o} {} {} {}\n",
+                    name,
+                    meta.mode() & 0o7777,
+                    uid,
+                    gid,
+                    contents_command(&data, sparse_map.as_deref(), *size)
+                ));
+            }
+            EntryKind::Symlink { hash, .. } => {
+                let blob = blob_path(repo, hash);
+                let meta = fs::symlink_metadata(&blob).with_path(&blob)?;
+                let (uid, gid) = inside_ids(&meta);
+                let target = String::from_utf8_lossy(&read_blob(repo, hash)?).into_owned();
+                out.push_str(&format!("{} s 777 {} {} {}\n", name, uid, gid, target));
+            }
+            EntryKind::BlockDevice {
+                major,
+                minor,
+                uid,
+                gid,
+                mode,
+                ..
+            } => out.push_str(&format!(
+                "{} b {:o} {} {} {} {}\n",
+                name,
+                mode & 0o7777,
+                uid,
+                gid,
+                major,
+                minor
+            )),
+            EntryKind::CharDevice {
+                major,
+                minor,
+                uid,
+                gid,
+                mode,
+                ..
+            } => out.push_str(&format!(
+                "{} c {:o} {} {} {} {}\n",
+                name,
+                mode & 0o7777,
+                uid,
+                gid,
+                major,
+                minor
+            )),
+            EntryKind::Fifo { uid, gid, mode, .. } => out.push_str(&format!(
+                "{} i {:o} {} {} p\n",
+                name,
+                mode & 0o7777,
+                uid,
+                gid
+            )),
+            EntryKind::Socket { uid, gid, mode, .. } => out.push_str(&format!(
+                "{} i {:o} {} {} s\n",
+                name,
+                mode & 0o7777,
+                uid,
+                gid
+            )),
+            EntryKind::Hardlink { target_path } => hardlinks.push((path, target_path.clone())),
+        }
+    }
+
+    Ok(())
+}
+
+/// Shell command producing a file's contents, expanding sparse holes.
+fn contents_command(
+    data: &Path,
+    sparse_map: Option<&[crate::types::SparseRegion]>,
+    size: u64,
+) -> String {
+    let data = shell_quote(&data.to_string_lossy());
+    let Some(regions) = sparse_map else {
+        return format!("cat {}", data);
+    };
+
+    // data regions are stored back to back; holes are zero-filled
+    let mut parts = Vec::new();
+    let (mut pos, mut data_pos) = (0u64, 0u64);
+    for region in regions {
+        if region.offset > pos {
+            parts.push(format!("head -c {} /dev/zero", region.offset - pos));
+        }
+        parts.push(format!(
+            "tail -c +{} {} | head -c {}",
+            data_pos + 1,
+            data,
+            region.length
+        ));
+        pos = region.end();
+        data_pos += region.length;
+    }
+    if size > pos {
+        parts.push(format!("head -c {} /dev/zero", size - pos));
+    }
+    if parts.is_empty() {
+        return "true".to_string();
+    }
+    format!("{{ {}; }}", parts.join("; "))
+}
+
+/// Quote a path for a pseudo definition (double quotes, backslash escapes).
+fn pseudo_quote(path: &str) -> String {
+    let mut quoted = String::with_capacity(path.len() + 2);
+    quoted.push('"');
+    for c in path.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::ops::commit::commit;
-    use std::os::unix::fs::MetadataExt;
     use tempfile::tempdir;
 
     fn test_repo() -> (tempfile::TempDir, Repo) {
@@ -242,4 +557,128 @@ mod tests {
 
         assert_eq!(fs::read_to_string(&dest).unwrap(), "old");
     }
+
+    fn squashfs_source(dir: &Path) -> std::path::PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let source = dir.join("source");
+        fs::create_dir_all(source.join("etc")).unwrap();
+        fs::set_permissions(source.join("etc"), fs::Permissions::from_mode(0o750)).unwrap();
+        fs::write(source.join("etc/my \"conf\""), "key=value\n").unwrap();
+        fs::hard_link(source.join("etc/my \"conf\""), source.join("linked")).unwrap();
+        std::os::unix::fs::symlink("etc/my \"conf\"", source.join("link")).unwrap();
+        nix::unistd::mkfifo(
+            &source.join("pipe"),
+            nix::sys::stat::Mode::from_bits_truncate(0o600),
+        )
+        .unwrap();
+
+        // 8k hole, 4 bytes, 8k hole
+        let sparse = fs::File::create(source.join("sparse")).unwrap();
+        sparse.set_len(16 * 1024 + 4).unwrap();
+        std::os::unix::fs::FileExt::write_at(&sparse, b"data", 8 * 1024).unwrap();
+        source
+    }
+
+    #[test]
+    fn squashfs_pseudo_definitions_cover_tree() {
+        let (dir, repo) = test_repo();
+        let source = squashfs_source(dir.path());
+        commit(&repo, &source, "image", None, None).unwrap();
+
+        let commit_obj = read_commit(&repo, &resolve_ref(&repo, "image").unwrap()).unwrap();
+        let tree = read_tree(&repo, &commit_obj.tree).unwrap();
+        let pseudo = squashfs_pseudo_definitions(&repo, &tree).unwrap();
+        let lines: Vec<&str> = pseudo.lines().collect();
+
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+        assert!(lines.contains(&format!("\"etc\" d 750 {} {}", uid, gid).as_str()));
+        assert!(lines.contains(&format!("\"link\" s 777 {} {} etc/my \"conf\"", uid, gid).as_str()));
+        assert!(lines.contains(&format!("\"pipe\" i 600 {} {} p", uid, gid).as_str()));
+
+        // directories precede their contents, hardlinks come last
+        let etc = lines
+            .iter()
+            .position(|l| l.starts_with("\"etc\" "))
+            .unwrap();
+        let conf = lines
+            .iter()
+            .position(|l| l.starts_with("\"etc/my \\\"conf\\\"\" f "))
+            .unwrap();
+        assert!(etc < conf);
+        assert_eq!(
+            lines.last().unwrap(),
+            &"\"linked\" L \"etc/my \\\"conf\\\"\""
+        );
+
+        // the generated commands reproduce each file, holes included
+        for (name, expected) in [
+            (
+                "etc/my \\\"conf\\\"",
+                fs::read(source.join("etc/my \"conf\"")).unwrap(),
+            ),
+            ("sparse", fs::read(source.join("sparse")).unwrap()),
+        ] {
+            let line = lines
+                .iter()
+                .find(|l| l.starts_with(&format!("\"{}\" f ", name)))
+                .unwrap();
+            // name" f mode uid gid command
+            let fields = line.split_once("\" f ").unwrap().1;
+            let command = fields.splitn(4, ' ').nth(3).unwrap();
+            let output = Command::new("sh").arg("-c").arg(command).output().unwrap();
+            assert_eq!(output.stdout, expected, "contents of {}", name);
+        }
+    }
+
+    #[test]
+    fn exports_squashfs_image() {
+        let tools_present = ["mksquashfs", "unsquashfs"].iter().all(|tool| {
+            Command::new(tool)
+                .arg("-version")
+                .output()
+                .is_ok_and(|o| o.status.success())
+        });
+        if !tools_present {
+            eprintln!("squashfs-tools not installed, skipping");
+            return;
+        }
+
+        let (dir, repo) = test_repo();
+        let source = squashfs_source(dir.path());
+        commit(&repo, &source, "image", None, None).unwrap();
+
+        let image = dir.path().join("image.sqsh");
+        export_squashfs(&repo, "image", &image, SquashfsOptions::default()).unwrap();
+
+        let unpacked = dir.path().join("unpacked");
+        let status = Command::new("unsquashfs")
+            .arg("-no-progress")
+            .arg("-d")
+            .arg(&unpacked)
+            .arg(&image)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let conf = unpacked.join("etc/my \"conf\"");
+        assert_eq!(fs::read_to_string(&conf).unwrap(), "key=value\n");
+        assert_eq!(
+            fs::metadata(&conf).unwrap().ino(),
+            fs::metadata(unpacked.join("linked")).unwrap().ino()
+        );
+        assert_eq!(
+            fs::read(unpacked.join("sparse")).unwrap(),
+            fs::read(source.join("sparse")).unwrap()
+        );
+        assert_eq!(
+            fs::read_link(unpacked.join("link")).unwrap(),
+            Path::new("etc/my \"conf\"")
+        );
+        assert_eq!(
+            fs::metadata(unpacked.join("etc")).unwrap().mode() & 0o7777,
+            0o750
+        );
+    }
 }
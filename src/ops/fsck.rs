@@ -1,14 +1,20 @@
 use std::collections::HashSet;
-use std::fs;
+use std::fs::{self, File};
+use std::io::Write;
+use std::sync::Mutex;
 
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
 
-use crate::error::Result;
+use crate::error::{IoResultExt, Result};
+use crate::fs::TempFile;
 use crate::hash::Hash;
-use crate::object::{read_commit, read_tree};
+use crate::object::{read_commit, read_packed, read_tree, with_packed, PackedKind};
 use crate::refs::list_refs;
 use crate::repo::Repo;
-use crate::types::EntryKind;
+use crate::transport::RateLimiter;
+use crate::types::{Commit, EntryKind, Tree};
 
 /// fsck report
 #[derive(Debug, Default)]
@@ -21,6 +27,8 @@ pub struct FsckReport {
     pub missing_objects: Vec<MissingObject>,
     /// dangling objects (not reachable from any ref)
     pub dangling_objects: Vec<Hash>,
+    /// ref the check was limited to; `dangling_objects` is not populated then
+    pub scoped_to: Option<String>,
 }
 
 impl FsckReport {
@@ -43,11 +51,13 @@ pub struct MissingObject {
     pub referenced_by: String,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ObjectType {
     Blob,
     Tree,
     Commit,
+    Content,
 }
 
 impl std::fmt::Display for ObjectType {
@@ -56,6 +66,18 @@ impl std::fmt::Display for ObjectType {
             ObjectType::Blob => write!(f, "blob"),
             ObjectType::Tree => write!(f, "tree"),
             ObjectType::Commit => write!(f, "commit"),
+            ObjectType::Content => write!(f, "content"),
+        }
+    }
+}
+
+impl ObjectType {
+    /// the pack kind for types that can be packed
+    pub(crate) fn packed_kind(self) -> Option<PackedKind> {
+        match self {
+            ObjectType::Tree => Some(PackedKind::Tree),
+            ObjectType::Commit => Some(PackedKind::Commit),
+            ObjectType::Blob | ObjectType::Content => None,
         }
     }
 }
@@ -83,65 +105,345 @@ pub fn fsck(repo: &Repo) -> Result<FsckReport> {
 
     // find all objects on disk
     let all_blobs = list_objects(&repo.blobs_path())?;
-    let all_trees = list_objects(&repo.trees_path())?;
-    let all_commits = list_objects(&repo.commits_path())?;
+    let all_trees = with_packed(repo, list_objects(&repo.trees_path())?, PackedKind::Tree)?;
+    let all_commits = with_packed(
+        repo,
+        list_objects(&repo.commits_path())?,
+        PackedKind::Commit,
+    )?;
+    let all_content = list_objects(&repo.content_path())?;
+
+    // hash-verify trees, commits and content objects, sharded across workers
+    report
+        .corrupt_objects
+        .extend(verify_objects(repo, ObjectType::Tree, &all_trees));
+    report
+        .corrupt_objects
+        .extend(verify_objects(repo, ObjectType::Commit, &all_commits));
+    report
+        .corrupt_objects
+        .extend(verify_objects(repo, ObjectType::Content, &all_content));
+
+    // find dangling objects
+    // blob hash includes metadata, can't verify without knowing uid/gid/mode/xattrs
+    for (hashes, reachable) in [
+        (&all_blobs, &reachable_blobs),
+        (&all_trees, &reachable_trees),
+        (&all_commits, &reachable_commits),
+        (&all_content, &reachable_blobs),
+    ] {
+        report.objects_checked += hashes.len();
+        report
+            .dangling_objects
+            .extend(hashes.iter().filter(|h| !reachable.contains(*h)));
+    }
 
-    // verify object hashes and find dangling objects
-    for hash in &all_blobs {
-        report.objects_checked += 1;
-        // blob hash includes metadata, can't verify without knowing uid/gid/mode/xattrs
-        // just check file exists and is readable
+    report.dangling_objects.sort();
+    Ok(report)
+}
 
-        if !reachable_blobs.contains(hash) {
-            report.dangling_objects.push(*hash);
+/// hash-verify objects of one type in parallel, one task per prefix directory
+///
+/// results are sorted by hash so the report doesn't depend on scheduling.
+fn verify_objects(repo: &Repo, object_type: ObjectType, hashes: &[Hash]) -> Vec<CorruptObject> {
+    let mut sorted = hashes.to_vec();
+    sorted.sort();
+    let shards: Vec<&[Hash]> = sorted
+        .chunk_by(|a, b| a.as_bytes()[0] == b.as_bytes()[0])
+        .collect();
+
+    let corrupt = Mutex::new(Vec::new());
+    shards.par_iter().for_each(|shard| {
+        let found: Vec<_> = shard
+            .iter()
+            .filter_map(|hash| verify_object(repo, object_type, hash).1)
+            .collect();
+        if !found.is_empty() {
+            corrupt.lock().unwrap().extend(found);
         }
+    });
+
+    let mut corrupt = corrupt.into_inner().unwrap();
+    corrupt.sort_by_key(|c| c.hash);
+    corrupt
+}
+
+/// verify only the objects reachable from one ref
+///
+/// commits and trees are hash-checked as they are read and blobs are checked
+/// for existence. the store is never scanned, so dangling objects are not reported.
+pub fn fsck_ref(repo: &Repo, ref_name: &str) -> Result<FsckReport> {
+    let mut report = FsckReport {
+        scoped_to: Some(ref_name.to_string()),
+        ..Default::default()
+    };
+    let mut reachable_blobs = HashSet::new();
+    let mut reachable_trees = HashSet::new();
+    let mut reachable_commits = HashSet::new();
+
+    let commit_hash = crate::refs::read_ref(repo, ref_name)?;
+    check_commit(
+        repo,
+        &commit_hash,
+        ref_name,
+        &mut reachable_blobs,
+        &mut reachable_trees,
+        &mut reachable_commits,
+        &mut report,
+    )?;
+
+    report.objects_checked =
+        reachable_blobs.len() + reachable_trees.len() + reachable_commits.len();
+    Ok(report)
+}
+
+/// recompute the hash of a tree, commit or content object
+///
+/// returns the number of bytes read and the corruption found, if any.
+/// objects that vanish before they can be read (e.g. removed by gc) are skipped.
+fn verify_object(
+    repo: &Repo,
+    object_type: ObjectType,
+    hash: &Hash,
+) -> (u64, Option<CorruptObject>) {
+    let path = match object_type {
+        ObjectType::Tree => crate::object::tree_path(repo, hash),
+        ObjectType::Commit => crate::object::commit_path(repo, hash),
+        ObjectType::Content => crate::object::content_path(repo, hash),
+        // blob hash includes metadata, can't verify without knowing uid/gid/mode/xattrs
+        ObjectType::Blob => return (0, None),
+    };
+
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(_) => match object_type
+            .packed_kind()
+            .map(|kind| read_packed(repo, kind, hash))
+        {
+            Some(Ok(Some(data))) => data,
+            _ => return (0, None),
+        },
+    };
+
+    let actual_hash = match object_type {
+        ObjectType::Content => crate::hash::compute_content_hash(&data),
+        _ => Hash::from_bytes(*blake3::hash(&data).as_bytes()),
+    };
+
+    let message = if actual_hash != *hash {
+        Some(format!(
+            "hash mismatch: expected {}, zub{}",
+            hash, actual_hash
+        ))
+    } else {
+        schema_error(repo, object_type, hash, &path, &data)
+    };
+    let corrupt = message.map(|message| CorruptObject {
+        hash: *hash,
+        object_type,
+        message,
+    });
+
+    (data.len() as u64, corrupt)
+}
+
+/// check that a hash-valid tree or commit decodes into a well-formed object
+///
+/// the hash only covers the stored bytes, so a buggy writer can leave an
+/// object that verifies but can't be read back.
+fn schema_error(
+    repo: &Repo,
+    object_type: ObjectType,
+    hash: &Hash,
+    path: &std::path::Path,
+    data: &[u8],
+) -> Option<String> {
+    if !matches!(object_type, ObjectType::Tree | ObjectType::Commit) {
+        return None;
     }
+    let limit = repo.config().max_object_size;
+    let cbor =
+        match crate::object::decompress_object(data, repo.zstd_dictionary(), limit, hash, path) {
+            Ok(cbor) => cbor,
+            Err(e) => return Some(format!("schema error: can't decompress: {}", e)),
+        };
+    let result = match object_type {
+        ObjectType::Tree => ciborium::from_reader::<Tree, _>(&cbor[..])
+            .map_err(crate::Error::from)
+            .and_then(|tree| tree.validate()),
+        _ => ciborium::from_reader::<Commit, _>(&cbor[..])
+            .map(drop)
+            .map_err(crate::Error::from),
+    };
+    result
+        .err()
+        .map(|e| format!("schema error: not a valid {}: {}", object_type, e))
+}
 
-    for hash in &all_trees {
-        report.objects_checked += 1;
+/// position in a scrub, the last object verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrubCursor {
+    pub object_type: ObjectType,
+    pub hash: Hash,
+}
 
-        // verify tree hash
-        let path = crate::object::tree_path(repo, hash);
-        if let Ok(compressed) = fs::read(&path) {
-            let actual_hash = Hash::from_bytes(*blake3::hash(&compressed).as_bytes());
-            if actual_hash != *hash {
-                report.corrupt_objects.push(CorruptObject {
-                    hash: *hash,
-                    object_type: ObjectType::Tree,
-                    message: format!("hash mismatch: expected {}, zub{}", hash, actual_hash),
-                });
-            }
+impl ScrubCursor {
+    /// parse the `TYPE HASH` form written by `Display`
+    pub fn parse(s: &str) -> Option<Self> {
+        let (kind, hex) = s.trim().split_once(' ')?;
+        let object_type = match kind {
+            "tree" => ObjectType::Tree,
+            "commit" => ObjectType::Commit,
+            "content" => ObjectType::Content,
+            _ => return None,
+        };
+        let hash = Hash::from_hex(hex).ok()?;
+        Some(Self { object_type, hash })
+    }
+}
+
+impl std::fmt::Display for ScrubCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.object_type, self.hash)
+    }
+}
+
+/// called after each object with (objects checked, objects total)
+pub type ScrubProgress = Box<dyn Fn(usize, usize)>;
+
+/// scrub options
+#[derive(Default)]
+pub struct ScrubOptions {
+    /// read rate limit, unlimited if none
+    pub bytes_per_sec: Option<u64>,
+    /// continue after this object (see `read_scrub_cursor`)
+    pub resume_from: Option<ScrubCursor>,
+    /// stop after this many objects, leaving a cursor for the next run
+    pub max_objects: Option<usize>,
+    pub progress: Option<ScrubProgress>,
+}
+
+/// scrub report
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    pub objects_checked: usize,
+    pub bytes_read: u64,
+    pub corrupt_objects: Vec<CorruptObject>,
+    /// where the next run should resume, none if the scrub reached the end
+    pub cursor: Option<ScrubCursor>,
+}
+
+/// how often the cursor is persisted during a scrub
+const SCRUB_SAVE_INTERVAL: usize = 256;
+
+/// verify tree, commit and content hashes incrementally at a bounded read rate
+///
+/// objects are visited in a stable order (trees, commits, then content, each by
+/// hash) and the position is persisted so an interrupted or bounded scrub can be
+/// resumed. objects written behind the cursor are picked up on the next full pass.
+/// blobs are skipped: their hash covers metadata that only the trees know.
+pub fn scrub(repo: &Repo, opts: &ScrubOptions) -> Result<ScrubReport> {
+    let mut report = ScrubReport::default();
+
+    let mut objects = Vec::new();
+    for (object_type, dir) in [
+        (ObjectType::Tree, repo.trees_path()),
+        (ObjectType::Commit, repo.commits_path()),
+        (ObjectType::Content, repo.content_path()),
+    ] {
+        let mut hashes = list_objects(&dir)?;
+        hashes.sort();
+        objects.extend(
+            hashes
+                .into_iter()
+                .map(|hash| ScrubCursor { object_type, hash }),
+        );
+    }
+
+    let start = match &opts.resume_from {
+        Some(cursor) => objects
+            .iter()
+            .position(|o| scrub_order(o) > scrub_order(cursor))
+            .unwrap_or(objects.len()),
+        None => 0,
+    };
+    let pending = &objects[start..];
+
+    let limiter = opts.bytes_per_sec.map(RateLimiter::new);
+
+    for (i, object) in pending.iter().enumerate() {
+        if opts.max_objects.is_some_and(|max| i >= max) {
+            break;
         }
 
-        if !reachable_trees.contains(hash) {
-            report.dangling_objects.push(*hash);
+        let (bytes, corrupt) = verify_object(repo, object.object_type, &object.hash);
+        if let Some(limiter) = &limiter {
+            limiter.consume(bytes);
         }
-    }
 
-    for hash in &all_commits {
         report.objects_checked += 1;
+        report.bytes_read += bytes;
+        report.corrupt_objects.extend(corrupt);
+        report.cursor = Some(*object);
 
-        // verify commit hash
-        let path = crate::object::commit_path(repo, hash);
-        if let Ok(compressed) = fs::read(&path) {
-            let actual_hash = Hash::from_bytes(*blake3::hash(&compressed).as_bytes());
-            if actual_hash != *hash {
-                report.corrupt_objects.push(CorruptObject {
-                    hash: *hash,
-                    object_type: ObjectType::Commit,
-                    message: format!("hash mismatch: expected {}, zub{}", hash, actual_hash),
-                });
-            }
+        if let Some(progress) = &opts.progress {
+            progress(start + i + 1, objects.len());
         }
-
-        if !reachable_commits.contains(hash) {
-            report.dangling_objects.push(*hash);
+        if report.objects_checked % SCRUB_SAVE_INTERVAL == 0 {
+            save_scrub_cursor(repo, report.cursor.as_ref())?;
         }
     }
 
+    // reaching the end means the next run starts a fresh pass
+    if report.objects_checked == pending.len() {
+        report.cursor = None;
+    }
+    save_scrub_cursor(repo, report.cursor.as_ref())?;
+
     Ok(report)
 }
 
+/// read the cursor persisted by an unfinished scrub
+pub fn read_scrub_cursor(repo: &Repo) -> Result<Option<ScrubCursor>> {
+    let path = repo.scrub_cursor_path();
+    match fs::read_to_string(&path) {
+        Ok(s) => Ok(ScrubCursor::parse(&s)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(crate::Error::Io { path, source: e }),
+    }
+}
+
+fn save_scrub_cursor(repo: &Repo, cursor: Option<&ScrubCursor>) -> Result<()> {
+    let path = repo.scrub_cursor_path();
+    let Some(cursor) = cursor else {
+        if path.exists() {
+            fs::remove_file(&path).with_path(&path)?;
+        }
+        return Ok(());
+    };
+
+    let tmp = TempFile::new_in(&repo.tmp_path());
+    let tmp_path = tmp.path();
+    {
+        let mut tmp_file = File::create(tmp_path).with_path(tmp_path)?;
+        writeln!(tmp_file, "{}", cursor).with_path(tmp_path)?;
+        tmp_file.sync_all().with_path(tmp_path)?;
+    }
+    fs::rename(tmp_path, &path).with_path(&path)?;
+    tmp.into_persisted();
+    Ok(())
+}
+
+fn scrub_order(cursor: &ScrubCursor) -> (u8, Hash) {
+    let rank = match cursor.object_type {
+        ObjectType::Tree => 0,
+        ObjectType::Commit => 1,
+        ObjectType::Content => 2,
+        ObjectType::Blob => 3,
+    };
+    (rank, cursor.hash)
+}
+
 fn check_commit(
     repo: &Repo,
     commit_hash: &Hash,
@@ -195,6 +497,13 @@ fn check_commit(
                 message: "hash mismatch".to_string(),
             });
         }
+        Err(e @ crate::Error::CborDecode(_)) => {
+            report.corrupt_objects.push(CorruptObject {
+                hash: *commit_hash,
+                object_type: ObjectType::Commit,
+                message: format!("schema error: not a valid commit: {}", e),
+            });
+        }
         Err(e) => return Err(e),
     }
 
@@ -216,17 +525,40 @@ fn check_tree(
 
     match read_tree(repo, tree_hash) {
         Ok(tree) => {
+            if let Err(e) = tree.validate() {
+                report.corrupt_objects.push(CorruptObject {
+                    hash: *tree_hash,
+                    object_type: ObjectType::Tree,
+                    message: format!("schema error: not a valid tree: {}", e),
+                });
+            }
             for entry in tree.entries() {
                 match &entry.kind {
-                    EntryKind::Regular { hash, .. } => {
-                        reachable_blobs.insert(*hash);
-                        if !crate::object::blob_exists(repo, hash) {
+                    EntryKind::Regular { hash, content, .. } => {
+                        let file = crate::object::blob_file_hash(hash, content.as_ref());
+                        reachable_blobs.insert(file);
+                        if !crate::object::blob_exists(repo, &file) {
                             report.missing_objects.push(MissingObject {
-                                hash: *hash,
+                                hash: file,
                                 object_type: ObjectType::Blob,
                                 referenced_by: format!("tree {} entry {}", tree_hash, entry.name),
                             });
                         }
+                        // content hashes share the blob reachability set; they live in
+                        // their own directory so the two never get compared directly
+                        if let Some(content_hash) = content {
+                            reachable_blobs.insert(*content_hash);
+                            if !crate::object::content_exists(repo, content_hash) {
+                                report.missing_objects.push(MissingObject {
+                                    hash: *content_hash,
+                                    object_type: ObjectType::Content,
+                                    referenced_by: format!(
+                                        "tree {} entry {}",
+                                        tree_hash, entry.name
+                                    ),
+                                });
+                            }
+                        }
                     }
                     EntryKind::Symlink { hash, .. } => {
                         reachable_blobs.insert(*hash);
@@ -266,6 +598,13 @@ fn check_tree(
                 message: "hash mismatch".to_string(),
             });
         }
+        Err(e @ crate::Error::CborDecode(_)) => {
+            report.corrupt_objects.push(CorruptObject {
+                hash: *tree_hash,
+                object_type: ObjectType::Tree,
+                message: format!("schema error: not a valid tree: {}", e),
+            });
+        }
         Err(e) => return Err(e),
     }
 
@@ -338,6 +677,78 @@ mod tests {
         assert!(report.dangling_objects.is_empty());
     }
 
+    /// store `value` as a tree exactly as a writer would, without checks
+    fn write_raw_tree(repo: &Repo, value: &impl Serialize) -> Hash {
+        let mut cbor = Vec::new();
+        ciborium::into_writer(value, &mut cbor).unwrap();
+        let compressed = zstd::encode_all(&cbor[..], 3).unwrap();
+        let hash = Hash::from_bytes(*blake3::hash(&compressed).as_bytes());
+        let path = crate::object::tree_path(repo, &hash);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, compressed).unwrap();
+        hash
+    }
+
+    #[test]
+    fn test_fsck_flags_schema_invalid_objects() {
+        use crate::types::TreeEntry;
+
+        let (_dir, repo) = test_repo();
+
+        // hash-consistent, but not a tree at all
+        let garbage = write_raw_tree(&repo, &"not a tree");
+        let report = fsck(&repo).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.corrupt_objects.len(), 1);
+        let corrupt = &report.corrupt_objects[0];
+        assert_eq!(corrupt.hash, garbage);
+        assert_eq!(corrupt.object_type, ObjectType::Tree);
+        assert!(
+            corrupt
+                .message
+                .starts_with("schema error: not a valid tree"),
+            "{}",
+            corrupt.message
+        );
+        fs::remove_file(crate::object::tree_path(&repo, &garbage)).unwrap();
+
+        // decodes fine, but entries are out of order
+        #[derive(Serialize)]
+        struct RawTree {
+            entries: Vec<TreeEntry>,
+        }
+        let fifo = |name| {
+            let kind = EntryKind::Fifo {
+                uid: 0,
+                gid: 0,
+                mode: 0o10644,
+                xattrs: vec![],
+                times: None,
+            };
+            TreeEntry::new(name, kind)
+        };
+        let unsorted = write_raw_tree(
+            &repo,
+            &RawTree {
+                entries: vec![fifo("b"), fifo("a")],
+            },
+        );
+        let commit = Commit::new(unsorted, vec![], "test", "unsorted");
+        let commit_hash = crate::object::write_commit(&repo, &commit).unwrap();
+        crate::refs::write_ref(&repo, "unsorted", &commit_hash).unwrap();
+
+        let report = fsck_ref(&repo, "unsorted").unwrap();
+        assert_eq!(report.corrupt_objects.len(), 1);
+        assert_eq!(report.corrupt_objects[0].hash, unsorted);
+        assert!(report.corrupt_objects[0]
+            .message
+            .contains("b sorted before a"));
+
+        let report = fsck(&repo).unwrap();
+        assert!(report.corrupt_objects.iter().all(|c| c.hash == unsorted));
+        assert!(!report.is_ok());
+    }
+
     #[test]
     fn test_fsck_with_dangling() {
         let (dir, repo) = test_repo();
@@ -355,4 +766,211 @@ mod tests {
         // should find dangling objects
         assert!(!report.dangling_objects.is_empty());
     }
+
+    #[test]
+    fn test_fsck_with_alternate() {
+        let (dir, base) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "shared").unwrap();
+        let head = commit(&base, &source, "base", None, None).unwrap();
+
+        let mut overlay = Repo::init(&dir.path().join("overlay")).unwrap();
+        overlay.config_mut().alternates = vec![base.objects_path()];
+        crate::refs::write_ref(&overlay, "main", &head).unwrap();
+
+        // everything the ref reaches lives only in the alternate
+        let report = fsck(&overlay).unwrap();
+        assert!(report.is_ok());
+        assert!(report.dangling_objects.is_empty());
+    }
+
+    #[test]
+    fn test_fsck_verifies_content_objects() {
+        let (dir, mut repo) = test_repo();
+        repo.config_mut().content_addressed_blobs = true;
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        assert!(fsck(&repo).unwrap().is_ok());
+
+        // tamper with the content object
+        let content_hash = crate::hash::compute_content_hash(b"content");
+        let path = crate::object::content_path(&repo, &content_hash);
+        fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o644)).unwrap();
+        fs::write(&path, "tampered").unwrap();
+
+        let report = fsck(&repo).unwrap();
+        assert_eq!(report.corrupt_objects.len(), 1);
+        assert!(matches!(
+            report.corrupt_objects[0].object_type,
+            ObjectType::Content
+        ));
+    }
+
+    #[test]
+    fn test_fsck_parallel_matches_serial() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        for i in 0..20 {
+            fs::write(source.join(format!("file{i}.txt")), format!("content {i}")).unwrap();
+            commit(&repo, &source, "test", Some(&format!("commit {i}")), None).unwrap();
+        }
+
+        // corrupt one tree and one commit
+        let head = crate::refs::read_ref(&repo, "test").unwrap();
+        let tree = read_commit(&repo, &head).unwrap().tree;
+        for path in [
+            crate::object::tree_path(&repo, &tree),
+            crate::object::commit_path(&repo, &head),
+        ] {
+            fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o644))
+                .unwrap();
+            fs::write(&path, "tampered").unwrap();
+        }
+
+        let summary = |report: &FsckReport| {
+            let corrupt: Vec<_> = report
+                .corrupt_objects
+                .iter()
+                .map(|c| (c.hash, c.object_type, c.message.clone()))
+                .collect();
+            (
+                corrupt,
+                report.dangling_objects.clone(),
+                report.objects_checked,
+            )
+        };
+
+        let parallel = fsck(&repo).unwrap();
+        let serial = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap()
+            .install(|| fsck(&repo))
+            .unwrap();
+
+        assert!(!parallel.is_ok());
+        assert!(parallel
+            .corrupt_objects
+            .iter()
+            .any(|c| c.hash == tree && c.object_type == ObjectType::Tree));
+        assert!(parallel
+            .corrupt_objects
+            .iter()
+            .any(|c| c.hash == head && c.object_type == ObjectType::Commit));
+        assert_eq!(summary(&parallel), summary(&serial));
+    }
+
+    /// number of trees + commits in the repo
+    fn scrubbable_objects(repo: &Repo) -> usize {
+        list_objects(&repo.trees_path()).unwrap().len()
+            + list_objects(&repo.commits_path()).unwrap().len()
+    }
+
+    #[test]
+    fn test_scrub_rate_limited_checks_everything() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("a/b")).unwrap();
+        fs::write(source.join("a/b/file.txt"), "content").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+        fs::write(source.join("file2.txt"), "more").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        let opts = ScrubOptions {
+            bytes_per_sec: Some(64 * 1024),
+            ..Default::default()
+        };
+        let report = scrub(&repo, &opts).unwrap();
+
+        assert_eq!(report.objects_checked, scrubbable_objects(&repo));
+        assert!(report.bytes_read > 0);
+        assert!(report.corrupt_objects.is_empty());
+        assert!(report.cursor.is_none());
+        assert!(read_scrub_cursor(&repo).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_scrub_resume_cursor() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("a/b")).unwrap();
+        fs::write(source.join("a/b/file.txt"), "content").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+        let total = scrubbable_objects(&repo);
+        assert!(total > 2);
+
+        let first = scrub(
+            &repo,
+            &ScrubOptions {
+                max_objects: Some(2),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(first.objects_checked, 2);
+        let cursor = read_scrub_cursor(&repo).unwrap();
+        assert_eq!(cursor, first.cursor);
+        assert!(cursor.is_some());
+
+        let second = scrub(
+            &repo,
+            &ScrubOptions {
+                resume_from: cursor,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(first.objects_checked + second.objects_checked, total);
+        assert!(second.cursor.is_none());
+        assert!(read_scrub_cursor(&repo).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_fsck_ref_scoped() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file.txt"), "content").unwrap();
+        let hash = commit(&repo, &source, "test", None, None).unwrap();
+
+        let other = dir.path().join("other");
+        fs::create_dir(&other).unwrap();
+        fs::write(other.join("other.txt"), "other").unwrap();
+        commit(&repo, &other, "other", None, None).unwrap();
+
+        let report = fsck_ref(&repo, "test").unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.scoped_to.as_deref(), Some("test"));
+        assert!(report.dangling_objects.is_empty());
+        // commit, root tree, sub tree, blob
+        assert_eq!(report.objects_checked, 4);
+
+        // corrupt the subtree of "test"
+        let root = read_tree(&repo, &read_commit(&repo, &hash).unwrap().tree).unwrap();
+        let EntryKind::Directory { hash: sub, .. } = root.get("sub").unwrap().kind else {
+            panic!("expected directory");
+        };
+        let path = crate::object::tree_path(&repo, &sub);
+        fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o644)).unwrap();
+        fs::write(&path, "garbage").unwrap();
+
+        let report = fsck_ref(&repo, "test").unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.corrupt_objects.len(), 1);
+        assert_eq!(report.corrupt_objects[0].hash, sub);
+
+        // the other ref is unaffected
+        assert!(fsck_ref(&repo, "other").unwrap().is_ok());
+    }
 }
@@ -1,12 +1,19 @@
 use std::collections::HashSet;
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use walkdir::WalkDir;
 
 use crate::error::{IoResultExt, Result};
 use crate::hash::Hash;
-use crate::object::{read_commit, read_tree};
-use crate::refs::list_refs;
+use crate::object::{
+    blob_file_hash, commit_exists, list_packed_sizes, read_commit, read_tree, repack_without,
+    write_pack, PackedKind,
+};
+use crate::refs::{
+    delete_reflog, delete_refs, list_reflogs, list_refs, read_ref, read_reflog, ReflogEntry,
+};
 use crate::repo::Repo;
 use crate::types::EntryKind;
 
@@ -16,61 +23,636 @@ pub struct GcStats {
     pub blobs_removed: usize,
     pub trees_removed: usize,
     pub commits_removed: usize,
+    /// content objects removed (content-addressed blobs)
+    pub contents_removed: usize,
     pub bytes_freed: u64,
+    /// loose trees moved into a pack (`expire_loose_after`)
+    pub trees_packed: usize,
+    /// loose commits moved into a pack (`expire_loose_after`)
+    pub commits_packed: usize,
+    /// refs deleted for falling outside `keep_refs_since`/`keep_refs_until`
+    pub refs_expired: Vec<String>,
+    /// largest unreachable blobs swept (or, in a dry run, that would be) with
+    /// their sizes, largest first; empty unless `top_unreachable` is set
+    pub top_unreachable: Vec<(Hash, u64)>,
+    /// deleted refs whose reflog was removed (or, in a dry run, would be)
+    /// once `keep_deleted_refs_for` ran out
+    pub reflogs_expired: Vec<String>,
+}
+
+/// gc options
+#[derive(Debug, Clone)]
+pub struct GcOptions {
+    /// only count what would be removed
+    pub dry_run: bool,
+    /// most recent reflog values of each ref kept reachable, including the
+    /// current tip, so a few rewrites can always be rolled back. a deleted
+    /// ref's tombstone counts as its current tip
+    pub keep_reflog_generations: usize,
+    /// how long after its deletion a ref's reflog is kept, holding its last
+    /// values reachable for `recover_ref`; once past, gc removes the reflog.
+    /// `None` (or zero) removes them on the first gc after the deletion.
+    /// defaults to `DEFAULT_KEEP_DELETED_REFS_FOR`
+    pub keep_deleted_refs_for: Option<Duration>,
+    /// keep unreachable commits whose own timestamp is within this window of
+    /// now, along with their trees and blobs, regardless of file mtimes
+    pub keep_unreachable_commits_younger_than: Option<Duration>,
+    /// remove unreachable blobs and content objects
+    pub collect_blobs: bool,
+    /// remove unreachable trees; kept trees keep their blobs
+    pub collect_trees: bool,
+    /// remove unreachable commits; kept commits keep their trees, blobs
+    /// and parents
+    pub collect_commits: bool,
+    /// move kept trees and commits not read or written within this window
+    /// out of loose files into a new pack
+    pub expire_loose_after: Option<Duration>,
+    /// delete refs whose tip commit's timestamp is before this unix time,
+    /// without keeping their reflog values, so what only they reached is
+    /// collected
+    pub keep_refs_since: Option<i64>,
+    /// the same for refs whose tip commit's timestamp is after this unix time
+    pub keep_refs_until: Option<i64>,
+    /// list this many of the largest unreachable blobs in
+    /// `GcStats::top_unreachable`; 0 to skip collecting sizes
+    pub top_unreachable: usize,
+}
+
+/// default grace for deleted refs' reflogs: 30 days, as git's reflog expiry
+/// for unreachable entries
+pub const DEFAULT_KEEP_DELETED_REFS_FOR: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+impl Default for GcOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            keep_reflog_generations: 2,
+            keep_deleted_refs_for: Some(DEFAULT_KEEP_DELETED_REFS_FOR),
+            keep_unreachable_commits_younger_than: None,
+            collect_blobs: true,
+            collect_trees: true,
+            collect_commits: true,
+            expire_loose_after: None,
+            keep_refs_since: None,
+            keep_refs_until: None,
+            top_unreachable: 0,
+        }
+    }
 }
 
 /// garbage collect unreachable objects
 pub fn gc(repo: &Repo, dry_run: bool) -> Result<GcStats> {
+    gc_with_options(
+        repo,
+        &GcOptions {
+            dry_run,
+            ..Default::default()
+        },
+    )
+}
+
+/// garbage collect unreachable objects with options
+///
+/// gc marks and sweeps against a snapshot so it can share the repo with
+/// concurrent writers. it runs in up to five steps, 0 and 4 only when their
+/// options are set:
+///
+/// 0. with `keep_refs_since`/`keep_refs_until`, delete the refs whose tip
+///    commit falls outside the window
+/// 1. record a start time (from the filesystem clock) and snapshot every
+///    object on disk with its mtime
+/// 2. mark everything reachable from refs and recent reflog entries, plus
+///    dangling commits whose timestamp falls within
+///    `keep_unreachable_commits_younger_than`; the reflogs of refs deleted
+///    longer than `keep_deleted_refs_for` ago are dropped, unmarked
+/// 3. sweep only snapshotted objects that are unreachable and not newer than
+///    the start time; packs holding unreachable trees or commits are
+///    rewritten without them
+/// 4. with `expire_loose_after`, pack the kept trees and commits whose atime
+///    and mtime at snapshot time are both older than the window
+///
+/// types excluded by the `collect_*` options are kept along with everything
+/// they reference: a kept dangling commit marks its tree, blobs and parents,
+/// and a kept dangling tree its blobs, so nothing kept is left dangling.
+///
+/// an object written while gc runs is either missing from the snapshot or
+/// newer than the start, so a commit whose ref update lands after the mark
+/// never loses objects it just wrote. the remaining race is a writer that
+/// reuses an object which already existed unreachable before gc started
+/// (writes deduplicate, so its mtime isn't refreshed); that object can still
+/// be swept from under it.
+pub fn gc_with_options(repo: &Repo, opts: &GcOptions) -> Result<GcStats> {
+    gc_impl(repo, opts, || Ok(()))
+}
+
+/// refs whose tip commit is outside `keep_refs_since`..`keep_refs_until`
+fn expired_refs(repo: &Repo, opts: &GcOptions) -> Result<Vec<String>> {
+    if opts.keep_refs_since.is_none() && opts.keep_refs_until.is_none() {
+        return Ok(Vec::new());
+    }
+
+    let mut expired = Vec::new();
+    for ref_name in list_refs(repo)? {
+        // a ref whose tip is gone has no timestamp to judge it by; it is
+        // skipped here as in the mark phase, and left to prune_dangling_refs
+        let tip = read_ref(repo, &ref_name)?;
+        if !commit_exists(repo, &tip) {
+            continue;
+        }
+        let timestamp = read_commit(repo, &tip)?.timestamp;
+        let too_old = opts.keep_refs_since.is_some_and(|since| timestamp < since);
+        let too_new = opts.keep_refs_until.is_some_and(|until| timestamp > until);
+        if too_old || too_new {
+            expired.push(ref_name);
+        }
+    }
+    Ok(expired)
+}
+
+/// whether a reflog ends in a tombstone older than the `keep` window
+fn deletion_expired(reflog: &[ReflogEntry], keep: Option<Duration>, now: i64) -> bool {
+    let Some(last) = reflog.last().filter(|entry| entry.is_tombstone()) else {
+        return false;
+    };
+    match keep {
+        Some(window) => last.timestamp <= now.saturating_sub(window.as_secs() as i64),
+        None => true,
+    }
+}
+
+/// seconds since the unix epoch
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// object found on disk when gc started
+struct SnapshotEntry {
+    hash: Hash,
+    path: PathBuf,
+    mtime: SystemTime,
+    /// taken before the mark phase reads the object
+    atime: SystemTime,
+}
+
+/// on-disk objects of each type at gc start
+struct ObjectSnapshot {
+    started: SystemTime,
+    blobs: Vec<SnapshotEntry>,
+    trees: Vec<SnapshotEntry>,
+    commits: Vec<SnapshotEntry>,
+    content: Vec<SnapshotEntry>,
+    /// packed trees and commits with their stored sizes
+    packed_trees: Vec<(Hash, u64)>,
+    packed_commits: Vec<(Hash, u64)>,
+}
+
+impl ObjectSnapshot {
+    /// every snapshotted commit, loose or packed
+    fn all_commits(&self) -> impl Iterator<Item = Hash> + '_ {
+        let loose = self.commits.iter().map(|e| e.hash);
+        loose.chain(self.packed_commits.iter().map(|(hash, _)| *hash))
+    }
+
+    /// every snapshotted tree, loose or packed
+    fn all_trees(&self) -> impl Iterator<Item = Hash> + '_ {
+        let loose = self.trees.iter().map(|e| e.hash);
+        loose.chain(self.packed_trees.iter().map(|(hash, _)| *hash))
+    }
+}
+
+/// gc with a hook run between mark and sweep (used by tests to race a writer)
+fn gc_impl<F>(repo: &Repo, opts: &GcOptions, after_mark: F) -> Result<GcStats>
+where
+    F: FnOnce() -> Result<()>,
+{
+    let dry_run = opts.dry_run;
+
+    // expired refs are left out of the mark below, dry run or not
+    let refs_expired = expired_refs(repo, opts)?;
+    if !dry_run && !refs_expired.is_empty() {
+        delete_refs(repo, &refs_expired)?;
+    }
+    let expired: HashSet<&String> = refs_expired.iter().collect();
+
+    // snapshot phase: what exists now is all gc may remove
+    let snapshot = ObjectSnapshot {
+        started: filesystem_now(repo)?,
+        blobs: snapshot_objects(&repo.blobs_path())?,
+        trees: snapshot_objects(&repo.trees_path())?,
+        commits: snapshot_objects(&repo.commits_path())?,
+        content: snapshot_objects(&repo.content_path())?,
+        packed_trees: list_packed_sizes(repo, PackedKind::Tree)?,
+        packed_commits: list_packed_sizes(repo, PackedKind::Commit)?,
+    };
+
     // mark phase: collect all reachable objects
     let mut reachable_blobs = HashSet::new();
     let mut reachable_trees = HashSet::new();
     let mut reachable_commits = HashSet::new();
+    let mut reachable_content = HashSet::new();
 
-    // start from all refs
+    // start from all refs; a dangling one has nothing left to mark
     for ref_name in list_refs(repo)? {
+        if expired.contains(&ref_name) {
+            continue;
+        }
         let commit_hash = crate::refs::read_ref(repo, &ref_name)?;
+        if !commit_exists(repo, &commit_hash) {
+            continue;
+        }
         mark_commit(
             repo,
             &commit_hash,
             &mut reachable_blobs,
             &mut reachable_trees,
             &mut reachable_commits,
+            &mut reachable_content,
         )?;
     }
 
-    // sweep phase: remove unmarked objects
-    let mut stats = GcStats::default();
+    // recent previous tips, of deleted refs too (whose tombstone counts as a
+    // generation) until their grace window ends; ones already collected are
+    // skipped
+    let now = unix_now();
+    let mut reflogs_expired = Vec::new();
+    for ref_name in list_reflogs(repo)? {
+        if expired.contains(&ref_name) {
+            continue;
+        }
+        let reflog = read_reflog(repo, &ref_name)?;
+        if deletion_expired(&reflog, opts.keep_deleted_refs_for, now) {
+            reflogs_expired.push(ref_name);
+            continue;
+        }
+        let skip = reflog.len().saturating_sub(opts.keep_reflog_generations);
+        for entry in &reflog[skip..] {
+            if !commit_exists(repo, &entry.new) {
+                continue;
+            }
+            mark_commit(
+                repo,
+                &entry.new,
+                &mut reachable_blobs,
+                &mut reachable_trees,
+                &mut reachable_commits,
+                &mut reachable_content,
+            )?;
+        }
+    }
 
-    // sweep blobs
-    sweep_objects(
-        &repo.blobs_path(),
-        &reachable_blobs,
-        dry_run,
-        &mut stats.blobs_removed,
-        &mut stats.bytes_freed,
-    )?;
+    // dangling commits recent by their own timestamp; mtime can be reset by
+    // copies or restores, the commit's timestamp can't
+    if let Some(window) = opts.keep_unreachable_commits_younger_than {
+        let cutoff = now.saturating_sub(window.as_secs() as i64);
+        for hash in snapshot.all_commits() {
+            if reachable_commits.contains(&hash) {
+                continue;
+            }
+            let Ok(commit) = read_commit(repo, &hash) else {
+                continue;
+            };
+            if commit.timestamp >= cutoff {
+                mark_commit(
+                    repo,
+                    &hash,
+                    &mut reachable_blobs,
+                    &mut reachable_trees,
+                    &mut reachable_commits,
+                    &mut reachable_content,
+                )?;
+            }
+        }
+    }
+
+    // kept types hold on to what they reference
+    if !opts.collect_commits {
+        for hash in snapshot.all_commits() {
+            if reachable_commits.contains(&hash) {
+                continue;
+            }
+            mark_commit(
+                repo,
+                &hash,
+                &mut reachable_blobs,
+                &mut reachable_trees,
+                &mut reachable_commits,
+                &mut reachable_content,
+            )?;
+        }
+    }
+    if !opts.collect_trees {
+        for hash in snapshot.all_trees() {
+            mark_tree(
+                repo,
+                &hash,
+                &mut reachable_blobs,
+                &mut reachable_trees,
+                &mut reachable_content,
+            )?;
+        }
+    }
+
+    after_mark()?;
+
+    // the history of refs deleted long enough ago goes with their objects
+    if !dry_run {
+        for ref_name in &reflogs_expired {
+            delete_reflog(repo, ref_name)?;
+        }
+    }
+
+    // sweep phase: remove unmarked objects from the snapshot
+    let mut stats = GcStats {
+        refs_expired,
+        reflogs_expired,
+        ..Default::default()
+    };
+
+    // sweep blobs, noting their sizes if the largest were asked for
+    if opts.collect_blobs {
+        let mut sizes = Vec::new();
+        sweep_objects(
+            &repo.blobs_path(),
+            &snapshot.blobs,
+            &reachable_blobs,
+            snapshot.started,
+            dry_run,
+            &mut stats.blobs_removed,
+            &mut stats.bytes_freed,
+            (opts.top_unreachable > 0).then_some(&mut sizes),
+        )?;
+        sizes.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        sizes.truncate(opts.top_unreachable);
+        stats.top_unreachable = sizes;
+    }
 
     // sweep trees
-    sweep_objects(
-        &repo.trees_path(),
-        &reachable_trees,
-        dry_run,
-        &mut stats.trees_removed,
-        &mut stats.bytes_freed,
-    )?;
+    if opts.collect_trees {
+        sweep_objects(
+            &repo.trees_path(),
+            &snapshot.trees,
+            &reachable_trees,
+            snapshot.started,
+            dry_run,
+            &mut stats.trees_removed,
+            &mut stats.bytes_freed,
+            None,
+        )?;
+    }
 
     // sweep commits
-    sweep_objects(
-        &repo.commits_path(),
+    if opts.collect_commits {
+        sweep_objects(
+            &repo.commits_path(),
+            &snapshot.commits,
+            &reachable_commits,
+            snapshot.started,
+            dry_run,
+            &mut stats.commits_removed,
+            &mut stats.bytes_freed,
+            None,
+        )?;
+    }
+
+    // packed trees and commits go by rewriting the packs without them
+    sweep_packed(
+        repo,
+        &snapshot,
+        opts,
+        &reachable_trees,
         &reachable_commits,
-        dry_run,
-        &mut stats.commits_removed,
-        &mut stats.bytes_freed,
+        &mut stats,
     )?;
 
+    // sweep content objects, which go with the blobs they back
+    if opts.collect_blobs {
+        sweep_objects(
+            &repo.content_path(),
+            &snapshot.content,
+            &reachable_content,
+            snapshot.started,
+            dry_run,
+            &mut stats.contents_removed,
+            &mut stats.bytes_freed,
+            None,
+        )?;
+    }
+
+    if let Some(window) = opts.expire_loose_after {
+        let cutoff = snapshot.started.checked_sub(window).unwrap_or(UNIX_EPOCH);
+        pack_cold_objects(
+            repo,
+            &snapshot,
+            &reachable_trees,
+            &reachable_commits,
+            cutoff,
+            dry_run,
+            &mut stats,
+        )?;
+    }
+
     Ok(stats)
 }
 
+/// drop unreachable packed trees and commits of the collected types
+///
+/// packs are rewritten only when something in them is unreachable. an
+/// object also found loose was already counted by the loose sweep.
+fn sweep_packed(
+    repo: &Repo,
+    snapshot: &ObjectSnapshot,
+    opts: &GcOptions,
+    reachable_trees: &HashSet<Hash>,
+    reachable_commits: &HashSet<Hash>,
+    stats: &mut GcStats,
+) -> Result<()> {
+    let mut drop = HashSet::new();
+    for (kind, collect, packed, loose, reachable) in [
+        (
+            PackedKind::Tree,
+            opts.collect_trees,
+            &snapshot.packed_trees,
+            &snapshot.trees,
+            reachable_trees,
+        ),
+        (
+            PackedKind::Commit,
+            opts.collect_commits,
+            &snapshot.packed_commits,
+            &snapshot.commits,
+            reachable_commits,
+        ),
+    ] {
+        if !collect {
+            continue;
+        }
+        let loose: HashSet<Hash> = loose.iter().map(|e| e.hash).collect();
+        for (hash, size) in packed {
+            if reachable.contains(hash) {
+                continue;
+            }
+            drop.insert((kind, *hash));
+            if loose.contains(hash) {
+                continue;
+            }
+            match kind {
+                PackedKind::Tree => stats.trees_removed += 1,
+                PackedKind::Commit => stats.commits_removed += 1,
+            }
+            stats.bytes_freed += size;
+        }
+    }
+
+    if !drop.is_empty() && !opts.dry_run {
+        repack_without(repo, &drop)?;
+    }
+    Ok(())
+}
+
+/// move kept loose trees and commits last touched before `cutoff` into a pack
+///
+/// swept objects are never kept, so only marked ones are considered. an
+/// object that fails its hash check stays loose for fsck to report.
+///
+/// the mark phase reads every reachable object, which bumps atimes at least
+/// daily under relatime; objects left loose get their snapshot atime back so
+/// gc alone never keeps them warm.
+fn pack_cold_objects(
+    repo: &Repo,
+    snapshot: &ObjectSnapshot,
+    reachable_trees: &HashSet<Hash>,
+    reachable_commits: &HashSet<Hash>,
+    cutoff: SystemTime,
+    dry_run: bool,
+    stats: &mut GcStats,
+) -> Result<()> {
+    let mut objects = Vec::new();
+    let mut paths = Vec::new();
+    for (kind, entries, reachable) in [
+        (PackedKind::Tree, &snapshot.trees, reachable_trees),
+        (PackedKind::Commit, &snapshot.commits, reachable_commits),
+    ] {
+        for entry in entries {
+            let cold = reachable.contains(&entry.hash) && entry.mtime.max(entry.atime) < cutoff;
+            if !cold {
+                restore_atime(entry);
+                continue;
+            }
+            if !dry_run {
+                let data = match fs::read(&entry.path) {
+                    Ok(data) => data,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                    Err(e) => {
+                        return Err(crate::Error::Io {
+                            path: entry.path.clone(),
+                            source: e,
+                        })
+                    }
+                };
+                if Hash::from_bytes(*blake3::hash(&data).as_bytes()) != entry.hash {
+                    restore_atime(entry);
+                    continue;
+                }
+                objects.push((kind, entry.hash, data));
+                paths.push(entry.path.clone());
+            } else {
+                restore_atime(entry);
+            }
+            match kind {
+                PackedKind::Tree => stats.trees_packed += 1,
+                PackedKind::Commit => stats.commits_packed += 1,
+            }
+        }
+    }
+    if objects.is_empty() {
+        return Ok(());
+    }
+
+    // readers fall back to the pack once it is in place, so the loose files
+    // can go right after
+    write_pack(repo, &objects)?;
+    for path in paths {
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(crate::Error::Io { path, source: e }),
+        }
+    }
+    Ok(())
+}
+
+/// set an object's atime back to its snapshot value if gc's reads moved it;
+/// best effort, a failure only makes the object look recently used
+fn restore_atime(entry: &SnapshotEntry) {
+    let Ok(file) = fs::File::open(&entry.path) else {
+        return;
+    };
+    if file.metadata().and_then(|m| m.accessed()).ok() != Some(entry.atime) {
+        let _ = file.set_times(fs::FileTimes::new().set_accessed(entry.atime));
+    }
+}
+
+/// current time as the repo's filesystem stamps it
+///
+/// object mtimes come from the filesystem clock, which can lag the system
+/// clock by a tick, so the start time is read back from a scratch file.
+fn filesystem_now(repo: &Repo) -> Result<SystemTime> {
+    let marker = repo
+        .objects_tmp_path()
+        .join(format!("gc-start-{}", uuid::Uuid::new_v4()));
+    fs::write(&marker, b"").with_path(&marker)?;
+    let started = fs::metadata(&marker).and_then(|m| m.modified());
+    let _ = fs::remove_file(&marker);
+    started.with_path(&marker)
+}
+
+/// list the objects under an object type directory with their mtimes
+fn snapshot_objects(dir: &Path) -> Result<Vec<SnapshotEntry>> {
+    let mut objects = Vec::new();
+    if !dir.exists() {
+        return Ok(objects);
+    }
+
+    for entry in WalkDir::new(dir).min_depth(2).max_depth(2) {
+        let entry = entry.map_err(|e| crate::Error::Io {
+            path: dir.to_path_buf(),
+            source: e
+                .into_io_error()
+                .unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "walkdir error")),
+        })?;
+
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        // reconstruct hash from path: objects/type/XX/YYYYYY...
+        let path = entry.path();
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let parent_name = path
+            .parent()
+            .and_then(|p| p.file_name())
+            .and_then(|n| n.to_str())
+            .unwrap_or("");
+
+        let hex = format!("{}{}", parent_name, file_name);
+        if let Ok(hash) = Hash::from_hex(&hex) {
+            let meta = fs::metadata(path).with_path(path)?;
+            let mtime = meta.modified().with_path(path)?;
+            objects.push(SnapshotEntry {
+                hash,
+                path: path.to_path_buf(),
+                mtime,
+                atime: meta.accessed().unwrap_or(mtime),
+            });
+        }
+    }
+
+    Ok(objects)
+}
+
 /// recursively mark a commit and all its reachable objects
 fn mark_commit(
     repo: &Repo,
@@ -78,6 +660,7 @@ fn mark_commit(
     reachable_blobs: &mut HashSet<Hash>,
     reachable_trees: &mut HashSet<Hash>,
     reachable_commits: &mut HashSet<Hash>,
+    reachable_content: &mut HashSet<Hash>,
 ) -> Result<()> {
     // avoid re-processing
     if reachable_commits.contains(commit_hash) {
@@ -88,7 +671,13 @@ fn mark_commit(
     let commit = read_commit(repo, commit_hash)?;
 
     // mark tree
-    mark_tree(repo, &commit.tree, reachable_blobs, reachable_trees)?;
+    mark_tree(
+        repo,
+        &commit.tree,
+        reachable_blobs,
+        reachable_trees,
+        reachable_content,
+    )?;
 
     // recurse into parents
     for parent in &commit.parents {
@@ -98,6 +687,7 @@ fn mark_commit(
             reachable_blobs,
             reachable_trees,
             reachable_commits,
+            reachable_content,
         )?;
     }
 
@@ -110,6 +700,7 @@ fn mark_tree(
     tree_hash: &Hash,
     reachable_blobs: &mut HashSet<Hash>,
     reachable_trees: &mut HashSet<Hash>,
+    reachable_content: &mut HashSet<Hash>,
 ) -> Result<()> {
     if reachable_trees.contains(tree_hash) {
         return Ok(());
@@ -120,14 +711,23 @@ fn mark_tree(
 
     for entry in tree.entries() {
         match &entry.kind {
-            EntryKind::Regular { hash, .. } => {
-                reachable_blobs.insert(*hash);
+            EntryKind::Regular { hash, content, .. } => {
+                reachable_blobs.insert(blob_file_hash(hash, content.as_ref()));
+                if let Some(content_hash) = content {
+                    reachable_content.insert(*content_hash);
+                }
             }
             EntryKind::Symlink { hash, .. } => {
                 reachable_blobs.insert(*hash);
             }
             EntryKind::Directory { hash, .. } => {
-                mark_tree(repo, hash, reachable_blobs, reachable_trees)?;
+                mark_tree(
+                    repo,
+                    hash,
+                    reachable_blobs,
+                    reachable_trees,
+                    reachable_content,
+                )?;
             }
             // devices, fifos, sockets, hardlinks don't have blob content
             _ => {}
@@ -137,50 +737,48 @@ fn mark_tree(
     Ok(())
 }
 
-/// sweep a directory, removing objects not in the reachable set
+/// remove snapshotted objects that are unreachable and not newer than `started`
+///
+/// with `sizes`, each removed object's hash and size is pushed onto it.
+#[allow(clippy::too_many_arguments)]
 fn sweep_objects(
-    dir: &std::path::Path,
+    dir: &Path,
+    snapshot: &[SnapshotEntry],
     reachable: &HashSet<Hash>,
+    started: SystemTime,
     dry_run: bool,
     removed_count: &mut usize,
     bytes_freed: &mut u64,
+    mut sizes: Option<&mut Vec<(Hash, u64)>>,
 ) -> Result<()> {
     if !dir.exists() {
         return Ok(());
     }
 
-    for entry in WalkDir::new(dir).min_depth(2).max_depth(2) {
-        let entry = entry.map_err(|e| crate::Error::Io {
-            path: dir.to_path_buf(),
-            source: e
-                .into_io_error()
-                .unwrap_or_else(|| std::io::Error::new(std::io::ErrorKind::Other, "walkdir error")),
-        })?;
-
-        if !entry.file_type().is_file() {
+    for object in snapshot {
+        if reachable.contains(&object.hash) || object.mtime > started {
             continue;
         }
 
-        // reconstruct hash from path: objects/type/XX/YYYYYY...
-        let path = entry.path();
-        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-        let parent_name = path
-            .parent()
-            .and_then(|p| p.file_name())
-            .and_then(|n| n.to_str())
-            .unwrap_or("");
-
-        let hex = format!("{}{}", parent_name, file_name);
-        if let Ok(hash) = Hash::from_hex(&hex) {
-            if !reachable.contains(&hash) {
-                let meta = fs::metadata(path).with_path(path)?;
-                *bytes_freed += meta.len();
-                *removed_count += 1;
-
-                if !dry_run {
-                    fs::remove_file(path).with_path(path)?;
-                }
+        let meta = match fs::metadata(&object.path) {
+            Ok(meta) => meta,
+            // already gone, e.g. removed by another gc
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                return Err(crate::Error::Io {
+                    path: object.path.clone(),
+                    source: e,
+                })
             }
+        };
+        *bytes_freed += meta.len();
+        *removed_count += 1;
+        if let Some(sizes) = sizes.as_deref_mut() {
+            sizes.push((object.hash, meta.len()));
+        }
+
+        if !dry_run {
+            fs::remove_file(&object.path).with_path(&object.path)?;
         }
     }
 
@@ -217,6 +815,16 @@ mod tests {
         (dir, repo)
     }
 
+    /// gc with no grace for deleted refs, collecting what they reached at once
+    fn gc_expiring(repo: &Repo, dry_run: bool) -> Result<GcStats> {
+        let opts = GcOptions {
+            dry_run,
+            keep_deleted_refs_for: None,
+            ..Default::default()
+        };
+        gc_with_options(repo, &opts)
+    }
+
     #[test]
     fn test_gc_keeps_reachable() {
         let (dir, repo) = test_repo();
@@ -247,7 +855,7 @@ mod tests {
         crate::refs::delete_ref(&repo, "test").unwrap();
 
         // dry run
-        let stats = gc(&repo, true).unwrap();
+        let stats = gc_expiring(&repo, true).unwrap();
 
         // should report objects to remove
         assert!(stats.blobs_removed > 0 || stats.trees_removed > 0 || stats.commits_removed > 0);
@@ -263,6 +871,38 @@ mod tests {
         assert!(blobs_count > 0);
     }
 
+    #[test]
+    fn test_gc_dry_run_top_unreachable() {
+        use crate::object::{blob_exists, write_blob};
+
+        let (_dir, repo) = test_repo();
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+
+        let dangling: Vec<Hash> = [100, 3000, 50, 700]
+            .iter()
+            .map(|&size| write_blob(&repo, &vec![b'x'; size], uid, gid, 0o100644, &[]).unwrap())
+            .collect();
+
+        let opts = GcOptions {
+            dry_run: true,
+            top_unreachable: 3,
+            ..Default::default()
+        };
+        let stats = gc_with_options(&repo, &opts).unwrap();
+
+        assert_eq!(stats.blobs_removed, 4);
+        assert_eq!(
+            stats.top_unreachable,
+            vec![(dangling[1], 3000), (dangling[3], 700), (dangling[0], 100)]
+        );
+        assert!(dangling.iter().all(|h| blob_exists(&repo, h)));
+
+        // not collected unless asked for
+        let stats = gc(&repo, true).unwrap();
+        assert!(stats.top_unreachable.is_empty());
+    }
+
     #[test]
     fn test_gc_removes_unreachable() {
         let (dir, repo) = test_repo();
@@ -276,9 +916,447 @@ mod tests {
         crate::refs::delete_ref(&repo, "test").unwrap();
 
         // gc
-        let stats = gc(&repo, false).unwrap();
+        let stats = gc_expiring(&repo, false).unwrap();
 
         // should have removed objects
         assert!(stats.blobs_removed > 0 || stats.trees_removed > 0 || stats.commits_removed > 0);
     }
+
+    #[test]
+    fn test_gc_keeps_deleted_ref_recoverable() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+        let hash = commit(&repo, &source, "test", None, None).unwrap();
+
+        // within the window, the tombstone and the deleted tip are the two
+        // default generations
+        crate::refs::delete_ref(&repo, "test").unwrap();
+        let opts = GcOptions {
+            keep_deleted_refs_for: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let stats = gc_with_options(&repo, &opts).unwrap();
+        assert_eq!(stats.commits_removed, 0);
+        assert!(stats.reflogs_expired.is_empty());
+
+        assert_eq!(crate::refs::recover_ref(&repo, "test").unwrap(), hash);
+        assert!(crate::ops::fsck_ref(&repo, "test").unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_gc_keeps_deleted_ref_by_default() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+        let hash = commit(&repo, &source, "test", None, None).unwrap();
+
+        crate::refs::delete_ref(&repo, "test").unwrap();
+        let stats = gc(&repo, false).unwrap();
+        assert_eq!(stats.commits_removed, 0);
+        assert!(stats.reflogs_expired.is_empty());
+
+        assert_eq!(crate::refs::recover_ref(&repo, "test").unwrap(), hash);
+        assert!(crate::ops::fsck_ref(&repo, "test").unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_gc_expires_deleted_ref_reflog() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+        crate::refs::delete_ref(&repo, "test").unwrap();
+
+        // a dry run leaves the reflog in place
+        let stats = gc_expiring(&repo, true).unwrap();
+        assert_eq!(stats.reflogs_expired, vec!["test".to_string()]);
+        assert_eq!(list_reflogs(&repo).unwrap(), vec!["test".to_string()]);
+
+        // past the window, the history and the objects go
+        let stats = gc_expiring(&repo, false).unwrap();
+        assert_eq!(stats.reflogs_expired, vec!["test".to_string()]);
+        assert_eq!(stats.commits_removed, 1);
+        assert!(list_reflogs(&repo).unwrap().is_empty());
+        assert!(matches!(
+            crate::refs::recover_ref(&repo, "test"),
+            Err(crate::Error::RefNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_gc_keeps_reflog_generations() {
+        use crate::object::{blob_exists, write_blob, write_commit, write_tree};
+        use crate::refs::write_ref;
+        use crate::types::{Commit, EntryKind, Tree, TreeEntry};
+
+        let (_dir, repo) = test_repo();
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+
+        // three unrelated root commits, so history alone keeps nothing alive
+        let mut blobs = Vec::new();
+        for content in ["one", "two", "three"] {
+            let blob = write_blob(&repo, content.as_bytes(), uid, gid, 0o100644, &[]).unwrap();
+            let tree = Tree::new(vec![TreeEntry::new(
+                "file.txt",
+                EntryKind::regular(blob, content.len() as u64, vec![]),
+            )])
+            .unwrap();
+            let tree_hash = write_tree(&repo, &tree).unwrap();
+            let commit = Commit::new(tree_hash, vec![], "test", content);
+            let commit_hash = write_commit(&repo, &commit).unwrap();
+            write_ref(&repo, "test", &commit_hash).unwrap();
+            blobs.push(blob);
+        }
+
+        let opts = GcOptions {
+            keep_reflog_generations: 2,
+            ..Default::default()
+        };
+        let stats = gc_with_options(&repo, &opts).unwrap();
+
+        assert_eq!(stats.commits_removed, 1);
+        assert!(!blob_exists(&repo, &blobs[0]));
+        assert!(blob_exists(&repo, &blobs[1]));
+        assert!(blob_exists(&repo, &blobs[2]));
+    }
+
+    #[test]
+    fn test_gc_keeps_recent_unreachable_commits() {
+        use crate::object::{blob_exists, commit_exists, write_blob, write_commit, write_tree};
+        use crate::types::{Commit, Tree, TreeEntry};
+
+        let (_dir, repo) = test_repo();
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        // two dangling commits, one a day old by timestamp and one fresh
+        let mut written = Vec::new();
+        for (content, timestamp) in [(b"old".as_slice(), now - 86400), (b"new", now)] {
+            let blob = write_blob(&repo, content, uid, gid, 0o100644, &[]).unwrap();
+            let tree = Tree::new(vec![TreeEntry::new(
+                "file",
+                EntryKind::regular(blob, content.len() as u64, vec![]),
+            )])
+            .unwrap();
+            let tree_hash = write_tree(&repo, &tree).unwrap();
+            let commit = Commit::with_timestamp(tree_hash, vec![], "test", timestamp, "dangling");
+            written.push((write_commit(&repo, &commit).unwrap(), blob));
+        }
+
+        let opts = GcOptions {
+            keep_unreachable_commits_younger_than: Some(Duration::from_secs(3600)),
+            ..Default::default()
+        };
+        let stats = gc_with_options(&repo, &opts).unwrap();
+
+        assert_eq!(stats.commits_removed, 1);
+        assert!(!commit_exists(&repo, &written[0].0));
+        assert!(!blob_exists(&repo, &written[0].1));
+        assert!(commit_exists(&repo, &written[1].0));
+        assert!(blob_exists(&repo, &written[1].1));
+    }
+
+    #[test]
+    fn test_gc_since_skips_dangling_ref() {
+        use crate::refs::{read_ref, write_ref};
+
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "kept").unwrap();
+        let live = commit(&repo, &source, "live", None, None).unwrap();
+
+        // a ref whose tip commit is gone
+        let missing = Hash::from_bytes([9; 32]);
+        write_ref(&repo, "gone", &missing).unwrap();
+
+        let opts = GcOptions {
+            keep_refs_since: Some(0),
+            keep_refs_until: Some(i64::MAX),
+            ..Default::default()
+        };
+        let stats = gc_with_options(&repo, &opts).unwrap();
+        assert!(stats.refs_expired.is_empty());
+        assert_eq!(stats.commits_removed, 0);
+
+        // neither ref is touched; the dangling one is left for maintenance
+        assert_eq!(read_ref(&repo, "live").unwrap(), live);
+        assert_eq!(read_ref(&repo, "gone").unwrap(), missing);
+    }
+
+    #[test]
+    fn test_gc_expires_refs_outside_window() {
+        use crate::object::{blob_exists, commit_exists, write_blob, write_commit, write_tree};
+        use crate::refs::{read_ref, write_ref};
+        use crate::types::{Commit, Tree, TreeEntry};
+
+        let (_dir, repo) = test_repo();
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        // one ref per CI run, ten days apart
+        let mut written = Vec::new();
+        for (name, timestamp) in [("ci/old", now - 10 * 86400), ("ci/new", now)] {
+            let blob = write_blob(&repo, name.as_bytes(), uid, gid, 0o100644, &[]).unwrap();
+            let tree = Tree::new(vec![TreeEntry::new(
+                "file",
+                EntryKind::regular(blob, name.len() as u64, vec![]),
+            )])
+            .unwrap();
+            let tree_hash = write_tree(&repo, &tree).unwrap();
+            let commit = Commit::with_timestamp(tree_hash, vec![], "ci", timestamp, name);
+            let hash = write_commit(&repo, &commit).unwrap();
+            write_ref(&repo, name, &hash).unwrap();
+            written.push((hash, blob));
+        }
+
+        // a dry run deletes nothing but counts as if the ref were gone
+        let mut opts = GcOptions {
+            dry_run: true,
+            keep_refs_since: Some(now - 86400),
+            ..Default::default()
+        };
+        let stats = gc_with_options(&repo, &opts).unwrap();
+        assert_eq!(stats.refs_expired, vec!["ci/old"]);
+        assert_eq!(stats.commits_removed, 1);
+        assert_eq!(read_ref(&repo, "ci/old").unwrap(), written[0].0);
+
+        // the other end of the window selects the newer ref
+        let until = GcOptions {
+            dry_run: true,
+            keep_refs_until: Some(now - 86400),
+            ..Default::default()
+        };
+        assert_eq!(
+            gc_with_options(&repo, &until).unwrap().refs_expired,
+            vec!["ci/new"]
+        );
+
+        // the reflog doesn't keep the expired tip alive
+        opts.dry_run = false;
+        let stats = gc_with_options(&repo, &opts).unwrap();
+        assert_eq!(stats.refs_expired, vec!["ci/old"]);
+        assert_eq!(stats.commits_removed, 1);
+        assert_eq!(stats.blobs_removed, 1);
+        assert!(read_ref(&repo, "ci/old").is_err());
+        assert!(!commit_exists(&repo, &written[0].0));
+        assert!(!blob_exists(&repo, &written[0].1));
+        assert_eq!(read_ref(&repo, "ci/new").unwrap(), written[1].0);
+        assert!(commit_exists(&repo, &written[1].0));
+        assert!(blob_exists(&repo, &written[1].1));
+    }
+
+    #[test]
+    fn test_gc_only_blobs_keeps_dangling_commit_closure() {
+        use crate::object::{
+            blob_exists, commit_exists, tree::tree_exists, write_blob, write_commit, write_tree,
+        };
+        use crate::types::{Commit, Tree, TreeEntry};
+
+        let (_dir, repo) = test_repo();
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+
+        // a dangling commit with its own tree and blob
+        let kept_blob = write_blob(&repo, b"kept", uid, gid, 0o100644, &[]).unwrap();
+        let tree = Tree::new(vec![TreeEntry::new(
+            "file",
+            EntryKind::regular(kept_blob, 4, vec![]),
+        )])
+        .unwrap();
+        let tree_hash = write_tree(&repo, &tree).unwrap();
+        let commit = write_commit(&repo, &Commit::new(tree_hash, vec![], "test", "")).unwrap();
+
+        // and a loose blob nothing refers to
+        let loose = write_blob(&repo, b"loose", uid, gid, 0o100644, &[]).unwrap();
+
+        let opts = GcOptions {
+            collect_trees: false,
+            collect_commits: false,
+            ..Default::default()
+        };
+        let stats = gc_with_options(&repo, &opts).unwrap();
+
+        assert_eq!(stats.blobs_removed, 1);
+        assert_eq!(stats.trees_removed, 0);
+        assert_eq!(stats.commits_removed, 0);
+        assert!(!blob_exists(&repo, &loose));
+        assert!(commit_exists(&repo, &commit));
+        assert!(tree_exists(&repo, &tree_hash));
+        assert!(blob_exists(&repo, &kept_blob));
+
+        // keeping commits alone still keeps the trees and blobs they need
+        let opts = GcOptions {
+            collect_commits: false,
+            ..Default::default()
+        };
+        let stats = gc_with_options(&repo, &opts).unwrap();
+        assert_eq!(stats.blobs_removed + stats.trees_removed, 0);
+        assert!(tree_exists(&repo, &tree_hash));
+        assert!(blob_exists(&repo, &kept_blob));
+    }
+
+    #[test]
+    fn test_gc_spares_objects_written_during_gc() {
+        use crate::object::{blob_exists, tree_path, write_blob, write_tree};
+        use crate::types::{Tree, TreeEntry};
+
+        let (dir, repo) = test_repo();
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        let stale = write_blob(&repo, b"stale", uid, gid, 0o100644, &[]).unwrap();
+
+        // a concurrent commit writes its objects after the mark phase, before
+        // its ref update lands
+        let mut written = None;
+        let stats = gc_impl(&repo, &GcOptions::default(), || {
+            let blob = write_blob(&repo, b"in flight", uid, gid, 0o100644, &[])?;
+            let tree = Tree::new(vec![TreeEntry::new(
+                "new.txt",
+                EntryKind::regular(blob, 9, vec![]),
+            )])?;
+            written = Some((blob, write_tree(&repo, &tree)?));
+            Ok(())
+        })
+        .unwrap();
+
+        let (blob, tree) = written.unwrap();
+        assert!(blob_exists(&repo, &blob));
+        assert!(tree_path(&repo, &tree).exists());
+
+        // objects unreachable before gc started are still collected
+        assert_eq!(stats.blobs_removed, 1);
+        assert!(!blob_exists(&repo, &stale));
+    }
+
+    #[test]
+    fn test_gc_packs_cold_trees_and_commits() {
+        use crate::object::{commit_path, list_packed, tree_path};
+        use crate::ops::{checkout, fsck, CheckoutOptions};
+
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file.txt"), "old").unwrap();
+        let old = commit(&repo, &source, "old", None, None).unwrap();
+        let old_tree = read_commit(&repo, &old).unwrap().tree;
+
+        // age every object so far, then write a recent commit. atime after
+        // mtime, so relatime leaves it alone when the commit reads its parent
+        let now = SystemTime::now();
+        let times = fs::FileTimes::new()
+            .set_accessed(now - Duration::from_secs(3600))
+            .set_modified(now - Duration::from_secs(7200));
+        for dir in [repo.trees_path(), repo.commits_path()] {
+            for entry in WalkDir::new(dir).min_depth(2).max_depth(2) {
+                let entry = entry.unwrap();
+                fs::File::open(entry.path())
+                    .unwrap()
+                    .set_times(times)
+                    .unwrap();
+            }
+        }
+        fs::write(source.join("new.txt"), "new").unwrap();
+        let new = commit(&repo, &source, "new", None, None).unwrap();
+
+        let opts = GcOptions {
+            dry_run: true,
+            expire_loose_after: Some(Duration::from_secs(600)),
+            ..Default::default()
+        };
+        let stats = gc_with_options(&repo, &opts).unwrap();
+        // the old root tree and its subtree, which the new commit shares
+        assert_eq!((stats.trees_packed, stats.commits_packed), (2, 1));
+        assert!(commit_path(&repo, &old).exists());
+
+        let opts = GcOptions {
+            dry_run: false,
+            ..opts
+        };
+        let stats = gc_with_options(&repo, &opts).unwrap();
+        assert_eq!((stats.trees_packed, stats.commits_packed), (2, 1));
+        assert!(!commit_path(&repo, &old).exists());
+        assert!(!tree_path(&repo, &old_tree).exists());
+        assert!(commit_path(&repo, &new).exists());
+        assert_eq!(list_packed(&repo, PackedKind::Commit).unwrap(), vec![old]);
+
+        // reads fall back to the pack, from a fresh handle too
+        let repo = Repo::open(repo.path()).unwrap();
+        assert_eq!(read_commit(&repo, &old).unwrap().tree, old_tree);
+        let target = dir.path().join("out");
+        checkout(&repo, "old", &target, CheckoutOptions::default()).unwrap();
+        assert_eq!(fs::read(target.join("sub/file.txt")).unwrap(), b"old");
+
+        // nothing left to pack
+        let stats = gc_with_options(&repo, &opts).unwrap();
+        assert_eq!((stats.trees_packed, stats.commits_packed), (0, 0));
+
+        // fsck counts and verifies packed objects
+        let report = fsck(&repo).unwrap();
+        assert!(report.is_ok());
+        assert!(report.dangling_objects.is_empty());
+        let pack = repo.packs_path().join("0000.pack");
+        let mut data = fs::read(&pack).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        fs::write(&pack, data).unwrap();
+        let report = fsck(&repo).unwrap();
+        assert!(!report.is_ok());
+        assert!(report.corrupt_objects.iter().all(|c| c.hash == old));
+    }
+
+    #[test]
+    fn test_gc_collects_unreachable_packed() {
+        use crate::object::list_packed;
+        use crate::object::pack::tests::pack_loose;
+
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "a").unwrap();
+        let a = commit(&repo, &source, "a", None, None).unwrap();
+        fs::write(source.join("file.txt"), "b").unwrap();
+        let b = commit(&repo, &source, "b", None, None).unwrap();
+        pack_loose(&repo);
+        // a handle that loaded the pack index before the repack
+        let stale = Repo::open(repo.path()).unwrap();
+        assert!(read_commit(&stale, &b).is_ok());
+
+        crate::refs::delete_ref(&repo, "a").unwrap();
+        let stats = gc_expiring(&repo, true).unwrap();
+        assert_eq!((stats.commits_removed, stats.trees_removed), (1, 1));
+        assert_eq!(list_packed(&repo, PackedKind::Commit).unwrap().len(), 2);
+
+        let stats = gc_expiring(&repo, false).unwrap();
+        assert_eq!((stats.commits_removed, stats.trees_removed), (1, 1));
+        assert_eq!(list_packed(&repo, PackedKind::Commit).unwrap(), vec![b]);
+        assert!(!repo.packs_path().join("0000.pack").exists());
+        assert!(read_commit(&repo, &a).is_err());
+
+        // the stale handle finds the kept commit in the new pack
+        let tree = read_commit(&stale, &b).unwrap().tree;
+        assert!(read_tree(&stale, &tree).is_ok());
+    }
 }
@@ -1,6 +1,9 @@
-use crate::error::Result;
+use std::collections::HashSet;
+
+use crate::error::{Error, Result};
 use crate::hash::Hash;
-use crate::object::read_commit;
+use crate::object::{read_commit, read_commit_header};
+use crate::ops::ancestry::is_ancestor;
 use crate::refs::resolve_ref;
 use crate::repo::Repo;
 use crate::types::Commit;
@@ -12,30 +15,125 @@ pub struct LogEntry {
     pub commit: Commit,
 }
 
+/// history walk options
+#[derive(Debug, Clone, Default)]
+pub struct LogOptions {
+    /// maximum number of commits to return
+    pub max_count: Option<usize>,
+    /// follow only the first parent of each commit, e.g. to get the linear
+    /// history of one branch through union commits
+    pub first_parent_only: bool,
+    /// don't walk further than this many commits from the head (the head is 1)
+    pub depth: Option<usize>,
+    /// stop at commits older than this unix timestamp
+    pub since: Option<i64>,
+}
+
 /// get commit history for a ref
 pub fn log(repo: &Repo, ref_name: &str, max_count: Option<usize>) -> Result<Vec<LogEntry>> {
+    let opts = LogOptions {
+        max_count,
+        ..Default::default()
+    };
+    log_with_options(repo, ref_name, &opts)
+}
+
+/// get commit history for a ref with full options
+pub fn log_with_options(repo: &Repo, ref_name: &str, opts: &LogOptions) -> Result<Vec<LogEntry>> {
     let head_hash = resolve_ref(repo, ref_name)?;
+    walk_log(repo, head_hash, HashSet::new(), opts)
+}
+
+/// get commits reachable from `to` but not from `from` (git's `from..to`)
+///
+/// `from`'s history is walked first and excluded. if `from` is not an ancestor of
+/// `to`, this still yields the commits unique to `to`, unless `require_ancestor`
+/// is set, in which case it errors.
+pub fn log_range(
+    repo: &Repo,
+    from: Option<&str>,
+    to: &str,
+    max_count: Option<usize>,
+    require_ancestor: bool,
+) -> Result<Vec<LogEntry>> {
+    let opts = LogOptions {
+        max_count,
+        ..Default::default()
+    };
+    log_range_with_options(repo, from, to, require_ancestor, &opts)
+}
+
+/// `log_range` with full options; they only apply to the walk from `to`
+pub fn log_range_with_options(
+    repo: &Repo,
+    from: Option<&str>,
+    to: &str,
+    require_ancestor: bool,
+    opts: &LogOptions,
+) -> Result<Vec<LogEntry>> {
+    let head_hash = resolve_ref(repo, to)?;
+
+    let mut excluded = HashSet::new();
+    if let Some(from) = from {
+        let from_hash = resolve_ref(repo, from)?;
+        let mut to_visit = vec![from_hash];
+        while let Some(hash) = to_visit.pop() {
+            if !excluded.insert(hash) {
+                continue;
+            }
+            let header = read_commit_header(repo, &hash)?;
+            to_visit.extend(header.parents.iter().copied());
+        }
+
+        if require_ancestor && !is_ancestor(repo, &from_hash, &head_hash)? {
+            return Err(Error::InvalidRef(format!(
+                "{} is not an ancestor of {}",
+                from, to
+            )));
+        }
+    }
+
+    walk_log(repo, head_hash, excluded, opts)
+}
+
+/// walk history from head, skipping anything already in `visited`
+fn walk_log(
+    repo: &Repo,
+    head_hash: Hash,
+    mut visited: HashSet<Hash>,
+    opts: &LogOptions,
+) -> Result<Vec<LogEntry>> {
     let mut entries = Vec::new();
-    let mut to_visit = vec![head_hash];
-    let mut visited = std::collections::HashSet::new();
+    // commits to visit with their distance from head
+    let mut to_visit = vec![(head_hash, 1)];
 
-    while let Some(hash) = to_visit.pop() {
+    while let Some((hash, depth)) = to_visit.pop() {
         if visited.contains(&hash) {
             continue;
         }
         visited.insert(hash);
 
-        if let Some(max) = max_count {
+        if let Some(max) = opts.max_count {
             if entries.len() >= max {
                 break;
             }
         }
 
         let commit = read_commit(repo, &hash)?;
+        if opts.since.is_some_and(|since| commit.timestamp < since) {
+            continue;
+        }
 
         // add parents to visit queue (oldest first for linear history)
-        for parent in commit.parents.iter().rev() {
-            to_visit.push(*parent);
+        if opts.depth.is_none_or(|max| depth < max) {
+            let parents = if opts.first_parent_only {
+                &commit.parents[..commit.parents.len().min(1)]
+            } else {
+                &commit.parents[..]
+            };
+            for parent in parents.iter().rev() {
+                to_visit.push((*parent, depth + 1));
+            }
         }
 
         entries.push(LogEntry { hash, commit });
@@ -45,7 +143,7 @@ pub fn log(repo: &Repo, ref_name: &str, max_count: Option<usize>) -> Result<Vec<
     entries.sort_by(|a, b| b.commit.timestamp.cmp(&a.commit.timestamp));
 
     // apply limit after sorting
-    if let Some(max) = max_count {
+    if let Some(max) = opts.max_count {
         entries.truncate(max);
     }
 
@@ -173,6 +271,55 @@ mod tests {
         assert_eq!(entries.len(), 2);
     }
 
+    #[test]
+    fn test_log_range() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+
+        let mut hashes = Vec::new();
+        for i in 0..5 {
+            fs::write(source.join("file.txt"), format!("v{}", i)).unwrap();
+            let msg = format!("commit {}", i);
+            let hash = commit(&repo, &source, "test", Some(&msg), None).unwrap();
+            hashes.push(hash);
+        }
+
+        let from = hashes[1].to_hex();
+        let to = hashes[3].to_hex();
+        let entries = log_range(&repo, Some(&from), &to, None, true).unwrap();
+
+        let mut got: Vec<Hash> = entries.iter().map(|e| e.hash).collect();
+        got.sort();
+        let mut want = vec![hashes[2], hashes[3]];
+        want.sort();
+        assert_eq!(got, want);
+
+        // no lower bound behaves like log
+        let entries = log_range(&repo, None, "test", None, false).unwrap();
+        assert_eq!(entries.len(), 5);
+    }
+
+    #[test]
+    fn test_log_range_not_ancestor() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+
+        fs::write(source.join("file.txt"), "a").unwrap();
+        commit(&repo, &source, "a", Some("a"), None).unwrap();
+        fs::write(source.join("file.txt"), "b").unwrap();
+        commit(&repo, &source, "b", Some("b"), None).unwrap();
+
+        let entries = log_range(&repo, Some("a"), "b", None, false).unwrap();
+        assert_eq!(entries.len(), 1);
+
+        let result = log_range(&repo, Some("a"), "b", None, true);
+        assert!(matches!(result, Err(Error::InvalidRef(_))));
+    }
+
     #[test]
     fn test_log_entry_display() {
         let (dir, repo) = test_repo();
@@ -196,4 +343,109 @@ mod tests {
         assert!(display.contains("Author: Test Author"));
         assert!(display.contains("test message"));
     }
+
+    #[test]
+    fn test_log_first_parent_through_union() {
+        use crate::ops::{union_trees, UnionOptions};
+
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        for i in 0..3 {
+            fs::write(source.join("a.txt"), format!("a{}", i)).unwrap();
+            commit(&repo, &source, "a", Some(&format!("a{}", i)), None).unwrap();
+        }
+        let other = dir.path().join("other");
+        fs::create_dir(&other).unwrap();
+        for i in 0..2 {
+            fs::write(other.join("b.txt"), format!("b{}", i)).unwrap();
+            commit(&repo, &other, "b", Some(&format!("b{}", i)), None).unwrap();
+        }
+        union_trees(&repo, &["a", "b"], "merged", UnionOptions::default()).unwrap();
+
+        // the full walk crosses into both parents
+        assert_eq!(log(&repo, "merged", None).unwrap().len(), 6);
+
+        let opts = LogOptions {
+            first_parent_only: true,
+            ..Default::default()
+        };
+        let entries = log_with_options(&repo, "merged", &opts).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].commit.parents.len(), 2);
+        // a linear chain: each entry's first parent is the next entry
+        for pair in entries.windows(2) {
+            assert_eq!(pair[0].commit.parents[0], pair[1].hash);
+        }
+        assert!(entries[3].commit.parents.is_empty());
+        let messages: Vec<_> = entries[1..]
+            .iter()
+            .map(|e| e.commit.message.as_str())
+            .collect();
+        assert_eq!(messages, ["a2", "a1", "a0"]);
+    }
+
+    #[test]
+    fn test_log_depth_and_since() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        for i in 0..5 {
+            fs::write(source.join("file.txt"), format!("v{}", i)).unwrap();
+            commit(&repo, &source, "test", Some(&format!("commit {}", i)), None).unwrap();
+        }
+
+        let opts = LogOptions {
+            depth: Some(2),
+            ..Default::default()
+        };
+        let entries = log_with_options(&repo, "test", &opts).unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let all = log(&repo, "test", None).unwrap();
+        let newest = all.iter().map(|e| e.commit.timestamp).max().unwrap();
+        let opts = LogOptions {
+            since: Some(newest + 1),
+            ..Default::default()
+        };
+        assert!(log_with_options(&repo, "test", &opts).unwrap().is_empty());
+        let opts = LogOptions {
+            since: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(log_with_options(&repo, "test", &opts).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_log_reads_no_trees() {
+        use crate::object::tree::tests::tree_reads;
+
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        for i in 0..3 {
+            fs::write(source.join("sub/file.txt"), format!("v{}", i)).unwrap();
+            commit(&repo, &source, "test", Some(&format!("commit {}", i)), None).unwrap();
+        }
+
+        let before = tree_reads();
+        assert_eq!(log(&repo, "test", None).unwrap().len(), 3);
+        let oldest = log(&repo, "test", None).unwrap()[2].hash.to_hex();
+        assert_eq!(
+            log_range(&repo, Some(&oldest), "test", None, true)
+                .unwrap()
+                .len(),
+            2
+        );
+        let header = read_commit_header(&repo, &resolve_ref(&repo, "test").unwrap()).unwrap();
+        assert_eq!(header.parents.len(), 1);
+        assert_eq!(tree_reads(), before);
+
+        // the counter does see tree reads
+        crate::object::read_tree(&repo, &header.tree).unwrap();
+        assert_eq!(tree_reads(), before + 1);
+    }
 }
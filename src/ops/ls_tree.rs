@@ -3,10 +3,12 @@ use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 
 use crate::error::Result;
-use crate::object::{blob_path, read_blob, read_commit, read_tree};
+use crate::object::{
+    blob_meta_path, blob_path, read_blob, read_blob_prefix, read_commit, read_tree,
+};
 use crate::refs::resolve_ref;
 use crate::repo::Repo;
-use crate::types::{EntryKind, Tree, TreeEntry};
+use crate::types::{EntryKind, SparseRegion, Tree, TreeEntry, Xattr};
 
 /// options for ls-tree output
 #[derive(Clone, Default)]
@@ -15,6 +17,8 @@ pub struct LsTreeOptions {
     pub long: bool,
     /// show human-readable sizes
     pub human: bool,
+    /// tag each entry with its `FileClass`
+    pub classify: bool,
 }
 
 /// resolved metadata for long format display
@@ -33,6 +37,176 @@ pub struct LsTreeEntry {
     pub entry: TreeEntry,
     /// resolved metadata (only populated in long mode)
     pub metadata: Option<EntryMetadata>,
+    /// rough file type (only populated with `classify`)
+    pub class: Option<FileClass>,
+}
+
+/// rough type of an entry: its kind, or for a regular file what its
+/// leading bytes look like
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileClass {
+    Elf,
+    /// starts with `#!`
+    Script,
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+    Zip,
+    /// printable ascii or utf-8 as far as `classify` looked
+    Text,
+    /// anything else
+    Data,
+    Empty,
+    Directory,
+    Symlink,
+    Hardlink,
+    Device,
+    Fifo,
+    Socket,
+}
+
+impl FileClass {
+    /// short lowercase tag, as shown by `ls-tree --classify`
+    pub fn tag(&self) -> &'static str {
+        match self {
+            FileClass::Elf => "elf",
+            FileClass::Script => "script",
+            FileClass::Gzip => "gzip",
+            FileClass::Zstd => "zstd",
+            FileClass::Xz => "xz",
+            FileClass::Bzip2 => "bzip2",
+            FileClass::Zip => "zip",
+            FileClass::Text => "text",
+            FileClass::Data => "data",
+            FileClass::Empty => "empty",
+            FileClass::Directory => "dir",
+            FileClass::Symlink => "symlink",
+            FileClass::Hardlink => "hardlink",
+            FileClass::Device => "device",
+            FileClass::Fifo => "fifo",
+            FileClass::Socket => "socket",
+        }
+    }
+}
+
+impl std::fmt::Display for FileClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.tag())
+    }
+}
+
+/// leading bytes `classify` reads from a regular file
+const MAGIC_LEN: usize = 16;
+
+/// magic numbers matched at the start of a regular file
+const MAGIC: &[(&[u8], FileClass)] = &[
+    (b"\x7fELF", FileClass::Elf),
+    (b"#!", FileClass::Script),
+    (b"\x1f\x8b", FileClass::Gzip),
+    (b"\x28\xb5\x2f\xfd", FileClass::Zstd),
+    (b"\xfd7zXZ\x00", FileClass::Xz),
+    (b"BZh", FileClass::Bzip2),
+    (b"PK\x03\x04", FileClass::Zip),
+];
+
+/// classify an entry without checking it out
+///
+/// only the first `MAGIC_LEN` bytes of a regular file are read; other kinds
+/// are classified by kind alone. hardlinks aren't followed.
+pub fn classify(repo: &Repo, entry: &TreeEntry) -> Result<FileClass> {
+    let class = match &entry.kind {
+        EntryKind::Regular { size: 0, .. } => FileClass::Empty,
+        EntryKind::Regular {
+            hash,
+            size,
+            sparse_map,
+            content,
+            ..
+        } => {
+            let head = match sparse_map {
+                Some(regions) => sparse_head(repo, hash, content.as_ref(), regions, *size)?,
+                None => read_blob_prefix(repo, hash, content.as_ref(), MAGIC_LEN)?,
+            };
+            classify_bytes(&head)
+        }
+        EntryKind::Directory { .. } => FileClass::Directory,
+        EntryKind::Symlink { .. } => FileClass::Symlink,
+        EntryKind::Hardlink { .. } => FileClass::Hardlink,
+        EntryKind::BlockDevice { .. } | EntryKind::CharDevice { .. } => FileClass::Device,
+        EntryKind::Fifo { .. } => FileClass::Fifo,
+        EntryKind::Socket { .. } => FileClass::Socket,
+    };
+    Ok(class)
+}
+
+/// the first logical bytes of a sparse file, holes read as zeros
+///
+/// the blob holds the data regions back to back, so those bytes come from
+/// at most as many bytes at its start.
+fn sparse_head(
+    repo: &Repo,
+    hash: &crate::hash::Hash,
+    content: Option<&crate::hash::Hash>,
+    regions: &[SparseRegion],
+    size: u64,
+) -> Result<Vec<u8>> {
+    let len = size.min(MAGIC_LEN as u64) as usize;
+    let mut head = vec![0u8; len];
+    let data = read_blob_prefix(repo, hash, content, len)?;
+    let mut pos = 0usize;
+    for region in regions {
+        if region.offset >= len as u64 || pos >= data.len() {
+            break;
+        }
+        let start = region.offset as usize;
+        let n = (region.length.min(len as u64) as usize)
+            .min(len - start)
+            .min(data.len() - pos);
+        head[start..start + n].copy_from_slice(&data[pos..pos + n]);
+        pos += n;
+    }
+    Ok(head)
+}
+
+fn classify_bytes(head: &[u8]) -> FileClass {
+    if let Some((_, class)) = MAGIC.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return *class;
+    }
+    // bytes >= 0x80 pass, since a utf-8 sequence may be cut off at the end
+    let text = head
+        .iter()
+        .all(|&b| matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x20..=0x7e | 0x80..));
+    if text {
+        FileClass::Text
+    } else {
+        FileClass::Data
+    }
+}
+
+/// listing entry for `entry` at `path`, resolving what `opts` asks for
+fn list_entry(
+    repo: &Repo,
+    path: String,
+    entry: &TreeEntry,
+    opts: &LsTreeOptions,
+) -> Result<LsTreeEntry> {
+    let metadata = if opts.long {
+        resolve_metadata(repo, &entry.kind)
+    } else {
+        None
+    };
+    let class = if opts.classify {
+        Some(classify(repo, entry)?)
+    } else {
+        None
+    };
+    Ok(LsTreeEntry {
+        path,
+        entry: entry.clone(),
+        metadata,
+        class,
+    })
 }
 
 /// list tree contents, optionally at a specific path
@@ -103,16 +277,7 @@ fn ls_tree_at_path(
                         } else {
                             format!("{}/{}", current_path, component)
                         };
-                        let metadata = if opts.long {
-                            resolve_metadata(repo, &entry.kind)
-                        } else {
-                            None
-                        };
-                        return Ok(vec![LsTreeEntry {
-                            path: full_path,
-                            entry: entry.clone(),
-                            metadata,
-                        }]);
+                        return Ok(vec![list_entry(repo, full_path, entry, opts)?]);
                     }
                 }
             }
@@ -143,17 +308,7 @@ fn ls_tree_flat(
             format!("{}/{}", prefix, entry.name)
         };
 
-        let metadata = if opts.long {
-            resolve_metadata(repo, &entry.kind)
-        } else {
-            None
-        };
-
-        entries.push(LsTreeEntry {
-            path,
-            entry: entry.clone(),
-            metadata,
-        });
+        entries.push(list_entry(repo, path, entry, opts)?);
     }
 
     Ok(entries)
@@ -188,17 +343,7 @@ fn ls_tree_recursive_impl(
             format!("{}/{}", prefix, entry.name)
         };
 
-        let metadata = if opts.long {
-            resolve_metadata(repo, &entry.kind)
-        } else {
-            None
-        };
-
-        entries.push(LsTreeEntry {
-            path: path.clone(),
-            entry: entry.clone(),
-            metadata,
-        });
+        entries.push(list_entry(repo, path.clone(), entry, opts)?);
 
         // recurse into directories
         if let EntryKind::Directory { hash, .. } = &entry.kind {
@@ -210,12 +355,24 @@ fn ls_tree_recursive_impl(
     Ok(())
 }
 
+/// sum of the logical sizes of regular files in a listing
+///
+/// hardlinks aren't counted again, and sparse files count at their full size.
+pub fn total_size(entries: &[LsTreeEntry]) -> u64 {
+    entries.iter().filter_map(|e| e.entry.kind.size()).sum()
+}
+
 /// resolve metadata for an entry (reads blob file for regular/symlink)
 fn resolve_metadata(repo: &Repo, kind: &EntryKind) -> Option<EntryMetadata> {
     match kind {
-        EntryKind::Regular { hash, size, .. } => {
+        EntryKind::Regular {
+            hash,
+            size,
+            content,
+            ..
+        } => {
             // read uid/gid/mode from blob file
-            let blob = blob_path(repo, hash);
+            let blob = blob_meta_path(repo, hash, content.as_ref());
             if let Ok(meta) = fs::metadata(&blob) {
                 Some(EntryMetadata {
                     uid: meta.uid(),
@@ -293,10 +450,19 @@ fn resolve_metadata(repo: &Repo, kind: &EntryKind) -> Option<EntryMetadata> {
 impl LsTreeEntry {
     /// format entry with options
     pub fn format(&self, opts: &LsTreeOptions) -> String {
-        if opts.long {
+        let line = if opts.long {
             self.format_long(opts.human)
         } else {
             self.format_short()
+        };
+        self.with_class(line)
+    }
+
+    /// append the `classify` tag, if resolved
+    fn with_class(&self, line: String) -> String {
+        match self.class {
+            Some(class) => format!("{}  [{}]", line, class),
+            None => line,
         }
     }
 
@@ -360,6 +526,90 @@ impl LsTreeEntry {
     }
 }
 
+/// render every field of a tree's entries as a table (`cat-file --pretty`)
+///
+/// regular files and symlinks take uid/gid/mode from their blob. devices show
+/// `major,minor` in the size column, hardlinks their target after the name;
+/// content hashes and recorded mtimes follow it too.
+pub fn format_tree_pretty(repo: &Repo, tree: &Tree) -> Vec<String> {
+    let mut lines = vec![format!(
+        "{:<7} {:<12} {:>6} {:>6} {:>12} {:<64} {:<6} {:>6} name",
+        "mode", "type", "uid", "gid", "size", "hash", "sparse", "xattrs"
+    )];
+
+    for entry in tree.entries() {
+        let kind = &entry.kind;
+        let meta = resolve_metadata(repo, kind);
+        let (mode, uid, gid) = match &meta {
+            Some(m) => (
+                format!("{:06o}", m.mode),
+                m.uid.to_string(),
+                m.gid.to_string(),
+            ),
+            None => ("-".to_string(), "-".to_string(), "-".to_string()),
+        };
+        let size = match kind {
+            EntryKind::BlockDevice { major, minor, .. }
+            | EntryKind::CharDevice { major, minor, .. } => format!("{},{}", major, minor),
+            EntryKind::Regular { size, .. } => size.to_string(),
+            _ => meta
+                .map(|m| m.size.to_string())
+                .unwrap_or_else(|| "-".to_string()),
+        };
+        let hash = kind
+            .hash()
+            .map(|h| h.to_hex())
+            .unwrap_or_else(|| "-".to_string());
+        let sparse = match kind {
+            EntryKind::Regular {
+                sparse_map: Some(_),
+                ..
+            } => "yes",
+            EntryKind::Regular { .. } => "no",
+            _ => "-",
+        };
+
+        let mut line = format!(
+            "{:<7} {:<12} {:>6} {:>6} {:>12} {:<64} {:<6} {:>6} {}",
+            mode,
+            kind.type_name(),
+            uid,
+            gid,
+            size,
+            hash,
+            sparse,
+            entry_xattrs(kind).len(),
+            entry.name
+        );
+        if let EntryKind::Hardlink { target_path } = kind {
+            line.push_str(&format!(" -> {}", target_path));
+        }
+        if let Some(content) = kind.content_hash() {
+            line.push_str(&format!(" (content {})", content));
+        }
+        if let Some(times) = kind.times() {
+            line.push_str(&format!(" (mtime {})", times.mtime));
+        }
+        lines.push(line);
+    }
+
+    lines
+}
+
+/// xattrs stored on an entry (hardlinks share their target's)
+fn entry_xattrs(kind: &EntryKind) -> &[Xattr] {
+    match kind {
+        EntryKind::Regular { xattrs, .. }
+        | EntryKind::Symlink { xattrs, .. }
+        | EntryKind::Directory { xattrs, .. }
+        | EntryKind::BlockDevice { xattrs, .. }
+        | EntryKind::CharDevice { xattrs, .. }
+        | EntryKind::Fifo { xattrs, .. }
+        | EntryKind::Socket { xattrs, .. } => xattrs,
+        EntryKind::Hardlink { .. } => &[],
+    }
+}
+
 /// format mode as permission string (e.g., -rwxr-xr-x)
 fn format_permissions(mode: u32) -> String {
     let file_type = match mode & 0o170000 {
@@ -417,7 +667,22 @@ fn format_human_size(size: u64) -> String {
 
 impl std::fmt::Display for LsTreeEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.format_short())
+        // metadata is only resolved in long mode
+        let line = if self.metadata.is_some() {
+            self.format_long(false)
+        } else {
+            self.format_short()
+        };
+        write!(f, "{}", self.with_class(line))
+    }
+}
+
+/// format the total line printed after a long listing
+pub fn format_total(total: u64, human: bool) -> String {
+    if human {
+        format!("total {}", format_human_size(total).trim_start())
+    } else {
+        format!("total {}", total)
     }
 }
 
@@ -520,6 +785,7 @@ mod tests {
         let opts = LsTreeOptions {
             long: true,
             human: false,
+            classify: false,
         };
         let entries = ls_tree(&repo, "test", None, &opts).unwrap();
 
@@ -544,4 +810,116 @@ mod tests {
         assert_eq!(format_human_size(1048576), "1.0M");
         assert_eq!(format_human_size(1073741824), "1.0G");
     }
+
+    #[test]
+    fn test_ls_tree_sizes_and_total() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("file.txt"), "twelve bytes").unwrap();
+        fs::write(source.join("sub/other.txt"), "abc").unwrap();
+        fs::hard_link(source.join("file.txt"), source.join("link.txt")).unwrap();
+
+        // sparse file: logical size, not stored bytes
+        let sparse = fs::File::create(source.join("sparse.img")).unwrap();
+        sparse.set_len(1 << 20).unwrap();
+        drop(sparse);
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        let opts = LsTreeOptions {
+            long: true,
+            human: false,
+            classify: false,
+        };
+        let entries = ls_tree_recursive(&repo, "test", &opts).unwrap();
+        let file = entries.iter().find(|e| e.path == "file.txt").unwrap();
+        assert_eq!(file.entry.kind.size(), Some(12));
+        assert!(format!("{}", file).contains("      12 file.txt"));
+
+        let sparse = entries.iter().find(|e| e.path == "sparse.img").unwrap();
+        assert_eq!(sparse.entry.kind.size(), Some(1 << 20));
+
+        assert_eq!(total_size(&entries), 12 + 3 + (1 << 20));
+        assert_eq!(format_total(2048, true), "total 2.0K");
+
+        // short display is unchanged
+        let short = ls_tree(&repo, "test", None, &LsTreeOptions::default()).unwrap();
+        assert!(format!("{}", short[0]).contains("regular"));
+    }
+
+    #[test]
+    fn test_format_tree_pretty() {
+        use std::os::unix::fs::MetadataExt;
+
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+        let commit_hash = commit(&repo, &source, "test", None, None).unwrap();
+        let meta = fs::metadata(source.join("file.txt")).unwrap();
+
+        let tree = read_tree(&repo, &read_commit(&repo, &commit_hash).unwrap().tree).unwrap();
+        let lines = format_tree_pretty(&repo, &tree);
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("mode"));
+        let fields: Vec<&str> = lines[1].split_whitespace().collect();
+        assert_eq!(fields[0], format!("{:06o}", meta.mode()));
+        assert_eq!(fields[1], "regular");
+        assert_eq!(fields[2], meta.uid().to_string());
+        assert_eq!(fields[4], "7");
+        assert_eq!(fields[6], "no");
+        assert_eq!(fields[8], "file.txt");
+    }
+
+    #[test]
+    fn test_classify() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("etc")).unwrap();
+        let mut elf = b"\x7fELF\x02\x01\x01\x00".to_vec();
+        elf.extend_from_slice(&[0u8; 4096]);
+        fs::write(source.join("app"), &elf).unwrap();
+        fs::write(source.join("etc/hosts"), "127.0.0.1 localhost\n").unwrap();
+        fs::write(source.join("run.sh"), "#!/bin/sh\nexec app\n").unwrap();
+        fs::write(source.join("empty"), "").unwrap();
+        std::os::unix::fs::symlink("app", source.join("link")).unwrap();
+        // starts with a hole, so its leading bytes are zeros
+        let sparse = fs::File::create(source.join("disk.img")).unwrap();
+        sparse.set_len(1 << 20).unwrap();
+        std::os::unix::fs::FileExt::write_all_at(&sparse, b"#!not a script", 1 << 19).unwrap();
+        drop(sparse);
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        let opts = LsTreeOptions {
+            classify: true,
+            ..Default::default()
+        };
+        let entries = ls_tree_recursive(&repo, "test", &opts).unwrap();
+        let class = |path: &str| {
+            entries
+                .iter()
+                .find(|e| e.path == path)
+                .unwrap()
+                .class
+                .unwrap()
+        };
+        assert_eq!(class("app"), FileClass::Elf);
+        assert_eq!(class("etc/hosts"), FileClass::Text);
+        assert_eq!(class("run.sh"), FileClass::Script);
+        assert_eq!(class("empty"), FileClass::Empty);
+        assert_eq!(class("etc"), FileClass::Directory);
+        assert_eq!(class("link"), FileClass::Symlink);
+        assert_eq!(class("disk.img"), FileClass::Data);
+
+        let app = entries.iter().find(|e| e.path == "app").unwrap();
+        assert!(app.format(&opts).ends_with("app  [elf]"));
+
+        // not resolved unless asked for
+        let plain = ls_tree(&repo, "test", None, &LsTreeOptions::default()).unwrap();
+        assert!(plain.iter().all(|e| e.class.is_none()));
+    }
 }
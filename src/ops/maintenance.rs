@@ -0,0 +1,210 @@
+//! combined maintenance pass: prune dangling refs, gc, prune tmp
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use crate::error::{IoResultExt, Result};
+use crate::ops::gc::{gc_with_options, GcOptions, GcStats};
+use crate::refs::prune_dangling_refs;
+use crate::repo::Repo;
+
+/// options for a maintenance pass
+#[derive(Debug, Clone)]
+pub struct MaintenanceOptions {
+    /// only report what would be removed
+    pub dry_run: bool,
+    /// passed on to gc
+    pub keep_reflog_generations: usize,
+    /// passed on to gc
+    pub keep_deleted_refs_for: Option<Duration>,
+    /// leave `tmp/` entries younger than this alone; they may belong to a
+    /// write still in progress
+    pub tmp_grace: Duration,
+}
+
+impl Default for MaintenanceOptions {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            keep_reflog_generations: GcOptions::default().keep_reflog_generations,
+            keep_deleted_refs_for: GcOptions::default().keep_deleted_refs_for,
+            tmp_grace: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// what a maintenance pass did (or would do, in dry-run)
+#[derive(Debug, Default)]
+pub struct MaintenanceReport {
+    /// refs whose commit was missing
+    pub pruned_refs: Vec<String>,
+    pub gc: GcStats,
+    /// stale entries removed from `tmp/`
+    pub tmp_removed: usize,
+    pub tmp_bytes_freed: u64,
+}
+
+/// run a full maintenance pass under a single repo lock
+///
+/// dangling refs go first so the objects only they pointed at become
+/// collectable by the gc that follows; leftover temp files from interrupted
+/// writes are removed last.
+pub fn maintenance(repo: &Repo, opts: &MaintenanceOptions) -> Result<MaintenanceReport> {
+    let _lock = repo.lock()?;
+
+    let mut report = MaintenanceReport {
+        pruned_refs: prune_dangling_refs(repo, opts.dry_run)?,
+        ..Default::default()
+    };
+
+    // in dry-run the dangling refs are still there, but gc marks nothing
+    // through them, so the preview matches the real pass
+    let gc_opts = GcOptions {
+        dry_run: opts.dry_run,
+        keep_reflog_generations: opts.keep_reflog_generations,
+        keep_deleted_refs_for: opts.keep_deleted_refs_for,
+        ..Default::default()
+    };
+    report.gc = gc_with_options(repo, &gc_opts)?;
+
+    prune_tmp(&repo.tmp_path(), opts, &mut report)?;
+    let objects_tmp = repo.objects_tmp_path();
+    if objects_tmp != repo.tmp_path() {
+        prune_tmp(&objects_tmp, opts, &mut report)?;
+    }
+
+    Ok(report)
+}
+
+/// remove entries of a tmp dir last modified before the grace period
+fn prune_tmp(tmp: &Path, opts: &MaintenanceOptions, report: &mut MaintenanceReport) -> Result<()> {
+    if !tmp.exists() {
+        return Ok(());
+    }
+    let cutoff = SystemTime::now()
+        .checked_sub(opts.tmp_grace)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    for entry in fs::read_dir(tmp).with_path(tmp)? {
+        let entry = entry.with_path(tmp)?;
+        // object writers keep their lock files here, removing each when done
+        if entry.file_name() == crate::object::blob::LOCKS_DIR {
+            continue;
+        }
+        let path = entry.path();
+        let meta = match fs::symlink_metadata(&path) {
+            Ok(meta) => meta,
+            // removed by its writer meanwhile
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_path(&path),
+        };
+        if meta.modified().with_path(&path)? > cutoff {
+            continue;
+        }
+
+        let size = if meta.is_dir() {
+            walkdir::WalkDir::new(&path)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter_map(|e| e.metadata().ok())
+                .filter(|m| m.is_file())
+                .map(|m| m.len())
+                .sum()
+        } else {
+            meta.len()
+        };
+
+        if !opts.dry_run {
+            let removed = if meta.is_dir() {
+                fs::remove_dir_all(&path)
+            } else {
+                fs::remove_file(&path)
+            };
+            match removed {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_path(&path),
+            }
+        }
+        report.tmp_removed += 1;
+        report.tmp_bytes_freed += size;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::blob::tests::tmp_leftovers;
+    use crate::object::{commit_path, write_blob};
+    use crate::ops::commit::commit;
+    use crate::ops::fsck::fsck;
+    use crate::refs::{list_refs, ref_exists, resolve_ref};
+    use tempfile::tempdir;
+
+    fn test_repo() -> (tempfile::TempDir, Repo) {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        let repo = Repo::init(&repo_path).unwrap();
+        (dir, repo)
+    }
+
+    #[test]
+    fn test_maintenance_cleans_up() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("kept.txt"), "kept").unwrap();
+        commit(&repo, &source, "live", None, None).unwrap();
+
+        // a ref whose commit is gone, leaving its tree and blob orphaned
+        fs::write(source.join("kept.txt"), "orphaned").unwrap();
+        let gone = commit(&repo, &source, "gone", None, None).unwrap();
+        fs::remove_file(commit_path(&repo, &gone)).unwrap();
+
+        // an object nothing ever referenced, and a leftover temp file
+        write_blob(&repo, b"stray", 0, 0, 0o100644, &[]).unwrap();
+        fs::write(repo.tmp_path().join("partial"), "12345").unwrap();
+
+        let opts = MaintenanceOptions {
+            tmp_grace: Duration::ZERO,
+            ..Default::default()
+        };
+
+        let dry = maintenance(
+            &repo,
+            &MaintenanceOptions {
+                dry_run: true,
+                ..opts.clone()
+            },
+        )
+        .unwrap();
+        assert_eq!(dry.pruned_refs, vec!["gone".to_string()]);
+        assert!(ref_exists(&repo, "gone"));
+        assert!(repo.tmp_path().join("partial").exists());
+
+        let report = maintenance(&repo, &opts).unwrap();
+        assert_eq!(report.pruned_refs, vec!["gone".to_string()]);
+        assert_eq!(report.gc.blobs_removed, 2);
+        assert_eq!(report.gc.trees_removed, 1);
+        assert_eq!(report.tmp_removed, 1);
+        assert_eq!(report.tmp_bytes_freed, 5);
+
+        assert_eq!(list_refs(&repo).unwrap(), vec!["live".to_string()]);
+        assert!(resolve_ref(&repo, "live").is_ok());
+        let fsck_report = fsck(&repo).unwrap();
+        assert!(fsck_report.is_ok(), "{:?}", fsck_report);
+        assert!(fsck_report.dangling_objects.is_empty());
+        assert_eq!(tmp_leftovers(&repo.tmp_path()), 0);
+
+        // the lock is held for the whole pass
+        let _lock = repo.lock().unwrap();
+        assert!(matches!(
+            maintenance(&repo, &opts),
+            Err(crate::Error::LockContention)
+        ));
+    }
+}
@@ -0,0 +1,202 @@
+//! inventory of every object reachable from a ref
+
+use std::collections::HashSet;
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::hash::Hash;
+use crate::object::{blob_path, commit_path, content_path, packed_size, tree_path};
+use crate::ops::fsck::ObjectType;
+use crate::refs::resolve_ref;
+use crate::repo::Repo;
+use crate::transport::local::ObjectSet;
+use crate::transport::pull::collect_commit_objects;
+
+/// one object in a manifest; size is the stored file size
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub object_type: ObjectType,
+    pub hash: Hash,
+    pub size: u64,
+}
+
+/// every object reachable from a commit, history included, sorted by type
+/// then hash
+///
+/// unlike a bundle this holds no object bytes, only the inventory, so a
+/// consumer can check that a transfer is complete.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub commit: Hash,
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// fingerprint of the whole inventory: blake3 over the CBOR-encoded
+    /// entry list. deterministic, since the entries are sorted.
+    pub fn manifest_hash(&self) -> Hash {
+        let mut buf = Vec::new();
+        ciborium::into_writer(&self.entries, &mut buf).expect("cbor serialization failed");
+        Hash::from_bytes(*blake3::hash(&buf).as_bytes())
+    }
+}
+
+/// build the manifest of a ref's full reachable closure
+pub fn manifest(repo: &Repo, ref_name: &str) -> Result<Manifest> {
+    let commit = resolve_ref(repo, ref_name)?;
+
+    let mut objects = ObjectSet::new();
+    collect_commit_objects(repo, &commit, &mut objects, &mut HashSet::new())?;
+
+    let typed = [
+        (ObjectType::Blob, &objects.blobs),
+        (ObjectType::Tree, &objects.trees),
+        (ObjectType::Commit, &objects.commits),
+        (ObjectType::Content, &objects.contents),
+    ];
+
+    let mut entries = Vec::with_capacity(objects.total_count());
+    for (object_type, hashes) in typed {
+        for hash in hashes {
+            let size = stored_size(repo, object_type, hash)?;
+            entries.push(ManifestEntry {
+                object_type,
+                hash: *hash,
+                size,
+            });
+        }
+    }
+    entries.sort_by_key(|e| (e.object_type, e.hash));
+
+    Ok(Manifest { commit, entries })
+}
+
+/// size of an object's loose file, or of its pack entry once packed
+fn stored_size(repo: &Repo, object_type: ObjectType, hash: &Hash) -> Result<u64> {
+    let path = match object_type {
+        ObjectType::Blob => blob_path(repo, hash),
+        ObjectType::Tree => tree_path(repo, hash),
+        ObjectType::Commit => commit_path(repo, hash),
+        ObjectType::Content => content_path(repo, hash),
+    };
+    match fs::symlink_metadata(&path) {
+        Ok(meta) => Ok(meta.len()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let packed = match object_type.packed_kind() {
+                Some(kind) => packed_size(repo, kind, hash)?,
+                None => None,
+            };
+            packed.ok_or(Error::ObjectNotFound(*hash))
+        }
+        Err(e) => Err(Error::Io { path, source: e }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::commit::commit;
+    use crate::transport::local::list_all_objects;
+    use crate::transport::{pull_local, PullOptions};
+    use tempfile::tempdir;
+
+    fn test_repo() -> (tempfile::TempDir, Repo) {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        let repo = Repo::init(&repo_path).unwrap();
+        (dir, repo)
+    }
+
+    #[test]
+    fn test_manifest_matches_pull() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a.txt"), "a").unwrap();
+        fs::write(source.join("sub/b.txt"), "b").unwrap();
+        std::os::unix::fs::symlink("a.txt", source.join("link")).unwrap();
+        commit(&repo, &source, "main", None, None).unwrap();
+        fs::write(source.join("a.txt"), "a2").unwrap();
+        commit(&repo, &source, "main", None, None).unwrap();
+
+        // unrelated objects stay out
+        let other = dir.path().join("other");
+        fs::create_dir(&other).unwrap();
+        fs::write(other.join("c.txt"), "c").unwrap();
+        commit(&repo, &other, "other", None, None).unwrap();
+
+        let first = manifest(&repo, "main").unwrap();
+        let second = manifest(&repo, "main").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first.manifest_hash(), second.manifest_hash());
+
+        // exactly what a pull into an empty repo copies
+        let dst = Repo::init(&dir.path().join("dst")).unwrap();
+        pull_local(&repo, &dst, "main", &PullOptions::default()).unwrap();
+        let pulled = list_all_objects(&dst).unwrap();
+        let mut expected: Vec<_> = [
+            (ObjectType::Blob, pulled.blobs),
+            (ObjectType::Tree, pulled.trees),
+            (ObjectType::Commit, pulled.commits),
+            (ObjectType::Content, pulled.contents),
+        ]
+        .into_iter()
+        .flat_map(|(t, hashes)| hashes.into_iter().map(move |h| (t, h)))
+        .collect();
+        expected.sort();
+        let listed: Vec<_> = first
+            .entries
+            .iter()
+            .map(|e| (e.object_type, e.hash))
+            .collect();
+        assert_eq!(listed, expected);
+
+        // the pulled copy yields the same manifest
+        assert_eq!(manifest(&dst, "main").unwrap(), first);
+
+        let mut changed = first.clone();
+        changed.entries[0].size += 1;
+        assert_ne!(changed.manifest_hash(), first.manifest_hash());
+    }
+
+    #[test]
+    fn test_manifest_of_packed_objects() {
+        use crate::object::pack::tests::pack_loose;
+        use crate::ops::stats;
+        use crate::transport::local::count_all_objects;
+
+        let (dir, repo) = test_repo();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/a.txt"), "a").unwrap();
+        commit(&repo, &source, "main", None, None).unwrap();
+
+        let before = manifest(&repo, "main").unwrap();
+        let objects = list_all_objects(&repo).unwrap();
+        let counts = count_all_objects(&repo).unwrap();
+        let repo_stats = stats(&repo).unwrap();
+
+        pack_loose(&repo);
+        let repo = Repo::open(repo.path()).unwrap();
+
+        // sizes come from the pack index, which records the same bytes
+        assert_eq!(manifest(&repo, "main").unwrap(), before);
+        let mut packed = list_all_objects(&repo).unwrap();
+        packed.trees.sort();
+        let mut trees = objects.trees;
+        trees.sort();
+        assert_eq!(packed.trees, trees);
+        assert_eq!(packed.commits, objects.commits);
+        assert_eq!(count_all_objects(&repo).unwrap(), counts);
+        let packed_stats = stats(&repo).unwrap();
+        assert_eq!(packed_stats.total_trees, repo_stats.total_trees);
+        assert_eq!(packed_stats.total_trees_bytes, repo_stats.total_trees_bytes);
+        assert_eq!(
+            packed_stats.total_commits_bytes,
+            repo_stats.total_commits_bytes
+        );
+    }
+}
@@ -1,29 +1,68 @@
 //! high-level operations on zub repositories
 
+mod ancestry;
+mod cat;
 mod checkout;
 mod commit;
+mod dedup;
+mod dictionary;
 mod diff;
 mod export;
 mod fsck;
 mod gc;
 mod log;
 mod ls_tree;
+mod maintenance;
+mod manifest;
 mod map;
 mod stats;
+mod sync;
+mod tree_view;
 mod truncate;
 mod union;
 mod union_checkout;
+mod verify;
 
-pub use checkout::{checkout, checkout_from_tree_hash, CheckoutOptions};
-pub use commit::{commit, commit_with_metadata};
-pub use diff::{diff, diff_trees};
-pub use export::{export_path, ExportOptions};
-pub use fsck::{fsck, CorruptObject, FsckReport, MissingObject, ObjectType};
-pub use gc::{gc, GcStats};
-pub use log::{log, LogEntry};
-pub use ls_tree::{ls_tree, ls_tree_recursive, LsTreeEntry, LsTreeOptions};
+pub use ancestry::{is_ancestor, merge_base, next_generation};
+pub use cat::{cat_blob, cat_file};
+pub use checkout::{
+    checkout, checkout_from_tree_hash, verify_checkout, CheckoutDiscrepancy, CheckoutOptions,
+    CheckoutStats, DiscrepancyKind, CHECKOUT_PROGRESS_FILE,
+};
+pub use commit::{
+    commit, commit_paths, commit_with_changelist, commit_with_metadata, commit_with_options,
+    commit_with_report, repo_within_source, snapshot_tree, snapshot_tree_with_options,
+    CommitOptions, CommitReport, CommitValidator, DanglingSymlinkPolicy, DerefMode, PrefixDirMeta,
+    UnreadablePolicy, UnsupportedXattrPolicy,
+};
+pub use dedup::{analyze_dedup, DedupReport, DuplicateContent};
+pub use dictionary::train_dictionary;
+pub use diff::{
+    diff, diff_ref_worktree, diff_trees, diff_trees_with_options, diff_with_options, DiffOptions,
+};
+pub use export::{export_path, export_squashfs, ExportOptions, SquashfsOptions};
+pub use fsck::{
+    fsck, fsck_ref, read_scrub_cursor, scrub, CorruptObject, FsckReport, MissingObject, ObjectType,
+    ScrubCursor, ScrubOptions, ScrubProgress, ScrubReport,
+};
+pub use gc::{gc, gc_with_options, GcOptions, GcStats, DEFAULT_KEEP_DELETED_REFS_FOR};
+pub use log::{log, log_range, log_range_with_options, log_with_options, LogEntry, LogOptions};
+pub use ls_tree::{
+    classify, format_total, format_tree_pretty, ls_tree, ls_tree_recursive, total_size, FileClass,
+    LsTreeEntry, LsTreeOptions,
+};
+pub use maintenance::{maintenance, MaintenanceOptions, MaintenanceReport};
+pub use manifest::{manifest, Manifest, ManifestEntry};
 pub use map::{map, MapOptions, MapStats};
 pub use stats::{du, du_tree, PathSize, RefSize, RepoStats, stats};
+pub use sync::{sync, SyncOptions, SyncStats};
+pub use tree_view::TreeView;
 pub use truncate::{truncate_history, TruncateStats};
-pub use union::{union as union_trees, ConflictResolution, UnionOptions};
-pub use union_checkout::{checkout_union as union_checkout, UnionCheckoutOptions};
+pub use union::{
+    find_conflicts, union as union_trees, union_trees_by_hash, ConflictInfo, ConflictKind,
+    ConflictResolution, UnionOptions,
+};
+pub use union_checkout::{
+    checkout_union as union_checkout, OriginMap, UnionCheckoutOptions, UnionCheckoutResult,
+};
+pub use verify::verify_ref;
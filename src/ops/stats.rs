@@ -7,9 +7,14 @@ use walkdir::WalkDir;
 
 use crate::error::Result;
 use crate::hash::Hash;
-use crate::object::{read_commit, read_tree};
+use crate::object::blob::stored_size;
+use crate::object::{
+    blob_file_hash, blob_path, list_packed_sizes, read_commit, read_tree, PackedKind,
+};
+use crate::ops::ObjectType;
 use crate::refs::{list_refs, list_refs_matching, read_ref};
 use crate::repo::Repo;
+use crate::transport::local::iter_objects;
 use crate::types::EntryKind;
 
 /// repository statistics
@@ -19,7 +24,10 @@ pub struct RepoStats {
     pub total_trees: usize,
     pub total_commits: usize,
     pub total_refs: usize,
+    /// logical size of all blob data, content objects included
     pub total_blobs_bytes: u64,
+    /// space blob files take on disk, zero blocks stored as holes excluded
+    pub total_blobs_stored_bytes: u64,
     pub total_trees_bytes: u64,
     pub total_commits_bytes: u64,
     pub reachable_blobs: usize,
@@ -36,12 +44,15 @@ pub fn stats(repo: &Repo) -> Result<RepoStats> {
     s.total_refs = list_refs(repo)?.len();
 
     // count and measure objects on disk
-    let (blobs, blob_bytes) = count_objects(&repo.blobs_path());
-    let (trees, tree_bytes) = count_objects(&repo.trees_path());
-    let (commits, commit_bytes) = count_objects(&repo.commits_path());
+    let (blobs, blob_bytes, blob_stored_bytes) = count_objects(&repo.blobs_path());
+    // content-addressed blobs are empty stubs; their data is a content object
+    let (_, content_bytes, content_stored_bytes) = count_objects(&repo.content_path());
+    let (trees, tree_bytes) = count_with_packed(repo, PackedKind::Tree)?;
+    let (commits, commit_bytes) = count_with_packed(repo, PackedKind::Commit)?;
 
     s.total_blobs = blobs;
-    s.total_blobs_bytes = blob_bytes;
+    s.total_blobs_bytes = blob_bytes + content_bytes;
+    s.total_blobs_stored_bytes = blob_stored_bytes + content_stored_bytes;
     s.total_trees = trees;
     s.total_trees_bytes = tree_bytes;
     s.total_commits = commits;
@@ -68,18 +79,20 @@ pub fn stats(repo: &Repo) -> Result<RepoStats> {
     s.reachable_commits = reachable_commits.len();
 
     // calculate unreachable blob bytes
-    s.unreachable_blobs_bytes = calculate_unreachable_bytes(&repo.blobs_path(), &reachable_blobs);
+    s.unreachable_blobs_bytes = calculate_unreachable_bytes(repo, &reachable_blobs)?;
 
     Ok(s)
 }
 
-fn count_objects(dir: &std::path::Path) -> (usize, u64) {
+/// object count, logical bytes and bytes stored on disk
+fn count_objects(dir: &std::path::Path) -> (usize, u64, u64) {
     if !dir.exists() {
-        return (0, 0);
+        return (0, 0, 0);
     }
 
     let mut count = 0;
     let mut bytes = 0;
+    let mut stored = 0;
 
     for entry in WalkDir::new(dir).min_depth(2).max_depth(2) {
         if let Ok(entry) = entry {
@@ -87,47 +100,37 @@ fn count_objects(dir: &std::path::Path) -> (usize, u64) {
                 count += 1;
                 if let Ok(meta) = fs::metadata(entry.path()) {
                     bytes += meta.len();
+                    stored += stored_size(&meta);
                 }
             }
         }
     }
 
-    (count, bytes)
+    (count, bytes, stored)
 }
 
-fn calculate_unreachable_bytes(dir: &std::path::Path, reachable: &HashSet<Hash>) -> u64 {
-    if !dir.exists() {
-        return 0;
-    }
-
-    let mut bytes = 0;
-
-    for entry in WalkDir::new(dir).min_depth(2).max_depth(2) {
-        if let Ok(entry) = entry {
-            if !entry.file_type().is_file() {
-                continue;
-            }
-
-            let path = entry.path();
-            let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-            let parent_name = path
-                .parent()
-                .and_then(|p| p.file_name())
-                .and_then(|n| n.to_str())
-                .unwrap_or("");
-
-            let hex = format!("{}{}", parent_name, file_name);
-            if let Ok(hash) = Hash::from_hex(&hex) {
-                if !reachable.contains(&hash) {
-                    if let Ok(meta) = fs::metadata(path) {
-                        bytes += meta.len();
-                    }
-                }
-            }
+/// object count and stored bytes of a packable type, loose and packed
+fn count_with_packed(repo: &Repo, kind: PackedKind) -> Result<(usize, u64)> {
+    let dir = match kind {
+        PackedKind::Tree => repo.trees_path(),
+        PackedKind::Commit => repo.commits_path(),
+    };
+    let (mut count, mut bytes, _) = count_objects(&dir);
+    for (hash, size) in list_packed_sizes(repo, kind)? {
+        if !kind.loose_path(repo, &hash).exists() {
+            count += 1;
+            bytes += size;
         }
     }
+    Ok((count, bytes))
+}
 
-    bytes
+fn calculate_unreachable_bytes(repo: &Repo, reachable: &HashSet<Hash>) -> Result<u64> {
+    Ok(iter_objects(repo, ObjectType::Blob)?
+        .filter(|hash| !reachable.contains(hash))
+        .filter_map(|hash| fs::metadata(blob_path(repo, &hash)).ok())
+        .map(|meta| meta.len())
+        .sum())
 }
 
 /// recursively mark a commit and all its reachable objects
@@ -175,8 +178,8 @@ fn mark_tree(
 
     for entry in tree.entries() {
         match &entry.kind {
-            EntryKind::Regular { hash, .. } => {
-                reachable_blobs.insert(*hash);
+            EntryKind::Regular { hash, content, .. } => {
+                reachable_blobs.insert(blob_file_hash(hash, content.as_ref()));
             }
             EntryKind::Symlink { hash, .. } => {
                 reachable_blobs.insert(*hash);
@@ -270,8 +273,8 @@ fn collect_tree_blobs(repo: &Repo, tree_hash: &Hash, blobs: &mut HashSet<Hash>)
 
     for entry in tree.entries() {
         match &entry.kind {
-            EntryKind::Regular { hash, .. } => {
-                blobs.insert(*hash);
+            EntryKind::Regular { hash, content, .. } => {
+                blobs.insert(blob_file_hash(hash, content.as_ref()));
             }
             EntryKind::Symlink { hash, .. } => {
                 blobs.insert(*hash);
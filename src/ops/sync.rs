@@ -0,0 +1,377 @@
+//! update an existing checkout in place, touching only what changed
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::diagnostics::{sink_or_stderr, Diagnostics};
+use crate::error::{Error, IoResultExt, Result};
+use crate::fs::{apply_metadata_graceful, create_hardlink, CheckoutHardlinkTracker};
+use crate::object::{read_commit, read_tree};
+use crate::ops::checkout::{
+    checkout_regular_file, checkout_special, checkout_symlink, entry_matches, make_dir_writable,
+    owner_mode_matches, remove_path, CheckoutOptions, PendingDirMetadata,
+};
+use crate::refs::resolve_ref;
+use crate::repo::Repo;
+use crate::types::{EntryKind, Tree};
+
+/// sync options
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    /// remove files on disk that are not in the tree
+    pub delete: bool,
+    /// use hardlinks for new or changed files when possible (default: true)
+    pub hardlink: bool,
+    /// preserve sparse file holes
+    pub preserve_sparse: bool,
+    /// where skipped device nodes, sockets and xattrs are reported;
+    /// stderr when `None`
+    pub diagnostics: Option<Arc<dyn Diagnostics>>,
+}
+
+impl Default for SyncOptions {
+    fn default() -> Self {
+        Self {
+            delete: false,
+            hardlink: true,
+            preserve_sparse: false,
+            diagnostics: None,
+        }
+    }
+}
+
+/// sync statistics
+#[derive(Debug, Default)]
+pub struct SyncStats {
+    pub created: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub deleted: usize,
+}
+
+/// bring `target` in line with a ref's tree, rewriting only entries that differ
+///
+/// unchanged files keep their inode and mtime. entries on disk but not in the
+/// tree are left alone unless `opts.delete` is set.
+pub fn sync(repo: &Repo, ref_name: &str, target: &Path, opts: SyncOptions) -> Result<SyncStats> {
+    let commit_hash = resolve_ref(repo, ref_name)?;
+    let commit = read_commit(repo, &commit_hash)?;
+    let tree = read_tree(repo, &commit.tree)?;
+
+    let checkout_opts = CheckoutOptions {
+        force: true,
+        hardlink: opts.hardlink,
+        preserve_sparse: opts.preserve_sparse,
+        diagnostics: opts.diagnostics.clone(),
+        ..Default::default()
+    };
+
+    let mut state = SyncState {
+        repo,
+        opts: &opts,
+        checkout_opts: &checkout_opts,
+        stats: SyncStats::default(),
+        hardlinks: CheckoutHardlinkTracker::new(),
+        pending_hardlinks: Vec::new(),
+        pending_dirs: Vec::new(),
+    };
+    state.sync_tree(&tree, target, "")?;
+
+    // hardlinks last, once every target is in place
+    for (entry_path, target_path) in std::mem::take(&mut state.pending_hardlinks) {
+        let target_fs_path = state
+            .hardlinks
+            .get(&target_path)
+            .ok_or_else(|| Error::HardlinkTargetNotFound(target_path.clone()))?
+            .to_path_buf();
+        let target_meta = fs::symlink_metadata(&target_fs_path).with_path(&target_fs_path)?;
+
+        match fs::symlink_metadata(&entry_path) {
+            Ok(meta) if meta.dev() == target_meta.dev() && meta.ino() == target_meta.ino() => {
+                state.stats.unchanged += 1;
+                continue;
+            }
+            Ok(meta) => {
+                remove_path(&entry_path, &meta)?;
+                state.stats.updated += 1;
+            }
+            Err(_) => state.stats.created += 1,
+        }
+        create_hardlink(&entry_path, &target_fs_path)?;
+    }
+
+    // directory metadata goes on only now, deepest first, so a read-only
+    // directory can't lock out the hardlinks placed into it above
+    let diag = sink_or_stderr(&opts.diagnostics);
+    for dir in state.pending_dirs {
+        let meta = fs::symlink_metadata(&dir.path).with_path(&dir.path)?;
+        if !owner_mode_matches(&meta, dir.uid, dir.gid, dir.mode) || !dir.xattrs.is_empty() {
+            apply_metadata_graceful(&dir.path, dir.uid, dir.gid, dir.mode, &dir.xattrs, diag)?;
+        }
+    }
+
+    Ok(state.stats)
+}
+
+struct SyncState<'a> {
+    repo: &'a Repo,
+    opts: &'a SyncOptions,
+    checkout_opts: &'a CheckoutOptions,
+    stats: SyncStats,
+    hardlinks: CheckoutHardlinkTracker,
+    pending_hardlinks: Vec<(PathBuf, String)>,
+    pending_dirs: Vec<PendingDirMetadata>,
+}
+
+impl SyncState<'_> {
+    fn sync_tree(&mut self, tree: &Tree, dir: &Path, prefix: &str) -> Result<()> {
+        fs::create_dir_all(dir).with_path(dir)?;
+
+        for entry in tree.entries() {
+            let entry_path = dir.join(&entry.name);
+            let logical_path = if prefix.is_empty() {
+                entry.name.clone()
+            } else {
+                format!("{}/{}", prefix, entry.name)
+            };
+            let existing = fs::symlink_metadata(&entry_path).ok();
+
+            if let EntryKind::Hardlink { target_path } = &entry.kind {
+                self.pending_hardlinks
+                    .push((entry_path, target_path.clone()));
+                continue;
+            }
+
+            if let EntryKind::Directory {
+                hash,
+                uid,
+                gid,
+                mode,
+                xattrs,
+                ..
+            } = &entry.kind
+            {
+                match &existing {
+                    // a read-only directory has to take new entries; its
+                    // mode is reapplied afterwards anyway
+                    Some(meta) if meta.is_dir() => make_dir_writable(&entry_path)?,
+                    Some(meta) => {
+                        remove_path(&entry_path, meta)?;
+                        self.stats.updated += 1;
+                    }
+                    None => self.stats.created += 1,
+                }
+
+                let subtree = read_tree(self.repo, hash)?;
+                self.sync_tree(&subtree, &entry_path, &logical_path)?;
+
+                self.pending_dirs.push(PendingDirMetadata {
+                    path: entry_path,
+                    logical_path,
+                    uid: *uid,
+                    gid: *gid,
+                    mode: *mode,
+                    xattrs: xattrs.clone(),
+                });
+                continue;
+            }
+
+            if let Some(meta) = &existing {
+                if entry_matches(self.repo, &entry_path, meta, &entry.kind, None)? {
+                    self.stats.unchanged += 1;
+                    self.hardlinks.record(&logical_path, entry_path);
+                    continue;
+                }
+                remove_path(&entry_path, meta)?;
+                self.stats.updated += 1;
+            } else {
+                self.stats.created += 1;
+            }
+
+            match &entry.kind {
+                EntryKind::Regular {
+                    hash,
+                    size,
+                    sparse_map,
+                    xattrs,
+                    content,
+                    ..
+                } => {
+                    checkout_regular_file(
+                        self.repo,
+                        &entry_path,
+                        hash,
+                        *size,
+                        content.as_ref(),
+                        sparse_map.as_deref(),
+                        xattrs,
+                        entry.kind.times(),
+                        self.checkout_opts,
+                    )?;
+                }
+                EntryKind::Symlink { hash, xattrs, .. } => {
+                    let diag = sink_or_stderr(&self.opts.diagnostics);
+                    checkout_symlink(self.repo, &entry_path, hash, xattrs, None, diag)?
+                }
+                other => {
+                    checkout_special(&entry_path, other, sink_or_stderr(&self.opts.diagnostics))?
+                }
+            }
+            self.hardlinks.record(&logical_path, entry_path);
+        }
+
+        if self.opts.delete {
+            for dir_entry in fs::read_dir(dir).with_path(dir)? {
+                let dir_entry = dir_entry.with_path(dir)?;
+                let name = dir_entry.file_name().to_string_lossy().to_string();
+                if tree.get(&name).is_none() {
+                    let path = dir_entry.path();
+                    let meta = fs::symlink_metadata(&path).with_path(&path)?;
+                    remove_path(&path, &meta)?;
+                    self.stats.deleted += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::checkout::checkout;
+    use crate::ops::commit::commit;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn test_repo() -> (tempfile::TempDir, Repo) {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        let repo = Repo::init(&repo_path).unwrap();
+        (dir, repo)
+    }
+
+    #[test]
+    fn test_sync_only_rewrites_changed() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("same.txt"), "unchanged").unwrap();
+        fs::write(source.join("sub/nested.txt"), "nested").unwrap();
+        fs::write(source.join("changed.txt"), "old").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        let target = dir.path().join("target");
+        let copy = CheckoutOptions {
+            hardlink: false,
+            ..Default::default()
+        };
+        checkout(&repo, "test", &target, copy).unwrap();
+
+        let same_before = fs::metadata(target.join("same.txt")).unwrap();
+        let nested_before = fs::metadata(target.join("sub/nested.txt")).unwrap();
+        let changed_before = fs::metadata(target.join("changed.txt")).unwrap();
+
+        // make sure a rewrite would get a different mtime
+        std::thread::sleep(Duration::from_millis(20));
+
+        fs::write(source.join("changed.txt"), "new content").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        let opts = SyncOptions {
+            hardlink: false,
+            ..Default::default()
+        };
+        let stats = sync(&repo, "test", &target, opts).unwrap();
+        assert_eq!(stats.updated, 1);
+        assert_eq!(stats.unchanged, 2);
+        assert_eq!(stats.created, 0);
+
+        let same_after = fs::metadata(target.join("same.txt")).unwrap();
+        assert_eq!(same_after.ino(), same_before.ino());
+        assert_eq!(same_after.mtime_nsec(), same_before.mtime_nsec());
+        let nested_after = fs::metadata(target.join("sub/nested.txt")).unwrap();
+        assert_eq!(nested_after.ino(), nested_before.ino());
+        assert_eq!(nested_after.mtime_nsec(), nested_before.mtime_nsec());
+
+        let changed_after = fs::metadata(target.join("changed.txt")).unwrap();
+        assert_ne!(
+            (changed_after.ino(), changed_after.mtime_nsec()),
+            (changed_before.ino(), changed_before.mtime_nsec())
+        );
+        assert_eq!(
+            fs::read_to_string(target.join("changed.txt")).unwrap(),
+            "new content"
+        );
+    }
+
+    #[test]
+    fn test_sync_delete_is_opt_in() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        let target = dir.path().join("target");
+        fs::create_dir_all(target.join("extra_dir")).unwrap();
+        fs::write(target.join("extra.txt"), "extra").unwrap();
+
+        let stats = sync(&repo, "test", &target, SyncOptions::default()).unwrap();
+        assert_eq!(stats.created, 1);
+        assert_eq!(stats.deleted, 0);
+        assert!(target.join("extra.txt").exists());
+
+        let opts = SyncOptions {
+            delete: true,
+            ..Default::default()
+        };
+        let stats = sync(&repo, "test", &target, opts).unwrap();
+        assert_eq!(stats.unchanged, 1);
+        assert_eq!(stats.deleted, 2);
+        assert!(!target.join("extra.txt").exists());
+        assert!(!target.join("extra_dir").exists());
+        assert_eq!(
+            fs::read_to_string(target.join("file.txt")).unwrap(),
+            "content"
+        );
+    }
+
+    #[test]
+    fn test_sync_read_only_dir_with_hardlinks() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("ro")).unwrap();
+        fs::write(source.join("ro/a.txt"), "shared").unwrap();
+        fs::hard_link(source.join("ro/a.txt"), source.join("ro/b.txt")).unwrap();
+        fs::set_permissions(source.join("ro"), fs::Permissions::from_mode(0o555)).unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        let target = dir.path().join("target");
+        sync(&repo, "test", &target, SyncOptions::default()).unwrap();
+
+        let meta = fs::metadata(target.join("ro")).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o555);
+        let a = fs::metadata(target.join("ro/a.txt")).unwrap();
+        let b = fs::metadata(target.join("ro/b.txt")).unwrap();
+        assert_eq!(a.ino(), b.ino());
+
+        // syncing again restores the link inside the read-only directory
+        fs::set_permissions(target.join("ro"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_file(target.join("ro/b.txt")).unwrap();
+        fs::set_permissions(target.join("ro"), fs::Permissions::from_mode(0o555)).unwrap();
+        let stats = sync(&repo, "test", &target, SyncOptions::default()).unwrap();
+        assert_eq!(stats.created, 1);
+        let b = fs::metadata(target.join("ro/b.txt")).unwrap();
+        assert_eq!(a.ino(), b.ino());
+        let meta = fs::metadata(target.join("ro")).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o555);
+    }
+}
@@ -0,0 +1,263 @@
+//! read-only random access into a committed tree
+
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use crate::error::{Error, Result};
+use crate::hash::Hash;
+use crate::object::{read_blob_data, read_commit, read_tree_shared, Lru};
+use crate::ops::checkout::expand_sparse;
+use crate::refs::resolve_ref;
+use crate::repo::Repo;
+use crate::types::{EntryKind, Tree, TreeEntry};
+
+/// default number of subtrees kept in memory
+const DEFAULT_CACHE_CAPACITY: usize = 256;
+
+/// a committed tree opened for reading without checking it out
+///
+/// subtrees are read lazily as paths are accessed and kept in a small LRU
+/// cache, so deep traversals don't hold the whole tree in memory.
+pub struct TreeView<'a> {
+    repo: &'a Repo,
+    commit: Hash,
+    root: Hash,
+    cache: RefCell<Lru<Arc<Tree>>>,
+}
+
+impl<'a> TreeView<'a> {
+    /// open the tree of the commit a ref points to
+    pub fn open(repo: &'a Repo, ref_name: &str) -> Result<Self> {
+        let commit = resolve_ref(repo, ref_name)?;
+        let root = read_commit(repo, &commit)?.tree;
+        Ok(Self {
+            repo,
+            commit,
+            root,
+            cache: RefCell::new(Lru::new(DEFAULT_CACHE_CAPACITY)),
+        })
+    }
+
+    /// set how many subtrees are cached (at least one)
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache = RefCell::new(Lru::new(capacity));
+        self
+    }
+
+    /// commit this view was opened at
+    pub fn commit(&self) -> Hash {
+        self.commit
+    }
+
+    /// root tree hash
+    pub fn root(&self) -> Hash {
+        self.root
+    }
+
+    /// tree entry at `path`
+    pub fn metadata(&self, path: &str) -> Result<TreeEntry> {
+        self.lookup_path(path)
+    }
+
+    /// entries of the directory at `path` ("" or "/" for the root)
+    pub fn read_dir(&self, path: &str) -> Result<Vec<TreeEntry>> {
+        let tree_hash = if is_root(path) {
+            self.root
+        } else {
+            match self.lookup_path(path)?.kind {
+                EntryKind::Directory { hash, .. } => hash,
+                other => return Err(Error::InvalidObjectType(other.type_name().to_string())),
+            }
+        };
+        Ok(self.tree(&tree_hash)?.entries().to_vec())
+    }
+
+    /// contents of the regular file at `path`, following hardlinks
+    ///
+    /// sparse files are returned at their full size with holes zero-filled.
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        let mut entry = self.lookup_path(path)?;
+        if let EntryKind::Hardlink { target_path } = &entry.kind {
+            entry = self.lookup_path(target_path)?;
+        }
+
+        match entry.kind {
+            EntryKind::Regular {
+                hash,
+                size,
+                sparse_map,
+                content,
+                ..
+            } => {
+                let data = read_blob_data(self.repo, &hash, content.as_ref())?;
+                match sparse_map {
+                    Some(regions) => expand_sparse(&hash, &data, &regions, size),
+                    None => Ok(data),
+                }
+            }
+            other => Err(Error::InvalidObjectType(other.type_name().to_string())),
+        }
+    }
+
+    /// find the entry at `path`, reading subtrees through the cache
+    fn lookup_path(&self, path: &str) -> Result<TreeEntry> {
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let Some((last, parents)) = components.split_last() else {
+            return Err(Error::PathNotFound(path.to_string()));
+        };
+
+        let mut tree = self.tree(&self.root)?;
+        for component in parents {
+            let next = match tree.get(component).map(|e| &e.kind) {
+                Some(EntryKind::Directory { hash, .. }) => *hash,
+                _ => return Err(Error::PathNotFound(path.to_string())),
+            };
+            tree = self.tree(&next)?;
+        }
+
+        tree.get(last)
+            .cloned()
+            .ok_or_else(|| Error::PathNotFound(path.to_string()))
+    }
+
+    fn tree(&self, hash: &Hash) -> Result<Arc<Tree>> {
+        if let Some(tree) = self.cache.borrow_mut().get(hash) {
+            return Ok(tree);
+        }
+        let tree = read_tree_shared(self.repo, hash)?;
+        self.cache.borrow_mut().insert(*hash, tree.clone());
+        Ok(tree)
+    }
+}
+
+fn is_root(path: &str) -> bool {
+    path.split('/').all(|c| c.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::commit::commit;
+    use std::fs;
+    use tempfile::tempdir;
+
+    fn test_repo() -> (tempfile::TempDir, Repo) {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        let repo = Repo::init(&repo_path).unwrap();
+        (dir, repo)
+    }
+
+    fn nested_source(dir: &std::path::Path) -> std::path::PathBuf {
+        let source = dir.join("source");
+        fs::create_dir_all(source.join("a/b/c")).unwrap();
+        fs::write(source.join("top.txt"), "top").unwrap();
+        fs::write(source.join("a/b/one.txt"), "one").unwrap();
+        fs::write(source.join("a/b/c/deep.txt"), "deep").unwrap();
+        source
+    }
+
+    #[test]
+    fn test_read_nested_file() {
+        let (dir, repo) = test_repo();
+        commit(&repo, &nested_source(dir.path()), "test", None, None).unwrap();
+
+        let view = TreeView::open(&repo, "test").unwrap();
+        assert_eq!(view.read_file("a/b/c/deep.txt").unwrap(), b"deep");
+        assert_eq!(view.read_file("/top.txt").unwrap(), b"top");
+
+        let meta = view.metadata("a/b/one.txt").unwrap();
+        assert!(matches!(meta.kind, EntryKind::Regular { size: 3, .. }));
+    }
+
+    #[test]
+    fn test_read_dir() {
+        let (dir, repo) = test_repo();
+        commit(&repo, &nested_source(dir.path()), "test", None, None).unwrap();
+
+        // a cache of one forces evictions on every level
+        let view = TreeView::open(&repo, "test")
+            .unwrap()
+            .with_cache_capacity(1);
+
+        let names: Vec<String> = view
+            .read_dir("a/b")
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, vec!["c", "one.txt"]);
+
+        assert_eq!(view.read_dir("").unwrap().len(), 2);
+        assert_eq!(view.read_file("a/b/c/deep.txt").unwrap(), b"deep");
+    }
+
+    #[test]
+    fn test_missing_path() {
+        let (dir, repo) = test_repo();
+        commit(&repo, &nested_source(dir.path()), "test", None, None).unwrap();
+
+        let view = TreeView::open(&repo, "test").unwrap();
+        assert!(matches!(
+            view.read_file("a/missing.txt"),
+            Err(Error::PathNotFound(p)) if p == "a/missing.txt"
+        ));
+        // a file can't be traversed into
+        assert!(matches!(
+            view.metadata("top.txt/x"),
+            Err(Error::PathNotFound(_))
+        ));
+        assert!(matches!(
+            view.read_dir("top.txt"),
+            Err(Error::InvalidObjectType(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_file_malformed_sparse_map() {
+        use crate::object::{write_blob, write_commit, write_tree};
+        use crate::types::{Commit, SparseRegion};
+
+        let (_dir, repo) = test_repo();
+        let uid = nix::unistd::getuid().as_raw();
+        let gid = nix::unistd::getgid().as_raw();
+        let blob = write_blob(&repo, b"data", uid, gid, 0o100644, &[]).unwrap();
+        let sparse = |name: &str, size, regions| {
+            TreeEntry::new(
+                name,
+                EntryKind::Regular {
+                    hash: blob,
+                    size,
+                    sparse_map: Some(regions),
+                    xattrs: vec![],
+                    content: None,
+                    times: None,
+                },
+            )
+        };
+        let entries = vec![
+            sparse("fine", 16, vec![SparseRegion::new(8, 4)]),
+            // more data than the blob holds
+            sparse("short", 16, vec![SparseRegion::new(0, 8)]),
+            // a region past the logical size
+            sparse("past", 16, vec![SparseRegion::new(14, 4)]),
+            // a region whose end overflows
+            sparse("overflow", 16, vec![SparseRegion::new(u64::MAX - 1, 4)]),
+        ];
+        let tree = write_tree(&repo, &Tree::new(entries).unwrap()).unwrap();
+        let commit = write_commit(&repo, &Commit::new(tree, vec![], "test", "sparse")).unwrap();
+        crate::refs::write_ref(&repo, "sparse", &commit).unwrap();
+
+        let view = TreeView::open(&repo, "sparse").unwrap();
+        let mut expected = vec![0u8; 16];
+        expected[8..12].copy_from_slice(b"data");
+        assert_eq!(view.read_file("fine").unwrap(), expected);
+        for name in ["short", "past", "overflow"] {
+            assert!(
+                matches!(view.read_file(name), Err(Error::CorruptObject(h)) if h == blob),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -45,6 +45,8 @@ pub fn truncate_history(repo: &Repo, dry_run: bool) -> Result<TruncateStats> {
             author: commit.author,
             timestamp: commit.timestamp,
             metadata: commit.metadata,
+            ns_fingerprint: commit.ns_fingerprint,
+            generation: Some(0),
         };
 
         let new_hash = write_commit(repo, &new_commit)?;
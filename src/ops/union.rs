@@ -1,6 +1,9 @@
+use std::path::{Path, PathBuf};
+
 use crate::error::{Error, Result};
 use crate::hash::Hash;
 use crate::object::{read_commit, read_tree, write_commit, write_tree};
+use crate::ops::ancestry::next_generation;
 use crate::refs::{resolve_ref, write_ref};
 use crate::repo::Repo;
 use crate::types::{Commit, EntryKind, Tree, TreeEntry};
@@ -25,6 +28,26 @@ pub struct UnionOptions {
     pub on_conflict: ConflictResolution,
 }
 
+/// how the entries at a conflicting path disagree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// non-directory entries with different content or metadata
+    File,
+    /// directory on one side, non-directory on another (never resolvable)
+    Type,
+}
+
+/// a path where two or more refs disagree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictInfo {
+    pub path: PathBuf,
+    pub kind: ConflictKind,
+    /// refs that have an entry at this path, in input order
+    pub refs: Vec<String>,
+    /// entry type for each ref in `refs`
+    pub types: Vec<&'static str>,
+}
+
 /// merge multiple refs into a new commit in the object store
 ///
 /// this operation does NOT touch the filesystem - it merges trees directly
@@ -35,30 +58,31 @@ pub fn union(repo: &Repo, refs: &[&str], output_ref: &str, opts: UnionOptions) -
     }
 
     // resolve all refs to their root trees
-    let mut trees = Vec::new();
+    let mut tree_hashes = Vec::new();
     let mut parent_commits = Vec::new();
 
     for ref_name in refs {
         let commit_hash = resolve_ref(repo, ref_name)?;
         parent_commits.push(commit_hash);
-
-        let commit = read_commit(repo, &commit_hash)?;
-        let tree = read_tree(repo, &commit.tree)?;
-        trees.push(tree);
+        tree_hashes.push(read_commit(repo, &commit_hash)?.tree);
     }
 
     // merge trees
-    let merged_tree = merge_trees(repo, &trees, opts.on_conflict)?;
-    let tree_hash = write_tree(repo, &merged_tree)?;
+    let tree_hash = union_trees_by_hash(repo, &tree_hashes, &opts)?;
 
     // create commit
-    let commit = Commit::new(
+    let generation = next_generation(repo, &parent_commits)?;
+    let mut commit = Commit::new(
         tree_hash,
         parent_commits,
         opts.author.as_deref().unwrap_or("zub"),
         opts.message.as_deref().unwrap_or(""),
     );
 
+    if let Some(generation) = generation {
+        commit = commit.with_generation(generation);
+    }
+
     let commit_hash = write_commit(repo, &commit)?;
 
     // update ref
@@ -67,8 +91,110 @@ pub fn union(repo: &Repo, refs: &[&str], output_ref: &str, opts: UnionOptions) -
     Ok(commit_hash)
 }
 
-/// merge multiple trees into one
-fn merge_trees(repo: &Repo, trees: &[Tree], on_conflict: ConflictResolution) -> Result<Tree> {
+/// merge trees given by hash and write the result, returning its hash
+///
+/// no commit or ref is written, so intermediate trees can be composed
+/// without wrapping them in commits. only `opts.on_conflict` is used.
+pub fn union_trees_by_hash(repo: &Repo, tree_hashes: &[Hash], opts: &UnionOptions) -> Result<Hash> {
+    if tree_hashes.is_empty() {
+        return Err(Error::InvalidRef("no trees to union".to_string()));
+    }
+
+    let trees = tree_hashes
+        .iter()
+        .map(|hash| read_tree(repo, hash))
+        .collect::<Result<Vec<_>>>()?;
+
+    let merged_tree = merge_trees(repo, &trees, opts.on_conflict)?;
+    write_tree(repo, &merged_tree)
+}
+
+/// list every path where the given refs disagree, without writing anything
+///
+/// walks the same merge as `union`, but collects conflicts instead of
+/// stopping at the first one. paths with identical entries are not reported.
+pub fn find_conflicts(repo: &Repo, refs: &[&str]) -> Result<Vec<ConflictInfo>> {
+    let mut trees = Vec::new();
+    for ref_name in refs {
+        let commit = read_commit(repo, &resolve_ref(repo, ref_name)?)?;
+        trees.push(read_tree(repo, &commit.tree)?);
+    }
+
+    let ref_names: Vec<String> = refs.iter().map(|r| r.to_string()).collect();
+    let tree_refs: Vec<usize> = (0..trees.len()).collect();
+    let mut conflicts = Vec::new();
+    collect_conflicts(
+        repo,
+        &trees,
+        &tree_refs,
+        &ref_names,
+        Path::new(""),
+        &mut conflicts,
+    )?;
+    Ok(conflicts)
+}
+
+/// recursive half of `find_conflicts`; `tree_refs[i]` is the ref index of `trees[i]`
+fn collect_conflicts(
+    repo: &Repo,
+    trees: &[Tree],
+    tree_refs: &[usize],
+    ref_names: &[String],
+    prefix: &Path,
+    conflicts: &mut Vec<ConflictInfo>,
+) -> Result<()> {
+    for (name, entries) in entries_by_name(trees) {
+        if entries.len() == 1 {
+            continue;
+        }
+
+        let path = prefix.join(&name);
+
+        if entries.iter().all(|(_, e)| e.kind.is_directory()) {
+            let mut subtrees = Vec::new();
+            let mut sub_refs = Vec::new();
+            for (i, entry) in &entries {
+                if let EntryKind::Directory { hash, .. } = &entry.kind {
+                    subtrees.push(read_tree(repo, hash)?);
+                    sub_refs.push(tree_refs[*i]);
+                }
+            }
+            collect_conflicts(repo, &subtrees, &sub_refs, ref_names, &path, conflicts)?;
+            continue;
+        }
+
+        if entries.iter().all(|(_, e)| e.kind == entries[0].1.kind) {
+            continue;
+        }
+
+        let first_is_dir = entries[0].1.kind.is_directory();
+        let kind = if entries
+            .iter()
+            .any(|(_, e)| e.kind.is_directory() != first_is_dir)
+        {
+            ConflictKind::Type
+        } else {
+            ConflictKind::File
+        };
+
+        conflicts.push(ConflictInfo {
+            path,
+            kind,
+            refs: entries
+                .iter()
+                .map(|(i, _)| ref_names[tree_refs[*i]].clone())
+                .collect(),
+            types: entries.iter().map(|(_, e)| e.type_name()).collect(),
+        });
+    }
+
+    Ok(())
+}
+
+/// group entries by name across trees, sorted by name
+///
+/// each group holds `(tree index, entry)` for the trees that have the name.
+fn entries_by_name(trees: &[Tree]) -> Vec<(String, Vec<(usize, &TreeEntry)>)> {
     // collect all entry names across all trees
     let mut all_names: Vec<String> = trees
         .iter()
@@ -77,16 +203,25 @@ fn merge_trees(repo: &Repo, trees: &[Tree], on_conflict: ConflictResolution) ->
     all_names.sort();
     all_names.dedup();
 
-    let mut merged_entries = Vec::new();
+    all_names
+        .into_iter()
+        .map(|name| {
+            // collect entries with this name from each tree
+            let entries: Vec<(usize, &TreeEntry)> = trees
+                .iter()
+                .enumerate()
+                .filter_map(|(i, t)| t.get(&name).map(|e| (i, e)))
+                .collect();
+            (name, entries)
+        })
+        .collect()
+}
 
-    for name in all_names {
-        // collect entries with this name from each tree
-        let entries_for_name: Vec<(usize, &TreeEntry)> = trees
-            .iter()
-            .enumerate()
-            .filter_map(|(i, t)| t.get(&name).map(|e| (i, e)))
-            .collect();
+/// merge multiple trees into one
+fn merge_trees(repo: &Repo, trees: &[Tree], on_conflict: ConflictResolution) -> Result<Tree> {
+    let mut merged_entries = Vec::new();
 
+    for (name, entries_for_name) in entries_by_name(trees) {
         if entries_for_name.len() == 1 {
             // only one tree has this entry, use it
             merged_entries.push(entries_for_name[0].1.clone());
@@ -97,7 +232,7 @@ fn merge_trees(repo: &Repo, trees: &[Tree], on_conflict: ConflictResolution) ->
         }
     }
 
-    Tree::new(merged_entries)
+    Tree::with_policy(merged_entries, &repo.config().entry_name_policy)
 }
 
 /// merge multiple entries with the same name
@@ -122,6 +257,7 @@ fn merge_entries(
                 gid,
                 mode,
                 xattrs,
+                ..
             } = &entry.kind
             {
                 let subtree = read_tree(repo, hash)?;
@@ -147,7 +283,7 @@ fn merge_entries(
         for (_, entry) in entries.iter().skip(1) {
             if entry.kind.is_directory() != first_is_dir {
                 return Err(Error::UnionTypeConflict {
-                    path: std::path::PathBuf::from(name),
+                    path: PathBuf::from(name),
                     first_type: entries[0].1.type_name(),
                     second_type: entry.type_name(),
                 });
@@ -156,7 +292,7 @@ fn merge_entries(
 
         // same type conflict (both files, both symlinks, etc.)
         match on_conflict {
-            ConflictResolution::Error => Err(Error::UnionConflict(std::path::PathBuf::from(name))),
+            ConflictResolution::Error => Err(Error::UnionConflict(PathBuf::from(name))),
             ConflictResolution::First => Ok(entries[0].1.clone()),
             ConflictResolution::Last => Ok(entries[entries.len() - 1].1.clone()),
         }
@@ -204,6 +340,47 @@ mod tests {
         assert!(tree.get("file2.txt").is_some());
     }
 
+    #[test]
+    fn test_union_trees_by_hash() {
+        use crate::object::write_blob;
+
+        let (_dir, repo) = test_repo();
+
+        // standalone trees: dir/a + top, and dir/b
+        let blob = |data: &[u8]| write_blob(&repo, data, 0, 0, 0o100644, &[]).unwrap();
+        let file = |name: &str, data: &[u8]| {
+            TreeEntry::new(
+                name,
+                EntryKind::regular(blob(data), data.len() as u64, vec![]),
+            )
+        };
+        let tree = |entries| write_tree(&repo, &Tree::new(entries).unwrap()).unwrap();
+        let dir = |name: &str, hash| TreeEntry::new(name, EntryKind::directory(hash, 0, 0, 0o755));
+
+        let sub1 = tree(vec![file("a", b"a")]);
+        let tree1 = tree(vec![dir("dir", sub1), file("top", b"top")]);
+        let sub2 = tree(vec![file("b", b"b")]);
+        let tree2 = tree(vec![dir("dir", sub2)]);
+
+        let merged = union_trees_by_hash(&repo, &[tree1, tree2], &Default::default()).unwrap();
+
+        let root = read_tree(&repo, &merged).unwrap();
+        assert!(root.get("top").is_some());
+        let EntryKind::Directory { hash, .. } = &root.get("dir").unwrap().kind else {
+            panic!("dir should stay a directory");
+        };
+        assert_eq!(read_tree(&repo, hash).unwrap().len(), 2);
+
+        // same result as the ref-based union of commits wrapping those trees
+        for (ref_name, tree_hash) in [("ref1", tree1), ("ref2", tree2)] {
+            let commit_hash =
+                write_commit(&repo, &Commit::new(tree_hash, vec![], "test", "")).unwrap();
+            write_ref(&repo, ref_name, &commit_hash).unwrap();
+        }
+        let hash = union(&repo, &["ref1", "ref2"], "merged", Default::default()).unwrap();
+        assert_eq!(read_commit(&repo, &hash).unwrap().tree, merged);
+    }
+
     #[test]
     fn test_union_directory_merge() {
         let (dir, repo) = test_repo();
@@ -343,4 +520,56 @@ mod tests {
         // should have all three as parents
         assert_eq!(commit_obj.parents.len(), 3);
     }
+
+    #[test]
+    fn test_find_conflicts_reports_all() {
+        let (dir, repo) = test_repo();
+
+        let source1 = dir.path().join("source1");
+        fs::create_dir_all(source1.join("sub")).unwrap();
+        fs::write(source1.join("a.txt"), "a1").unwrap();
+        fs::write(source1.join("b.txt"), "b1").unwrap();
+        fs::write(source1.join("sub/c.txt"), "c1").unwrap();
+        fs::write(source1.join("same.txt"), "same").unwrap();
+        fs::write(source1.join("kind"), "file").unwrap();
+        commit(&repo, &source1, "ref1", None, None).unwrap();
+
+        let source2 = dir.path().join("source2");
+        fs::create_dir_all(source2.join("sub")).unwrap();
+        fs::create_dir_all(source2.join("kind")).unwrap();
+        fs::write(source2.join("a.txt"), "a2").unwrap();
+        fs::write(source2.join("b.txt"), "b2").unwrap();
+        fs::write(source2.join("sub/c.txt"), "c2").unwrap();
+        fs::write(source2.join("same.txt"), "same").unwrap();
+        commit(&repo, &source2, "ref2", None, None).unwrap();
+
+        let conflicts = find_conflicts(&repo, &["ref1", "ref2"]).unwrap();
+
+        let files: Vec<_> = conflicts
+            .iter()
+            .filter(|c| c.kind == ConflictKind::File)
+            .map(|c| c.path.clone())
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("a.txt"),
+                PathBuf::from("b.txt"),
+                PathBuf::from("sub/c.txt")
+            ]
+        );
+        assert!(conflicts
+            .iter()
+            .all(|c| c.refs == vec!["ref1".to_string(), "ref2".to_string()]));
+
+        let type_conflict = conflicts
+            .iter()
+            .find(|c| c.kind == ConflictKind::Type)
+            .unwrap();
+        assert_eq!(type_conflict.path, PathBuf::from("kind"));
+        assert_eq!(type_conflict.types, vec!["regular", "directory"]);
+
+        // nothing was written
+        assert!(resolve_ref(&repo, "merged").is_err());
+    }
 }
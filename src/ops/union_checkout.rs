@@ -1,6 +1,9 @@
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::Path;
+use std::sync::Arc;
 
+use crate::diagnostics::{sink_or_stderr, Diagnostics, Warning};
 use crate::error::{Error, IoResultExt, Result};
 use crate::fs::{
     apply_metadata_graceful, create_block_device, create_char_device, create_fifo,
@@ -8,7 +11,9 @@ use crate::fs::{
     CheckoutHardlinkTracker,
 };
 use crate::hash::Hash;
-use crate::object::{blob_path, read_blob, read_commit, read_tree};
+use crate::object::{
+    blob_data_path, blob_meta_path, blob_path, read_blob, read_blob_data, read_commit, read_tree,
+};
 use crate::ops::union::ConflictResolution;
 use crate::refs::resolve_ref;
 use crate::repo::Repo;
@@ -23,6 +28,22 @@ pub struct UnionCheckoutOptions {
     pub on_conflict: ConflictResolution,
     /// use hardlinks when possible
     pub hardlink: bool,
+    /// record which ref each checked-out path came from
+    pub record_origins: bool,
+    /// where skipped device nodes, sockets and xattrs are reported;
+    /// stderr when `None`
+    pub diagnostics: Option<Arc<dyn Diagnostics>>,
+}
+
+/// logical path -> name of the ref whose entry ended up there
+pub type OriginMap = BTreeMap<String, String>;
+
+/// result of a union checkout
+#[derive(Debug, Default)]
+pub struct UnionCheckoutResult {
+    /// winning ref per non-directory path; empty unless `record_origins` is set.
+    /// directories are merged, so they have no single origin.
+    pub origins: OriginMap,
 }
 
 /// pending hardlink for deferred creation
@@ -40,7 +61,7 @@ pub fn checkout_union(
     refs: &[&str],
     target: &Path,
     opts: UnionCheckoutOptions,
-) -> Result<()> {
+) -> Result<UnionCheckoutResult> {
     if refs.is_empty() {
         return Err(Error::InvalidRef("no refs to checkout".to_string()));
     }
@@ -59,6 +80,7 @@ pub fn checkout_union(
 
     let mut hardlink_tracker = CheckoutHardlinkTracker::new();
     let mut pending_hardlinks = Vec::new();
+    let mut result = UnionCheckoutResult::default();
 
     // process each ref in order
     for ref_name in refs {
@@ -74,6 +96,9 @@ pub fn checkout_union(
             opts.on_conflict,
             &mut hardlink_tracker,
             &mut pending_hardlinks,
+            ref_name,
+            opts.record_origins.then_some(&mut result.origins),
+            sink_or_stderr(&opts.diagnostics),
         )?;
     }
 
@@ -86,13 +111,15 @@ pub fn checkout_union(
         create_hardlink(&pending.entry_path, target_fs_path)?;
     }
 
-    Ok(())
+    Ok(result)
 }
 
 /// checkout a tree with union semantics
 ///
 /// hardlinks are collected in pending_hardlinks for deferred creation,
-/// allowing targets in sibling directories to be processed first.
+/// allowing targets in sibling directories to be processed first. entries
+/// that win are attributed to `ref_name` in `origins`, if given.
+#[allow(clippy::too_many_arguments)]
 fn checkout_tree_union(
     repo: &Repo,
     tree: &Tree,
@@ -101,6 +128,9 @@ fn checkout_tree_union(
     on_conflict: ConflictResolution,
     hardlink_tracker: &mut CheckoutHardlinkTracker,
     pending_hardlinks: &mut Vec<PendingHardlink>,
+    ref_name: &str,
+    mut origins: Option<&mut OriginMap>,
+    diag: &dyn Diagnostics,
 ) -> Result<()> {
     fs::create_dir_all(target).with_path(target)?;
 
@@ -138,6 +168,7 @@ fn checkout_tree_union(
                 hash,
                 sparse_map,
                 xattrs,
+                content,
                 ..
             } => {
                 if entry_path.exists() {
@@ -161,11 +192,19 @@ fn checkout_tree_union(
                     }
                 }
 
-                checkout_file(repo, &entry_path, hash, sparse_map.as_deref(), xattrs)?;
+                checkout_file(
+                    repo,
+                    &entry_path,
+                    hash,
+                    content.as_ref(),
+                    sparse_map.as_deref(),
+                    xattrs,
+                    diag,
+                )?;
                 hardlink_tracker.record(&logical_path, entry_path);
             }
 
-            EntryKind::Symlink { hash, xattrs } => {
+            EntryKind::Symlink { hash, xattrs, .. } => {
                 if entry_path.exists() || entry_path.symlink_metadata().is_ok() {
                     if entry_path.is_dir() {
                         return Err(Error::UnionTypeConflict {
@@ -186,7 +225,7 @@ fn checkout_tree_union(
                     }
                 }
 
-                checkout_symlink(repo, &entry_path, hash, xattrs)?;
+                checkout_symlink(repo, &entry_path, hash, xattrs, diag)?;
                 hardlink_tracker.record(&logical_path, entry_path);
             }
 
@@ -196,6 +235,7 @@ fn checkout_tree_union(
                 gid,
                 mode,
                 xattrs,
+                ..
             } => {
                 if entry_path.exists() && !entry_path.is_dir() {
                     // file exists where we want a directory
@@ -215,10 +255,13 @@ fn checkout_tree_union(
                     on_conflict,
                     hardlink_tracker,
                     pending_hardlinks,
+                    ref_name,
+                    origins.as_deref_mut(),
+                    diag,
                 )?;
 
                 // apply directory metadata
-                apply_metadata_graceful(&entry_path, *uid, *gid, *mode, xattrs)?;
+                apply_metadata_graceful(&entry_path, *uid, *gid, *mode, xattrs, diag)?;
             }
 
             EntryKind::BlockDevice {
@@ -228,6 +271,7 @@ fn checkout_tree_union(
                 gid,
                 mode,
                 xattrs,
+                ..
             } => {
                 if entry_path.exists() {
                     match on_conflict {
@@ -243,12 +287,10 @@ fn checkout_tree_union(
 
                 match create_block_device(&entry_path, *major, *minor, *uid, *gid, *mode, xattrs) {
                     Ok(()) => {}
-                    Err(Error::DeviceNodePermission(_)) => {
-                        eprintln!(
-                            "warning: cannot create block device {:?} without privileges, skipping",
-                            entry_path
-                        );
-                    }
+                    Err(Error::DeviceNodePermission(_)) => diag.warn(Warning::DeviceNodeSkipped {
+                        path: entry_path.clone(),
+                        device_type: "block",
+                    }),
                     Err(e) => return Err(e),
                 }
             }
@@ -260,6 +302,7 @@ fn checkout_tree_union(
                 gid,
                 mode,
                 xattrs,
+                ..
             } => {
                 if entry_path.exists() {
                     match on_conflict {
@@ -275,12 +318,10 @@ fn checkout_tree_union(
 
                 match create_char_device(&entry_path, *major, *minor, *uid, *gid, *mode, xattrs) {
                     Ok(()) => {}
-                    Err(Error::DeviceNodePermission(_)) => {
-                        eprintln!(
-                            "warning: cannot create char device {:?} without privileges, skipping",
-                            entry_path
-                        );
-                    }
+                    Err(Error::DeviceNodePermission(_)) => diag.warn(Warning::DeviceNodeSkipped {
+                        path: entry_path.clone(),
+                        device_type: "char",
+                    }),
                     Err(e) => return Err(e),
                 }
             }
@@ -290,6 +331,7 @@ fn checkout_tree_union(
                 gid,
                 mode,
                 xattrs,
+                ..
             } => {
                 if entry_path.exists() {
                     match on_conflict {
@@ -311,6 +353,7 @@ fn checkout_tree_union(
                 gid,
                 mode,
                 xattrs,
+                ..
             } => {
                 if entry_path.exists() {
                     match on_conflict {
@@ -324,7 +367,14 @@ fn checkout_tree_union(
                     }
                 }
 
-                create_socket_placeholder(&entry_path, *uid, *gid, *mode, xattrs)?;
+                create_socket_placeholder(&entry_path, *uid, *gid, *mode, xattrs, diag)?;
+            }
+        }
+
+        // skipped entries `continue` above, so this one won
+        if let Some(origins) = origins.as_deref_mut() {
+            if !matches!(entry.kind, EntryKind::Directory { .. }) {
+                origins.insert(logical_path, ref_name.to_string());
             }
         }
     }
@@ -336,23 +386,26 @@ fn checkout_file(
     repo: &Repo,
     dest: &Path,
     hash: &Hash,
+    content: Option<&Hash>,
     sparse_map: Option<&[crate::types::SparseRegion]>,
     xattrs: &[Xattr],
+    diag: &dyn Diagnostics,
 ) -> Result<()> {
     // can only hardlink if no xattrs (since blob no longer stores xattrs)
-    let can_hardlink = xattrs.is_empty() && sparse_map.is_none();
+    // and the bytes aren't in a shared content object
+    let can_hardlink = xattrs.is_empty() && sparse_map.is_none() && content.is_none();
 
     match sparse_map {
         Some(regions) if !regions.is_empty() => {
-            let data = read_blob(repo, hash)?;
+            let data = read_blob_data(repo, hash, content)?;
             let total_size: u64 = regions.iter().map(|r| r.end()).max().unwrap_or(0);
             write_sparse_file(dest, &data, regions, total_size)?;
 
             // apply metadata from blob and xattrs from tree
-            let blob = blob_path(repo, hash);
+            let blob = blob_meta_path(repo, hash, content);
             let meta = fs::metadata(&blob).with_path(&blob)?;
             use std::os::unix::fs::MetadataExt;
-            apply_metadata_graceful(dest, meta.uid(), meta.gid(), meta.mode(), xattrs)?;
+            apply_metadata_graceful(dest, meta.uid(), meta.gid(), meta.mode(), xattrs, diag)?;
         }
         Some(_) => {
             fs::write(dest, b"").with_path(dest)?;
@@ -362,20 +415,27 @@ fn checkout_file(
             fs::hard_link(&blob, dest).with_path(dest)?;
         }
         _ => {
-            // copy mode (has xattrs)
-            let blob = blob_path(repo, hash);
-            fs::copy(&blob, dest).with_path(dest)?;
+            // copy mode (has xattrs or content-addressed)
+            let blob = blob_meta_path(repo, hash, content);
+            let data_path = blob_data_path(repo, hash, content);
+            fs::copy(&data_path, dest).with_path(dest)?;
 
             // apply metadata from blob and xattrs from tree
             let meta = fs::metadata(&blob).with_path(&blob)?;
             use std::os::unix::fs::MetadataExt;
-            apply_metadata_graceful(dest, meta.uid(), meta.gid(), meta.mode(), xattrs)?;
+            apply_metadata_graceful(dest, meta.uid(), meta.gid(), meta.mode(), xattrs, diag)?;
         }
     }
     Ok(())
 }
 
-fn checkout_symlink(repo: &Repo, dest: &Path, hash: &Hash, xattrs: &[Xattr]) -> Result<()> {
+fn checkout_symlink(
+    repo: &Repo,
+    dest: &Path,
+    hash: &Hash,
+    xattrs: &[Xattr],
+    diag: &dyn Diagnostics,
+) -> Result<()> {
     let target_bytes = read_blob(repo, hash)?;
     let target = String::from_utf8_lossy(&target_bytes);
 
@@ -383,7 +443,7 @@ fn checkout_symlink(repo: &Repo, dest: &Path, hash: &Hash, xattrs: &[Xattr]) ->
     let meta = fs::symlink_metadata(&blob).with_path(&blob)?;
 
     use std::os::unix::fs::MetadataExt;
-    create_symlink(dest, &target, meta.uid(), meta.gid(), xattrs)?;
+    create_symlink(dest, &target, meta.uid(), meta.gid(), xattrs, diag)?;
     Ok(())
 }
 
@@ -471,6 +531,58 @@ mod tests {
         assert_eq!(content, "version1");
     }
 
+    #[test]
+    fn test_union_checkout_record_origins() {
+        let (dir, repo) = test_repo();
+
+        let source1 = dir.path().join("source1");
+        fs::create_dir_all(source1.join("shared")).unwrap();
+        fs::write(source1.join("conflict.txt"), "version1").unwrap();
+        fs::write(source1.join("shared/a.txt"), "a").unwrap();
+        std::os::unix::fs::symlink("a.txt", source1.join("shared/link")).unwrap();
+        commit(&repo, &source1, "ref1", None, None).unwrap();
+
+        let source2 = dir.path().join("source2");
+        fs::create_dir_all(source2.join("shared")).unwrap();
+        fs::write(source2.join("conflict.txt"), "version2").unwrap();
+        fs::write(source2.join("shared/b.txt"), "b").unwrap();
+        std::os::unix::fs::symlink("b.txt", source2.join("shared/link")).unwrap();
+        commit(&repo, &source2, "ref2", None, None).unwrap();
+
+        for (on_conflict, winner) in [
+            (ConflictResolution::First, "ref1"),
+            (ConflictResolution::Last, "ref2"),
+        ] {
+            let target = dir.path().join(format!("target-{}", winner));
+            let opts = UnionCheckoutOptions {
+                on_conflict,
+                record_origins: true,
+                ..Default::default()
+            };
+            let result = checkout_union(&repo, &["ref1", "ref2"], &target, opts).unwrap();
+
+            let expected: OriginMap = [
+                ("conflict.txt", winner),
+                ("shared/a.txt", "ref1"),
+                ("shared/b.txt", "ref2"),
+                ("shared/link", winner),
+            ]
+            .into_iter()
+            .map(|(path, ref_name)| (path.to_string(), ref_name.to_string()))
+            .collect();
+            assert_eq!(result.origins, expected);
+        }
+
+        // not recorded unless asked for
+        let target = dir.path().join("target-none");
+        let opts = UnionCheckoutOptions {
+            on_conflict: ConflictResolution::Last,
+            ..Default::default()
+        };
+        let result = checkout_union(&repo, &["ref1", "ref2"], &target, opts).unwrap();
+        assert!(result.origins.is_empty());
+    }
+
     #[test]
     fn test_union_checkout_directory_merge() {
         let (dir, repo) = test_repo();
@@ -0,0 +1,298 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::hash::{BlobHasher, Hash, SYMLINK_MODE};
+use crate::namespace::outside_to_inside;
+use crate::object::{blob_file_hash, blob_path, content_path, read_commit, read_tree};
+use crate::ops::fsck::{fsck_ref, CorruptObject, FsckReport, ObjectType};
+use crate::repo::Repo;
+use crate::types::{EntryKind, Xattr};
+
+/// what a tree entry says about a blob, needed to recompute its hash
+struct BlobRef {
+    hash: Hash,
+    xattrs: Vec<Xattr>,
+    content: Option<Hash>,
+    symlink: bool,
+}
+
+/// verify everything reachable from a ref, recomputing blob hashes
+///
+/// runs `fsck_ref` first, then re-hashes every regular and symlink blob in
+/// the history: uid/gid/mode come from the blob file, xattrs from the tree
+/// entry. sparse files are hashed over their stored data regions only, as at
+/// commit time; holes are described by the sparse map, not the hash.
+pub fn verify_ref(repo: &Repo, ref_name: &str) -> Result<FsckReport> {
+    let mut report = fsck_ref(repo, ref_name)?;
+
+    let mut blobs = HashMap::new();
+    let mut seen_commits = HashSet::new();
+    let mut seen_trees = HashSet::new();
+    let mut pending = vec![crate::refs::read_ref(repo, ref_name)?];
+
+    while let Some(commit_hash) = pending.pop() {
+        if !seen_commits.insert(commit_hash) {
+            continue;
+        }
+        // unreadable objects were already reported by fsck_ref
+        let Some(commit) = readable(read_commit(repo, &commit_hash))? else {
+            continue;
+        };
+        pending.extend(commit.parents.iter().copied());
+        collect_blobs(repo, &commit.tree, &mut seen_trees, &mut blobs)?;
+    }
+
+    for (file, blob) in &blobs {
+        if let Some(corrupt) = verify_blob(repo, file, blob)? {
+            report.corrupt_objects.push(corrupt);
+        }
+    }
+
+    Ok(report)
+}
+
+fn collect_blobs(
+    repo: &Repo,
+    tree_hash: &Hash,
+    seen: &mut HashSet<Hash>,
+    blobs: &mut HashMap<Hash, BlobRef>,
+) -> Result<()> {
+    if !seen.insert(*tree_hash) {
+        return Ok(());
+    }
+    let Some(tree) = readable(read_tree(repo, tree_hash))? else {
+        return Ok(());
+    };
+
+    for entry in tree.entries() {
+        match &entry.kind {
+            EntryKind::Regular {
+                hash,
+                xattrs,
+                content,
+                ..
+            } => {
+                let file = blob_file_hash(hash, content.as_ref());
+                blobs.entry(file).or_insert_with(|| BlobRef {
+                    hash: *hash,
+                    xattrs: xattrs.clone(),
+                    content: *content,
+                    symlink: false,
+                });
+            }
+            EntryKind::Symlink { hash, xattrs, .. } => {
+                blobs.entry(*hash).or_insert_with(|| BlobRef {
+                    hash: *hash,
+                    xattrs: xattrs.clone(),
+                    content: None,
+                    symlink: true,
+                });
+            }
+            EntryKind::Directory { hash, .. } => {
+                collect_blobs(repo, hash, seen, blobs)?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// missing or corrupt objects become `None`, other errors propagate
+fn readable<T>(result: Result<T>) -> Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(Error::ObjectNotFound(_)) | Err(Error::CorruptObject(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// recompute one blob's hash (and its content hash, if content-addressed)
+///
+/// `file` is the key of the blob file holding its ownership and mode.
+fn verify_blob(repo: &Repo, file: &Hash, blob: &BlobRef) -> Result<Option<CorruptObject>> {
+    let hash = &blob.hash;
+    let path = blob_path(repo, file);
+    let meta = match fs::metadata(&path) {
+        Ok(meta) => meta,
+        // reported as missing by fsck_ref
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::Io { path, source: e }),
+    };
+
+    let corrupt = |object_type, hash: &Hash, message: String| {
+        Some(CorruptObject {
+            hash: *hash,
+            object_type,
+            message,
+        })
+    };
+
+    let ns = &repo.config().namespace;
+    let (Some(uid), Some(gid)) = (
+        outside_to_inside(meta.uid(), &ns.uid_map),
+        outside_to_inside(meta.gid(), &ns.gid_map),
+    ) else {
+        return Ok(corrupt(
+            ObjectType::Blob,
+            hash,
+            format!("owner {}:{} not mappable", meta.uid(), meta.gid()),
+        ));
+    };
+    let mode = if blob.symlink {
+        SYMLINK_MODE
+    } else {
+        meta.mode()
+    };
+
+    let mut hasher = BlobHasher::new(uid, gid, mode, &blob.xattrs);
+    let mut content_hasher = blake3::Hasher::new();
+    let data_path = match &blob.content {
+        Some(content_hash) => {
+            let path = content_path(repo, content_hash);
+            if !path.exists() {
+                // reported as missing by fsck_ref
+                return Ok(None);
+            }
+            path
+        }
+        None => path,
+    };
+
+    let mut file = File::open(&data_path).with_path(&data_path)?;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).with_path(&data_path)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        content_hasher.update(&buf[..n]);
+    }
+
+    if let Some(content_hash) = &blob.content {
+        let actual = Hash::from_bytes(*content_hasher.finalize().as_bytes());
+        if actual != *content_hash {
+            return Ok(corrupt(
+                ObjectType::Content,
+                content_hash,
+                format!("hash mismatch: expected {}, got {}", content_hash, actual),
+            ));
+        }
+    }
+
+    let actual = hasher.finalize();
+    if actual != *hash {
+        return Ok(corrupt(
+            ObjectType::Blob,
+            hash,
+            format!("hash mismatch: expected {}, got {}", hash, actual),
+        ));
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ops::commit::commit;
+    use crate::ops::fsck::fsck;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    fn test_repo() -> (tempfile::TempDir, Repo) {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        let repo = Repo::init(&repo_path).unwrap();
+        (dir, repo)
+    }
+
+    fn blob_of(repo: &Repo, name: &str) -> Hash {
+        let commit = read_commit(repo, &crate::refs::read_ref(repo, "main").unwrap()).unwrap();
+        let tree = read_tree(repo, &commit.tree).unwrap();
+        *tree.get(name).unwrap().kind.hash().unwrap()
+    }
+
+    #[test]
+    fn test_verify_ref_clean() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a.txt"), "a").unwrap();
+        fs::write(source.join("sub/b.txt"), "b").unwrap();
+        std::os::unix::fs::symlink("a.txt", source.join("link")).unwrap();
+        {
+            use std::io::{Seek, SeekFrom, Write};
+            let mut sparse = File::create(source.join("sparse")).unwrap();
+            sparse.seek(SeekFrom::Start(1 << 20)).unwrap();
+            sparse.write_all(b"tail").unwrap();
+        }
+        commit(&repo, &source, "main", None, None).unwrap();
+        fs::write(source.join("a.txt"), "a2").unwrap();
+        commit(&repo, &source, "main", None, None).unwrap();
+
+        let report = verify_ref(&repo, "main").unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+    }
+
+    #[test]
+    fn test_verify_ref_flags_corrupt_blob() {
+        let (dir, repo) = test_repo();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("a.txt"), "original").unwrap();
+        fs::write(source.join("b.txt"), "untouched").unwrap();
+        commit(&repo, &source, "main", None, None).unwrap();
+
+        // rewrite a blob in place, keeping its size, owner and mode
+        let hash = blob_of(&repo, "a.txt");
+        let path = blob_path(&repo, &hash);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::write(&path, "tampered").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+
+        // fsck can't tell
+        assert!(fsck(&repo).unwrap().is_ok());
+
+        let report = verify_ref(&repo, "main").unwrap();
+        assert_eq!(report.corrupt_objects.len(), 1);
+        assert_eq!(report.corrupt_objects[0].hash, hash);
+        assert_eq!(report.corrupt_objects[0].object_type, ObjectType::Blob);
+
+        // a changed mode is caught too
+        fs::write(&path, "original").unwrap();
+        assert!(verify_ref(&repo, "main").unwrap().is_ok());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(!verify_ref(&repo, "main").unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_verify_ref_content_addressed() {
+        let (dir, mut repo) = test_repo();
+        repo.config_mut().content_addressed_blobs = true;
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("a.txt"), "shared bytes").unwrap();
+        commit(&repo, &source, "main", None, None).unwrap();
+        assert!(verify_ref(&repo, "main").unwrap().is_ok());
+
+        let commit = read_commit(&repo, &crate::refs::read_ref(&repo, "main").unwrap()).unwrap();
+        let tree = read_tree(&repo, &commit.tree).unwrap();
+        let content_hash = *tree.get("a.txt").unwrap().kind.content_hash().unwrap();
+        let path = content_path(&repo, &content_hash);
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        fs::write(&path, "other bytes!").unwrap();
+
+        let report = verify_ref(&repo, "main").unwrap();
+        assert_eq!(report.corrupt_objects.len(), 1);
+        assert_eq!(report.corrupt_objects[0].hash, content_hash);
+    }
+}
@@ -1,18 +1,65 @@
-use std::fs::{self, File};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use nix::fcntl::{Flock, FlockArg};
 
 use crate::error::{Error, IoResultExt, Result};
+use crate::fs::TempFile;
 use crate::hash::Hash;
+use crate::object::{commit_exists, list_packed, read_commit, PackedKind};
 use crate::repo::Repo;
 
+/// ref namespace of branches, what the plain ref functions work on
+pub const HEADS: &str = "heads";
+
+/// ref namespace of tags
+pub const TAGS: &str = "tags";
+
+/// names under `refs/` that aren't ref namespaces
+const RESERVED_NAMESPACES: [&str; 2] = ["packed", "artifacts"];
+
 /// write a ref (create or update)
 ///
 /// ref_name can contain slashes for hierarchical refs like "x86_64/pkg/foo/1.0/outputs/bin"
+///
+/// with `packed_refs` set in the config the ref goes into `refs/packed`, and
+/// any loose file for it is removed so it can't shadow the new value.
 pub fn write_ref(repo: &Repo, ref_name: &str, hash: &Hash) -> Result<()> {
+    write_ref_in(repo, HEADS, ref_name, hash)
+}
+
+/// write a ref under `refs/<namespace>`, e.g. `tags` or `remotes/origin`
+///
+/// only `heads` refs are ever packed; other namespaces are always loose.
+pub fn write_ref_in(repo: &Repo, namespace: &str, ref_name: &str, hash: &Hash) -> Result<()> {
+    validate_namespace(namespace)?;
     validate_ref_name(ref_name)?;
 
-    let ref_path = ref_path(repo, ref_name);
+    let _updates = lock_ref_updates(repo)?;
+    let ref_path = ref_path_in(repo, namespace, ref_name);
+    let old = read_ref_in(repo, namespace, ref_name).ok();
+
+    if namespace == HEADS && repo.config().packed_refs {
+        if ref_name.contains('\n') {
+            return Err(Error::InvalidRef(format!(
+                "packed ref name cannot contain a newline: {:?}",
+                ref_name
+            )));
+        }
+
+        let _lock = lock_packed_refs(repo)?;
+        let mut packed = read_packed_refs(repo)?;
+        packed.insert(ref_name.to_string(), *hash);
+        write_packed_refs(repo, &packed)?;
+        remove_if_exists(&ref_path)?;
+
+        if old != Some(*hash) {
+            append_reflog(repo, namespace, ref_name, old, hash)?;
+        }
+        return Ok(());
+    }
 
     // ensure parent directories exist
     if let Some(parent) = ref_path.parent() {
@@ -20,92 +67,443 @@ pub fn write_ref(repo: &Repo, ref_name: &str, hash: &Hash) -> Result<()> {
     }
 
     // atomic write: temp -> fsync -> rename
-    let tmp_path = repo.tmp_path().join(uuid::Uuid::new_v4().to_string());
-    {
-        let mut tmp_file = File::create(&tmp_path).with_path(&tmp_path)?;
-        writeln!(tmp_file, "{}", hash.to_hex()).with_path(&tmp_path)?;
-        tmp_file.sync_all().with_path(&tmp_path)?;
+    let tmp = stage_ref_file(repo, hash)?;
+    place_ref_file(tmp, &ref_path)?;
+
+    if old != Some(*hash) {
+        append_reflog(repo, namespace, ref_name, old, hash)?;
     }
 
-    // rename to final location
-    fs::rename(&tmp_path, &ref_path).with_path(&ref_path)?;
+    Ok(())
+}
+
+/// write a ref value to a synced temp file, to be renamed into place
+fn stage_ref_file(repo: &Repo, hash: &Hash) -> Result<TempFile> {
+    let tmp = TempFile::new_in(&repo.tmp_path());
+    let tmp_path = tmp.path();
+    let mut tmp_file = File::create(tmp_path).with_path(tmp_path)?;
+    writeln!(tmp_file, "{}", hash.to_hex()).with_path(tmp_path)?;
+    tmp_file.sync_all().with_path(tmp_path)?;
+    Ok(tmp)
+}
+
+/// rename a staged ref file to `ref_path` and fsync its directory
+fn place_ref_file(tmp: TempFile, ref_path: &Path) -> Result<()> {
+    fs::rename(tmp.path(), ref_path).with_path(ref_path)?;
+    tmp.into_persisted();
 
-    // fsync parent directory
     if let Some(parent) = ref_path.parent() {
         let dir = File::open(parent).with_path(parent)?;
         dir.sync_all().with_path(parent)?;
     }
-
     Ok(())
 }
 
+/// one recorded update of a ref
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    /// previous value, none when the ref was created
+    pub old: Option<Hash>,
+    pub new: Hash,
+    /// unix timestamp of the update
+    pub timestamp: i64,
+}
+
+impl ReflogEntry {
+    /// whether this entry records the ref being deleted (`new` is zero)
+    pub fn is_tombstone(&self) -> bool {
+        self.new == Hash::ZERO
+    }
+}
+
+/// read the update history of a ref, oldest first
+///
+/// refs that were never written through `write_ref` have an empty reflog.
+/// the history outlives the ref: deleting it appends a tombstone entry.
+pub fn read_reflog(repo: &Repo, ref_name: &str) -> Result<Vec<ReflogEntry>> {
+    let path = reflog_path(repo, ref_name);
+    let content = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::Io { path, source: e }),
+    };
+
+    let mut entries = Vec::new();
+    for line in content.lines() {
+        let mut parts = line.split_whitespace();
+        let (Some(old), Some(new), Some(timestamp)) = (parts.next(), parts.next(), parts.next())
+        else {
+            continue;
+        };
+        let old = Hash::from_hex(old)?;
+        entries.push(ReflogEntry {
+            old: (old != Hash::ZERO).then_some(old),
+            new: Hash::from_hex(new)?,
+            timestamp: timestamp.parse().unwrap_or(0),
+        });
+    }
+    Ok(entries)
+}
+
+/// append an update to a ref's reflog
+fn append_reflog(
+    repo: &Repo,
+    namespace: &str,
+    ref_name: &str,
+    old: Option<Hash>,
+    new: &Hash,
+) -> Result<()> {
+    let path = repo.ref_logs_path(namespace).join(ref_name);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_path(parent)?;
+    }
+
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_path(&path)?;
+    writeln!(
+        file,
+        "{} {} {}",
+        old.unwrap_or(Hash::ZERO).to_hex(),
+        new.to_hex(),
+        timestamp
+    )
+    .with_path(&path)
+}
+
 /// read a ref
+///
+/// a loose ref file wins over an entry in `refs/packed`.
 pub fn read_ref(repo: &Repo, ref_name: &str) -> Result<Hash> {
-    let ref_path = ref_path(repo, ref_name);
+    read_ref_in(repo, HEADS, ref_name)
+}
 
-    let content = fs::read_to_string(&ref_path).map_err(|e| {
-        if e.kind() == std::io::ErrorKind::NotFound {
-            Error::RefNotFound(ref_name.to_string())
-        } else {
-            Error::Io {
-                path: ref_path.clone(),
-                source: e,
-            }
-        }
-    })?;
+/// read a ref under `refs/<namespace>`
+pub fn read_ref_in(repo: &Repo, namespace: &str, ref_name: &str) -> Result<Hash> {
+    validate_namespace(namespace)?;
+    match read_loose_ref(repo, namespace, ref_name)? {
+        Some(hash) => Ok(hash),
+        None if namespace == HEADS => read_packed_refs(repo)?
+            .get(ref_name)
+            .copied()
+            .ok_or_else(|| Error::RefNotFound(ref_name.to_string())),
+        None => Err(Error::RefNotFound(format!("{}/{}", namespace, ref_name))),
+    }
+}
 
-    let hex = content.trim();
-    Hash::from_hex(hex)
+/// read a ref's own file, none if it has none
+fn read_loose_ref(repo: &Repo, namespace: &str, ref_name: &str) -> Result<Option<Hash>> {
+    let ref_path = ref_path_in(repo, namespace, ref_name);
+
+    match fs::read_to_string(&ref_path) {
+        Ok(content) => Hash::from_hex(content.trim()).map(Some),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Io {
+            path: ref_path,
+            source: e,
+        }),
+    }
 }
 
 /// delete a ref
 pub fn delete_ref(repo: &Repo, ref_name: &str) -> Result<()> {
-    let ref_path = ref_path(repo, ref_name);
-
-    fs::remove_file(&ref_path).map_err(|e| {
-        if e.kind() == std::io::ErrorKind::NotFound {
-            Error::RefNotFound(ref_name.to_string())
-        } else {
-            Error::Io {
-                path: ref_path,
-                source: e,
-            }
-        }
-    })
+    if !delete_refs(repo, &[ref_name.to_string()])? {
+        return Err(Error::RefNotFound(ref_name.to_string()));
+    }
+    Ok(())
 }
 
 /// delete refs matching a glob pattern, returns list of deleted refs
 pub fn delete_refs_matching(repo: &Repo, pattern: &str) -> Result<Vec<String>> {
     let matching = list_refs_matching(repo, pattern)?;
-    for ref_name in &matching {
-        delete_ref(repo, ref_name)?;
-    }
+    delete_refs(repo, &matching)?;
     Ok(matching)
 }
 
+/// remove refs in both forms, rewriting `refs/packed` at most once;
+/// returns whether anything was removed
+pub(crate) fn delete_refs(repo: &Repo, ref_names: &[String]) -> Result<bool> {
+    let _updates = lock_ref_updates(repo)?;
+
+    // values before removal, for the tombstones
+    let tips: Vec<(&String, Hash)> = ref_names
+        .iter()
+        .filter_map(|name| read_ref(repo, name).ok().map(|hash| (name, hash)))
+        .collect();
+
+    let mut removed = false;
+    for ref_name in ref_names {
+        removed |= remove_if_exists(&ref_path(repo, ref_name))?;
+    }
+
+    if repo.packed_refs_path().exists() {
+        let _lock = lock_packed_refs(repo)?;
+        let mut packed = read_packed_refs(repo)?;
+        let before = packed.len();
+        for ref_name in ref_names {
+            packed.remove(ref_name);
+        }
+        if packed.len() != before {
+            write_packed_refs(repo, &packed)?;
+            removed = true;
+        }
+    }
+
+    // keep the history, ending in a tombstone, so `recover_ref` can
+    // bring the ref back
+    for (ref_name, hash) in tips {
+        append_reflog(repo, HEADS, ref_name, Some(hash), &Hash::ZERO)?;
+    }
+
+    Ok(removed)
+}
+
+/// recreate a deleted ref at the last value its reflog recorded
+///
+/// fails if the ref exists, has no history, or its last commit has been
+/// collected.
+pub fn recover_ref(repo: &Repo, ref_name: &str) -> Result<Hash> {
+    if ref_exists(repo, ref_name) {
+        return Err(Error::RefExists(ref_name.to_string()));
+    }
+
+    let hash = read_reflog(repo, ref_name)?
+        .iter()
+        .rev()
+        .find(|entry| !entry.is_tombstone())
+        .map(|entry| entry.new)
+        .ok_or_else(|| Error::RefNotFound(ref_name.to_string()))?;
+    if !commit_exists(repo, &hash) {
+        return Err(Error::ObjectNotFound(hash));
+    }
+
+    write_ref(repo, ref_name, &hash)?;
+    Ok(hash)
+}
+
+/// remove a ref's reflog, returning false if it had none
+pub(crate) fn delete_reflog(repo: &Repo, ref_name: &str) -> Result<bool> {
+    remove_if_exists(&reflog_path(repo, ref_name))
+}
+
+/// remove a file, returning false if it wasn't there
+fn remove_if_exists(path: &Path) -> Result<bool> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(Error::Io {
+            path: path.to_path_buf(),
+            source: e,
+        }),
+    }
+}
+
+/// find refs whose target commit no longer exists, removing them unless dry_run
+///
+/// only a definitive `ObjectNotFound` counts as dangling; corrupt commits and
+/// i/o errors are propagated so a flaky disk never causes refs to be pruned.
+pub fn prune_dangling_refs(repo: &Repo, dry_run: bool) -> Result<Vec<String>> {
+    let mut dangling = Vec::new();
+
+    for ref_name in list_refs(repo)? {
+        let hash = read_ref(repo, &ref_name)?;
+        match read_commit(repo, &hash) {
+            Ok(_) => {}
+            Err(Error::ObjectNotFound(_)) => dangling.push(ref_name),
+            Err(e) => return Err(e),
+        }
+    }
+
+    if !dry_run {
+        delete_refs(repo, &dangling)?;
+    }
+
+    Ok(dangling)
+}
+
+/// shortest hex prefix accepted as an abbreviated hash
+const MIN_SHORT_HASH: usize = 4;
+
 /// resolve a ref or hash string to a hash
 ///
 /// if the string looks like a hash (64 hex chars), parse it directly.
-/// otherwise, look it up as a ref name.
+/// otherwise, look it up as a ref name; failing that, a hex string of at
+/// least 4 chars is taken as an abbreviated object hash.
+///
+/// a missing ref is `RefNotFound` only for names that aren't all hex. an
+/// all-hex string that is no ref and no object's prefix (e.g. a 40-char git
+/// hash) is `InvalidHashHex`, naming the length a full hash has.
 pub fn resolve_ref(repo: &Repo, ref_or_hash: &str) -> Result<Hash> {
+    let is_hex = !ref_or_hash.is_empty() && ref_or_hash.chars().all(|c| c.is_ascii_hexdigit());
+
     // if it's 64 hex chars, treat as hash
-    if ref_or_hash.len() == 64 && ref_or_hash.chars().all(|c| c.is_ascii_hexdigit()) {
+    if is_hex && ref_or_hash.len() == 64 {
         return Hash::from_hex(ref_or_hash);
     }
 
-    // otherwise, look up as ref
-    read_ref(repo, ref_or_hash)
+    // otherwise, look up as ref; refs win over short hashes
+    match read_ref(repo, ref_or_hash) {
+        Err(Error::RefNotFound(_)) if is_hex => {
+            if (MIN_SHORT_HASH..64).contains(&ref_or_hash.len()) {
+                resolve_short_hash(repo, ref_or_hash)
+            } else {
+                Err(not_a_hash(ref_or_hash))
+            }
+        }
+        result => result,
+    }
+}
+
+/// error for hex that names neither a ref nor an object
+fn not_a_hash(hex: &str) -> Error {
+    Error::InvalidHashHex(format!(
+        "{} ({} hex chars, matching no ref or object; a full hash has 64)",
+        hex,
+        hex.len()
+    ))
+}
+
+/// find the single blob, tree or commit whose hash starts with `prefix`
+///
+/// only the shard directory named by the prefix's first two digits is
+/// listed for each object type, then the pack indexes. no match is reported
+/// as `InvalidHashHex`.
+fn resolve_short_hash(repo: &Repo, prefix: &str) -> Result<Hash> {
+    let prefix = prefix.to_ascii_lowercase();
+    let (shard, rest) = prefix.split_at(2);
+
+    let mut matches = BTreeSet::new();
+    for objects in [repo.blobs_path(), repo.trees_path(), repo.commits_path()] {
+        let dir = objects.join(shard);
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_path(&dir),
+        };
+        for entry in entries {
+            let name = entry.with_path(&dir)?.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            // temp files and other non-object names don't parse
+            if name.starts_with(rest) {
+                if let Ok(hash) = Hash::from_hex(&format!("{}{}", shard, name)) {
+                    matches.insert(hash);
+                }
+            }
+        }
+    }
+
+    // packed trees and commits have no loose file to list
+    for kind in [PackedKind::Tree, PackedKind::Commit] {
+        matches.extend(
+            list_packed(repo, kind)?
+                .into_iter()
+                .filter(|hash| hash.to_hex().starts_with(&prefix)),
+        );
+    }
+
+    let mut matches = matches.into_iter();
+    match (matches.next(), matches.next()) {
+        (Some(hash), None) => Ok(hash),
+        (Some(_), Some(_)) => Err(Error::AmbiguousHash(prefix)),
+        (None, _) => Err(not_a_hash(&prefix)),
+    }
 }
 
-/// list all refs
+/// list all refs, loose and packed
 pub fn list_refs(repo: &Repo) -> Result<Vec<String>> {
-    let refs_dir = repo.refs_path();
+    list_refs_in(repo, HEADS)
+}
+
+/// list the refs under `refs/<namespace>`, named relative to it
+pub fn list_refs_in(repo: &Repo, namespace: &str) -> Result<Vec<String>> {
+    validate_namespace(namespace)?;
+    let mut refs = list_loose_refs(repo, namespace)?;
+    if namespace == HEADS {
+        refs.extend(read_packed_refs(repo)?.into_keys());
+    }
+
+    refs.sort();
+    refs.dedup();
+    Ok(refs)
+}
+
+fn list_loose_refs(repo: &Repo, namespace: &str) -> Result<Vec<String>> {
+    let refs_dir = repo.ref_namespace_path(namespace);
     let mut refs = Vec::new();
 
     if refs_dir.exists() {
         collect_refs(&refs_dir, &refs_dir, &mut refs)?;
     }
+    Ok(refs)
+}
+
+/// refs arranged by their `/`-separated components
+///
+/// a node is a ref if `hash` is set, otherwise a directory of refs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RefTree {
+    pub hash: Option<Hash>,
+    pub children: BTreeMap<String, RefTree>,
+}
+
+impl RefTree {
+    /// add a ref at the node its components lead to
+    pub fn insert(&mut self, ref_name: &str, hash: Hash) {
+        let node = ref_name.split('/').fold(self, |node, component| {
+            node.children.entry(component.to_string()).or_default()
+        });
+        node.hash = Some(hash);
+    }
+
+    /// render like `tree(1)`, one line per node, refs followed by their hash
+    pub fn format(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        self.format_children("", &mut lines);
+        lines
+    }
+
+    fn format_children(&self, indent: &str, lines: &mut Vec<String>) {
+        let count = self.children.len();
+        for (i, (name, child)) in self.children.iter().enumerate() {
+            let last = i + 1 == count;
+            let branch = if last { "└── " } else { "├── " };
+            match &child.hash {
+                Some(hash) => lines.push(format!("{}{}{} {}", indent, branch, name, hash)),
+                None => lines.push(format!("{}{}{}", indent, branch, name)),
+            }
+            let nested = format!("{}{}", indent, if last { "    " } else { "│   " });
+            child.format_children(&nested, lines);
+        }
+    }
+}
+
+/// list all refs as a hierarchy of their path components
+pub fn list_refs_tree(repo: &Repo) -> Result<RefTree> {
+    let mut tree = RefTree::default();
+    for ref_name in list_refs(repo)? {
+        let hash = read_ref(repo, &ref_name)?;
+        tree.insert(&ref_name, hash);
+    }
+    Ok(tree)
+}
 
+/// list every ref with a reflog, including deleted ones
+pub fn list_reflogs(repo: &Repo) -> Result<Vec<String>> {
+    let logs_dir = repo.logs_path();
+    let mut refs = Vec::new();
+
+    if logs_dir.exists() {
+        collect_refs(&logs_dir, &logs_dir, &mut refs)?;
+    }
     refs.sort();
     Ok(refs)
 }
@@ -121,11 +519,131 @@ pub fn list_refs_matching(repo: &Repo, pattern: &str) -> Result<Vec<String>> {
 /// check if a ref exists
 pub fn ref_exists(repo: &Repo, ref_name: &str) -> bool {
     ref_path(repo, ref_name).exists()
+        || read_packed_refs(repo)
+            .map(|packed| packed.contains_key(ref_name))
+            .unwrap_or(false)
+}
+
+/// fold all loose refs into `refs/packed`, returns how many were packed
+///
+/// a loose file is only removed if it still holds the value that was packed,
+/// so a concurrent loose write is not lost. emptied ref directories are
+/// pruned. names containing a newline can't be packed and stay loose.
+pub fn pack_refs(repo: &Repo) -> Result<usize> {
+    let _lock = lock_packed_refs(repo)?;
+    let mut packed = read_packed_refs(repo)?;
+
+    let mut folded = Vec::new();
+    for ref_name in list_loose_refs(repo, HEADS)? {
+        if ref_name.contains('\n') {
+            continue;
+        }
+        if let Some(hash) = read_loose_ref(repo, HEADS, &ref_name)? {
+            packed.insert(ref_name.clone(), hash);
+            folded.push((ref_name, hash));
+        }
+    }
+    write_packed_refs(repo, &packed)?;
+
+    let refs_dir = repo.refs_path();
+    for (ref_name, hash) in &folded {
+        if read_loose_ref(repo, HEADS, ref_name)? == Some(*hash) {
+            let path = ref_path(repo, ref_name);
+            remove_if_exists(&path)?;
+
+            // drop parent directories left empty, up to refs/heads
+            let mut dir = path.parent();
+            while let Some(d) = dir {
+                if d == refs_dir || fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        }
+    }
+
+    Ok(folded.len())
+}
+
+/// read `refs/packed` (`<hash> <name>` per line); a missing file is empty
+fn read_packed_refs(repo: &Repo) -> Result<BTreeMap<String, Hash>> {
+    let path = repo.packed_refs_path();
+    let content = match fs::read_to_string(&path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(Error::Io { path, source: e }),
+    };
+
+    let mut refs = BTreeMap::new();
+    for line in content.lines() {
+        if let Some((hex, name)) = line.split_once(' ') {
+            refs.insert(name.to_string(), Hash::from_hex(hex)?);
+        }
+    }
+    Ok(refs)
+}
+
+/// atomically replace `refs/packed`; caller holds the packed refs lock
+fn write_packed_refs(repo: &Repo, refs: &BTreeMap<String, Hash>) -> Result<()> {
+    let path = repo.packed_refs_path();
+    let tmp = TempFile::new_in(&repo.tmp_path());
+    let tmp_path = tmp.path();
+    {
+        let mut tmp_file = std::io::BufWriter::new(File::create(tmp_path).with_path(tmp_path)?);
+        for (name, hash) in refs {
+            writeln!(tmp_file, "{} {}", hash.to_hex(), name).with_path(tmp_path)?;
+        }
+        let tmp_file = tmp_file
+            .into_inner()
+            .map_err(|e| e.into_error())
+            .with_path(tmp_path)?;
+        tmp_file.sync_all().with_path(tmp_path)?;
+    }
+
+    fs::rename(tmp_path, &path).with_path(&path)?;
+    tmp.into_persisted();
+    if let Some(parent) = path.parent() {
+        let dir = File::open(parent).with_path(parent)?;
+        dir.sync_all().with_path(parent)?;
+    }
+    Ok(())
+}
+
+/// serialize read-modify-write cycles of `refs/packed` (blocking)
+/// wait for exclusive use of the refs among writers
+///
+/// taken by single-ref writes, deletions and transactions alike, so nothing
+/// lands between a transaction's checks and its renames.
+fn lock_ref_updates(repo: &Repo) -> Result<Flock<File>> {
+    let path = repo.packed_refs_path().with_file_name("update.lock");
+    let file = File::create(&path).with_path(&path)?;
+    Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, errno)| Error::Io {
+        path,
+        source: std::io::Error::from(errno),
+    })
+}
+
+fn lock_packed_refs(repo: &Repo) -> Result<Flock<File>> {
+    let path = repo.packed_refs_path().with_extension("lock");
+    let file = File::create(&path).with_path(&path)?;
+    Flock::lock(file, FlockArg::LockExclusive).map_err(|(_, errno)| Error::Io {
+        path,
+        source: std::io::Error::from(errno),
+    })
 }
 
 /// get filesystem path for a ref
 fn ref_path(repo: &Repo, ref_name: &str) -> PathBuf {
-    repo.refs_path().join(ref_name)
+    ref_path_in(repo, HEADS, ref_name)
+}
+
+fn ref_path_in(repo: &Repo, namespace: &str, ref_name: &str) -> PathBuf {
+    repo.ref_namespace_path(namespace).join(ref_name)
+}
+
+/// get filesystem path for a ref's reflog
+fn reflog_path(repo: &Repo, ref_name: &str) -> PathBuf {
+    repo.logs_path().join(ref_name)
 }
 
 /// recursively collect refs from directory
@@ -147,6 +665,211 @@ fn collect_refs(base: &PathBuf, dir: &PathBuf, refs: &mut Vec<String>) -> Result
     Ok(())
 }
 
+// --- Ref transactions ---
+
+/// several ref updates and deletions applied all-or-nothing
+///
+/// `commit` takes the repository lock and the lock every ref writer takes,
+/// checks every expected value, stages every new value in a temp file and
+/// only then renames them into place, so a concurrent writer or a failed
+/// check leaves all refs untouched. should a
+/// rename fail midway, the refs already moved are put back (best effort).
+#[derive(Debug, Default)]
+pub struct RefTransaction {
+    updates: Vec<RefUpdate>,
+}
+
+#[derive(Debug)]
+struct RefUpdate {
+    ref_name: String,
+    /// new value, none to delete the ref
+    new: Option<Hash>,
+    /// value the ref must have at commit (none: absent), unchecked if unset
+    expected: Option<Option<Hash>>,
+}
+
+impl RefTransaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// point `ref_name` at `new`, or delete it if `new` is none
+    pub fn update(&mut self, ref_name: &str, new: Option<Hash>) -> &mut Self {
+        self.updates.push(RefUpdate {
+            ref_name: ref_name.to_string(),
+            new,
+            expected: None,
+        });
+        self
+    }
+
+    /// like `update`, but abort the transaction unless the ref currently
+    /// has `expected` (none: doesn't exist)
+    pub fn update_if(
+        &mut self,
+        ref_name: &str,
+        expected: Option<Hash>,
+        new: Option<Hash>,
+    ) -> &mut Self {
+        self.updates.push(RefUpdate {
+            ref_name: ref_name.to_string(),
+            new,
+            expected: Some(expected),
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+
+    /// apply every staged update, or none of them
+    ///
+    /// waits for other ref writers rather than the repository lock, so gc or
+    /// maintenance running meanwhile doesn't fail it. fails with `RefChanged`
+    /// if an expected value doesn't match.
+    pub fn commit(self, repo: &Repo) -> Result<()> {
+        let mut seen = BTreeSet::new();
+        for update in &self.updates {
+            validate_ref_name(&update.ref_name)?;
+            if !seen.insert(update.ref_name.as_str()) {
+                return Err(Error::InvalidRef(format!(
+                    "ref updated twice in one transaction: {}",
+                    update.ref_name
+                )));
+            }
+        }
+
+        let _updates = lock_ref_updates(repo)?;
+
+        let mut old = Vec::with_capacity(self.updates.len());
+        for update in &self.updates {
+            let current = match read_ref(repo, &update.ref_name) {
+                Ok(hash) => Some(hash),
+                Err(Error::RefNotFound(_)) => None,
+                Err(e) => return Err(e),
+            };
+            if let Some(expected) = update.expected {
+                if expected != current {
+                    return Err(Error::RefChanged {
+                        ref_name: update.ref_name.clone(),
+                        expected,
+                        actual: current,
+                    });
+                }
+            }
+            old.push(current);
+        }
+
+        if repo.config().packed_refs {
+            self.apply_packed(repo)?;
+        } else {
+            self.apply_loose(repo, &old)?;
+        }
+
+        for (update, old) in self.updates.iter().zip(old) {
+            match (old, update.new) {
+                (old, Some(new)) if old != Some(new) => {
+                    append_reflog(repo, HEADS, &update.ref_name, old, &new)?
+                }
+                (Some(old), None) => {
+                    append_reflog(repo, HEADS, &update.ref_name, Some(old), &Hash::ZERO)?
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// a single rewrite of `refs/packed` carries every update
+    fn apply_packed(&self, repo: &Repo) -> Result<()> {
+        if let Some(update) = self.updates.iter().find(|u| u.ref_name.contains('\n')) {
+            return Err(Error::InvalidRef(format!(
+                "packed ref name cannot contain a newline: {:?}",
+                update.ref_name
+            )));
+        }
+
+        let _lock = lock_packed_refs(repo)?;
+        let mut packed = read_packed_refs(repo)?;
+        for update in &self.updates {
+            match update.new {
+                Some(hash) => packed.insert(update.ref_name.clone(), hash),
+                None => packed.remove(&update.ref_name),
+            };
+        }
+        write_packed_refs(repo, &packed)?;
+
+        // loose copies would shadow the new values
+        for update in &self.updates {
+            remove_if_exists(&ref_path(repo, &update.ref_name))?;
+        }
+        Ok(())
+    }
+
+    /// stage all new values, then rename them into place, rolling back the
+    /// renamed ones if any step fails
+    fn apply_loose(&self, repo: &Repo, old: &[Option<Hash>]) -> Result<()> {
+        let mut staged = Vec::with_capacity(self.updates.len());
+        for update in &self.updates {
+            let tmp = match update.new {
+                Some(hash) => {
+                    let path = ref_path(repo, &update.ref_name);
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent).with_path(parent)?;
+                    }
+                    Some(stage_ref_file(repo, &hash)?)
+                }
+                None => None,
+            };
+            staged.push(tmp);
+        }
+
+        let mut applied = 0;
+        let mut result = Ok(());
+        for (update, tmp) in self.updates.iter().zip(staged) {
+            let path = ref_path(repo, &update.ref_name);
+            result = match tmp {
+                Some(tmp) => place_ref_file(tmp, &path),
+                None => remove_if_exists(&path).map(|_| ()),
+            };
+            if result.is_err() {
+                break;
+            }
+            applied += 1;
+        }
+
+        // deleted refs may also have a packed entry
+        if result.is_ok() && repo.packed_refs_path().exists() {
+            result = self.remove_packed_deletions(repo);
+        }
+
+        if result.is_err() {
+            for (update, old) in self.updates.iter().zip(old).take(applied) {
+                let path = ref_path(repo, &update.ref_name);
+                let _ = match old {
+                    Some(hash) => stage_ref_file(repo, hash).and_then(|t| place_ref_file(t, &path)),
+                    None => remove_if_exists(&path).map(|_| ()),
+                };
+            }
+        }
+        result
+    }
+
+    fn remove_packed_deletions(&self, repo: &Repo) -> Result<()> {
+        let _lock = lock_packed_refs(repo)?;
+        let mut packed = read_packed_refs(repo)?;
+        let before = packed.len();
+        for update in self.updates.iter().filter(|u| u.new.is_none()) {
+            packed.remove(&update.ref_name);
+        }
+        if packed.len() != before {
+            write_packed_refs(repo, &packed)?;
+        }
+        Ok(())
+    }
+}
+
 // --- Artifact ref helpers ---
 
 /// write an artifact ref at the given path
@@ -164,14 +887,16 @@ pub fn write_artifact_ref(repo: &Repo, path: &str, artifact_hash: &Hash) -> Resu
     }
 
     // atomic write
-    let tmp_path = repo.tmp_path().join(uuid::Uuid::new_v4().to_string());
+    let tmp = TempFile::new_in(&repo.tmp_path());
+    let tmp_path = tmp.path();
     {
-        let mut tmp_file = File::create(&tmp_path).with_path(&tmp_path)?;
-        writeln!(tmp_file, "{}", artifact_hash.to_hex()).with_path(&tmp_path)?;
-        tmp_file.sync_all().with_path(&tmp_path)?;
+        let mut tmp_file = File::create(tmp_path).with_path(tmp_path)?;
+        writeln!(tmp_file, "{}", artifact_hash.to_hex()).with_path(tmp_path)?;
+        tmp_file.sync_all().with_path(tmp_path)?;
     }
 
-    fs::rename(&tmp_path, &ref_path).with_path(&ref_path)?;
+    fs::rename(tmp_path, &ref_path).with_path(&ref_path)?;
+    tmp.into_persisted();
 
     if let Some(parent) = ref_path.parent() {
         let dir = File::open(parent).with_path(parent)?;
@@ -250,6 +975,20 @@ pub fn delete_artifact_refs_matching(repo: &Repo, pattern: &str) -> Result<Vec<S
     Ok(matching)
 }
 
+/// validate a ref namespace: a ref-like name that isn't taken by
+/// something else under `refs/`
+fn validate_namespace(namespace: &str) -> Result<()> {
+    validate_ref_name(namespace)?;
+    let top = namespace.split('/').next().unwrap_or_default();
+    if RESERVED_NAMESPACES.contains(&top) {
+        return Err(Error::InvalidRef(format!(
+            "reserved ref namespace: {}",
+            namespace
+        )));
+    }
+    Ok(())
+}
+
 /// validate ref name
 fn validate_ref_name(name: &str) -> Result<()> {
     if name.is_empty() {
@@ -385,6 +1124,27 @@ mod tests {
         assert_eq!(refs.len(), 2);
     }
 
+    #[test]
+    fn test_delete_refs_matching() {
+        let (_dir, repo) = test_repo();
+
+        write_ref(&repo, "x86_64/pkg/foo/1.0", &Hash::ZERO).unwrap();
+        write_ref(&repo, "x86_64/pkg/bar/2.0", &Hash::ZERO).unwrap();
+        write_ref(&repo, "aarch64/pkg/foo/1.0", &Hash::ZERO).unwrap();
+
+        // dry run only lists
+        let matching = list_refs_matching(&repo, "x86_64/*").unwrap();
+        assert_eq!(matching.len(), 2);
+        assert_eq!(list_refs(&repo).unwrap().len(), 3);
+
+        let deleted = delete_refs_matching(&repo, "x86_64/*").unwrap();
+        assert_eq!(deleted, matching);
+        assert_eq!(
+            list_refs(&repo).unwrap(),
+            vec!["aarch64/pkg/foo/1.0".to_string()]
+        );
+    }
+
     #[test]
     fn test_resolve_ref_hash() {
         let (_dir, repo) = test_repo();
@@ -408,6 +1168,86 @@ mod tests {
         assert_eq!(resolved, hash);
     }
 
+    #[test]
+    fn test_resolve_short_hash() {
+        let (_dir, repo) = test_repo();
+
+        let commit = crate::types::Commit::with_timestamp(Hash::ZERO, vec![], "test", 1, "msg");
+        let hash = crate::object::write_commit(&repo, &commit).unwrap();
+        let hex = hash.to_hex();
+        assert_eq!(resolve_ref(&repo, &hex[..12]).unwrap(), hash);
+        assert_eq!(resolve_ref(&repo, &hex[..12].to_uppercase()).unwrap(), hash);
+
+        // still found once packed
+        crate::object::pack::tests::pack_loose(&repo);
+        assert_eq!(resolve_ref(&repo, &hex[..12]).unwrap(), hash);
+
+        // two blobs sharing a prefix
+        let shard = repo.blobs_path().join("ab");
+        fs::create_dir_all(&shard).unwrap();
+        let first = format!("cd0{}", "0".repeat(59));
+        let second = format!("cd1{}", "1".repeat(59));
+        fs::write(shard.join(&first), "").unwrap();
+        fs::write(shard.join(&second), "").unwrap();
+        fs::write(shard.join(".tmp-abcd"), "").unwrap();
+
+        assert!(matches!(
+            resolve_ref(&repo, "abcd"),
+            Err(Error::AmbiguousHash(_))
+        ));
+        let resolved = resolve_ref(&repo, "abcd1").unwrap();
+        assert_eq!(resolved.to_hex(), format!("ab{}", second));
+
+        // a ref of the same name takes priority
+        write_ref(&repo, "abcd", &hash).unwrap();
+        assert_eq!(resolve_ref(&repo, "abcd").unwrap(), hash);
+
+        // hex matching nothing, or too short to be taken as a hash
+        assert!(matches!(
+            resolve_ref(&repo, "abce"),
+            Err(Error::InvalidHashHex(_))
+        ));
+        assert!(matches!(
+            resolve_ref(&repo, "abc"),
+            Err(Error::InvalidHashHex(_))
+        ));
+    }
+
+    #[test]
+    fn test_resolve_ref_wrong_length_hex() {
+        let (_dir, repo) = test_repo();
+
+        // a git-length hash is not taken for a missing ref
+        let git_hash = "0123456789abcdef0123456789abcdef01234567";
+        match resolve_ref(&repo, git_hash) {
+            Err(Error::InvalidHashHex(message)) => {
+                assert!(message.contains("40 hex chars"), "{}", message);
+                assert!(message.contains("64"), "{}", message);
+            }
+            other => panic!("expected InvalidHashHex, got {:?}", other),
+        }
+        let too_long = "a".repeat(65);
+        assert!(matches!(
+            resolve_ref(&repo, &too_long),
+            Err(Error::InvalidHashHex(_))
+        ));
+
+        // names that aren't all hex are refs, missing or not
+        assert!(matches!(
+            resolve_ref(&repo, "release/v1"),
+            Err(Error::RefNotFound(name)) if name == "release/v1"
+        ));
+        assert!(matches!(
+            resolve_ref(&repo, "nightly"),
+            Err(Error::RefNotFound(_))
+        ));
+
+        // an all-hex ref name still resolves as a ref
+        let hash = Hash::from_hex(&"2".repeat(64)).unwrap();
+        write_ref(&repo, git_hash, &hash).unwrap();
+        assert_eq!(resolve_ref(&repo, git_hash).unwrap(), hash);
+    }
+
     #[test]
     fn test_invalid_ref_names() {
         assert!(validate_ref_name("").is_err());
@@ -442,8 +1282,324 @@ mod tests {
         assert_eq!(read_hash, hash2);
     }
 
+    #[test]
+    fn test_reflog() {
+        let (_dir, repo) = test_repo();
+
+        let hash1 = Hash::from_bytes([1u8; 32]);
+        let hash2 = Hash::from_bytes([2u8; 32]);
+
+        write_ref(&repo, "a/myref", &hash1).unwrap();
+        write_ref(&repo, "a/myref", &hash1).unwrap();
+        write_ref(&repo, "a/myref", &hash2).unwrap();
+
+        // unchanged writes are not logged
+        let log = read_reflog(&repo, "a/myref").unwrap();
+        assert_eq!(log.len(), 2);
+        assert_eq!(log[0].old, None);
+        assert_eq!(log[0].new, hash1);
+        assert_eq!(log[1].old, Some(hash1));
+        assert_eq!(log[1].new, hash2);
+
+        // deleting leaves a tombstone
+        delete_ref(&repo, "a/myref").unwrap();
+        let log = read_reflog(&repo, "a/myref").unwrap();
+        assert_eq!(log.len(), 3);
+        assert_eq!(log[2].old, Some(hash2));
+        assert!(log[2].is_tombstone());
+    }
+
+    #[test]
+    fn test_recover_ref() {
+        use crate::object::write_commit;
+        use crate::types::Commit;
+
+        let (_dir, repo) = test_repo();
+        let first = write_commit(&repo, &Commit::new(Hash::ZERO, vec![], "test", "1")).unwrap();
+        let second = write_commit(&repo, &Commit::new(Hash::ZERO, vec![], "test", "2")).unwrap();
+        write_ref(&repo, "myref", &first).unwrap();
+        write_ref(&repo, "myref", &second).unwrap();
+
+        assert!(matches!(
+            recover_ref(&repo, "myref"),
+            Err(Error::RefExists(_))
+        ));
+
+        delete_ref(&repo, "myref").unwrap();
+        assert!(!ref_exists(&repo, "myref"));
+        assert_eq!(list_reflogs(&repo).unwrap(), vec!["myref".to_string()]);
+
+        assert_eq!(recover_ref(&repo, "myref").unwrap(), second);
+        assert_eq!(read_ref(&repo, "myref").unwrap(), second);
+
+        assert!(matches!(
+            recover_ref(&repo, "never"),
+            Err(Error::RefNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_prune_dangling_refs() {
+        use crate::object::{commit_path, write_commit};
+        use crate::types::Commit;
+
+        let (_dir, repo) = test_repo();
+
+        let live = Commit::with_timestamp(Hash::ZERO, vec![], "test", 1, "live");
+        let gone = Commit::with_timestamp(Hash::ZERO, vec![], "test", 2, "gone");
+        let live = write_commit(&repo, &live).unwrap();
+        let gone = write_commit(&repo, &gone).unwrap();
+        write_ref(&repo, "live", &live).unwrap();
+        write_ref(&repo, "gone", &gone).unwrap();
+
+        fs::remove_file(commit_path(&repo, &gone)).unwrap();
+
+        let reported = prune_dangling_refs(&repo, true).unwrap();
+        assert_eq!(reported, vec!["gone".to_string()]);
+        assert!(ref_exists(&repo, "gone"));
+
+        let pruned = prune_dangling_refs(&repo, false).unwrap();
+        assert_eq!(pruned, vec!["gone".to_string()]);
+        assert!(!ref_exists(&repo, "gone"));
+        assert!(ref_exists(&repo, "live"));
+    }
+
     // --- Artifact ref tests ---
 
+    #[test]
+    fn test_pack_refs() {
+        let (_dir, repo) = test_repo();
+
+        let hash_for = |i: u32| {
+            let mut bytes = [0u8; 32];
+            bytes[..4].copy_from_slice(&i.to_le_bytes());
+            Hash::from_bytes(bytes)
+        };
+        for i in 0..500 {
+            write_ref(&repo, &format!("x86_64/pkg/p{}/1.0", i), &hash_for(i)).unwrap();
+        }
+        write_ref(&repo, "aarch64/pkg/p0/1.0", &hash_for(1000)).unwrap();
+
+        assert_eq!(pack_refs(&repo).unwrap(), 501);
+        // loose files and their emptied directories are gone
+        assert_eq!(fs::read_dir(repo.refs_path()).unwrap().count(), 0);
+
+        assert_eq!(read_ref(&repo, "x86_64/pkg/p42/1.0").unwrap(), hash_for(42));
+        assert!(ref_exists(&repo, "aarch64/pkg/p0/1.0"));
+        assert!(matches!(
+            read_ref(&repo, "x86_64/pkg/missing"),
+            Err(Error::RefNotFound(_))
+        ));
+        assert_eq!(list_refs(&repo).unwrap().len(), 501);
+        assert_eq!(
+            list_refs_matching(&repo, "aarch64/*").unwrap(),
+            vec!["aarch64/pkg/p0/1.0"]
+        );
+        assert_eq!(
+            list_refs_matching(&repo, "x86_64/pkg/p4?/*").unwrap().len(),
+            10
+        );
+
+        // deleting a packed ref rewrites the packed file
+        delete_refs_matching(&repo, "x86_64/pkg/p1*/*").unwrap();
+        assert_eq!(list_refs(&repo).unwrap().len(), 501 - 111);
+        delete_ref(&repo, "aarch64/pkg/p0/1.0").unwrap();
+        assert!(!ref_exists(&repo, "aarch64/pkg/p0/1.0"));
+        assert!(delete_ref(&repo, "aarch64/pkg/p0/1.0").is_err());
+    }
+
+    #[test]
+    fn test_loose_ref_shadows_packed() {
+        let (_dir, repo) = test_repo();
+        let old = Hash::ZERO;
+        let new = Hash::from_bytes([1; 32]);
+
+        write_ref(&repo, "main", &old).unwrap();
+        pack_refs(&repo).unwrap();
+        write_ref(&repo, "main", &new).unwrap();
+
+        assert_eq!(read_ref(&repo, "main").unwrap(), new);
+        assert_eq!(list_refs(&repo).unwrap(), vec!["main"]);
+        assert_eq!(read_reflog(&repo, "main").unwrap().len(), 2);
+
+        // deleting removes both copies instead of uncovering the stale one
+        delete_ref(&repo, "main").unwrap();
+        assert!(matches!(
+            read_ref(&repo, "main"),
+            Err(Error::RefNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_packed_refs_mode() {
+        let (_dir, mut repo) = test_repo();
+        write_ref(&repo, "loose", &Hash::ZERO).unwrap();
+
+        repo.config_mut().packed_refs = true;
+        let hash = Hash::from_bytes([2; 32]);
+        write_ref(&repo, "a/b", &hash).unwrap();
+        write_ref(&repo, "loose", &hash).unwrap();
+
+        // no loose files are written, and the old loose one is replaced
+        assert!(!repo.refs_path().join("a").exists());
+        assert!(!repo.refs_path().join("loose").exists());
+        assert_eq!(read_ref(&repo, "loose").unwrap(), hash);
+        assert_eq!(list_refs(&repo).unwrap(), vec!["a/b", "loose"]);
+
+        assert!(write_ref(&repo, "bad\nname", &hash).is_err());
+    }
+
+    #[test]
+    fn test_ref_namespaces() {
+        let (_dir, repo) = test_repo();
+        let (a, b, c) = (
+            Hash::from_bytes([1; 32]),
+            Hash::from_bytes([2; 32]),
+            Hash::from_bytes([3; 32]),
+        );
+
+        write_ref(&repo, "main", &a).unwrap();
+        write_ref_in(&repo, TAGS, "v1.0", &b).unwrap();
+        write_ref_in(&repo, "remotes/origin", "main", &c).unwrap();
+
+        // the same name resolves independently in each namespace
+        assert_eq!(read_ref_in(&repo, HEADS, "main").unwrap(), a);
+        assert_eq!(read_ref_in(&repo, "remotes/origin", "main").unwrap(), c);
+        assert!(matches!(
+            read_ref_in(&repo, TAGS, "main"),
+            Err(Error::RefNotFound(_))
+        ));
+
+        assert_eq!(list_refs(&repo).unwrap(), vec!["main"]);
+        assert_eq!(list_refs_in(&repo, TAGS).unwrap(), vec!["v1.0"]);
+        assert_eq!(list_refs_in(&repo, "remotes/origin").unwrap(), vec!["main"]);
+        assert_eq!(list_refs_in(&repo, "remotes").unwrap(), vec!["origin/main"]);
+        assert!(list_refs_in(&repo, "notes").unwrap().is_empty());
+
+        assert!(repo.tags_path().join("v1.0").is_file());
+        assert_eq!(read_reflog(&repo, "main").unwrap().len(), 1);
+        assert!(repo.ref_logs_path(TAGS).join("v1.0").is_file());
+
+        // names used by other things under refs/ aren't namespaces
+        assert!(write_ref_in(&repo, "packed", "x", &a).is_err());
+        assert!(write_ref_in(&repo, "artifacts/x86_64", "x", &a).is_err());
+        assert!(list_refs_in(&repo, "").is_err());
+    }
+
+    #[test]
+    fn test_ref_transaction() {
+        for packed in [false, true] {
+            let (_dir, mut repo) = test_repo();
+            let (a, b, c) = (
+                Hash::from_bytes([1; 32]),
+                Hash::from_bytes([2; 32]),
+                Hash::from_bytes([3; 32]),
+            );
+            write_ref(&repo, "update", &a).unwrap();
+            write_ref(&repo, "delete", &a).unwrap();
+            repo.config_mut().packed_refs = packed;
+
+            let stage = || {
+                let mut tx = RefTransaction::new();
+                tx.update("create/x", Some(b))
+                    .update_if("update", Some(a), Some(c))
+                    .update("delete", None);
+                tx
+            };
+
+            // the repo lock (gc, maintenance) doesn't stop it; another ref
+            // writer makes it wait, seeing no partial transaction meanwhile
+            let repo_lock = repo.lock().unwrap();
+            let updates = lock_ref_updates(&repo).unwrap();
+            std::thread::scope(|scope| {
+                let tx = scope.spawn(|| stage().commit(&repo));
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                assert!(!ref_exists(&repo, "create/x"));
+                assert_eq!(read_ref(&repo, "update").unwrap(), a);
+                assert_eq!(read_ref(&repo, "delete").unwrap(), a);
+
+                drop(updates);
+                tx.join().unwrap().unwrap();
+            });
+            drop(repo_lock);
+            assert_eq!(read_ref(&repo, "create/x").unwrap(), b);
+            assert_eq!(read_ref(&repo, "update").unwrap(), c);
+            assert!(!ref_exists(&repo, "delete"));
+            assert_eq!(list_refs(&repo).unwrap(), vec!["create/x", "update"]);
+
+            let log = read_reflog(&repo, "delete").unwrap();
+            assert!(log.last().unwrap().is_tombstone());
+            assert_eq!(read_reflog(&repo, "update").unwrap().last().unwrap().new, c);
+        }
+    }
+
+    #[test]
+    fn test_ref_writes_wait_for_updates_lock() {
+        let (_dir, repo) = test_repo();
+        let a = Hash::from_bytes([1; 32]);
+        write_ref(&repo, "gone", &a).unwrap();
+
+        let lock = lock_ref_updates(&repo).unwrap();
+        std::thread::scope(|scope| {
+            let writer = scope.spawn(|| write_ref(&repo, "new", &a));
+            let deleter = scope.spawn(|| delete_ref(&repo, "gone"));
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            assert!(!ref_exists(&repo, "new"));
+            assert!(ref_exists(&repo, "gone"));
+
+            drop(lock);
+            writer.join().unwrap().unwrap();
+            deleter.join().unwrap().unwrap();
+        });
+        assert!(ref_exists(&repo, "new"));
+        assert!(!ref_exists(&repo, "gone"));
+    }
+
+    #[test]
+    fn test_ref_transaction_expected_mismatch() {
+        let (_dir, repo) = test_repo();
+        let (a, b) = (Hash::from_bytes([1; 32]), Hash::from_bytes([2; 32]));
+        write_ref(&repo, "one", &a).unwrap();
+        write_ref(&repo, "two", &a).unwrap();
+
+        let mut tx = RefTransaction::new();
+        tx.update("one", Some(b))
+            .update_if("two", Some(b), Some(b))
+            .update_if("three", None, Some(b));
+        let err = tx.commit(&repo).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::RefChanged { ref ref_name, expected: Some(_), actual: Some(_) }
+                if ref_name == "two"
+        ));
+
+        // nothing was applied, not even the unconditional update
+        assert_eq!(read_ref(&repo, "one").unwrap(), a);
+        assert_eq!(read_ref(&repo, "two").unwrap(), a);
+        assert!(!ref_exists(&repo, "three"));
+        assert_eq!(read_reflog(&repo, "one").unwrap().len(), 1);
+
+        // the same ref twice is rejected up front
+        let mut tx = RefTransaction::new();
+        tx.update("one", Some(b)).update("one", None);
+        assert!(matches!(tx.commit(&repo), Err(Error::InvalidRef(_))));
+    }
+
+    #[test]
+    fn test_packed_refs_mode_leaves_other_namespaces_loose() {
+        let (_dir, mut repo) = test_repo();
+        repo.config_mut().packed_refs = true;
+
+        let hash = Hash::from_bytes([4; 32]);
+        write_ref(&repo, "main", &hash).unwrap();
+        write_ref_in(&repo, TAGS, "v1.0", &hash).unwrap();
+
+        assert!(!repo.refs_path().join("main").exists());
+        assert!(repo.tags_path().join("v1.0").is_file());
+        assert_eq!(list_refs(&repo).unwrap(), vec!["main"]);
+        assert_eq!(list_refs_in(&repo, TAGS).unwrap(), vec!["v1.0"]);
+    }
+
     #[test]
     fn test_write_and_read_artifact_ref() {
         let (_dir, repo) = test_repo();
@@ -452,7 +1608,12 @@ mod tests {
             Hash::from_hex("1111111111111111111111111111111111111111111111111111111111111111")
                 .unwrap();
 
-        write_artifact_ref(&repo, "x86_64/pkg/foo/1.0/abc123/outputs/bin", &artifact_hash).unwrap();
+        write_artifact_ref(
+            &repo,
+            "x86_64/pkg/foo/1.0/abc123/outputs/bin",
+            &artifact_hash,
+        )
+        .unwrap();
 
         let read_hash = read_artifact_ref(&repo, "x86_64/pkg/foo/1.0/abc123/outputs/bin").unwrap();
         assert_eq!(artifact_hash, read_hash);
@@ -464,12 +1625,26 @@ mod tests {
 
         let artifact_hash = Hash::ZERO;
 
-        assert!(!artifact_ref_exists(&repo, "x86_64/pkg/foo/1.0/abc123/outputs/bin"));
-
-        write_artifact_ref(&repo, "x86_64/pkg/foo/1.0/abc123/outputs/bin", &artifact_hash).unwrap();
-
-        assert!(artifact_ref_exists(&repo, "x86_64/pkg/foo/1.0/abc123/outputs/bin"));
-        assert!(!artifact_ref_exists(&repo, "x86_64/pkg/foo/1.0/abc123/outputs/lib"));
+        assert!(!artifact_ref_exists(
+            &repo,
+            "x86_64/pkg/foo/1.0/abc123/outputs/bin"
+        ));
+
+        write_artifact_ref(
+            &repo,
+            "x86_64/pkg/foo/1.0/abc123/outputs/bin",
+            &artifact_hash,
+        )
+        .unwrap();
+
+        assert!(artifact_ref_exists(
+            &repo,
+            "x86_64/pkg/foo/1.0/abc123/outputs/bin"
+        ));
+        assert!(!artifact_ref_exists(
+            &repo,
+            "x86_64/pkg/foo/1.0/abc123/outputs/lib"
+        ));
     }
 
     #[test]
@@ -478,9 +1653,24 @@ mod tests {
 
         let artifact_hash = Hash::ZERO;
 
-        write_artifact_ref(&repo, "x86_64/pkg/foo/1.0/abc123/bundles/dev", &artifact_hash).unwrap();
-        write_artifact_ref(&repo, "x86_64/pkg/foo/1.0/abc123/bundles/full", &artifact_hash).unwrap();
-        write_artifact_ref(&repo, "x86_64/pkg/bar/2.0/def456/outputs/bin", &artifact_hash).unwrap();
+        write_artifact_ref(
+            &repo,
+            "x86_64/pkg/foo/1.0/abc123/bundles/dev",
+            &artifact_hash,
+        )
+        .unwrap();
+        write_artifact_ref(
+            &repo,
+            "x86_64/pkg/foo/1.0/abc123/bundles/full",
+            &artifact_hash,
+        )
+        .unwrap();
+        write_artifact_ref(
+            &repo,
+            "x86_64/pkg/bar/2.0/def456/outputs/bin",
+            &artifact_hash,
+        )
+        .unwrap();
 
         let refs = list_artifact_refs(&repo).unwrap();
         assert_eq!(refs.len(), 3);
@@ -492,9 +1682,24 @@ mod tests {
 
         let artifact_hash = Hash::ZERO;
 
-        write_artifact_ref(&repo, "x86_64/pkg/foo/1.0/abc123/outputs/bin", &artifact_hash).unwrap();
-        write_artifact_ref(&repo, "x86_64/pkg/bar/2.0/def456/outputs/bin", &artifact_hash).unwrap();
-        write_artifact_ref(&repo, "x86_64/bootstrap/baz/1.0/ghi789/outputs/lib", &artifact_hash).unwrap();
+        write_artifact_ref(
+            &repo,
+            "x86_64/pkg/foo/1.0/abc123/outputs/bin",
+            &artifact_hash,
+        )
+        .unwrap();
+        write_artifact_ref(
+            &repo,
+            "x86_64/pkg/bar/2.0/def456/outputs/bin",
+            &artifact_hash,
+        )
+        .unwrap();
+        write_artifact_ref(
+            &repo,
+            "x86_64/bootstrap/baz/1.0/ghi789/outputs/lib",
+            &artifact_hash,
+        )
+        .unwrap();
 
         let refs = list_artifact_refs_matching(&repo, "*/pkg/*").unwrap();
         assert_eq!(refs.len(), 2);
@@ -509,9 +1714,24 @@ mod tests {
 
         let artifact_hash = Hash::ZERO;
 
-        write_artifact_ref(&repo, "x86_64/pkg/foo/1.0/abc123/outputs/bin", &artifact_hash).unwrap();
-        write_artifact_ref(&repo, "x86_64/pkg/bar/2.0/def456/outputs/bin", &artifact_hash).unwrap();
-        write_artifact_ref(&repo, "x86_64/bootstrap/baz/1.0/ghi789/outputs/lib", &artifact_hash).unwrap();
+        write_artifact_ref(
+            &repo,
+            "x86_64/pkg/foo/1.0/abc123/outputs/bin",
+            &artifact_hash,
+        )
+        .unwrap();
+        write_artifact_ref(
+            &repo,
+            "x86_64/pkg/bar/2.0/def456/outputs/bin",
+            &artifact_hash,
+        )
+        .unwrap();
+        write_artifact_ref(
+            &repo,
+            "x86_64/bootstrap/baz/1.0/ghi789/outputs/lib",
+            &artifact_hash,
+        )
+        .unwrap();
 
         let deleted = delete_artifact_refs_matching(&repo, "*/pkg/*").unwrap();
         assert_eq!(deleted.len(), 2);
@@ -528,4 +1748,43 @@ mod tests {
         let result = read_artifact_ref(&repo, "nonexistent/path");
         assert!(matches!(result, Err(Error::RefNotFound(_))));
     }
+
+    #[test]
+    fn test_list_refs_tree() {
+        let (_dir, repo) = test_repo();
+        let h1 = Hash::from_bytes([1u8; 32]);
+        let h2 = Hash::from_bytes([2u8; 32]);
+        let h3 = Hash::from_bytes([3u8; 32]);
+
+        write_ref(&repo, "x86_64/foo/1.0", &h1).unwrap();
+        write_ref(&repo, "x86_64/foo/2.0", &h2).unwrap();
+        write_ref(&repo, "x86_64/bar/1.0", &h3).unwrap();
+        write_ref(&repo, "main", &h1).unwrap();
+
+        let tree = list_refs_tree(&repo).unwrap();
+        let names: Vec<&str> = tree.children.keys().map(String::as_str).collect();
+        assert_eq!(names, vec!["main", "x86_64"]);
+        assert_eq!(tree.hash, None);
+
+        let arch = &tree.children["x86_64"];
+        assert_eq!(arch.hash, None);
+        assert_eq!(arch.children["foo"].children["1.0"].hash, Some(h1));
+        assert_eq!(arch.children["foo"].children["2.0"].hash, Some(h2));
+        assert_eq!(arch.children["bar"].children["1.0"].hash, Some(h3));
+        assert!(arch.children["foo"].children["1.0"].children.is_empty());
+        assert_eq!(tree.children["main"].hash, Some(h1));
+
+        assert_eq!(
+            tree.format(),
+            vec![
+                format!("├── main {}", h1),
+                "└── x86_64".to_string(),
+                "    ├── bar".to_string(),
+                format!("    │   └── 1.0 {}", h3),
+                "    └── foo".to_string(),
+                format!("        ├── 1.0 {}", h1),
+                format!("        └── 2.0 {}", h2),
+            ]
+        );
+    }
 }
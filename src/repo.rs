@@ -3,43 +3,97 @@ use std::path::{Path, PathBuf};
 
 use nix::fcntl::{Flock, FlockArg};
 
-use crate::config::Config;
+use crate::config::{Config, FORMAT_VERSION};
 use crate::error::{Error, IoResultExt, Result};
 use crate::namespace::{current_gid_map, current_uid_map, NsConfig};
+use crate::object::{CacheStats, ObjectCache, PackCache};
+
+/// whether `path` looks like a repository root
+fn is_repo_dir(path: &Path) -> bool {
+    path.join("config.toml").is_file()
+        && (path.join("objects").is_dir() || path.join("refs").is_dir())
+}
 
 /// a zub repository
 pub struct Repo {
     path: PathBuf,
     config: Config,
+    cache: Option<ObjectCache>,
+    dictionary: Option<Vec<u8>>,
+    packs: PackCache,
+}
+
+/// options for `Repo::init_with_options`
+#[derive(Debug, Clone, Default)]
+pub struct InitOptions {
+    /// namespace mapping to pin instead of the current process's, for a
+    /// repository holding content for another namespace, e.g. a container's
+    /// mapping when building on the host
+    pub namespace: Option<NsConfig>,
+    /// keep the object store here instead of `objects/`. a relative path is
+    /// taken relative to the repository and stored as given, so the pair can
+    /// be moved together
+    pub objects_dir: Option<PathBuf>,
+    /// compress trees and commits with this zstd dictionary (see
+    /// `ops::train_dictionary`). it is part of the object format: it changes
+    /// tree and commit hashes, so it is fixed for the lifetime of the
+    /// repository
+    pub dictionary: Option<Vec<u8>>,
 }
 
 impl Repo {
     /// initialize a new repository at the given path
     pub fn init(path: &Path) -> Result<Self> {
+        Self::init_with_options(path, &InitOptions::default())
+    }
+
+    /// initialize a new repository with the given options
+    pub fn init_with_options(path: &Path, opts: &InitOptions) -> Result<Self> {
         let config_path = path.join("config.toml");
         if config_path.exists() {
             return Err(Error::RepoExists(path.to_path_buf()));
         }
 
+        // capture current namespace mapping unless one is given
+        let ns = match &opts.namespace {
+            Some(ns) => {
+                ns.validate()?;
+                ns.clone()
+            }
+            None => NsConfig {
+                uid_map: current_uid_map()?,
+                gid_map: current_gid_map()?,
+            },
+        };
+
+        let mut config = Config::new(ns);
+        config.objects_dir = opts.objects_dir.clone();
+
+        let repo = Self {
+            path: path.to_path_buf(),
+            config,
+            cache: None,
+            dictionary: opts.dictionary.clone(),
+            packs: PackCache::default(),
+        };
+
         // create directory structure
-        std::fs::create_dir_all(path.join("objects/blobs")).with_path(path)?;
-        std::fs::create_dir_all(path.join("objects/trees")).with_path(path)?;
-        std::fs::create_dir_all(path.join("objects/commits")).with_path(path)?;
+        for dir in [repo.blobs_path(), repo.trees_path(), repo.commits_path()] {
+            std::fs::create_dir_all(&dir).with_path(&dir)?;
+        }
         std::fs::create_dir_all(path.join("refs/heads")).with_path(path)?;
         std::fs::create_dir_all(path.join("refs/tags")).with_path(path)?;
         std::fs::create_dir_all(path.join("tmp")).with_path(path)?;
+        let objects_tmp = repo.objects_tmp_path();
+        std::fs::create_dir_all(&objects_tmp).with_path(&objects_tmp)?;
+        if let Some(dict) = &repo.dictionary {
+            let dict_path = repo.dictionary_path();
+            std::fs::write(&dict_path, dict).with_path(&dict_path)?;
+        }
 
-        // capture current namespace mapping
-        let uid_map = current_uid_map()?;
-        let gid_map = current_gid_map()?;
-
-        let config = Config::new(NsConfig { uid_map, gid_map });
-        config.save(&config_path)?;
+        repo.config.save(&config_path)?;
 
-        Ok(Self {
-            path: path.to_path_buf(),
-            config,
-        })
+        Ok(repo)
     }
 
     /// open an existing repository
@@ -50,11 +104,94 @@ impl Repo {
         }
 
         let config = Config::load(&config_path)?;
+        if config.format_version > FORMAT_VERSION {
+            return Err(Error::UnsupportedRepoVersion(config.format_version));
+        }
 
-        Ok(Self {
+        let mut repo = Self {
             path: path.to_path_buf(),
             config,
-        })
+            cache: None,
+            dictionary: None,
+            packs: PackCache::default(),
+        };
+        let dict_path = repo.dictionary_path();
+        if dict_path.exists() {
+            repo.dictionary = Some(std::fs::read(&dict_path).with_path(&dict_path)?);
+        }
+
+        Ok(repo)
+    }
+
+    /// upgrade the repository at `path` to `FORMAT_VERSION`, returning the
+    /// version it was at
+    ///
+    /// versionless repositories share version 1's layout, so for them this
+    /// only records the version in config.toml.
+    pub fn migrate(path: &Path) -> Result<u32> {
+        let repo = Self::open(path)?;
+        let _lock = repo.lock()?;
+
+        // re-read under the lock in case another migration got there first
+        let config_path = repo.config_path();
+        let mut config = Config::load(&config_path)?;
+        let from = config.format_version;
+        if from < FORMAT_VERSION {
+            config.format_version = FORMAT_VERSION;
+            config.save(&config_path)?;
+        }
+
+        Ok(from)
+    }
+
+    /// open the repository containing `start`, searching up through its ancestors
+    ///
+    /// a directory counts if it has `config.toml` and `objects/` or `refs/`, either
+    /// itself or under a `.zub` entry (directory or symlink).
+    pub fn discover(start: &Path) -> Result<Self> {
+        Self::open(&Self::discover_path(start)?)
+    }
+
+    /// root path of the repository `discover` would open
+    pub fn discover_path(start: &Path) -> Result<PathBuf> {
+        // canonicalizing first resolves symlinked parents, so walking up
+        // strictly shortens the path and always ends at `/`
+        let start_abs = std::fs::canonicalize(start).with_path(start)?;
+
+        for dir in start_abs.ancestors() {
+            for candidate in [dir.to_path_buf(), dir.join(".zub")] {
+                if is_repo_dir(&candidate) {
+                    return Ok(candidate);
+                }
+            }
+        }
+
+        Err(Error::NoRepo(start.to_path_buf()))
+    }
+
+    /// open an existing repository, caching up to `capacity` decoded trees and commits
+    pub fn open_with_cache(path: &Path, capacity: usize) -> Result<Self> {
+        let mut repo = Self::open(path)?;
+        repo.cache = Some(ObjectCache::new(capacity));
+        Ok(repo)
+    }
+
+    /// object cache counters, if caching is enabled
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|c| c.stats())
+    }
+
+    pub(crate) fn object_cache(&self) -> Option<&ObjectCache> {
+        self.cache.as_ref()
+    }
+
+    pub(crate) fn pack_cache(&self) -> &PackCache {
+        &self.packs
+    }
+
+    /// zstd dictionary for trees and commits, if the repo was created with one
+    pub fn zstd_dictionary(&self) -> Option<&[u8]> {
+        self.dictionary.as_deref()
     }
 
     /// repository root path
@@ -82,9 +219,26 @@ impl Repo {
         self.path.join("config.toml")
     }
 
-    /// path to objects directory
+    /// path to objects directory, `objects/` unless configured elsewhere
     pub fn objects_path(&self) -> PathBuf {
-        self.path.join("objects")
+        match &self.config.objects_dir {
+            Some(dir) => self.path.join(dir),
+            None => self.path.join("objects"),
+        }
+    }
+
+    /// object stores of the configured alternates, in lookup order
+    pub fn alternate_objects_paths(&self) -> Vec<PathBuf> {
+        self.config
+            .alternates
+            .iter()
+            .map(|dir| self.path.join(dir))
+            .collect()
+    }
+
+    /// path to the zstd dictionary, present only if set at init
+    pub fn dictionary_path(&self) -> PathBuf {
+        self.objects_path().join("zstd.dict")
     }
 
     /// path to blobs directory
@@ -107,11 +261,31 @@ impl Repo {
         self.objects_path().join("artifacts")
     }
 
+    /// path to content directory (content-addressed blobs)
+    pub fn content_path(&self) -> PathBuf {
+        self.objects_path().join("content")
+    }
+
     /// path to refs directory
     pub fn refs_path(&self) -> PathBuf {
         self.path.join("refs/heads")
     }
 
+    /// path to the refs of a namespace (`heads`, `tags`, `remotes/origin`, ...)
+    pub fn ref_namespace_path(&self, namespace: &str) -> PathBuf {
+        self.path.join("refs").join(namespace)
+    }
+
+    /// path to pack files of cold trees and commits
+    pub fn packs_path(&self) -> PathBuf {
+        self.objects_path().join("pack")
+    }
+
+    /// path to the packed refs file (one `<hash> <name>` line per ref)
+    pub fn packed_refs_path(&self) -> PathBuf {
+        self.path.join("refs/packed")
+    }
+
     /// path to tags directory
     pub fn tags_path(&self) -> PathBuf {
         self.path.join("refs/tags")
@@ -122,11 +296,35 @@ impl Repo {
         self.path.join("refs/artifacts")
     }
 
+    /// path to reflogs of refs/heads
+    pub fn logs_path(&self) -> PathBuf {
+        self.path.join("logs/refs/heads")
+    }
+
+    /// path to reflogs of a ref namespace
+    pub fn ref_logs_path(&self, namespace: &str) -> PathBuf {
+        self.path.join("logs/refs").join(namespace)
+    }
+
     /// path to tmp directory (for atomic writes)
     pub fn tmp_path(&self) -> PathBuf {
         self.path.join("tmp")
     }
 
+    /// tmp directory for atomic object writes, on the object store's
+    /// filesystem: `tmp/` normally, `tmp/` inside an external objects dir
+    pub fn objects_tmp_path(&self) -> PathBuf {
+        match self.config.objects_dir {
+            Some(_) => self.objects_path().join("tmp"),
+            None => self.tmp_path(),
+        }
+    }
+
+    /// path to the resume cursor of an unfinished scrub
+    pub fn scrub_cursor_path(&self) -> PathBuf {
+        self.path.join("scrub-cursor")
+    }
+
     /// path to lock file
     pub fn lock_path(&self) -> PathBuf {
         self.path.join(".lock")
@@ -177,6 +375,7 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::object::blob::tests::tmp_leftovers;
     use tempfile::tempdir;
 
     #[test]
@@ -230,6 +429,41 @@ mod tests {
         assert!(matches!(result, Err(Error::NoRepo(_))));
     }
 
+    #[test]
+    fn test_repo_discover() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+
+        let repo_path = root.join("project");
+        Repo::init(&repo_path).unwrap();
+        let nested = repo_path.join("work/a/b");
+        std::fs::create_dir_all(&nested).unwrap();
+
+        let repo = Repo::discover(&nested).unwrap();
+        assert_eq!(repo.path(), repo_path);
+
+        // a symlink back up the tree resolves instead of looping
+        std::os::unix::fs::symlink(&repo_path, nested.join("up")).unwrap();
+        let repo = Repo::discover(&nested.join("up/work")).unwrap();
+        assert_eq!(repo.path(), repo_path);
+
+        // checkouts find the repo through a .zub symlink
+        let checkout = root.join("checkout/sub");
+        std::fs::create_dir_all(&checkout).unwrap();
+        std::os::unix::fs::symlink(&repo_path, root.join("checkout/.zub")).unwrap();
+        assert_eq!(
+            Repo::discover_path(&checkout).unwrap(),
+            root.join("checkout/.zub")
+        );
+    }
+
+    #[test]
+    fn test_repo_discover_not_found() {
+        let dir = tempdir().unwrap();
+        let result = Repo::discover(dir.path());
+        assert!(matches!(result, Err(Error::NoRepo(_))));
+    }
+
     #[test]
     fn test_repo_paths() {
         let dir = tempdir().unwrap();
@@ -243,6 +477,133 @@ mod tests {
         assert_eq!(repo.tmp_path(), repo_path.join("tmp"));
     }
 
+    #[test]
+    fn test_repo_init_with_namespace() {
+        use crate::namespace::{outside_to_inside, MapEntry};
+        use crate::object::{read_commit, read_tree};
+        use crate::ops::commit;
+        use crate::types::EntryKind;
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        // a container's view, not ours: our root is its uid 1000
+        let ns = NsConfig {
+            uid_map: vec![MapEntry::new(1000, 0, 1), MapEntry::new(0, 100000, 1000)],
+            gid_map: vec![MapEntry::new(2000, 0, 1), MapEntry::new(0, 200000, 2000)],
+        };
+        let opts = InitOptions {
+            namespace: Some(ns.clone()),
+            ..Default::default()
+        };
+        let repo = Repo::init_with_options(&repo_path, &opts).unwrap();
+        assert_eq!(repo.config().namespace, ns);
+        assert_eq!(Repo::open(&repo_path).unwrap().config().namespace, ns);
+
+        let source = dir.path().join("source");
+        std::fs::create_dir_all(source.join("sub")).unwrap();
+        let hash = commit(&repo, &source, "main", None, None).unwrap();
+        let tree = read_tree(&repo, &read_commit(&repo, &hash).unwrap().tree).unwrap();
+        let meta = std::fs::metadata(source.join("sub")).unwrap();
+        match &tree.get("sub").unwrap().kind {
+            EntryKind::Directory { uid, gid, .. } => {
+                assert_eq!(Some(*uid), outside_to_inside(meta.uid(), &ns.uid_map));
+                assert_eq!(Some(*gid), outside_to_inside(meta.gid(), &ns.gid_map));
+            }
+            other => panic!("expected a directory, got {:?}", other),
+        }
+
+        // malformed maps are refused before anything is created
+        let overlapping = NsConfig {
+            uid_map: vec![MapEntry::new(0, 1000, 10), MapEntry::new(5, 2000, 10)],
+            gid_map: vec![MapEntry::new(0, 1000, 1)],
+        };
+        let bad_path = dir.path().join("bad");
+        let opts = InitOptions {
+            namespace: Some(overlapping),
+            ..Default::default()
+        };
+        let result = Repo::init_with_options(&bad_path, &opts);
+        assert!(matches!(result, Err(Error::InvalidIdMap(_))));
+        assert!(!bad_path.exists());
+    }
+
+    #[test]
+    fn test_repo_external_objects_dir() {
+        use crate::object::{blob_path, read_commit, read_tree, tree_path};
+        use crate::ops::commit;
+
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("meta");
+        let store = dir.path().join("bulk/objects");
+        let opts = InitOptions {
+            objects_dir: Some(store.clone()),
+            ..Default::default()
+        };
+        let repo = Repo::init_with_options(&repo_path, &opts).unwrap();
+
+        assert!(!repo_path.join("objects").exists());
+        assert!(store.join("blobs").is_dir());
+        assert_eq!(repo.blobs_path(), store.join("blobs"));
+        assert_eq!(repo.objects_tmp_path(), store.join("tmp"));
+
+        let source = dir.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("file.txt"), "hello").unwrap();
+        let hash = commit(&repo, &source, "main", None, None).unwrap();
+
+        // the config carries the location, so a fresh open finds the objects
+        let repo = Repo::open(&repo_path).unwrap();
+        let commit_obj = read_commit(&repo, &hash).unwrap();
+        assert!(tree_path(&repo, &commit_obj.tree).starts_with(&store));
+        let tree = read_tree(&repo, &commit_obj.tree).unwrap();
+        let blob = tree.get("file.txt").unwrap().kind.hash().unwrap();
+        assert!(blob_path(&repo, blob).starts_with(&store));
+        assert_eq!(crate::object::read_blob(&repo, blob).unwrap(), b"hello");
+        assert_eq!(tmp_leftovers(&store.join("tmp")), 0);
+        assert_eq!(Repo::discover_path(&repo_path).unwrap(), repo_path);
+
+        // relative paths resolve against the repository
+        let repo_path = dir.path().join("rel");
+        let opts = InitOptions {
+            objects_dir: Some(PathBuf::from("../rel-objects")),
+            ..Default::default()
+        };
+        let repo = Repo::init_with_options(&repo_path, &opts).unwrap();
+        assert_eq!(
+            repo.config().objects_dir,
+            Some(PathBuf::from("../rel-objects"))
+        );
+        assert!(dir.path().join("rel-objects/trees").is_dir());
+        commit(&repo, &source, "main", None, None).unwrap();
+    }
+
+    #[test]
+    fn test_repo_init_with_combined_options() {
+        use crate::namespace::MapEntry;
+
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("repo");
+        let store = dir.path().join("store");
+        let ns = NsConfig {
+            uid_map: vec![MapEntry::new(0, 100000, 65536)],
+            gid_map: vec![MapEntry::new(0, 100000, 65536)],
+        };
+        let opts = InitOptions {
+            namespace: Some(ns.clone()),
+            objects_dir: Some(store.clone()),
+            dictionary: Some(vec![7u8; 1024]),
+        };
+        Repo::init_with_options(&repo_path, &opts).unwrap();
+
+        let repo = Repo::open(&repo_path).unwrap();
+        assert_eq!(repo.config().namespace, ns);
+        assert_eq!(repo.blobs_path(), store.join("blobs"));
+        assert!(store.join("trees").is_dir());
+        assert_eq!(repo.zstd_dictionary(), Some(&[7u8; 1024][..]));
+        assert!(repo.dictionary_path().starts_with(&store));
+    }
+
     #[test]
     fn test_repo_lock() {
         let dir = tempdir().unwrap();
@@ -280,4 +641,51 @@ mod tests {
         assert_eq!(repo2.config().remotes.len(), 1);
         assert_eq!(repo2.config().remotes[0].name, "origin");
     }
+
+    #[test]
+    fn test_repo_format_version() {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("test-repo");
+        let repo = Repo::init(&repo_path).unwrap();
+        assert_eq!(repo.config().format_version, FORMAT_VERSION);
+
+        // a repository from a newer release is refused
+        let mut config = repo.config().clone();
+        config.format_version = FORMAT_VERSION + 1;
+        config.save(&repo.config_path()).unwrap();
+        assert!(matches!(
+            Repo::open(&repo_path),
+            Err(Error::UnsupportedRepoVersion(v)) if v == FORMAT_VERSION + 1
+        ));
+        assert!(matches!(
+            Repo::migrate(&repo_path),
+            Err(Error::UnsupportedRepoVersion(_))
+        ));
+    }
+
+    #[test]
+    fn test_repo_migrate_versionless() {
+        let dir = tempdir().unwrap();
+        let repo_path = dir.path().join("test-repo");
+        let repo = Repo::init(&repo_path).unwrap();
+
+        // config.toml as written before the version was recorded
+        let config_path = repo.config_path();
+        let content = std::fs::read_to_string(&config_path).unwrap();
+        let versionless: String = content
+            .lines()
+            .filter(|line| !line.starts_with("format_version"))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        assert_ne!(versionless, content);
+        std::fs::write(&config_path, versionless).unwrap();
+        assert_eq!(Repo::open(&repo_path).unwrap().config().format_version, 0);
+
+        assert_eq!(Repo::migrate(&repo_path).unwrap(), 0);
+        let repo = Repo::open(&repo_path).unwrap();
+        assert_eq!(repo.config().format_version, FORMAT_VERSION);
+
+        // already current: nothing to do
+        assert_eq!(Repo::migrate(&repo_path).unwrap(), FORMAT_VERSION);
+    }
 }
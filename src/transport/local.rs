@@ -1,50 +1,254 @@
 //! local file transport for repository operations
 
 use std::fs::{self, Permissions};
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use walkdir::WalkDir;
 
-use crate::error::{IoResultExt, Result};
+use nix::unistd::{Gid, Uid};
+
+use crate::error::{Error, IoResultExt, Result};
 use crate::hash::Hash;
+use crate::namespace::{mappings_equal, remap};
+use crate::object::{list_packed, packed_size, read_stored, with_packed, PackedKind};
+use crate::ops::ObjectType;
 use crate::repo::Repo;
+use crate::transport::retry::RetryPolicy;
 
 /// copy objects from source repo to destination repo
 pub fn copy_objects(src: &Repo, dst: &Repo, hashes: &ObjectSet) -> Result<TransferStats> {
+    copy_objects_with_progress(src, dst, hashes, None)
+}
+
+/// copy objects, calling `progress` after each one
+pub fn copy_objects_with_progress(
+    src: &Repo,
+    dst: &Repo,
+    hashes: &ObjectSet,
+    progress: Option<&mut dyn FnMut(TransferProgress)>,
+) -> Result<TransferStats> {
+    copy_objects_with_options(src, dst, hashes, &CopyOptions::default(), progress)
+}
+
+/// options for copying objects between local repositories
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CopyOptions<'a> {
+    /// hold copied bytes to this limiter's rate; hardlinked and skipped
+    /// objects move no data and aren't throttled
+    pub limiter: Option<&'a RateLimiter>,
+    /// times to retry an object copy failing with a transient I/O error
+    /// (interrupted, timed out, full or busy device, stale NFS handle),
+    /// backing off exponentially. other errors fail at once
+    pub max_retries: u32,
+    /// when the repos' namespace mappings differ, give each copied blob the
+    /// destination's outside owner for its inside owner; those blobs are
+    /// copied rather than hardlinked
+    pub remap_ownership: bool,
+    /// with `remap_ownership`, copy blobs whose owner either mapping lacks
+    /// unchanged instead of failing with `UnmappedUid`/`UnmappedGid`
+    pub keep_unmapped_owners: bool,
+}
+
+/// copy objects with `opts`, calling `progress` after each one
+pub fn copy_objects_with_options(
+    src: &Repo,
+    dst: &Repo,
+    hashes: &ObjectSet,
+    opts: &CopyOptions,
+    mut progress: Option<&mut dyn FnMut(TransferProgress)>,
+) -> Result<TransferStats> {
+    // trees and commits are only readable with the dictionary they were
+    // compressed with, so both sides must agree on it
+    let has_compressed = !hashes.trees.is_empty() || !hashes.commits.is_empty();
+    if has_compressed && src.zstd_dictionary() != dst.zstd_dictionary() {
+        return Err(crate::Error::Transport {
+            message: "repositories use different zstd dictionaries".to_string(),
+        });
+    }
+
+    let started = Instant::now();
     let mut stats = TransferStats::default();
+    let total = hashes.total_count();
+    let mut done = 0;
+
+    // only blobs carry ownership
+    let remap_blobs =
+        opts.remap_ownership && !mappings_equal(&src.config().namespace, &dst.config().namespace);
+    let kinds = [
+        (
+            src.blobs_path(),
+            dst.blobs_path(),
+            &hashes.blobs,
+            remap_blobs,
+            None,
+        ),
+        (
+            src.trees_path(),
+            dst.trees_path(),
+            &hashes.trees,
+            false,
+            Some(PackedKind::Tree),
+        ),
+        (
+            src.commits_path(),
+            dst.commits_path(),
+            &hashes.commits,
+            false,
+            Some(PackedKind::Commit),
+        ),
+        (
+            src.content_path(),
+            dst.content_path(),
+            &hashes.contents,
+            false,
+            None,
+        ),
+    ];
+    for (src_dir, dst_dir, list, remap_owner, packed) in kinds {
+        for hash in list {
+            let owner = match remap_owner {
+                true => remapped_owner(src, dst, &object_path(&src_dir, hash), opts)?,
+                false => None,
+            };
+            match packed {
+                Some(kind) if !object_path(&src_dir, hash).exists() => {
+                    copy_packed_object(src, kind, &dst_dir, hash, opts, &mut stats)?
+                }
+                _ => copy_object(&src_dir, &dst_dir, hash, owner, opts, &mut stats)?,
+            }
+            done += 1;
+            report_progress(&mut progress, done, total, &stats);
+        }
+    }
+
+    stats.elapsed = started.elapsed();
+    Ok(stats)
+}
+
+/// token bucket capping bytes per second, for transfers and scrub
+///
+/// the bucket holds one second's worth of bytes, so short bursts go through
+/// at full speed. accounting happens under a lock: copiers sharing one
+/// limiter across threads are held to its rate together.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// bytes that may go out now; negative while earlier consumers sleep off debt
+    tokens: f64,
+    refilled: Instant,
+}
 
-    // copy blobs
-    for hash in &hashes.blobs {
-        copy_object(&src.blobs_path(), &dst.blobs_path(), hash, &mut stats)?;
+impl RateLimiter {
+    /// a limiter for `bytes_per_sec` (at least 1), starting with a full bucket
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        RateLimiter {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                refilled: Instant::now(),
+            }),
+        }
     }
 
-    // copy trees
-    for hash in &hashes.trees {
-        copy_object(&src.trees_path(), &dst.trees_path(), hash, &mut stats)?;
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
     }
 
-    // copy commits
-    for hash in &hashes.commits {
-        copy_object(&src.commits_path(), &dst.commits_path(), hash, &mut stats)?;
+    /// take `bytes` from the bucket, sleeping until the rate allows them
+    pub fn consume(&self, bytes: u64) {
+        let rate = self.bytes_per_sec as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+            bucket.refilled = now;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            thread::sleep(wait);
+        }
     }
+}
 
-    Ok(stats)
+/// progress of an object transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// objects handled so far, including ones the destination already had
+    pub objects_done: usize,
+    pub objects_total: usize,
+    pub bytes_transferred: u64,
 }
 
-/// copy a single object file
+/// call the progress callback, if any, with the current counts
+pub(crate) fn report_progress(
+    progress: &mut Option<&mut dyn FnMut(TransferProgress)>,
+    objects_done: usize,
+    objects_total: usize,
+    stats: &TransferStats,
+) {
+    if let Some(progress) = progress {
+        progress(TransferProgress {
+            objects_done,
+            objects_total,
+            bytes_transferred: stats.bytes_transferred,
+        });
+    }
+}
+
+fn object_path(dir: &Path, hash: &Hash) -> PathBuf {
+    let hex = hash.to_hex();
+    dir.join(&hex[..2]).join(&hex[2..])
+}
+
+/// the outside (uid, gid) `dst` stores a blob of `src` under, if it differs
+/// from the one the blob has in `src`
+fn remapped_owner(
+    src: &Repo,
+    dst: &Repo,
+    src_path: &Path,
+    opts: &CopyOptions,
+) -> Result<Option<(u32, u32)>> {
+    let meta = fs::symlink_metadata(src_path).with_path(src_path)?;
+    let (uid, gid) = (meta.uid(), meta.gid());
+    let src_ns = &src.config().namespace;
+    let dst_ns = &dst.config().namespace;
+    let new_uid = remap(uid, &src_ns.uid_map, &dst_ns.uid_map);
+    let new_gid = remap(gid, &src_ns.gid_map, &dst_ns.gid_map);
+    let owner = match (new_uid, new_gid) {
+        (Some(new_uid), Some(new_gid)) => (new_uid, new_gid),
+        _ if opts.keep_unmapped_owners => return Ok(None),
+        (None, _) => return Err(Error::UnmappedUid(uid)),
+        (_, None) => return Err(Error::UnmappedGid(gid)),
+    };
+    Ok((owner != (uid, gid)).then_some(owner))
+}
+
+/// copy a single object file, giving it `owner` if set
 fn copy_object(
     src_dir: &Path,
     dst_dir: &Path,
     hash: &Hash,
+    owner: Option<(u32, u32)>,
+    opts: &CopyOptions,
     stats: &mut TransferStats,
 ) -> Result<()> {
-    let hex = hash.to_hex();
-    let prefix = &hex[..2];
-    let suffix = &hex[2..];
-
-    let src_path = src_dir.join(prefix).join(suffix);
-    let dst_path = dst_dir.join(prefix).join(suffix);
+    let src_path = object_path(src_dir, hash);
+    let dst_path = object_path(dst_dir, hash);
 
     if dst_path.exists() {
         stats.skipped += 1;
@@ -56,39 +260,186 @@ fn copy_object(
         fs::create_dir_all(parent).with_path(parent)?;
     }
 
-    // try hardlink first (same filesystem), fall back to copy
-    if fs::hard_link(&src_path, &dst_path).is_ok() {
+    // try hardlink first (same filesystem), fall back to copy. a blob
+    // changing owner needs its own inode
+    if owner.is_none() && hardlink_object(&src_path, &dst_path) {
         stats.hardlinked += 1;
     } else {
-        copy_object_file(&src_path, &dst_path, stats)?;
+        RetryPolicy::new(opts.max_retries)
+            .run(|| copy_object_file(&src_path, &dst_path, opts.limiter, stats))?;
+    }
+
+    if let Some((uid, gid)) = owner {
+        set_owner(&dst_path, uid, gid)?;
+        stats.remapped += 1;
     }
 
     Ok(())
 }
 
-fn copy_object_file(src_path: &Path, dst_path: &Path, stats: &mut TransferStats) -> Result<()> {
+/// copy a packed tree or commit out to a loose file in the destination
+fn copy_packed_object(
+    src: &Repo,
+    kind: PackedKind,
+    dst_dir: &Path,
+    hash: &Hash,
+    opts: &CopyOptions,
+    stats: &mut TransferStats,
+) -> Result<()> {
+    let dst_path = object_path(dst_dir, hash);
+    if dst_path.exists() {
+        stats.skipped += 1;
+        return Ok(());
+    }
+    if let Some(parent) = dst_path.parent() {
+        fs::create_dir_all(parent).with_path(parent)?;
+    }
+
+    let content = RetryPolicy::new(opts.max_retries).run(|| read_stored(src, kind, hash))?;
+    if let Some(limiter) = opts.limiter {
+        limiter.consume(content.len() as u64);
+    }
+    fs::write(&dst_path, &content).with_path(&dst_path)?;
+    stats.bytes_transferred += content.len() as u64;
+    stats.copied += 1;
+    Ok(())
+}
+
+/// chown a copied object, then restore the setuid/setgid bits chown clears
+fn set_owner(path: &Path, uid: u32, gid: u32) -> Result<()> {
+    let mode = fs::symlink_metadata(path).with_path(path)?.mode() & 0o7777;
+    nix::unistd::chown(path, Some(Uid::from_raw(uid)), Some(Gid::from_raw(gid))).map_err(|e| {
+        Error::Io {
+            path: path.to_path_buf(),
+            source: std::io::Error::from(e),
+        }
+    })?;
+    fs::set_permissions(path, Permissions::from_mode(mode)).with_path(path)
+}
+
+fn hardlink_object(src_path: &Path, dst_path: &Path) -> bool {
+    #[cfg(test)]
+    if tests::hardlinks_disabled(dst_path) {
+        return false;
+    }
+    fs::hard_link(src_path, dst_path).is_ok()
+}
+
+fn copy_object_file(
+    src_path: &Path,
+    dst_path: &Path,
+    limiter: Option<&RateLimiter>,
+    stats: &mut TransferStats,
+) -> Result<()> {
     let content = fs::read(src_path).with_path(src_path)?;
+    if let Some(limiter) = limiter {
+        limiter.consume(content.len() as u64);
+    }
     let mode = fs::metadata(src_path)
         .with_path(src_path)?
         .permissions()
         .mode()
         & 0o7777;
-    stats.bytes_transferred += content.len() as u64;
     fs::write(dst_path, &content).with_path(dst_path)?;
     fs::set_permissions(dst_path, Permissions::from_mode(mode)).with_path(dst_path)?;
+    stats.bytes_transferred += content.len() as u64;
     stats.copied += 1;
     Ok(())
 }
 
 /// list all objects in a repository
 pub fn list_all_objects(repo: &Repo) -> Result<ObjectSet> {
+    let trees = list_objects_in_dir(&repo.trees_path())?;
+    let commits = list_objects_in_dir(&repo.commits_path())?;
     Ok(ObjectSet {
         blobs: list_objects_in_dir(&repo.blobs_path())?,
-        trees: list_objects_in_dir(&repo.trees_path())?,
-        commits: list_objects_in_dir(&repo.commits_path())?,
+        trees: with_packed(repo, trees, PackedKind::Tree)?,
+        commits: with_packed(repo, commits, PackedKind::Commit)?,
+        contents: list_objects_in_dir(&repo.content_path())?,
     })
 }
 
+/// count the blobs, trees and commits in a repository without listing them
+pub fn count_all_objects(repo: &Repo) -> Result<(usize, usize, usize)> {
+    Ok((
+        iter_objects(repo, ObjectType::Blob)?.count(),
+        iter_objects(repo, ObjectType::Tree)?.count(),
+        iter_objects(repo, ObjectType::Commit)?.count(),
+    ))
+}
+
+/// directory holding the loose objects of one type
+fn object_dir(repo: &Repo, kind: ObjectType) -> PathBuf {
+    match kind {
+        ObjectType::Blob => repo.blobs_path(),
+        ObjectType::Tree => repo.trees_path(),
+        ObjectType::Commit => repo.commits_path(),
+        ObjectType::Content => repo.content_path(),
+    }
+}
+
+/// stream the hashes of one type of object, for callers that don't need the
+/// whole list at once; unreadable loose entries are skipped
+///
+/// loose objects come first, then packed ones without a loose copy.
+pub fn iter_objects(repo: &Repo, kind: ObjectType) -> Result<impl Iterator<Item = Hash> + '_> {
+    let packed = match kind.packed_kind() {
+        Some(packed) => Some((packed, list_packed(repo, packed)?)),
+        None => None,
+    };
+    let loose = WalkDir::new(object_dir(repo, kind))
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| hash_from_object_path(entry.path()));
+    let packed = packed.into_iter().flat_map(move |(packed, hashes)| {
+        hashes
+            .into_iter()
+            .filter(move |hash| !packed.loose_path(repo, hash).exists())
+    });
+    Ok(loose.chain(packed))
+}
+
+/// whether `repo` stores an object, loose or packed
+fn has_object(repo: &Repo, kind: ObjectType, hash: &Hash) -> Result<bool> {
+    if object_path(&object_dir(repo, kind), hash).exists() {
+        return Ok(true);
+    }
+    match kind.packed_kind() {
+        Some(packed) => Ok(packed_size(repo, packed, hash)?.is_some()),
+        None => Ok(false),
+    }
+}
+
+/// drop the objects `repo` already has from `set`, looking each one up
+/// rather than listing its store
+pub(crate) fn retain_missing(repo: &Repo, set: &mut ObjectSet) -> Result<()> {
+    for (kind, hashes) in [
+        (ObjectType::Blob, &mut set.blobs),
+        (ObjectType::Tree, &mut set.trees),
+        (ObjectType::Commit, &mut set.commits),
+        (ObjectType::Content, &mut set.contents),
+    ] {
+        let mut missing = Vec::with_capacity(hashes.len());
+        for hash in hashes.drain(..) {
+            if !has_object(repo, kind, &hash)? {
+                missing.push(hash);
+            }
+        }
+        *hashes = missing;
+    }
+    Ok(())
+}
+
+/// the hash named by an object's `XX/YYYY...` path
+fn hash_from_object_path(path: &Path) -> Option<Hash> {
+    let file_name = path.file_name()?.to_str()?;
+    let parent_name = path.parent()?.file_name()?.to_str()?;
+    Hash::from_hex(&format!("{}{}", parent_name, file_name)).ok()
+}
+
 /// list objects in a directory
 fn list_objects_in_dir(dir: &Path) -> Result<Vec<Hash>> {
     let mut hashes = Vec::new();
@@ -108,17 +459,7 @@ fn list_objects_in_dir(dir: &Path) -> Result<Vec<Hash>> {
         if !entry.file_type().is_file() {
             continue;
         }
-
-        let path = entry.path();
-        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-        let parent_name = path
-            .parent()
-            .and_then(|p| p.file_name())
-            .and_then(|n| n.to_str())
-            .unwrap_or("");
-
-        let hex = format!("{}{}", parent_name, file_name);
-        if let Ok(hash) = Hash::from_hex(&hex) {
+        if let Some(hash) = hash_from_object_path(entry.path()) {
             hashes.push(hash);
         }
     }
@@ -132,6 +473,8 @@ pub struct ObjectSet {
     pub blobs: Vec<Hash>,
     pub trees: Vec<Hash>,
     pub commits: Vec<Hash>,
+    /// content objects (content-addressed blobs)
+    pub contents: Vec<Hash>,
 }
 
 impl ObjectSet {
@@ -140,11 +483,14 @@ impl ObjectSet {
     }
 
     pub fn is_empty(&self) -> bool {
-        self.blobs.is_empty() && self.trees.is_empty() && self.commits.is_empty()
+        self.blobs.is_empty()
+            && self.trees.is_empty()
+            && self.commits.is_empty()
+            && self.contents.is_empty()
     }
 
     pub fn total_count(&self) -> usize {
-        self.blobs.len() + self.trees.len() + self.commits.len()
+        self.blobs.len() + self.trees.len() + self.commits.len() + self.contents.len()
     }
 }
 
@@ -155,14 +501,45 @@ pub struct TransferStats {
     pub hardlinked: usize,
     pub skipped: usize,
     pub bytes_transferred: u64,
+    /// blobs given a new owner for the destination (`remap_ownership`)
+    pub remapped: usize,
+    /// wall time spent moving objects
+    pub elapsed: Duration,
+}
+
+impl TransferStats {
+    /// effective throughput of `bytes_transferred` over `elapsed`
+    pub fn bytes_per_sec(&self) -> u64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            (self.bytes_transferred as f64 / secs) as u64
+        } else {
+            0
+        }
+    }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use crate::ops::commit;
+    use std::path::PathBuf;
     use tempfile::tempdir;
 
+    /// roots under which objects are always copied, as across filesystems
+    static NO_HARDLINK_ROOTS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+    /// make transfers into `root` copy instead of hardlinking, for the rest of
+    /// the test run
+    pub(crate) fn without_hardlinks(root: &Path) {
+        NO_HARDLINK_ROOTS.lock().unwrap().push(root.to_path_buf());
+    }
+
+    pub(crate) fn hardlinks_disabled(path: &Path) -> bool {
+        let roots = NO_HARDLINK_ROOTS.lock().unwrap_or_else(|e| e.into_inner());
+        roots.iter().any(|root| path.starts_with(root))
+    }
+
     #[test]
     fn test_list_objects() {
         let dir = tempdir().unwrap();
@@ -181,6 +558,74 @@ mod tests {
         assert!(!objects.commits.is_empty());
     }
 
+    #[test]
+    fn test_count_and_iter_objects() {
+        let dir = tempdir().unwrap();
+        let repo = Repo::init(&dir.path().join("repo")).unwrap();
+
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a.txt"), "a").unwrap();
+        fs::write(source.join("sub/b.txt"), "b").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+        fs::write(source.join("a.txt"), "changed").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+
+        let objects = list_all_objects(&repo).unwrap();
+        assert_eq!(
+            count_all_objects(&repo).unwrap(),
+            (
+                objects.blobs.len(),
+                objects.trees.len(),
+                objects.commits.len()
+            )
+        );
+        assert_eq!(objects.commits.len(), 2);
+
+        let mut trees: Vec<_> = iter_objects(&repo, ObjectType::Tree).unwrap().collect();
+        let mut listed = objects.trees.clone();
+        trees.sort();
+        listed.sort();
+        assert_eq!(trees, listed);
+    }
+
+    #[test]
+    fn test_retain_missing() {
+        use crate::object::pack::tests::pack_loose;
+
+        let dir = tempdir().unwrap();
+        let repo = Repo::init(&dir.path().join("repo")).unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+        commit(&repo, &source, "test", None, None).unwrap();
+        pack_loose(&repo);
+
+        // loose blobs and packed trees and commits are all found
+        let stored = list_all_objects(&repo).unwrap();
+        let absent = Hash::from_bytes([7; 32]);
+        let mut set = stored.clone();
+        set.blobs.push(absent);
+        set.trees.push(absent);
+        set.contents.push(absent);
+        retain_missing(&repo, &mut set).unwrap();
+        assert_eq!(set.blobs, vec![absent]);
+        assert_eq!(set.trees, vec![absent]);
+        assert!(set.commits.is_empty());
+        assert_eq!(set.contents, vec![absent]);
+
+        // an unreadable pack index fails the lookup rather than passing as missing
+        fs::write(repo.packs_path().join("broken.idx"), "garbage").unwrap();
+        let mut set = ObjectSet {
+            trees: vec![absent],
+            ..Default::default()
+        };
+        assert!(matches!(
+            retain_missing(&repo, &mut set),
+            Err(Error::CorruptPackIndex(_))
+        ));
+    }
+
     #[test]
     fn test_copy_objects() {
         let dir = tempdir().unwrap();
@@ -208,6 +653,35 @@ mod tests {
         assert_eq!(objects.commits.len(), dst_objects.commits.len());
     }
 
+    #[test]
+    fn test_copy_objects_progress() {
+        let dir = tempdir().unwrap();
+        let src = Repo::init(&dir.path().join("src_repo")).unwrap();
+        let dst = Repo::init(&dir.path().join("dst_repo")).unwrap();
+
+        // 3 blobs, 2 trees, 1 commit
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("a"), "a").unwrap();
+        fs::write(source.join("b"), "b").unwrap();
+        fs::write(source.join("sub/c"), "c").unwrap();
+        commit(&src, &source, "test", None, None).unwrap();
+
+        let objects = list_all_objects(&src).unwrap();
+        assert_eq!(objects.total_count(), 6);
+
+        let mut updates = Vec::new();
+        let mut progress = |p: TransferProgress| updates.push(p);
+        let stats = copy_objects_with_progress(&src, &dst, &objects, Some(&mut progress)).unwrap();
+
+        assert_eq!(updates.len(), 6);
+        for (i, update) in updates.iter().enumerate() {
+            assert_eq!(update.objects_done, i + 1);
+            assert_eq!(update.objects_total, 6);
+        }
+        assert_eq!(updates[5].bytes_transferred, stats.bytes_transferred);
+    }
+
     #[test]
     fn test_copy_object_file_preserves_object_modes() {
         let dir = tempdir().unwrap();
@@ -218,11 +692,59 @@ mod tests {
         fs::set_permissions(&src_blob, Permissions::from_mode(0o755)).unwrap();
 
         let mut stats = TransferStats::default();
-        copy_object_file(&src_blob, &dst_blob, &mut stats).unwrap();
+        copy_object_file(&src_blob, &dst_blob, None, &mut stats).unwrap();
         assert_eq!(stats.bytes_transferred, 7);
         assert_eq!(stats.copied, 1);
 
         let copied_mode = fs::metadata(dst_blob).unwrap().permissions().mode() & 0o7777;
         assert_eq!(copied_mode, 0o755);
     }
+
+    #[test]
+    fn test_copy_objects_rate_limited() {
+        let dir = tempdir().unwrap();
+        let src = Repo::init(&dir.path().join("src_repo")).unwrap();
+        let dst = Repo::init(&dir.path().join("dst_repo")).unwrap();
+        without_hardlinks(dst.path());
+
+        // 150 KiB of incompressible content in three files
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        let mut state = 0x9e3779b97f4a7c15u64;
+        for name in ["a", "b", "c"] {
+            let data: Vec<u8> = (0..50 * 1024)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect();
+            fs::write(source.join(name), data).unwrap();
+        }
+        commit(&src, &source, "test", None, None).unwrap();
+        let objects = list_all_objects(&src).unwrap();
+
+        // a full bucket covers the first 100 KB; the rest takes ~half a second
+        let limiter = RateLimiter::new(100_000);
+        let started = Instant::now();
+        let opts = CopyOptions {
+            limiter: Some(&limiter),
+            ..Default::default()
+        };
+        let stats = copy_objects_with_options(&src, &dst, &objects, &opts, None).unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(stats.copied, objects.total_count());
+        assert_eq!(stats.hardlinked, 0);
+        assert!(stats.bytes_transferred > 150 * 1024);
+        assert!(elapsed >= Duration::from_millis(400), "took {:?}", elapsed);
+        assert!(stats.elapsed >= Duration::from_millis(400));
+        // the initial burst is counted in the effective rate
+        let rate = stats.bytes_per_sec();
+        assert!(rate > 0 && rate < stats.bytes_transferred * 1000 / 400);
+
+        let dst_objects = list_all_objects(&dst).unwrap();
+        assert_eq!(dst_objects.total_count(), objects.total_count());
+    }
 }
@@ -3,11 +3,24 @@
 pub mod local;
 pub mod pull;
 pub mod push;
+mod retry;
 pub mod serve;
 pub mod ssh;
+mod wire;
 
-pub use local::{copy_objects, list_all_objects, ObjectSet, TransferStats};
-pub use pull::{pull_local, pull_ssh, PullOptions, PullResult};
-pub use push::{push_local, push_ssh, PushOptions, PushResult};
+pub use local::{
+    copy_objects, copy_objects_with_options, copy_objects_with_progress, count_all_objects,
+    iter_objects, list_all_objects, CopyOptions, ObjectSet, RateLimiter, TransferProgress,
+    TransferStats,
+};
+pub use pull::{
+    pull_into, pull_into_with_progress, pull_local, pull_local_with_progress, pull_refs,
+    pull_refs_matching, pull_refs_matching_ssh, pull_refs_ssh, pull_ssh, pull_ssh_into,
+    pull_ssh_into_with_progress, pull_ssh_with_progress, tracking_ref_name, PullOptions,
+    PullRefsResult, PullResult,
+};
+pub use push::{
+    push_local, push_local_with_progress, push_ssh, push_ssh_with_progress, PushOptions, PushResult,
+};
 pub use serve::serve_remote;
 pub use ssh::SshConnection;
@@ -1,17 +1,27 @@
 //! pull operation - fetch objects from remote
 
 use std::collections::HashSet;
-use std::fs::{self, Permissions};
+use std::fs::{self, File, Permissions};
+use std::io::Write;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
 
-use crate::error::{IoResultExt, Result};
+use crate::error::{Error, IoResultExt, Result};
+use crate::fs::{fsync_dir, TempFile};
 use crate::hash::Hash;
-use crate::object::{read_commit, read_tree};
-use crate::refs::{read_ref, write_ref};
+use crate::object::{blob_file_hash, commit_exists, read_commit, read_tree};
+use crate::refs::{list_refs_matching, read_ref, write_ref, RefTransaction};
 use crate::repo::Repo;
-use crate::transport::local::{copy_objects, list_all_objects, ObjectSet, TransferStats};
-use crate::transport::ssh::SshConnection;
+use crate::transport::local::{
+    copy_objects_with_options, list_all_objects, report_progress, retain_missing, CopyOptions,
+    ObjectSet, RateLimiter, TransferProgress, TransferStats,
+};
+use crate::transport::retry::RetryPolicy;
+use crate::transport::ssh::{ReceivedObject, SshConnection};
 use crate::types::EntryKind;
 
 /// pull options
@@ -21,6 +31,24 @@ pub struct PullOptions {
     pub fetch_only: bool,
     /// dry run - show what would be transferred without doing it
     pub dry_run: bool,
+    /// only update refs, transferring no objects (they arrive some other way)
+    pub refs_only: bool,
+    /// with `refs_only`, update refs whose commit isn't present locally
+    pub allow_missing: bool,
+    /// with `pull_refs_matching`, delete local refs matching the pattern
+    /// that the source no longer has
+    pub mirror: bool,
+    /// cap on bytes received per second; hardlinked objects don't count
+    pub rate_limit_bytes_per_sec: Option<u64>,
+    /// times to retry an object transfer or connection read/write failing
+    /// with a transient I/O error, backing off exponentially
+    pub max_retries: u32,
+    /// between local repos with different namespace mappings, store copied
+    /// blobs under the destination's outside owner for their inside owner
+    pub remap_ownership: bool,
+    /// with `remap_ownership`, keep the owner of blobs it can't map instead
+    /// of failing
+    pub keep_unmapped_owners: bool,
 }
 
 /// pull a ref from a local repository
@@ -30,37 +58,94 @@ pub fn pull_local(
     ref_name: &str,
     options: &PullOptions,
 ) -> Result<PullResult> {
-    let src_hash = read_ref(src, ref_name)?;
+    pull_local_with_progress(src, dst, ref_name, options, None)
+}
 
-    // collect all objects reachable from the commit
-    let mut needed = ObjectSet::new();
-    collect_commit_objects(src, &src_hash, &mut needed, &mut HashSet::new())?;
+/// pull a ref from a local repository, calling `progress` after each object
+pub fn pull_local_with_progress(
+    src: &Repo,
+    dst: &Repo,
+    ref_name: &str,
+    options: &PullOptions,
+    progress: Option<&mut dyn FnMut(TransferProgress)>,
+) -> Result<PullResult> {
+    pull_local_to(src, dst, ref_name, ref_name, options, progress)
+}
 
-    // filter out objects we already have
-    let existing = list_all_objects(dst)?;
-    let existing_blobs: HashSet<_> = existing.blobs.into_iter().collect();
-    let existing_trees: HashSet<_> = existing.trees.into_iter().collect();
-    let existing_commits: HashSet<_> = existing.commits.into_iter().collect();
+/// pull a ref from a local repository into the remote-tracking ref
+/// `<dst_ref_prefix>/<ref_name>`, leaving the local ref of that name alone
+pub fn pull_into(
+    src: &Repo,
+    dst: &Repo,
+    ref_name: &str,
+    dst_ref_prefix: &str,
+    options: &PullOptions,
+) -> Result<PullResult> {
+    pull_into_with_progress(src, dst, ref_name, dst_ref_prefix, options, None)
+}
+
+/// `pull_into`, calling `progress` after each object
+pub fn pull_into_with_progress(
+    src: &Repo,
+    dst: &Repo,
+    ref_name: &str,
+    dst_ref_prefix: &str,
+    options: &PullOptions,
+    progress: Option<&mut dyn FnMut(TransferProgress)>,
+) -> Result<PullResult> {
+    let dst_ref = tracking_ref_name(dst_ref_prefix, ref_name);
+    pull_local_to(src, dst, ref_name, &dst_ref, options, progress)
+}
+
+/// name of the ref `pull_into` writes for `ref_name`
+pub fn tracking_ref_name(dst_ref_prefix: &str, ref_name: &str) -> String {
+    format!("{}/{}", dst_ref_prefix.trim_end_matches('/'), ref_name)
+}
+
+/// pull `ref_name` from a local repository, writing its tip to `dst_ref`
+fn pull_local_to(
+    src: &Repo,
+    dst: &Repo,
+    ref_name: &str,
+    dst_ref: &str,
+    options: &PullOptions,
+    progress: Option<&mut dyn FnMut(TransferProgress)>,
+) -> Result<PullResult> {
+    let src_hash = read_ref(src, ref_name)?;
+
+    if options.refs_only {
+        update_ref_only(dst, dst_ref, &src_hash, options)?;
+        return Ok(PullResult {
+            hash: src_hash,
+            stats: TransferStats::default(),
+            objects_to_transfer: 0,
+        });
+    }
 
-    needed.blobs.retain(|h| !existing_blobs.contains(h));
-    needed.trees.retain(|h| !existing_trees.contains(h));
-    needed.commits.retain(|h| !existing_commits.contains(h));
+    let needed = missing_objects(src, dst, &[src_hash])?;
 
     // dry run: return what would be transferred without doing anything
     if options.dry_run {
         return Ok(PullResult {
             hash: src_hash,
             stats: TransferStats::default(),
-            objects_to_transfer: needed.blobs.len() + needed.trees.len() + needed.commits.len(),
+            objects_to_transfer: needed.total_count(),
         });
     }
 
     // copy needed objects
-    let stats = copy_objects(src, dst, &needed)?;
+    let limiter = options.rate_limit_bytes_per_sec.map(RateLimiter::new);
+    let copy = CopyOptions {
+        limiter: limiter.as_ref(),
+        max_retries: options.max_retries,
+        remap_ownership: options.remap_ownership,
+        keep_unmapped_owners: options.keep_unmapped_owners,
+    };
+    let stats = copy_objects_with_options(src, dst, &needed, &copy, progress)?;
 
     // update ref
     if !options.fetch_only {
-        write_ref(dst, ref_name, &src_hash)?;
+        write_ref(dst, dst_ref, &src_hash)?;
     }
 
     Ok(PullResult {
@@ -77,18 +162,105 @@ pub fn pull_ssh(
     local: &Repo,
     ref_name: &str,
     options: &PullOptions,
+) -> Result<PullResult> {
+    pull_ssh_with_progress(remote, remote_path, local, ref_name, options, None)
+}
+
+/// pull a ref via SSH, calling `progress` after each object received
+pub fn pull_ssh_with_progress(
+    remote: &str,
+    remote_path: &Path,
+    local: &Repo,
+    ref_name: &str,
+    options: &PullOptions,
+    progress: Option<&mut dyn FnMut(TransferProgress)>,
+) -> Result<PullResult> {
+    pull_ssh_to(
+        remote,
+        remote_path,
+        local,
+        ref_name,
+        ref_name,
+        options,
+        progress,
+    )
+}
+
+/// `pull_into` from a remote repository via SSH
+pub fn pull_ssh_into(
+    remote: &str,
+    remote_path: &Path,
+    local: &Repo,
+    ref_name: &str,
+    dst_ref_prefix: &str,
+    options: &PullOptions,
+) -> Result<PullResult> {
+    pull_ssh_into_with_progress(
+        remote,
+        remote_path,
+        local,
+        ref_name,
+        dst_ref_prefix,
+        options,
+        None,
+    )
+}
+
+/// `pull_ssh_into`, calling `progress` after each object received
+pub fn pull_ssh_into_with_progress(
+    remote: &str,
+    remote_path: &Path,
+    local: &Repo,
+    ref_name: &str,
+    dst_ref_prefix: &str,
+    options: &PullOptions,
+    progress: Option<&mut dyn FnMut(TransferProgress)>,
+) -> Result<PullResult> {
+    let dst_ref = tracking_ref_name(dst_ref_prefix, ref_name);
+    pull_ssh_to(
+        remote,
+        remote_path,
+        local,
+        ref_name,
+        &dst_ref,
+        options,
+        progress,
+    )
+}
+
+/// pull `ref_name` via SSH, writing its tip to `dst_ref`
+fn pull_ssh_to(
+    remote: &str,
+    remote_path: &Path,
+    local: &Repo,
+    ref_name: &str,
+    dst_ref: &str,
+    options: &PullOptions,
+    progress: Option<&mut dyn FnMut(TransferProgress)>,
 ) -> Result<PullResult> {
     let mut conn = SshConnection::connect(remote, remote_path)?;
+    conn.set_max_retries(options.max_retries);
 
     // get ref from remote
     let remote_hash = conn
         .get_ref(ref_name)?
         .ok_or_else(|| crate::Error::RefNotFound(ref_name.to_string()))?;
 
+    if options.refs_only {
+        conn.close()?;
+        update_ref_only(local, dst_ref, &remote_hash, options)?;
+        return Ok(PullResult {
+            hash: remote_hash,
+            stats: TransferStats::default(),
+            objects_to_transfer: 0,
+        });
+    }
+
     // collect what we have
     let existing = list_all_objects(local)?;
 
     // ask remote what we need
+    conn.check_dictionary(local)?;
     let needed = conn.have_objects(&existing)?;
 
     // dry run: return what would be transferred without doing anything
@@ -97,53 +269,466 @@ pub fn pull_ssh(
         return Ok(PullResult {
             hash: remote_hash,
             stats: TransferStats::default(),
-            objects_to_transfer: needed.blobs.len() + needed.trees.len() + needed.commits.len(),
+            objects_to_transfer: needed.total_count(),
         });
     }
 
-    // receive objects
+    let stats = receive_objects(&mut conn, local, needed.total_count(), options, progress)?;
+
+    // update ref
+    if !options.fetch_only {
+        write_ref(local, dst_ref, &remote_hash)?;
+    }
+
+    conn.close()?;
+
+    Ok(PullResult {
+        hash: remote_hash,
+        stats,
+        objects_to_transfer: 0,
+    })
+}
+
+/// bytes of received objects held in memory between the reader and the writers
+const RECEIVE_BUFFER_BYTES: u64 = 64 * 1024 * 1024;
+
+/// threads storing received objects
+const RECEIVE_WRITERS: usize = 4;
+
+/// store the objects the remote sends after `have_objects`
+fn receive_objects(
+    conn: &mut SshConnection,
+    local: &Repo,
+    total: usize,
+    options: &PullOptions,
+    progress: Option<&mut dyn FnMut(TransferProgress)>,
+) -> Result<TransferStats> {
+    let in_flight = InFlight::new(RECEIVE_BUFFER_BYTES);
+    receive_pipelined(
+        || conn.receive_object(),
+        local,
+        total,
+        options,
+        progress,
+        &in_flight,
+    )
+}
+
+/// caps the bytes of objects read off the wire but not yet stored
+struct InFlight {
+    limit: u64,
+    state: Mutex<InFlightState>,
+    freed: Condvar,
+}
+
+#[derive(Default)]
+struct InFlightState {
+    bytes: u64,
+    peak: u64,
+}
+
+impl InFlight {
+    fn new(limit: u64) -> Self {
+        InFlight {
+            limit,
+            state: Mutex::new(InFlightState::default()),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// wait until `bytes` more fit; an object over the limit waits for an empty queue
+    fn acquire(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        while state.bytes > 0 && state.bytes + bytes > self.limit {
+            state = self.freed.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state.bytes += bytes;
+        state.peak = state.peak.max(state.bytes);
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.bytes -= bytes;
+        self.freed.notify_all();
+    }
+
+    /// the most bytes ever held at once
+    #[cfg(test)]
+    fn peak(&self) -> u64 {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).peak
+    }
+}
+
+/// a received object on its way to disk
+struct Received {
+    path: PathBuf,
+    data: Vec<u8>,
+    /// permissions to restore, for blobs
+    mode: Option<u32>,
+}
+
+/// store objects from `next` on writer threads while a reader thread keeps decoding
+///
+/// the reader stops once `in_flight` is full, until writers catch up, so the
+/// remote is slowed by the pipe filling up rather than memory growing. with a
+/// rate limit, reading also pauses after each object until the rate allows it.
+/// every object is on disk when this returns, so refs can be written after.
+fn receive_pipelined(
+    mut next: impl FnMut() -> Result<Option<ReceivedObject>> + Send,
+    local: &Repo,
+    total: usize,
+    options: &PullOptions,
+    mut progress: Option<&mut dyn FnMut(TransferProgress)>,
+    in_flight: &InFlight,
+) -> Result<TransferStats> {
+    let limiter = options.rate_limit_bytes_per_sec.map(RateLimiter::new);
+    let retry = RetryPolicy::new(options.max_retries);
+    let started = Instant::now();
     let mut stats = TransferStats::default();
+    let failed = AtomicBool::new(false);
+    let tmp_dir = local.objects_tmp_path();
+
+    let (queue_tx, queue_rx) = mpsc::sync_channel::<Received>(RECEIVE_WRITERS);
+    let queue_rx = Mutex::new(queue_rx);
+    let (done_tx, done_rx) = mpsc::channel::<Result<Option<u64>>>();
 
-    while let Some((obj_type, hash, data, mode)) = conn.receive_object()? {
-        let path = match obj_type.as_str() {
-            "blob" => object_path(&local.blobs_path(), &hash),
-            "tree" => object_path(&local.trees_path(), &hash),
-            "commit" => object_path(&local.commits_path(), &hash),
-            _ => continue,
+    let (read_result, write_error) = thread::scope(|scope| {
+        let reader = {
+            let (limiter, failed) = (&limiter, &failed);
+            scope.spawn(move || -> Result<()> {
+                while !failed.load(Ordering::Relaxed) {
+                    let Some((obj_type, hash, data, mode)) = next()? else {
+                        break;
+                    };
+                    if let Some(limiter) = limiter {
+                        limiter.consume(data.len() as u64);
+                    }
+                    let (path, mode) = match obj_type.as_str() {
+                        "blob" => (
+                            object_path(&local.blobs_path(), &hash),
+                            Some(mode).filter(|&mode| mode != 0),
+                        ),
+                        "tree" => (object_path(&local.trees_path(), &hash), None),
+                        "commit" => (object_path(&local.commits_path(), &hash), None),
+                        "content" => (object_path(&local.content_path(), &hash), None),
+                        _ => continue,
+                    };
+                    in_flight.acquire(data.len() as u64);
+                    if queue_tx.send(Received { path, data, mode }).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            })
         };
 
-        if !path.exists() {
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent).with_path(parent)?;
-            }
-            stats.bytes_transferred += data.len() as u64;
-            fs::write(&path, &data).with_path(&path)?;
-            // restore file permissions for blobs
-            if obj_type == "blob" && mode != 0 {
-                fs::set_permissions(&path, Permissions::from_mode(mode)).with_path(&path)?;
+        for _ in 0..RECEIVE_WRITERS {
+            let done_tx = done_tx.clone();
+            let (queue_rx, failed, retry, tmp_dir) = (&queue_rx, &failed, &retry, &tmp_dir);
+            scope.spawn(move || loop {
+                let received = queue_rx.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                let Ok(received) = received else {
+                    break;
+                };
+                let size = received.data.len() as u64;
+                // after a failure, keep draining so the reader never blocks
+                if !failed.load(Ordering::Relaxed) {
+                    let stored = store_received(retry, tmp_dir, received);
+                    if stored.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    let _ = done_tx.send(stored);
+                }
+                in_flight.release(size);
+            });
+        }
+        drop(done_tx);
+
+        let mut done = 0;
+        let mut write_error = None;
+        for stored in done_rx {
+            match stored {
+                Ok(Some(bytes)) => {
+                    stats.bytes_transferred += bytes;
+                    stats.copied += 1;
+                }
+                Ok(None) => stats.skipped += 1,
+                Err(e) => {
+                    write_error.get_or_insert(e);
+                    continue;
+                }
             }
-            stats.copied += 1;
+            done += 1;
+            report_progress(&mut progress, done, total, &stats);
+        }
+        let read_result = reader
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        (read_result, write_error)
+    });
+
+    read_result?;
+    if let Some(e) = write_error {
+        return Err(e);
+    }
+    stats.elapsed = started.elapsed();
+    Ok(stats)
+}
+
+/// write a received object unless it is already stored, returning the bytes written
+///
+/// the object is written and synced in `tmp_dir`, then renamed into place:
+/// since a stored object is never rewritten, a failed write must not leave a
+/// torn one behind.
+fn store_received(retry: &RetryPolicy, tmp_dir: &Path, received: Received) -> Result<Option<u64>> {
+    let Received { path, data, mode } = received;
+    if path.exists() {
+        return Ok(None);
+    }
+
+    let tmp = TempFile::new_in(tmp_dir);
+    retry.run(|| {
+        let mut file = File::create(tmp.path()).with_path(tmp.path())?;
+        file.write_all(&data).with_path(tmp.path())?;
+        file.sync_all().with_path(tmp.path())
+    })?;
+    if let Some(mode) = mode {
+        fs::set_permissions(tmp.path(), Permissions::from_mode(mode)).with_path(tmp.path())?;
+    }
+
+    let parent = path.parent().unwrap_or(tmp_dir);
+    fs::create_dir_all(parent).with_path(parent)?;
+    fs::rename(tmp.path(), &path).with_path(&path)?;
+    tmp.into_persisted();
+    fsync_dir(parent)?;
+    Ok(Some(data.len() as u64))
+}
+
+/// result of pulling every ref matching a pattern
+#[derive(Debug, Default)]
+pub struct PullRefsResult {
+    /// (ref name, hash) pairs updated, or that would be with `dry_run`
+    pub updated: Vec<(String, Hash)>,
+    /// local refs deleted by `mirror`, or that would be with `dry_run`
+    pub deleted: Vec<String>,
+    pub stats: TransferStats,
+    /// number of objects that would be transferred (for dry run)
+    pub objects_to_transfer: usize,
+}
+
+/// pull every ref matching `pattern` from a local repository
+///
+/// objects for all refs are copied in one pass, then the refs are updated.
+/// with `mirror`, refs in `dst` matching `pattern` that `src` no longer has
+/// are deleted; refs outside the pattern are never touched.
+pub fn pull_refs_matching(
+    src: &Repo,
+    dst: &Repo,
+    pattern: &str,
+    options: &PullOptions,
+) -> Result<PullRefsResult> {
+    let refs = list_refs_matching(src, pattern)?
+        .into_iter()
+        .map(|name| {
+            let hash = read_ref(src, &name)?;
+            Ok((name, hash))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut result = PullRefsResult::default();
+    if !options.refs_only {
+        let heads: Vec<Hash> = refs.iter().map(|(_, hash)| *hash).collect();
+        let needed = missing_objects(src, dst, &heads)?;
+        if options.dry_run {
+            result.objects_to_transfer = needed.total_count();
         } else {
-            stats.skipped += 1;
+            let limiter = options.rate_limit_bytes_per_sec.map(RateLimiter::new);
+            let copy = CopyOptions {
+                limiter: limiter.as_ref(),
+                max_retries: options.max_retries,
+                remap_ownership: options.remap_ownership,
+                keep_unmapped_owners: options.keep_unmapped_owners,
+            };
+            result.stats = copy_objects_with_options(src, dst, &needed, &copy, None)?;
         }
     }
+    finish_pull_refs(dst, pattern, refs, options, result)
+}
 
-    // update ref
-    if !options.fetch_only {
-        write_ref(local, ref_name, &remote_hash)?;
+/// pull every ref matching `pattern` from a remote repository via SSH
+///
+/// see `pull_refs_matching`; the remote sends every object we lack, as
+/// `pull_ssh` does.
+pub fn pull_refs_matching_ssh(
+    remote: &str,
+    remote_path: &Path,
+    local: &Repo,
+    pattern: &str,
+    options: &PullOptions,
+) -> Result<PullRefsResult> {
+    let glob = glob::Pattern::new(pattern).map_err(|e| Error::InvalidRef(e.to_string()))?;
+
+    let mut conn = SshConnection::connect(remote, remote_path)?;
+    conn.set_max_retries(options.max_retries);
+    let refs: Vec<(String, Hash)> = conn
+        .list_refs()?
+        .into_iter()
+        .filter(|(name, _)| glob.matches(name))
+        .collect();
+
+    let mut result = PullRefsResult::default();
+    if !options.refs_only {
+        conn.check_dictionary(local)?;
+        let existing = list_all_objects(local)?;
+        let needed = conn.have_objects(&existing)?;
+        if options.dry_run {
+            result.objects_to_transfer = needed.total_count();
+        } else {
+            result.stats = receive_objects(&mut conn, local, needed.total_count(), options, None)?;
+        }
+    }
+    conn.close()?;
+
+    finish_pull_refs(local, pattern, refs, options, result)
+}
+
+/// update the pulled refs and, with `mirror`, prune the ones gone upstream
+fn finish_pull_refs(
+    dst: &Repo,
+    pattern: &str,
+    refs: Vec<(String, Hash)>,
+    options: &PullOptions,
+    mut result: PullRefsResult,
+) -> Result<PullRefsResult> {
+    if options.fetch_only {
+        return Ok(result);
+    }
+
+    let mut refs = refs;
+    refs.sort();
+    if options.refs_only {
+        check_commits_present(dst, &refs, options)?;
+    }
+
+    if options.mirror {
+        // only refs the pattern selects are candidates for removal
+        let upstream: HashSet<&String> = refs.iter().map(|(name, _)| name).collect();
+        result.deleted = list_refs_matching(dst, pattern)?
+            .into_iter()
+            .filter(|name| !upstream.contains(name))
+            .collect();
+    }
+
+    // updates and removals land together or not at all
+    if !options.dry_run {
+        let mut tx = RefTransaction::new();
+        for (name, hash) in &refs {
+            tx.update(name, Some(*hash));
+        }
+        for name in &result.deleted {
+            tx.update(name, None);
+        }
+        tx.commit(dst)?;
     }
+    result.updated = refs;
+
+    Ok(result)
+}
+
+/// copy refs matching `pattern` from a local repository without transferring objects
+///
+/// each ref's commit must already exist in `dst` unless `allow_missing` is set.
+/// returns the (ref name, hash) pairs updated, or that would be with `dry_run`.
+pub fn pull_refs(
+    src: &Repo,
+    dst: &Repo,
+    pattern: &str,
+    options: &PullOptions,
+) -> Result<Vec<(String, Hash)>> {
+    let refs = list_refs_matching(src, pattern)?
+        .into_iter()
+        .map(|name| {
+            let hash = read_ref(src, &name)?;
+            Ok((name, hash))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    update_refs_only(dst, refs, options)
+}
+
+/// copy refs matching `pattern` from a remote repository via SSH without
+/// transferring objects
+pub fn pull_refs_ssh(
+    remote: &str,
+    remote_path: &Path,
+    local: &Repo,
+    pattern: &str,
+    options: &PullOptions,
+) -> Result<Vec<(String, Hash)>> {
+    let glob = glob::Pattern::new(pattern).map_err(|e| Error::InvalidRef(e.to_string()))?;
 
+    let mut conn = SshConnection::connect(remote, remote_path)?;
+    conn.set_max_retries(options.max_retries);
+    let refs = conn.list_refs()?;
     conn.close()?;
 
-    Ok(PullResult {
-        hash: remote_hash,
-        stats,
-        objects_to_transfer: 0,
-    })
+    let matching = refs
+        .into_iter()
+        .filter(|(name, _)| glob.matches(name))
+        .collect();
+    update_refs_only(local, matching, options)
+}
+
+/// check every ref before writing any, so a missing commit leaves `dst` untouched
+fn update_refs_only(
+    dst: &Repo,
+    mut refs: Vec<(String, Hash)>,
+    options: &PullOptions,
+) -> Result<Vec<(String, Hash)>> {
+    refs.sort();
+    check_commits_present(dst, &refs, options)?;
+    if !options.dry_run {
+        let mut tx = RefTransaction::new();
+        for (name, hash) in &refs {
+            tx.update(name, Some(*hash));
+        }
+        tx.commit(dst)?;
+    }
+    Ok(refs)
+}
+
+/// with `refs_only`, every commit must already be in `dst` unless
+/// `allow_missing` is set
+fn check_commits_present(dst: &Repo, refs: &[(String, Hash)], options: &PullOptions) -> Result<()> {
+    if !options.allow_missing {
+        if let Some((_, hash)) = refs.iter().find(|(_, hash)| !commit_exists(dst, hash)) {
+            return Err(Error::ObjectNotFound(*hash));
+        }
+    }
+    Ok(())
+}
+
+/// point a single ref at `hash` for a refs-only pull
+fn update_ref_only(dst: &Repo, ref_name: &str, hash: &Hash, options: &PullOptions) -> Result<()> {
+    update_refs_only(dst, vec![(ref_name.to_string(), *hash)], options).map(|_| ())
+}
+
+/// objects reachable from `commits` in `src` that `dst` doesn't have
+fn missing_objects(src: &Repo, dst: &Repo, commits: &[Hash]) -> Result<ObjectSet> {
+    // collect all objects reachable from the commits
+    let mut needed = ObjectSet::new();
+    let mut visited = HashSet::new();
+    for hash in commits {
+        collect_commit_objects(src, hash, &mut needed, &mut visited)?;
+    }
+
+    // filter out objects we already have
+    retain_missing(dst, &mut needed)?;
+
+    Ok(needed)
 }
 
 /// collect all objects reachable from a commit
-fn collect_commit_objects(
+pub(crate) fn collect_commit_objects(
     repo: &Repo,
     commit_hash: &Hash,
     objects: &mut ObjectSet,
@@ -187,10 +772,17 @@ fn collect_tree_objects(
 
     for entry in tree.entries() {
         match &entry.kind {
-            EntryKind::Regular { hash, .. } => {
-                if !visited.contains(hash) {
-                    visited.insert(*hash);
-                    objects.blobs.push(*hash);
+            EntryKind::Regular { hash, content, .. } => {
+                let file = blob_file_hash(hash, content.as_ref());
+                if !visited.contains(&file) {
+                    visited.insert(file);
+                    objects.blobs.push(file);
+                }
+                if let Some(content_hash) = content {
+                    if !visited.contains(content_hash) {
+                        visited.insert(*content_hash);
+                        objects.contents.push(*content_hash);
+                    }
                 }
             }
             EntryKind::Symlink { hash, .. } => {
@@ -209,7 +801,7 @@ fn collect_tree_objects(
     Ok(())
 }
 
-fn object_path(base: &Path, hash: &Hash) -> std::path::PathBuf {
+fn object_path(base: &Path, hash: &Hash) -> PathBuf {
     let hex = hash.to_hex();
     base.join(&hex[..2]).join(&hex[2..])
 }
@@ -271,7 +863,7 @@ mod tests {
 
         let options = PullOptions {
             fetch_only: true,
-            dry_run: false,
+            ..Default::default()
         };
         let result = pull_local(&src, &dst, "test", &options).unwrap();
 
@@ -281,6 +873,35 @@ mod tests {
         assert!(read_ref(&dst, "test").is_err());
     }
 
+    #[test]
+    fn test_pull_into_tracking_prefix() {
+        let dir = tempdir().unwrap();
+        let src = Repo::init(&dir.path().join("src_repo")).unwrap();
+        let dst = Repo::init(&dir.path().join("dst_repo")).unwrap();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "local").unwrap();
+        let local = commit(&dst, &source, "main", Some("local"), None).unwrap();
+        fs::write(source.join("file.txt"), "upstream").unwrap();
+        let upstream = commit(&src, &source, "main", Some("upstream"), None).unwrap();
+
+        let result = pull_into(
+            &src,
+            &dst,
+            "main",
+            "remotes/origin/",
+            &PullOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(result.hash, upstream);
+        assert_eq!(read_ref(&dst, "remotes/origin/main").unwrap(), upstream);
+        assert!(commit_exists(&dst, &upstream));
+
+        // the local ref of the same name is untouched
+        assert_eq!(read_ref(&dst, "main").unwrap(), local);
+    }
+
     #[test]
     fn test_pull_incremental() {
         let dir = tempdir().unwrap();
@@ -310,4 +931,232 @@ mod tests {
         // some objects should have been skipped (already exist)
         // note: exact counts depend on object sharing
     }
+
+    #[test]
+    fn test_pull_refs_only() {
+        let dir = tempdir().unwrap();
+        let src = Repo::init(&dir.path().join("src_repo")).unwrap();
+        let dst = Repo::init(&dir.path().join("dst_repo")).unwrap();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file.txt"), "content").unwrap();
+        let hash = commit(&src, &source, "main", Some("initial"), None).unwrap();
+        write_ref(&src, "release/v1", &hash).unwrap();
+
+        // objects arrive separately
+        let fetch = PullOptions {
+            fetch_only: true,
+            ..Default::default()
+        };
+        pull_local(&src, &dst, "main", &fetch).unwrap();
+        let objects_before = list_all_objects(&dst).unwrap().total_count();
+
+        let options = PullOptions {
+            refs_only: true,
+            ..Default::default()
+        };
+        let updated = pull_refs(&src, &dst, "*", &options).unwrap();
+
+        assert_eq!(
+            updated,
+            vec![("main".to_string(), hash), ("release/v1".to_string(), hash)]
+        );
+        assert_eq!(read_ref(&dst, "main").unwrap(), hash);
+        assert_eq!(read_ref(&dst, "release/v1").unwrap(), hash);
+        assert_eq!(
+            list_all_objects(&dst).unwrap().total_count(),
+            objects_before
+        );
+
+        // a commit dst doesn't have is refused unless allowed
+        fs::write(source.join("file.txt"), "v2").unwrap();
+        let hash2 = commit(&src, &source, "main", Some("v2"), None).unwrap();
+        assert!(matches!(
+            pull_local(&src, &dst, "main", &options),
+            Err(Error::ObjectNotFound(h)) if h == hash2
+        ));
+        assert_eq!(read_ref(&dst, "main").unwrap(), hash);
+
+        let allow = PullOptions {
+            allow_missing: true,
+            ..options
+        };
+        pull_local(&src, &dst, "main", &allow).unwrap();
+        assert_eq!(read_ref(&dst, "main").unwrap(), hash2);
+        assert_eq!(
+            list_all_objects(&dst).unwrap().total_count(),
+            objects_before
+        );
+    }
+
+    #[test]
+    fn test_pull_mirror() {
+        let dir = tempdir().unwrap();
+        let src = Repo::init(&dir.path().join("src_repo")).unwrap();
+        let dst = Repo::init(&dir.path().join("dst_repo")).unwrap();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        for name in ["pkg/a", "pkg/b", "pkg/c"] {
+            fs::write(source.join("file.txt"), name).unwrap();
+            commit(&src, &source, name, None, None).unwrap();
+        }
+
+        let options = PullOptions {
+            mirror: true,
+            ..Default::default()
+        };
+        let result = pull_refs_matching(&src, &dst, "pkg/*", &options).unwrap();
+        assert_eq!(result.updated.len(), 3);
+        assert!(result.deleted.is_empty());
+        for (name, hash) in &result.updated {
+            assert_eq!(&read_ref(&dst, name).unwrap(), hash);
+            assert!(commit_exists(&dst, hash));
+        }
+
+        // a local ref outside the pattern must survive the mirror
+        let local = read_ref(&dst, "pkg/a").unwrap();
+        write_ref(&dst, "other/keep", &local).unwrap();
+
+        crate::refs::delete_ref(&src, "pkg/b").unwrap();
+
+        let dry_run = PullOptions {
+            dry_run: true,
+            ..options.clone()
+        };
+        let result = pull_refs_matching(&src, &dst, "pkg/*", &dry_run).unwrap();
+        assert_eq!(result.deleted, vec!["pkg/b".to_string()]);
+        assert!(read_ref(&dst, "pkg/b").is_ok());
+
+        let result = pull_refs_matching(&src, &dst, "pkg/*", &options).unwrap();
+        assert_eq!(result.deleted, vec!["pkg/b".to_string()]);
+        assert_eq!(
+            list_refs_matching(&dst, "*").unwrap(),
+            vec!["other/keep", "pkg/a", "pkg/c"]
+        );
+    }
+
+    /// the in-process server's answer to a client holding nothing, positioned
+    /// at the object stream, and the number of objects in it
+    fn serve_everything(src: &Repo, commit_hash: &Hash) -> (std::io::Cursor<Vec<u8>>, usize) {
+        use crate::transport::serve::handle_have_objects;
+        use std::io::{BufRead, Cursor};
+
+        let mut request = Cursor::new(b"end\n".to_vec());
+        let mut out = Vec::new();
+        handle_have_objects(src, &mut request, &mut out, Some(commit_hash), false).unwrap();
+        let mut response = Cursor::new(out);
+        let mut total = 0;
+        loop {
+            let mut line = String::new();
+            response.read_line(&mut line).unwrap();
+            if line.trim() == "end" {
+                break;
+            }
+            total += 1;
+        }
+        (response, total)
+    }
+
+    #[test]
+    fn test_receive_pipelined_bounds_in_flight() {
+        use crate::object::read_blob;
+        use crate::transport::ssh::read_object;
+
+        let dir = tempdir().unwrap();
+        let src = Repo::init(&dir.path().join("src")).unwrap();
+        let dst = Repo::init(&dir.path().join("dst")).unwrap();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        for i in 0..32 {
+            fs::write(source.join(format!("f{:02}", i)), vec![i as u8; 1000]).unwrap();
+        }
+        let commit_hash = commit(&src, &source, "main", None, None).unwrap();
+        let (mut response, total) = serve_everything(&src, &commit_hash);
+
+        let in_flight = InFlight::new(2500);
+        let mut reported = 0;
+        let mut on_progress = |p: TransferProgress| reported = p.objects_done;
+        let stats = receive_pipelined(
+            || read_object(&mut response),
+            &dst,
+            total,
+            &PullOptions::default(),
+            Some(&mut on_progress),
+            &in_flight,
+        )
+        .unwrap();
+
+        assert_eq!(stats.copied, total);
+        assert_eq!(reported, total);
+        assert!(in_flight.peak() > 0);
+        assert!(
+            in_flight.peak() <= 2500,
+            "peak of {} bytes",
+            in_flight.peak()
+        );
+
+        let tree = read_tree(&dst, &read_commit(&dst, &commit_hash).unwrap().tree).unwrap();
+        assert_eq!(tree.entries().len(), 32);
+        for (i, entry) in tree.entries().iter().enumerate() {
+            let data = read_blob(&dst, &entry.kind.hash().unwrap()).unwrap();
+            assert_eq!(data, vec![i as u8; 1000]);
+        }
+    }
+
+    #[test]
+    fn test_receive_failed_write_leaves_nothing_behind() {
+        use crate::object::read_blob;
+        use crate::transport::ssh::read_object;
+
+        let dir = tempdir().unwrap();
+        let src = Repo::init(&dir.path().join("src")).unwrap();
+        let dst = Repo::init(&dir.path().join("dst")).unwrap();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        fs::write(source.join("file"), vec![7u8; 4096]).unwrap();
+        let commit_hash = commit(&src, &source, "main", None, None).unwrap();
+        let tree = read_tree(&src, &read_commit(&src, &commit_hash).unwrap().tree).unwrap();
+        let blob = *tree.entries()[0].kind.hash().unwrap();
+
+        // no tmp dir to write into: every object write fails
+        let tmp = dst.objects_tmp_path();
+        fs::remove_dir_all(&tmp).unwrap();
+        fs::write(&tmp, "").unwrap();
+        let (mut response, total) = serve_everything(&src, &commit_hash);
+        let in_flight = InFlight::new(RECEIVE_BUFFER_BYTES);
+        let options = PullOptions::default();
+        let result = receive_pipelined(
+            || read_object(&mut response),
+            &dst,
+            total,
+            &options,
+            None,
+            &in_flight,
+        );
+        assert!(result.is_err());
+        assert!(!crate::object::blob_exists(&dst, &blob));
+        assert!(!commit_exists(&dst, &commit_hash));
+
+        // once writes work again, pulling again stores every object whole
+        fs::remove_file(&tmp).unwrap();
+        fs::create_dir(&tmp).unwrap();
+        let (mut response, total) = serve_everything(&src, &commit_hash);
+        let stats = receive_pipelined(
+            || read_object(&mut response),
+            &dst,
+            total,
+            &options,
+            None,
+            &in_flight,
+        )
+        .unwrap();
+        assert_eq!(stats.copied, total);
+        assert_eq!(read_blob(&dst, &blob).unwrap(), vec![7u8; 4096]);
+        assert!(crate::ops::fsck(&dst).unwrap().is_ok());
+        assert_eq!(fs::read_dir(&tmp).unwrap().count(), 0);
+    }
 }
@@ -3,13 +3,19 @@
 use std::collections::HashSet;
 use std::fs;
 use std::path::Path;
+use std::time::Instant;
 
 use crate::error::{IoResultExt, Result};
 use crate::hash::Hash;
-use crate::object::{read_commit, read_tree};
+use crate::object::{blob_file_hash, read_commit, read_stored, read_tree, PackedKind};
+use crate::ops::is_ancestor;
 use crate::refs::{read_ref, write_ref};
 use crate::repo::Repo;
-use crate::transport::local::{copy_objects, list_all_objects, ObjectSet, TransferStats};
+use crate::transport::local::{
+    copy_objects_with_options, report_progress, retain_missing, CopyOptions, ObjectSet,
+    RateLimiter, TransferProgress, TransferStats,
+};
+use crate::transport::retry::RetryPolicy;
 use crate::transport::ssh::SshConnection;
 use crate::types::EntryKind;
 
@@ -20,6 +26,17 @@ pub struct PushOptions {
     pub force: bool,
     /// dry run - show what would be transferred without doing it
     pub dry_run: bool,
+    /// cap on bytes sent per second; hardlinked objects don't count
+    pub rate_limit_bytes_per_sec: Option<u64>,
+    /// times to retry an object transfer or connection read/write failing
+    /// with a transient I/O error, backing off exponentially
+    pub max_retries: u32,
+    /// between local repos with different namespace mappings, store copied
+    /// blobs under the destination's outside owner for their inside owner
+    pub remap_ownership: bool,
+    /// with `remap_ownership`, keep the owner of blobs it can't map instead
+    /// of failing
+    pub keep_unmapped_owners: bool,
 }
 
 /// push a ref to a local repository
@@ -28,6 +45,17 @@ pub fn push_local(
     dst: &Repo,
     ref_name: &str,
     options: &PushOptions,
+) -> Result<PushResult> {
+    push_local_with_progress(src, dst, ref_name, options, None)
+}
+
+/// push a ref to a local repository, calling `progress` after each object
+pub fn push_local_with_progress(
+    src: &Repo,
+    dst: &Repo,
+    ref_name: &str,
+    options: &PushOptions,
+    progress: Option<&mut dyn FnMut(TransferProgress)>,
 ) -> Result<PushResult> {
     let src_hash = read_ref(src, ref_name)?;
 
@@ -48,31 +76,26 @@ pub fn push_local(
     collect_commit_objects(src, &src_hash, &mut needed, &mut HashSet::new())?;
 
     // filter out objects that already exist in destination
-    let existing = list_all_objects(dst)?;
-    let existing_blobs: HashSet<_> = existing.blobs.into_iter().collect();
-    let existing_trees: HashSet<_> = existing.trees.into_iter().collect();
-    let existing_commits: HashSet<_> = existing.commits.into_iter().collect();
-
-    needed.blobs.retain(|h| !existing_blobs.contains(h));
-    needed.trees.retain(|h| !existing_trees.contains(h));
-    needed.commits.retain(|h| !existing_commits.contains(h));
+    retain_missing(dst, &mut needed)?;
 
     // dry run: return what would be transferred without doing anything
     if options.dry_run {
         return Ok(PushResult {
             hash: src_hash,
-            stats: TransferStats {
-                copied: 0,
-                hardlinked: 0,
-                skipped: 0,
-                bytes_transferred: 0,
-            },
-            objects_to_transfer: needed.blobs.len() + needed.trees.len() + needed.commits.len(),
+            stats: TransferStats::default(),
+            objects_to_transfer: needed.total_count(),
         });
     }
 
     // copy objects
-    let stats = copy_objects(src, dst, &needed)?;
+    let limiter = options.rate_limit_bytes_per_sec.map(RateLimiter::new);
+    let copy = CopyOptions {
+        limiter: limiter.as_ref(),
+        max_retries: options.max_retries,
+        remap_ownership: options.remap_ownership,
+        keep_unmapped_owners: options.keep_unmapped_owners,
+    };
+    let stats = copy_objects_with_options(src, dst, &needed, &copy, progress)?;
 
     // update ref
     write_ref(dst, ref_name, &src_hash)?;
@@ -91,10 +114,23 @@ pub fn push_ssh(
     remote_path: &Path,
     ref_name: &str,
     options: &PushOptions,
+) -> Result<PushResult> {
+    push_ssh_with_progress(local, remote, remote_path, ref_name, options, None)
+}
+
+/// push a ref via SSH, calling `progress` after each object sent
+pub fn push_ssh_with_progress(
+    local: &Repo,
+    remote: &str,
+    remote_path: &Path,
+    ref_name: &str,
+    options: &PushOptions,
+    mut progress: Option<&mut dyn FnMut(TransferProgress)>,
 ) -> Result<PushResult> {
     let local_hash = read_ref(local, ref_name)?;
 
     let mut conn = SshConnection::connect(remote, remote_path)?;
+    conn.set_max_retries(options.max_retries);
 
     // check remote ref for fast-forward
     if !options.force {
@@ -113,6 +149,7 @@ pub fn push_ssh(
     collect_commit_objects(local, &local_hash, &mut all_objects, &mut HashSet::new())?;
 
     // ask remote what it needs
+    conn.check_dictionary(local)?;
     let needed = conn.want_objects(&all_objects)?;
 
     // dry run: return what would be transferred without doing anything
@@ -121,36 +158,42 @@ pub fn push_ssh(
         return Ok(PushResult {
             hash: local_hash,
             stats: TransferStats::default(),
-            objects_to_transfer: needed.blobs.len() + needed.trees.len() + needed.commits.len(),
+            objects_to_transfer: needed.total_count(),
         });
     }
 
     // send needed objects
+    let limiter = options.rate_limit_bytes_per_sec.map(RateLimiter::new);
+    let retry = RetryPolicy::new(options.max_retries);
+    let started = Instant::now();
     let mut stats = TransferStats::default();
-
-    for hash in &needed.blobs {
-        let path = object_path(&local.blobs_path(), hash);
-        let data = fs::read(&path).with_path(&path)?;
-        conn.send_object("blob", hash, &data)?;
-        stats.bytes_transferred += data.len() as u64;
-        stats.copied += 1;
-    }
-
-    for hash in &needed.trees {
-        let path = object_path(&local.trees_path(), hash);
-        let data = fs::read(&path).with_path(&path)?;
-        conn.send_object("tree", hash, &data)?;
-        stats.bytes_transferred += data.len() as u64;
-        stats.copied += 1;
-    }
-
-    for hash in &needed.commits {
-        let path = object_path(&local.commits_path(), hash);
-        let data = fs::read(&path).with_path(&path)?;
-        conn.send_object("commit", hash, &data)?;
-        stats.bytes_transferred += data.len() as u64;
-        stats.copied += 1;
+    let total = needed.total_count();
+    let mut done = 0;
+
+    let kinds = [
+        ("blob", local.blobs_path(), &needed.blobs),
+        ("tree", local.trees_path(), &needed.trees),
+        ("content", local.content_path(), &needed.contents),
+        ("commit", local.commits_path(), &needed.commits),
+    ];
+    for (obj_type, dir, hashes) in kinds {
+        for hash in hashes {
+            let path = object_path(&dir, hash);
+            let data = retry.run(|| match PackedKind::from_type_name(obj_type) {
+                Some(kind) => read_stored(local, kind, hash),
+                None => fs::read(&path).with_path(&path),
+            })?;
+            if let Some(limiter) = &limiter {
+                limiter.consume(data.len() as u64);
+            }
+            conn.send_object(obj_type, hash, &data)?;
+            stats.bytes_transferred += data.len() as u64;
+            stats.copied += 1;
+            done += 1;
+            report_progress(&mut progress, done, total, &stats);
+        }
     }
+    stats.elapsed = started.elapsed();
 
     // update remote ref
     conn.update_ref(ref_name, &local_hash)?;
@@ -164,35 +207,6 @@ pub fn push_ssh(
     })
 }
 
-/// check if ancestor is an ancestor of descendant
-fn is_ancestor(repo: &Repo, ancestor: &Hash, descendant: &Hash) -> Result<bool> {
-    if ancestor == descendant {
-        return Ok(true);
-    }
-
-    let mut to_visit = vec![*descendant];
-    let mut visited = HashSet::new();
-
-    while let Some(hash) = to_visit.pop() {
-        if hash == *ancestor {
-            return Ok(true);
-        }
-
-        if visited.contains(&hash) {
-            continue;
-        }
-        visited.insert(hash);
-
-        if let Ok(commit) = read_commit(repo, &hash) {
-            for parent in &commit.parents {
-                to_visit.push(*parent);
-            }
-        }
-    }
-
-    Ok(false)
-}
-
 /// collect all objects reachable from a commit
 fn collect_commit_objects(
     repo: &Repo,
@@ -238,10 +252,17 @@ fn collect_tree_objects(
 
     for entry in tree.entries() {
         match &entry.kind {
-            EntryKind::Regular { hash, .. } => {
-                if !visited.contains(hash) {
-                    visited.insert(*hash);
-                    objects.blobs.push(*hash);
+            EntryKind::Regular { hash, content, .. } => {
+                let file = blob_file_hash(hash, content.as_ref());
+                if !visited.contains(&file) {
+                    visited.insert(file);
+                    objects.blobs.push(file);
+                }
+                if let Some(content_hash) = content {
+                    if !visited.contains(content_hash) {
+                        visited.insert(*content_hash);
+                        objects.contents.push(*content_hash);
+                    }
                 }
             }
             EntryKind::Symlink { hash, .. } => {
@@ -253,6 +274,9 @@ fn collect_tree_objects(
             EntryKind::Directory { hash, .. } => {
                 collect_tree_objects(repo, hash, objects, visited)?;
             }
+            // a hardlink names another path in the same commit tree, which is
+            // always recorded as a regular entry, so its blob is collected there
+            EntryKind::Hardlink { .. } => {}
             _ => {}
         }
     }
@@ -393,44 +417,119 @@ mod tests {
         // force push should succeed
         let options = PushOptions {
             force: true,
-            dry_run: false,
+            ..Default::default()
         };
         let result = push_local(&src2, &dst, "test", &options).unwrap();
         assert_eq!(result.hash, hash2);
     }
 
     #[test]
-    fn test_is_ancestor() {
-        let dir = tempdir().unwrap();
+    fn test_push_hardlinks() {
+        use crate::ops::{checkout, CheckoutOptions};
+        use std::os::unix::fs::MetadataExt;
 
-        let repo_path = dir.path().join("repo");
-        let repo = Repo::init(&repo_path).unwrap();
+        let dir = tempdir().unwrap();
+        let src = Repo::init(&dir.path().join("src_repo")).unwrap();
+        let dst = Repo::init(&dir.path().join("dst_repo")).unwrap();
 
         let source = dir.path().join("source");
-        fs::create_dir(&source).unwrap();
-
-        fs::write(source.join("file.txt"), "v1").unwrap();
-        let hash1 = commit(&repo, &source, "test", Some("v1"), None).unwrap();
-
-        fs::write(source.join("file.txt"), "v2").unwrap();
-        let hash2 = commit(&repo, &source, "test", Some("v2"), None).unwrap();
+        fs::create_dir_all(source.join("a")).unwrap();
+        fs::create_dir_all(source.join("b")).unwrap();
+        fs::write(source.join("a/file.txt"), "v1").unwrap();
+        commit(&src, &source, "test", Some("v1"), None).unwrap();
 
-        fs::write(source.join("file.txt"), "v3").unwrap();
-        let hash3 = commit(&repo, &source, "test", Some("v3"), None).unwrap();
+        // second commit links across directories; the target blob is new
+        fs::write(source.join("a/file.txt"), "shared").unwrap();
+        fs::hard_link(source.join("a/file.txt"), source.join("b/link.txt")).unwrap();
+        commit(&src, &source, "test", Some("v2"), None).unwrap();
 
-        // hash1 is ancestor of hash3
-        assert!(is_ancestor(&repo, &hash1, &hash3).unwrap());
+        push_local(&src, &dst, "test", &PushOptions::default()).unwrap();
 
-        // hash1 is ancestor of hash2
-        assert!(is_ancestor(&repo, &hash1, &hash2).unwrap());
+        // whichever link the commit walk reached first carries the blob
+        let head = read_commit(&src, &read_ref(&src, "test").unwrap()).unwrap();
+        let tree = read_tree(&src, &head.tree).unwrap();
+        let blobs: Vec<Hash> = ["a", "b"]
+            .iter()
+            .filter_map(|dir| match &tree.get(dir).unwrap().kind {
+                EntryKind::Directory { hash, .. } => Some(read_tree(&src, hash).unwrap()),
+                _ => None,
+            })
+            .flat_map(|sub| sub.entries().to_vec())
+            .filter_map(|entry| match entry.kind {
+                EntryKind::Regular { hash, .. } => Some(hash),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(blobs.len(), 1);
+        assert!(crate::object::blob_exists(&dst, &blobs[0]));
+
+        let target = dir.path().join("out");
+        checkout(&dst, "test", &target, CheckoutOptions::default()).unwrap();
+        let first = fs::metadata(target.join("a/file.txt")).unwrap();
+        let second = fs::metadata(target.join("b/link.txt")).unwrap();
+        assert_eq!(first.ino(), second.ino());
+        assert_eq!(
+            fs::read_to_string(target.join("b/link.txt")).unwrap(),
+            "shared"
+        );
+    }
 
-        // hash2 is ancestor of hash3
-        assert!(is_ancestor(&repo, &hash2, &hash3).unwrap());
+    #[test]
+    fn test_push_local_remap_ownership() {
+        use crate::namespace::{MapEntry, NsConfig};
+        use crate::object::blob_path;
+        use crate::repo::InitOptions;
+        use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+
+        let ns = |outside, count| InitOptions {
+            namespace: Some(NsConfig {
+                uid_map: vec![MapEntry::new(0, outside, count)],
+                gid_map: vec![MapEntry::new(0, outside, count)],
+            }),
+            ..Default::default()
+        };
+        let dir = tempdir().unwrap();
+        let src = Repo::init_with_options(&dir.path().join("a"), &ns(100000, 65536)).unwrap();
+        let dst = Repo::init_with_options(&dir.path().join("b"), &ns(200000, 65536)).unwrap();
+        // inside 1000 has no outside id here
+        let small = Repo::init_with_options(&dir.path().join("c"), &ns(300000, 500)).unwrap();
 
-        // hash3 is NOT ancestor of hash1
-        assert!(!is_ancestor(&repo, &hash3, &hash1).unwrap());
+        // a file owned by inside 1000:1000 in the source repo
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        let file = source.join("tool");
+        fs::write(&file, "tool").unwrap();
+        chown(&file, Some(101000), Some(101000)).unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o755)).unwrap();
+        let head = commit(&src, &source, "test", None, None).unwrap();
+        let tree = read_tree(&src, &read_commit(&src, &head).unwrap().tree).unwrap();
+        let blob = *tree.get("tool").unwrap().kind.hash().unwrap();
+        let owner = |repo: &Repo| {
+            let meta = fs::metadata(blob_path(repo, &blob)).unwrap();
+            (meta.uid(), meta.gid(), meta.mode() & 0o7777)
+        };
+        assert_eq!(owner(&src), (101000, 101000, 0o755));
 
-        // same commit is its own ancestor
-        assert!(is_ancestor(&repo, &hash2, &hash2).unwrap());
+        let remap = PushOptions {
+            remap_ownership: true,
+            ..Default::default()
+        };
+        let result = push_local(&src, &dst, "test", &remap).unwrap();
+        assert_eq!(result.stats.remapped, 1);
+        assert_eq!(owner(&dst), (201000, 201000, 0o755));
+        // copied, not hardlinked, so the source keeps its owner
+        assert_eq!(owner(&src), (101000, 101000, 0o755));
+
+        assert!(matches!(
+            push_local(&src, &small, "test", &remap),
+            Err(crate::Error::UnmappedUid(101000))
+        ));
+        let keep = PushOptions {
+            keep_unmapped_owners: true,
+            ..remap
+        };
+        let result = push_local(&src, &small, "test", &keep).unwrap();
+        assert_eq!(result.stats.remapped, 0);
+        assert_eq!(owner(&small), (101000, 101000, 0o755));
     }
 }
@@ -0,0 +1,230 @@
+//! retry with exponential backoff for transient I/O errors
+
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// first retry waits this long, doubling each time
+const BASE_DELAY: Duration = Duration::from_millis(50);
+
+/// no single wait is longer than this
+const MAX_DELAY: Duration = Duration::from_secs(5);
+
+/// errors that may go away if the operation is simply tried again
+pub(crate) trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for io::Error {
+    /// interrupted or timed-out calls, a full or busy device (which another
+    /// process may free up), and stale NFS handles. missing files, permission
+    /// problems and bad data are permanent.
+    fn is_transient(&self) -> bool {
+        if matches!(
+            self.kind(),
+            io::ErrorKind::Interrupted
+                | io::ErrorKind::WouldBlock
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::StorageFull
+                | io::ErrorKind::ResourceBusy
+        ) {
+            return true;
+        }
+        self.raw_os_error() == Some(nix::errno::Errno::ESTALE as i32)
+    }
+}
+
+impl Transient for Error {
+    fn is_transient(&self) -> bool {
+        match self {
+            Error::Io { source, .. } => source.is_transient(),
+            _ => false,
+        }
+    }
+}
+
+/// how often and how patiently to retry
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    max_retries: u32,
+    base_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// up to `max_retries` retries after the first attempt; 0 never retries
+    pub(crate) fn new(max_retries: u32) -> Self {
+        Self {
+            max_retries,
+            base_delay: BASE_DELAY,
+        }
+    }
+
+    #[cfg(test)]
+    fn with_base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// wait before retry number `attempt` (0-based)
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(MAX_DELAY)
+    }
+
+    /// run `op`, retrying it on transient errors; others fail at once
+    pub(crate) fn run<T, E: Transient>(
+        &self,
+        mut op: impl FnMut() -> std::result::Result<T, E>,
+    ) -> std::result::Result<T, E> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e) if attempt < self.max_retries && e.is_transient() => {
+                    thread::sleep(self.delay(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+/// reader/writer retrying each failed call under a `RetryPolicy`
+///
+/// a failed `read` or `write` call consumed nothing, so repeating it is safe
+/// on a stream too; the protocol never sees a gap or a duplicate.
+pub(crate) struct Retrying<T> {
+    inner: T,
+    pub(crate) policy: RetryPolicy,
+}
+
+impl<T> Retrying<T> {
+    pub(crate) fn new(inner: T, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+impl<T: Read> Read for Retrying<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.read(buf))
+    }
+}
+
+impl<T: Write> Write for Retrying<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        self.policy.run(|| inner.flush())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// fails the first `failures` calls with `kind`, then behaves normally
+    struct Flaky<T> {
+        inner: T,
+        failures: u32,
+        kind: io::ErrorKind,
+        calls: u32,
+    }
+
+    impl<T> Flaky<T> {
+        fn new(inner: T, failures: u32, kind: io::ErrorKind) -> Self {
+            Self {
+                inner,
+                failures,
+                kind,
+                calls: 0,
+            }
+        }
+
+        fn fail(&mut self) -> io::Result<()> {
+            self.calls += 1;
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from(self.kind));
+            }
+            Ok(())
+        }
+    }
+
+    impl<T: Read> Read for Flaky<T> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.fail()?;
+            self.inner.read(buf)
+        }
+    }
+
+    impl<T: Write> Write for Flaky<T> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.fail()?;
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy::new(max_retries).with_base_delay(Duration::from_millis(1))
+    }
+
+    #[test]
+    fn test_retry_within_budget() {
+        let flaky = Flaky::new(&b"payload"[..], 3, io::ErrorKind::TimedOut);
+        let mut reader = Retrying::new(flaky, policy(3));
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"payload");
+
+        let flaky = Flaky::new(Vec::new(), 2, io::ErrorKind::StorageFull);
+        let mut writer = Retrying::new(flaky, policy(3));
+        writer.write_all(b"payload").unwrap();
+        assert_eq!(writer.inner.inner, b"payload");
+        assert_eq!(writer.inner.calls, 3);
+    }
+
+    #[test]
+    fn test_retry_past_budget_fails() {
+        let flaky = Flaky::new(&b"payload"[..], 4, io::ErrorKind::TimedOut);
+        let mut reader = Retrying::new(flaky, policy(3));
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(reader.inner.calls, 4);
+    }
+
+    #[test]
+    fn test_permanent_errors_fail_fast() {
+        let flaky = Flaky::new(Vec::new(), 1, io::ErrorKind::NotFound);
+        let mut writer = Retrying::new(flaky, policy(5));
+        let err = writer.write_all(b"payload").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(writer.inner.calls, 1);
+
+        // crate errors are classified by their io source
+        let mut calls = 0;
+        let result: crate::Result<()> = policy(5).run(|| {
+            calls += 1;
+            Err(Error::CorruptObjectMessage("bad".to_string()))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
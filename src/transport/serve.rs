@@ -3,14 +3,23 @@
 //! implements the protocol that responds to pull/push requests from remote clients
 
 use std::collections::HashSet;
-use std::fs;
-use std::io::{BufRead, BufReader, Write};
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 
+use crate::error::IoResultExt;
+use crate::fs::TempFile;
 use crate::hash::Hash;
-use crate::object::{read_commit, read_tree};
+use crate::object::{
+    blob_file_hash, packed_exists, read_blob_to, read_commit, read_stored, read_tree, PackedKind,
+};
 use crate::refs::{list_refs, read_ref, write_ref};
 use crate::repo::Repo;
+use crate::transport::wire::{
+    copy_body, dictionary_id, encode_body, encode_stream, read_body_to, ObjectHeader, CAPABILITIES,
+    CAP_DICT, CAP_ZSTD, DICT_PREFIX, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, STREAM_THRESHOLD,
+};
 use crate::types::EntryKind;
 use crate::Result;
 
@@ -20,9 +29,15 @@ pub fn serve_remote(repo: &Repo) -> Result<()> {
     let stdin = std::io::stdin();
     let mut reader = BufReader::new(stdin.lock());
     let mut stdout = std::io::stdout();
+    serve(repo, &mut reader, &mut stdout)
+}
 
+/// serve the protocol on an arbitrary stream pair
+pub(crate) fn serve(repo: &Repo, reader: &mut impl BufRead, stdout: &mut impl Write) -> Result<()> {
     // track the last requested ref for have-objects
     let mut last_ref_hash: Option<Hash> = None;
+    // compress outgoing object bodies once the client advertises zstd
+    let mut compress = false;
 
     loop {
         let mut line = String::new();
@@ -40,28 +55,36 @@ pub fn serve_remote(repo: &Repo) -> Result<()> {
         let args = parts.get(1).copied().unwrap_or("");
 
         match cmd {
+            "hello" => {
+                handle_hello(args, stdout)?;
+            }
+
+            "capabilities" => {
+                compress = handle_capabilities(repo, args, stdout)?;
+            }
+
             "list-refs" => {
-                handle_list_refs(repo, &mut stdout)?;
+                handle_list_refs(repo, stdout)?;
             }
 
             "get-ref" => {
-                last_ref_hash = handle_get_ref(repo, args, &mut stdout)?;
+                last_ref_hash = handle_get_ref(repo, args, stdout)?;
             }
 
             "have-objects" => {
-                handle_have_objects(repo, &mut reader, &mut stdout, last_ref_hash.as_ref())?;
+                handle_have_objects(repo, reader, stdout, last_ref_hash.as_ref(), compress)?;
             }
 
             "want-objects" => {
-                handle_want_objects(repo, &mut reader, &mut stdout)?;
+                handle_want_objects(repo, reader, stdout)?;
             }
 
             "object" => {
-                handle_receive_object(repo, args, &mut reader, &mut stdout)?;
+                handle_receive_object(repo, args, reader, stdout)?;
             }
 
             "update-ref" => {
-                handle_update_ref(repo, args, &mut stdout)?;
+                handle_update_ref(repo, args, stdout)?;
             }
 
             "quit" => {
@@ -69,7 +92,7 @@ pub fn serve_remote(repo: &Repo) -> Result<()> {
             }
 
             _ => {
-                write_error(&mut stdout, &format!("unknown command: {}", cmd))?;
+                write_error(stdout, &format!("unknown command: {}", cmd))?;
             }
         }
     }
@@ -77,6 +100,43 @@ pub fn serve_remote(repo: &Repo) -> Result<()> {
     Ok(())
 }
 
+/// reply to the handshake with our protocol version and the oldest we accept
+///
+/// clients that skip it are v0 and get the same commands, so nothing here
+/// depends on the outcome; the client decides whether the versions fit.
+fn handle_hello(args: &str, stdout: &mut impl Write) -> Result<()> {
+    if args.trim().parse::<u32>().is_err() {
+        return write_error(stdout, &format!("invalid protocol version: {}", args));
+    }
+    writeln!(
+        stdout,
+        "version {} {}",
+        PROTOCOL_VERSION, MIN_PROTOCOL_VERSION
+    )
+    .map_err(io_err)?;
+    write_end(stdout)
+}
+
+/// reply with the capabilities both sides support, returns whether zstd is on
+///
+/// with `dict`, the repo's dictionary id follows, so the client can refuse to
+/// move trees and commits neither side could read after.
+fn handle_capabilities(repo: &Repo, args: &str, stdout: &mut impl Write) -> Result<bool> {
+    let common: Vec<&str> = args
+        .split_whitespace()
+        .filter(|c| CAPABILITIES.contains(c))
+        .collect();
+    for cap in &common {
+        writeln!(stdout, "{}", cap).map_err(io_err)?;
+    }
+    if common.contains(&CAP_DICT) {
+        let id = dictionary_id(repo.zstd_dictionary());
+        writeln!(stdout, "{}{}", DICT_PREFIX, id).map_err(io_err)?;
+    }
+    write_end(stdout)?;
+    Ok(common.contains(&CAP_ZSTD))
+}
+
 fn handle_list_refs(repo: &Repo, stdout: &mut impl Write) -> Result<()> {
     let refs = list_refs(repo)?;
     for ref_name in refs {
@@ -101,28 +161,18 @@ fn handle_get_ref(repo: &Repo, ref_name: &str, stdout: &mut impl Write) -> Resul
     }
 }
 
-fn handle_have_objects(
+pub(crate) fn handle_have_objects(
     repo: &Repo,
     reader: &mut impl BufRead,
     stdout: &mut impl Write,
     last_ref_hash: Option<&Hash>,
+    compress: bool,
 ) -> Result<()> {
     // read what client has
     let mut client_has: HashSet<Hash> = HashSet::new();
-    loop {
-        let mut obj_line = String::new();
-        reader.read_line(&mut obj_line).unwrap_or(0);
-        let obj_line = obj_line.trim();
-        if obj_line == "end" {
-            break;
-        }
-        let obj_parts: Vec<&str> = obj_line.splitn(2, ' ').collect();
-        if obj_parts.len() == 2 {
-            if let Ok(hash) = Hash::from_hex(obj_parts[1]) {
-                client_has.insert(hash);
-            }
-        }
-    }
+    read_object_list(reader, |_, hash| {
+        client_has.insert(hash);
+    });
 
     // find what client needs from the last requested ref
     let mut to_send: Vec<(String, Hash)> = Vec::new();
@@ -143,21 +193,126 @@ fn handle_have_objects(
 
     // report what client is missing
     for (obj_type, hash) in &to_send {
-        writeln!(stdout, "{} {}", obj_type, hash).map_err(io_err)?;
+        write_object_line(stdout, obj_type, hash)?;
     }
     write_end(stdout)?;
 
     // now send the actual objects
     for (obj_type, hash) in &to_send {
-        let (data, mode) = read_object_data_with_mode(repo, obj_type, hash)?;
-        writeln!(stdout, "object {} {} {} {}", obj_type, hash, data.len(), mode).map_err(io_err)?;
-        stdout.write_all(&data).map_err(io_err)?;
+        send_object(repo, obj_type, hash, compress, stdout)?;
     }
     write_end(stdout)?;
 
     Ok(())
 }
 
+/// write one object frame; large objects are streamed from disk in bounded
+/// chunks instead of being read into memory
+fn send_object(
+    repo: &Repo,
+    obj_type: &str,
+    hash: &Hash,
+    compress: bool,
+    stdout: &mut impl Write,
+) -> Result<()> {
+    let path = object_path(repo, obj_type, hash);
+    let packed = PackedKind::from_type_name(obj_type);
+    let meta = match (fs::metadata(&path), packed) {
+        (Ok(meta), _) => meta,
+        // packed trees and commits have no file of their own
+        (Err(e), Some(kind)) if e.kind() == std::io::ErrorKind::NotFound => {
+            let data = read_stored(repo, kind, hash)?;
+            return send_whole(obj_type, hash, &data, 0, compress, stdout);
+        }
+        (Err(e), _) => return Err(crate::Error::Io { path, source: e }),
+    };
+    let mode = if obj_type == "blob" {
+        meta.mode() & 0o7777
+    } else {
+        0
+    };
+
+    if meta.len() <= STREAM_THRESHOLD {
+        let data = fs::read(&path).with_path(&path)?;
+        return send_whole(obj_type, hash, &data, mode, compress, stdout);
+    }
+
+    let mut header = ObjectHeader {
+        obj_type: obj_type.to_string(),
+        hash: *hash,
+        size: meta.len() as usize,
+        mode: Some(mode),
+        compressed_size: None,
+    };
+
+    // compression needs the compressed size up front, so spool it to tmp
+    if compress && matches!(obj_type, "blob" | "content") {
+        let tmp = TempFile::new_in(&repo.tmp_path());
+        if send_compressed(&path, tmp.path(), &mut header, stdout)? {
+            return Ok(());
+        }
+    }
+
+    writeln!(stdout, "{}", header.format()).map_err(io_err)?;
+    if obj_type == "blob" {
+        read_blob_to(repo, hash, stdout)?;
+    } else {
+        let mut file = File::open(&path).with_path(&path)?;
+        copy_body(&mut file, stdout, meta.len())?;
+    }
+    Ok(())
+}
+
+/// write one object frame for data already in memory
+fn send_whole(
+    obj_type: &str,
+    hash: &Hash,
+    data: &[u8],
+    mode: u32,
+    compress: bool,
+    stdout: &mut impl Write,
+) -> Result<()> {
+    let (body, compressed_size) = encode_body(obj_type, data, compress);
+    let header = ObjectHeader {
+        obj_type: obj_type.to_string(),
+        hash: *hash,
+        size: data.len(),
+        mode: Some(mode),
+        compressed_size,
+    };
+    writeln!(stdout, "{}", header.format()).map_err(io_err)?;
+    stdout.write_all(&body).map_err(io_err)
+}
+
+/// compress `path` into `tmp` and send it if that shrinks the body;
+/// returns false (nothing written) when the raw body should be sent instead
+fn send_compressed(
+    path: &Path,
+    tmp: &Path,
+    header: &mut ObjectHeader,
+    stdout: &mut impl Write,
+) -> Result<bool> {
+    let mut src = File::open(path).with_path(path)?;
+    let mut spool = File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(tmp)
+        .with_path(tmp)?;
+    encode_stream(&mut src, &mut spool)?;
+
+    let compressed_len = spool.stream_position().with_path(tmp)?;
+    if compressed_len >= header.size as u64 {
+        return Ok(false);
+    }
+
+    header.compressed_size = Some(compressed_len as usize);
+    writeln!(stdout, "{}", header.format()).map_err(io_err)?;
+    spool.seek(SeekFrom::Start(0)).with_path(tmp)?;
+    copy_body(&mut spool, stdout, compressed_len)?;
+    Ok(true)
+}
+
 fn handle_want_objects(
     repo: &Repo,
     reader: &mut impl BufRead,
@@ -165,28 +320,49 @@ fn handle_want_objects(
 ) -> Result<()> {
     // read object list, report what we don't have (for push)
     let mut needed = Vec::new();
+    read_object_list(reader, |obj_type, hash| {
+        if !object_exists(repo, obj_type, &hash) {
+            needed.push((obj_type.to_string(), hash));
+        }
+    });
+
+    for (obj_type, hash) in &needed {
+        write_object_line(stdout, obj_type, hash)?;
+    }
+    write_end(stdout)
+}
+
+/// read `<type> <hash>` lines up to `end` (or EOF), skipping malformed ones.
+/// one line buffer is reused and hashes decode in place, so long lists
+/// don't allocate per line.
+fn read_object_list(reader: &mut impl BufRead, mut f: impl FnMut(&str, Hash)) {
+    let mut line = Vec::new();
     loop {
-        let mut obj_line = String::new();
-        reader.read_line(&mut obj_line).unwrap_or(0);
-        let obj_line = obj_line.trim();
-        if obj_line == "end" {
+        line.clear();
+        if reader.read_until(b'\n', &mut line).unwrap_or(0) == 0 {
             break;
         }
-        let obj_parts: Vec<&str> = obj_line.splitn(2, ' ').collect();
-        if obj_parts.len() == 2 {
-            let obj_type = obj_parts[0];
-            if let Ok(hash) = Hash::from_hex(obj_parts[1]) {
-                if !object_exists(repo, obj_type, &hash) {
-                    needed.push((obj_type.to_string(), hash));
-                }
-            }
+        let obj_line = line.trim_ascii();
+        if obj_line == b"end" {
+            break;
+        }
+        let Some(space) = obj_line.iter().position(|&b| b == b' ') else {
+            continue;
+        };
+        let (obj_type, hex) = (&obj_line[..space], &obj_line[space + 1..]);
+        if let (Ok(obj_type), Ok(hash)) = (std::str::from_utf8(obj_type), Hash::from_hex_bytes(hex))
+        {
+            f(obj_type, hash);
         }
     }
+}
 
-    for (obj_type, hash) in needed {
-        writeln!(stdout, "{} {}", obj_type, hash).map_err(io_err)?;
-    }
-    write_end(stdout)
+/// write one `<type> <hash>` line without formatting through a `String`
+fn write_object_line(stdout: &mut impl Write, obj_type: &str, hash: &Hash) -> Result<()> {
+    stdout.write_all(obj_type.as_bytes()).map_err(io_err)?;
+    stdout.write_all(b" ").map_err(io_err)?;
+    hash.write_hex(stdout).map_err(io_err)?;
+    stdout.write_all(b"\n").map_err(io_err)
 }
 
 fn handle_receive_object(
@@ -195,32 +371,28 @@ fn handle_receive_object(
     reader: &mut impl BufRead,
     stdout: &mut impl Write,
 ) -> Result<()> {
-    let obj_parts: Vec<&str> = args.splitn(3, ' ').collect();
-    if obj_parts.len() != 3 {
-        return write_error(stdout, "invalid object args");
+    let header = match ObjectHeader::parse(args) {
+        Ok(header) => header,
+        Err(_) => return write_error(stdout, "invalid object args"),
+    };
+    let obj_type = header.obj_type.as_str();
+    let hash = header.hash;
+
+    // stream into tmp and rename, so neither memory use nor a torn write
+    // depends on the object size. decompresses wire-compressed bodies, so
+    // the at-rest format is unchanged
+    let tmp = TempFile::new_in(&repo.objects_tmp_path());
+    {
+        let mut file = File::create(tmp.path()).with_path(tmp.path())?;
+        read_body_to(reader, &header, &mut file)?;
     }
 
-    let obj_type = obj_parts[0];
-    let hash = Hash::from_hex(obj_parts[1])?;
-    let size: usize = obj_parts[2].parse().unwrap_or(0);
-
-    let mut data = vec![0u8; size];
-    reader.read_exact(&mut data).map_err(|e| crate::Error::Io {
-        path: "stdin".into(),
-        source: e,
-    })?;
-
     let dest = object_path(repo, obj_type, &hash);
     if let Some(parent) = dest.parent() {
-        fs::create_dir_all(parent).map_err(|e| crate::Error::Io {
-            path: parent.to_path_buf(),
-            source: e,
-        })?;
+        fs::create_dir_all(parent).with_path(parent)?;
     }
-    fs::write(&dest, &data).map_err(|e| crate::Error::Io {
-        path: dest,
-        source: e,
-    })?;
+    fs::rename(tmp.path(), &dest).with_path(&dest)?;
+    tmp.into_persisted();
 
     writeln!(stdout, "ok").map_err(io_err)?;
     write_end(stdout)
@@ -281,9 +453,16 @@ fn collect_tree_objects(
     for entry in tree.entries() {
         match &entry.kind {
             EntryKind::Regular { hash, .. } | EntryKind::Symlink { hash, .. } => {
-                if !visited.contains(hash) {
-                    visited.insert(*hash);
-                    objects.push(("blob".to_string(), *hash));
+                let file = blob_file_hash(hash, entry.kind.content_hash());
+                if !visited.contains(&file) {
+                    visited.insert(file);
+                    objects.push(("blob".to_string(), file));
+                }
+                if let Some(content_hash) = entry.kind.content_hash() {
+                    if !visited.contains(content_hash) {
+                        visited.insert(*content_hash);
+                        objects.push(("content".to_string(), *content_hash));
+                    }
                 }
             }
             EntryKind::Directory { hash, .. } => {
@@ -298,6 +477,7 @@ fn collect_tree_objects(
 
 fn object_exists(repo: &Repo, obj_type: &str, hash: &Hash) -> bool {
     object_path(repo, obj_type, hash).exists()
+        || PackedKind::from_type_name(obj_type).is_some_and(|kind| packed_exists(repo, kind, hash))
 }
 
 fn object_path(repo: &Repo, obj_type: &str, hash: &Hash) -> PathBuf {
@@ -306,28 +486,12 @@ fn object_path(repo: &Repo, obj_type: &str, hash: &Hash) -> PathBuf {
         "blob" => repo.blobs_path(),
         "tree" => repo.trees_path(),
         "commit" => repo.commits_path(),
+        "content" => repo.content_path(),
         _ => return PathBuf::new(),
     };
     base.join(&hex[..2]).join(&hex[2..])
 }
 
-fn read_object_data_with_mode(repo: &Repo, obj_type: &str, hash: &Hash) -> Result<(Vec<u8>, u32)> {
-    use std::os::unix::fs::MetadataExt;
-    let path = object_path(repo, obj_type, hash);
-    let data = fs::read(&path).map_err(|e| crate::Error::Io {
-        path: path.clone(),
-        source: e,
-    })?;
-    let mode = if obj_type == "blob" {
-        fs::metadata(&path)
-            .map(|m| m.mode() & 0o7777)
-            .unwrap_or(0o644)
-    } else {
-        0
-    };
-    Ok((data, mode))
-}
-
 fn write_end(stdout: &mut impl Write) -> Result<()> {
     writeln!(stdout, "end").map_err(io_err)?;
     stdout.flush().map_err(io_err)?;
@@ -345,3 +509,212 @@ fn io_err(e: std::io::Error) -> crate::Error {
         source: e,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::blob::tests::tmp_leftovers;
+    use crate::object::{read_blob, write_blob};
+    use crate::ops::commit;
+    use crate::transport::ssh::read_object;
+    use std::io::{Cursor, Read};
+    use tempfile::tempdir;
+
+    /// records the largest single read/write, to check memory stays bounded
+    struct Tracking<T> {
+        inner: T,
+        largest: usize,
+    }
+
+    impl<T: Read> Read for Tracking<T> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.largest = self.largest.max(buf.len());
+            self.inner.read(buf)
+        }
+    }
+
+    impl<T: Write> Write for Tracking<T> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.largest = self.largest.max(buf.len());
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// 4 MiB that compresses, but not down to nothing
+    fn large_content() -> Vec<u8> {
+        (0..4 * 1024 * 1024u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8 & 0x0f)
+            .collect()
+    }
+
+    fn current_ids() -> (u32, u32) {
+        (
+            nix::unistd::getuid().as_raw(),
+            nix::unistd::getgid().as_raw(),
+        )
+    }
+
+    #[test]
+    fn test_capabilities_handshake() {
+        let dir = tempdir().unwrap();
+        let repo = Repo::init(&dir.path().join("repo")).unwrap();
+
+        let mut out = Vec::new();
+        assert!(handle_capabilities(&repo, "zstd future-cap", &mut out).unwrap());
+        assert_eq!(String::from_utf8(out).unwrap(), "zstd\nend\n");
+
+        let mut out = Vec::new();
+        assert!(!handle_capabilities(&repo, "", &mut out).unwrap());
+    }
+
+    #[test]
+    fn test_have_objects_compressed() {
+        let dir = tempdir().unwrap();
+        let repo = Repo::init(&dir.path().join("repo")).unwrap();
+
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        let content = "compress me ".repeat(1000);
+        fs::write(source.join("big.txt"), &content).unwrap();
+        let commit_hash = commit(&repo, &source, "main", None, None).unwrap();
+
+        // client has nothing
+        let mut request = Cursor::new(b"end\n".to_vec());
+        let mut out = Vec::new();
+        handle_have_objects(&repo, &mut request, &mut out, Some(&commit_hash), true).unwrap();
+
+        let text = String::from_utf8_lossy(&out);
+        assert!(text.contains(" zstd="));
+
+        // skip the list of missing objects, then decode the frames
+        let mut response = Cursor::new(out);
+        loop {
+            let mut line = String::new();
+            response.read_line(&mut line).unwrap();
+            if line.trim() == "end" {
+                break;
+            }
+        }
+
+        let mut blobs = 0;
+        while let Some((obj_type, hash, data, _mode)) = read_object(&mut response).unwrap() {
+            if obj_type == "blob" {
+                assert_eq!(data, read_blob(&repo, &hash).unwrap());
+                assert_eq!(data, content.as_bytes());
+                blobs += 1;
+            }
+        }
+        assert_eq!(blobs, 1);
+    }
+
+    #[test]
+    fn test_receive_compressed_object() {
+        let dir = tempdir().unwrap();
+        let src = Repo::init(&dir.path().join("src")).unwrap();
+        let dst = Repo::init(&dir.path().join("dst")).unwrap();
+        let (uid, gid) = current_ids();
+
+        let content = vec![b'z'; 8192];
+        let hash = write_blob(&src, &content, uid, gid, 0o644, &[]).unwrap();
+
+        let (body, compressed_size) = encode_body("blob", &content, true);
+        assert!(compressed_size.is_some());
+
+        let header = ObjectHeader {
+            obj_type: "blob".to_string(),
+            hash,
+            size: content.len(),
+            mode: None,
+            compressed_size,
+        };
+        let args = header.format();
+        let args = args.strip_prefix("object ").unwrap();
+
+        // a body cut short fails without leaving its temp file behind
+        let mut reader = Cursor::new(body[..body.len() / 2].to_vec());
+        let mut out = Vec::new();
+        assert!(handle_receive_object(&dst, args, &mut reader, &mut out).is_err());
+        assert_eq!(tmp_leftovers(&dst.objects_tmp_path()), 0);
+        assert!(read_blob(&dst, &hash).is_err());
+
+        let mut reader = Cursor::new(body.into_owned());
+        let mut out = Vec::new();
+        handle_receive_object(&dst, args, &mut reader, &mut out).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), "ok\nend\n");
+        // stored uncompressed at rest
+        assert_eq!(read_blob(&dst, &hash).unwrap(), content);
+    }
+
+    #[test]
+    fn test_large_objects_stream_in_bounded_chunks() {
+        let dir = tempdir().unwrap();
+        let src = Repo::init(&dir.path().join("src")).unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir(&source).unwrap();
+        let content = large_content();
+        fs::write(source.join("large.bin"), &content).unwrap();
+        let commit_hash = commit(&src, &source, "main", None, None).unwrap();
+
+        for compress in [false, true] {
+            // pull: the server sends every object the client lacks
+            let mut request = Cursor::new(b"end\n".to_vec());
+            let mut out = Tracking {
+                inner: Vec::new(),
+                largest: 0,
+            };
+            handle_have_objects(&src, &mut request, &mut out, Some(&commit_hash), compress)
+                .unwrap();
+            assert!(out.largest <= 64 * 1024, "write of {} bytes", out.largest);
+
+            let mut response = Cursor::new(out.inner);
+            loop {
+                let mut line = String::new();
+                response.read_line(&mut line).unwrap();
+                if line.trim() == "end" {
+                    break;
+                }
+            }
+
+            // push: feed each frame back into a fresh repo's receive handler
+            let dst = Repo::init(&dir.path().join(format!("dst-{}", compress))).unwrap();
+            loop {
+                let mut line = String::new();
+                response.read_line(&mut line).unwrap();
+                let Some(args) = line.trim().strip_prefix("object ") else {
+                    break;
+                };
+                let header = ObjectHeader::parse(args).unwrap();
+                assert_eq!(
+                    header.compressed_size.is_some(),
+                    compress && header.obj_type == "blob"
+                );
+                let mut body = vec![0u8; header.wire_size()];
+                response.read_exact(&mut body).unwrap();
+
+                let mut reader = BufReader::with_capacity(
+                    8 * 1024,
+                    Tracking {
+                        inner: Cursor::new(body),
+                        largest: 0,
+                    },
+                );
+                let mut ack = Vec::new();
+                handle_receive_object(&dst, args, &mut reader, &mut ack).unwrap();
+                assert_eq!(ack, b"ok\nend\n");
+                let largest = reader.get_ref().largest;
+                assert!(largest <= 256 * 1024, "read of {} bytes", largest);
+            }
+
+            let tree = read_tree(&dst, &read_commit(&dst, &commit_hash).unwrap().tree).unwrap();
+            let blob_hash = tree.entries()[0].kind.hash().unwrap();
+            assert_eq!(read_blob(&dst, blob_hash).unwrap(), content);
+            assert_eq!(tmp_leftovers(&dst.tmp_path()), 0);
+        }
+        assert_eq!(tmp_leftovers(&src.tmp_path()), 0);
+    }
+}
@@ -2,19 +2,33 @@
 //!
 //! uses the `zub-remote` helper on the remote side (similar to git-receive-pack)
 
-use std::io::{BufRead, BufReader, Read, Write};
+use std::io::{BufRead, BufReader, Write};
 use std::path::Path;
 use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
 
 use crate::error::Result;
 use crate::hash::Hash;
 use crate::transport::local::ObjectSet;
+use crate::transport::retry::{RetryPolicy, Retrying};
+use crate::transport::wire::{
+    dictionary_id, encode_body, read_body, ObjectHeader, CAPABILITIES, CAP_ZSTD, DICT_PREFIX,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+
+/// object received over the wire: (type, hash, data, mode)
+pub type ReceivedObject = (String, Hash, Vec<u8>, u32);
 
 /// SSH connection to a remote repository
 pub struct SshConnection {
     child: Child,
-    reader: BufReader<ChildStdout>,
-    writer: ChildStdin,
+    reader: BufReader<Retrying<ChildStdout>>,
+    writer: Retrying<ChildStdin>,
+    /// zstd-compress object bodies on the wire (negotiated)
+    compress: bool,
+    /// protocol version agreed in the handshake
+    version: u32,
+    /// the remote repo's dictionary id, if its helper named one
+    remote_dictionary: Option<String>,
 }
 
 impl SshConnection {
@@ -37,11 +51,39 @@ impl SshConnection {
             message: "stdin not available".to_string(),
         })?;
 
-        Ok(Self {
+        let mut conn = Self {
             child,
-            reader: BufReader::new(stdout),
-            writer: stdin,
-        })
+            reader: BufReader::new(Retrying::new(stdout, RetryPolicy::default())),
+            writer: Retrying::new(stdin, RetryPolicy::default()),
+            compress: false,
+            version: 0,
+            remote_dictionary: None,
+        };
+        conn.version = hello(&mut conn.reader, &mut conn.writer)?;
+        let negotiated = negotiate(&mut conn.reader, &mut conn.writer, conn.version)?;
+        conn.compress = negotiated.compress;
+        conn.remote_dictionary = negotiated.dictionary;
+
+        Ok(conn)
+    }
+
+    /// protocol version agreed with the remote helper
+    pub fn protocol_version(&self) -> u32 {
+        self.version
+    }
+
+    /// retry reads and writes failing with a transient error up to
+    /// `max_retries` times, with exponential backoff (none by default)
+    pub fn set_max_retries(&mut self, max_retries: u32) {
+        let policy = RetryPolicy::new(max_retries);
+        self.reader.get_mut().policy = policy;
+        self.writer.policy = policy;
+    }
+
+    /// refuse to move trees and commits to or from a remote repo with a
+    /// different zstd dictionary, which the receiving side couldn't read
+    pub fn check_dictionary(&self, local: &crate::Repo) -> Result<()> {
+        check_dictionary(self.remote_dictionary.as_deref(), local)
     }
 
     /// list refs on the remote
@@ -75,6 +117,9 @@ impl SshConnection {
         for hash in &objects.commits {
             request.push_str(&format!("commit {}\n", hash));
         }
+        for hash in &objects.contents {
+            request.push_str(&format!("content {}\n", hash));
+        }
         request.push_str("end\n");
 
         self.send_raw(&request)?;
@@ -89,6 +134,7 @@ impl SshConnection {
                         "blob" => needed.blobs.push(hash),
                         "tree" => needed.trees.push(hash),
                         "commit" => needed.commits.push(hash),
+                        "content" => needed.contents.push(hash),
                         _ => {}
                     }
                 }
@@ -100,12 +146,21 @@ impl SshConnection {
 
     /// send an object to the remote
     pub fn send_object(&mut self, obj_type: &str, hash: &Hash, data: &[u8]) -> Result<()> {
-        let header = format!("object {} {} {}\n", obj_type, hash, data.len());
-        self.send_raw(&header)?;
+        let (body, compressed_size) = encode_body(obj_type, data, self.compress);
+        let header = ObjectHeader {
+            obj_type: obj_type.to_string(),
+            hash: *hash,
+            size: data.len(),
+            mode: None,
+            compressed_size,
+        };
+        self.send_raw(&format!("{}\n", header.format()))?;
 
-        self.writer.write_all(data).map_err(|e| crate::Error::Transport {
-            message: format!("failed to write object: {}", e),
-        })?;
+        self.writer
+            .write_all(&body)
+            .map_err(|e| crate::Error::Transport {
+                message: format!("failed to write object: {}", e),
+            })?;
 
         self.expect_ok()
     }
@@ -129,6 +184,9 @@ impl SshConnection {
         for hash in &objects.commits {
             request.push_str(&format!("commit {}\n", hash));
         }
+        for hash in &objects.contents {
+            request.push_str(&format!("content {}\n", hash));
+        }
         request.push_str("end\n");
 
         self.send_raw(&request)?;
@@ -143,6 +201,7 @@ impl SshConnection {
                         "blob" => missing.blobs.push(hash),
                         "tree" => missing.trees.push(hash),
                         "commit" => missing.commits.push(hash),
+                        "content" => missing.contents.push(hash),
                         _ => {}
                     }
                 }
@@ -154,46 +213,8 @@ impl SshConnection {
 
     /// receive an object from the remote
     /// returns (type, hash, data, mode) where mode is file permissions for blobs
-    pub fn receive_object(&mut self) -> Result<Option<(String, Hash, Vec<u8>, u32)>> {
-        let mut line = String::new();
-        self.reader
-            .read_line(&mut line)
-            .map_err(|e| crate::Error::Transport {
-                message: format!("failed to read: {}", e),
-            })?;
-
-        let line = line.trim();
-        if line == "end" {
-            return Ok(None);
-        }
-
-        // parse "object TYPE HASH SIZE MODE"
-        let parts: Vec<&str> = line.splitn(5, ' ').collect();
-        if parts.len() < 4 || parts[0] != "object" {
-            return Err(crate::Error::Transport {
-                message: format!("unexpected response: {}", line),
-            });
-        }
-
-        let obj_type = parts[1].to_string();
-        let hash = Hash::from_hex(parts[2])?;
-        let size: usize = parts[3].parse().map_err(|_| crate::Error::Transport {
-            message: format!("invalid size: {}", parts[3]),
-        })?;
-        // mode is optional for backwards compat, default to 0644
-        let mode: u32 = parts
-            .get(4)
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(0o644);
-
-        let mut data = vec![0u8; size];
-        self.reader
-            .read_exact(&mut data)
-            .map_err(|e| crate::Error::Transport {
-                message: format!("failed to read object data: {}", e),
-            })?;
-
-        Ok(Some((obj_type, hash, data, mode)))
+    pub fn receive_object(&mut self) -> Result<Option<ReceivedObject>> {
+        read_object(&mut self.reader)
     }
 
     /// request ref value from remote
@@ -220,47 +241,11 @@ impl SshConnection {
     }
 
     fn send_raw(&mut self, data: &str) -> Result<()> {
-        self.writer
-            .write_all(data.as_bytes())
-            .map_err(|e| crate::Error::Transport {
-                message: format!("failed to write: {}", e),
-            })?;
-
-        self.writer.flush().map_err(|e| crate::Error::Transport {
-            message: format!("failed to flush: {}", e),
-        })
+        send_raw(&mut self.writer, data)
     }
 
     fn read_response(&mut self) -> Result<String> {
-        let mut response = String::new();
-
-        loop {
-            let mut line = String::new();
-            let n = self
-                .reader
-                .read_line(&mut line)
-                .map_err(|e| crate::Error::Transport {
-                    message: format!("failed to read: {}", e),
-                })?;
-
-            if n == 0 {
-                break;
-            }
-
-            if line.trim() == "end" {
-                break;
-            }
-
-            if line.starts_with("error:") {
-                return Err(crate::Error::Transport {
-                    message: line[6..].trim().to_string(),
-                });
-            }
-
-            response.push_str(&line);
-        }
-
-        Ok(response)
+        read_response(&mut self.reader)
     }
 
     fn expect_ok(&mut self) -> Result<()> {
@@ -281,6 +266,183 @@ impl Drop for SshConnection {
     }
 }
 
+/// send `hello` and return the protocol version both sides will speak
+///
+/// helpers predating the handshake reject the command and speak v0.
+pub(crate) fn hello(reader: &mut impl BufRead, writer: &mut impl Write) -> Result<u32> {
+    send_raw(writer, &format!("hello {}\n", PROTOCOL_VERSION))?;
+    let response = match read_response(reader) {
+        Ok(response) => response,
+        Err(crate::Error::Transport { message }) if message.starts_with("unknown command") => {
+            return Ok(0);
+        }
+        Err(e) => return Err(e),
+    };
+
+    // `version VERSION MIN_VERSION`
+    let mut parts = response.split_whitespace();
+    let (remote, remote_min) = match (parts.next(), parts.next(), parts.next()) {
+        (Some("version"), Some(v), Some(min)) => match (v.parse::<u32>(), min.parse::<u32>()) {
+            (Ok(v), Ok(min)) => (v, min),
+            _ => return Err(bad_hello(&response)),
+        },
+        _ => return Err(bad_hello(&response)),
+    };
+
+    // the newer side steps down, unless that's below what either accepts
+    let agreed = remote.min(PROTOCOL_VERSION);
+    let accepted = MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION;
+    if agreed < remote_min || !accepted.contains(&agreed) {
+        return Err(crate::Error::ProtocolVersion {
+            local: PROTOCOL_VERSION,
+            remote,
+        });
+    }
+    Ok(agreed)
+}
+
+/// what the `capabilities` exchange settled
+#[derive(Debug, Default)]
+pub(crate) struct Negotiated {
+    /// zstd-compress object bodies on the wire
+    pub(crate) compress: bool,
+    /// the remote repo's dictionary id, if its helper named one
+    pub(crate) dictionary: Option<String>,
+}
+
+/// exchange capabilities with the remote helper
+///
+/// v0 helpers may reject the command, in which case everything stays
+/// uncompressed.
+pub(crate) fn negotiate(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    version: u32,
+) -> Result<Negotiated> {
+    send_raw(
+        writer,
+        &format!("capabilities {}\n", CAPABILITIES.join(" ")),
+    )?;
+    match read_response(reader) {
+        Ok(response) => Ok(Negotiated {
+            compress: response.split_whitespace().any(|c| c == CAP_ZSTD),
+            dictionary: response
+                .split_whitespace()
+                .find_map(|c| c.strip_prefix(DICT_PREFIX))
+                .map(str::to_string),
+        }),
+        Err(crate::Error::Transport { .. }) if version == 0 => Ok(Negotiated::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// compare a remote dictionary id with the local repo's
+///
+/// a helper that names none predates dictionaries, so its repo has none.
+pub(crate) fn check_dictionary(remote: Option<&str>, local: &crate::Repo) -> Result<()> {
+    let remote = remote.unwrap_or("none");
+    if remote != dictionary_id(local.zstd_dictionary()) {
+        return Err(crate::Error::Transport {
+            message: "local and remote repositories use different zstd dictionaries".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn bad_hello(response: &str) -> crate::Error {
+    crate::Error::Transport {
+        message: format!("invalid hello reply: {}", response.trim()),
+    }
+}
+
+fn send_raw(writer: &mut impl Write, data: &str) -> Result<()> {
+    writer
+        .write_all(data.as_bytes())
+        .map_err(|e| crate::Error::Transport {
+            message: format!("failed to write: {}", e),
+        })?;
+
+    writer.flush().map_err(|e| crate::Error::Transport {
+        message: format!("failed to flush: {}", e),
+    })
+}
+
+/// read response lines up to the terminating "end"
+fn read_response(reader: &mut impl BufRead) -> Result<String> {
+    let mut response = String::new();
+
+    loop {
+        let mut line = String::new();
+        let n = reader
+            .read_line(&mut line)
+            .map_err(|e| crate::Error::Transport {
+                message: format!("failed to read: {}", e),
+            })?;
+
+        if n == 0 {
+            break;
+        }
+
+        if line.trim() == "end" {
+            break;
+        }
+
+        if line.starts_with("error:") {
+            // drain up to the terminating "end" so the stream stays in sync
+            skip_to_end(reader)?;
+            return Err(crate::Error::Transport {
+                message: line[6..].trim().to_string(),
+            });
+        }
+
+        response.push_str(&line);
+    }
+
+    Ok(response)
+}
+
+fn skip_to_end(reader: &mut impl BufRead) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        let n = reader
+            .read_line(&mut line)
+            .map_err(|e| crate::Error::Transport {
+                message: format!("failed to read: {}", e),
+            })?;
+        if n == 0 || line.trim() == "end" {
+            return Ok(());
+        }
+    }
+}
+
+/// read one `object` frame (or the terminating "end") from a protocol stream
+pub(crate) fn read_object(reader: &mut impl BufRead) -> Result<Option<ReceivedObject>> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .map_err(|e| crate::Error::Transport {
+            message: format!("failed to read: {}", e),
+        })?;
+
+    let line = line.trim();
+    if line == "end" {
+        return Ok(None);
+    }
+
+    let args = line
+        .strip_prefix("object ")
+        .ok_or_else(|| crate::Error::Transport {
+            message: format!("unexpected response: {}", line),
+        })?;
+    let header = ObjectHeader::parse(args)?;
+    let data = read_body(reader, &header)?;
+
+    // mode is optional for backwards compat, default to 0644
+    let mode = header.mode.unwrap_or(0o644);
+
+    Ok(Some((header.obj_type, header.hash, data, mode)))
+}
+
 fn parse_remote(remote: &str) -> (String, Option<String>) {
     if remote.contains('@') {
         let parts: Vec<&str> = remote.splitn(2, '@').collect();
@@ -293,23 +455,57 @@ fn parse_remote(remote: &str) -> (String, Option<String>) {
 // deployed binary path: use $TMPDIR if set, otherwise ~/.cache
 const REMOTE_ZUB_PATH: &str = "${TMPDIR:-$HOME/.cache}/zub_auto_deployed";
 
+/// shell expression for the zub binary the remote side runs: PATH first,
+/// then the deployed copy
+fn remote_zub_command() -> String {
+    format!("$(command -v zub || echo {})", REMOTE_ZUB_PATH)
+}
+
+/// check that the remote has a zub that actually runs
+///
+/// a deploy interrupted by an older version could leave a truncated binary
+/// that passes `test -x`, so the binary is asked for its version instead.
 fn check_remote_zub(host: &str, user: Option<&str>) -> Result<bool> {
     let mut cmd = Command::new("ssh");
     if let Some(u) = user {
         cmd.arg("-l").arg(u);
     }
     cmd.arg(host);
-    // check both PATH and our deploy location
-    cmd.arg(format!(
-        "command -v zub >/dev/null 2>&1 || test -x {}",
-        REMOTE_ZUB_PATH
-    ));
+    cmd.arg(format!("{} --version 2>/dev/null", remote_zub_command()));
+    cmd.stderr(Stdio::inherit());
 
-    let status = cmd.status().map_err(|e| crate::Error::Transport {
+    let output = cmd.output().map_err(|e| crate::Error::Transport {
         message: format!("failed to check remote zub: {}", e),
     })?;
 
-    Ok(status.success())
+    Ok(output.status.success() && parse_version_probe(&output.stdout).is_some())
+}
+
+/// version reported by `zub --version`, if the output looks like one
+fn parse_version_probe(output: &[u8]) -> Option<String> {
+    let output = String::from_utf8_lossy(output);
+    let version = output.lines().next()?.trim().strip_prefix("zub ")?.trim();
+    if version.is_empty() || !version.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    Some(version.to_string())
+}
+
+/// quote a string for a POSIX shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// remote command moving an uploaded binary into place
+///
+/// the rename is atomic, so other connections see either the old binary or
+/// the complete new one; a failed install leaves no temp file behind.
+fn install_command(tmp_path: &str, final_path: &str) -> String {
+    let tmp = shell_quote(tmp_path);
+    format!(
+        "chmod +x {tmp} && mv -f {tmp} {} || {{ rm -f {tmp}; exit 1; }}",
+        shell_quote(final_path)
+    )
 }
 
 fn deploy_zub_to_remote(host: &str, user: Option<&str>) -> Result<()> {
@@ -325,12 +521,6 @@ fn deploy_zub_to_remote(host: &str, user: Option<&str>) -> Result<()> {
     // get the resolved remote path
     let resolved_path = get_resolved_remote_path(host, user)?;
 
-    let remote_target = if let Some(u) = user {
-        format!("{}@{}:{}", u, host, resolved_path)
-    } else {
-        format!("{}:{}", host, resolved_path)
-    };
-
     // ensure parent directory exists on remote
     let mut mkdir_cmd = Command::new("ssh");
     if let Some(u) = user {
@@ -349,36 +539,51 @@ fn deploy_zub_to_remote(host: &str, user: Option<&str>) -> Result<()> {
         });
     }
 
-    // copy the binary
+    // upload next to the final path, then rename it into place
+    let tmp_path = format!("{}.tmp-{}", resolved_path, uuid::Uuid::new_v4());
+    let remote_tmp = if let Some(u) = user {
+        format!("{}@{}:{}", u, host, tmp_path)
+    } else {
+        format!("{}:{}", host, tmp_path)
+    };
+
     let status = Command::new("scp")
         .arg(&local_exe)
-        .arg(&remote_target)
+        .arg(&remote_tmp)
         .status()
         .map_err(|e| crate::Error::Transport {
             message: format!("failed to copy zub to remote: {}", e),
         })?;
 
     if !status.success() {
+        // best effort: don't leave a partial upload around
+        let mut rm_cmd = Command::new("ssh");
+        if let Some(u) = user {
+            rm_cmd.arg("-l").arg(u);
+        }
+        rm_cmd.arg(host);
+        rm_cmd.arg(format!("rm -f {}", shell_quote(&tmp_path)));
+        let _ = rm_cmd.status();
+
         return Err(crate::Error::Transport {
             message: "failed to copy zub binary to remote".to_string(),
         });
     }
 
-    // make it executable
-    let mut chmod_cmd = Command::new("ssh");
+    let mut install_cmd = Command::new("ssh");
     if let Some(u) = user {
-        chmod_cmd.arg("-l").arg(u);
+        install_cmd.arg("-l").arg(u);
     }
-    chmod_cmd.arg(host);
-    chmod_cmd.arg(format!("chmod +x {}", REMOTE_ZUB_PATH));
+    install_cmd.arg(host);
+    install_cmd.arg(install_command(&tmp_path, &resolved_path));
 
-    let status = chmod_cmd.status().map_err(|e| crate::Error::Transport {
-        message: format!("failed to chmod zub on remote: {}", e),
+    let status = install_cmd.status().map_err(|e| crate::Error::Transport {
+        message: format!("failed to install zub on remote: {}", e),
     })?;
 
     if !status.success() {
         return Err(crate::Error::Transport {
-            message: "failed to make zub executable on remote".to_string(),
+            message: "failed to install zub binary on remote".to_string(),
         });
     }
 
@@ -417,8 +622,8 @@ fn spawn_remote(host: &str, user: Option<&str>, repo_path: &Path) -> Result<std:
     cmd.arg(host);
     // try zub in PATH first, fall back to deployed location
     cmd.arg(format!(
-        "$(command -v zub || echo {}) zub-remote {}",
-        REMOTE_ZUB_PATH,
+        "{} zub-remote {}",
+        remote_zub_command(),
         repo_path.display()
     ));
 
@@ -431,4 +636,142 @@ fn spawn_remote(host: &str, user: Option<&str>, repo_path: &Path) -> Result<std:
     })
 }
 
-// note: SSH transport tests require a remote server, so they're integration tests
+// note: SSH transport tests require a remote server, so they're integration tests;
+// only the remote command construction is covered here
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_install_command() {
+        assert_eq!(
+            install_command("/home/u/.cache/zub.tmp-1", "/home/u/.cache/zub"),
+            "chmod +x '/home/u/.cache/zub.tmp-1' && mv -f '/home/u/.cache/zub.tmp-1' \
+             '/home/u/.cache/zub' || { rm -f '/home/u/.cache/zub.tmp-1'; exit 1; }"
+        );
+        assert_eq!(shell_quote("it's here"), "'it'\\''s here'");
+    }
+
+    #[test]
+    fn test_parse_version_probe() {
+        assert_eq!(
+            parse_version_probe(b"zub 0.1.0\n").as_deref(),
+            Some("0.1.0")
+        );
+        assert_eq!(
+            parse_version_probe(b"zub 1.2.3-rc1\nextra\n").as_deref(),
+            Some("1.2.3-rc1")
+        );
+        // truncated binaries print nothing or garbage
+        assert_eq!(parse_version_probe(b""), None);
+        assert_eq!(parse_version_probe(b"zub \n"), None);
+        assert_eq!(parse_version_probe(b"zub zub-remote\n"), None);
+        assert_eq!(parse_version_probe(b"\x7fELF\x02\x01"), None);
+        assert_eq!(parse_version_probe(b"git version 2.43.0\n"), None);
+    }
+
+    /// run the client handshake against a scripted peer reply
+    fn hello_with(reply: &str) -> (Result<u32>, String) {
+        let mut sent = Vec::new();
+        let result = hello(&mut std::io::Cursor::new(reply), &mut sent);
+        (result, String::from_utf8(sent).unwrap())
+    }
+
+    #[test]
+    fn test_hello_handshake() {
+        // a helper predating the handshake rejects it and is spoken to as v0
+        let (version, sent) = hello_with("error: unknown command: hello\nend\n");
+        assert_eq!(version.unwrap(), 0);
+        assert_eq!(sent, format!("hello {}\n", PROTOCOL_VERSION));
+
+        // a newer helper that still accepts us settles on our version
+        let newer = format!(
+            "version {} {}\nend\n",
+            PROTOCOL_VERSION + 1,
+            PROTOCOL_VERSION
+        );
+        assert_eq!(hello_with(&newer).0.unwrap(), PROTOCOL_VERSION);
+
+        // one that no longer does is a typed mismatch
+        let too_new = format!(
+            "version {} {}\nend\n",
+            PROTOCOL_VERSION + 2,
+            PROTOCOL_VERSION + 1
+        );
+        assert!(matches!(
+            hello_with(&too_new).0,
+            Err(crate::Error::ProtocolVersion { local, remote })
+                if local == PROTOCOL_VERSION && remote == PROTOCOL_VERSION + 2
+        ));
+
+        assert!(matches!(
+            hello_with("garbage\nend\n").0,
+            Err(crate::Error::Transport { .. })
+        ));
+    }
+
+    #[test]
+    fn test_hello_against_helper() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = crate::Repo::init(&dir.path().join("repo")).unwrap();
+
+        // the helper answers the client's hello with a version both accept
+        let mut request = Vec::new();
+        let _ = hello(&mut std::io::Cursor::new(""), &mut request);
+        let mut reply = Vec::new();
+        crate::transport::serve::serve(&repo, &mut std::io::Cursor::new(request), &mut reply)
+            .unwrap();
+        let (version, _) = hello_with(std::str::from_utf8(&reply).unwrap());
+        assert_eq!(version.unwrap(), PROTOCOL_VERSION);
+
+        // a v0 client never says hello and is served as before
+        let mut reply = Vec::new();
+        let input = "list-refs\nquit\n";
+        crate::transport::serve::serve(&repo, &mut std::io::Cursor::new(input), &mut reply)
+            .unwrap();
+        assert_eq!(reply, b"end\n");
+    }
+
+    #[test]
+    fn test_dictionary_checked_against_helper() {
+        let dir = tempfile::tempdir().unwrap();
+        let plain = crate::Repo::init(&dir.path().join("plain")).unwrap();
+        let dict = vec![7u8; 1024];
+        let init = |name: &str, dictionary: Vec<u8>| {
+            let opts = crate::InitOptions {
+                dictionary: Some(dictionary),
+                ..Default::default()
+            };
+            crate::Repo::init_with_options(&dir.path().join(name), &opts).unwrap()
+        };
+        let with_dict = init("a", dict.clone());
+        let other = init("b", vec![9u8; 1024]);
+
+        // what the helper serving `remote` tells a client
+        let negotiate_with = |remote: &crate::Repo| {
+            let mut request = Vec::new();
+            let _ = negotiate(&mut std::io::Cursor::new(""), &mut request, 1);
+            let mut reply = Vec::new();
+            crate::transport::serve::serve(remote, &mut std::io::Cursor::new(request), &mut reply)
+                .unwrap();
+            negotiate(&mut std::io::Cursor::new(reply), &mut Vec::new(), 1).unwrap()
+        };
+
+        let negotiated = negotiate_with(&with_dict);
+        assert!(negotiated.compress);
+        let remote = negotiated.dictionary.as_deref();
+        assert!(check_dictionary(remote, &with_dict).is_ok());
+        assert!(check_dictionary(remote, &plain).is_err());
+        assert!(check_dictionary(remote, &other).is_err());
+
+        let remote = negotiate_with(&plain).dictionary;
+        assert_eq!(remote.as_deref(), Some("none"));
+        assert!(check_dictionary(remote.as_deref(), &plain).is_ok());
+        assert!(check_dictionary(remote.as_deref(), &with_dict).is_err());
+
+        // a helper predating dictionaries names none
+        assert!(check_dictionary(None, &plain).is_ok());
+        assert!(check_dictionary(None, &with_dict).is_err());
+    }
+}
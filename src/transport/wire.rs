@@ -0,0 +1,325 @@
+//! wire encoding shared by the SSH client and the remote helper
+//!
+//! object bodies may be zstd-compressed in transit when both sides advertise
+//! the `zstd` capability. the at-rest format is never affected.
+
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+use crate::error::Result;
+use crate::hash::Hash;
+
+/// protocol version announced in the `hello` handshake
+///
+/// 0 is a peer predating the handshake, where `capabilities` may be missing
+/// too; from 1 on both sides support `capabilities`.
+pub(crate) const PROTOCOL_VERSION: u32 = 1;
+
+/// oldest protocol version this build can still talk to
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 0;
+
+/// capability token for zstd-compressed object bodies
+pub(crate) const CAP_ZSTD: &str = "zstd";
+
+/// capability token asking the helper which zstd dictionary its repo uses
+pub(crate) const CAP_DICT: &str = "dict";
+
+/// prefix of the helper's reply naming its dictionary, after `CAP_DICT`
+pub(crate) const DICT_PREFIX: &str = "dict=";
+
+/// capabilities this build understands
+pub(crate) const CAPABILITIES: &[&str] = &[CAP_ZSTD, CAP_DICT];
+
+const WIRE_ZSTD_LEVEL: i32 = 3;
+
+/// how a repo's zstd dictionary is named in the handshake: its blake3 hash,
+/// or `none`
+pub(crate) fn dictionary_id(dictionary: Option<&[u8]>) -> String {
+    match dictionary {
+        Some(dict) => blake3::hash(dict).to_hex().to_string(),
+        None => "none".to_string(),
+    }
+}
+
+/// objects larger than this are streamed rather than held in memory
+pub(crate) const STREAM_THRESHOLD: u64 = 1024 * 1024;
+
+/// chunk size for streamed bodies
+pub(crate) const WIRE_CHUNK: usize = 64 * 1024;
+
+/// parsed `object TYPE HASH SIZE [MODE] [zstd=CSIZE]` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ObjectHeader {
+    pub obj_type: String,
+    pub hash: Hash,
+    /// size of the object as stored on disk
+    pub size: usize,
+    pub mode: Option<u32>,
+    /// size of the body on the wire when compressed
+    pub compressed_size: Option<usize>,
+}
+
+impl ObjectHeader {
+    /// parse the arguments following the `object` keyword
+    pub fn parse(args: &str) -> Result<Self> {
+        let mut parts = args.split_whitespace();
+        let (obj_type, hash, size) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(t), Some(h), Some(s)) => (t, h, s),
+            _ => return Err(transport_err(format!("invalid object header: {}", args))),
+        };
+
+        let hash = Hash::from_hex(hash)?;
+        let size = size
+            .parse()
+            .map_err(|_| transport_err(format!("invalid size: {}", size)))?;
+
+        let mut mode = None;
+        let mut compressed_size = None;
+        for part in parts {
+            if let Some(csize) = part.strip_prefix("zstd=") {
+                compressed_size =
+                    Some(csize.parse().map_err(|_| {
+                        transport_err(format!("invalid compressed size: {}", csize))
+                    })?);
+            } else if mode.is_none() {
+                mode = part.parse().ok();
+            }
+        }
+
+        Ok(Self {
+            obj_type: obj_type.to_string(),
+            hash,
+            size,
+            mode,
+            compressed_size,
+        })
+    }
+
+    /// format as a full header line (without trailing newline)
+    pub fn format(&self) -> String {
+        let mut line = format!("object {} {} {}", self.obj_type, self.hash, self.size);
+        if let Some(mode) = self.mode {
+            line.push_str(&format!(" {}", mode));
+        }
+        if let Some(csize) = self.compressed_size {
+            line.push_str(&format!(" zstd={}", csize));
+        }
+        line
+    }
+
+    /// number of body bytes following the header on the wire
+    pub fn wire_size(&self) -> usize {
+        self.compressed_size.unwrap_or(self.size)
+    }
+}
+
+/// encode an object body for the wire
+///
+/// trees and commits are already compressed at rest, so only blob-like objects
+/// are compressed, and only when it actually shrinks the body.
+pub(crate) fn encode_body<'a>(
+    obj_type: &str,
+    data: &'a [u8],
+    compress: bool,
+) -> (Cow<'a, [u8]>, Option<usize>) {
+    if !compress || !matches!(obj_type, "blob" | "content") || data.is_empty() {
+        return (Cow::Borrowed(data), None);
+    }
+
+    match zstd::encode_all(data, WIRE_ZSTD_LEVEL) {
+        Ok(compressed) if compressed.len() < data.len() => {
+            let len = compressed.len();
+            (Cow::Owned(compressed), Some(len))
+        }
+        _ => (Cow::Borrowed(data), None),
+    }
+}
+
+/// read an object body described by `header`, decompressing if needed
+pub(crate) fn read_body(reader: &mut impl Read, header: &ObjectHeader) -> Result<Vec<u8>> {
+    let mut body = vec![0u8; header.wire_size()];
+    reader
+        .read_exact(&mut body)
+        .map_err(|e| transport_err(format!("failed to read object data: {}", e)))?;
+
+    if header.compressed_size.is_none() {
+        return Ok(body);
+    }
+
+    // never inflate past the announced size, however small the body
+    let mut data = Vec::new();
+    zstd::stream::read::Decoder::new(body.as_slice())
+        .and_then(|d| d.take(header.size as u64 + 1).read_to_end(&mut data))
+        .map_err(|e| transport_err(format!("failed to decompress object: {}", e)))?;
+    if data.len() != header.size {
+        return Err(transport_err(format!(
+            "object {} decompressed to {} bytes, expected {}",
+            header.hash,
+            data.len(),
+            header.size
+        )));
+    }
+    Ok(data)
+}
+
+/// zstd-compress `src` into `dest` without buffering the whole object
+pub(crate) fn encode_stream(src: &mut impl Read, dest: &mut impl Write) -> Result<()> {
+    zstd::stream::copy_encode(src, dest, WIRE_ZSTD_LEVEL)
+        .map_err(|e| transport_err(format!("failed to compress object: {}", e)))
+}
+
+/// copy exactly `len` bytes from `reader` to `writer` in `WIRE_CHUNK` pieces
+pub(crate) fn copy_body(reader: &mut impl Read, writer: &mut impl Write, len: u64) -> Result<()> {
+    let mut buf = vec![0u8; WIRE_CHUNK];
+    let mut remaining = len;
+    while remaining > 0 {
+        let want = remaining.min(WIRE_CHUNK as u64) as usize;
+        reader
+            .read_exact(&mut buf[..want])
+            .map_err(|e| transport_err(format!("failed to read object data: {}", e)))?;
+        writer
+            .write_all(&buf[..want])
+            .map_err(|e| transport_err(format!("failed to write object data: {}", e)))?;
+        remaining -= want as u64;
+    }
+    Ok(())
+}
+
+/// stream an object body described by `header` into `writer`, decompressing
+/// if needed; memory use is bounded regardless of the object size
+pub(crate) fn read_body_to(
+    reader: &mut impl Read,
+    header: &ObjectHeader,
+    writer: &mut impl Write,
+) -> Result<()> {
+    if header.compressed_size.is_none() {
+        return copy_body(reader, writer, header.size as u64);
+    }
+
+    let mut body = reader.take(header.wire_size() as u64);
+    let mut counted = CountingWriter {
+        inner: writer,
+        count: 0,
+    };
+    // as in `read_body`, a frame can't write more than the announced size
+    zstd::stream::read::Decoder::new(&mut body)
+        .and_then(|d| std::io::copy(&mut d.take(header.size as u64 + 1), &mut counted))
+        .map_err(|e| transport_err(format!("failed to decompress object: {}", e)))?;
+    // keep the stream aligned on the next frame even if the encoder padded
+    std::io::copy(&mut body, &mut std::io::sink())
+        .map_err(|e| transport_err(format!("failed to read object data: {}", e)))?;
+
+    if counted.count != header.size as u64 {
+        return Err(transport_err(format!(
+            "object {} decompressed to {} bytes, expected {}",
+            header.hash, counted.count, header.size
+        )));
+    }
+    Ok(())
+}
+
+struct CountingWriter<'a, W> {
+    inner: &'a mut W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn transport_err(message: String) -> crate::Error {
+    crate::Error::Transport { message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_roundtrip() {
+        let header = ObjectHeader {
+            obj_type: "blob".to_string(),
+            hash: Hash::ZERO,
+            size: 1234,
+            mode: Some(0o755),
+            compressed_size: Some(99),
+        };
+        let line = header.format();
+        let args = line.strip_prefix("object ").unwrap();
+        assert_eq!(ObjectHeader::parse(args).unwrap(), header);
+
+        // legacy headers without mode or compression
+        let legacy = ObjectHeader::parse(&format!("tree {} 10", Hash::ZERO)).unwrap();
+        assert_eq!(legacy.mode, None);
+        assert_eq!(legacy.compressed_size, None);
+        assert_eq!(legacy.wire_size(), 10);
+    }
+
+    #[test]
+    fn test_read_body_to_roundtrip() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 7) as u8).collect();
+
+        let mut compressed = Vec::new();
+        encode_stream(&mut data.as_slice(), &mut compressed).unwrap();
+        let header = ObjectHeader {
+            obj_type: "blob".to_string(),
+            hash: Hash::ZERO,
+            size: data.len(),
+            mode: None,
+            compressed_size: Some(compressed.len()),
+        };
+        let mut stream = compressed.clone();
+        stream.extend_from_slice(b"end\n");
+
+        let mut reader = stream.as_slice();
+        let mut out = Vec::new();
+        read_body_to(&mut reader, &header, &mut out).unwrap();
+        assert_eq!(out, data);
+        assert_eq!(reader, b"end\n");
+
+        // a size mismatch is caught
+        let wrong = ObjectHeader { size: 5, ..header };
+        let mut out = Vec::new();
+        assert!(read_body_to(&mut compressed.as_slice(), &wrong, &mut out).is_err());
+    }
+
+    #[test]
+    fn test_read_body_stops_at_announced_size() {
+        let bomb = zstd::encode_all(&vec![0u8; 8 << 20][..], 19).unwrap();
+        let header = ObjectHeader {
+            obj_type: "blob".to_string(),
+            hash: Hash::ZERO,
+            size: 16,
+            mode: None,
+            compressed_size: Some(bomb.len()),
+        };
+
+        assert!(read_body(&mut bomb.as_slice(), &header).is_err());
+
+        let mut out = Vec::new();
+        assert!(read_body_to(&mut bomb.as_slice(), &header, &mut out).is_err());
+        assert_eq!(out.len(), 17);
+    }
+
+    #[test]
+    fn test_encode_skips_trees_and_incompressible() {
+        let data = vec![b'a'; 4096];
+        let (body, csize) = encode_body("tree", &data, true);
+        assert!(csize.is_none());
+        assert_eq!(body.len(), data.len());
+
+        let (_, csize) = encode_body("blob", &data, false);
+        assert!(csize.is_none());
+
+        let (_, csize) = encode_body("blob", b"x", true);
+        assert!(csize.is_none());
+    }
+}
@@ -20,6 +20,16 @@ pub struct Commit {
     /// optional key-value metadata (uses BTreeMap for deterministic serialization)
     #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
     pub metadata: BTreeMap<String, String>,
+    /// `NsConfig::fingerprint` of the repo mapping the tree was committed
+    /// under. it is serialized like any other field, so it's part of the
+    /// commit hash; commits without it (older ones) encode and hash as before.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ns_fingerprint: Option<Hash>,
+    /// 0 for a root, else one more than the highest parent generation.
+    /// none on older commits and on commits with a parent lacking one;
+    /// ancestry walks only prune on commits that have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
 }
 
 impl Commit {
@@ -40,6 +50,8 @@ impl Commit {
                 .unwrap_or(0),
             message: message.into(),
             metadata: BTreeMap::new(),
+            ns_fingerprint: None,
+            generation: None,
         }
     }
 
@@ -58,6 +70,8 @@ impl Commit {
             timestamp,
             message: message.into(),
             metadata: BTreeMap::new(),
+            ns_fingerprint: None,
+            generation: None,
         }
     }
 
@@ -67,6 +81,18 @@ impl Commit {
         self
     }
 
+    /// record the namespace mapping fingerprint
+    pub fn with_ns_fingerprint(mut self, fingerprint: Hash) -> Self {
+        self.ns_fingerprint = Some(fingerprint);
+        self
+    }
+
+    /// record the generation number
+    pub fn with_generation(mut self, generation: u64) -> Self {
+        self.generation = Some(generation);
+        self
+    }
+
     /// is this an initial commit (no parents)
     pub fn is_root(&self) -> bool {
         self.parents.is_empty()
@@ -78,6 +104,37 @@ impl Commit {
     }
 }
 
+/// a commit's fields other than its message, for walking history
+///
+/// decoded from the same object as `Commit`; the message is skipped.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct CommitHeader {
+    pub tree: Hash,
+    pub parents: Vec<Hash>,
+    pub author: String,
+    pub timestamp: i64,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub ns_fingerprint: Option<Hash>,
+    #[serde(default)]
+    pub generation: Option<u64>,
+}
+
+impl From<Commit> for CommitHeader {
+    fn from(commit: Commit) -> Self {
+        Self {
+            tree: commit.tree,
+            parents: commit.parents,
+            author: commit.author,
+            timestamp: commit.timestamp,
+            metadata: commit.metadata,
+            ns_fingerprint: commit.ns_fingerprint,
+            generation: commit.generation,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
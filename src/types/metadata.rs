@@ -37,8 +37,39 @@ impl SparseRegion {
     }
 }
 
+/// a point in time, seconds and nanoseconds since the unix epoch
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Timespec {
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl Timespec {
+    pub fn new(sec: i64, nsec: u32) -> Self {
+        Self { sec, nsec }
+    }
+}
+
+impl std::fmt::Display for Timespec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{:09}", self.sec, self.nsec)
+    }
+}
+
+/// file times recorded with `CommitOptions::capture_times`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryTimes {
+    pub mtime: Timespec,
+    pub atime: Timespec,
+    /// birth time, where the platform reports it; kept for inspection only,
+    /// checkout can't set it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub btime: Option<Timespec>,
+}
+
 /// diff entry change kind
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum ChangeKind {
     Added,
     Modified,
@@ -58,7 +89,7 @@ impl std::fmt::Display for ChangeKind {
 }
 
 /// entry in a diff result
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DiffEntry {
     pub path: String,
     pub kind: ChangeKind,
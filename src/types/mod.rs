@@ -4,6 +4,6 @@ mod metadata;
 mod tree;
 
 pub use artifact::Artifact;
-pub use commit::Commit;
-pub use metadata::{ChangeKind, DiffEntry, SparseRegion, Xattr};
-pub use tree::{EntryKind, Tree, TreeEntry};
+pub use commit::{Commit, CommitHeader};
+pub use metadata::{ChangeKind, DiffEntry, EntryTimes, SparseRegion, Timespec, Xattr};
+pub use tree::{EntryKind, EntryNamePolicy, Tree, TreeEntry};
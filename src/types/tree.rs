@@ -1,8 +1,10 @@
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 use crate::error::{Error, Result};
 use crate::hash::Hash;
-use crate::types::{SparseRegion, Xattr};
+use crate::types::{EntryTimes, SparseRegion, Xattr};
 
 /// a directory tree - collection of entries sorted by name
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -10,13 +12,94 @@ pub struct Tree {
     entries: Vec<TreeEntry>,
 }
 
+/// optional restrictions on tree entry names, on top of the always-enforced rules
+///
+/// the default is permissive: any name that is valid on linux is accepted.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EntryNamePolicy {
+    /// reject names that are not valid UTF-8 (stored lossily with U+FFFD)
+    pub reject_non_utf8: bool,
+    /// reject names in one tree that are equal once case-folded
+    pub reject_case_collisions: bool,
+    /// reject decomposed (NFD-style) names containing combining marks, so
+    /// normalizing filesystems can't fold two stored names together
+    pub reject_mixed_normalization: bool,
+}
+
+impl EntryNamePolicy {
+    /// every check enabled
+    pub fn strict() -> Self {
+        Self {
+            reject_non_utf8: true,
+            reject_case_collisions: true,
+            reject_mixed_normalization: true,
+        }
+    }
+
+    /// true when no extra checks are enabled
+    pub fn is_permissive(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// check the names of a tree's entries against this policy
+    fn check(&self, entries: &[TreeEntry]) -> Result<()> {
+        let mut folded: HashMap<String, &str> = HashMap::new();
+
+        for entry in entries {
+            let name = entry.name.as_str();
+            if self.reject_non_utf8 && name.contains(char::REPLACEMENT_CHARACTER) {
+                return Err(Error::InvalidEntryName(format!(
+                    "name is not valid UTF-8: {}",
+                    name
+                )));
+            }
+            if self.reject_mixed_normalization && name.chars().any(is_combining_mark) {
+                return Err(Error::InvalidEntryName(format!(
+                    "name is not in composed form: {}",
+                    name
+                )));
+            }
+            if self.reject_case_collisions {
+                if let Some(other) = folded.insert(name.to_lowercase(), name) {
+                    return Err(Error::InvalidEntryName(format!(
+                        "{} collides with {} when case-folded",
+                        name, other
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// combining marks from the blocks that precomposed characters decompose into
+fn is_combining_mark(c: char) -> bool {
+    matches!(
+        c,
+        '\u{0300}'..='\u{036F}'
+            | '\u{1AB0}'..='\u{1AFF}'
+            | '\u{1DC0}'..='\u{1DFF}'
+            | '\u{20D0}'..='\u{20FF}'
+            | '\u{3099}'..='\u{309A}'
+            | '\u{FE20}'..='\u{FE2F}'
+    )
+}
+
 impl Tree {
     /// create a new tree, validating and sorting entries
-    pub fn new(mut entries: Vec<TreeEntry>) -> Result<Self> {
+    pub fn new(entries: Vec<TreeEntry>) -> Result<Self> {
+        Self::with_policy(entries, &EntryNamePolicy::default())
+    }
+
+    /// create a new tree, additionally enforcing a name policy
+    pub fn with_policy(mut entries: Vec<TreeEntry>, policy: &EntryNamePolicy) -> Result<Self> {
         // validate entry names
         for entry in &entries {
             validate_entry_name(&entry.name)?;
         }
+        policy.check(&entries)?;
 
         // sort by name (byte-wise)
         entries.sort_by(|a, b| a.name.as_bytes().cmp(b.name.as_bytes()));
@@ -63,6 +146,31 @@ impl Tree {
     pub fn is_empty(&self) -> bool {
         self.entries.is_empty()
     }
+
+    /// check the invariants `new` establishes: valid names, sorted, no duplicates
+    ///
+    /// deserializing doesn't go through `new`, so a tree read from the store
+    /// only has them if its writer did.
+    pub fn validate(&self) -> Result<()> {
+        for entry in &self.entries {
+            validate_entry_name(&entry.name)?;
+        }
+        for window in self.entries.windows(2) {
+            match window[0].name.as_bytes().cmp(window[1].name.as_bytes()) {
+                std::cmp::Ordering::Less => {}
+                std::cmp::Ordering::Equal => {
+                    return Err(Error::DuplicateEntryName(window[0].name.clone()))
+                }
+                std::cmp::Ordering::Greater => {
+                    return Err(Error::InvalidEntryName(format!(
+                        "{} sorted before {}",
+                        window[0].name, window[1].name
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 /// validate an entry name
@@ -121,6 +229,11 @@ pub enum EntryKind {
         sparse_map: Option<Vec<SparseRegion>>,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         xattrs: Vec<Xattr>,
+        /// content-only hash when stored in content-addressed mode
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content: Option<Hash>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        times: Option<EntryTimes>,
     },
 
     /// symbolic link
@@ -128,6 +241,8 @@ pub enum EntryKind {
         hash: Hash,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         xattrs: Vec<Xattr>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        times: Option<EntryTimes>,
     },
 
     /// directory
@@ -138,6 +253,8 @@ pub enum EntryKind {
         mode: u32,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         xattrs: Vec<Xattr>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        times: Option<EntryTimes>,
     },
 
     /// block device
@@ -149,6 +266,8 @@ pub enum EntryKind {
         mode: u32,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         xattrs: Vec<Xattr>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        times: Option<EntryTimes>,
     },
 
     /// character device
@@ -160,6 +279,8 @@ pub enum EntryKind {
         mode: u32,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         xattrs: Vec<Xattr>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        times: Option<EntryTimes>,
     },
 
     /// named pipe (fifo)
@@ -169,6 +290,8 @@ pub enum EntryKind {
         mode: u32,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         xattrs: Vec<Xattr>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        times: Option<EntryTimes>,
     },
 
     /// unix socket
@@ -178,6 +301,8 @@ pub enum EntryKind {
         mode: u32,
         #[serde(default, skip_serializing_if = "Vec::is_empty")]
         xattrs: Vec<Xattr>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        times: Option<EntryTimes>,
     },
 
     /// hardlink to another file in the same tree
@@ -227,6 +352,14 @@ impl EntryKind {
         }
     }
 
+    /// logical size of a regular file (sparse holes included), none for other kinds
+    pub fn size(&self) -> Option<u64> {
+        match self {
+            EntryKind::Regular { size, .. } => Some(*size),
+            _ => None,
+        }
+    }
+
     /// create a regular file entry
     pub fn regular(hash: Hash, size: u64, xattrs: Vec<Xattr>) -> Self {
         Self::Regular {
@@ -234,6 +367,8 @@ impl EntryKind {
             size,
             sparse_map: None,
             xattrs,
+            content: None,
+            times: None,
         }
     }
 
@@ -244,12 +379,78 @@ impl EntryKind {
             size,
             sparse_map: Some(sparse_map),
             xattrs,
+            content: None,
+            times: None,
+        }
+    }
+
+    /// set the content hash on a regular file entry (no-op for other kinds)
+    pub fn with_content(mut self, content_hash: Option<Hash>) -> Self {
+        if let Self::Regular { content, .. } = &mut self {
+            *content = content_hash;
+        }
+        self
+    }
+
+    /// get the content hash of a content-addressed regular file
+    pub fn content_hash(&self) -> Option<&Hash> {
+        match self {
+            EntryKind::Regular { content, .. } => content.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// set recorded times on an entry (no-op for hardlinks, which share
+    /// their target's)
+    pub fn with_times(mut self, entry_times: Option<EntryTimes>) -> Self {
+        match &mut self {
+            EntryKind::Regular { times, .. }
+            | EntryKind::Symlink { times, .. }
+            | EntryKind::Directory { times, .. }
+            | EntryKind::BlockDevice { times, .. }
+            | EntryKind::CharDevice { times, .. }
+            | EntryKind::Fifo { times, .. }
+            | EntryKind::Socket { times, .. } => *times = entry_times,
+            EntryKind::Hardlink { .. } => {}
+        }
+        self
+    }
+
+    /// times recorded at commit, if captured
+    pub fn times(&self) -> Option<&EntryTimes> {
+        match self {
+            EntryKind::Regular { times, .. }
+            | EntryKind::Symlink { times, .. }
+            | EntryKind::Directory { times, .. }
+            | EntryKind::BlockDevice { times, .. }
+            | EntryKind::CharDevice { times, .. }
+            | EntryKind::Fifo { times, .. }
+            | EntryKind::Socket { times, .. } => times.as_ref(),
+            EntryKind::Hardlink { .. } => None,
+        }
+    }
+
+    /// xattrs recorded on an entry (none on hardlinks)
+    pub fn xattrs_mut(&mut self) -> Option<&mut Vec<Xattr>> {
+        match self {
+            EntryKind::Regular { xattrs, .. }
+            | EntryKind::Symlink { xattrs, .. }
+            | EntryKind::Directory { xattrs, .. }
+            | EntryKind::BlockDevice { xattrs, .. }
+            | EntryKind::CharDevice { xattrs, .. }
+            | EntryKind::Fifo { xattrs, .. }
+            | EntryKind::Socket { xattrs, .. } => Some(xattrs),
+            EntryKind::Hardlink { .. } => None,
         }
     }
 
     /// create a symlink entry
     pub fn symlink(hash: Hash, xattrs: Vec<Xattr>) -> Self {
-        Self::Symlink { hash, xattrs }
+        Self::Symlink {
+            hash,
+            xattrs,
+            times: None,
+        }
     }
 
     /// create a directory entry
@@ -260,6 +461,7 @@ impl EntryKind {
             gid,
             mode,
             xattrs: vec![],
+            times: None,
         }
     }
 
@@ -277,6 +479,7 @@ impl EntryKind {
             gid,
             mode,
             xattrs,
+            times: None,
         }
     }
 
@@ -366,6 +569,39 @@ mod tests {
         assert!(Tree::new(entries).is_err());
     }
 
+    #[test]
+    fn test_tree_case_collision_policy() {
+        let entries = vec![
+            TreeEntry::new("README", EntryKind::regular(Hash::ZERO, 0, vec![])),
+            TreeEntry::new("readme", EntryKind::regular(Hash::ZERO, 0, vec![])),
+        ];
+        assert!(Tree::new(entries.clone()).is_ok());
+        assert!(matches!(
+            Tree::with_policy(entries, &EntryNamePolicy::strict()),
+            Err(Error::InvalidEntryName(_))
+        ));
+    }
+
+    #[test]
+    fn test_tree_encoding_policy() {
+        let policy = EntryNamePolicy::strict();
+        let tree = |name: &str| {
+            Tree::with_policy(
+                vec![TreeEntry::new(
+                    name,
+                    EntryKind::regular(Hash::ZERO, 0, vec![]),
+                )],
+                &policy,
+            )
+        };
+
+        assert!(tree("caf\u{e9}").is_ok());
+        // "e" + combining acute accent
+        assert!(tree("cafe\u{301}").is_err());
+        // lossily converted non-UTF-8 name
+        assert!(tree("bad\u{fffd}name").is_err());
+    }
+
     #[test]
     fn test_entry_kind_type_names() {
         assert_eq!(EntryKind::regular(Hash::ZERO, 0, vec![]).type_name(), "regular");
@@ -404,7 +640,8 @@ mod tests {
             uid: 0,
             gid: 0,
             mode: 0o644,
-            xattrs: vec![]
+            xattrs: vec![],
+            times: None,
         }
         .hash()
         .is_none());
@@ -425,6 +662,7 @@ mod tests {
                     gid: 6,
                     mode: 0o660,
                     xattrs: vec![],
+                    times: None,
                 },
             ),
             TreeEntry::new("hardlink", EntryKind::hardlink("file.txt")),